@@ -0,0 +1,68 @@
+//! `countdown:<n>` reads `n`, `n-1`, ... `1`, one per line.
+//!
+//! Shows how a crate using websocat as a library adds its own specifier class:
+//! implement `SpecifierClass` and `Specifier`, then call `register` before parsing.
+
+extern crate futures;
+extern crate websocat;
+
+use std::io::{sink, Cursor};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use websocat::specparse::ArgKind;
+use websocat::{ConstructParams, Peer, PeerConstructor, Specifier, SpecifierClass, SpecifierType};
+
+pub struct CountdownClass;
+
+impl SpecifierClass for CountdownClass {
+    fn get_name(&self) -> &'static str {
+        "CountdownClass"
+    }
+    fn get_prefixes(&self) -> Vec<&'static str> {
+        vec!["countdown:"]
+    }
+    fn help(&self) -> &'static str {
+        r#"
+Read numbers from the given one down to 1, one per line.
+
+Example:
+
+    websocat -u countdown:3 -
+"#
+    }
+    fn arg_kind(&self) -> ArgKind {
+        ArgKind::Other
+    }
+    fn construct(&self, _full: &str, just_arg: &str) -> Result<Rc<Specifier>, Box<::std::error::Error>> {
+        let n = just_arg
+            .parse()
+            .map_err(|e| format!("countdown: expects a number, not `{}`: {}", just_arg, e))?;
+        Ok(Rc::new(Countdown(n)))
+    }
+}
+
+#[derive(Debug)]
+pub struct Countdown(pub u32);
+
+impl Specifier for Countdown {
+    fn construct(&self, _p: ConstructParams) -> PeerConstructor {
+        let text: String = (1..self.0 + 1).rev().map(|i| format!("{}\n", i)).collect();
+        let peer = Peer::new(Cursor::new(text.into_bytes()), sink());
+        websocat::once(Box::new(futures::future::ok(peer)))
+    }
+    fn is_multiconnect(&self) -> bool {
+        false
+    }
+    fn uses_global_state(&self) -> bool {
+        false
+    }
+    fn get_type(&self) -> SpecifierType {
+        SpecifierType::Other
+    }
+}
+
+/// Make `countdown:` known to websocat, on all threads
+pub fn register() {
+    websocat::register_specifier_class(Arc::new(CountdownClass));
+}
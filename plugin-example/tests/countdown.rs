@@ -0,0 +1,34 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate websocat;
+extern crate websocat_plugin_example;
+
+use futures::Future;
+use tokio_core::reactor::Core;
+use websocat::builder::WebsocatBuilder;
+
+#[test]
+fn countdown() {
+    assert!(websocat::spec("countdown:3").is_err());
+    websocat_plugin_example::register();
+
+    // Parsing, introspection and `--list-features` know about it
+    let t = websocat::spectree::parse("log:countdown:3").unwrap();
+    assert_eq!(t.overlay_chain(), vec!["LogClass", "CountdownClass"]);
+    let json = websocat::capabilities::to_json(&[]);
+    assert!(json.contains(r#""name":"CountdownClass","needs_argument":true,"prefixes":["countdown:"]"#));
+    assert!(websocat::spec("countdown:three").is_err());
+
+    // Runs like a built-in one, from other threads too
+    let run = || {
+        let mut core = Core::new().unwrap();
+        let prog = WebsocatBuilder::new()
+            .left("countdown:3")
+            .right("assert:3\n2\n1\n")
+            .configure(|o| o.unidirectional = true)
+            .run(&core.handle());
+        core.run(prog.map_err(|e| e.to_string()))
+    };
+    run().unwrap();
+    std::thread::spawn(run).join().unwrap().unwrap();
+}
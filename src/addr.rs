@@ -0,0 +1,159 @@
+//! Socket addresses of `tcp:`, `tcp-l:`, `udp:`, `udp-l:` and hosts of `ws://` URLs.
+//!
+//! IPv6 literals are bracketed and may have a zone: `[fe80::1%eth0]:8080`, or
+//! `ws://[fe80::1%25eth0]:8080/` in URLs (RFC 6874). The zone, an interface name or
+//! index, becomes the scope id of the address. It is never sent, `Host:` goes without it.
+
+#[cfg(all(unix, feature = "libc"))]
+extern crate libc;
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+fn invalid(s: &str, why: &str) -> String {
+    format!("Invalid socket address `{}`: {}", s, why)
+}
+
+/// Parse `1.2.3.4:80`, `[::1]:80` or `[fe80::1%eth0]:80`
+pub fn parse_socket_addr(s: &str) -> Result<SocketAddr, String> {
+    if s.is_empty() {
+        return Err(invalid(s, "empty"));
+    }
+    if s.starts_with('[') {
+        let end = match s.find(']') {
+            Some(x) => x,
+            None => return Err(invalid(s, "missing `]` after the IPv6 address")),
+        };
+        if !s[end + 1..].starts_with(':') {
+            return Err(invalid(s, "expected `:port` after `]`"));
+        }
+        let port = parse_port(&s[end + 2..]).map_err(|e| invalid(s, &e))?;
+        let (ip, scope) = ipv6_with_zone(&s[1..end]).map_err(|e| invalid(s, &e))?;
+        return Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope)));
+    }
+    let colon = match s.rfind(':') {
+        Some(x) => x,
+        None => return Err(invalid(s, "missing `:port`")),
+    };
+    let host = &s[..colon];
+    if host.contains(':') {
+        return Err(invalid(s, "IPv6 addresses need brackets, like `[::1]:8080`"));
+    }
+    let ip: Ipv4Addr = match host.parse() {
+        Ok(x) => x,
+        Err(_) => return Err(invalid(s, &format!("`{}` is not an IPv4 address", host))),
+    };
+    let port = parse_port(&s[colon + 1..]).map_err(|e| invalid(s, &e))?;
+    Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+}
+
+fn parse_port(s: &str) -> Result<u16, String> {
+    if s.is_empty() {
+        return Err("missing port number".to_string());
+    }
+    s.parse().map_err(|_| format!("`{}` is not a port number", s))
+}
+
+/// `fe80::1` or `fe80::1%eth0`, without brackets
+fn ipv6_with_zone(s: &str) -> Result<(Ipv6Addr, u32), String> {
+    let (ip, zone) = match s.find('%') {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let ip: Ipv6Addr = match ip.parse() {
+        Ok(x) => x,
+        Err(_) => return Err(format!("`{}` is not an IPv6 address", ip)),
+    };
+    let scope = match zone {
+        Some(z) => zone_scope(z)?,
+        None => 0,
+    };
+    Ok((ip, scope))
+}
+
+/// Scope id for a zone: an interface index or name
+pub fn zone_scope(zone: &str) -> Result<u32, String> {
+    if zone.is_empty() {
+        return Err("empty zone id after `%`".to_string());
+    }
+    if zone.contains('%') {
+        return Err(format!("`%` in zone id `{}`", zone));
+    }
+    if let Ok(x) = zone.parse() {
+        return Ok(x);
+    }
+    interface_index(zone)
+}
+
+#[cfg(all(unix, feature = "libc"))]
+fn interface_index(name: &str) -> Result<u32, String> {
+    let c = match ::std::ffi::CString::new(name) {
+        Ok(x) => x,
+        Err(_) => return Err(format!("No network interface named `{}`", name)),
+    };
+    match unsafe { libc::if_nametoindex(c.as_ptr()) } {
+        0 => Err(format!("No network interface named `{}`", name)),
+        x => Ok(x),
+    }
+}
+
+#[cfg(not(all(unix, feature = "libc")))]
+fn interface_index(name: &str) -> Result<u32, String> {
+    Err(format!(
+        "Zone id `{}` should be an interface index on this platform or build",
+        name
+    ))
+}
+
+/// Take the zone out of a bracketed IPv6 host of a URL, as the URL parser rejects it.
+/// Returns the URL without the zone and the scope id, if there was a zone.
+///
+/// The zone follows `%25`, the encoded `%` (RFC 6874): `[fe80::1%25eth0]`, `[fe80::1%251]`.
+pub fn split_url_zone(url: &str) -> Result<(String, Option<u32>), String> {
+    let start = match url.find("://") {
+        Some(x) => x + 3,
+        None => return Ok((url.to_string(), None)),
+    };
+    let rest = &url[start..];
+    let authority = match rest.find(|c: char| c == '/' || c == '?' || c == '#') {
+        Some(x) => &rest[..x],
+        None => rest,
+    };
+    let host_start = start + authority.rfind('@').map_or(0, |x| x + 1);
+    let host = &url[host_start..start + authority.len()];
+    if !host.starts_with('[') {
+        return Ok((url.to_string(), None));
+    }
+    let end = match host.find(']') {
+        Some(x) => host_start + x,
+        None => return Err(format!("Missing `]` after the IPv6 address in `{}`", url)),
+    };
+    let pct = match url[host_start..end].find('%') {
+        Some(x) => host_start + x,
+        None => return Ok((url.to_string(), None)),
+    };
+    let ip = &url[host_start + 1..pct];
+    if ip.parse::<Ipv6Addr>().is_err() {
+        return Err(format!("`{}` in `{}` is not an IPv6 address", ip, url));
+    }
+    let zone = &url[pct + 1..end];
+    if !zone.starts_with("25") {
+        return Err(format!("Zone id should follow `%25`, like `[fe80::1%25eth0]`, in `{}`", url));
+    }
+    let zone = &zone[2..];
+    if zone.is_empty() {
+        return Err(format!("empty zone id after `%25` in `{}`", url));
+    }
+    let scope = zone_scope(zone).map_err(|e| format!("{} in `{}`", e, url))?;
+    Ok((format!("{}{}", &url[..pct], &url[end..]), Some(scope)))
+}
+
+/// Address with the scope id of the zone (if any) from `split_url_zone`
+pub fn with_scope(addr: SocketAddr, scope: Option<u32>) -> SocketAddr {
+    match (addr, scope) {
+        (SocketAddr::V6(mut a), Some(s)) => {
+            a.set_scope_id(s);
+            SocketAddr::V6(a)
+        }
+        (a, _) => a,
+    }
+}
@@ -3,7 +3,11 @@
 macro_rules! list_of_all_specifier_classes {
     ($your_macro:ident) => {
         $your_macro!($crate::ws_client_peer::WsClientClass);
+        #[cfg(feature = "ssl")]
+        $your_macro!($crate::ws_client_peer::WssClientClass);
         $your_macro!($crate::ws_server_peer::WsServerClass);
+        #[cfg(feature = "ssl")]
+        $your_macro!($crate::ssl_peer::SslAcceptClass);
 
         #[cfg(all(unix, feature = "unix_stdio"))]
         $your_macro!($crate::stdio_peer::StdioClass);
@@ -17,6 +21,8 @@ macro_rules! list_of_all_specifier_classes {
         $your_macro!($crate::process_peer::ShCClass);
         #[cfg(feature = "tokio-process")]
         $your_macro!($crate::process_peer::ExecClass);
+        #[cfg(feature = "tokio-process")]
+        $your_macro!($crate::filtermsg_peer::FilterMsgClass);
 
         $your_macro!($crate::file_peer::ReadFileClass);
         $your_macro!($crate::file_peer::WriteFileClass);
@@ -25,6 +31,9 @@ macro_rules! list_of_all_specifier_classes {
         $your_macro!($crate::primitive_reuse_peer::ReuserClass);
         $your_macro!($crate::broadcast_reuse_peer::BroadcastReuserClass);
         $your_macro!($crate::reconnect_peer::AutoReconnectClass);
+        $your_macro!($crate::lb_peer::LbClass);
+        $your_macro!($crate::lb_peer::FailoverClass);
+        $your_macro!($crate::multilisten_peer::MultiListenClass);
 
         $your_macro!($crate::ws_client_peer::WsConnectClass);
 
@@ -51,18 +60,50 @@ macro_rules! list_of_all_specifier_classes {
         #[cfg(unix)]
         $your_macro!($crate::unix_peer::AbstractDgramClass);
 
+        #[cfg(windows)]
+        $your_macro!($crate::named_pipe_peer::NamedPipeConnectClass);
+        #[cfg(windows)]
+        $your_macro!($crate::named_pipe_peer::NamedPipeListenClass);
+
         $your_macro!($crate::line_peer::Message2LineClass);
         $your_macro!($crate::line_peer::Line2MessageClass);
+        $your_macro!($crate::lenprefix_peer::LengthPrefixClass);
+        $your_macro!($crate::log_peer::LogClass);
+        $your_macro!($crate::jsonwrap_peer::JsonWrapClass);
+        $your_macro!($crate::msgpack_peer::MsgPack2JsonClass);
+        $your_macro!($crate::msgpack_peer::Cbor2JsonClass);
+        $your_macro!($crate::throttle_peer::ThrottleClass);
+        $your_macro!($crate::delay_peer::DelayClass);
+        $your_macro!($crate::clog_peer::ClogClass);
+        $your_macro!($crate::chunk_peer::ChunkClass);
+        $your_macro!($crate::chunk_peer::UnchunkClass);
+        $your_macro!($crate::record_peer::RecordClass);
+        $your_macro!($crate::replay_peer::ReplayClass);
+        $your_macro!($crate::prepend_peer::PrependClass);
+        $your_macro!($crate::prepend_peer::PrependFileClass);
+        $your_macro!($crate::prepend_peer::AppendClass);
+        $your_macro!($crate::seqnum_peer::SeqNumClass);
+        $your_macro!($crate::crc_peer::CrcClass);
+        $your_macro!($crate::crc_peer::CrcStreamClass);
         $your_macro!($crate::mirror_peer::MirrorClass);
+        $your_macro!($crate::mirror_peer::BadMirrorClass);
         $your_macro!($crate::mirror_peer::LiteralReplyClass);
         $your_macro!($crate::trivial_peer::CloggedClass);
         $your_macro!($crate::trivial_peer::LiteralClass);
+        $your_macro!($crate::trivial_peer::LiteralHexClass);
+        $your_macro!($crate::trivial_peer::LiteralFileClass);
+        $your_macro!($crate::generator_peer::RandomClass);
+        $your_macro!($crate::generator_peer::ZeroClass);
+        $your_macro!($crate::count_peer::NullClass);
+        $your_macro!($crate::count_peer::CountClass);
         $your_macro!($crate::trivial_peer::AssertClass);
         $your_macro!($crate::trivial_peer::Assert2Class);
+        $your_macro!($crate::trivial_peer::AssertLiteralClass);
+        $your_macro!($crate::trivial_peer::AssertFileClass);
 
-        #[cfg(feature = "seqpacket")]
+        #[cfg(all(unix, feature = "seqpacket"))]
         $your_macro!($crate::unix_peer::SeqpacketConnectClass);
-        #[cfg(feature = "seqpacket")]
+        #[cfg(all(unix, feature = "seqpacket"))]
         $your_macro!($crate::unix_peer::SeqpacketListenClass);
 
         /*
@@ -0,0 +1,107 @@
+//! Session-level coalescing of small messages (`--batch-window-ms`)
+//!
+//! A batch is an ordinary message for the other side, so it is sent as text or binary
+//! like any other message (all-text input stays text). WebSocket pings are produced
+//! by the WebSocket peer itself and are neither delayed nor batched.
+
+use futures::Async::NotReady;
+use futures::Future;
+
+use std::time::Duration;
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::{ReadDebt, Options};
+
+use std::io::{Error as IoError, ErrorKind, Read};
+use tokio_io::AsyncRead;
+
+/// Batch accumulates messages for at most `window` after the first one,
+/// or until `max_bytes` / `max_count` is reached, then gets delivered
+/// as one message. Also flushed on EOF.
+pub struct BatchRead {
+    inner: Box<AsyncRead>,
+    batch: Vec<u8>,
+    count: usize,
+    window: Duration,
+    max_bytes: Option<usize>,
+    max_count: Option<usize>,
+    separator: Vec<u8>,
+    timer: Option<Timeout>,
+    handle: Handle,
+    eof: bool,
+    debt: ReadDebt,
+}
+
+impl BatchRead {
+    pub fn new(inner: Box<AsyncRead>, window_ms: u64, opts: &Options, h: &Handle) -> BatchRead {
+        BatchRead {
+            inner,
+            batch: vec![],
+            count: 0,
+            window: Duration::from_millis(window_ms),
+            max_bytes: opts.batch_max_bytes,
+            max_count: opts.batch_max_count,
+            separator: opts.batch_separator.clone(),
+            timer: None,
+            handle: h.clone(),
+            eof: false,
+            debt: ReadDebt(None),
+        }
+    }
+
+    fn full(&self) -> bool {
+        self.max_count.map_or(false, |m| self.count >= m)
+            || self.max_bytes.map_or(false, |m| self.batch.len() >= m)
+    }
+
+    fn flush(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        debug!("Flushing batch of {} messages", self.count);
+        self.timer = None;
+        self.count = 0;
+        let batch = ::std::mem::replace(&mut self.batch, vec![]);
+        self.debt.process_message(buf, &batch)
+    }
+}
+
+impl Read for BatchRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        loop {
+            if self.count > 0 && (self.eof || self.full()) {
+                return self.flush(buf);
+            }
+            if self.eof {
+                return Ok(0);
+            }
+            match self.inner.read(buf) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    if self.count == 0 {
+                        self.timer = Some(Timeout::new(self.window, &self.handle)?);
+                    } else {
+                        self.batch.extend_from_slice(&self.separator);
+                    }
+                    self.batch.extend_from_slice(&buf[..n]);
+                    self.count += 1;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock && self.count > 0 => {
+                    if let NotReady = self.timer.as_mut().unwrap().poll()? {
+                        // Both the timer and the inner reader will wake us up
+                        return Err(ErrorKind::WouldBlock.into());
+                    }
+                    return self.flush(buf);
+                }
+                Err(e) => {
+                    if self.count > 0 {
+                        warn!("Discarding batch of {} messages because of error", self.count);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+impl AsyncRead for BatchRead {}
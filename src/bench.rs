@@ -0,0 +1,438 @@
+//! `--bench`: echo latency and throughput measurement.
+//!
+//! Each connection gets a generator in place of the left specifier. It sends
+//! numbered messages and matches the echoes coming back. With `--bench-rate`
+//! messages are sent on a fixed schedule and latency is counted from the
+//! scheduled time, so a stalled server can't hide its stalls by slowing the
+//! generator down (coordinated omission). Without it, the next message is sent
+//! only when the previous echo arrives.
+
+extern crate serde_json;
+
+use futures::future::{join_all, ok, Future};
+use futures::task::{self, Task};
+use futures::Async::{NotReady, Ready};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{Error as IoError, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use self::serde_json::{Map, Value};
+
+use super::{once, wouldblock, BoxedNewPeerFuture, ConstructParams, Options, Peer, PeerConstructor, Specifier};
+use super::WebsocatConfiguration;
+
+/// Sequence number and send time, both big-endian u64
+pub const HEADER_LEN: usize = 16;
+
+/// How long to wait for outstanding echoes after the last message is sent
+const DRAIN_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BenchParams {
+    /// Total for all connections
+    pub messages: u64,
+    pub size: usize,
+    pub concurrency: usize,
+    /// Messages per second for all connections; `None` means send on echo
+    pub rate: Option<f64>,
+}
+
+struct ConnState {
+    total: u64,
+    size: usize,
+    interval: Option<Duration>,
+    /// Reset when the first message is about to be sent, so that connecting does not count
+    start: Instant,
+    started: bool,
+    /// When each message was (or should have been) sent, indexed by sequence number
+    scheduled: Vec<Instant>,
+    echoed: Vec<bool>,
+    received: u64,
+    latencies: Vec<Duration>,
+    mismatched: u64,
+    last_echo: Option<Instant>,
+    reader: Option<Task>,
+}
+
+impl ConnState {
+    fn new(total: u64, size: usize, interval: Option<Duration>) -> ConnState {
+        ConnState {
+            total,
+            size,
+            interval,
+            start: Instant::now(),
+            started: false,
+            scheduled: Vec::with_capacity(total as usize),
+            echoed: Vec::with_capacity(total as usize),
+            received: 0,
+            latencies: Vec::with_capacity(total as usize),
+            mismatched: 0,
+            last_echo: None,
+            reader: None,
+        }
+    }
+
+    fn sent(&self) -> u64 {
+        self.scheduled.len() as u64
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(t) = self.reader.take() {
+            t.notify();
+        }
+    }
+}
+
+fn filler(seq: u64, i: usize) -> u8 {
+    (seq as usize).wrapping_add(i) as u8
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn be_u64(b: &[u8]) -> u64 {
+    b.iter().fold(0, |a, &x| (a << 8) | u64::from(x))
+}
+
+/// Takes the place of the left specifier for one connection
+#[derive(Clone)]
+struct Bench(Rc<RefCell<ConnState>>);
+impl fmt::Debug for Bench {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bench")
+    }
+}
+impl Specifier for Bench {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let r = BenchRead {
+            st: self.0.clone(),
+            handle: cp.tokio_handle.clone(),
+            timer: None,
+        };
+        let w = BenchWrite(self.0.clone());
+        once(Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture)
+    }
+    specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
+}
+
+/// Generates the messages
+struct BenchRead {
+    st: Rc<RefCell<ConnState>>,
+    handle: Handle,
+    /// Wakeup and the time it is for
+    timer: Option<(Instant, Timeout)>,
+}
+
+impl BenchRead {
+    /// `true` if `t` has come, otherwise arranges for a wakeup
+    fn wait_until(&mut self, t: Instant) -> Result<bool, IoError> {
+        let now = Instant::now();
+        if t <= now {
+            self.timer = None;
+            return Ok(true);
+        }
+        if self.timer.as_ref().map_or(true, |x| x.0 != t) {
+            self.timer = Some((t, Timeout::new(t - now, &self.handle)?));
+        }
+        match self.timer.as_mut().unwrap().1.poll()? {
+            Ready(()) => {
+                self.timer = None;
+                Ok(true)
+            }
+            NotReady => Ok(false),
+        }
+    }
+}
+
+impl Read for BenchRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        {
+            let mut s = self.st.borrow_mut();
+            if !s.started {
+                s.started = true;
+                s.start = Instant::now();
+            }
+        }
+        let (sent, total, size, interval, start, answered) = {
+            let s = self.st.borrow();
+            (s.sent(), s.total, s.size, s.interval, s.start, s.received + s.mismatched)
+        };
+        let waiting = answered < sent;
+        if sent == total || (interval.is_none() && waiting) {
+            // Wait for outstanding echoes. Without them, end the session.
+            let last = *self.st.borrow().scheduled.last().unwrap_or(&start);
+            if !waiting {
+                if sent == total {
+                    return Ok(0);
+                }
+            } else if self.wait_until(last + Duration::from_secs(DRAIN_SECS))? {
+                return Ok(0);
+            } else {
+                self.st.borrow_mut().reader = Some(task::current());
+                return wouldblock();
+            }
+        }
+        let at = match interval {
+            None => Instant::now(),
+            Some(i) => {
+                let due = start + i * sent as u32;
+                if !self.wait_until(due)? {
+                    return wouldblock();
+                }
+                due
+            }
+        };
+        if buf.len() < size {
+            Err(IoError::new(
+                ::std::io::ErrorKind::Other,
+                "--bench-size is bigger than the copy buffer, increase --buffer-size",
+            ))?
+        }
+        let ts = nanos(at.duration_since(start));
+        for i in 0..8 {
+            buf[i] = (sent >> (56 - i * 8)) as u8;
+            buf[8 + i] = (ts >> (56 - i * 8)) as u8;
+        }
+        for (i, b) in buf[HEADER_LEN..size].iter_mut().enumerate() {
+            *b = filler(sent, i);
+        }
+        let mut s = self.st.borrow_mut();
+        s.scheduled.push(at);
+        s.echoed.push(false);
+        Ok(size)
+    }
+}
+impl AsyncRead for BenchRead {}
+
+/// Matches the echoes
+struct BenchWrite(Rc<RefCell<ConnState>>);
+impl Write for BenchWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let now = Instant::now();
+        let mut s = self.0.borrow_mut();
+        let seq = if buf.len() >= HEADER_LEN {
+            be_u64(&buf[..8])
+        } else {
+            u64::max_value()
+        };
+        let intact = buf.len() == s.size
+            && seq < s.sent()
+            && !s.echoed[seq as usize]
+            && be_u64(&buf[8..16]) == nanos(s.scheduled[seq as usize].duration_since(s.start))
+            && buf[HEADER_LEN..].iter().enumerate().all(|(i, &b)| b == filler(seq, i));
+        if intact {
+            let l = now.duration_since(s.scheduled[seq as usize]);
+            s.echoed[seq as usize] = true;
+            s.latencies.push(l);
+            s.received += 1;
+            s.last_echo = Some(now);
+        } else {
+            debug!("Unexpected echo of {} bytes", buf.len());
+            s.mismatched += 1;
+        }
+        s.wake_reader();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+impl AsyncWrite for BenchWrite {
+    fn shutdown(&mut self) -> ::futures::Poll<(), IoError> {
+        Ok(Ready(()))
+    }
+}
+
+/// Results of one connection, or of all of them together
+#[derive(Debug, Default, Clone)]
+pub struct BenchReport {
+    /// Sorted
+    pub latencies: Vec<Duration>,
+    pub sent: u64,
+    pub received: u64,
+    pub mismatched: u64,
+    pub lost: u64,
+    pub bytes: u64,
+    /// From the start until the last echo
+    pub elapsed: Duration,
+    pub error: Option<String>,
+}
+
+fn secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+impl BenchReport {
+    fn from_state(s: &ConnState, error: Option<String>) -> BenchReport {
+        let mut latencies = s.latencies.clone();
+        latencies.sort();
+        BenchReport {
+            latencies,
+            sent: s.sent(),
+            received: s.received,
+            mismatched: s.mismatched,
+            lost: s.sent() - s.received,
+            bytes: s.received * s.size as u64,
+            elapsed: s.last_echo.map_or(Duration::from_secs(0), |t| t.duration_since(s.start)),
+            error,
+        }
+    }
+
+    /// Sums up several connections
+    pub fn aggregate(all: &[BenchReport]) -> BenchReport {
+        let mut r = BenchReport::default();
+        for x in all {
+            r.latencies.extend_from_slice(&x.latencies);
+            r.sent += x.sent;
+            r.received += x.received;
+            r.mismatched += x.mismatched;
+            r.lost += x.lost;
+            r.bytes += x.bytes;
+            r.elapsed = r.elapsed.max(x.elapsed);
+            if r.error.is_none() {
+                r.error = x.error.clone();
+            }
+        }
+        r.latencies.sort();
+        r
+    }
+
+    /// Latency at the given percentile, 0 to 100
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let i = ((p / 100.0) * (self.latencies.len() - 1) as f64).round() as usize;
+        Some(self.latencies[i])
+    }
+
+    pub fn messages_per_sec(&self) -> f64 {
+        let s = secs_f64(self.elapsed);
+        if s > 0.0 {
+            self.received as f64 / s
+        } else {
+            0.0
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        let s = secs_f64(self.elapsed);
+        if s > 0.0 {
+            self.bytes as f64 / s
+        } else {
+            0.0
+        }
+    }
+
+    /// Everything sent came back intact
+    pub fn ok(&self) -> bool {
+        self.error.is_none() && self.lost == 0 && self.mismatched == 0
+    }
+
+    fn latency_ms(&self, p: f64) -> Option<f64> {
+        self.percentile(p).map(|d| secs_f64(d) * 1000.0)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut o = Map::new();
+        for &(k, p) in &[("min_ms", 0.0), ("p50_ms", 50.0), ("p95_ms", 95.0), ("p99_ms", 99.0), ("max_ms", 100.0)] {
+            o.insert(k.into(), self.latency_ms(p).map_or(Value::Null, |x| x.into()));
+        }
+        o.insert("sent".into(), self.sent.into());
+        o.insert("received".into(), self.received.into());
+        o.insert("mismatched".into(), self.mismatched.into());
+        o.insert("lost".into(), self.lost.into());
+        o.insert("messages_per_sec".into(), self.messages_per_sec().into());
+        o.insert("bytes_per_sec".into(), self.bytes_per_sec().into());
+        o.insert("error".into(), self.error.as_ref().map_or(Value::Null, |x| x.as_str().into()));
+        Value::Object(o)
+    }
+
+    fn write_row<W: Write>(&self, name: &str, w: &mut W) -> Result<(), IoError> {
+        let ms = |p| self.latency_ms(p).map_or("-".to_string(), |x| format!("{:.3}", x));
+        writeln!(
+            w,
+            "{:6} {:>9} {:>9} {:>9} {:>9} {:>9} {:>10.1} {:>12.0} {:>7} {:>7} {:>7}{}",
+            name,
+            ms(0.0),
+            ms(50.0),
+            ms(95.0),
+            ms(99.0),
+            ms(100.0),
+            self.messages_per_sec(),
+            self.bytes_per_sec(),
+            self.received,
+            self.lost,
+            self.mismatched,
+            self.error.as_ref().map_or(String::new(), |e| format!("  {}", e)),
+        )
+    }
+}
+
+/// Per-connection rows followed by the aggregate
+pub fn write_table<W: Write>(conns: &[BenchReport], w: &mut W) -> Result<(), IoError> {
+    writeln!(
+        w,
+        "{:6} {:>9} {:>9} {:>9} {:>9} {:>9} {:>10} {:>12} {:>7} {:>7} {:>7}",
+        "CONN", "MIN_MS", "P50_MS", "P95_MS", "P99_MS", "MAX_MS", "MSG/S", "BYTES/S", "ECHOED", "LOST", "BAD"
+    )?;
+    for (i, c) in conns.iter().enumerate() {
+        c.write_row(&i.to_string(), w)?;
+    }
+    BenchReport::aggregate(conns).write_row("total", w)
+}
+
+/// Per-connection results and the aggregate as one JSON object
+pub fn to_json(conns: &[BenchReport]) -> String {
+    let mut o = Map::new();
+    o.insert("total".into(), BenchReport::aggregate(conns).to_json());
+    o.insert("connections".into(), Value::Array(conns.iter().map(|c| c.to_json()).collect()));
+    Value::Object(o).to_string()
+}
+
+/// Run the benchmark against `target`, which should echo messages back
+pub fn run_bench(
+    h: &Handle,
+    target: Rc<Specifier>,
+    opts: Options,
+    p: BenchParams,
+) -> Box<Future<Item = Vec<BenchReport>, Error = ()>> {
+    let n = p.concurrency.max(1) as u64;
+    let conns = (0..n).map(|i| {
+        // Spread the remainder over the first connections
+        let total = p.messages / n + if i < p.messages % n { 1 } else { 0 };
+        let interval = p.rate.map(|r| {
+            let per_conn = r / n as f64;
+            let ns = (1e9 / per_conn) as u64;
+            Duration::new(ns / 1_000_000_000, (ns % 1_000_000_000) as u32)
+        });
+        let st = Rc::new(RefCell::new(ConnState::new(total, p.size, interval)));
+        let error = Rc::new(RefCell::new(None));
+        let error2 = error.clone();
+        let conf = WebsocatConfiguration {
+            opts: opts.clone(),
+            s1: Rc::new(Bench(st.clone())),
+            s2: target.clone(),
+        };
+        conf.serve(
+            h.clone(),
+            Rc::new(move |e: Box<::std::error::Error>| {
+                let mut x = error2.borrow_mut();
+                if x.is_none() {
+                    *x = Some(format!("{}", e));
+                }
+            }),
+        ).then(move |_| {
+            let e = error.borrow_mut().take();
+            Ok::<_, ()>(BenchReport::from_state(&st.borrow(), e))
+        })
+    });
+    Box::new(join_all(conns.collect::<Vec<_>>()))
+}
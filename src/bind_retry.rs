@@ -0,0 +1,248 @@
+//! `--bind-retry`: listeners whose address is still held by a previous instance
+//! (`EADDRINUSE`, or `EADDRNOTAVAIL` while interfaces come up) try binding again
+//! after `--bind-retry-delay`, on reactor timers.
+//!
+//! Readiness (`--pid-file`, `READY=1`, `--notify-fd`) is to be reported only after
+//! all listeners are bound, and not at all if one of them failed, see `all_bound`.
+//! Each listener is counted as bound or failed once it is known, as pending before that.
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+
+use std::cell::RefCell;
+use std::io::{Error as IoError, ErrorKind};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::error::{io_context, WebsocatError};
+use super::{box_up_err, peer_err_s, BoxedNewPeerStream, Options, Peer};
+
+#[derive(Default)]
+struct Counts {
+    /// Listeners still trying to bind
+    pending: usize,
+    bound: usize,
+    /// Listeners that gave up binding
+    failed: usize,
+    /// `AllBound` futures waiting for the above to change
+    waiting: Vec<Task>,
+}
+
+/// Bind outcomes of the listeners of one program: its main thread and its `--workers`
+/// threads record to the same `Outcomes` (see `share`). Other threads have their own.
+#[derive(Clone, Default)]
+pub struct Outcomes(Arc<Mutex<Counts>>);
+
+thread_local! {
+    static OUTCOMES: RefCell<Outcomes> = RefCell::new(Outcomes::default());
+}
+
+/// Where listeners of this thread record their bind outcomes
+pub fn outcomes() -> Outcomes {
+    OUTCOMES.with(|x| x.borrow().clone())
+}
+
+/// Record bind outcomes of this thread in `o`, like a `--workers` thread does
+/// with the outcomes of the main thread
+pub fn share(o: Outcomes) {
+    OUTCOMES.with(|x| *x.borrow_mut() = o);
+}
+
+fn with_counts<R, F: FnOnce(&mut Counts) -> R>(f: F) -> R {
+    let o = outcomes();
+    let mut c = o.0.lock().unwrap();
+    f(&mut c)
+}
+
+/// Number of listeners that are waiting to retry their bind
+pub fn pending() -> usize {
+    with_counts(|c| c.pending)
+}
+
+/// Number of listeners that got bound
+pub fn bound() -> usize {
+    with_counts(|c| c.bound)
+}
+
+/// Number of listeners that failed to bind
+pub fn failed() -> usize {
+    with_counts(|c| c.failed)
+}
+
+/// Count one more listener as bound or failed, and maybe no longer as pending
+fn record(ok: bool, was_pending: bool) {
+    let waiting = with_counts(|c| {
+        if was_pending {
+            c.pending -= 1;
+        }
+        if ok {
+            c.bound += 1;
+        } else {
+            c.failed += 1;
+        }
+        ::std::mem::replace(&mut c.waiting, vec![])
+    });
+    for t in waiting {
+        t.notify();
+    }
+}
+
+/// Counted as failed
+fn give_up(what: &str, e: IoError) -> WebsocatError {
+    record(false, false);
+    io_context(what, e)
+}
+
+/// Resolves when no listener is waiting to retry its bind and all of them got bound.
+/// Fails as soon as some listener could not be bound, or gave up retrying.
+pub struct AllBound;
+
+pub fn all_bound() -> AllBound {
+    AllBound
+}
+
+impl Future for AllBound {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        with_counts(|c| {
+            if c.failed > 0 {
+                return Err(());
+            }
+            if c.pending == 0 {
+                return Ok(Async::Ready(()));
+            }
+            c.waiting.push(task::current());
+            Ok(Async::NotReady)
+        })
+    }
+}
+
+/// Counted as pending until `finish`ed, or as failed if it is dropped before that
+struct PendingBind(bool);
+
+impl PendingBind {
+    fn new() -> Self {
+        with_counts(|c| c.pending += 1);
+        PendingBind(false)
+    }
+
+    fn finish(&mut self, ok: bool) {
+        if !self.0 {
+            self.0 = true;
+            record(ok, true);
+        }
+    }
+}
+
+impl Drop for PendingBind {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
+
+fn retriable(e: &IoError) -> bool {
+    e.kind() == ErrorKind::AddrInUse || e.kind() == ErrorKind::AddrNotAvailable
+}
+
+/// Listener stream for the socket made by `bind`, with `incoming` turning it into peers.
+/// `what` names the listener in messages, like `tcp-l:127.0.0.1:8080`.
+pub fn listen<L, B, F>(h: &Handle, what: String, opts: &Options, bind: B, incoming: F) -> BoxedNewPeerStream
+where
+    L: 'static,
+    B: Fn() -> Result<L, IoError> + 'static,
+    F: FnOnce(L) -> BoxedNewPeerStream + 'static,
+{
+    let e = match bind() {
+        Ok(l) => {
+            record(true, false);
+            return incoming(l);
+        }
+        Err(e) => e,
+    };
+    if !retriable(&e) || opts.bind_retry == 0 {
+        return peer_err_s(give_up(&what, e));
+    }
+    let delay = Duration::from_millis(opts.bind_retry_delay);
+    warn!(
+        "Failed to bind {}: {}. Retrying in {} ms, {} attempts left",
+        what, e, opts.bind_retry_delay, opts.bind_retry
+    );
+    let timer = match Timeout::new(delay, h) {
+        Ok(x) => x,
+        Err(e) => return peer_err_s(give_up(&what, e)),
+    };
+    Box::new(Retrying {
+        state: State::Waiting(timer, PendingBind::new()),
+        attempts_left: opts.bind_retry,
+        delay_ms: opts.bind_retry_delay,
+        h: h.clone(),
+        what,
+        bind,
+        incoming: Some(incoming),
+        _listener: PhantomData,
+    }) as BoxedNewPeerStream
+}
+
+enum State {
+    Waiting(Timeout, PendingBind),
+    Bound(BoxedNewPeerStream),
+}
+
+struct Retrying<L, B, F> {
+    state: State,
+    attempts_left: usize,
+    delay_ms: u64,
+    h: Handle,
+    what: String,
+    bind: B,
+    incoming: Option<F>,
+    _listener: PhantomData<L>,
+}
+
+impl<L, B, F> Stream for Retrying<L, B, F>
+where
+    B: Fn() -> Result<L, IoError>,
+    F: FnOnce(L) -> BoxedNewPeerStream,
+{
+    type Item = Peer;
+    type Error = Box<::std::error::Error>;
+    fn poll(&mut self) -> Poll<Option<Peer>, Box<::std::error::Error>> {
+        loop {
+            let bound = match self.state {
+                State::Bound(ref mut s) => return s.poll(),
+                State::Waiting(ref mut timer, ref mut pending) => {
+                    if let Async::NotReady = timer.poll().map_err(box_up_err)? {
+                        return Ok(Async::NotReady);
+                    }
+                    self.attempts_left -= 1;
+                    match (self.bind)() {
+                        Ok(l) => {
+                            pending.finish(true);
+                            l
+                        }
+                        Err(e) => {
+                            if !retriable(&e) || self.attempts_left == 0 {
+                                pending.finish(false);
+                                return Err(box_up_err(io_context(&self.what, e)));
+                            }
+                            warn!(
+                                "Failed to bind {}: {}. Retrying in {} ms, {} attempts left",
+                                self.what, e, self.delay_ms, self.attempts_left
+                            );
+                            let delay = Duration::from_millis(self.delay_ms);
+                            *timer = Timeout::new(delay, &self.h).map_err(box_up_err)?;
+                            continue;
+                        }
+                    }
+                }
+            };
+            info!("Bound {}", self.what);
+            let incoming = self.incoming.take().unwrap();
+            self.state = State::Bound(incoming(bound));
+        }
+    }
+}
@@ -4,7 +4,9 @@ extern crate futures;
 extern crate tokio_io;
 
 use futures::future::ok;
-use std::cell::RefCell;
+use futures::task::{self, Task};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use std::rc::Rc;
 
 use super::{brokenpipe, io_other_error, simple_err, wouldblock, BoxedNewPeerFuture, Peer};
@@ -12,6 +14,7 @@ use super::{brokenpipe, io_other_error, simple_err, wouldblock, BoxedNewPeerFutu
 use std::io::{Error as IoError, Read, Write};
 use tokio_io::{AsyncRead, AsyncWrite};
 
+use super::reconnect_peer::ReconnectHook;
 use super::{once, ConstructParams, Handle, Options, PeerConstructor, ProgramState, Specifier};
 use futures::Async;
 use futures::AsyncSink;
@@ -31,8 +34,23 @@ impl Specifier for BroadcastReuser {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
         let mut reuser = p.global_state.borrow_mut().reuser2.clone();
         let h = p.tokio_handle.clone();
+        let opts = p.program_options.clone();
+        if overflow_mode(&opts.reuse_buffer_overflow).is_none() {
+            let e: Box<::std::error::Error> = format!(
+                "Unknown --reuse-buffer-overflow mode `{}`",
+                opts.reuse_buffer_overflow
+            ).into();
+            return once(Box::new(::futures::future::err(e)) as BoxedNewPeerFuture);
+        }
+        let generation = Rc::new(Cell::new(0));
+        let g = generation.clone();
+        let mut p = p;
+        p.reconnect_hook = Some(Rc::new(move || g.set(g.get() + 1)) as ReconnectHook);
         let inner = || self.0.construct(p).get_only_first_conn();
-        once(connection_reuser(&h, &mut reuser, inner))
+        once(connection_reuser(&h, &mut reuser, inner, &opts, generation))
+    }
+    fn is_shared_between_sessions(&self) -> bool {
+        true
     }
     specifier_boilerplate!(singleconnect has_subspec typ=Reuser globalstate);
     self_0_is_subspecifier!(...);
@@ -43,28 +61,233 @@ specifier_class!(
     target = BroadcastReuser,
     prefixes = ["reuse-broadcast:", "broadcast-reuse:", "broadcast:"],
     arg_handling = subspec,
+    consumed_options = &[
+        "--broadcast-retain",
+        "--broadcast-retain-count",
+        "--broadcast-retain-max-bytes",
+        "--retain-across-reconnect",
+        "--reuse-buffer",
+        "--reuse-buffer-bytes",
+        "--reuse-buffer-overflow",
+    ],
     help = r#"
 Reuse subspecifier for serving multiple clients: broadcast mode.
 
 Messages from any connected client get directed to subspecifier,
 replies from the subspecifier get duplicated across all connected
-clients (and are dropped if there are none).
+clients (and are dropped if there are none, unless --reuse-buffer is set).
 
 If WebSocket client is too slow for accepting incoming data,
 messages get accumulated up to the configurable --broadcast-buffer, then dropped.
 
+With --broadcast-retain, the most recent message from the subspecifier
+(or last N messages with --broadcast-retain-count N, limited in total size
+by --broadcast-retain-max-bytes, 0 means no limit) is delivered to each newly connected client
+before live traffic. Retained messages are forgotten when `autoreconnect:`
+inside the subspecifier reconnects, unless --retain-across-reconnect is set.
+
+With --reuse-buffer N, up to N messages (and --reuse-buffer-bytes bytes, 1 MiB by default)
+arriving while no clients are connected are queued and delivered, in order,
+to the next client before live traffic. --reuse-buffer-overflow chooses what
+happens when the queue is full: `drop-oldest` (default), `drop-newest` or
+`backpressure` (stop reading from the subspecifier until a client connects).
+The queue is dropped when `autoreconnect:` inside the subspecifier reconnects.
+
 Example: Simple data exchange between connected WebSocket clients
 
     websocat -E ws-l:0.0.0.0:8800 reuse-broadcast:mirror:
+
+Example: Status feed where late joiners immediately get the current status
+
+    websocat -E --broadcast-retain ws-l:0.0.0.0:8800 broadcast:autoreconnect:tcp:127.0.0.1:1234
 "#
 );
 
 type SailingBuffer = Rc<Vec<u8>>;
 type Clients = Slab<BroadcastClientIndex, mpsc::Sender<SailingBuffer>>;
 
+/// Recent messages from the inner peer, replayed to newly connected clients
+struct Retained {
+    messages: VecDeque<SailingBuffer>,
+    bytes: usize,
+    max_count: usize,
+    max_bytes: usize,
+    keep_across_reconnect: bool,
+}
+
+impl Retained {
+    fn new(opts: &Options) -> Self {
+        let max_count = match opts.broadcast_retain_count {
+            Some(n) => n,
+            None if opts.broadcast_retain => 1,
+            None => 0,
+        };
+        Retained {
+            messages: VecDeque::new(),
+            bytes: 0,
+            max_count,
+            max_bytes: match opts.broadcast_retain_max_bytes {
+                0 => usize::max_value(),
+                x => x,
+            },
+            keep_across_reconnect: opts.retain_across_reconnect,
+        }
+    }
+
+    fn push(&mut self, m: &SailingBuffer) {
+        if self.max_count == 0 {
+            return;
+        }
+        self.bytes += m.len();
+        self.messages.push_back(m.clone());
+        while self.messages.len() > self.max_count || self.bytes > self.max_bytes {
+            match self.messages.pop_front() {
+                Some(x) => self.bytes -= x.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.bytes = 0;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Overflow {
+    DropOldest,
+    DropNewest,
+    Backpressure,
+}
+
+/// Value of `--reuse-buffer-overflow`, empty meaning the default
+fn overflow_mode(s: &str) -> Option<Overflow> {
+    match s {
+        "" | "drop-oldest" => Some(Overflow::DropOldest),
+        "drop-newest" => Some(Overflow::DropNewest),
+        "backpressure" => Some(Overflow::Backpressure),
+        _ => None,
+    }
+}
+
+/// `--reuse-buffer-bytes` unless set
+pub const DEFAULT_REUSE_BUFFER_BYTES: usize = 1048576;
+
+/// Messages from the inner peer that arrived while no clients were connected
+struct DetachedQueue {
+    messages: VecDeque<SailingBuffer>,
+    bytes: usize,
+    max_count: usize,
+    max_bytes: usize,
+    overflow: Overflow,
+}
+
+impl DetachedQueue {
+    fn new(opts: &Options) -> Self {
+        // Checked when constructing the specifier
+        let overflow = overflow_mode(&opts.reuse_buffer_overflow).unwrap_or(Overflow::DropOldest);
+        DetachedQueue {
+            messages: VecDeque::new(),
+            bytes: 0,
+            max_count: opts.reuse_buffer.unwrap_or(0),
+            max_bytes: opts.reuse_buffer_bytes.unwrap_or(DEFAULT_REUSE_BUFFER_BYTES),
+            overflow,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_count > 0
+    }
+
+    /// Reading from the inner peer should pause until a client connects
+    fn must_block(&self) -> bool {
+        self.enabled()
+            && self.overflow == Overflow::Backpressure
+            && (self.messages.len() >= self.max_count || self.bytes >= self.max_bytes)
+    }
+
+    fn push(&mut self, m: &SailingBuffer) {
+        if !self.enabled() {
+            info!("Dropping broadcast due to no clients being connected");
+            return;
+        }
+        if self.overflow == Overflow::DropNewest
+            && (self.messages.len() >= self.max_count || self.bytes + m.len() > self.max_bytes)
+        {
+            warn!("Reuse buffer is full, dropping a message");
+            return;
+        }
+        self.bytes += m.len();
+        self.messages.push_back(m.clone());
+        while self.messages.len() > self.max_count || self.bytes > self.max_bytes {
+            match self.messages.pop_front() {
+                Some(x) => {
+                    warn!("Reuse buffer is full, dropping a message");
+                    self.bytes -= x.len();
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn take(&mut self) -> VecDeque<SailingBuffer> {
+        self.bytes = 0;
+        ::std::mem::replace(&mut self.messages, VecDeque::new())
+    }
+
+    /// Return messages a departed client has not read to the front of the queue
+    fn give_back(&mut self, unread: Vec<SailingBuffer>) {
+        for m in unread.into_iter().rev() {
+            self.bytes += m.len();
+            self.messages.push_front(m);
+        }
+    }
+}
+
 pub struct Broadcaster {
     inner_peer: Peer,
     clients: Clients,
+    retained: Retained,
+    detached: DetachedQueue,
+    /// Bumped by `autoreconnect:` inside the subspecifier
+    upstream_generation: Rc<Cell<u64>>,
+    seen_generation: u64,
+    /// Inner peer reader paused by `--reuse-buffer-overflow backpressure`
+    blocked_reader: Option<Task>,
+}
+
+impl Broadcaster {
+    /// Forget buffered messages that came from a previous upstream connection
+    fn check_reconnect(&mut self) {
+        let g = self.upstream_generation.get();
+        if g == self.seen_generation {
+            return;
+        }
+        self.seen_generation = g;
+        info!("Upstream reconnected");
+        if !self.retained.keep_across_reconnect {
+            self.retained.clear();
+        }
+        self.detached.take();
+    }
+
+    /// Messages a newly connected client reads before live traffic, and how
+    /// many of them (at the end) were taken from the detached queue
+    fn backlog(&mut self) -> (VecDeque<SailingBuffer>, usize) {
+        self.check_reconnect();
+        let queued = self.detached.take();
+        let mut v: VecDeque<SailingBuffer> = self
+            .retained
+            .messages
+            .iter()
+            .filter(|m| !queued.iter().any(|q| Rc::ptr_eq(m, q)))
+            .cloned()
+            .collect();
+        let n = queued.len();
+        v.extend(queued);
+        (v, n)
+    }
 }
 pub type HBroadCaster = Rc<RefCell<Option<Broadcaster>>>;
 
@@ -75,6 +298,10 @@ struct PeerHandleR(
     HBroadCaster,
     mpsc::Receiver<SailingBuffer>,
     BroadcastClientIndex,
+    /// Retained and queued messages not yet read by this client
+    VecDeque<SailingBuffer>,
+    /// Number of messages at the end of the above taken from the detached queue
+    usize,
 );
 struct InnerPeerReader(HBroadCaster, Vec<u8>);
 
@@ -85,17 +312,23 @@ impl Future for InnerPeerReader {
         loop {
             let mut meb = self.0.borrow_mut();
             let mut me = meb.as_mut().expect("Assertion failed 16293");
+            if me.clients.len() == 0 && me.detached.must_block() {
+                me.blocked_reader = Some(task::current());
+                return Ok(Async::NotReady);
+            }
             match me.inner_peer.0.read(&mut self.1[..]) {
                 Ok(0) => {
                     info!("Underlying peer finished");
                     return Ok(futures::Async::Ready(()));
                 }
                 Ok(n) => {
+                    let sb = Rc::new(self.1[0..n].to_vec());
+                    me.check_reconnect();
+                    me.retained.push(&sb);
                     if me.clients.len() == 0 {
-                        info!("Dropping broadcast due to no clients being connected");
+                        me.detached.push(&sb);
                         continue;
                     }
-                    let sb = Rc::new(self.1[0..n].to_vec());
                     for (_, client) in me.clients.iter_mut() {
                         match client.start_send(sb.clone()) {
                             Ok(AsyncSink::Ready) => match client.poll_complete() {
@@ -130,31 +363,35 @@ impl Future for InnerPeerReader {
 
 impl Drop for PeerHandleR {
     fn drop(&mut self) {
-        self.0
-            .borrow_mut()
-            .as_mut()
-            .expect("Assertion failed 16292")
-            .clients
-            .remove(self.2);
+        let mut b = self.0.borrow_mut();
+        let me = b.as_mut().expect("Assertion failed 16292");
+        me.clients.remove(self.2);
+        if me.clients.len() == 0 {
+            let unread = self.3.len().min(self.4);
+            let skip = self.3.len() - unread;
+            me.detached.give_back(self.3.drain(skip..).collect());
+        }
     }
 }
 
 impl Read for PeerHandleR {
     fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
         loop {
-            return match self.1.poll() {
-                Ok(Async::Ready(Some(v))) => {
-                    if v.len() > b.len() {
-                        error!("Too big message dropped");
-                        continue;
-                    }
-                    b[0..(v.len())].copy_from_slice(&v[..]);
-                    Ok(v.len())
-                }
-                Ok(Async::Ready(None)) => brokenpipe(),
-                Ok(Async::NotReady) => wouldblock(),
-                Err(()) => Err(simple_err("Something unexpected".into())),
+            let v = match self.3.pop_front() {
+                Some(v) => v,
+                None => match self.1.poll() {
+                    Ok(Async::Ready(Some(v))) => v,
+                    Ok(Async::Ready(None)) => return brokenpipe(),
+                    Ok(Async::NotReady) => return wouldblock(),
+                    Err(()) => return Err(simple_err("Something unexpected".into())),
+                },
             };
+            if v.len() > b.len() {
+                error!("Too big message dropped");
+                continue;
+            }
+            b[0..(v.len())].copy_from_slice(&v[..]);
+            return Ok(v.len());
         }
 
         /*if let &mut Some(ref mut x) = self.0.borrow_mut().deref_mut() {
@@ -197,13 +434,17 @@ impl AsyncWrite for PeerHandleW {
 fn makeclient(ps: HBroadCaster) -> Peer {
     let n = 1; // TODO: de-hardcode
     let (send, recv) = mpsc::channel(n);
-    let k = ps
-        .borrow_mut()
-        .as_mut()
-        .expect("Assertion failed 16291")
-        .clients
-        .insert(send);
-    let ph1 = PeerHandleR(ps.clone(), recv, k);
+    let (k, backlog, queued) = {
+        let mut b = ps.borrow_mut();
+        let me = b.as_mut().expect("Assertion failed 16291");
+        // Taken together with registering the client, so nothing is missed or delivered twice
+        let (backlog, queued) = me.backlog();
+        if let Some(t) = me.blocked_reader.take() {
+            t.notify();
+        }
+        (me.clients.insert(send), backlog, queued)
+    };
+    let ph1 = PeerHandleR(ps.clone(), recv, k, backlog, queued);
     let ph2 = PeerHandleW(ps);
     Peer::new(ph1, ph2)
 }
@@ -212,11 +453,15 @@ pub fn connection_reuser<F: FnOnce() -> BoxedNewPeerFuture>(
     h: &Handle,
     s: &mut GlobalState,
     inner_peer: F,
+    opts: &Options,
+    upstream_generation: Rc<Cell<u64>>,
 ) -> BoxedNewPeerFuture {
     let need_init = s.borrow().is_none();
 
     let rc = s.clone();
     let hh = h.clone();
+    let retained = Retained::new(opts);
+    let detached = DetachedQueue::new(opts);
     if need_init {
         info!("Initializing");
         Box::new(inner_peer().and_then(move |inner| {
@@ -226,6 +471,11 @@ pub fn connection_reuser<F: FnOnce() -> BoxedNewPeerFuture>(
                 *x = Some(Broadcaster {
                     inner_peer: inner,
                     clients: Clients::new(),
+                    retained,
+                    detached,
+                    upstream_generation,
+                    seen_generation: 0,
+                    blocked_reader: None,
                 });
                 hh.spawn(InnerPeerReader(rc.clone(), vec![0; 65536]));
             }
@@ -0,0 +1,60 @@
+//! Process-wide traffic budget (`--total-max-bytes`, `--total-max-messages`),
+//! counted across all sessions and reconnects
+
+use std::io::{Error as IoError, Read};
+use tokio_io::AsyncRead;
+
+use super::{metrics, shutdown, Options};
+
+pub fn enabled(opts: &Options) -> bool {
+    opts.total_max_bytes.is_some() || opts.total_max_messages.is_some()
+}
+
+/// Prefix of shutdown reasons that mean a budget was used up
+pub const BUDGET_REASON: &str = "budget reached";
+
+/// Reader that reports EOF once the process-wide budget is used up
+/// and requests graceful shutdown. Must wrap a `metrics::CountRead`.
+pub struct BudgetRead {
+    pub inner: Box<AsyncRead>,
+    pub max_bytes: Option<u64>,
+    pub max_messages: Option<u64>,
+}
+
+impl BudgetRead {
+    pub fn new(inner: Box<AsyncRead>, opts: &Options) -> BudgetRead {
+        BudgetRead {
+            inner,
+            max_bytes: opts.total_max_bytes,
+            max_messages: opts.total_max_messages,
+        }
+    }
+
+    /// Bytes left, or error message if nothing is left
+    fn left(&self) -> Result<Option<u64>, &'static str> {
+        let (bytes, messages) = metrics::totals();
+        if self.max_messages.map_or(false, |m| messages >= m) {
+            return Err("--total-max-messages");
+        }
+        match self.max_bytes {
+            Some(m) if bytes >= m => Err("--total-max-bytes"),
+            Some(m) => Ok(Some(m - bytes)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Read for BudgetRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let l = match self.left() {
+            Err(what) => {
+                shutdown::initiate(&format!("{} {}", what, BUDGET_REASON));
+                return Ok(0);
+            }
+            Ok(Some(left)) if (buf.len() as u64) > left => left as usize,
+            Ok(_) => buf.len(),
+        };
+        self.inner.read(&mut buf[..l])
+    }
+}
+impl AsyncRead for BudgetRead {}
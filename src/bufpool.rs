@@ -0,0 +1,194 @@
+//! Reusable buffers for the layers that turn one message into another
+//! (`msg2line:`, `line2msg:`, `jsonwrap:` with its base64, partial reads of long messages),
+//! so that high message rates don't end up allocating a fresh `Vec` per message.
+//!
+//! Payloads of received WebSocket messages are recycled into the pool once delivered.
+//!
+//! Each session gets its own pool. Buffers are taken from the pool of the session being polled
+//! and go back to it when dropped; outside of sessions `take` just allocates.
+//! A buffer is only ever owned by one `PooledBuf` (or `Vec`) at a time, it gets reused
+//! only after the message in it has been dropped.
+
+use futures::{Future, Poll};
+
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+use super::metrics;
+
+/// Buffers kept for reuse, per session
+pub const MAX_BUFFERS: usize = 8;
+/// Buffers that grew larger than this are freed instead of being kept
+pub const MAX_CAPACITY: usize = 256 * 1024;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers handed out
+    pub taken: u64,
+    /// ... of them not freshly allocated
+    pub reused: u64,
+    /// Buffers kept for reuse after being dropped
+    pub returned: u64,
+    /// Buffers freed because they were too large or the pool was full
+    pub discarded: u64,
+}
+
+struct PoolInner {
+    free: RefCell<Vec<Vec<u8>>>,
+    stats: Cell<PoolStats>,
+}
+
+impl Drop for PoolInner {
+    fn drop(&mut self) {
+        let s = self.stats.get();
+        if s.taken > 0 {
+            metrics::add("pooled_buffers_taken", s.taken);
+            debug!(
+                "Buffer pool: {} taken, {} reused, {} returned, {} discarded",
+                s.taken, s.reused, s.returned, s.discarded
+            );
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BufPool(Rc<PoolInner>);
+
+impl BufPool {
+    pub fn new() -> BufPool {
+        BufPool(Rc::new(PoolInner {
+            free: RefCell::new(Vec::with_capacity(MAX_BUFFERS)),
+            stats: Cell::new(Default::default()),
+        }))
+    }
+
+    /// An empty buffer with at least `capacity` bytes of room
+    pub fn take(&self, capacity: usize) -> PooledBuf {
+        let mut s = self.0.stats.get();
+        s.taken += 1;
+        let buf = match self.0.free.borrow_mut().pop() {
+            Some(mut b) => {
+                s.reused += 1;
+                b.reserve(capacity);
+                b
+            }
+            None => Vec::with_capacity(capacity),
+        };
+        self.0.stats.set(s);
+        PooledBuf {
+            buf,
+            pool: Some(self.clone()),
+        }
+    }
+
+    /// Keep `buf` for reuse, unless it is too large or there are enough buffers already
+    pub fn put(&self, mut buf: Vec<u8>) {
+        let mut s = self.0.stats.get();
+        let mut free = self.0.free.borrow_mut();
+        if buf.capacity() == 0 {
+            return;
+        }
+        if buf.capacity() > MAX_CAPACITY || free.len() >= MAX_BUFFERS {
+            s.discarded += 1;
+        } else {
+            buf.clear();
+            free.push(buf);
+            s.returned += 1;
+        }
+        self.0.stats.set(s);
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.0.stats.get()
+    }
+}
+
+impl Default for BufPool {
+    fn default() -> BufPool {
+        BufPool::new()
+    }
+}
+
+/// A buffer that goes back to its pool when dropped
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Option<BufPool>,
+}
+
+impl PooledBuf {
+    /// Take the buffer out of pool management. Use `recycle` to give it back later.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        mem::replace(&mut self.buf, vec![])
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(ref pool) = self.pool {
+            pool.put(mem::replace(&mut self.buf, vec![]));
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<BufPool>> = RefCell::new(None);
+}
+
+/// A buffer from the pool of the session being polled
+pub fn take(capacity: usize) -> PooledBuf {
+    match CURRENT.with(|x| x.borrow().clone()) {
+        Some(pool) => pool.take(capacity),
+        None => PooledBuf {
+            buf: Vec::with_capacity(capacity),
+            pool: None,
+        },
+    }
+}
+
+/// Give a buffer that is no longer needed to the pool of the session being polled
+pub fn recycle(buf: Vec<u8>) {
+    CURRENT.with(|x| {
+        if let Some(ref pool) = *x.borrow() {
+            pool.put(buf);
+        }
+    })
+}
+
+/// Future that has `pool` as the current one while being polled
+pub struct InPool<F> {
+    inner: F,
+    pool: BufPool,
+}
+
+impl<F: Future> InPool<F> {
+    pub fn new(inner: F, pool: BufPool) -> InPool<F> {
+        InPool { inner, pool }
+    }
+}
+
+impl<F: Future> Future for InPool<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let prev = CURRENT.with(|x| mem::replace(&mut *x.borrow_mut(), Some(self.pool.clone())));
+        let ret = self.inner.poll();
+        CURRENT.with(|x| *x.borrow_mut() = prev);
+        ret
+    }
+}
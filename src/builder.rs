@@ -0,0 +1,369 @@
+//! Entry point for running websocat from another Rust program.
+//!
+//! ```no_run
+//! extern crate tokio_core;
+//! extern crate websocat;
+//!
+//! use websocat::builder::WebsocatBuilder;
+//!
+//! fn main() {
+//!     let mut core = tokio_core::reactor::Core::new().unwrap();
+//!     let prog = WebsocatBuilder::new()
+//!         .left("ws-l:127.0.0.1:8080")
+//!         .right("autoreconnect:tcp:127.0.0.1:5678")
+//!         .websocket_text_mode(true)
+//!         .run(&core.handle());
+//!     core.run(prog).unwrap();
+//! }
+//! ```
+//!
+//! The `websocat` binary goes through it as well, after turning command line into `Options`.
+
+use futures::future::{self, Future};
+use futures::sync::mpsc;
+use futures::Stream;
+use tokio_core::reactor::Handle;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::lifecycle::SessionCallbacks;
+use super::lints::Diagnostic;
+use super::error::WebsocatError;
+use super::middleware::{self, Message, MiddlewareAction};
+use super::shutdown::Scope;
+use super::{spec, Options, Specifier, WebsocatConfiguration};
+
+/// Why a websocat run could not start or did not succeed
+#[derive(Debug)]
+pub enum Error {
+    /// A specifier string did not parse
+    Specifier(Box<::std::error::Error>),
+    /// Left or right specifier is not set
+    MissingSpecifier,
+    /// Specifiers and options don't work together
+    Configuration(String),
+    /// Both specifiers are stdio: there is nothing to serve, stdin could just be copied to stdout
+    StdinToStdout,
+    /// Both directions are inhibited, nothing to do
+    NothingToDo,
+    /// A session failed. Only the first failure is reported.
+    Session(Box<::std::error::Error>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Specifier(ref e) => write!(f, "{}", e),
+            Error::MissingSpecifier => write!(f, "Both left and right specifiers are required"),
+            Error::Configuration(ref x) => write!(f, "{}", x),
+            Error::StdinToStdout => write!(f, "Both specifiers are stdio, stdin would just be copied to stdout"),
+            Error::NothingToDo => write!(f, "Both directions are inhibited, nothing to do"),
+            Error::Session(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error {
+    /// Structured reason of a failed specifier or session, if there is one
+    pub fn websocat_error(&self) -> Option<&WebsocatError> {
+        match *self {
+            Error::Specifier(ref e) | Error::Session(ref e) => super::error::find(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn description(&self) -> &str {
+        "websocat error"
+    }
+}
+
+enum SpecInput {
+    Str(String),
+    Parsed(Rc<Specifier>),
+}
+
+impl SpecInput {
+    fn resolve(self) -> Result<Rc<Specifier>, Error> {
+        match self {
+            SpecInput::Str(x) => spec(&x).map_err(Error::Specifier),
+            SpecInput::Parsed(x) => Ok(x),
+        }
+    }
+}
+
+/// How a cancellable run ended
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+    /// Served everything there was to serve
+    Finished,
+    /// Stopped by `CancelHandle`
+    Cancelled,
+}
+
+/// Stops a run started with `WebsocatBuilder::run_cancellable`.
+/// Can be cloned and sent to other threads; cancelling more than once,
+/// or after the run has ended, does nothing.
+#[derive(Clone)]
+pub struct CancelHandle(mpsc::UnboundedSender<bool>);
+
+impl CancelHandle {
+    /// Stop accepting new connections and wait for sessions in progress
+    /// up to `Options::drain_timeout` seconds
+    pub fn cancel(&self) {
+        let _ = self.0.unbounded_send(false);
+    }
+    /// Like `cancel`, also closing sessions in progress the way SIGTERM does,
+    /// with `Options::shutdown_close_code` (1001 if unset)
+    pub fn cancel_and_close(&self) {
+        let _ = self.0.unbounded_send(true);
+    }
+}
+
+type SessionErrorHandler = Rc<Fn(Box<::std::error::Error>)>;
+
+/// Two specifiers and options, ready to be served on a tokio `Handle`
+pub struct WebsocatBuilder {
+    s1: Option<SpecInput>,
+    s2: Option<SpecInput>,
+    opts: Options,
+    linemode: bool,
+    on_warning: Rc<Fn(&Diagnostic)>,
+    on_session_error: Option<SessionErrorHandler>,
+}
+
+macro_rules! setters {
+    ($($(#[$m:meta])* $name:ident : $t:ty,)*) => {
+        $(
+            $(#[$m])*
+            pub fn $name(mut self, v: $t) -> Self {
+                self.opts.$name = v;
+                self
+            }
+        )*
+    };
+}
+
+impl Default for WebsocatBuilder {
+    fn default() -> Self {
+        WebsocatBuilder::new()
+    }
+}
+
+impl WebsocatBuilder {
+    pub fn new() -> Self {
+        WebsocatBuilder {
+            s1: None,
+            s2: None,
+            opts: Default::default(),
+            linemode: false,
+            on_warning: Rc::new(|x: &Diagnostic| warn!("{}", x)),
+            on_session_error: None,
+        }
+    }
+
+    /// Left specifier, like `ws-l:127.0.0.1:8080` or `-`
+    pub fn left(mut self, s: &str) -> Self {
+        self.s1 = Some(SpecInput::Str(s.to_string()));
+        self
+    }
+    /// Right specifier, like `ws://127.0.0.1:8080/`
+    pub fn right(mut self, s: &str) -> Self {
+        self.s2 = Some(SpecInput::Str(s.to_string()));
+        self
+    }
+    pub fn left_spec(mut self, s: Rc<Specifier>) -> Self {
+        self.s1 = Some(SpecInput::Parsed(s));
+        self
+    }
+    pub fn right_spec(mut self, s: Rc<Specifier>) -> Self {
+        self.s2 = Some(SpecInput::Parsed(s));
+        self
+    }
+
+    /// Replace all options at once
+    pub fn options(mut self, opts: Options) -> Self {
+        self.opts = opts;
+        self
+    }
+    /// Change options not covered by the setters below
+    pub fn configure<F: FnOnce(&mut Options)>(mut self, f: F) -> Self {
+        f(&mut self.opts);
+        self
+    }
+
+    setters!(
+        websocket_text_mode: bool,
+        websocket_protocol: Option<String>,
+        unidirectional: bool,
+        unidirectional_reverse: bool,
+        exit_on_eof: bool,
+        oneshot: bool,
+        origin: Option<String>,
+        websocket_dont_close: bool,
+        one_message: bool,
+        close_timeout: Option<u64>,
+        idle_timeout: Option<u64>,
+        max_messages_in: Option<u64>,
+        max_messages_out: Option<u64>,
+        max_sessions: Option<u64>,
+    );
+
+    /// Add a request header for WebSocket clients
+    pub fn header(mut self, name: &str, value: &[u8]) -> Self {
+        self.opts.custom_headers.push((name.to_string(), value.to_vec()));
+        self
+    }
+
+    /// Get notified about sessions starting and ending, see `lifecycle` for what callbacks may do
+    pub fn callbacks(mut self, cb: Rc<SessionCallbacks>) -> Self {
+        self.opts.callbacks.0.push(cb);
+        self
+    }
+
+    /// Inspect, rewrite or drop each message coming from the right specifier, see `middleware`.
+    /// Can be called more than once; hooks run in order.
+    pub fn map_incoming<F: FnMut(Message) -> MiddlewareAction + 'static>(mut self, f: F) -> Self {
+        self.opts.middleware.incoming.push(middleware::hook(f));
+        self
+    }
+    /// Like `map_incoming`, for messages sent to the right specifier
+    pub fn map_outgoing<F: FnMut(Message) -> MiddlewareAction + 'static>(mut self, f: F) -> Self {
+        self.opts.middleware.outgoing.push(middleware::hook(f));
+        self
+    }
+
+    /// Insert `msg2line:`/`line2msg:` next to the WebSocket specifier, like `--line`
+    pub fn linemode(mut self, v: bool) -> Self {
+        self.linemode = v;
+        self
+    }
+
+    /// Where warnings about the configuration go. Logged by default.
+    pub fn on_warning<F: Fn(&Diagnostic) + 'static>(mut self, f: F) -> Self {
+        self.on_warning = Rc::new(f);
+        self
+    }
+
+    /// Handle failed sessions here instead of failing the whole run with the first one.
+    /// Listeners keep serving regardless.
+    pub fn on_session_error<F: Fn(Box<::std::error::Error>) + 'static>(mut self, f: F) -> Self {
+        self.on_session_error = Some(Rc::new(f));
+        self
+    }
+
+    /// Parse specifiers and sort out what the command line would have complained about
+    /// or adjusted automatically
+    pub fn build(self) -> Result<WebsocatConfiguration, Error> {
+        let s1 = self.s1.ok_or(Error::MissingSpecifier)?.resolve()?;
+        let s2 = self.s2.ok_or(Error::MissingSpecifier)?.resolve()?;
+        let mut websocat = WebsocatConfiguration {
+            opts: self.opts,
+            s1,
+            s2,
+        };
+        if self.linemode {
+            websocat = websocat
+                .auto_install_linemode()
+                .map_err(|(c, _)| Error::Configuration(c.message().to_string()))?;
+        }
+        for d in websocat.option_diagnostics() {
+            (self.on_warning)(&d);
+        }
+        while let Some(concern) = websocat.get_concern() {
+            use lints::ConfigurationConcern::*;
+            match concern {
+                StdinToStdout => return Err(Error::StdinToStdout),
+                DegenerateMode => return Err(Error::NothingToDo),
+                NeedsStdioReuser => {
+                    if let Some(d) = concern.diagnostic() {
+                        (self.on_warning)(&d);
+                    }
+                    websocat = websocat.auto_install_reuser();
+                }
+                NeedsStdioReuser2 => websocat = websocat.auto_install_reuser(),
+                MultipleReusers => {
+                    info!("Specifier dump: {:?} {:?}", websocat.s1, websocat.s2);
+                    return Err(Error::Configuration("Multiple reusers is not allowed".to_string()));
+                }
+                StdioConflict | TimestampsInBinaryMode | WorkersWithoutTcpListener | WorkersWithSharedState => {
+                    let message = concern.diagnostic().map_or(String::new(), |d| d.message);
+                    return Err(Error::Configuration(message));
+                }
+            }
+        }
+        Ok(websocat)
+    }
+
+    /// Future that resolves when the session ends, or, for listening specifiers, when serving stops.
+    /// Fails with the first failed session unless `on_session_error` is set.
+    pub fn run(self, h: &Handle) -> Box<Future<Item = (), Error = Error>> {
+        let on_session_error = self.on_session_error.clone();
+        let websocat = match self.build() {
+            Ok(x) => x,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let failure = Rc::new(RefCell::new(None));
+        let failure2 = failure.clone();
+        let prog = websocat.serve(
+            h.clone(),
+            Rc::new(move |e: Box<::std::error::Error>| match on_session_error {
+                Some(ref f) => f(e),
+                None => {
+                    let mut f = failure2.borrow_mut();
+                    if f.is_none() {
+                        *f = Some(e);
+                    }
+                }
+            }),
+        );
+        Box::new(prog.then(move |r| {
+            match (failure.borrow_mut().take(), r) {
+                (Some(e), _) => Err(Error::Session(e)),
+                (None, Err(())) => Err(Error::Session("serving failed".into())),
+                (None, Ok(())) => Ok(()),
+            }
+        }))
+    }
+
+    /// Like `run`, but can be stopped from outside.
+    /// The future resolves with `Outcome::Cancelled` once cancelled sessions are done with.
+    pub fn run_cancellable(
+        mut self,
+        h: &Handle,
+    ) -> (Box<Future<Item = Outcome, Error = Error>>, CancelHandle) {
+        let scope = Scope::default();
+        self.opts.shutdown_scope = Some(scope.clone());
+        let drain_timeout = Duration::from_secs(self.opts.drain_timeout);
+        let close_code = match self.opts.shutdown_close_code {
+            0 => 1001,
+            x => x,
+        };
+        let (tx, rx) = mpsc::unbounded();
+        let scope2 = scope.clone();
+        // Ends when all handles are dropped. `cancel_and_close` after `cancel` still closes sessions.
+        h.spawn(rx.for_each(move |close| {
+            if close {
+                scope2.initiate_and_close_sessions("cancelled", close_code);
+            } else {
+                scope2.initiate("cancelled");
+            }
+            Ok(())
+        }));
+        let h = h.clone();
+        let prog = self.run(&h).then(move |r| -> Box<Future<Item = Outcome, Error = Error>> {
+            if scope.reason().is_none() {
+                return Box::new(future::result(r.map(|()| Outcome::Finished)));
+            }
+            if let Err(e) = r {
+                debug!("Error while cancelled: {}", e);
+            }
+            Box::new(scope.drained(&h, drain_timeout).then(|_| Ok(Outcome::Cancelled)))
+        });
+        (Box::new(prog), CancelHandle(tx))
+    }
+}
@@ -0,0 +1,106 @@
+//! `--list-features`: what this particular build can do, for tools driving websocat
+
+extern crate serde_json;
+
+use std::io::{Error as IoError, Write};
+
+use self::serde_json::{Map, Value};
+
+use super::specparse::{specifier_classes, ArgKind};
+
+/// Cargo features this binary was built with
+pub fn enabled_features() -> Vec<&'static str> {
+    let mut r = vec![];
+    macro_rules! f {
+        ($($name:tt),*) => {
+            $(
+                if cfg!(feature = $name) {
+                    r.push($name);
+                }
+            )*
+        };
+    }
+    f!(
+        "ssl",
+        "signal_handler",
+        "tokio-process",
+        "unix_stdio",
+        "regex",
+        "workaround1",
+        "seqpacket",
+        "pty",
+        "readline"
+    );
+    r
+}
+
+fn arg_name(a: ArgKind) -> &'static str {
+    match a {
+        ArgKind::NoArg => "none",
+        ArgKind::Subspecifier => "specifier",
+        ArgKind::Path => "path",
+        ArgKind::Other => "other",
+    }
+}
+
+/// First line of `--long-help` text of a specifier
+fn summary(help: &str) -> &str {
+    help.lines().map(|x| x.trim()).find(|x| !x.is_empty()).unwrap_or("")
+}
+
+/// A command line option and its type: `flag`, `value` or `values` (may be repeated)
+pub type OptionType = (String, &'static str);
+
+/// The whole listing as a JSON document.
+/// Keys: `version`, `features`, `specifiers` (`name`, `prefixes`, `needs_argument`,
+/// `argument`, `help`) and `options` (`name`, `type`).
+pub fn to_json(options: &[OptionType]) -> String {
+    let mut o = Map::new();
+    o.insert("version".into(), env!("CARGO_PKG_VERSION").into());
+    o.insert(
+        "features".into(),
+        Value::Array(enabled_features().into_iter().map(Value::from).collect()),
+    );
+    let specifiers = specifier_classes()
+        .into_iter()
+        .map(|c| {
+            let mut s = Map::new();
+            s.insert("name".into(), c.name.into());
+            s.insert(
+                "prefixes".into(),
+                Value::Array(c.prefixes.into_iter().map(Value::from).collect()),
+            );
+            s.insert("needs_argument".into(), (c.arg != ArgKind::NoArg).into());
+            s.insert("argument".into(), arg_name(c.arg).into());
+            s.insert("help".into(), summary(c.help).into());
+            Value::Object(s)
+        })
+        .collect();
+    o.insert("specifiers".into(), Value::Array(specifiers));
+    let options = options
+        .iter()
+        .map(|&(ref name, typ)| {
+            let mut x = Map::new();
+            x.insert("name".into(), name.as_str().into());
+            x.insert("type".into(), typ.into());
+            Value::Object(x)
+        })
+        .collect();
+    o.insert("options".into(), Value::Array(options));
+    Value::Object(o).to_string()
+}
+
+/// Human-readable listing
+pub fn write_text<W: Write>(options: &[OptionType], w: &mut W) -> Result<(), IoError> {
+    writeln!(w, "websocat {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(w, "Features: {}", enabled_features().join(" "))?;
+    writeln!(w, "Specifiers:")?;
+    for c in specifier_classes() {
+        writeln!(w, "  {:40} {}", c.prefixes.join(" "), arg_name(c.arg))?;
+    }
+    writeln!(w, "Options:")?;
+    for &(ref name, typ) in options {
+        writeln!(w, "  {:40} {}", name, typ)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,294 @@
+use futures;
+use futures::future::ok;
+
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::{peer_strerr, simple_err, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, ReadDebt, Specifier};
+
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct Chunk<T: Specifier>(pub T, pub usize);
+impl<T: Specifier> Specifier for Chunk<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let size = self.1;
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| chunk_peer(p, size, &opts))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = ChunkClass,
+    target = Chunk,
+    prefixes = ["chunk:"],
+    arg_handling = {
+        fn construct(
+            self: &ChunkClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            let (size, sub) = match just_arg.find(':') {
+                Some(i) => (&just_arg[..i], &just_arg[i + 1..]),
+                None => Err("Expected chunk:<size>:<subspecifier>")?,
+            };
+            let size: usize = size.parse()?;
+            if size == 0 {
+                Err("chunk: size must be positive")?
+            }
+            Ok(Rc::new(Chunk(super::spec(sub)?, size)))
+        }
+    },
+    consumed_options = &["--chunk-header"],
+    help = r#"
+Split each message written to the subspecifier into consecutive messages
+of at most the given number of bytes. Reads are passed through unchanged.
+
+With --chunk-header seq/total, each piece is prefixed by a header like `2/3:`
+(1-based index, number of pieces, colon), counted towards the size limit.
+Empty messages then become a single header-only piece; without a header
+they are not sent at all.
+
+Example: pass 100 KiB blobs to a consumer accepting only 1 KiB messages
+
+    websocat ws-l:127.0.0.1:8080 chunk:1024:ws://127.0.0.1:5678
+"#
+);
+
+#[derive(Debug)]
+pub struct Unchunk<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Unchunk<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| unchunk_peer(p, &opts))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = UnchunkClass,
+    target = Unchunk,
+    prefixes = ["unchunk:"],
+    arg_handling = subspec,
+    consumed_options = &["--chunk-header"],
+    help = r#"
+Reassemble messages read from the subspecifier that were split by
+`chunk:` with --chunk-header seq/total. Writes are passed through unchanged.
+
+Reassembled messages bigger than --buffer-size are delivered in parts.
+
+Example: two websocat instances cooperating across a hop limited to 1 KiB messages
+
+    websocat --chunk-header seq/total ws-l:127.0.0.1:8080 chunk:1024:ws://hop:1234
+    websocat --chunk-header seq/total unchunk:ws-l:0.0.0.0:1234 tcp:127.0.0.1:5678
+
+Use `chunk:1024:unchunk:...` to split and reassemble in both directions.
+"#
+);
+
+/// Interpret `--chunk-header`: whether `seq/total:` headers are used
+pub fn uses_chunk_header(s: &str) -> Option<bool> {
+    match s {
+        "" | "none" => Some(false),
+        "seq/total" => Some(true),
+        _ => None,
+    }
+}
+
+fn digits(mut x: usize) -> usize {
+    let mut d = 1;
+    while x >= 10 {
+        x /= 10;
+        d += 1;
+    }
+    d
+}
+
+/// Cut the message into pieces of at most `size` bytes, including headers
+fn split_message(msg: &[u8], size: usize, header: bool) -> Result<Vec<Vec<u8>>, IoError> {
+    if !header {
+        return Ok(msg.chunks(size).map(|x| x.to_vec()).collect());
+    }
+    // Header length depends on the number of pieces and vice versa
+    let mut total = 1;
+    let payload = loop {
+        // `seq` never has more digits than `total`
+        let hl = 2 * digits(total) + 2;
+        if hl >= size {
+            return Err(simple_err(format!(
+                "chunk: size {} is too small to fit the header",
+                size
+            )));
+        }
+        let payload = size - hl;
+        let t = ((msg.len() + payload - 1) / payload).max(1);
+        if digits(t) == digits(total) {
+            total = t;
+            break payload;
+        }
+        total = t;
+    };
+    let mk = |i: usize, piece: &[u8]| {
+        let mut v = format!("{}/{}:", i + 1, total).into_bytes();
+        v.extend_from_slice(piece);
+        v
+    };
+    if msg.is_empty() {
+        return Ok(vec![mk(0, b"")]);
+    }
+    Ok(msg.chunks(payload)
+        .enumerate()
+        .map(|(i, x)| mk(i, x))
+        .collect())
+}
+
+/// Parse `seq/total:` header, returning seq, total and the header length
+fn parse_header(msg: &[u8]) -> Option<(usize, usize, usize)> {
+    let colon = msg.iter().take(42).position(|&x| x == b':')?;
+    let h = ::std::str::from_utf8(&msg[..colon]).ok()?;
+    let mut it = h.splitn(2, '/');
+    let seq: usize = it.next()?.parse().ok()?;
+    let total: usize = it.next()?.parse().ok()?;
+    if seq == 0 || seq > total {
+        return None;
+    }
+    Some((seq, total, colon + 1))
+}
+
+pub fn chunk_peer(inner_peer: Peer, size: usize, opts: &Options) -> BoxedNewPeerFuture {
+    let header = match uses_chunk_header(&opts.chunk_header) {
+        Some(x) => x,
+        None => return peer_strerr("--chunk-header must be `none` or `seq/total`"),
+    };
+    let w = ChunkWrite {
+        inner: inner_peer.1,
+        size,
+        header,
+        pending: None,
+        pos: 0,
+    };
+    Box::new(ok(Peer::new(inner_peer.0, w))) as BoxedNewPeerFuture
+}
+
+pub fn unchunk_peer(inner_peer: Peer, opts: &Options) -> BoxedNewPeerFuture {
+    if uses_chunk_header(&opts.chunk_header) != Some(true) {
+        return peer_strerr("unchunk: requires --chunk-header seq/total");
+    }
+    let r = UnchunkRead {
+        inner: inner_peer.0,
+        assembled: vec![],
+        next: 1,
+        debt: ReadDebt(None),
+    };
+    Box::new(ok(Peer::new(r, inner_peer.1))) as BoxedNewPeerFuture
+}
+
+struct ChunkWrite {
+    inner: Box<AsyncWrite>,
+    size: usize,
+    header: bool,
+    /// Pieces of the current message not yet written
+    pending: Option<VecDeque<Vec<u8>>>,
+    /// How much of the first pending piece is already written
+    pos: usize,
+}
+
+impl Write for ChunkWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if self.pending.is_none() {
+            self.pending = Some(split_message(buf, self.size, self.header)?.into());
+            self.pos = 0;
+        }
+        // On `WouldBlock` the same message is retried, so we continue from where we stopped
+        loop {
+            let pieces = self.pending.as_mut().unwrap();
+            let len = match pieces.front() {
+                None => break,
+                Some(piece) => {
+                    let n = self.inner.write(&piece[self.pos..])?;
+                    if n == 0 {
+                        return Err(IoError::new(ErrorKind::WriteZero, "chunk: failed to write a piece"));
+                    }
+                    self.pos += n;
+                    piece.len()
+                }
+            };
+            if self.pos == len {
+                pieces.pop_front();
+                self.pos = 0;
+            }
+        }
+        self.pending = None;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for ChunkWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
+
+struct UnchunkRead {
+    inner: Box<AsyncRead>,
+    assembled: Vec<u8>,
+    /// Expected sequence number of the next piece
+    next: usize,
+    debt: ReadDebt,
+}
+
+impl Read for UnchunkRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        loop {
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                if !self.assembled.is_empty() || self.next != 1 {
+                    warn!(
+                        "unchunk: throwing away {} bytes of incomplete message",
+                        self.assembled.len()
+                    );
+                }
+                return Ok(0);
+            }
+            let (seq, total, hl) = match parse_header(&buf[..n]) {
+                Some(x) => x,
+                None => return Err(simple_err("unchunk: invalid chunk header".to_string())),
+            };
+            if seq != self.next {
+                return Err(simple_err(format!(
+                    "unchunk: expected piece {}, got {}/{}",
+                    self.next, seq, total
+                )));
+            }
+            if seq < total {
+                self.assembled.extend_from_slice(&buf[hl..n]);
+                self.next += 1;
+                continue;
+            }
+            self.next = 1;
+            if self.assembled.is_empty() {
+                // Single piece: just strip the header
+                if n == hl {
+                    debug!("unchunk: skipping empty message");
+                    continue;
+                }
+                let msg = buf[hl..n].to_vec();
+                return self.debt.process_message(buf, &msg);
+            }
+            self.assembled.extend_from_slice(&buf[hl..n]);
+            let msg = ::std::mem::replace(&mut self.assembled, vec![]);
+            return self.debt.process_message(buf, &msg);
+        }
+    }
+}
+impl AsyncRead for UnchunkRead {}
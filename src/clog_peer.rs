@@ -0,0 +1,178 @@
+use futures;
+use futures::future::ok;
+use futures::Async::NotReady;
+use futures::Future;
+
+use std::time::Duration;
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::{peer_strerr, wouldblock, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct Clog<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Clog<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let h = cp.tokio_handle.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| clog_peer(p, &opts, &h))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = ClogClass,
+    target = Clog,
+    prefixes = ["clog:"],
+    arg_handling = subspec,
+    consumed_options = &["--clog-direction", "--clog-after-bytes", "--clog-duration"],
+    help = r#"
+Stall reading from and/or writing to the subspecifier, to test backpressure handling.
+
+Options: --clog-direction read|write|both (default both),
+--clog-after-bytes <n> (let n bytes through before stalling, default 0),
+--clog-duration <secs> (resume after that time; stall forever if not set).
+Each direction counts its bytes and keeps its own timer.
+
+Example: reads proceed, writes hang for 30 seconds after first kilobyte, then resume
+
+    websocat --clog-direction write --clog-after-bytes 1024 --clog-duration 30 ws-l:127.0.0.1:8080 clog:tcp:127.0.0.1:5678
+"#
+);
+
+/// Interpret `read`, `write` or `both` (also empty string) as (read, write) flags
+pub fn parse_clog_direction(s: &str) -> Option<(bool, bool)> {
+    match s {
+        "" | "both" => Some((true, true)),
+        "read" => Some((true, false)),
+        "write" => Some((false, true)),
+        _ => None,
+    }
+}
+
+enum State {
+    Flowing { left: u64 },
+    Clogged(Option<Timeout>),
+    Unclogged,
+}
+
+/// Clogging state machine for one direction
+struct Valve {
+    state: State,
+    duration: Option<Duration>,
+    handle: Handle,
+}
+
+impl Valve {
+    fn new(enabled: bool, opts: &Options, h: &Handle) -> Valve {
+        Valve {
+            state: if enabled {
+                State::Flowing {
+                    left: opts.clog_after_bytes,
+                }
+            } else {
+                State::Unclogged
+            },
+            duration: opts.clog_duration.map(Duration::from_secs),
+            handle: h.clone(),
+        }
+    }
+
+    /// How many bytes may pass now. `WouldBlock` while clogged;
+    /// the current task gets notified when the clog is over.
+    fn allowance(&mut self, want: usize) -> Result<usize, IoError> {
+        loop {
+            let next = match self.state {
+                State::Unclogged => return Ok(want),
+                State::Flowing { left } if left > 0 => {
+                    return Ok(if (want as u64) < left {
+                        want
+                    } else {
+                        left as usize
+                    })
+                }
+                State::Flowing { .. } => {
+                    info!("clog: stalling");
+                    match self.duration {
+                        Some(d) => State::Clogged(Some(Timeout::new(d, &self.handle)?)),
+                        // Nobody will wake us up
+                        None => State::Clogged(None),
+                    }
+                }
+                State::Clogged(None) => return wouldblock(),
+                State::Clogged(Some(ref mut t)) => {
+                    if let NotReady = t.poll()? {
+                        return wouldblock();
+                    }
+                    info!("clog: resuming");
+                    State::Unclogged
+                }
+            };
+            self.state = next;
+        }
+    }
+
+    fn passed(&mut self, n: usize) {
+        if let State::Flowing { ref mut left } = self.state {
+            *left -= n as u64;
+        }
+    }
+}
+
+pub fn clog_peer(inner_peer: Peer, opts: &Options, h: &Handle) -> BoxedNewPeerFuture {
+    let (cr, cw) = match parse_clog_direction(&opts.clog_direction) {
+        Some(x) => x,
+        None => return peer_strerr("--clog-direction must be `read`, `write` or `both`"),
+    };
+    let r = ClogRead {
+        inner: inner_peer.0,
+        valve: Valve::new(cr, opts, h),
+    };
+    let w = ClogWrite {
+        inner: inner_peer.1,
+        valve: Valve::new(cw, opts, h),
+    };
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+struct ClogRead {
+    inner: Box<AsyncRead>,
+    valve: Valve,
+}
+
+impl Read for ClogRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let l = self.valve.allowance(buf.len())?;
+        let n = self.inner.read(&mut buf[..l])?;
+        self.valve.passed(n);
+        Ok(n)
+    }
+}
+impl AsyncRead for ClogRead {}
+
+struct ClogWrite {
+    inner: Box<AsyncWrite>,
+    valve: Valve,
+}
+
+impl Write for ClogWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let l = self.valve.allowance(buf.len())?;
+        let n = self.inner.write(&buf[..l])?;
+        self.valve.passed(n);
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for ClogWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,221 @@
+//! `--completions`: shell completion scripts that also know specifier prefixes.
+//!
+//! Option names are completed by the script clap generates. This adds a wrapper that
+//! completes positional arguments from the specifier class registry instead,
+//! descending into overlays like `reuse:log:` and completing file names after `readfile:` and such.
+
+use super::specparse::{specifier_classes, ArgKind};
+
+struct Prefixes {
+    all: Vec<&'static str>,
+    overlays: Vec<&'static str>,
+    paths: Vec<&'static str>,
+}
+
+fn prefixes() -> Prefixes {
+    let mut p = Prefixes {
+        all: vec![],
+        overlays: vec![],
+        paths: vec![],
+    };
+    for c in specifier_classes() {
+        for x in c.prefixes {
+            if p.all.contains(&x) {
+                continue;
+            }
+            p.all.push(x);
+            match c.arg {
+                ArgKind::Subspecifier => p.overlays.push(x),
+                ArgKind::Path => p.paths.push(x),
+                _ => (),
+            }
+        }
+    }
+    p
+}
+
+fn words(x: &[&str]) -> String {
+    x.iter().map(|w| format!("'{}'", w)).collect::<Vec<_>>().join(" ")
+}
+
+fn owned_words(x: &[String]) -> String {
+    x.iter().map(|w| format!("'{}'", w)).collect::<Vec<_>>().join(" ")
+}
+
+/// Script for `shell` (`bash`, `zsh` or `fish`), given what clap generated for it
+/// and the options that take a value (after which there is no specifier to complete)
+pub fn completion_script(
+    shell: &str,
+    clap_script: &str,
+    value_options: &[String],
+) -> Result<String, String> {
+    let p = prefixes();
+    let (all, overlays, paths) = (words(&p.all), words(&p.overlays), words(&p.paths));
+    let value_options = owned_words(value_options);
+    match shell {
+        "bash" => Ok(format!(
+            r#"{clap}
+_websocat_specifier() {{
+    local cur="$1" head="" p f again=1
+    local overlays=({overlays})
+    local paths=({paths})
+    local all=({all})
+    while [ -n "$again" ]; do
+        again=
+        for p in "${{overlays[@]}}"; do
+            if [[ "$cur" == "$p"* ]]; then
+                head="$head$p"; cur="${{cur#"$p"}}"; again=1; break
+            fi
+        done
+    done
+    for p in "${{paths[@]}}"; do
+        if [[ "$cur" == "$p"* ]]; then
+            while IFS= read -r f; do
+                COMPREPLY+=("$head$p$f")
+            done < <(compgen -f -- "${{cur#"$p"}}")
+            return
+        fi
+    done
+    for p in "${{all[@]}}"; do
+        if [[ "$p" == "$cur"* ]]; then
+            COMPREPLY+=("$head$p")
+        fi
+    done
+}}
+
+_websocat_full() {{
+    local value_opts=({value_options})
+    local line="${{COMP_LINE:0:COMP_POINT}}"
+    local cur="${{line##*[[:space:]]}}"
+    local before="${{line%"$cur"}}"
+    before="${{before%"${{before##*[![:space:]]}}"}}"
+    local prev="${{before##*[[:space:]]}}" o
+    if [[ "$cur" != -* ]]; then
+        for o in "${{value_opts[@]}}"; do
+            [[ "$prev" == "$o" ]] && {{ _websocat "$@"; return; }}
+        done
+        COMPREPLY=()
+        _websocat_specifier "$cur"
+        # Colons are word breaks for bash
+        local colon_word="${{cur%"${{cur##*:}}"}}"
+        COMPREPLY=("${{COMPREPLY[@]#"$colon_word"}}")
+        compopt -o nospace
+        return 0
+    fi
+    _websocat "$@"
+}}
+
+complete -F _websocat_full -o bashdefault -o default websocat
+"#,
+            clap = clap_script,
+            overlays = overlays,
+            paths = paths,
+            all = all,
+            value_options = value_options,
+        )),
+        "zsh" => {
+            // clap's script ends with calling its function, which should be ours instead
+            let clap = clap_script.trim_right();
+            let clap = if clap.ends_with(r#"_websocat "$@""#) {
+                &clap[..clap.len() - r#"_websocat "$@""#.len()]
+            } else {
+                clap
+            };
+            Ok(format!(
+                r#"{clap}
+_websocat_specifier() {{
+    local cur="$PREFIX" head="" p again=1
+    local -a overlays paths all
+    overlays=({overlays})
+    paths=({paths})
+    all=({all})
+    while (( again )); do
+        again=0
+        for p in $overlays; do
+            if [[ "$cur" == "$p"* ]]; then
+                head="$head$p"; cur="${{cur#"$p"}}"; again=1; break
+            fi
+        done
+    done
+    for p in $paths; do
+        if [[ "$cur" == "$p"* ]]; then
+            IPREFIX="$IPREFIX$head$p"
+            PREFIX="${{cur#"$p"}}"
+            _files
+            return
+        fi
+    done
+    IPREFIX="$IPREFIX$head"
+    PREFIX="$cur"
+    compadd -S '' -- $all
+}}
+
+_websocat_full() {{
+    local -a value_opts
+    value_opts=({value_options})
+    if [[ "$PREFIX" != -* && ${{value_opts[(Ie)${{words[CURRENT-1]}}]}} -eq 0 ]]; then
+        _websocat_specifier
+    else
+        _websocat "$@"
+    fi
+}}
+
+_websocat_full "$@"
+"#,
+                clap = clap,
+                overlays = overlays,
+                paths = paths,
+                all = all,
+                value_options = value_options,
+            ))
+        }
+        "fish" => Ok(format!(
+            r#"{clap}
+function __websocat_specifier
+    set -l cur (commandline -ct)
+    set -l head ''
+    set -l overlays {overlays}
+    set -l paths {paths}
+    set -l all {all}
+    set -l again 1
+    while test -n "$again"
+        set again ''
+        for p in $overlays
+            if string match -q -- "$p*" "$cur"
+                set head "$head$p"
+                set cur (string sub -s (math (string length -- "$p") + 1) -- "$cur")
+                set again 1
+                break
+            end
+        end
+    end
+    for p in $paths
+        if string match -q -- "$p*" "$cur"
+            for f in (__fish_complete_path (string sub -s (math (string length -- "$p") + 1) -- "$cur"))
+                echo "$head$p$f"
+            end
+            return
+        end
+    end
+    for p in $all
+        echo "$head$p"
+    end
+end
+
+function __websocat_wants_specifier
+    set -l value_opts {value_options}
+    set -l prev (commandline -opc)[-1]
+    not string match -q -- '-*' (commandline -ct); and not contains -- "$prev" $value_opts
+end
+
+complete -c websocat -f -n __websocat_wants_specifier -a '(__websocat_specifier)'
+"#,
+            clap = clap_script,
+            overlays = overlays,
+            paths = paths,
+            all = all,
+            value_options = value_options,
+        )),
+        _ => Err("--completions supports `bash`, `zsh` and `fish`".to_string()),
+    }
+}
@@ -0,0 +1,50 @@
+//! Text conversions of the Windows console (see `windows_stdio`): it reads and writes
+//! UTF-16, websocat relays UTF-8. Nothing here is Windows-specific, so it is built everywhere.
+
+/// Keeps a UTF-16 high surrogate that arrived without its pair until the next read
+#[derive(Default)]
+pub struct Utf16Decoder {
+    pending: Option<u16>,
+}
+
+impl Utf16Decoder {
+    pub fn decode(&mut self, units: &[u16], out: &mut Vec<u8>) {
+        let mut v: Vec<u16> = self.pending.take().into_iter().collect();
+        v.extend_from_slice(units);
+        if let Some(&last) = v.last() {
+            if last >= 0xD800 && last < 0xDC00 {
+                self.pending = v.pop();
+            }
+        }
+        out.extend_from_slice(String::from_utf16_lossy(&v).as_bytes());
+    }
+}
+
+/// Keeps an incomplete UTF-8 sequence at the end of a write until the next one
+#[derive(Default)]
+pub struct Utf8Encoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Encoder {
+    pub fn encode(&mut self, bytes: &[u8], out: &mut Vec<u16>) {
+        self.pending.extend_from_slice(bytes);
+        // Invalid sequences are replaced, an incomplete one at the end waits for the rest
+        let mut valid = 0;
+        while valid < self.pending.len() {
+            match ::std::str::from_utf8(&self.pending[valid..]) {
+                Ok(_) => valid = self.pending.len(),
+                Err(e) => match e.error_len() {
+                    None => {
+                        valid += e.valid_up_to();
+                        break;
+                    }
+                    Some(n) => valid += e.valid_up_to() + n,
+                },
+            }
+        }
+        let rest = self.pending.split_off(valid);
+        out.extend(String::from_utf8_lossy(&self.pending).encode_utf16());
+        self.pending = rest;
+    }
+}
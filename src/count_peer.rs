@@ -0,0 +1,257 @@
+use futures;
+use futures::task::{self, Task};
+use futures::Async::Ready;
+
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::rc::Rc;
+use std::time::Instant;
+
+use super::{once, wouldblock, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, Specifier, SpecifierInfo};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug, Clone)]
+pub struct Null;
+impl Specifier for Null {
+    fn construct(&self, _: ConstructParams) -> PeerConstructor {
+        once(Box::new(futures::future::ok(get_null_peer())) as BoxedNewPeerFuture)
+    }
+    specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
+}
+specifier_class!(
+    name = NullClass,
+    target = Null,
+    prefixes = ["null:"],
+    arg_handling = noarg,
+    help = r#"
+Discard all input, never produce output. EOF is signaled only after
+the other side finishes sending, so the session is not cut short.
+
+Example: measure how fast a server pushes data, without storing it
+
+    time websocat -u ws://127.0.0.1:8080/stream null:
+"#
+);
+
+#[derive(Default)]
+struct NullState {
+    closed: bool,
+    reader: Option<Task>,
+}
+
+impl NullState {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(t) = self.reader.take() {
+            t.notify();
+        }
+    }
+}
+
+pub fn get_null_peer() -> Peer {
+    let s = Rc::new(RefCell::new(NullState::default()));
+    Peer::new(NullRead(s.clone()), NullWrite(s))
+}
+
+struct NullRead(Rc<RefCell<NullState>>);
+impl Read for NullRead {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, IoError> {
+        let mut s = self.0.borrow_mut();
+        if s.closed {
+            return Ok(0);
+        }
+        s.reader = Some(task::current());
+        wouldblock()
+    }
+}
+impl AsyncRead for NullRead {}
+
+struct NullWrite(Rc<RefCell<NullState>>);
+impl Write for NullWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+impl AsyncWrite for NullWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.0.borrow_mut().close();
+        Ok(Ready(()))
+    }
+}
+impl Drop for NullWrite {
+    fn drop(&mut self) {
+        self.0.borrow_mut().close();
+    }
+}
+
+#[derive(Debug)]
+pub struct Count(pub Option<Rc<Specifier>>);
+impl Specifier for Count {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        match self.0 {
+            Some(ref sub) => sub.construct(cp).map(move |p| count_peer(p, &opts)),
+            None => once(count_peer(get_null_peer(), &opts)),
+        }
+    }
+    fn is_multiconnect(&self) -> bool {
+        self.0.as_ref().map_or(false, |x| x.is_multiconnect())
+    }
+    fn get_info(&self) -> SpecifierInfo {
+        SpecifierInfo {
+            this: self.get_info_without_subspecs(),
+            subspecifier: self.0.as_ref().map(|x| Box::new(x.get_info())),
+        }
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+}
+specifier_class!(
+    name = CountClass,
+    target = Count,
+    prefixes = ["count:"],
+    arg_handling = {
+        fn construct(
+            self: &CountClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            if just_arg.is_empty() {
+                return Ok(Rc::new(Count(None)));
+            }
+            Ok(Rc::new(Count(Some(super::spec(just_arg)?))))
+        }
+    },
+    consumed_options = &["--count-output"],
+    help = r#"
+Count messages (read or write calls) and bytes, printing a summary to stderr
+when the session ends, even if it ends with an error.
+
+Without an argument it acts like `null:`. With a subspecifier it passes data
+through, counting it separately for both directions (`in` is data read from
+the subspecifier, `out` is data written to it).
+
+With --count-output <file>, summaries are appended to the file as JSON lines instead.
+
+Example: measure a server's push rate
+
+    websocat -u ws://127.0.0.1:8080/stream count:
+
+    count: in: messages=0 bytes=0 rate=0B/s out: messages=1234 bytes=5678901 rate=461699B/s duration=12.3s
+"#
+);
+
+#[derive(Default)]
+struct Tally {
+    messages: u64,
+    bytes: u64,
+}
+
+impl Tally {
+    fn add(&mut self, n: usize) {
+        self.messages += 1;
+        self.bytes += n as u64;
+    }
+}
+
+/// Counters for one session. Summary is reported when it is dropped.
+struct Stats {
+    inc: Tally,
+    out: Tally,
+    start: Instant,
+    output: Option<String>,
+}
+
+impl Drop for Stats {
+    fn drop(&mut self) {
+        let d = self.start.elapsed();
+        let secs = d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9;
+        let rate = |t: &Tally| {
+            if secs > 0.0 {
+                (t.bytes as f64 / secs) as u64
+            } else {
+                0
+            }
+        };
+        match self.output {
+            None => eprintln!(
+                "count: in: messages={} bytes={} rate={}B/s out: messages={} bytes={} rate={}B/s duration={:.1}s",
+                self.inc.messages,
+                self.inc.bytes,
+                rate(&self.inc),
+                self.out.messages,
+                self.out.bytes,
+                rate(&self.out),
+                secs
+            ),
+            Some(ref path) => {
+                let line = format!(
+                    "{{\"in\":{{\"messages\":{},\"bytes\":{},\"rate\":{}}},\"out\":{{\"messages\":{},\"bytes\":{},\"rate\":{}}},\"duration\":{:.3}}}\n",
+                    self.inc.messages,
+                    self.inc.bytes,
+                    rate(&self.inc),
+                    self.out.messages,
+                    self.out.bytes,
+                    rate(&self.out),
+                    secs
+                );
+                let r = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut f| f.write_all(line.as_bytes()));
+                if let Err(e) = r {
+                    error!("count: failed to write summary to {}: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+pub fn count_peer(inner_peer: Peer, opts: &Options) -> BoxedNewPeerFuture {
+    let s = Rc::new(RefCell::new(Stats {
+        inc: Default::default(),
+        out: Default::default(),
+        start: Instant::now(),
+        output: opts.count_output.clone(),
+    }));
+    let r = CountRead(inner_peer.0, s.clone());
+    let w = CountWrite(inner_peer.1, s);
+    Box::new(futures::future::ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+struct CountRead(Box<AsyncRead>, Rc<RefCell<Stats>>);
+
+impl Read for CountRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let n = self.0.read(buf)?;
+        if n > 0 {
+            self.1.borrow_mut().inc.add(n);
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for CountRead {}
+
+struct CountWrite(Box<AsyncWrite>, Rc<RefCell<Stats>>);
+
+impl Write for CountWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let n = self.0.write(buf)?;
+        self.1.borrow_mut().out.add(n);
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.flush()
+    }
+}
+impl AsyncWrite for CountWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.0.shutdown()
+    }
+}
@@ -0,0 +1,325 @@
+use futures;
+use futures::future::ok;
+
+use std::rc::Rc;
+
+use super::{peer_strerr, simple_err, BoxedNewPeerFuture, Peer, ReadDebt, WriteDebt};
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct Crc<T: Specifier>(pub T, pub bool);
+impl<T: Specifier> Specifier for Crc<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let framed = self.1;
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| crc_peer(p, &opts, framed))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = CrcClass,
+    target = Crc,
+    prefixes = ["crc:"],
+    arg_handling = {
+        fn construct(self: &CrcClass, _full: &str, just_arg: &str) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(Crc(super::spec(just_arg)?, false)))
+        }
+    },
+    consumed_options = &["--crc-algo", "--crc-strict"],
+    help = r#"
+Append a 4-byte big-endian checksum of each message written to the subspecifier,
+verify and strip it from each message read from it.
+
+Checksum is CRC32C by default, or XXH32 (seed 0) with --crc-algo xxhash.
+Messages with wrong checksum are logged and dropped, or abort the session
+with --crc-strict. Number of mismatches is reported at the end of session.
+
+The subspecifier should preserve message boundaries (WebSocket, UDP, ...);
+use `crc-stream:` for byte streams.
+
+Example: detect corruption on an untrusted link between two websocat instances
+
+    websocat ws-l:127.0.0.1:8080 crc:ws://relay.example.com/
+    websocat crc:ws-l:0.0.0.0:80 ws://127.0.0.1:8081/
+"#
+);
+specifier_class!(
+    name = CrcStreamClass,
+    target = Crc,
+    prefixes = ["crc-stream:"],
+    arg_handling = {
+        fn construct(
+            self: &CrcStreamClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(Crc(super::spec(just_arg)?, true)))
+        }
+    },
+    consumed_options = &["--crc-algo", "--crc-strict"],
+    help = r#"
+Like `crc:`, but for byte streams (TCP, pipes, ...): each chunk is framed
+with a 4-byte big-endian length header before payload and checksum,
+so verification does not depend on how the stream is split by reads.
+
+Example:
+
+    websocat ws-l:127.0.0.1:8080 crc-stream:tcp:10.0.0.2:1234
+"#
+);
+
+/// Limit for a frame length read from `crc-stream:`, to detect desync early
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CrcAlgo {
+    Crc32c,
+    XxHash32,
+}
+
+impl CrcAlgo {
+    pub fn checksum(self, data: &[u8]) -> u32 {
+        match self {
+            CrcAlgo::Crc32c => crc32c(data),
+            CrcAlgo::XxHash32 => xxh32(data, 0),
+        }
+    }
+}
+
+/// CRC-32C (Castagnoli), as used in iSCSI and SCTP
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut c = !0u32;
+    for &b in data {
+        c ^= b as u32;
+        for _ in 0..8 {
+            c = (c >> 1) ^ (0x82F6_3B78 & (c & 1).wrapping_neg());
+        }
+    }
+    !c
+}
+
+/// xxHash, 32-bit variant
+pub fn xxh32(data: &[u8], seed: u32) -> u32 {
+    const P1: u32 = 0x9E37_79B1;
+    const P2: u32 = 0x85EB_CA77;
+    const P3: u32 = 0xC2B2_AE3D;
+    const P4: u32 = 0x27D4_EB2F;
+    const P5: u32 = 0x1656_67B1;
+    let word = |b: &[u8]| {
+        (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+    };
+    let round = |acc: u32, w: u32| {
+        acc.wrapping_add(w.wrapping_mul(P2))
+            .rotate_left(13)
+            .wrapping_mul(P1)
+    };
+    let mut rest = data;
+    let mut h = if data.len() >= 16 {
+        let mut v = [
+            seed.wrapping_add(P1).wrapping_add(P2),
+            seed.wrapping_add(P2),
+            seed,
+            seed.wrapping_sub(P1),
+        ];
+        while rest.len() >= 16 {
+            for i in 0..4 {
+                v[i] = round(v[i], word(&rest[(i * 4)..]));
+            }
+            rest = &rest[16..];
+        }
+        v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(P5)
+    };
+    h = h.wrapping_add(data.len() as u32);
+    while rest.len() >= 4 {
+        h = h.wrapping_add(word(rest).wrapping_mul(P3))
+            .rotate_left(17)
+            .wrapping_mul(P4);
+        rest = &rest[4..];
+    }
+    for &b in rest {
+        h = h.wrapping_add((b as u32).wrapping_mul(P5))
+            .rotate_left(11)
+            .wrapping_mul(P1);
+    }
+    h ^= h >> 15;
+    h = h.wrapping_mul(P2);
+    h ^= h >> 13;
+    h = h.wrapping_mul(P3);
+    h ^= h >> 16;
+    h
+}
+
+fn be32(b: &[u8]) -> u32 {
+    (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32)
+}
+
+fn push_be32(v: &mut Vec<u8>, x: u32) {
+    v.extend_from_slice(&[(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8]);
+}
+
+pub fn crc_peer(inner_peer: Peer, opts: &Options, framed: bool) -> BoxedNewPeerFuture {
+    let algo = match &opts.crc_algo[..] {
+        "" | "crc32c" => CrcAlgo::Crc32c,
+        "xxhash" => CrcAlgo::XxHash32,
+        _ => return peer_strerr("--crc-algo must be `crc32c` or `xxhash`"),
+    };
+    let r = CrcRead {
+        inner: inner_peer.0,
+        algo,
+        framed,
+        strict: opts.crc_strict,
+        queue: vec![],
+        debt: Default::default(),
+        messages: 0,
+        mismatches: 0,
+    };
+    let w = CrcWrite {
+        inner: inner_peer.1,
+        algo,
+        framed,
+        debt: Default::default(),
+    };
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+struct CrcRead {
+    inner: Box<AsyncRead>,
+    algo: CrcAlgo,
+    framed: bool,
+    strict: bool,
+    /// Incomplete frames in `crc-stream:` mode
+    queue: Vec<u8>,
+    debt: ReadDebt,
+    messages: u64,
+    mismatches: u64,
+}
+
+impl CrcRead {
+    /// Check `data` (payload followed by checksum). `Ok(false)` means the message should be dropped.
+    fn verify(&mut self, data: &[u8]) -> Result<bool, IoError> {
+        self.messages += 1;
+        if data.len() >= 4 {
+            let (payload, sum) = data.split_at(data.len() - 4);
+            if self.algo.checksum(payload) == be32(sum) {
+                return Ok(true);
+            }
+        }
+        self.mismatches += 1;
+        let msg = format!("crc: checksum mismatch in a message of {} bytes", data.len());
+        if self.strict {
+            return Err(simple_err(msg));
+        }
+        warn!("{}, dropping it", msg);
+        Ok(false)
+    }
+
+    /// Length of the first complete frame in the queue
+    fn complete_frame(&self) -> Result<Option<usize>, IoError> {
+        if self.queue.len() < 4 {
+            return Ok(None);
+        }
+        let len = be32(&self.queue) as usize;
+        if len > MAX_FRAME {
+            return Err(simple_err(format!(
+                "crc-stream: frame length {} is too large, the stream is out of sync",
+                len
+            )));
+        }
+        if self.queue.len() < 4 + len + 4 {
+            return Ok(None);
+        }
+        Ok(Some(4 + len + 4))
+    }
+}
+
+impl Read for CrcRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        loop {
+            if !self.framed {
+                let n = self.inner.read(buf)?;
+                if n == 0 {
+                    return Ok(0);
+                }
+                // Empty message can't be delivered, as zero-length read means EOF
+                if self.verify(&buf[..n])? && n > 4 {
+                    return Ok(n - 4);
+                }
+                continue;
+            }
+            if let Some(flen) = self.complete_frame()? {
+                let frame: Vec<u8> = self.queue.drain(..flen).collect();
+                if self.verify(&frame[4..])? && flen > 8 {
+                    return self.debt.process_message(buf, &frame[4..(flen - 4)]);
+                }
+                continue;
+            }
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                if !self.queue.is_empty() {
+                    warn!(
+                        "crc-stream: throwing away {} bytes of incomplete frame",
+                        self.queue.len()
+                    );
+                }
+                return Ok(0);
+            }
+            self.queue.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+impl AsyncRead for CrcRead {}
+
+impl Drop for CrcRead {
+    fn drop(&mut self) {
+        if self.mismatches > 0 {
+            eprintln!(
+                "crc: {} of {} messages had wrong checksum",
+                self.mismatches, self.messages
+            );
+        }
+    }
+}
+
+struct CrcWrite {
+    inner: Box<AsyncWrite>,
+    algo: CrcAlgo,
+    framed: bool,
+    debt: WriteDebt,
+}
+
+impl Write for CrcWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let (algo, framed) = (self.algo, self.framed);
+        self.debt.write_frame(&mut self.inner, || {
+            let mut v = Vec::with_capacity(buf.len() + 8);
+            if framed {
+                push_be32(&mut v, buf.len() as u32);
+            }
+            v.extend_from_slice(buf);
+            push_be32(&mut v, algo.checksum(buf));
+            Ok(v)
+        })?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for CrcWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,55 @@
+//! Writing halves of datagram peers (`udp:`, `unix-dgram:`, `seqpacket:`), where each write
+//! is one packet. A short send must not leave a tail for the copy loop to send later as a
+//! packet of its own, so it is either an error or, with `--allow-partial-datagrams`,
+//! reported as fully written with the rest dropped.
+
+use futures::Poll;
+use std::io::{Error, ErrorKind, Result as IoResult, Write};
+use tokio_io::AsyncWrite;
+
+use super::metrics;
+
+/// All-or-nothing writes on top of `send`-like `write`s
+pub struct WholeDatagrams<W> {
+    inner: W,
+    allow_partial: bool,
+}
+
+impl<W> WholeDatagrams<W> {
+    pub fn new(inner: W, allow_partial: bool) -> Self {
+        WholeDatagrams {
+            inner,
+            allow_partial,
+        }
+    }
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+}
+
+impl<W: Write> Write for WholeDatagrams<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let n = self.inner.write(buf)?;
+        if n == buf.len() {
+            return Ok(n);
+        }
+        if !self.allow_partial {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("Short datagram send: {} of {} bytes", n, buf.len()),
+            ));
+        }
+        warn!("Sent only {} of {} bytes of a datagram, dropping the rest", n, buf.len());
+        metrics::add("datagrams_truncated", 1);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for WholeDatagrams<W> {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        self.inner.shutdown()
+    }
+}
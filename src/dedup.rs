@@ -0,0 +1,89 @@
+//! Session-level suppression of repeated messages (`--dedup-consecutive`, `--dedup-window`)
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use std::io::{Error as IoError, Read};
+use tokio_io::AsyncRead;
+
+/// Drops messages identical (byte for byte) to one of the last `window`
+/// distinct messages. With `max_silence`, a duplicate is let through
+/// anyway if nothing has passed for that long.
+pub struct DedupRead {
+    inner: Box<AsyncRead>,
+    recent: VecDeque<Vec<u8>>,
+    window: usize,
+    max_silence: Option<Duration>,
+    last_passed: Instant,
+    /// Duplicates in the current run, for debug log
+    run: u64,
+    suppressed: u64,
+    /// `incoming` or `outgoing`, for messages
+    direction: &'static str,
+}
+
+impl DedupRead {
+    pub fn new(
+        inner: Box<AsyncRead>,
+        window: usize,
+        max_silence: Option<u64>,
+        direction: &'static str,
+    ) -> DedupRead {
+        DedupRead {
+            inner,
+            recent: VecDeque::with_capacity(window),
+            window,
+            max_silence: max_silence.map(Duration::from_secs),
+            last_passed: Instant::now(),
+            run: 0,
+            suppressed: 0,
+            direction,
+        }
+    }
+
+    /// Whether the message should pass, updating the window
+    fn check(&mut self, msg: &[u8]) -> bool {
+        if !self.recent.iter().any(|x| &x[..] == msg) {
+            if self.run > 0 {
+                debug!("Suppressed {} duplicate {} messages", self.run, self.direction);
+                self.run = 0;
+            }
+            if self.recent.len() >= self.window {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(msg.to_vec());
+            return true;
+        }
+        if let Some(s) = self.max_silence {
+            if self.last_passed.elapsed() >= s {
+                debug!("Passing a duplicate {} message as a heartbeat", self.direction);
+                return true;
+            }
+        }
+        self.run += 1;
+        self.suppressed += 1;
+        false
+    }
+}
+
+impl Read for DedupRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        loop {
+            let n = self.inner.read(buf)?;
+            if n == 0 || self.check(&buf[..n]) {
+                self.last_passed = Instant::now();
+                return Ok(n);
+            }
+        }
+    }
+}
+impl AsyncRead for DedupRead {}
+
+impl Drop for DedupRead {
+    fn drop(&mut self) {
+        if self.suppressed > 0 {
+            info!("Suppressed {} duplicate {} messages", self.suppressed, self.direction);
+            super::metrics::add("duplicates_suppressed", self.suppressed);
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use futures;
+use futures::future::ok;
+use futures::task::{self, Task};
+use futures::Async::{NotReady, Ready};
+use futures::{Future, Poll};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::util::{parse_direction, XorShift};
+use super::{brokenpipe, wouldblock, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, ReadDebt, Specifier, WriteDebt};
+
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct Delay<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Delay<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let h = cp.tokio_handle.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| delay_peer(p, &opts, &h))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = DelayClass,
+    target = Delay,
+    prefixes = ["delay:"],
+    arg_handling = subspec,
+    consumed_options = &[
+        "--delay-ms",
+        "--delay-jitter-ms",
+        "--delay-max-queued",
+        "--delay-direction",
+    ],
+    help = r#"
+Add latency to messages (or read chunks) going through the subspecifier.
+
+Options: --delay-ms (fixed part), --delay-jitter-ms (uniformly random part),
+--delay-max-queued (stop reading when that many messages are waiting),
+--delay-direction in|out|both (`in` is data read from the subspecifier,
+`out` is data written to it).
+
+Messages are never reordered: a message with smaller random delay waits for the
+previous one. Queued messages are delivered before EOF is propagated.
+
+Example: emulate a laggy link
+
+    websocat --delay-ms 200 --delay-jitter-ms 50 ws-l:127.0.0.1:8080 delay:tcp:127.0.0.1:5678
+"#
+);
+
+#[derive(Clone, Copy)]
+struct Params {
+    delay: Duration,
+    jitter_ms: u64,
+    max_queued: usize,
+}
+
+/// Queue of messages with delivery times, nondecreasing
+struct DelayLine {
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    last: Option<Instant>,
+    rng: XorShift,
+    timer: Option<Timeout>,
+    handle: Handle,
+    p: Params,
+}
+
+impl DelayLine {
+    fn new(p: Params, h: &Handle) -> DelayLine {
+        DelayLine {
+            queue: VecDeque::new(),
+            last: None,
+            rng: XorShift::new(),
+            timer: None,
+            handle: h.clone(),
+            p,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.queue.len() >= self.p.max_queued
+    }
+
+    fn push(&mut self, data: Vec<u8>) {
+        let jitter = Duration::from_millis(self.rng.below(self.p.jitter_ms + 1));
+        let mut t = Instant::now() + self.p.delay + jitter;
+        if let Some(l) = self.last {
+            if t < l {
+                t = l;
+            }
+        }
+        self.last = Some(t);
+        self.queue.push_back((t, data));
+    }
+
+    /// Get the first message if it is due. `Ready(None)` means the queue is empty.
+    fn poll_pop(&mut self) -> Poll<Option<Vec<u8>>, IoError> {
+        let due = match self.queue.front() {
+            None => return Ok(Ready(None)),
+            Some(&(t, _)) => t,
+        };
+        let now = Instant::now();
+        if due > now {
+            if self.timer.is_none() {
+                self.timer = Some(Timeout::new(due - now, &self.handle)?);
+            }
+            if let NotReady = self.timer.as_mut().unwrap().poll()? {
+                return Ok(NotReady);
+            }
+        }
+        self.timer = None;
+        Ok(Ready(self.queue.pop_front().map(|x| x.1)))
+    }
+}
+
+pub fn delay_peer(inner_peer: Peer, opts: &Options, h: &Handle) -> BoxedNewPeerFuture {
+    let (din, dout) = match parse_direction(&opts.delay_direction) {
+        Some(x) => x,
+        None => return super::peer_strerr("--delay-direction must be `in`, `out` or `both`"),
+    };
+    let p = Params {
+        delay: Duration::from_millis(opts.delay_ms),
+        jitter_ms: opts.delay_jitter_ms,
+        max_queued: match opts.delay_max_queued {
+            0 => 64,
+            x => x,
+        },
+    };
+    let r: Box<AsyncRead> = if din {
+        Box::new(DelayRead {
+            inner: inner_peer.0,
+            line: DelayLine::new(p, h),
+            eof: false,
+            err: None,
+            debt: Default::default(),
+        })
+    } else {
+        inner_peer.0
+    };
+    let w: Box<AsyncWrite> = if dout {
+        let s = Rc::new(RefCell::new(Shared {
+            line: DelayLine::new(p, h),
+            closing: false,
+            finished: false,
+            error: None,
+            writer_task: None,
+            pump_task: None,
+        }));
+        h.spawn(Pump {
+            s: s.clone(),
+            inner: inner_peer.1,
+            debt: Default::default(),
+        });
+        Box::new(DelayWrite(s))
+    } else {
+        inner_peer.1
+    };
+    Box::new(ok(Peer(r, w, None, None))) as BoxedNewPeerFuture
+}
+
+struct DelayRead {
+    inner: Box<AsyncRead>,
+    line: DelayLine,
+    eof: bool,
+    /// Error to be reported after the queue is drained
+    err: Option<IoError>,
+    debt: ReadDebt,
+}
+
+impl Read for DelayRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        while !self.eof && !self.line.is_full() {
+            match self.inner.read(buf) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.line.push(buf[..n].to_vec()),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    self.eof = true;
+                    self.err = Some(e);
+                }
+            }
+        }
+        match self.line.poll_pop()? {
+            Ready(Some(x)) => self.debt.process_message(buf, &x),
+            Ready(None) if self.eof => match self.err.take() {
+                Some(e) => Err(e),
+                None => Ok(0),
+            },
+            Ready(None) | NotReady => wouldblock(),
+        }
+    }
+}
+impl AsyncRead for DelayRead {}
+
+/// State shared between the `DelayWrite` handle and the `Pump` task,
+/// which delivers due messages to the inner writer in background.
+struct Shared {
+    line: DelayLine,
+    closing: bool,
+    finished: bool,
+    error: Option<IoError>,
+    writer_task: Option<Task>,
+    pump_task: Option<Task>,
+}
+
+fn wake(t: &mut Option<Task>) {
+    if let Some(t) = t.take() {
+        t.notify();
+    }
+}
+
+struct Pump {
+    s: Rc<RefCell<Shared>>,
+    inner: Box<AsyncWrite>,
+    debt: WriteDebt,
+}
+
+impl Pump {
+    fn poll_impl(&mut self) -> Poll<(), IoError> {
+        loop {
+            if self.debt.0.is_none() {
+                let mut s = self.s.borrow_mut();
+                match s.line.poll_pop()? {
+                    Ready(Some(x)) => {
+                        wake(&mut s.writer_task);
+                        self.debt.0 = Some((x, 0));
+                    }
+                    Ready(None) => {
+                        if s.closing {
+                            drop(s);
+                            return self.inner.shutdown();
+                        }
+                        s.pump_task = Some(task::current());
+                        return Ok(NotReady);
+                    }
+                    NotReady => return Ok(NotReady),
+                }
+            }
+            match self.debt.write_frame(&mut self.inner, || unreachable!()) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(NotReady),
+                x => x?,
+            }
+            match self.inner.flush() {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                x => x?,
+            }
+        }
+    }
+}
+
+impl Future for Pump {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        let ret = self.poll_impl();
+        let mut s = self.s.borrow_mut();
+        match ret {
+            Ok(NotReady) => return Ok(NotReady),
+            Ok(Ready(())) => (),
+            Err(e) => {
+                info!("delay: {}", e);
+                s.error = Some(e);
+            }
+        }
+        s.finished = true;
+        wake(&mut s.writer_task);
+        Ok(Ready(()))
+    }
+}
+
+struct DelayWrite(Rc<RefCell<Shared>>);
+
+impl Write for DelayWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let mut s = self.0.borrow_mut();
+        if let Some(e) = s.error.take() {
+            return Err(e);
+        }
+        if s.finished {
+            return brokenpipe();
+        }
+        if s.line.is_full() {
+            s.writer_task = Some(task::current());
+            return wouldblock();
+        }
+        s.line.push(buf.to_vec());
+        wake(&mut s.pump_task);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+impl AsyncWrite for DelayWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        let mut s = self.0.borrow_mut();
+        s.closing = true;
+        wake(&mut s.pump_task);
+        if !s.finished {
+            s.writer_task = Some(task::current());
+            return Ok(NotReady);
+        }
+        match s.error.take() {
+            Some(e) => Err(e),
+            None => Ok(Ready(())),
+        }
+    }
+}
+
+impl Drop for DelayWrite {
+    fn drop(&mut self) {
+        // Let the pump deliver what is left and finish
+        let mut s = self.0.borrow_mut();
+        s.closing = true;
+        wake(&mut s.pump_task);
+    }
+}
@@ -0,0 +1,171 @@
+//! Using a specifier as a plain byte stream in your own protocol code, without a `Session`.
+//!
+//! ```no_run
+//! extern crate futures;
+//! extern crate tokio_core;
+//! extern crate websocat;
+//!
+//! use futures::Future;
+//!
+//! fn main() {
+//!     let mut core = tokio_core::reactor::Core::new().unwrap();
+//!     let opts = websocat::Options {
+//!         websocket_text_mode: true,
+//!         ..Default::default()
+//!     };
+//!     let conn = websocat::embed::connect("ws://127.0.0.1:8080/", opts, &core.handle());
+//!     let conn = core.run(conn).unwrap();
+//!     // `conn` is `AsyncRead + AsyncWrite`, use it with `tokio_io::io` and friends
+//! }
+//! ```
+//!
+//! Message-oriented specifiers are flattened: each incoming WebSocket message is just
+//! appended to the byte stream, each write becomes one outgoing message, text or binary
+//! depending on `Options::websocket_text_mode`. Options that are applied by a session
+//! (timeouts, message limits, `--line` and similar) have no effect here.
+//!
+//! Lifetimes: everything here is `Rc`-based and `!Send`. A `Connection` must stay on
+//! the thread whose reactor `Handle` created it, and is only useful while that reactor
+//! is being run. Dropping a `Connection` releases it, but stdio-based specifiers
+//! share state with all other connections made by the same `connect`/`listen` call;
+//! that state lives until the last of them is dropped.
+
+use futures::stream::FuturesUnordered;
+use futures::{Async, Future, Poll, Stream};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::cell::RefCell;
+use std::io::{Error as IoError, Read, Write};
+use std::rc::Rc;
+
+use super::{spec, BoxedNewPeerFuture, BoxedNewPeerStream, ConstructParams, L2rUser, Options, Peer, PeerConstructor, PeerOverlay, ProgramState, Result};
+
+/// An established connection of a specifier, as a byte stream
+pub struct Connection {
+    r: Box<AsyncRead>,
+    w: Box<AsyncWrite>,
+    _ps: Rc<RefCell<ProgramState>>,
+}
+
+impl Connection {
+    fn new(p: Peer, ps: Rc<RefCell<ProgramState>>) -> Self {
+        Connection {
+            r: p.0,
+            w: p.1,
+            _ps: ps,
+        }
+    }
+
+    /// Take reading and writing halves apart.
+    /// Shared state of stdio-based specifiers is released once the halves are dropped.
+    pub fn into_halves(self) -> (Box<AsyncRead>, Box<AsyncWrite>) {
+        (self.r, self.w)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::result::Result<usize, IoError> {
+        self.r.read(buf)
+    }
+}
+impl AsyncRead for Connection {}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> ::std::result::Result<usize, IoError> {
+        self.w.write(buf)
+    }
+    fn flush(&mut self) -> ::std::result::Result<(), IoError> {
+        self.w.flush()
+    }
+}
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> ::futures::Poll<(), IoError> {
+        self.w.shutdown()
+    }
+}
+
+fn construct(s: &str, opts: Options, h: &Handle) -> Result<(PeerConstructor, Rc<RefCell<ProgramState>>)> {
+    let spec = spec(s)?;
+    let ps = Rc::new(RefCell::new(ProgramState::default()));
+    let cp = ConstructParams {
+        tokio_handle: h.clone(),
+        program_options: Rc::new(opts),
+        global_state: ps.clone(),
+        left_to_right: L2rUser::FillIn(Default::default()),
+        ws_event_hook: None,
+        reconnect_hook: None,
+    };
+    Ok((spec.construct(cp), ps))
+}
+
+/// Connect a single-connection specifier like `wss://host/path` or `tcp:host:port`.
+/// For listening specifiers, use `listen`.
+pub fn connect(s: &str, opts: Options, h: &Handle) -> Box<Future<Item = Connection, Error = Box<::std::error::Error>>> {
+    let (pc, ps) = match construct(s, opts, h) {
+        Ok(x) => x,
+        Err(e) => return Box::new(::futures::future::err(e)),
+    };
+    let f: BoxedNewPeerFuture = match pc {
+        PeerConstructor::ServeOnce(_) | PeerConstructor::Overlay1(..) => pc.get_only_first_conn(),
+        PeerConstructor::ServeMultipleTimes(_) | PeerConstructor::OverlayM(..) => {
+            return Box::new(::futures::future::err(
+                format!("`{}` accepts multiple connections, use `embed::listen` for it", s).into(),
+            ))
+        }
+    };
+    Box::new(f.map(move |p| Connection::new(p, ps)))
+}
+
+/// Accept connections of a specifier like `ws-l:127.0.0.1:8080`.
+/// Single-connection specifiers give a stream with one item.
+///
+/// Overlays like the WebSocket upgrade are done for all accepted connections at once,
+/// in whatever order they complete. A failed handshake is logged and the connection dropped;
+/// only errors of the listener itself end the stream.
+pub fn listen(s: &str, opts: Options, h: &Handle) -> Box<Stream<Item = Connection, Error = Box<::std::error::Error>>> {
+    let (pc, ps) = match construct(s, opts, h) {
+        Ok(x) => x,
+        Err(e) => return Box::new(::futures::future::err::<Connection, _>(e).into_stream()),
+    };
+    let peers: BoxedNewPeerStream = match pc {
+        PeerConstructor::ServeMultipleTimes(s) => s,
+        PeerConstructor::OverlayM(s, mapper) => Box::new(Upgrades {
+            accepted: Some(s),
+            mapper,
+            pending: FuturesUnordered::new(),
+        }),
+        x => Box::new(x.get_only_first_conn().into_stream()),
+    };
+    Box::new(peers.map(move |p| Connection::new(p, ps.clone())))
+}
+
+/// Accepted connections going through the overlay concurrently
+struct Upgrades {
+    /// `None` once the listener has ended
+    accepted: Option<BoxedNewPeerStream>,
+    mapper: PeerOverlay,
+    pending: FuturesUnordered<BoxedNewPeerFuture>,
+}
+
+impl Stream for Upgrades {
+    type Item = Peer;
+    type Error = Box<::std::error::Error>;
+    fn poll(&mut self) -> Poll<Option<Peer>, Box<::std::error::Error>> {
+        while let Some(r) = self.accepted.as_mut().map(|x| x.poll()) {
+            match r? {
+                Async::Ready(Some(p)) => self.pending.push((self.mapper)(p)),
+                Async::Ready(None) => self.accepted = None,
+                Async::NotReady => break,
+            }
+        }
+        loop {
+            match self.pending.poll() {
+                Ok(Async::Ready(Some(p))) => return Ok(Async::Ready(Some(p))),
+                Ok(Async::Ready(None)) if self.accepted.is_none() => return Ok(Async::Ready(None)),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => warn!("Dropping an accepted connection: {}", e),
+            }
+        }
+    }
+}
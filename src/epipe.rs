@@ -0,0 +1,85 @@
+//! Stdout whose reader went away (`websocat ... | head -1`) means the local consumer is done,
+//! not that the session failed. The first `EPIPE` closes all sessions normally
+//! and further output is dropped. `--exit-on-epipe-status` turns it into an exit code.
+
+#[cfg(all(unix, feature = "libc"))]
+extern crate libc;
+
+use futures::{Async, Poll};
+use std::io::{ErrorKind, Result as IoResult, Write};
+use tokio_io::AsyncWrite;
+
+use super::{metrics, shutdown};
+
+/// Shutdown reason after stdout got closed
+pub const REASON: &str = "stdout closed";
+
+/// Status code of Close frames sent because of it
+const CLOSE_CODE: u16 = 1000;
+
+/// Get `EPIPE` from writes instead of being killed by `SIGPIPE`.
+/// The Rust runtime already does so before `main`; this keeps it from depending on that.
+pub fn ignore_sigpipe() {
+    #[cfg(all(unix, feature = "libc"))]
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
+}
+
+/// Writer to stdout that reports a closed pipe as described in the module docs
+pub struct EpipeAsEof<W> {
+    inner: W,
+    closed: bool,
+}
+
+impl<W> EpipeAsEof<W> {
+    pub fn new(inner: W) -> Self {
+        EpipeAsEof {
+            inner,
+            closed: false,
+        }
+    }
+
+    fn check<T>(&mut self, r: IoResult<T>, dropped: usize, ok: T) -> IoResult<T> {
+        match r {
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {
+                if !self.closed {
+                    info!("Stdout closed, finishing");
+                    self.closed = true;
+                    shutdown::initiate_and_close_sessions(REASON, CLOSE_CODE);
+                }
+                metrics::add("stdout_dropped_bytes", dropped as u64);
+                Ok(ok)
+            }
+            r => r,
+        }
+    }
+}
+
+impl<W: Write> Write for EpipeAsEof<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.closed {
+            metrics::add("stdout_dropped_bytes", buf.len() as u64);
+            return Ok(buf.len());
+        }
+        let r = self.inner.write(buf);
+        self.check(r, buf.len(), buf.len())
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let r = self.inner.flush();
+        self.check(r, 0, ())
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for EpipeAsEof<W> {
+    fn shutdown(&mut self) -> Poll<(), ::std::io::Error> {
+        if self.closed {
+            return Ok(Async::Ready(()));
+        }
+        let r = self.inner.shutdown();
+        self.check(r, 0, Async::Ready(()))
+    }
+}
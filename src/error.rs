@@ -0,0 +1,338 @@
+//! Errors that library users may want to match on.
+//!
+//! Most of websocat passes errors around as `Box<std::error::Error>`, or as `std::io::Error`
+//! inside `Read`/`Write` implementations. `WebsocatError` travels inside either of them;
+//! use `find` to get it back. Messages are the same as of the underlying errors,
+//! prefixed with the peer they happened with if that is known (see `PeerError`).
+//!
+//! I/O and WebSocket library errors convert with `From`; `box_up_err` does that for
+//! errors that get boxed, see `typed`.
+
+use futures::Poll;
+use std::error::Error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::rc::Rc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::WebSocketError;
+
+use super::exit_code::ExitCode;
+use super::{events, spectree};
+
+#[derive(Debug)]
+pub enum WebsocatError {
+    /// Specifier string did not parse
+    Specifier(String),
+    /// Failed to resolve host name of `url`
+    Dns { url: String, source: IoError },
+    /// Failed to establish a connection to `addr` (an URL for WebSocket clients)
+    Connect { addr: String, source: IoError },
+    Tls { url: String, message: String },
+    /// WebSocket handshake rejected, with HTTP status if the server sent a response
+    Handshake {
+        url: String,
+        status: Option<u16>,
+        message: String,
+    },
+    /// WebSocket peer violated the protocol or did not close properly,
+    /// with the status code of Close frame if one was received
+    Protocol {
+        close_code: Option<u16>,
+        message: String,
+    },
+    /// I/O error, with `context` naming the peer it happened with, if known
+    Io { context: String, source: IoError },
+}
+
+impl fmt::Display for WebsocatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::WebsocatError::*;
+        match *self {
+            Specifier(ref m) => m.fmt(f),
+            Dns { ref source, .. } => source.fmt(f),
+            Connect { ref source, .. } => source.fmt(f),
+            Tls { ref message, .. } => message.fmt(f),
+            Handshake { ref message, .. } => message.fmt(f),
+            Protocol { ref message, .. } => message.fmt(f),
+            Io {
+                ref context,
+                ref source,
+            } => if context.is_empty() {
+                source.fmt(f)
+            } else {
+                write!(f, "{}: {}", context, source)
+            },
+        }
+    }
+}
+
+impl Error for WebsocatError {
+    fn description(&self) -> &str {
+        "websocat error"
+    }
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            WebsocatError::Dns { ref source, .. }
+            | WebsocatError::Connect { ref source, .. }
+            | WebsocatError::Io { ref source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl WebsocatError {
+    /// What the `websocat` binary exits with because of this error
+    pub fn exit_code(&self) -> ExitCode {
+        use self::WebsocatError::*;
+        match *self {
+            // Turned into `Usage` by the binary if it happens while parsing the command line
+            Specifier(_) => ExitCode::Other,
+            Dns { .. } => ExitCode::Dns,
+            Connect { .. } => ExitCode::Connect,
+            Tls { .. } => ExitCode::Tls,
+            Handshake { status, .. } => ExitCode::Http(status),
+            Protocol { .. } => ExitCode::AbnormalClose,
+            Io { .. } => ExitCode::Io,
+        }
+    }
+
+    /// Peer the error happened with, like `tcp:10.0.0.5:9000 (right side), session 3`
+    pub fn context(&self) -> Option<&str> {
+        match *self {
+            WebsocatError::Io { ref context, .. } if !context.is_empty() => Some(context),
+            _ => None,
+        }
+    }
+
+    /// URL or address being connected to, if any
+    pub fn url(&self) -> Option<&str> {
+        use self::WebsocatError::*;
+        match *self {
+            Dns { ref url, .. } | Tls { ref url, .. } | Handshake { ref url, .. } => Some(url),
+            Connect { ref addr, .. } => Some(addr),
+            _ => None,
+        }
+    }
+
+    fn io_kind(&self) -> ErrorKind {
+        match *self {
+            WebsocatError::Connect { ref source, .. } | WebsocatError::Io { ref source, .. } => source.kind(),
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl From<WebsocatError> for IoError {
+    fn from(e: WebsocatError) -> IoError {
+        IoError::new(e.io_kind(), e)
+    }
+}
+
+/// The `WebsocatError` travelling inside, or `Io` without context
+impl From<IoError> for WebsocatError {
+    fn from(e: IoError) -> WebsocatError {
+        if e.get_ref().map_or(false, |x| x.is::<WebsocatError>()) {
+            match e.into_inner().map(|x| x.downcast::<WebsocatError>()) {
+                Some(Ok(x)) => return *x,
+                _ => unreachable!(),
+            }
+        }
+        WebsocatError::Io {
+            context: String::new(),
+            source: e,
+        }
+    }
+}
+
+/// Anything but I/O errors of the WebSocket library is a protocol violation
+impl From<WebSocketError> for WebsocatError {
+    fn from(e: WebSocketError) -> WebsocatError {
+        match e {
+            WebSocketError::IoError(x) => x.into(),
+            e => WebsocatError::Protocol {
+                close_code: None,
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+/// Convert I/O errors from the operating system (or carrying `WebsocatError`) and
+/// WebSocket library errors to `WebsocatError`. Other errors, including I/O errors
+/// with a message of their own, are left as they are.
+pub fn typed(e: Box<Error>) -> Box<Error> {
+    let e = match e.downcast::<IoError>() {
+        Ok(x) => {
+            if x.get_ref().map_or(true, |i| i.is::<WebsocatError>()) {
+                return Box::new(WebsocatError::from(*x));
+            }
+            return x;
+        }
+        Err(e) => e,
+    };
+    match e.downcast::<WebSocketError>() {
+        Ok(x) => Box::new(WebsocatError::from(*x)),
+        Err(e) => e,
+    }
+}
+
+/// `WebsocatError` in a boxed error or inside an `std::io::Error`, maybe wrapped in `PeerError`
+pub fn find(e: &(Error + 'static)) -> Option<&WebsocatError> {
+    if let Some(x) = e.downcast_ref::<PeerError>() {
+        return find(&*x.source);
+    }
+    if let Some(x) = e.downcast_ref::<WebsocatError>() {
+        return Some(x);
+    }
+    e.downcast_ref::<IoError>()
+        .and_then(|x| x.get_ref())
+        .and_then(|x| x.downcast_ref::<WebsocatError>())
+}
+
+/// Turn an I/O error into `WebsocatError::Io` naming the peer, unless it is one already
+pub fn io_context(context: &str, e: IoError) -> WebsocatError {
+    match WebsocatError::from(e) {
+        WebsocatError::Io { context: c, source } => WebsocatError::Io {
+            context: if c.is_empty() { context.to_string() } else { c },
+            source,
+        },
+        x => x,
+    }
+}
+
+/// Error from establishing a peer of a session, with the specifier it came from.
+/// Shown on one line, like
+/// `ws://10.0.0.5/ (right side, inside autoreconnect:), session 3: Connection refused`.
+#[derive(Debug)]
+pub struct PeerError {
+    /// What comes before the colon, see `side_context`
+    pub context: String,
+    pub source: Box<Error>,
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.source)
+    }
+}
+
+impl Error for PeerError {
+    fn description(&self) -> &str {
+        "peer error"
+    }
+    fn cause(&self) -> Option<&Error> {
+        Some(&*self.source)
+    }
+}
+
+/// Name of the innermost specifier as written in `spec`, which side of the session it is on,
+/// the overlays around it and the current session, like
+/// `tcp:10.0.0.5:9000 (right side, inside autoreconnect:), session 3`.
+/// `None` if the specifier string is not known, like for most library users.
+pub fn side_context(spec: Option<&str>, right: bool) -> Option<String> {
+    let contexts = if right {
+        PeerContexts::new(None, spec)
+    } else {
+        PeerContexts::new(spec, None)
+    };
+    contexts.side(right)
+}
+
+/// `side_context` of both specifiers, parsed once per `serve`.
+/// The session is added when a context is used.
+#[derive(Debug, Default)]
+pub struct PeerContexts {
+    left: Option<String>,
+    right: Option<String>,
+}
+
+impl PeerContexts {
+    pub fn new(left: Option<&str>, right: Option<&str>) -> Self {
+        PeerContexts {
+            left: left.and_then(|x| describe(x, "left side")),
+            right: right.and_then(|x| describe(x, "right side")),
+        }
+    }
+
+    /// Context of the left or right peer in the current session
+    pub fn side(&self, right: bool) -> Option<String> {
+        let s = if right { &self.right } else { &self.left };
+        s.as_ref().map(|s| match events::current_sid() {
+            Some(sid) => format!("{}, session {}", s, sid),
+            None => s.clone(),
+        })
+    }
+
+    /// `peer_context` for the left or right peer in the current session
+    pub fn wrap(&self, right: bool, e: Box<Error>) -> Box<Error> {
+        peer_context(self.side(right).as_ref().map(|x| &x[..]), e)
+    }
+}
+
+fn describe(spec: &str, side: &str) -> Option<String> {
+    let tree = spectree::parse(spec).ok()?;
+    let levels = tree.levels();
+    let (inner, overlays) = levels.split_last()?;
+    let mut s = format!("{}{} ({}", inner.prefix, inner.arg, side);
+    if !overlays.is_empty() {
+        s.push_str(", inside ");
+        for x in overlays {
+            s.push_str(x.prefix);
+        }
+    }
+    s.push(')');
+    Some(s)
+}
+
+/// Wrap an error from establishing a peer in `PeerError`, unless it names the peer already
+pub fn peer_context(context: Option<&str>, e: Box<Error>) -> Box<Error> {
+    let named = find(&*e).map_or(false, |x| x.context().is_some()) || e.is::<PeerError>();
+    match context {
+        Some(c) if !named => Box::new(PeerError {
+            context: c.to_string(),
+            source: e,
+        }),
+        _ => e,
+    }
+}
+
+/// Reader or writer of a session that names its peer in operating system errors,
+/// like `Connection reset by peer`. Errors made by websocat itself pass through as they are.
+pub struct WithContext<T> {
+    pub inner: T,
+    pub context: Rc<String>,
+}
+
+impl<T> WithContext<T> {
+    fn wrap(&self, e: IoError) -> IoError {
+        if e.get_ref().is_some() || e.kind() == ErrorKind::WouldBlock {
+            return e;
+        }
+        io_context(&self.context, e).into()
+    }
+}
+
+impl<T: Read> Read for WithContext<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let r = self.inner.read(buf);
+        r.map_err(|e| self.wrap(e))
+    }
+}
+impl<T: AsyncRead> AsyncRead for WithContext<T> {}
+
+impl<T: Write> Write for WithContext<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let r = self.inner.write(buf);
+        r.map_err(|e| self.wrap(e))
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        let r = self.inner.flush();
+        r.map_err(|e| self.wrap(e))
+    }
+}
+impl<T: AsyncWrite> AsyncWrite for WithContext<T> {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        let r = self.inner.shutdown();
+        r.map_err(|e| self.wrap(e))
+    }
+}
@@ -0,0 +1,285 @@
+//! Connection lifecycle events (accepted, handshake, reconnect, session end).
+//! With `--log-format json` they are written as one JSON object per line
+//! to `--events-file` or stderr; with `--log-syslog` or `--log-journald` they
+//! are also sent to the system log; otherwise they only go to the debug log.
+
+extern crate serde_json;
+
+use futures::{Future, Poll};
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Arc;
+
+use self::serde_json::{Map, Value};
+
+use std::io::{Error as IoError, Read};
+use tokio_io::AsyncRead;
+
+use super::lifecycle::{SessionCallbacks, SessionMeta, SessionStats};
+use super::remote_log::RemoteLog;
+use super::Options;
+
+struct Sink {
+    w: Box<Write>,
+    path: Option<String>,
+}
+
+/// What is known about a session, reported with `session_end` and to `--on-disconnect`
+#[derive(Default, Clone, Debug)]
+pub struct SessionInfo {
+    pub peer: Option<String>,
+    pub uri: Option<String>,
+    pub protocol: Option<String>,
+    /// Indexed by direction: 0 is `in`, 1 is `out`
+    pub bytes: [u64; 2],
+    pub close_code: Option<u16>,
+}
+
+thread_local! {
+    static SINK: RefCell<Option<Sink>> = RefCell::new(None);
+    static REMOTE: RefCell<Option<Arc<RemoteLog>>> = RefCell::new(None);
+    static RELOAD_HOOK_SET: Cell<bool> = Cell::new(false);
+    static CURRENT_SID: Cell<Option<u64>> = Cell::new(None);
+    static NEXT_SID: Cell<u64> = Cell::new(1);
+    static SESSIONS: RefCell<HashMap<u64, SessionInfo>> = RefCell::new(HashMap::new());
+}
+
+pub fn is_json_format(x: &str) -> Option<bool> {
+    match x {
+        "text" => Some(false),
+        "json" => Some(true),
+        _ => None,
+    }
+}
+
+fn open_sink(path: &Option<String>) -> Result<Box<Write>, IoError> {
+    Ok(match *path {
+        Some(ref p) => {
+            let f: File = OpenOptions::new().create(true).append(true).open(p)?;
+            Box::new(f) as Box<Write>
+        }
+        None => Box::new(::std::io::stderr()) as Box<Write>,
+    })
+}
+
+/// Set up event output according to `--log-format` and `--events-file`
+pub fn init(opts: &Options) {
+    if is_json_format(&opts.log_format) != Some(true) {
+        return;
+    }
+    let path = opts.events_file.clone();
+    let w = match open_sink(&path) {
+        Ok(w) => w,
+        Err(e) => {
+            error!("Can't open log file for events: {}, using stderr", e);
+            Box::new(::std::io::stderr()) as Box<Write>
+        }
+    };
+    SINK.with(|s| *s.borrow_mut() = Some(Sink { w, path }));
+    if !RELOAD_HOOK_SET.with(|x| x.replace(true)) {
+        super::reload::on_reload(|| {
+            SINK.with(|s| {
+                if let Some(ref mut s) = *s.borrow_mut() {
+                    if s.path.is_some() {
+                        match open_sink(&s.path) {
+                            Ok(w) => s.w = w,
+                            Err(e) => error!("Failed to reopen event log: {}", e),
+                        }
+                    }
+                }
+            });
+            true
+        });
+    }
+}
+
+/// Also send events to syslog or journald
+pub fn set_remote(r: Arc<RemoteLog>) {
+    REMOTE.with(|x| *x.borrow_mut() = Some(r))
+}
+
+pub fn enabled() -> bool {
+    SINK.with(|s| s.borrow().is_some()) || REMOTE.with(|x| x.borrow().is_some())
+}
+
+/// Whether sessions need to count their bytes for `session_end`
+pub fn tracking(opts: &Options) -> bool {
+    enabled() || opts.on_disconnect.is_some() || !opts.callbacks.0.is_empty()
+}
+
+/// Allocate an identifier for a new session
+pub fn new_sid() -> u64 {
+    NEXT_SID.with(|x| {
+        let sid = x.get();
+        x.set(sid + 1);
+        sid
+    })
+}
+
+/// Session on whose behalf the code runs right now, if any
+pub fn current_sid() -> Option<u64> {
+    CURRENT_SID.with(|x| x.get())
+}
+
+/// Future that makes events emitted while polling it carry the session identifier
+pub struct Scoped<F> {
+    sid: u64,
+    inner: F,
+}
+
+pub fn scoped<F: Future>(sid: u64, inner: F) -> Scoped<F> {
+    Scoped { sid, inner }
+}
+
+impl<F> Drop for Scoped<F> {
+    fn drop(&mut self) {
+        // Sessions that failed before starting have not been cleaned up by `session_end`
+        SESSIONS.with(|s| s.borrow_mut().remove(&self.sid));
+    }
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let old = CURRENT_SID.with(|x| x.replace(Some(self.sid)));
+        let ret = self.inner.poll();
+        CURRENT_SID.with(|x| x.set(old));
+        ret
+    }
+}
+
+/// Emit an event for the current session. `fields` should have stable names.
+pub fn emit(event: &str, fields: Vec<(&'static str, Value)>) {
+    let sid = current_sid();
+    debug!("Event {} sid={:?} {:?}", event, sid, fields);
+    REMOTE.with(|x| {
+        if let Some(ref r) = *x.borrow() {
+            let mut strs: Vec<(&str, String)> = vec![];
+            if let Some(sid) = sid {
+                strs.push(("sid", sid.to_string()));
+            }
+            for &(k, ref v) in &fields {
+                match *v {
+                    Value::Null => (),
+                    Value::String(ref x) => strs.push((k, x.clone())),
+                    ref x => strs.push((k, x.to_string())),
+                }
+            }
+            r.log(::log::Level::Info, event, &strs);
+        }
+    });
+    SINK.with(|s| {
+        let mut s = s.borrow_mut();
+        let s = match *s {
+            Some(ref mut s) => s,
+            None => return,
+        };
+        let mut o = Map::new();
+        o.insert("ts".into(), super::util::rfc3339_now().into());
+        o.insert("event".into(), event.into());
+        if let Some(sid) = sid {
+            o.insert("sid".into(), sid.into());
+        }
+        for (k, v) in fields {
+            o.insert(k.into(), v);
+        }
+        let mut line = Value::Object(o).to_string();
+        line.push('\n');
+        // One write per line, so lines from several writers don't mix
+        if let Err(e) = s.w.write_all(line.as_bytes()) {
+            warn!("Failed to write event: {}", e);
+        }
+    })
+}
+
+fn update_current<F: FnOnce(&mut SessionInfo)>(f: F) {
+    if let Some(sid) = current_sid() {
+        SESSIONS.with(|s| f(s.borrow_mut().entry(sid).or_insert_with(Default::default)));
+    }
+}
+
+/// A listener accepted a connection for the current session
+pub fn accepted(peer: Option<String>) {
+    let fields = peer.iter().map(|x| ("peer", x.as_str().into())).collect();
+    update_current(|i| i.peer = peer.clone());
+    emit("accepted", fields);
+}
+
+/// Remember a received WebSocket Close for the current session's `session_end`
+pub fn note_close(code: u16) {
+    update_current(|i| i.close_code = Some(code))
+}
+
+/// Remember the request URI of the current session
+pub fn note_uri(uri: &str) {
+    update_current(|i| i.uri = Some(uri.to_string()))
+}
+
+/// Remember the negotiated WebSocket subprotocol of the current session
+pub fn note_protocol(protocol: Option<String>) {
+    update_current(|i| i.protocol = protocol.clone())
+}
+
+/// What is known about the current session so far
+pub fn session_info() -> SessionInfo {
+    current_sid()
+        .and_then(|sid| SESSIONS.with(|s| s.borrow().get(&sid).cloned()))
+        .unwrap_or_default()
+}
+
+/// What is known about the current session, which has ended
+pub fn take_session_info() -> SessionInfo {
+    current_sid()
+        .and_then(|sid| SESSIONS.with(|s| s.borrow_mut().remove(&sid)))
+        .unwrap_or_default()
+}
+
+/// Writes `session_start`, `handshake_ok` and `session_end` events
+pub struct EventLog;
+
+impl SessionCallbacks for EventLog {
+    fn on_session_start(&self, _meta: &SessionMeta) {
+        emit("session_start", vec![]);
+    }
+    fn on_handshake(&self, meta: &SessionMeta, _headers: &[(String, String)]) {
+        let fields = meta.protocol.iter().map(|x| ("protocol", x.as_str().into())).collect();
+        emit("handshake_ok", fields);
+    }
+    fn on_session_end(&self, _meta: &SessionMeta, result: Result<(), &str>, stats: &SessionStats) {
+        let mut fields = vec![
+            ("bytes_in", stats.bytes_in.into()),
+            ("bytes_out", stats.bytes_out.into()),
+            ("close_code", stats.close_code.map_or(Value::Null, |c| c.into())),
+        ];
+        if let Err(e) = result {
+            fields.push(("error", e.into()));
+        }
+        emit("session_end", fields);
+    }
+}
+
+/// Reader that counts a session's bytes for the `session_end` event
+pub struct CountRead {
+    pub inner: Box<AsyncRead>,
+    pub sid: u64,
+    /// `true` for data coming from the right specifier
+    pub incoming: bool,
+}
+
+impl Read for CountRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            let i = if self.incoming { 0 } else { 1 };
+            SESSIONS.with(|s| {
+                s.borrow_mut().entry(self.sid).or_insert_with(Default::default).bytes[i] += n as u64
+            });
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for CountRead {}
@@ -0,0 +1,221 @@
+//! Process exit codes, and the error classification behind them.
+//!
+//! Errors that should lead to a specific exit code are `error::WebsocatError`s
+//! or constructed with `ExitCode::error`; everything else is classified by `classify`.
+//! `report` also collects the context for `--errors-json`.
+
+extern crate hyper;
+extern crate serde_json;
+
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+
+use super::error::{PeerError, WebsocatError};
+
+/// Why websocat exits. See `code` for the numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    Success,
+    /// Anything not covered below
+    Other,
+    /// `--idle-timeout` fired
+    IdleTimeout,
+    /// `--total-max-*` budget used up
+    BudgetReached,
+    /// Invalid command line options or specifier combination
+    Usage,
+    /// Failed to resolve host name
+    Dns,
+    /// TCP connection refused, timed out or reset
+    Connect,
+    Tls,
+    /// WebSocket handshake rejected, with HTTP status if known
+    Http(Option<u16>),
+    /// WebSocket closed with status other than 1000/1001, or without Close frame
+    AbnormalClose,
+    /// Local I/O error, like failure to bind or to open a file
+    Io,
+    /// Some of `--each-line-of` targets failed, or `--bench` did not get all echoes back
+    TargetsFailed,
+    /// Session failed with `--exit-status-from-exec`
+    SessionFailed,
+    /// Shut down by SIGINT or SIGTERM
+    Signal(i32),
+}
+
+impl ExitCode {
+    /// Process exit code. With `old` (`--old-exit-codes`), codes 10-19 map to 1
+    /// and signal-driven shutdown to 0. Codes 2, 3 and 125 are kept: they only
+    /// come from options that older versions don't have.
+    pub fn code(self, old: bool) -> i32 {
+        use self::ExitCode::*;
+        match self {
+            Success => 0,
+            Other => 1,
+            IdleTimeout => 2,
+            BudgetReached => 3,
+            SessionFailed => 125,
+            Signal(_) if old => 0,
+            Signal(n) => 128 + n,
+            _ if old => 1,
+            Usage => 10,
+            Dns => 11,
+            Connect => 12,
+            Tls => 13,
+            Http(Some(s)) if s >= 400 && s < 500 => 14,
+            Http(Some(s)) if s >= 500 && s < 600 => 15,
+            Http(_) => 16,
+            AbnormalClose => 17,
+            Io => 18,
+            TargetsFailed => 19,
+        }
+    }
+
+    /// Short name for `--errors-json`
+    pub fn category(self) -> &'static str {
+        use self::ExitCode::*;
+        match self {
+            Success => "ok",
+            Other => "error",
+            IdleTimeout => "idle_timeout",
+            BudgetReached => "budget_reached",
+            Usage => "usage",
+            Dns => "dns",
+            Connect => "connect_failed",
+            Tls => "tls",
+            Http(_) => "handshake_rejected",
+            AbnormalClose => "abnormal_close",
+            Io => "io",
+            TargetsFailed => "targets_failed",
+            SessionFailed => "session_failed",
+            Signal(_) => "signal",
+        }
+    }
+
+    /// Error that makes websocat exit with this code, if it ends up in the main error path
+    pub fn error<E: fmt::Display>(self, e: E) -> Box<Error> {
+        Box::new(ClassifiedError {
+            code: self,
+            msg: e.to_string(),
+        })
+    }
+}
+
+/// Error with a known exit code. Also travels inside `std::io::Error`.
+#[derive(Debug)]
+pub struct ClassifiedError {
+    pub code: ExitCode,
+    msg: String,
+}
+
+impl fmt::Display for ClassifiedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.msg.fmt(f)
+    }
+}
+
+impl Error for ClassifiedError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+/// What `--errors-json` prints about a fatal error
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    pub code: ExitCode,
+    pub detail: String,
+    pub url: Option<String>,
+    /// Peer the error happened with, like `tcp:10.0.0.5:9000 (right side), session 3`
+    pub context: Option<String>,
+}
+
+impl ErrorReport {
+    /// Single-line JSON object, with `code` being the process exit code
+    pub fn to_json(&self, old: bool) -> String {
+        let mut o = serde_json::Map::new();
+        o.insert("error".into(), self.code.category().into());
+        o.insert("detail".into(), self.detail.as_str().into());
+        if let Some(ref x) = self.url {
+            o.insert("url".into(), x.as_str().into());
+        }
+        if let Some(ref x) = self.context {
+            o.insert("context".into(), x.as_str().into());
+        }
+        o.insert("code".into(), self.code.code(old).into());
+        serde_json::Value::Object(o).to_string()
+    }
+}
+
+fn from_classified(x: &ClassifiedError) -> ErrorReport {
+    ErrorReport {
+        code: x.code,
+        detail: x.msg.clone(),
+        url: None,
+        context: None,
+    }
+}
+
+fn from_websocat_error(x: &WebsocatError) -> ErrorReport {
+    let detail = match *x {
+        WebsocatError::Handshake { status: Some(s), .. } => hyper::status::StatusCode::from_u16(s).to_string(),
+        WebsocatError::Io { ref source, .. } => source.to_string(),
+        _ => x.to_string(),
+    };
+    ErrorReport {
+        code: x.exit_code(),
+        detail,
+        url: x.url().map(|u| u.to_string()),
+        context: x.context().map(|c| c.to_string()),
+    }
+}
+
+/// Exit code and context for an error that reached the main error path
+pub fn report(e: &(Error + 'static)) -> ErrorReport {
+    if let Some(x) = e.downcast_ref::<PeerError>() {
+        let mut r = report(&*x.source);
+        r.context = Some(x.context.clone());
+        return r;
+    }
+    if let Some(x) = e.downcast_ref::<ClassifiedError>() {
+        return from_classified(x);
+    }
+    if let Some(x) = super::error::find(e) {
+        return from_websocat_error(x);
+    }
+    let code = if super::idle_timeout::is_idle_timeout(e) {
+        ExitCode::IdleTimeout
+    } else if let Some(x) = e.downcast_ref::<::std::io::Error>() {
+        if let Some(x) = x.get_ref().and_then(|i| i.downcast_ref::<ClassifiedError>()) {
+            return from_classified(x);
+        }
+        ExitCode::Io
+    } else {
+        ExitCode::Other
+    };
+    ErrorReport {
+        code,
+        detail: e.to_string(),
+        url: None,
+        context: None,
+    }
+}
+
+/// Exit code appropriate for an error that reached the main error path
+pub fn classify(e: &(Error + 'static)) -> ExitCode {
+    report(e).code
+}
+
+thread_local! {
+    static ABNORMAL_CLOSE: Cell<bool> = Cell::new(false);
+}
+
+/// A WebSocket client connection ended abnormally, even if without error
+pub fn note_abnormal_close() {
+    ABNORMAL_CLOSE.with(|x| x.set(true))
+}
+
+pub fn abnormal_close_seen() -> bool {
+    ABNORMAL_CLOSE.with(|x| x.get())
+}
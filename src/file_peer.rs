@@ -1,9 +1,11 @@
 use futures;
-use futures::Async;
+use futures::{Async, Future};
 use std;
 use std::io::Result as IoResult;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use std::fs::{File, OpenOptions};
@@ -11,17 +13,14 @@ use std::rc::Rc;
 
 use super::{BoxedNewPeerFuture, Peer, Result};
 
-use super::{once, ConstructParams, PeerConstructor, Specifier};
+use super::{once, ConstructParams, Options, PeerConstructor, Specifier};
 
 #[derive(Clone, Debug)]
 pub struct ReadFile(pub PathBuf);
 impl Specifier for ReadFile {
-    fn construct(&self, _: ConstructParams) -> PeerConstructor {
-        fn gp(p: &Path) -> Result<Peer> {
-            let f = File::open(p)?;
-            Ok(Peer::new(ReadFileWrapper(f), super::trivial_peer::DevNull))
-        }
-        once(Box::new(futures::future::result(gp(&self.0))) as BoxedNewPeerFuture)
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let r = read_file_peer(&self.0, &cp.program_options, &cp.tokio_handle);
+        once(Box::new(futures::future::result(r)) as BoxedNewPeerFuture)
     }
     specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
 }
@@ -30,27 +29,71 @@ specifier_class!(
     target = ReadFile,
     prefixes = ["readfile:"],
     arg_handling = into,
+    takes_path = true,
+    consumed_options = &["--file-start-offset", "--file-follow", "--file-max-bytes"],
     help = r#"
 Synchronously read a file. Argumen is a file path.
 
 Blocking on operations with the file pauses the whole process
 
+--file-start-offset <n> skips first n bytes, or starts n bytes before the end
+if negative. --file-max-bytes <n> limits how much is read.
+
+On Linux, when the other side is a plain `tcp:` or `unix:` socket with nothing
+in between, the file is sent with sendfile(2), without copying it through
+websocat. --no-splice disables that.
+
+With --file-follow, reaching end of file does not end the session: the file is
+checked for new data periodically, like `tail -F`. If the file gets truncated,
+it is read again from the beginning; if it gets replaced (log rotation),
+the new file is opened.
+
 Example: Serve the file once per connection, ignore all replies.
 
     websocat ws-l:127.0.0.1:8000 readfile:hello.json
 
+Example: Serve the tail of a growing log
+
+    websocat -U ws-l:127.0.0.1:8000 readfile:/var/log/app.log --file-follow --file-start-offset -4096
+
 "#
 );
 
+/// Open file for writing, honoring `--file-append`, `--file-create-new` and `--file-sync`
+fn write_file_peer(p: &Path, append: bool, opts: &Options) -> Result<Peer> {
+    let mut oo = OpenOptions::new();
+    oo.write(true);
+    if append || opts.file_append {
+        oo.append(true);
+    } else {
+        oo.truncate(true);
+    }
+    if opts.file_create_new {
+        oo.create_new(true);
+    } else {
+        oo.create(true);
+    }
+    let f = oo.open(p)?;
+    let w = WriteFileWrapper {
+        f,
+        sync: opts.file_sync,
+    };
+    let bufsize = super::my_copy::output_buffer_size(opts);
+    if bufsize > 0 {
+        return Ok(Peer::new(
+            super::trivial_peer::DevNull,
+            super::my_copy::BufferedWrite::new(w, bufsize),
+        ));
+    }
+    Ok(Peer::new(super::trivial_peer::DevNull, w))
+}
+
 #[derive(Clone, Debug)]
 pub struct WriteFile(pub PathBuf);
 impl Specifier for WriteFile {
-    fn construct(&self, _: ConstructParams) -> PeerConstructor {
-        fn gp(p: &Path) -> Result<Peer> {
-            let f = File::create(p)?;
-            Ok(Peer::new(super::trivial_peer::DevNull, WriteFileWrapper(f)))
-        }
-        once(Box::new(futures::future::result(gp(&self.0))) as BoxedNewPeerFuture)
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let r = write_file_peer(&self.0, false, &cp.program_options);
+        once(Box::new(futures::future::result(r)) as BoxedNewPeerFuture)
     }
     specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
 }
@@ -59,12 +102,24 @@ specifier_class!(
     target = WriteFile,
     prefixes = ["writefile:"],
     arg_handling = into,
+    takes_path = true,
+    consumed_options = &[
+        "--file-append",
+        "--file-create-new",
+        "--file-sync",
+        "--flush-after-message",
+        "--flush-interval-ms",
+        "--no-flush",
+    ],
     help = r#"
 
 Synchronously truncate and write a file.
 
 Blocking on operations with the file pauses the whole process
 
+Use --file-create-new to refuse overwriting an existing file, --file-append
+to append instead of truncating and --file-sync to fsync the file on close.
+
 Example:
 
     websocat ws-l:127.0.0.1:8000 writefile:data.txt
@@ -75,12 +130,9 @@ Example:
 #[derive(Clone, Debug)]
 pub struct AppendFile(pub PathBuf);
 impl Specifier for AppendFile {
-    fn construct(&self, _: ConstructParams) -> PeerConstructor {
-        fn gp(p: &Path) -> Result<Peer> {
-            let f = OpenOptions::new().create(true).append(true).open(p)?;
-            Ok(Peer::new(super::trivial_peer::DevNull, WriteFileWrapper(f)))
-        }
-        once(Box::new(futures::future::result(gp(&self.0))) as BoxedNewPeerFuture)
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let r = write_file_peer(&self.0, true, &cp.program_options);
+        once(Box::new(futures::future::result(r)) as BoxedNewPeerFuture)
     }
     specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
 }
@@ -89,39 +141,184 @@ specifier_class!(
     target = AppendFile,
     prefixes = ["appendfile:"],
     arg_handling = into,
+    takes_path = true,
+    consumed_options = &[
+        "--file-append",
+        "--file-create-new",
+        "--file-sync",
+        "--flush-after-message",
+        "--flush-interval-ms",
+        "--no-flush",
+    ],
     help = r#"
 
 Synchronously append a file.
 
 Blocking on operations with the file pauses the whole process
 
+Options --file-create-new and --file-sync apply, like for `writefile:`.
+
 Example: Logging all incoming data from WebSocket clients to one file
 
     websocat -u ws-l:127.0.0.1:8000 reuse:appendfile:log.txt
 "#
 );
 
-struct ReadFileWrapper(File);
+/// How often to check for new data in `--file-follow` mode
+const FOLLOW_INTERVAL_MS: u64 = 250;
+
+fn read_file_peer(p: &Path, opts: &Options, h: &Handle) -> Result<Peer> {
+    let mut f = File::open(p)?;
+    let pos = match opts.file_start_offset {
+        None => 0,
+        Some(x) if x >= 0 => f.seek(SeekFrom::Start(x as u64))?,
+        Some(x) => {
+            let len = f.metadata()?.len();
+            f.seek(SeekFrom::Start(len.saturating_sub(x.wrapping_neg() as u64)))?
+        }
+    };
+    let follow = if opts.file_follow {
+        Some(Follow {
+            path: p.to_path_buf(),
+            handle: h.clone(),
+            timer: None,
+        })
+    } else {
+        None
+    };
+    #[cfg(unix)]
+    let fd = if follow.is_none() {
+        use std::os::unix::io::AsRawFd;
+        Some(f.as_raw_fd())
+    } else {
+        None
+    };
+    #[cfg(not(unix))]
+    let fd = None;
+    let r = ReadFileWrapper {
+        f,
+        pos,
+        remaining: opts.file_max_bytes,
+        follow,
+    };
+    match fd {
+        // Positioned at the start offset already, can go to a socket with `sendfile(2)`
+        Some(fd) => Ok(Peer::with_file_fd(r, super::trivial_peer::DevNull, fd)),
+        None => Ok(Peer::new(r, super::trivial_peer::DevNull)),
+    }
+}
+
+struct Follow {
+    path: PathBuf,
+    handle: Handle,
+    timer: Option<Timeout>,
+}
+
+struct ReadFileWrapper {
+    f: File,
+    pos: u64,
+    /// Bytes left to read, for `--file-max-bytes`
+    remaining: Option<u64>,
+    follow: Option<Follow>,
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    true
+}
+
+impl ReadFileWrapper {
+    /// Reopen or rewind the file if it was replaced or truncated. Returns true if it was.
+    fn check_rotation(&mut self) -> IoResult<bool> {
+        let path = &self.follow.as_ref().unwrap().path;
+        let md = match std::fs::metadata(path) {
+            Ok(x) => x,
+            // May be in the middle of rotation
+            Err(_) => return Ok(false),
+        };
+        if !same_file(&md, &self.f.metadata()?) {
+            info!("{:?} was replaced, reopening it", path);
+            self.f = File::open(path)?;
+            self.pos = 0;
+            return Ok(true);
+        }
+        if md.len() < self.pos {
+            info!("{:?} was truncated, reading it from the beginning", path);
+            self.pos = self.f.seek(SeekFrom::Start(0))?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
 
 impl AsyncRead for ReadFileWrapper {}
 impl Read for ReadFileWrapper {
     fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
-        self.0.read(buf)
+        loop {
+            let limit = match self.remaining {
+                Some(0) => return Ok(0),
+                Some(x) if x < buf.len() as u64 => x as usize,
+                _ => buf.len(),
+            };
+            let n = self.f.read(&mut buf[..limit])?;
+            if n > 0 || self.follow.is_none() {
+                self.pos += n as u64;
+                if let Some(ref mut x) = self.remaining {
+                    *x -= n as u64;
+                }
+                return Ok(n);
+            }
+            if self.check_rotation()? {
+                continue;
+            }
+            let fo = self.follow.as_mut().unwrap();
+            if fo.timer.is_none() {
+                fo.timer = Some(Timeout::new(Duration::from_millis(FOLLOW_INTERVAL_MS), &fo.handle)?);
+            }
+            if let Async::NotReady = fo.timer.as_mut().unwrap().poll()? {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            fo.timer = None;
+        }
     }
 }
 
-struct WriteFileWrapper(File);
+struct WriteFileWrapper {
+    f: File,
+    /// fsync on shutdown or drop, whichever comes first
+    sync: bool,
+}
 
 impl AsyncWrite for WriteFileWrapper {
     fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        if self.sync {
+            self.sync = false;
+            self.f.sync_all()?;
+        }
         Ok(Async::Ready(()))
     }
 }
 impl Write for WriteFileWrapper {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        self.0.write(buf)
+        self.f.write(buf)
     }
     fn flush(&mut self) -> IoResult<()> {
-        self.0.flush()
+        self.f.flush()
+    }
+}
+
+impl Drop for WriteFileWrapper {
+    fn drop(&mut self) {
+        if self.sync {
+            if let Err(e) = self.f.sync_all() {
+                warn!("Failed to sync file: {}", e);
+            }
+        }
     }
 }
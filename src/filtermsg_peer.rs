@@ -0,0 +1,381 @@
+extern crate tokio_process;
+
+use futures;
+use futures::future::{ok, Future};
+use futures::stream::Stream;
+use futures::sync::mpsc;
+use futures::task::Task;
+use futures::Async::{NotReady, Ready};
+use futures::{AsyncSink, Sink};
+
+use std::cell::RefCell;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use tokio_core::reactor::Handle;
+
+use self::tokio_process::CommandExt;
+
+use super::my_copy::BufferSettings;
+use super::process_peer::ChildHandle;
+use super::util::parse_direction;
+use super::{brokenpipe, io_other_error, simple_err, wouldblock, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, ReadDebt, Specifier};
+
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use tokio_io::{self, AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct FilterMsg<T: Specifier>(pub T, pub String);
+impl<T: Specifier> Specifier for FilterMsg<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let h = cp.tokio_handle.clone();
+        let cmd = Rc::new(self.1.clone());
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| filtermsg_peer(p, cmd.clone(), &opts, &h))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = FilterMsgClass,
+    target = FilterMsg,
+    prefixes = ["filtermsg:"],
+    arg_handling = {
+        fn construct(
+            self: &FilterMsgClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            match just_arg.find(':') {
+                Some(i) => Ok(Rc::new(FilterMsg(
+                    super::spec(&just_arg[i + 1..])?,
+                    just_arg[..i].to_string(),
+                ))),
+                None => Err("Expected filtermsg:<command>:<subspecifier>")?,
+            }
+        }
+    },
+    consumed_options = &["--filter-persistent", "--filter-concurrency", "--filter-direction"],
+    help = r#"
+Pass each message through an external command (run with `sh -c`).
+Argument is the command, then a colon, then subspecifier.
+The command can't contain a colon; use a script for complex cases.
+
+By default a new process is started for each message. It gets the message on
+stdin; its stdout becomes the replacement message. Exit code 0 means pass
+the output on, 1 means drop the message, anything else is a session error.
+At most --filter-concurrency (default 4) processes run at once per direction;
+messages keep their order.
+
+With --filter-persistent, one process per direction per session is started
+instead. Messages are exchanged with it as frames: 4-byte big-endian length,
+then data. It must reply to each frame with exactly one frame; an empty
+reply frame drops the message.
+
+Output longer than --buffer-size is a session error.
+
+--filter-direction in|out|both selects messages to filter: `in` is data
+read from the subspecifier, `out` is data written to it.
+
+Example: uppercase everything coming from a WebSocket server
+
+    websocat --filter-direction in - filtermsg:'tr a-z A-Z':ws://127.0.0.1:8080/
+"#
+);
+
+/// Filter for one message: `None` means the message should be dropped
+type Filtered = Box<Future<Item = Option<Vec<u8>>, Error = IoError>>;
+type Filter = Box<FnMut(Vec<u8>) -> Filtered>;
+type MessageStream = Box<Stream<Item = Vec<u8>, Error = IoError>>;
+
+fn sh(cmd: &str) -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(cmd);
+    c.stdin(Stdio::piped()).stdout(Stdio::piped());
+    c
+}
+
+fn too_long(max: usize) -> IoError {
+    simple_err(format!("filtermsg: output is longer than {} bytes (--buffer-size)", max))
+}
+
+/// Run the command once for the message
+fn filter_once(cmd: &str, msg: Vec<u8>, max: usize, h: &Handle) -> Filtered {
+    let mut child = match sh(cmd).spawn_async(h) {
+        Ok(x) => x,
+        Err(e) => return Box::new(futures::future::err(e)),
+    };
+    let stdin = child.stdin().take().unwrap();
+    let stdout = child.stdout().take().unwrap();
+    // Dropping stdin after writing signals end of the message
+    let w = tokio_io::io::write_all(stdin, msg).then(|r| match r {
+        Ok(_) => Ok(()),
+        // The command is not interested in the message
+        Err(ref e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e),
+    });
+    // One byte more than allowed is enough to tell it is too long
+    let r = tokio_io::io::read_to_end(stdout.take(max as u64 + 1), vec![]).and_then(move |(_, v)| {
+        if v.len() > max {
+            Err(too_long(max))
+        } else {
+            Ok(v)
+        }
+    });
+    Box::new(
+        w.join(r)
+            .and_then(move |((), out)| child.map(move |st| (st, out)))
+            .and_then(|(st, out)| match st.code() {
+                Some(0) => Ok(Some(out)),
+                Some(1) => Ok(None),
+                _ => Err(simple_err(format!("filtermsg: command failed with {}", st))),
+            }),
+    )
+}
+
+struct Coprocess {
+    stdin: tokio_process::ChildStdin,
+    stdout: tokio_process::ChildStdout,
+    _child: ChildHandle,
+}
+
+fn frame(msg: &[u8]) -> Vec<u8> {
+    let l = msg.len() as u32;
+    let mut v = Vec::with_capacity(msg.len() + 4);
+    v.extend_from_slice(&[(l >> 24) as u8, (l >> 16) as u8, (l >> 8) as u8, l as u8]);
+    v.extend_from_slice(msg);
+    v
+}
+
+/// Exchange one frame with the persistent coprocess. Messages go one at a time,
+/// so the coprocess is always in the slot when this is called.
+fn filter_persistent(slot: Rc<RefCell<Option<Coprocess>>>, msg: Vec<u8>, max: usize) -> Filtered {
+    let cp = match slot.borrow_mut().take() {
+        Some(x) => x,
+        None => return Box::new(futures::future::err(simple_err("filtermsg: coprocess is gone".to_string()))),
+    };
+    let Coprocess {
+        stdin,
+        stdout,
+        _child,
+    } = cp;
+    Box::new(
+        tokio_io::io::write_all(stdin, frame(&msg))
+            .and_then(|(stdin, _)| {
+                tokio_io::io::read_exact(stdout, [0u8; 4]).map(move |(stdout, h)| (stdin, stdout, h))
+            })
+            .and_then(move |(stdin, stdout, h)| {
+                let len = (h[0] as usize) << 24 | (h[1] as usize) << 16 | (h[2] as usize) << 8 | (h[3] as usize);
+                if len > max {
+                    return futures::future::Either::A(futures::future::err(too_long(max)));
+                }
+                futures::future::Either::B(
+                    tokio_io::io::read_exact(stdout, vec![0u8; len]).map(move |(stdout, v)| (stdin, stdout, v)),
+                )
+            })
+            .map(move |(stdin, stdout, v)| {
+                *slot.borrow_mut() = Some(Coprocess {
+                    stdin,
+                    stdout,
+                    _child,
+                });
+                if v.is_empty() {
+                    None
+                } else {
+                    Some(v)
+                }
+            })
+            .map_err(|e| {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    simple_err("filtermsg: coprocess exited".to_string())
+                } else {
+                    e
+                }
+            }),
+    )
+}
+
+fn make_filter(cmd: Rc<String>, opts: &Options, h: &Handle) -> Result<Filter, Box<::std::error::Error>> {
+    let max = BufferSettings::from_options(opts).size;
+    if !opts.filter_persistent {
+        let h = h.clone();
+        return Ok(Box::new(move |m| filter_once(&cmd, m, max, &h)));
+    }
+    let mut child = sh(&cmd).spawn_async(h)?;
+    let stdin = child.stdin().take().unwrap();
+    let stdout = child.stdout().take().unwrap();
+    let slot = Rc::new(RefCell::new(Some(Coprocess {
+        stdin,
+        stdout,
+        _child: ChildHandle::new(child, h, opts)?,
+    })));
+    Ok(Box::new(move |m| filter_persistent(slot.clone(), m, max)))
+}
+
+/// Messages passed through the filter, in order
+fn filtered(s: MessageStream, mut f: Filter, opts: &Options) -> MessageStream {
+    // Coprocess handles one message at a time
+    let n = match opts.filter_concurrency {
+        _ if opts.filter_persistent => 1,
+        0 => 1,
+        x => x,
+    };
+    Box::new(s.map(move |m| f(m)).buffered(n).filter_map(|x| x))
+}
+
+pub fn filtermsg_peer(inner_peer: Peer, cmd: Rc<String>, opts: &Options, h: &Handle) -> BoxedNewPeerFuture {
+    let (din, dout) = match parse_direction(&opts.filter_direction) {
+        Some(x) => x,
+        None => return super::peer_strerr("--filter-direction must be `in`, `out` or `both`"),
+    };
+    let r: Box<AsyncRead> = if din {
+        let f = match make_filter(cmd.clone(), opts, h) {
+            Ok(x) => x,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        let s = Box::new(ReaderStream(inner_peer.0, vec![0; 65536])) as MessageStream;
+        Box::new(FilterRead {
+            s: filtered(s, f, opts),
+            debt: Default::default(),
+        })
+    } else {
+        inner_peer.0
+    };
+    let w: Box<AsyncWrite> = if dout {
+        let f = match make_filter(cmd, opts, h) {
+            Ok(x) => x,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>(0);
+        let s = Box::new(receiver.map_err(|()| simple_err("filtermsg: channel error".to_string())))
+            as MessageStream;
+        let state = Rc::new(RefCell::new(PumpState {
+            result: None,
+            waiter: None,
+        }));
+        let state2 = state.clone();
+        let inner = inner_peer.1;
+        let pump = filtered(s, f, opts)
+            .fold(inner, |w, m| tokio_io::io::write_all(w, m).map(|(w, _)| w))
+            .and_then(|w| tokio_io::io::shutdown(w))
+            .then(move |r| {
+                let mut st = state2.borrow_mut();
+                st.result = Some(r.map(|_| ()));
+                if let Some(t) = st.waiter.take() {
+                    t.notify();
+                }
+                Ok::<(), ()>(())
+            });
+        h.spawn(pump);
+        Box::new(FilterWrite {
+            sender: Some(sender),
+            state,
+        })
+    } else {
+        inner_peer.1
+    };
+    Box::new(ok(Peer(r, w, None, None))) as BoxedNewPeerFuture
+}
+
+/// Reads from an `AsyncRead` as a stream of messages
+struct ReaderStream(Box<AsyncRead>, Vec<u8>);
+impl Stream for ReaderStream {
+    type Item = Vec<u8>;
+    type Error = IoError;
+    fn poll(&mut self) -> futures::Poll<Option<Vec<u8>>, IoError> {
+        match self.0.read(&mut self.1) {
+            Ok(0) => Ok(Ready(None)),
+            Ok(n) => Ok(Ready(Some(self.1[..n].to_vec()))),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+struct FilterRead {
+    s: MessageStream,
+    debt: ReadDebt,
+}
+
+impl Read for FilterRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        loop {
+            match self.s.poll()? {
+                Ready(Some(ref m)) if m.is_empty() => continue,
+                Ready(Some(m)) => return self.debt.process_message(buf, &m),
+                Ready(None) => return Ok(0),
+                NotReady => return wouldblock(),
+            }
+        }
+    }
+}
+impl AsyncRead for FilterRead {}
+
+/// Outcome of the background task writing filtered messages
+struct PumpState {
+    result: Option<Result<(), IoError>>,
+    waiter: Option<Task>,
+}
+
+struct FilterWrite {
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    state: Rc<RefCell<PumpState>>,
+}
+
+impl FilterWrite {
+    /// Error from the background task, if it failed
+    fn check(&mut self) -> Result<(), IoError> {
+        match self.state.borrow_mut().result {
+            Some(Err(ref e)) => Err(simple_err(format!("{}", e))),
+            Some(Ok(())) => brokenpipe(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for FilterWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.check()?;
+        let s = match self.sender {
+            Some(ref mut x) => x,
+            None => return brokenpipe(),
+        };
+        match s.start_send(buf.to_vec()).map_err(io_other_error)? {
+            AsyncSink::NotReady(_) => wouldblock(),
+            AsyncSink::Ready => Ok(buf.len()),
+        }
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.check()?;
+        if let Some(ref mut s) = self.sender {
+            if let NotReady = s.poll_complete().map_err(io_other_error)? {
+                return wouldblock();
+            }
+        }
+        Ok(())
+    }
+}
+impl AsyncWrite for FilterWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        if let Some(mut s) = self.sender.take() {
+            if let NotReady = s.poll_complete().map_err(io_other_error)? {
+                self.sender = Some(s);
+                return Ok(NotReady);
+            }
+            // Dropping the sender ends the stream of messages
+        }
+        let mut st = self.state.borrow_mut();
+        match st.result.take() {
+            Some(r) => r.map(Ready),
+            None => {
+                st.waiter = Some(futures::task::current());
+                Ok(NotReady)
+            }
+        }
+    }
+}
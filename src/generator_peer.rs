@@ -0,0 +1,132 @@
+use futures;
+use std;
+use std::io::Read;
+use std::rc::Rc;
+
+use tokio_io::AsyncRead;
+
+use super::trivial_peer::DevNull;
+use super::util::XorShift;
+use super::{once, BoxedNewPeerFuture, ConstructParams, Options, Peer, PeerConstructor, Specifier};
+
+#[derive(Clone, Debug)]
+pub struct Random(pub Option<u64>);
+impl Specifier for Random {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        once(get_random_peer(self.0, &cp.program_options))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
+}
+specifier_class!(
+    name = RandomClass,
+    target = Random,
+    prefixes = ["random:"],
+    arg_handling = {
+        fn construct(
+            self: &RandomClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            if just_arg.is_empty() {
+                return Ok(Rc::new(Random(None)));
+            }
+            let n = just_arg
+                .parse()
+                .map_err(|_| format!("random: expects a byte count, not `{}`", just_arg))?;
+            Ok(Rc::new(Random(Some(n))))
+        }
+    },
+    consumed_options = &["--gen-message-size", "--random-seed"],
+    help = r#"
+Generate pseudo-random bytes, discard input. Argument is the number of bytes
+to emit before EOF; without it, the data is endless.
+
+Not suitable for cryptography. Use --random-seed for reproducible data
+and --gen-message-size to set size of each chunk (message).
+
+Example: send 1 megabyte of random binary messages, 1000 bytes each
+
+    websocat --gen-message-size 1000 ws://127.0.0.1:8080 random:1000000
+"#
+);
+
+#[derive(Clone, Debug)]
+pub struct Zero;
+impl Specifier for Zero {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        once(get_zero_peer(&cp.program_options))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
+}
+specifier_class!(
+    name = ZeroClass,
+    target = Zero,
+    prefixes = ["zero:"],
+    arg_handling = noarg,
+    consumed_options = &["--gen-message-size"],
+    help = r#"
+Generate endless stream of zero bytes, discard input.
+Use --gen-message-size to set size of each chunk (message).
+
+Example: measure throughput of a WebSocket echo server
+
+    websocat ws://127.0.0.1:8080 zero:
+"#
+);
+
+/// Reader that emits `left` bytes (or endlessly), in chunks of at most `chunk` bytes
+struct Generator {
+    rng: Option<XorShift>,
+    left: Option<u64>,
+    chunk: Option<usize>,
+}
+
+fn generator(rng: Option<XorShift>, count: Option<u64>, opts: &Options) -> BoxedNewPeerFuture {
+    let r = Generator {
+        rng,
+        left: count,
+        chunk: opts.gen_message_size,
+    };
+    Box::new(futures::future::ok(Peer::new(r, DevNull))) as BoxedNewPeerFuture
+}
+
+pub fn get_random_peer(count: Option<u64>, opts: &Options) -> BoxedNewPeerFuture {
+    let rng = match opts.random_seed {
+        Some(x) => XorShift::with_seed(x),
+        None => XorShift::new(),
+    };
+    generator(Some(rng), count, opts)
+}
+
+pub fn get_zero_peer(opts: &Options) -> BoxedNewPeerFuture {
+    generator(None, None, opts)
+}
+
+impl Read for Generator {
+    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
+        let mut n = buf.len();
+        if let Some(c) = self.chunk {
+            n = n.min(c.max(1));
+        }
+        if let Some(left) = self.left {
+            if left < n as u64 {
+                n = left as usize;
+            }
+            self.left = Some(left - n as u64);
+        }
+        let buf = &mut buf[..n];
+        match self.rng {
+            Some(ref mut rng) => for c in buf.chunks_mut(8) {
+                let x = rng.next_u64();
+                for (i, b) in c.iter_mut().enumerate() {
+                    *b = (x >> (i * 8)) as u8;
+                }
+            },
+            None => for b in buf.iter_mut() {
+                *b = 0;
+            },
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for Generator {}
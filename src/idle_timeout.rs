@@ -0,0 +1,141 @@
+//! Session-level inactivity timeout (`--idle-timeout`)
+
+use futures;
+use futures::Async::{NotReady, Ready};
+use futures::Future;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::ws_peer::with_close_code;
+
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// WebSocket status code "going away", sent when closing an idle session
+const IDLE_CLOSE_CODE: u16 = 1001;
+
+pub struct IdleState {
+    last: Instant,
+    timeout: Duration,
+    timer: Option<Timeout>,
+    handle: Handle,
+    fired: bool,
+}
+
+pub type HIdleState = Rc<RefCell<IdleState>>;
+
+impl IdleState {
+    pub fn new(timeout: Duration, h: &Handle) -> HIdleState {
+        Rc::new(RefCell::new(IdleState {
+            last: Instant::now(),
+            timeout,
+            timer: None,
+            handle: h.clone(),
+            fired: false,
+        }))
+    }
+
+    fn touch(&mut self) {
+        self.last = Instant::now();
+    }
+
+    pub fn fired(&self) -> bool {
+        self.fired
+    }
+
+    /// Check if there was no activity for too long. Otherwise
+    /// arrange for the current task to be woken up when the deadline comes.
+    fn poll_fired(&mut self) -> Result<bool, IoError> {
+        loop {
+            if self.fired {
+                return Ok(true);
+            }
+            let due = self.last + self.timeout;
+            let now = Instant::now();
+            if due <= now {
+                info!("No activity for {} seconds, closing", self.timeout.as_secs());
+                super::metrics::set_end_reason("idle timeout");
+                self.fired = true;
+                self.timer = None;
+                continue;
+            }
+            if self.timer.is_none() {
+                self.timer = Some(Timeout::new(due - now, &self.handle)?);
+            }
+            match self.timer.as_mut().unwrap().poll()? {
+                // Activity may have moved the deadline, check again
+                Ready(()) => self.timer = None,
+                NotReady => return Ok(false),
+            }
+        }
+    }
+}
+
+/// Reader that reports EOF after the timeout. `counts` tells whether
+/// data read from it counts as activity.
+pub struct IdleRead {
+    pub inner: Box<AsyncRead>,
+    pub state: HIdleState,
+    pub counts: bool,
+}
+
+impl Read for IdleRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.state.borrow_mut().poll_fired()? {
+            return Ok(0);
+        }
+        let ret = self.inner.read(buf);
+        if self.counts {
+            if let Ok(n) = ret {
+                if n > 0 {
+                    self.state.borrow_mut().touch();
+                }
+            }
+        }
+        ret
+    }
+}
+impl AsyncRead for IdleRead {}
+
+/// Writer that closes WebSocket peers with "going away" status after the timeout
+pub struct IdleWrite {
+    pub inner: Box<AsyncWrite>,
+    pub state: HIdleState,
+}
+
+impl Write for IdleWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for IdleWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        if self.state.borrow().fired {
+            let inner = &mut self.inner;
+            return with_close_code(IDLE_CLOSE_CODE, || inner.shutdown());
+        }
+        self.inner.shutdown()
+    }
+}
+
+const IDLE_TIMEOUT_MSG: &str = "Idle timeout";
+
+/// Error the session ends with after it was closed due to inactivity
+pub fn idle_timeout_error() -> IoError {
+    IoError::new(ErrorKind::TimedOut, IDLE_TIMEOUT_MSG)
+}
+
+/// Check if a session ended because of `--idle-timeout`
+pub fn is_idle_timeout(e: &(::std::error::Error + 'static)) -> bool {
+    match e.downcast_ref::<IoError>() {
+        Some(x) => x.kind() == ErrorKind::TimedOut && x.to_string() == IDLE_TIMEOUT_MSG,
+        None => false,
+    }
+}
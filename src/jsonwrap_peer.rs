@@ -0,0 +1,210 @@
+extern crate base64;
+extern crate serde_json;
+
+use futures;
+use futures::future::ok;
+
+use std::rc::Rc;
+
+use super::{BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, PeerConstructor, ReadDebt, Specifier, WriteDebt};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use self::serde_json::Value;
+use super::bufpool;
+use super::util::rfc3339_now;
+use super::ws_peer::{note_message_kind, Mode1};
+
+#[derive(Debug)]
+pub struct JsonWrap<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for JsonWrap<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let unwrap_input = cp.program_options.jsonl_input;
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| jsonwrap_peer(p, unwrap_input))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = JsonWrapClass,
+    target = JsonWrap,
+    prefixes = ["jsonwrap:"],
+    arg_handling = subspec,
+    consumed_options = &["--jsonl-input"],
+    help = r#"
+Render each message written to the subspecifier as one line of JSON:
+
+    {"ts":"2024-05-01T12:00:00.123Z","dir":"in","type":"text","data":"..."}
+
+Messages that are not valid UTF-8 are "binary", with base64 payload in `data_b64`.
+
+Data read from the subspecifier is passed as is, unless --jsonl-input is set.
+Then each line is expected to be a JSON object like above, its `data` (or `data_b64`)
+becomes a message. `type`, if present, is "text" or "binary" and sets the
+WebSocket message type instead of --text. Text messages must be valid UTF-8.
+Malformed lines are reported with their line number and skipped.
+
+Intended to be used on the local side.
+
+Example: feed incoming messages to jq
+
+    websocat -U jsonwrap:- ws://127.0.0.1:8080/feed | jq .data
+"#
+);
+
+pub fn jsonwrap_peer(inner_peer: Peer, unwrap_input: bool) -> BoxedNewPeerFuture {
+    let r = JsonWrapRead {
+        inner: inner_peer.0,
+        unwrap_input,
+        queue: vec![],
+        debt: Default::default(),
+        lineno: 0,
+        kind: None,
+    };
+    let w = JsonWrapWrite {
+        inner: inner_peer.1,
+        debt: Default::default(),
+    };
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+/// Serialize one message as a JSON line
+pub fn wrap_message(dir: &str, data: &[u8]) -> Vec<u8> {
+    let q = |v: &mut Vec<u8>, x: &str| serde_json::to_writer(v, x).expect("Assertion failed 4521");
+    let mut v = bufpool::take(data.len() + 80).into_vec();
+    v.extend_from_slice(br#"{"ts":"#);
+    q(&mut v, &rfc3339_now());
+    v.extend_from_slice(br#","dir":"#);
+    q(&mut v, dir);
+    match ::std::str::from_utf8(data) {
+        Ok(t) => {
+            v.extend_from_slice(br#","type":"text","data":"#);
+            q(&mut v, t);
+        }
+        Err(_) => {
+            // Empty, hence valid UTF-8
+            let mut b64 = String::from_utf8(bufpool::take(data.len() / 3 * 4 + 4).into_vec()).unwrap();
+            base64::encode_config_buf(data, base64::STANDARD, &mut b64);
+            v.extend_from_slice(br#","type":"binary","data_b64":"#);
+            q(&mut v, &b64);
+            bufpool::recycle(b64.into_bytes());
+        }
+    }
+    v.extend_from_slice(b"}\n");
+    v
+}
+
+/// Extract message payload and its type, if specified, from a JSON line
+pub fn unwrap_message(line: &[u8]) -> Result<(Vec<u8>, Option<Mode1>), String> {
+    let v: Value = serde_json::from_slice(line).map_err(|e| format!("{}", e))?;
+    let kind = match v.get("type") {
+        None => None,
+        Some(&Value::String(ref t)) if t == "text" => Some(Mode1::Text),
+        Some(&Value::String(ref t)) if t == "binary" => Some(Mode1::Binary),
+        Some(_) => return Err("`type` is neither \"text\" nor \"binary\"".to_string()),
+    };
+    let data = if let Some(x) = v.get("data_b64") {
+        let b = x.as_str().ok_or("`data_b64` is not a string")?;
+        base64::decode(b).map_err(|e| format!("bad base64 in `data_b64`: {}", e))?
+    } else {
+        match v.get("data") {
+            Some(&Value::String(ref s)) => s.as_bytes().to_vec(),
+            Some(_) => return Err("`data` is not a string".to_string()),
+            None => return Err("Neither `data` nor `data_b64` is present".to_string()),
+        }
+    };
+    if let Some(Mode1::Text) = kind {
+        if ::std::str::from_utf8(&data).is_err() {
+            return Err("text message is not valid UTF-8".to_string());
+        }
+    }
+    Ok((data, kind))
+}
+
+struct JsonWrapRead {
+    inner: Box<AsyncRead>,
+    unwrap_input: bool,
+    queue: Vec<u8>,
+    debt: ReadDebt,
+    lineno: usize,
+    /// Type of the message being returned, for the rest of it in `debt`
+    kind: Option<Mode1>,
+}
+
+impl JsonWrapRead {
+    fn note_kind(&self) {
+        if let Some(k) = self.kind {
+            note_message_kind(k);
+        }
+    }
+}
+
+impl Read for JsonWrapRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if !self.unwrap_input {
+            return self.inner.read(buf);
+        }
+        if let Some(ret) = self.debt.check_debt(buf) {
+            self.note_kind();
+            return ret;
+        }
+        loop {
+            if let Some(i) = self.queue.iter().position(|&x| x == b'\n') {
+                let mut line = bufpool::take(i + 1);
+                line.extend(self.queue.drain(0..(i + 1)));
+                self.lineno += 1;
+                let line = &line[..i];
+                if line.iter().all(|x| (*x as char).is_whitespace()) {
+                    continue;
+                }
+                match unwrap_message(line) {
+                    Ok((ref x, _)) if x.is_empty() => {
+                        warn!("Line {}: empty messages are not supported", self.lineno);
+                    }
+                    Ok((x, kind)) => {
+                        self.kind = kind;
+                        self.note_kind();
+                        return self.debt.process_owned(buf, x);
+                    }
+                    Err(e) => error!("Line {}: {}", self.lineno, e),
+                }
+                continue;
+            }
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                if !self.queue.is_empty() {
+                    // Last line without trailing newline
+                    self.queue.push(b'\n');
+                    continue;
+                }
+                return Ok(0);
+            }
+            self.queue.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+impl AsyncRead for JsonWrapRead {}
+
+struct JsonWrapWrite {
+    inner: Box<AsyncWrite>,
+    debt: WriteDebt,
+}
+
+impl Write for JsonWrapWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.debt
+            .write_frame(&mut self.inner, || Ok(wrap_message("in", buf)))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for JsonWrapWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
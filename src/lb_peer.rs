@@ -0,0 +1,242 @@
+use futures::future::Future;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::util::XorShift;
+use super::{once, peer_strerr, BoxedNewPeerFuture};
+use super::{ConstructParams, PeerConstructor, Specifier};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LbPolicy {
+    RoundRobin,
+    Random,
+    FirstAvailable,
+}
+
+impl LbPolicy {
+    pub fn from_str(s: &str) -> Option<LbPolicy> {
+        match s {
+            "" | "roundrobin" => Some(LbPolicy::RoundRobin),
+            "random" => Some(LbPolicy::Random),
+            "first-available" => Some(LbPolicy::FirstAvailable),
+            _ => None,
+        }
+    }
+}
+
+/// Health of backends, shared by all sessions using the same `lb:` or `failover:` specifier
+struct LbState {
+    next: usize,
+    quarantined_until: Vec<Option<Instant>>,
+    rng: XorShift,
+}
+
+struct Backends {
+    /// Specifier prefix, for messages
+    kind: &'static str,
+    names: Vec<String>,
+    specs: Vec<Rc<Specifier>>,
+    state: RefCell<LbState>,
+}
+
+pub struct Lb(Rc<Backends>);
+
+impl ::std::fmt::Debug for Lb {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Lb({:?})", self.0.specs)
+    }
+}
+
+impl Backends {
+    /// Parse `|`-separated list of specifiers
+    fn parse(kind: &'static str, arg: &str) -> super::Result<Rc<Backends>> {
+        let names: Vec<String> = arg.split('|').map(|x| x.to_string()).collect();
+        let mut specs = Vec::with_capacity(names.len());
+        for n in &names {
+            if n.is_empty() {
+                Err(format!("{} empty target in the list", kind))?
+            }
+            specs.push(super::spec(n)?);
+        }
+        let state = LbState {
+            next: 0,
+            quarantined_until: vec![None; names.len()],
+            rng: XorShift::new(),
+        };
+        Ok(Rc::new(Backends {
+            kind,
+            names,
+            specs,
+            state: RefCell::new(state),
+        }))
+    }
+
+    /// Backend indexes in the order they should be tried for a new session.
+    /// Quarantined backends go last, to be tried only if all others fail.
+    fn order(&self, policy: LbPolicy) -> Vec<usize> {
+        let mut s = self.state.borrow_mut();
+        let n = self.specs.len();
+        let start = match policy {
+            LbPolicy::RoundRobin => {
+                let x = s.next % n;
+                s.next = x + 1;
+                x
+            }
+            LbPolicy::Random => s.rng.below(n as u64) as usize,
+            LbPolicy::FirstAvailable => 0,
+        };
+        let now = Instant::now();
+        let (mut healthy, sick): (Vec<usize>, Vec<usize>) = (0..n)
+            .map(|i| (start + i) % n)
+            .partition(|&i| match s.quarantined_until[i] {
+                Some(t) => t <= now,
+                None => true,
+            });
+        healthy.extend(sick);
+        healthy
+    }
+
+    fn uses_global_state(&self) -> bool {
+        self.specs.iter().any(|x| x.uses_global_state())
+    }
+}
+
+impl Specifier for Lb {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let policy = match LbPolicy::from_str(&opts.lb_policy) {
+            Some(x) => x,
+            None => {
+                return once(peer_strerr(
+                    "--lb-policy must be `roundrobin`, `random` or `first-available`",
+                ))
+            }
+        };
+        let order = self.0.order(policy);
+        let quarantine = Duration::from_secs(opts.lb_quarantine);
+        once(try_backends(self.0.clone(), order, quarantine, cp))
+    }
+    fn is_multiconnect(&self) -> bool {
+        false
+    }
+    fn uses_global_state(&self) -> bool {
+        self.0.uses_global_state()
+    }
+    fn is_shared_between_sessions(&self) -> bool {
+        true
+    }
+    specifier_boilerplate!(typ=Other no_subspec);
+}
+specifier_class!(
+    name = LbClass,
+    target = Lb,
+    prefixes = ["lb:"],
+    arg_handling = {
+        fn construct(self: &LbClass, _full: &str, just_arg: &str) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(Lb(Backends::parse("lb:", just_arg)?)))
+        }
+    },
+    consumed_options = &["--lb-policy", "--lb-quarantine"],
+    help = r#"
+Distribute sessions across several backends, given as a `|`-separated list
+of specifiers. Each new session connects to the next backend according to
+--lb-policy: `roundrobin` (default), `random` or `first-available`.
+
+If connecting to a backend fails, the next one is tried and the failed one is
+quarantined for --lb-quarantine seconds (default 10): it is tried only after
+all healthy ones. Backend health is shared by all sessions.
+
+Example:
+
+    websocat ws-l:0.0.0.0:8080 lb:'tcp:10.0.0.1:9000|tcp:10.0.0.2:9000|unix:/run/local.sock'
+"#
+);
+
+fn try_backends(
+    b: Rc<Backends>,
+    mut order: Vec<usize>,
+    quarantine: Duration,
+    cp: ConstructParams,
+) -> BoxedNewPeerFuture {
+    if order.is_empty() {
+        return peer_strerr(&format!("{} no targets left", b.kind));
+    }
+    let i = order.remove(0);
+    info!("{} trying {}", b.kind, b.names[i]);
+    let attempt = b.specs[i].construct(cp.clone()).get_only_first_conn();
+    Box::new(attempt.then(move |r| match r {
+        Ok(p) => {
+            info!("{} session is served by {}", b.kind, b.names[i]);
+            b.state.borrow_mut().quarantined_until[i] = None;
+            cp.left_to_right.info().borrow_mut().served_by = Some(b.names[i].clone());
+            Box::new(::futures::future::ok(p)) as BoxedNewPeerFuture
+        }
+        Err(e) => {
+            warn!("{} {} failed: {}", b.kind, b.names[i], e);
+            if order.is_empty() {
+                let msg = format!("{} all targets failed, last error: {}", b.kind, e);
+                return peer_strerr(&msg);
+            }
+            b.state.borrow_mut().quarantined_until[i] = Some(Instant::now() + quarantine);
+            try_backends(b, order, quarantine, cp)
+        }
+    })) as BoxedNewPeerFuture
+}
+
+pub struct Failover(Rc<Backends>);
+
+impl ::std::fmt::Debug for Failover {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Failover({:?})", self.0.specs)
+    }
+}
+
+impl Specifier for Failover {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let order = self.0.order(LbPolicy::FirstAvailable);
+        let cooldown = Duration::from_secs(cp.program_options.failover_cooldown);
+        once(try_backends(self.0.clone(), order, cooldown, cp))
+    }
+    fn is_multiconnect(&self) -> bool {
+        false
+    }
+    fn uses_global_state(&self) -> bool {
+        self.0.uses_global_state()
+    }
+    fn is_shared_between_sessions(&self) -> bool {
+        true
+    }
+    specifier_boilerplate!(typ=Other no_subspec);
+}
+specifier_class!(
+    name = FailoverClass,
+    target = Failover,
+    prefixes = ["failover:"],
+    arg_handling = {
+        fn construct(
+            self: &FailoverClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(Failover(Backends::parse("failover:", just_arg)?)))
+        }
+    },
+    consumed_options = &["--failover-cooldown"],
+    help = r#"
+Connect to the first working target from a `|`-separated list of specifiers,
+trying them strictly in order for each session. A target that failed
+is skipped for --failover-cooldown seconds (default 30), unless all
+other targets fail too.
+
+Only connection establishment (including WebSocket handshake) is covered;
+a session that breaks later is not moved to another target.
+Wrap it in `autoreconnect:` for that: each reconnect starts from the primary
+target again, unless it is cooling down.
+
+Example:
+
+    websocat - autoreconnect:failover:'wss://primary/ws|wss://backup/ws'
+"#
+);
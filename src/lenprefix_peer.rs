@@ -0,0 +1,228 @@
+use futures::future::ok;
+
+use std::rc::Rc;
+
+use super::{BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, ReadDebt, Specifier, WriteDebt};
+
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct LengthPrefix<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for LengthPrefix<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| lenprefix_peer(p, &opts))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = LengthPrefixClass,
+    target = LengthPrefix,
+    prefixes = ["lenprefix:"],
+    arg_handling = subspec,
+    consumed_options = &[
+        "--lenprefix-bytes",
+        "--lenprefix-little-endian",
+        "--lenprefix-includes-header",
+        "--lenprefix-max",
+    ],
+    help = r#"
+Message framing filter: convert between messages and a byte stream of
+length-prefixed frames. Each write becomes a big-endian length (4 bytes
+by default) followed by the payload. Reads return one frame payload at a time,
+reassembling frames split across underlying reads.
+
+Options: --lenprefix-bytes 1|2|4|8, --lenprefix-little-endian,
+--lenprefix-includes-header (length counts the prefix itself),
+--lenprefix-max (reject bigger incoming frames, 1 MiB by default).
+
+Zero-length incoming frames are skipped. Oversized frames are errors.
+
+Example: map a binary TCP protocol's frames to WebSocket messages
+
+    websocat ws-l:127.0.0.1:8080 lenprefix:tcp:127.0.0.1:9000
+"#
+);
+
+/// `--lenprefix-max` when the option is 0, like in `Options::default()`
+const DEFAULT_MAX: usize = 1 << 20;
+
+#[derive(Clone, Copy)]
+struct Framing {
+    nbytes: usize,
+    little_endian: bool,
+    includes_header: bool,
+    max: usize,
+}
+
+impl Framing {
+    fn from_options(opts: &Options) -> Result<Framing, IoError> {
+        let nbytes = match opts.lenprefix_bytes {
+            0 => 4,
+            x @ 1 | x @ 2 | x @ 4 | x @ 8 => x,
+            _ => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    "--lenprefix-bytes must be 1, 2, 4 or 8",
+                ))
+            }
+        };
+        Ok(Framing {
+            nbytes,
+            little_endian: opts.lenprefix_little_endian,
+            includes_header: opts.lenprefix_includes_header,
+            max: match opts.lenprefix_max {
+                0 => DEFAULT_MAX,
+                x => x,
+            },
+        })
+    }
+
+    /// Payload length from the prefix. The whole frame, prefix included, fits in `usize`.
+    fn decode_payload_len(&self, header: &[u8]) -> Result<usize, IoError> {
+        let mut l: u64 = 0;
+        for i in 0..self.nbytes {
+            let b = if self.little_endian {
+                header[self.nbytes - 1 - i]
+            } else {
+                header[i]
+            };
+            l = (l << 8) | (b as u64);
+        }
+        if self.includes_header {
+            if l < self.nbytes as u64 {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    "Frame length is smaller than the length prefix itself",
+                ));
+            }
+            l -= self.nbytes as u64;
+        }
+        if l > self.max as u64 {
+            error!("Incoming frame of {} bytes exceeds --lenprefix-max", l);
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "Length-prefixed frame is too big",
+            ));
+        }
+        if l > (usize::max_value() - self.nbytes) as u64 {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "Length-prefixed frame is too big for this platform",
+            ));
+        }
+        Ok(l as usize)
+    }
+
+    fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, IoError> {
+        let mut l = payload.len() as u64;
+        if self.includes_header {
+            l += self.nbytes as u64;
+        }
+        if self.nbytes < 8 && l >= 1u64 << (8 * self.nbytes) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "Message is too big for the length prefix size",
+            ));
+        }
+        let mut v = Vec::with_capacity(self.nbytes + payload.len());
+        for i in 0..self.nbytes {
+            let shift = if self.little_endian {
+                8 * i
+            } else {
+                8 * (self.nbytes - 1 - i)
+            };
+            v.push((l >> shift) as u8);
+        }
+        v.extend_from_slice(payload);
+        Ok(v)
+    }
+}
+
+pub fn lenprefix_peer(inner_peer: Peer, opts: &Options) -> BoxedNewPeerFuture {
+    let framing = match Framing::from_options(opts) {
+        Ok(x) => x,
+        Err(e) => return super::peer_err(e),
+    };
+    let r = LengthPrefixRead {
+        inner: inner_peer.0,
+        queue: vec![],
+        debt: Default::default(),
+        framing,
+    };
+    let w = LengthPrefixWrite {
+        inner: inner_peer.1,
+        debt: Default::default(),
+        framing,
+    };
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+struct LengthPrefixRead {
+    inner: Box<AsyncRead>,
+    queue: Vec<u8>,
+    debt: ReadDebt,
+    framing: Framing,
+}
+
+impl Read for LengthPrefixRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        let hl = self.framing.nbytes;
+        loop {
+            if self.queue.len() >= hl {
+                let l = self.framing.decode_payload_len(&self.queue[..hl])?;
+                if self.queue.len() >= hl + l {
+                    let frame: Vec<u8> = self.queue.drain(0..(hl + l)).skip(hl).collect();
+                    if l == 0 {
+                        debug!("Skipping zero-length frame");
+                        continue;
+                    }
+                    return self.debt.process_message(buf, &frame);
+                }
+            }
+
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                if self.queue.len() != 0 {
+                    warn!(
+                        "Throwing away {} bytes of incomplete frame",
+                        self.queue.len()
+                    );
+                }
+                return Ok(0);
+            }
+            self.queue.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+impl AsyncRead for LengthPrefixRead {}
+
+struct LengthPrefixWrite {
+    inner: Box<AsyncWrite>,
+    debt: WriteDebt,
+    framing: Framing,
+}
+
+impl Write for LengthPrefixWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let framing = self.framing;
+        self.debt
+            .write_frame(&mut self.inner, || framing.encode(buf))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for LengthPrefixWrite {
+    fn shutdown(&mut self) -> ::futures::Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
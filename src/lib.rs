@@ -7,6 +7,9 @@
 //! 3. `PeerConstructor` - a future or stream that returns one or more connections. After completion, we get one or more of:
 //! 4. `Peer` - an active connection. Once we have two of them, we can start a:
 //! 5. `Session` with two `Transfer`s - forward and reverse.
+//!
+//! To run all of this from another program, start with `builder::WebsocatBuilder`.
+//! To use just one specifier as a byte stream, see `embed`.
 
 extern crate futures;
 extern crate tokio_core;
@@ -78,6 +81,182 @@ pub struct Options {
     pub websocket_version: Option<String>,
     pub websocket_dont_close: bool,
     pub one_message: bool,
+    pub close_timeout: Option<u64>,
+    pub lenprefix_bytes: usize,
+    pub lenprefix_little_endian: bool,
+    pub lenprefix_includes_header: bool,
+    pub lenprefix_max: usize,
+    pub log_file: Option<String>,
+    pub log_raw: bool,
+    pub log_base64: bool,
+    pub log_max_size: u64,
+    pub jsonl_input: bool,
+    pub binary_as_base64: bool,
+    pub throttle_bytes_per_sec: u64,
+    pub throttle_messages_per_sec: u64,
+    pub throttle_burst: u64,
+    pub throttle_direction: String,
+    pub delay_ms: u64,
+    pub delay_jitter_ms: u64,
+    pub delay_max_queued: usize,
+    pub delay_direction: String,
+    pub record_file: Option<String>,
+    pub replay_no_timing: bool,
+    pub replay_speed: f64,
+    pub replay_strict: bool,
+    pub idle_timeout: Option<u64>,
+    pub idle_timeout_direction: String,
+    pub separator: String,
+    pub separator_n: usize,
+    pub separator_conflict: String,
+    pub line_escape: Option<String>,
+    pub random_seed: Option<u64>,
+    pub gen_message_size: Option<usize>,
+    pub count_output: Option<String>,
+    pub seqnum_strict: bool,
+    pub crc_algo: String,
+    pub crc_strict: bool,
+    pub broadcast_retain: bool,
+    pub broadcast_retain_count: Option<usize>,
+    pub broadcast_retain_max_bytes: usize,
+    pub retain_across_reconnect: bool,
+    pub reuse_buffer: Option<usize>,
+    /// 1 MiB if not set
+    pub reuse_buffer_bytes: Option<usize>,
+    /// `drop-oldest` if empty
+    pub reuse_buffer_overflow: String,
+    pub lb_policy: String,
+    pub lb_quarantine: u64,
+    pub failover_cooldown: u64,
+    pub listen_best_effort: bool,
+    pub exec_arg: Vec<String>,
+    pub exec_env: Vec<(String, String)>,
+    pub exec_clearenv: bool,
+    pub exec_chdir: Option<String>,
+    pub exec_umask: Option<u32>,
+    pub exec_pty: bool,
+    pub pty_size: Option<String>,
+    pub pty_resize_prefix: Option<String>,
+    pub exec_kill_signal: String,
+    pub exec_kill_timeout: Option<u64>,
+    pub exec_no_kill: bool,
+    pub exit_status_from_exec: bool,
+    pub env_headers: Vec<String>,
+    pub file_append: bool,
+    pub file_create_new: bool,
+    pub file_truncate: bool,
+    pub file_sync: bool,
+    pub file_start_offset: Option<i64>,
+    pub file_max_bytes: Option<u64>,
+    pub file_follow: bool,
+    pub mirror_delay_ms: u64,
+    pub mirror_drop_rate: f64,
+    pub mirror_corrupt_rate: f64,
+    pub mirror_dup_rate: f64,
+    pub mirror_close_after: Option<u64>,
+    pub clog_direction: String,
+    pub clog_after_bytes: u64,
+    pub clog_duration: Option<u64>,
+    pub assert_allow_extra: bool,
+    pub assert_timeout: Option<u64>,
+    pub filter_persistent: bool,
+    pub filter_concurrency: usize,
+    pub filter_direction: String,
+    pub filter_in_regex: Option<String>,
+    pub filter_out_regex: Option<String>,
+    pub filter_mode: String,
+    pub rewrite: Vec<String>,
+    pub rewrite_direction: String,
+    pub rewrite_binary: bool,
+    pub rewrite_max_size: Option<usize>,
+    pub chunk_header: String,
+    pub batch_window_ms: Option<u64>,
+    pub batch_max_bytes: Option<usize>,
+    pub batch_max_count: Option<usize>,
+    pub batch_separator: Vec<u8>,
+    pub batch_direction: String,
+    pub dedup_consecutive: bool,
+    pub dedup_window: Option<usize>,
+    pub dedup_direction: String,
+    pub dedup_max_silence: Option<u64>,
+    pub max_messages_in: Option<u64>,
+    pub max_messages_out: Option<u64>,
+    pub max_bytes_in: Option<u64>,
+    pub max_bytes_out: Option<u64>,
+    pub metrics_addr: Option<std::net::SocketAddr>,
+    pub lazy_connect: bool,
+    pub lazy_buffer_bytes: usize,
+    pub total_max_bytes: Option<u64>,
+    pub total_max_messages: Option<u64>,
+    pub total_max_duration: Option<u64>,
+    pub drain_timeout: u64,
+    pub stats: bool,
+    pub stats_json: Option<String>,
+    pub pid_file: Option<std::path::PathBuf>,
+    /// Certificate and key for `ssl-accept:`
+    pub pkcs12_der: Option<std::path::PathBuf>,
+    pub pkcs12_passwd: Option<String>,
+    pub shutdown_close_code: u16,
+    pub max_sessions: Option<u64>,
+    pub max_sessions_backpressure: bool,
+    pub log_format: String,
+    /// Kept apart from `log_file`, so that traffic dumps don't split event lines
+    pub events_file: Option<String>,
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+    pub hook_max_concurrent: usize,
+    pub readline: bool,
+    pub no_close_on_stdin_eof: bool,
+    pub close_stdin_only: bool,
+    pub timestamps: Option<String>,
+    pub timestamp_separator: String,
+    pub notify_fd: Option<i32>,
+    pub flush_after_message: bool,
+    pub flush_interval_ms: Option<u64>,
+    pub no_flush: bool,
+    pub include_headers: bool,
+    pub include_headers_every_connect: bool,
+    pub response_header_file: Option<std::path::PathBuf>,
+    pub no_splice: bool,
+    /// Read buffer of the transfer loop, 64 KiB if not set
+    pub buffer_size: Option<usize>,
+    pub high_watermark: Option<usize>,
+    /// 200 if not set, 0 disables corking
+    pub cork_window_us: Option<u64>,
+    /// Threads serving a TCP listener besides the main one, see `workers`
+    pub workers: Option<usize>,
+    /// Spare connections kept by `autoreconnect:`
+    pub preconnect: usize,
+    /// Seconds after which spares are replaced, 0 for never
+    pub preconnect_max_age: u64,
+    /// Read ahead into one fixed buffer per direction, not keeping message boundaries
+    pub raw_relay: bool,
+    /// Clients served at once by `pipe-l:`, 1 if not set
+    pub pipe_instances: Option<usize>,
+    /// Security descriptor of pipes created by `pipe-l:`
+    pub pipe_sddl: Option<String>,
+    /// Message-type named pipes
+    pub pipe_message_mode: bool,
+    /// Drop the rest of a datagram that got sent only partially instead of failing
+    pub allow_partial_datagrams: bool,
+    /// Times to retry binding a listener whose address is in use
+    pub bind_retry: usize,
+    /// Milliseconds between bind attempts
+    pub bind_retry_delay: u64,
+    /// Seconds to wait for queued WebSocket output to be sent on shutdown, 0 for no limit
+    pub flush_timeout: Option<u64>,
+    /// Send path and query of WebSocket URLs as written, without percent-encoding them
+    pub no_url_normalization: bool,
+    /// Set by library users, see `lifecycle`
+    pub callbacks: lifecycle::CallbackList,
+    /// Set by `builder::WebsocatBuilder::run_cancellable`
+    pub shutdown_scope: Option<shutdown::Scope>,
+    /// Set by library users, see `middleware`
+    pub middleware: middleware::Middleware,
+    /// Left specifier as given on the command line, for `WEBSOCAT_LISTEN_SPEC` and error messages
+    pub listen_spec: Option<String>,
+    /// Right specifier as given on the command line, for error messages
+    pub right_spec: Option<String>,
 }
 
 #[derive(Default)]
@@ -87,18 +266,49 @@ pub struct ProgramState {
 
     reuser: primitive_reuse_peer::GlobalState,
     reuser2: broadcast_reuse_peer::GlobalState,
+    traffic_log: log_peer::GlobalState,
+    recorder: record_peer::GlobalState,
 }
 
-/// Some information passed from the left specifier Peer to the right
+/// Some information passed from the left specifier Peer to the right.
+/// Listeners attach it to each accepted `Peer`, the right specifier
+/// of that session gets a copy.
 #[derive(Default, Clone)]
-pub struct LeftSpecToRightSpec {}
+pub struct LeftSpecToRightSpec {
+    /// Target chosen by `lb:` or `failover:` for this session
+    pub served_by: Option<String>,
+    /// Peer address of the accepted connection, if known
+    pub client_addr: Option<String>,
+    /// Request URI of the accepted WebSocket connection
+    pub uri: Option<String>,
+    /// Request headers selected by `--env-headers`
+    pub headers: Vec<(String, String)>,
+    /// Which of the `multilisten:` listeners accepted the connection
+    pub listener: Option<usize>,
+}
 #[derive(Clone)]
 enum L2rUser {
     FillIn(Rc<RefCell<LeftSpecToRightSpec>>),
     ReadFrom(Rc<RefCell<LeftSpecToRightSpec>>),
 }
 
-pub struct Peer(Box<AsyncRead>, Box<AsyncWrite>);
+impl L2rUser {
+    fn info(&self) -> &Rc<RefCell<LeftSpecToRightSpec>> {
+        match *self {
+            L2rUser::FillIn(ref x) => x,
+            L2rUser::ReadFrom(ref x) => x,
+        }
+    }
+}
+
+/// Reading and writing halves, with the file descriptor behind them if it is a plain byte stream
+/// and what the listener knows about the client, if it was accepted from one
+pub struct Peer(
+    Box<AsyncRead>,
+    Box<AsyncWrite>,
+    Option<splice::PeerFd>,
+    Option<Rc<LeftSpecToRightSpec>>,
+);
 
 pub type BoxedNewPeerFuture = Box<Future<Item = Peer, Error = Box<std::error::Error>>>;
 pub type BoxedNewPeerStream = Box<Stream<Item = Peer, Error = Box<std::error::Error>>>;
@@ -115,14 +325,22 @@ pub enum SpecifierType {
 
 /// A trait for a each specified type's accompanying object
 ///
-/// Don't forget to register each instance at the `list_of_all_specifier_classes` macro.
-pub trait SpecifierClass {
+/// Don't forget to register each built-in instance at the `list_of_all_specifier_classes` macro.
+/// Classes from other crates are added with `register_specifier_class`.
+/// Classes are shared between threads, unlike the specifiers they construct.
+pub trait SpecifierClass: Send + Sync {
     /// The primary name of the class
     fn get_name(&self) -> &'static str;
     /// Names to match command line parameters against, with a `:` colon if needed
     fn get_prefixes(&self) -> Vec<&'static str>;
     /// --long-help snippet about this specifier
     fn help(&self) -> &'static str;
+    /// What follows the prefix, as far as the class declaration tells
+    fn arg_kind(&self) -> specparse::ArgKind;
+    /// Command line options that specifiers of this class pay attention to
+    fn consumed_options(&self) -> &'static [&'static str] {
+        &[]
+    }
     /// Given the command line text, construct the specifier
     ///
     /// Full str is like `ws://qwe` in `ws://qwe`
@@ -131,15 +349,24 @@ pub trait SpecifierClass {
     fn construct(&self, full: &str, just_arg: &str) -> Result<Rc<Specifier>>;
 }
 macro_rules! specifier_class {
-    (name=$n:ident, target=$t:ident, prefixes=[$($p:expr),*], arg_handling=$c:tt, help=$h:expr) => {
+    (name=$n:ident, target=$t:ident, prefixes=[$($p:expr),*], arg_handling=$c:tt,
+        $(takes_path=$tp:tt,)* $(consumed_options=$o:expr,)* help=$h:expr) => {
         pub struct $n;
         impl $crate::SpecifierClass for $n {
             fn get_name(&self) -> &'static str { stringify!($n) }
             fn get_prefixes(&self) -> Vec<&'static str> { vec![$($p),*] }
             fn help(&self) -> &'static str { $h }
+            fn arg_kind(&self) -> $crate::specparse::ArgKind { specifier_class!(arg_kind $c $($tp)*) }
+            fn consumed_options(&self) -> &'static [&'static str] { specifier_class!(options $($o)*) }
             specifier_class!(construct target=$t $c);
         }
     };
+    (options) => { &[] };
+    (options $o:expr) => { $o };
+    (arg_kind $c:tt true) => { $crate::specparse::ArgKind::Path };
+    (arg_kind noarg) => { $crate::specparse::ArgKind::NoArg };
+    (arg_kind subspec) => { $crate::specparse::ArgKind::Subspecifier };
+    (arg_kind $c:tt) => { $crate::specparse::ArgKind::Other };
     (construct target=$t:ident noarg) => {
         fn construct(&self, _full:&str, just_arg:&str) -> $crate::Result<Rc<Specifier>> {
             if just_arg != "" {
@@ -177,6 +404,10 @@ pub struct OneSpecifierInfo {
     pub multiconnect: bool,
     pub uses_global_state: bool,
     pub typ: SpecifierType,
+    /// Listens on a TCP port, so `--workers` can be used
+    pub tcp_listener: bool,
+    /// Keeps state for all sessions, which `--workers` threads would each have their own copy of
+    pub shared_between_sessions: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -205,6 +436,20 @@ pub struct ConstructParams {
     global_state: Rc<RefCell<ProgramState>>,
     program_options: Rc<Options>,
     left_to_right: L2rUser,
+    /// Observer for WebSocket control frames, set by `record:` for its subspecifier
+    ws_event_hook: Option<ws_peer::WsEventHook>,
+    /// Called by `autoreconnect:` each time it re-establishes its subspecifier, set by `broadcast:`
+    reconnect_hook: Option<reconnect_peer::ReconnectHook>,
+}
+
+impl ConstructParams {
+    /// For specifiers defined outside this crate
+    pub fn tokio_handle(&self) -> &Handle {
+        &self.tokio_handle
+    }
+    pub fn program_options(&self) -> Rc<Options> {
+        self.program_options.clone()
+    }
 }
 
 /// A parsed command line argument.
@@ -228,11 +473,19 @@ pub trait Specifier: std::fmt::Debug {
     }
 
     // Provided:
+    fn is_tcp_listener(&self) -> bool {
+        false
+    }
+    fn is_shared_between_sessions(&self) -> bool {
+        false
+    }
     fn get_info_without_subspecs(&self) -> OneSpecifierInfo {
         OneSpecifierInfo {
             multiconnect: self.is_multiconnect(),
             uses_global_state: self.uses_global_state(),
             typ: self.get_type(),
+            tcp_listener: self.is_tcp_listener(),
+            shared_between_sessions: self.is_shared_between_sessions(),
         }
     }
 }
@@ -251,6 +504,12 @@ impl Specifier for Rc<Specifier> {
     fn uses_global_state(&self) -> bool {
         (**self).uses_global_state()
     }
+    fn is_tcp_listener(&self) -> bool {
+        (**self).is_tcp_listener()
+    }
+    fn is_shared_between_sessions(&self) -> bool {
+        (**self).is_shared_between_sessions()
+    }
 
     fn get_info_without_subspecs(&self) -> OneSpecifierInfo {
         (**self).get_info_without_subspecs()
@@ -320,19 +579,88 @@ pub mod trivial_peer;
 pub mod ws_client_peer;
 pub mod ws_peer;
 pub mod ws_server_peer;
+#[cfg(feature = "ssl")]
+pub mod ssl_peer;
 
+#[cfg(feature = "tokio-process")]
+pub mod filtermsg_peer;
 #[cfg(feature = "tokio-process")]
 pub mod process_peer;
+#[cfg(all(unix, feature = "pty", feature = "tokio-process"))]
+pub mod pty_peer;
+#[cfg(all(unix, feature = "unix_stdio", feature = "readline"))]
+pub mod readline_peer;
 
 #[cfg(unix)]
 pub mod unix_peer;
+#[cfg(windows)]
+pub mod named_pipe_peer;
+#[cfg(windows)]
+pub mod windows_stdio;
+pub mod console_text;
+pub mod threaded_io;
 
 pub mod broadcast_reuse_peer;
+pub mod clog_peer;
+pub mod chunk_peer;
+pub mod count_peer;
+pub mod crc_peer;
+pub mod delay_peer;
+pub mod generator_peer;
+pub mod idle_timeout;
+pub mod addr;
+pub mod batching;
+pub mod bench;
+pub mod bind_retry;
+pub mod builder;
+pub mod datagram;
+pub mod dedup;
+pub mod embed;
+pub mod epipe;
+pub mod error;
+pub mod session_limits;
+pub mod metrics;
+pub mod middleware;
+pub mod shutdown;
+pub mod events;
+pub mod exit_code;
+pub mod session_cap;
+pub mod session_hooks;
+pub mod budget;
+pub mod bufpool;
+pub mod pid_file;
+pub mod reload;
+pub mod remote_log;
+pub mod jsonwrap_peer;
+pub mod lb_peer;
+pub mod lenprefix_peer;
+pub mod lifecycle;
 pub mod line_peer;
+pub mod log_peer;
+pub mod msgpack_peer;
+pub mod multilisten_peer;
+pub mod prepend_peer;
 pub mod primitive_reuse_peer;
 pub mod reconnect_peer;
+pub mod record_peer;
+#[cfg(feature = "regex")]
+pub mod regex_filter;
+pub mod replay_peer;
+pub mod response_headers;
+pub mod sd_notify;
+pub mod seqnum_peer;
 
+pub mod capabilities;
+pub mod completions;
+pub mod splice;
 pub mod specparse;
+pub mod spectree;
+pub mod targets;
+pub mod throttle_peer;
+pub mod urlnorm;
+pub mod util;
+pub mod vectored;
+pub mod workers;
 
 pub type PeerOverlay = Rc<Fn(Peer) -> BoxedNewPeerFuture>;
 
@@ -348,7 +676,16 @@ impl PeerConstructor {
     where
         F: Fn(Peer) -> BoxedNewPeerFuture,
     {
-        let f = Rc::new(func);
+        // Overlays build new peers, keep the client information of the inner one
+        let f = Rc::new(move |p: Peer| -> BoxedNewPeerFuture {
+            let info = p.3.clone();
+            Box::new(func(p).map(move |mut x| {
+                if x.3.is_none() {
+                    x.3 = info;
+                }
+                x
+            }))
+        });
         use PeerConstructor::*;
         match self {
             ServeOnce(x) => Overlay1(x, f),
@@ -405,6 +742,22 @@ impl PeerConstructor {
     }
 }
 
+thread_local! {
+    /// Set by `ReadDebt` when it returns a part of a message, with more of it to follow
+    static MESSAGE_CONTINUES: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+/// `r.read(buf)`, and whether what it returned is a message split by a `ReadDebt`
+/// that has the rest of it, to be returned by the following reads
+pub fn read_message_part<R: std::io::Read + ?Sized>(
+    r: &mut R,
+    buf: &mut [u8],
+) -> (std::io::Result<usize>, bool) {
+    MESSAGE_CONTINUES.with(|c| c.set(false));
+    let ret = r.read(buf);
+    (ret, MESSAGE_CONTINUES.with(|c| c.replace(false)))
+}
+
 /// A `Read` utility to deal with partial reads
 #[derive(Default)]
 pub struct ReadDebt(pub Option<Vec<u8>>);
@@ -420,8 +773,32 @@ impl ReadDebt {
         buf[..l].copy_from_slice(&buf_in[..l]);
 
         if l < buf_in.len() {
-            self.0 = Some(buf_in[l..].to_vec());
+            let mut v = bufpool::take(buf_in.len() - l).into_vec();
+            v.extend_from_slice(&buf_in[l..]);
+            self.0 = Some(v);
         }
+        MESSAGE_CONTINUES.with(|c| c.set(self.0.is_some()));
+
+        Ok(l)
+    }
+    /// Like `process_message`, but keeps the message's own buffer for the rest of it
+    /// instead of copying. The buffer is recycled once it is consumed.
+    pub fn process_owned(
+        &mut self,
+        buf: &mut [u8],
+        mut buf_in: Vec<u8>,
+    ) -> std::result::Result<usize, std::io::Error> {
+        assert_eq!(self.0, None);
+        let l = buf_in.len().min(buf.len());
+        buf[..l].copy_from_slice(&buf_in[..l]);
+
+        if l < buf_in.len() {
+            buf_in.drain(..l);
+            self.0 = Some(buf_in);
+        } else {
+            bufpool::recycle(buf_in);
+        }
+        MESSAGE_CONTINUES.with(|c| c.set(self.0.is_some()));
 
         Ok(l)
     }
@@ -430,13 +807,58 @@ impl ReadDebt {
         buf: &mut [u8],
     ) -> Option<std::result::Result<usize, std::io::Error>> {
         if let Some(debt) = self.0.take() {
-            Some(self.process_message(buf, debt.as_slice()))
+            Some(self.process_owned(buf, debt))
         } else {
             None
         }
     }
 }
 
+/// A `Write` utility to deliver a whole frame (e.g. a header plus a message)
+/// to an underlying stream despite partial writes and `WouldBlock`s
+#[derive(Default)]
+pub struct WriteDebt(pub Option<(Vec<u8>, usize)>);
+
+impl WriteDebt {
+    /// If there is no unfinished frame, obtain one from `getframe`, then continue writing it.
+    /// `Ok` means the frame is written completely.
+    pub fn write_frame<W, F>(&mut self, w: &mut W, getframe: F) -> std::io::Result<()>
+    where
+        W: std::io::Write + ?Sized,
+        F: FnOnce() -> std::io::Result<Vec<u8>>,
+    {
+        if self.0.is_none() {
+            self.0 = Some((getframe()?, 0));
+        }
+        loop {
+            let done = match self.0 {
+                Some((ref frame, ref mut pos)) => {
+                    if *pos >= frame.len() {
+                        true
+                    } else {
+                        let n = w.write(&frame[*pos..])?;
+                        if n == 0 {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::WriteZero,
+                                "write zero byte into writer",
+                            ));
+                        }
+                        *pos += n;
+                        false
+                    }
+                }
+                None => unreachable!(),
+            };
+            if done {
+                if let Some((frame, _)) = self.0.take() {
+                    bufpool::recycle(frame);
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
 pub fn once(x: BoxedNewPeerFuture) -> PeerConstructor {
     PeerConstructor::ServeOnce(x)
 }
@@ -461,20 +883,55 @@ pub fn simple_err(e: String) -> std::io::Error {
     let e2 = ::std::io::Error::new(::std::io::ErrorKind::Other, e1);
     e2
 }
+/// Box an error, turning bare I/O and WebSocket library errors into `WebsocatError`
 pub fn box_up_err<E: std::error::Error + 'static>(e: E) -> Box<std::error::Error> {
-    Box::new(e) as Box<std::error::Error>
+    error::typed(Box::new(e))
 }
 
 impl Peer {
-    fn new<R: AsyncRead + 'static, W: AsyncWrite + 'static>(r: R, w: W) -> Self {
+    pub fn new<R: AsyncRead + 'static, W: AsyncWrite + 'static>(r: R, w: W) -> Self {
         Peer(
             Box::new(r) as Box<AsyncRead>,
             Box::new(w) as Box<AsyncWrite>,
+            None,
+            None,
         )
     }
+
+    /// Socket or similar with nothing in between, eligible for `splice(2)` forwarding
+    pub fn with_raw_fd<R: AsyncRead + 'static, W: AsyncWrite + 'static>(r: R, w: W, fd: splice::RawFd) -> Self {
+        Peer(
+            Box::new(r) as Box<AsyncRead>,
+            Box::new(w) as Box<AsyncWrite>,
+            Some(splice::PeerFd { fd, read_only: false }),
+            None,
+        )
+    }
+
+    /// Regular file read with nothing in between, eligible for `sendfile(2)`
+    pub fn with_file_fd<R: AsyncRead + 'static, W: AsyncWrite + 'static>(r: R, w: W, fd: splice::RawFd) -> Self {
+        Peer(
+            Box::new(r) as Box<AsyncRead>,
+            Box::new(w) as Box<AsyncWrite>,
+            Some(splice::PeerFd { fd, read_only: true }),
+            None,
+        )
+    }
+
+    /// Attach what is known about the client this peer was accepted from
+    pub fn with_client_info(mut self, info: LeftSpecToRightSpec) -> Self {
+        self.3 = Some(Rc::new(info));
+        self
+    }
+
+    /// Client information attached by the listener, see `with_client_info`
+    pub fn client_info(&self) -> Option<&Rc<LeftSpecToRightSpec>> {
+        self.3.as_ref()
+    }
 }
 
-pub use specparse::spec;
+pub use error::WebsocatError;
+pub use specparse::{register_specifier_class, spec};
 
 pub fn peer_from_str(
     ps: Rc<RefCell<ProgramState>>,
@@ -492,30 +949,71 @@ pub fn peer_from_str(
         program_options: opts,
         global_state: ps,
         left_to_right: L2rUser::ReadFrom(l2r),
+        ws_event_hook: None,
+        reconnect_hook: None,
     };
     spec.construct(cp)
 }
 
 pub struct Transfer {
-    from: Box<AsyncRead>,
-    to: Box<AsyncWrite>,
+    from: shutdown::ShutdownRead,
+    to: shutdown::ShutdownWrite,
+    flush: my_copy::FlushPolicy,
+    /// Descriptors to `splice(2)` or `sendfile(2)` between, if nothing needs to see the data
+    splice: Option<(splice::RawFd, splice::RawFd)>,
+    /// `--raw-relay`, or a byte stream on both ends and nothing in between
+    raw: bool,
+}
+
+impl Transfer {
+    fn copy(self, opts: &Options) -> splice::Copy {
+        let mut buffering = my_copy::BufferSettings::from_options(opts);
+        buffering.raw = self.raw;
+        let scope = opts.shutdown_scope.clone();
+        splice::copy(
+            self.from,
+            self.to,
+            opts.one_message,
+            self.flush,
+            buffering,
+            self.splice,
+            opts.file_max_bytes,
+            scope,
+        )
+    }
+}
+/// What the sessions of one `serve` have in common, worked out once
+pub struct SessionSetup {
+    /// Names of the peers for errors
+    pub contexts: error::PeerContexts,
+    #[cfg(feature = "regex")]
+    filters: regex_filter::Filters,
 }
-pub struct Session(Transfer, Transfer, Rc<Options>);
+
+impl SessionSetup {
+    /// Fails if `--filter-*-regex` or `--rewrite` does not compile, rather than
+    /// letting messages through unfiltered
+    pub fn new(opts: &Options) -> Result<SessionSetup> {
+        Ok(SessionSetup {
+            contexts: error::PeerContexts::new(
+                opts.listen_spec.as_ref().map(|x| &x[..]),
+                opts.right_spec.as_ref().map(|x| &x[..]),
+            ),
+            #[cfg(feature = "regex")]
+            filters: regex_filter::Filters::new(opts)?,
+        })
+    }
+}
+
+pub struct Session(Transfer, Transfer, Rc<Options>, Option<idle_timeout::HIdleState>);
 
 impl Session {
     pub fn run(self) -> Box<Future<Item = (), Error = Box<std::error::Error>>> {
-        let once = self.2.one_message;
-        let f1 = my_copy::copy(self.0.from, self.0.to, true, once);
-        let f2 = my_copy::copy(self.1.from, self.1.to, true, once);
+        let opts = self.2.clone();
+        let idle = self.3;
+        let c1 = self.0.copy(&opts);
+        let f2 = self.1.copy(&opts);
 
-        let f1 = f1.and_then(|(_, r, w)| {
-            info!("Forward finished");
-            std::mem::drop(r);
-            tokio_io::io::shutdown(w).map(|w|{
-                info!("Forward shutdown finished");
-                std::mem::drop(w);
-            })
-        });
         let f2 = f2.and_then(|(_, r, w)| {
             info!("Reverse finished");
             std::mem::drop(r);
@@ -530,28 +1028,68 @@ impl Session {
             self.2.exit_on_eof,
         );
         type Ret = Box<Future<Item = (), Error = Box<std::error::Error>>>;
-        match (unif, unir, eeof) {
+        if !unif && !unir && !eeof && (self.2.no_close_on_stdin_eof || self.2.close_stdin_only) {
+            // EOF from the left side only stops the forward direction.
+            // Its writer gets fully shut down (WebSocket Close sent) when the reverse direction ends.
+            let half_close = self.2.close_stdin_only;
+            let f1 = c1.and_then(move |(_, r, w)| {
+                info!("Forward finished, waiting for the reverse direction");
+                std::mem::drop(r);
+                let mut w = Some(w);
+                futures::future::poll_fn(move || -> futures::Poll<shutdown::ShutdownWrite, std::io::Error> {
+                    if half_close {
+                        let inner = w.as_mut().unwrap();
+                        if let futures::Async::NotReady = ws_peer::without_close(|| inner.shutdown())? {
+                            return Ok(futures::Async::NotReady);
+                        }
+                    }
+                    Ok(futures::Async::Ready(w.take().unwrap()))
+                })
+            });
+            let ret = f1
+                .join(f2)
+                .and_then(|(w, ())| {
+                    tokio_io::io::shutdown(w).map(|w| {
+                        info!("Forward shutdown finished");
+                        std::mem::drop(w);
+                    })
+                })
+                .map(|()| {
+                    info!("Finished");
+                })
+                .map_err(box_up_err);
+            return Session::finish(Box::new(ret) as Ret, idle, opts);
+        }
+        let f1 = c1.and_then(|(_, r, w)| {
+            info!("Forward finished");
+            std::mem::drop(r);
+            tokio_io::io::shutdown(w).map(|w|{
+                info!("Forward shutdown finished");
+                std::mem::drop(w);
+            })
+        });
+        let ret = match (unif, unir, eeof) {
             (false, false, false) => Box::new(
                 f1.join(f2)
                     .map(|(_, _)| {
                         info!("Finished");
                     })
-                    .map_err(|x| Box::new(x) as Box<std::error::Error>),
+                    .map_err(box_up_err),
             ) as Ret,
             (false, false, true) => Box::new(
                 f1.select(f2)
                     .map(|(_, _)| {
                         info!("One of directions finished");
                     })
-                    .map_err(|(x, _)| Box::new(x) as Box<std::error::Error>),
+                    .map_err(|(x, _)| box_up_err(x)),
             ) as Ret,
             (true, false, _) => Box::new({
                 ::std::mem::drop(f2);
-                f1.map_err(|x| Box::new(x) as Box<std::error::Error>)
+                f1.map_err(box_up_err)
             }) as Ret,
             (false, true, _) => Box::new({
                 ::std::mem::drop(f1);
-                f2.map_err(|x| Box::new(x) as Box<std::error::Error>)
+                f2.map_err(box_up_err)
             }) as Ret,
             (true, true, _) => Box::new({
                 // Just open connection and close it.
@@ -559,23 +1097,305 @@ impl Session {
                 ::std::mem::drop(f2);
                 futures::future::ok(())
             }) as Ret,
-        }
+        };
+        Session::finish(ret, idle, opts)
+    }
+
+    /// Idle timeout check and session accounting around the data transfer
+    fn finish(
+        ret: Box<Future<Item = (), Error = Box<std::error::Error>>>,
+        idle: Option<idle_timeout::HIdleState>,
+        opts: Rc<Options>,
+    ) -> Box<Future<Item = (), Error = Box<std::error::Error>>> {
+        type Ret = Box<Future<Item = (), Error = Box<std::error::Error>>>;
+        let ret = match idle {
+            None => ret,
+            Some(idle) => Box::new(ret.and_then(move |()| {
+                if idle.borrow().fired() {
+                    Err(Box::new(idle_timeout::idle_timeout_error()) as Box<std::error::Error>)
+                } else {
+                    Ok(())
+                }
+            })) as Ret,
+        };
+        metrics::session_started();
+        lifecycle::session_start(&opts);
+        let ret = ret.then(move |r| {
+            metrics::session_ended();
+            let error = r.as_ref().err().map(|e| format!("{}", e));
+            lifecycle::session_end(&opts, error.as_ref().map(|x| &x[..]));
+            r
+        });
+        // Converters of this session take their buffers from here
+        Box::new(bufpool::InPool::new(ret, bufpool::BufPool::new())) as Ret
     }
-    pub fn new(peer1: Peer, peer2: Peer, opts: Rc<Options>) -> Self {
+    /// Fails if `--filter-*-regex` or `--rewrite` does not compile
+    pub fn new(peer1: Peer, peer2: Peer, opts: Rc<Options>, h: &Handle) -> Result<Self> {
+        let setup = SessionSetup::new(&opts)?;
+        Ok(Session::with_setup(peer1, peer2, opts, &setup, h))
+    }
+    fn with_setup(peer1: Peer, peer2: Peer, opts: Rc<Options>, setup: &SessionSetup, h: &Handle) -> Self {
+        let transparent = splice::transparent(&opts);
+        let raw1 = opts.raw_relay || (transparent && splice::pair(peer1.2, peer2.2).is_some());
+        let raw2 = opts.raw_relay || (transparent && splice::pair(peer2.2, peer1.2).is_some());
+        // Both sides of a direction are plain byte streams and nothing below needs to see the data
+        let (fd1, fd2) = if splice::enabled(&opts) {
+            (peer1.2, peer2.2)
+        } else {
+            (None, None)
+        };
+        let (mut r1, mut w1, mut r2, mut w2) = (peer1.0, peer1.1, peer2.0, peer2.1);
+        // Name the peers in operating system errors, if their specifiers are known
+        if let Some(c) = setup.contexts.side(false) {
+            let context = Rc::new(c);
+            r1 = Box::new(error::WithContext {
+                inner: r1,
+                context: context.clone(),
+            });
+            w1 = Box::new(error::WithContext { inner: w1, context });
+        }
+        if let Some(c) = setup.contexts.side(true) {
+            let context = Rc::new(c);
+            r2 = Box::new(error::WithContext {
+                inner: r2,
+                context: context.clone(),
+            });
+            w2 = Box::new(error::WithContext { inner: w2, context });
+        }
+        let idle = match opts.idle_timeout {
+            Some(secs) if secs > 0 => {
+                // `in` is data coming from the right specifier, `out` is data sent to it
+                let (din, dout) = util::parse_direction(&opts.idle_timeout_direction)
+                    .unwrap_or((true, true));
+                let st = idle_timeout::IdleState::new(std::time::Duration::from_secs(secs), h);
+                let rd = |inner: Box<AsyncRead>, counts: bool| -> Box<AsyncRead> {
+                    Box::new(idle_timeout::IdleRead {
+                        inner,
+                        state: st.clone(),
+                        counts,
+                    })
+                };
+                let wr = |inner: Box<AsyncWrite>| -> Box<AsyncWrite> {
+                    Box::new(idle_timeout::IdleWrite {
+                        inner,
+                        state: st.clone(),
+                    })
+                };
+                r1 = rd(r1, dout);
+                r2 = rd(r2, din);
+                w1 = wr(w1);
+                w2 = wr(w2);
+                Some(st.clone())
+            }
+            _ => None,
+        };
+        if opts.dedup_consecutive || opts.dedup_window.is_some() {
+            let window = opts.dedup_window.unwrap_or(1).max(1);
+            // `in` is data coming from the right specifier, `out` is data sent to it
+            let (din, dout) = util::parse_direction(&opts.dedup_direction).unwrap_or((true, true));
+            let silence = opts.dedup_max_silence;
+            if dout {
+                r1 = Box::new(dedup::DedupRead::new(r1, window, silence, "outgoing"));
+            }
+            if din {
+                r2 = Box::new(dedup::DedupRead::new(r2, window, silence, "incoming"));
+            }
+        }
+        if let Some(ms) = opts.batch_window_ms {
+            // `in` is data coming from the right specifier, `out` is data sent to it
+            let (din, dout) = util::parse_direction(&opts.batch_direction).unwrap_or((false, true));
+            if dout {
+                r1 = Box::new(batching::BatchRead::new(r1, ms, &opts, h));
+            }
+            if din {
+                r2 = Box::new(batching::BatchRead::new(r2, ms, &opts, h));
+            }
+        }
+        // `in` is data coming from the right specifier, `out` is data sent to it
+        let (mut hooks_in, mut hooks_out) = (vec![], vec![]);
+        #[cfg(feature = "regex")]
+        {
+            let (filters_in, filters_out) = setup.filters.hooks();
+            hooks_in.extend(filters_in);
+            hooks_out.extend(filters_out);
+        }
+        hooks_in.extend(opts.middleware.incoming.iter().cloned());
+        hooks_out.extend(opts.middleware.outgoing.iter().cloned());
+        if !hooks_in.is_empty() || !hooks_out.is_empty() {
+            use middleware::{MiddlewareRead, MiddlewareWrite};
+            let st: middleware::HMiddlewareState = Default::default();
+            if !hooks_out.is_empty() {
+                r1 = Box::new(MiddlewareRead::new(r1, hooks_out, st.clone(), "outgoing"));
+            }
+            if !hooks_in.is_empty() {
+                r2 = Box::new(MiddlewareRead::new(r2, hooks_in, st.clone(), "incoming"));
+            }
+            w1 = Box::new(MiddlewareWrite {
+                inner: w1,
+                state: st.clone(),
+            });
+            w2 = Box::new(MiddlewareWrite { inner: w2, state: st });
+        }
+        if opts.max_messages_in.is_some()
+            || opts.max_messages_out.is_some()
+            || opts.max_bytes_in.is_some()
+            || opts.max_bytes_out.is_some()
+        {
+            use session_limits::{LimitRead, LimitState, LimitWrite};
+            let st = LimitState::new();
+            // `in` is data coming from the right specifier, `out` is data sent to it
+            r1 = Box::new(LimitRead::new(
+                r1,
+                st.clone(),
+                opts.max_messages_out,
+                opts.max_bytes_out,
+                "out",
+            ));
+            r2 = Box::new(LimitRead::new(
+                r2,
+                st.clone(),
+                opts.max_messages_in,
+                opts.max_bytes_in,
+                "in",
+            ));
+            w1 = Box::new(LimitWrite {
+                inner: w1,
+                state: st.clone(),
+            });
+            w2 = Box::new(LimitWrite {
+                inner: w2,
+                state: st,
+            });
+        }
+        if metrics::needed(&opts) {
+            // `in` is data coming from the right specifier, `out` is data sent to it
+            r1 = Box::new(metrics::CountRead {
+                inner: r1,
+                incoming: false,
+            });
+            r2 = Box::new(metrics::CountRead {
+                inner: r2,
+                incoming: true,
+            });
+        }
+        if let (true, Some(sid)) = (events::tracking(&opts), events::current_sid()) {
+            // `in` is data coming from the right specifier, `out` is data sent to it
+            r1 = Box::new(events::CountRead {
+                inner: r1,
+                sid,
+                incoming: false,
+            });
+            r2 = Box::new(events::CountRead {
+                inner: r2,
+                sid,
+                incoming: true,
+            });
+        }
+        if budget::enabled(&opts) {
+            r1 = Box::new(budget::BudgetRead::new(r1, &opts));
+            r2 = Box::new(budget::BudgetRead::new(r2, &opts));
+        }
+        if opts.include_headers || opts.include_headers_every_connect {
+            r2 = Box::new(response_headers::IncludeHeadersRead::new(r2, &opts));
+        }
+        let r1 = shutdown::ShutdownRead::new(r1, opts.shutdown_scope.clone());
+        let r2 = shutdown::ShutdownRead::new(r2, opts.shutdown_scope.clone());
+        let w1 = shutdown::ShutdownWrite(w1, opts.shutdown_scope.clone());
+        let w2 = shutdown::ShutdownWrite(w2, opts.shutdown_scope.clone());
         Session(
             Transfer {
-                from: peer1.0,
-                to: peer2.1,
+                from: r1,
+                to: w2,
+                flush: my_copy::FlushPolicy::after_write(&opts, h),
+                splice: splice::pair(fd1, fd2),
+                raw: raw1,
             },
             Transfer {
-                from: peer2.0,
-                to: peer1.1,
+                from: r2,
+                to: w1,
+                flush: my_copy::FlushPolicy::from_options(&opts, h),
+                splice: splice::pair(fd2, fd1),
+                raw: raw2,
             },
             opts,
+            idle,
         )
     }
 }
 
+/// Left peer's reader that first returns data already read from it
+struct PrefixedRead {
+    debt: ReadDebt,
+    inner: Box<AsyncRead>,
+}
+
+impl std::io::Read for PrefixedRead {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        std::io::Read::read(&mut self.inner, buf)
+    }
+}
+impl AsyncRead for PrefixedRead {}
+
+/// Establish the right peer and run the session. With `--lazy-connect`,
+/// wait for the left peer's first data before connecting; if the left peer
+/// reaches EOF first, the right specifier is never constructed.
+fn connect_right_and_run(
+    peer1: Peer,
+    s2: &Rc<Specifier>,
+    cp2: ConstructParams,
+    opts: Rc<Options>,
+    setup: Rc<SessionSetup>,
+    h: &Handle,
+) -> Box<Future<Item = (), Error = Box<std::error::Error>>> {
+    let h = h.clone();
+    let setup2 = setup.clone();
+    // A copy for this session only: concurrent sessions must not see each other's client
+    let info = peer1.3.as_ref().map(|x| (**x).clone()).unwrap_or_default();
+    let cp2 = ConstructParams {
+        left_to_right: L2rUser::ReadFrom(Rc::new(RefCell::new(info))),
+        ..cp2
+    };
+    let right = move |s2: &Rc<Specifier>, cp2: ConstructParams| {
+        s2.construct(cp2)
+            .get_only_first_conn()
+            .map_err(move |e| setup2.contexts.wrap(true, e))
+    };
+    if !opts.lazy_connect {
+        return Box::new(right(s2, cp2).and_then(move |peer2| {
+            Session::with_setup(peer1, peer2, opts, &setup, &h).run()
+        }));
+    }
+    let s2 = s2.clone();
+    let Peer(r1, w1, _, _) = peer1;
+    let first = tokio_io::io::read(r1, vec![0; opts.lazy_buffer_bytes.max(1)]);
+    let setup2 = setup.clone();
+    Box::new(first.map_err(move |e| setup2.contexts.wrap(false, box_up_err(e))).and_then(
+        move |(r1, mut buf, n)| -> Box<Future<Item = (), Error = Box<std::error::Error>>> {
+            if n == 0 {
+                info!("EOF before any data, not connecting");
+                return Box::new(futures::future::ok(()));
+            }
+            debug!("Got {} bytes, connecting lazily", n);
+            buf.truncate(n);
+            let peer1 = Peer(
+                Box::new(PrefixedRead {
+                    debt: ReadDebt(Some(buf)),
+                    inner: r1,
+                }),
+                w1,
+                None,
+                None,
+            );
+            Box::new(right(&s2, cp2).and_then(move |peer2| {
+                Session::with_setup(peer1, peer2, opts, &setup, &h).run()
+            }))
+        },
+    ))
+}
+
 pub fn serve<OE>(
     h: Handle,
     s1: Rc<Specifier>,
@@ -587,6 +1407,7 @@ where
     OE: Fn(Box<std::error::Error>) -> () + 'static,
 {
     info!("Serving {:?} to {:?} with {:?}", s1, s2, opts);
+    events::init(&opts);
     let ps = Rc::new(RefCell::new(ProgramState::default()));
 
     use PeerConstructor::{Overlay1, OverlayM, ServeMultipleTimes, ServeOnce};
@@ -599,6 +1420,13 @@ where
 
     let opts1 = Rc::new(opts);
     let opts2 = opts1.clone();
+    let setup = match SessionSetup::new(&opts1) {
+        Ok(x) => Rc::new(x),
+        Err(e) => {
+            onerror(e);
+            return Box::new(futures::future::err(()));
+        }
+    };
 
     let l2r = Rc::new(RefCell::new(Default::default()));
     let cp1 = ConstructParams {
@@ -606,12 +1434,16 @@ where
         program_options: opts1.clone(),
         global_state: ps.clone(),
         left_to_right: L2rUser::FillIn(l2r.clone()),
+        ws_event_hook: None,
+        reconnect_hook: None,
     };
     let cp2 = ConstructParams {
         tokio_handle: h.clone(),
         program_options: opts1,
         global_state: ps.clone(),
         left_to_right: L2rUser::ReadFrom(l2r),
+        ws_event_hook: None,
+        reconnect_hook: None,
     };
     let mut left = s1.construct(cp1);
 
@@ -621,80 +1453,100 @@ where
 
     let prog = match left {
         ServeMultipleTimes(stream) => {
-            let runner = stream
-                .map(move |peer1| {
+            let stream = session_cap::Capped::new(stream, &opts2, false, &h1);
+            let runner = shutdown::UntilShutdown(stream, opts2.shutdown_scope.clone())
+                .map(move |(peer1, slot)| {
                     let opts3 = opts2.clone();
                     let e1_1 = e1.clone();
                     let cp2 = cp2.clone();
-                    h1.spawn(
-                        s2.construct(cp2)
-                            .get_only_first_conn()
-                            .and_then(move |peer2| {
-                                let s = Session::new(peer1, peer2, opts3);
-                                s.run()
+                    let h2 = h1.clone();
+                    let setup = setup.clone();
+                    let sid = events::new_sid();
+                    let peer = peer1.3.as_ref().and_then(|x| x.client_addr.clone());
+                    let session = futures::future::lazy(move || {
+                        events::accepted(peer);
+                        connect_right_and_run(peer1, &s2, cp2, opts3, setup, &h2)
+                    });
+                    h1.spawn(events::scoped(
+                        sid,
+                        session
+                            .then(move |r| {
+                                ::std::mem::drop(slot);
+                                r
                             })
                             .map_err(move |e| e1_1(e)),
-                    )
+                    ))
                 })
                 .for_each(|()| futures::future::ok(()));
             Box::new(runner.map_err(move |e| e2(e))) as Box<Future<Item = (), Error = ()>>
         }
         OverlayM(stream, mapper) => {
-            let runner = stream
-                .map(move |peer1_| {
+            // Refuse WebSocket clients politely, before the upgrade
+            let http_refusal = s1.get_type() == SpecifierType::WebSocket;
+            let stream = session_cap::Capped::new(stream, &opts2, http_refusal, &h1);
+            let runner = shutdown::UntilShutdown(stream, opts2.shutdown_scope.clone())
+                .map(move |(peer1_, slot)| {
                     debug!("Underlying connection established");
                     let opts3 = opts2.clone();
                     let e1_1 = e1.clone();
                     let s2 = s2.clone();
                     let h1 = h1.clone();
+                    let h2 = h1.clone();
                     let cp2 = cp2.clone();
-                    h1.spawn(
-                        mapper(peer1_)
+                    let mapper = mapper.clone();
+                    let sid = events::new_sid();
+                    let peer = peer1_.3.as_ref().and_then(|x| x.client_addr.clone());
+                    let setup = setup.clone();
+                    let setup2 = setup.clone();
+                    let upgraded = futures::future::lazy(move || {
+                        events::accepted(peer);
+                        mapper(peer1_).map_err(move |e| setup2.contexts.wrap(false, e))
+                    });
+                    h1.spawn(events::scoped(
+                        sid,
+                        upgraded
                             .and_then(move |peer1| {
-                                s2.construct(cp2)
-                                    .get_only_first_conn()
-                                    .and_then(move |peer2| {
-                                        let s = Session::new(peer1, peer2, opts3);
-                                        s.run()
-                                    })
+                                connect_right_and_run(peer1, &s2, cp2, opts3, setup, &h2)
+                            })
+                            .then(move |r| {
+                                ::std::mem::drop(slot);
+                                r
                             })
                             .map_err(move |e| e1_1(e)),
-                    )
+                    ))
                 })
                 .for_each(|()| futures::future::ok(()));
             Box::new(runner.map_err(move |e| e2(e))) as Box<Future<Item = (), Error = ()>>
         }
         ServeOnce(peer1c) => {
+            let setup2 = setup.clone();
+            let peer1c = peer1c.map_err(move |e| setup2.contexts.wrap(false, e));
             let runner = peer1c.and_then(move |peer1| {
-                let right = s2.construct(cp2);
-                let fut = right.get_only_first_conn();
-                fut.and_then(move |peer2| {
-                    let s = Session::new(peer1, peer2, opts2);
-                    s.run().map(|()| {
-                        ::std::mem::drop(ps)
-                        // otherwise ps will be dropped sooner
-                        // and stdin/stdout may become blocking sooner
-                    })
+                connect_right_and_run(peer1, &s2, cp2, opts2, setup, &h1).map(|()| {
+                    ::std::mem::drop(ps)
+                    // otherwise ps will be dropped sooner
+                    // and stdin/stdout may become blocking sooner
                 })
             });
+            let runner = events::scoped(events::new_sid(), runner);
             Box::new(runner.map_err(move |e| e3(e))) as Box<Future<Item = (), Error = ()>>
         }
         Overlay1(peer1c, mapper) => {
+            let setup2 = setup.clone();
+            let setup3 = setup.clone();
+            let peer1c = peer1c.map_err(move |e| setup2.contexts.wrap(false, e));
             let runner = peer1c.and_then(move |peer1_| {
                 debug!("Underlying connection established");
-                mapper(peer1_).and_then(move |peer1| {
-                    let right = s2.construct(cp2);
-                    let fut = right.get_only_first_conn();
-                    fut.and_then(move |peer2| {
-                        let s = Session::new(peer1, peer2, opts2);
-                        s.run().map(|()| {
-                            ::std::mem::drop(ps)
-                            // otherwise ps will be dropped sooner
-                            // and stdin/stdout may become blocking sooner
-                        })
+                let upgraded = mapper(peer1_).map_err(move |e| setup3.contexts.wrap(false, e));
+                upgraded.and_then(move |peer1| {
+                    connect_right_and_run(peer1, &s2, cp2, opts2, setup, &h1).map(|()| {
+                        ::std::mem::drop(ps)
+                        // otherwise ps will be dropped sooner
+                        // and stdin/stdout may become blocking sooner
                     })
                 })
             });
+            let runner = events::scoped(events::new_sid(), runner);
             Box::new(runner.map_err(move |e| e3(e))) as Box<Future<Item = (), Error = ()>>
         }
     };
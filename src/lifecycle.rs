@@ -0,0 +1,137 @@
+//! Session lifecycle callbacks: session start, WebSocket handshake and session end.
+//!
+//! The JSON event log (`--log-format json`) and `--on-connect`/`--on-disconnect` commands
+//! are built on this too; library users add their own with `WebsocatBuilder::callbacks`.
+//!
+//! Callbacks are called synchronously on the reactor thread, so all sessions wait for them.
+//! They must be cheap: anything that may take a while (running commands, network or disk I/O)
+//! should be handed over to another thread, like `--on-connect` commands are.
+//! Callbacks taking longer than `SLOW_CALLBACK_MS` are reported in the log.
+
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::events;
+use super::session_hooks::HookCommands;
+use super::Options;
+
+/// Callbacks slower than this get a warning
+pub const SLOW_CALLBACK_MS: u64 = 20;
+
+/// What is known about a session when a callback is called
+#[derive(Clone, Debug, Default)]
+pub struct SessionMeta {
+    /// Same as `sid` of JSON events and `WEBSOCAT_SESSION_ID` of hook commands
+    pub sid: Option<u64>,
+    /// Client address, for sessions started by a listener
+    pub peer: Option<String>,
+    /// Request URI of WebSocket server sessions, URL of WebSocket client ones
+    pub uri: Option<String>,
+    /// Negotiated WebSocket subprotocol
+    pub protocol: Option<String>,
+}
+
+/// Totals of a finished session
+#[derive(Clone, Debug, Default)]
+pub struct SessionStats {
+    /// Bytes that came from the right specifier
+    pub bytes_in: u64,
+    /// Bytes that came from the left specifier
+    pub bytes_out: u64,
+    /// Code of WebSocket Close frame received, if any
+    pub close_code: Option<u16>,
+}
+
+/// Observer of session lifecycle. See the module documentation for constraints.
+pub trait SessionCallbacks {
+    fn on_session_start(&self, _meta: &SessionMeta) {}
+    /// WebSocket handshake succeeded. `headers` are request headers for servers,
+    /// response headers for clients.
+    fn on_handshake(&self, _meta: &SessionMeta, _headers: &[(String, String)]) {}
+    fn on_session_end(&self, _meta: &SessionMeta, _result: Result<(), &str>, _stats: &SessionStats) {}
+}
+
+/// Callbacks set by the library user, carried in `Options`
+#[derive(Clone, Default)]
+pub struct CallbackList(pub Vec<Rc<SessionCallbacks>>);
+
+impl fmt::Debug for CallbackList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} callback(s)", self.0.len())
+    }
+}
+
+fn current_meta() -> SessionMeta {
+    let i = events::session_info();
+    SessionMeta {
+        sid: events::current_sid(),
+        peer: i.peer,
+        uri: i.uri,
+        protocol: i.protocol,
+    }
+}
+
+/// Call `f` for built-in consumers, then for user's callbacks
+fn dispatch<F: Fn(&SessionCallbacks)>(opts: &Options, f: F) {
+    let hooks = HookCommands(opts);
+    let builtin: [&SessionCallbacks; 2] = [&events::EventLog, &hooks];
+    for cb in builtin.iter() {
+        f(*cb);
+    }
+    for cb in &opts.callbacks.0 {
+        let start = Instant::now();
+        f(&**cb);
+        let took = start.elapsed();
+        if took > Duration::from_millis(SLOW_CALLBACK_MS) {
+            warn!(
+                "Session callback took {} ms, holding up all sessions. Offload slow work to another thread.",
+                took.as_secs() * 1000 + u64::from(took.subsec_nanos() / 1_000_000)
+            );
+        }
+    }
+}
+
+/// The current session is about to start transferring data
+pub fn session_start(opts: &Options) {
+    let meta = current_meta();
+    dispatch(opts, |cb| cb.on_session_start(&meta));
+}
+
+/// A WebSocket handshake of the current session succeeded
+pub fn handshake(opts: &Options, headers: &[(String, String)], protocol: Option<String>) {
+    if protocol.is_some() {
+        events::note_protocol(protocol);
+    }
+    let meta = current_meta();
+    dispatch(opts, |cb| cb.on_handshake(&meta, headers));
+}
+
+/// The current session has ended, with an error message if it failed
+pub fn session_end(opts: &Options, error: Option<&str>) {
+    let meta = current_meta();
+    let info = events::take_session_info();
+    let stats = SessionStats {
+        bytes_in: info.bytes[0],
+        bytes_out: info.bytes[1],
+        close_code: info.close_code,
+    };
+    let result = match error {
+        None => Ok(()),
+        Some(e) => Err(e),
+    };
+    dispatch(opts, |cb| cb.on_session_end(&meta, result, &stats));
+}
+
+/// Headers as name-value pairs, for `on_handshake`
+pub fn header_pairs(headers: &::websocket::header::Headers) -> Vec<(String, String)> {
+    headers.iter().map(|h| (h.name().to_string(), h.value_string())).collect()
+}
+
+/// Subprotocol in the handshake response
+pub fn protocol(response: &::websocket::header::Headers) -> Option<String> {
+    response
+        .iter()
+        .find(|h| h.name().eq_ignore_ascii_case("Sec-WebSocket-Protocol"))
+        .map(|h| h.value_string())
+}
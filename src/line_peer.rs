@@ -1,11 +1,17 @@
+extern crate base64;
+
 use futures::future::ok;
 
 use std::rc::Rc;
 
-use super::{BoxedNewPeerFuture, Peer};
-use super::{ConstructParams, PeerConstructor, Specifier};
+use super::bufpool;
+use super::my_copy::DEFAULT_BUFFER_SIZE;
+use super::util::{find_subslice, format_rfc3339, unescape};
+use super::{peer_strerr, simple_err, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, ReadDebt, Specifier};
 
 use std::io::Read;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio_io::AsyncRead;
 
 use std::io::Error as IoError;
@@ -14,8 +20,12 @@ use std::io::Error as IoError;
 pub struct Message2Line<T: Specifier>(pub T);
 impl<T: Specifier> Specifier for Message2Line<T> {
     fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let settings = LineSettings::from_options(&cp.program_options);
         let inner = self.0.construct(cp.clone());
-        inner.map(move |p| packet2line_peer(p))
+        inner.map(move |p| match settings {
+            Ok(ref s) => packet2line_peer(p, s.clone()),
+            Err(ref e) => peer_strerr(e),
+        })
     }
     specifier_boilerplate!(typ=Line noglobalstate has_subspec);
     self_0_is_subspecifier!(proxy_is_multiconnect);
@@ -25,6 +35,15 @@ specifier_class!(
     target = Message2Line,
     prefixes = ["msg2line:"],
     arg_handling = subspec,
+    consumed_options = &[
+        "--separator",
+        "--separator-n",
+        "--separator-conflict",
+        "--line-escape",
+        "--linemode-retain-newlines",
+        "--timestamps",
+        "--timestamp-separator",
+    ],
     help = r#"
 Line filter: ensure each message (a chunk from one read call from underlying specifier)
 contains no inner newlines and terminates with one newline.
@@ -33,6 +52,18 @@ Reverse of the `line2msg:`.
 
 Replaces both newlines (\x0A) and carrige returns (\x0D) with spaces (\x20) for each read.
 
+Separator can be changed with --separator (e.g. `\0` or `\r\n`), --separator-n
+(number of consecutive separators forming a boundary). Messages containing the
+separator are handled according to --separator-conflict:
+`space` (replace it with spaces, the default), `error` (abort the session),
+`escape` (backslash-escape it, `line2msg:` with the same option reverses that),
+`pass` (leave it as is), `strip` (remove it) or `base64` (encode such messages as base64).
+
+--line-escape backslash|base64|strip|error is the same, overriding --separator-conflict.
+
+--timestamps prefixes each line with the time it was read, followed by
+--timestamp-separator (a space by default).
+
 Does not affect writing at all. Use this specifier on both ends to get bi-directional behaviour.
 
 Automatically inserted by --line option on top of the stack containing a websocket.
@@ -45,9 +76,12 @@ Example: TODO
 pub struct Line2Message<T: Specifier>(pub T);
 impl<T: Specifier> Specifier for Line2Message<T> {
     fn construct(&self, cp: ConstructParams) -> PeerConstructor {
-        let retain_newlines = cp.program_options.linemode_retain_newlines;
+        let settings = LineSettings::from_options(&cp.program_options);
         let inner = self.0.construct(cp.clone());
-        inner.map(move |p| line2packet_peer(p, retain_newlines))
+        inner.map(move |p| match settings {
+            Ok(ref s) => line2packet_peer(p, s.clone()),
+            Err(ref e) => peer_strerr(e),
+        })
     }
     specifier_boilerplate!(typ=Line noglobalstate has_subspec);
     self_0_is_subspecifier!(proxy_is_multiconnect);
@@ -57,10 +91,13 @@ specifier_class!(
     target=Line2Message,
     prefixes=["line2msg:"], 
     arg_handling=subspec,
+    consumed_options=&["--separator", "--separator-n", "--separator-conflict", "--line-escape"],
     help=r#"
 Line filter: encure that each message (a successful read call) is obtained from a line
 coming from underlying specifier, buffering up or splitting content as needed.
 
+Lines are delimited by --separator repeated --separator-n times (`\n` by default).
+
 Reverse of the `msg2line:`.
 
 Does not affect writing at all. Use this specifier on both ends to get bi-directional behaviour.
@@ -71,51 +108,424 @@ Example: TODO
 "#
 );
 
-pub fn packet2line_peer(inner_peer: Peer) -> BoxedNewPeerFuture {
-    let filtered = Packet2LineWrapper(inner_peer.0);
-    let thepeer = Peer::new(filtered, inner_peer.1);
-    Box::new(ok(thepeer)) as BoxedNewPeerFuture
+/// What `msg2line:` does with messages containing the line boundary
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SeparatorConflict {
+    Space,
+    Error,
+    /// Backslash escapes, understood by `line2msg:`
+    Escape,
+    Pass,
+    Strip,
+    /// Encode whole message as base64, one-way
+    Base64,
 }
-struct Packet2LineWrapper(Box<AsyncRead>);
 
-impl Read for Packet2LineWrapper {
-    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
-        let l = b.len();
-        assert!(l > 1);
-        let mut n = match self.0.read(&mut b[..(l - 1)]) {
-            Ok(x) => x,
-            Err(e) => return Err(e),
+/// `--timestamps` format
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimestampFormat {
+    /// `2024-05-01T12:00:00.123Z`
+    Rfc3339,
+    /// Milliseconds since Unix epoch
+    EpochMs,
+    /// Seconds since the session start, like `1.250`
+    Relative,
+}
+
+impl TimestampFormat {
+    pub fn from_str(s: &str) -> Option<TimestampFormat> {
+        match s {
+            "rfc3339" => Some(TimestampFormat::Rfc3339),
+            "epoch-ms" => Some(TimestampFormat::EpochMs),
+            "relative" => Some(TimestampFormat::Relative),
+            _ => None,
+        }
+    }
+
+    /// Timestamp for a line read at `now`, `since_start` after the session started
+    pub fn format(self, now: SystemTime, since_start: Duration) -> String {
+        match self {
+            TimestampFormat::Rfc3339 => format_rfc3339(now),
+            TimestampFormat::EpochMs => {
+                let d = now.duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+                format!("{}", d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000)
+            }
+            TimestampFormat::Relative => format!(
+                "{}.{:03}",
+                since_start.as_secs(),
+                since_start.subsec_nanos() / 1_000_000
+            ),
+        }
+    }
+}
+
+/// Line mode settings, from `--separator*` and `--timestamp*` options
+#[derive(Clone, Debug)]
+pub struct LineSettings {
+    /// Separator repeated `--separator-n` times
+    pub boundary: Vec<u8>,
+    pub conflict: SeparatorConflict,
+    pub retain_newlines: bool,
+    pub timestamps: Option<TimestampFormat>,
+    pub timestamp_separator: Vec<u8>,
+}
+
+/// Lines are made in read buffers, which need room for the boundary
+/// (`--separator` repeated `--separator-n` times) and some data
+pub fn boundary_problem(opts: &Options) -> Option<String> {
+    let sep_len = if opts.separator.is_empty() {
+        1
+    } else {
+        unescape(&opts.separator).ok()?.len()
+    };
+    let size = opts.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    match sep_len.checked_mul(opts.separator_n.max(1)) {
+        Some(x) if x < size => None,
+        _ => Some(format!(
+            "--separator repeated --separator-n times must be shorter than --buffer-size ({} bytes)",
+            size
+        )),
+    }
+}
+
+impl LineSettings {
+    pub fn from_options(opts: &Options) -> Result<LineSettings, String> {
+        let sep = if opts.separator.is_empty() {
+            b"\n".to_vec()
+        } else {
+            unescape(&opts.separator)?
         };
-        if n == 0 {
-            return Ok(n);
+        let conflict = match opts.line_escape {
+            Some(ref x) => match &x[..] {
+                "backslash" => SeparatorConflict::Escape,
+                "base64" => SeparatorConflict::Base64,
+                "strip" => SeparatorConflict::Strip,
+                "error" => SeparatorConflict::Error,
+                _ => Err("--line-escape must be `backslash`, `base64`, `strip` or `error`")?,
+            },
+            None => match &opts.separator_conflict[..] {
+                "" | "space" => SeparatorConflict::Space,
+                "error" => SeparatorConflict::Error,
+                "escape" => SeparatorConflict::Escape,
+                "pass" => SeparatorConflict::Pass,
+                "strip" => SeparatorConflict::Strip,
+                "base64" => SeparatorConflict::Base64,
+                _ => Err("--separator-conflict must be `space`, `error`, `escape`, `pass`, `strip` or `base64`")?,
+            },
+        };
+        if conflict == SeparatorConflict::Escape && sep[0] == b'\\' {
+            Err("--separator-conflict escape is incompatible with a separator starting with a backslash")?;
         }
-        // chomp away \n or \r\n
-        if n > 0 && b[n - 1] == b'\n' {
-            n -= 1;
+        let timestamps = match opts.timestamps {
+            Some(ref x) => match TimestampFormat::from_str(x) {
+                Some(f) => Some(f),
+                None => Err("--timestamps must be `rfc3339`, `epoch-ms` or `relative`")?,
+            },
+            None => None,
+        };
+        let timestamp_separator = if opts.timestamp_separator.is_empty() {
+            b" ".to_vec()
+        } else {
+            unescape(&opts.timestamp_separator)?
+        };
+        if let Some(e) = boundary_problem(opts) {
+            Err(e)?;
         }
-        if n > 0 && b[n - 1] == b'\r' {
+        let mut boundary = Vec::with_capacity(sep.len() * opts.separator_n.max(1));
+        for _ in 0..opts.separator_n.max(1) {
+            boundary.extend_from_slice(&sep);
+        }
+        Ok(LineSettings {
+            boundary,
+            conflict,
+            retain_newlines: opts.linemode_retain_newlines,
+            timestamps,
+            timestamp_separator,
+        })
+    }
+
+    /// Whether a line can be longer than the message it is made of
+    fn grows(&self) -> bool {
+        match self.conflict {
+            SeparatorConflict::Escape | SeparatorConflict::Base64 => true,
+            _ => self.timestamps.is_some(),
+        }
+    }
+
+    /// Emit `--timestamps` prefix for a line, if enabled
+    fn timestamp_prefix(&self, start: Instant, out: &mut LineOut) {
+        if let Some(f) = self.timestamps {
+            out.extend(f.format(SystemTime::now(), start.elapsed()).as_bytes());
+            out.extend(&self.timestamp_separator);
+        }
+    }
+
+    /// Default separator, for which `\r\n` is also understood as a line ending
+    fn is_newline(&self) -> bool {
+        self.boundary == b"\n"
+    }
+
+    /// Chomp away the boundary (or \r\n) if the message already ends with it
+    fn chomp<'a>(&self, msg: &'a [u8]) -> &'a [u8] {
+        let mut m = msg;
+        if m.ends_with(&self.boundary) {
+            m = &m[..(m.len() - self.boundary.len())];
+        }
+        if self.is_newline() && m.ends_with(b"\r") {
+            m = &m[..(m.len() - 1)];
+        }
+        m
+    }
+
+    /// Whether the byte may be part of a boundary and needs escaping
+    fn is_special(&self, c: u8) -> bool {
+        c == self.boundary[0] || (self.is_newline() && c == b'\r')
+    }
+
+    /// Length of the message contained in a line that ends with the boundary
+    fn message_len(&self, line: &[u8]) -> usize {
+        if self.retain_newlines {
+            return line.len();
+        }
+        let mut n = line.len() - self.boundary.len();
+        if self.is_newline() && n > 0 && line[n - 1] == b'\r' {
             n -= 1;
         }
-        // replace those with spaces
-        for i in 0..n {
-            if b[i] == b'\n' || b[i] == b'\r' {
-                b[i] = b' ';
+        n
+    }
+
+    /// Turn a message in `b[..n]` into a line, in place.
+    /// `b` must have room for the boundary after the message.
+    fn finish_line(&self, b: &mut [u8], n: usize) -> Result<usize, IoError> {
+        let bd = &self.boundary[..];
+        let n = self.chomp(&b[..n]).len();
+        b[n..(n + bd.len())].copy_from_slice(bd);
+        match self.conflict {
+            SeparatorConflict::Space if self.is_newline() => for c in &mut b[..n] {
+                if *c == b'\n' || *c == b'\r' {
+                    *c = b' ';
+                }
+            },
+            SeparatorConflict::Space => {
+                let mut i = 0;
+                while let Some(j) = find_subslice(&b[i..n], bd) {
+                    for c in &mut b[(i + j)..(i + j + bd.len())] {
+                        *c = b' ';
+                    }
+                    i += j + bd.len();
+                }
+            }
+            SeparatorConflict::Error => {
+                if find_subslice(&b[..(n + bd.len())], bd) != Some(n) {
+                    return Err(simple_err(
+                        "msg2line: message contains the line separator".to_string(),
+                    ));
+                }
+            }
+            SeparatorConflict::Strip => {
+                // Remove boundaries, moving the rest of the message to the left
+                let (mut i, mut w) = (0, 0);
+                while i < n {
+                    if b[i..n].starts_with(bd) {
+                        i += bd.len();
+                        continue;
+                    }
+                    if self.is_newline() && b[i] == b'\r' {
+                        i += 1;
+                        continue;
+                    }
+                    b[w] = b[i];
+                    w += 1;
+                    i += 1;
+                }
+                b[w..(w + bd.len())].copy_from_slice(bd);
+                return Ok(w + bd.len());
+            }
+            SeparatorConflict::Pass | SeparatorConflict::Escape | SeparatorConflict::Base64 => (),
+        }
+        Ok(n + bd.len())
+    }
+
+    /// Emit a message as a line for modes that may need to grow it.
+    /// Runs of bytes that need no escaping are copied as they are, in one piece each.
+    fn encode_line(&self, msg: &[u8], out: &mut LineOut) {
+        let bd = &self.boundary[..];
+        if self.conflict == SeparatorConflict::Base64 {
+            let m = self.chomp(msg);
+            if find_subslice(m, bd).is_some() || m.iter().any(|&c| self.is_special(c)) {
+                let b64 = bufpool::take(m.len() / 3 * 4 + 4).into_vec();
+                // Empty, hence valid UTF-8
+                let mut b64 = String::from_utf8(b64).unwrap();
+                base64::encode_config_buf(m, base64::STANDARD, &mut b64);
+                out.extend(b64.as_bytes());
+                bufpool::recycle(b64.into_bytes());
+            } else {
+                out.extend(m);
+            }
+        } else {
+            let mut start = 0;
+            for (i, &c) in msg.iter().enumerate() {
+                if c == b'\\' || self.is_special(c) {
+                    out.extend(&msg[start..i]);
+                    escape_byte(out, c);
+                    start = i + 1;
+                }
+            }
+            out.extend(&msg[start..]);
+        }
+        out.extend(bd);
+    }
+}
+
+fn escape_byte(out: &mut LineOut, c: u8) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    match c {
+        b'\\' => out.extend(b"\\\\"),
+        b'\n' => out.extend(b"\\n"),
+        b'\r' => out.extend(b"\\r"),
+        b'\t' => out.extend(b"\\t"),
+        0 => out.extend(b"\\0"),
+        _ => out.extend(&[b'\\', b'x', HEX[(c >> 4) as usize], HEX[(c & 15) as usize]]),
+    }
+}
+
+/// Where a line is emitted: straight into the buffer of the `read` call,
+/// and what does not fit there into the read debt
+struct LineOut<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    rest: Vec<u8>,
+}
+
+impl<'a> LineOut<'a> {
+    fn new(buf: &'a mut [u8]) -> LineOut<'a> {
+        LineOut {
+            buf,
+            len: 0,
+            rest: vec![],
+        }
+    }
+
+    fn extend(&mut self, data: &[u8]) {
+        let k = data.len().min(self.buf.len() - self.len);
+        self.buf[self.len..(self.len + k)].copy_from_slice(&data[..k]);
+        self.len += k;
+        if k < data.len() {
+            if self.rest.capacity() == 0 {
+                self.rest = bufpool::take(data.len() - k).into_vec();
             }
+            self.rest.extend_from_slice(&data[k..]);
         }
-        // add back one \n
-        b[n] = b'\n';
-        n += 1;
+    }
 
-        Ok(n)
+    /// Length of the line part in the buffer; the rest is left for the next reads
+    fn finish(self, debt: &mut ReadDebt) -> usize {
+        if !self.rest.is_empty() {
+            debt.0 = Some(self.rest);
+        }
+        self.len
+    }
+}
+
+/// Reverse of backslash escaping done by `msg2line:`. Unknown escapes are left as is.
+fn unescape_line(x: &[u8], v: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < x.len() {
+        if x[i] == b'\\' && i + 1 < x.len() {
+            let simple = match x[i + 1] {
+                b'\\' => Some(b'\\'),
+                b'n' => Some(b'\n'),
+                b'r' => Some(b'\r'),
+                b't' => Some(b'\t'),
+                b'0' => Some(0),
+                _ => None,
+            };
+            if let Some(c) = simple {
+                v.push(c);
+                i += 2;
+                continue;
+            }
+            if x[i + 1] == b'x' && i + 4 <= x.len() {
+                let h = ::std::str::from_utf8(&x[(i + 2)..(i + 4)]).unwrap_or("");
+                if let Ok(c) = u8::from_str_radix(h, 16) {
+                    v.push(c);
+                    i += 4;
+                    continue;
+                }
+            }
+        }
+        v.push(x[i]);
+        i += 1;
+    }
+}
+
+pub fn packet2line_peer(inner_peer: Peer, settings: LineSettings) -> BoxedNewPeerFuture {
+    let filtered = Packet2LineWrapper {
+        inner: inner_peer.0,
+        s: settings,
+        debt: Default::default(),
+        start: Instant::now(),
+    };
+    let thepeer = Peer::new(filtered, inner_peer.1);
+    Box::new(ok(thepeer)) as BoxedNewPeerFuture
+}
+struct Packet2LineWrapper {
+    inner: Box<AsyncRead>,
+    s: LineSettings,
+    debt: ReadDebt,
+    /// Session start, for relative timestamps
+    start: Instant,
+}
+
+impl Read for Packet2LineWrapper {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(b) {
+            return ret;
+        }
+        let l = b.len();
+        let blen = self.s.boundary.len();
+        if self.s.grows() {
+            let mut msg = bufpool::take(l + blen);
+            msg.resize(l + blen, 0);
+            let n = self.inner.read(&mut msg[..l])?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut out = LineOut::new(b);
+            self.s.timestamp_prefix(self.start, &mut out);
+            match self.s.conflict {
+                SeparatorConflict::Escape | SeparatorConflict::Base64 => {
+                    self.s.encode_line(&msg[..n], &mut out)
+                }
+                _ => {
+                    let m = self.s.finish_line(&mut msg, n)?;
+                    out.extend(&msg[..m]);
+                }
+            }
+            return Ok(out.finish(&mut self.debt));
+        }
+        if l <= blen {
+            return Err(simple_err(format!(
+                "Read buffer of {} bytes has no room for a line boundary of {} bytes",
+                l, blen
+            )));
+        }
+        let n = self.inner.read(&mut b[..(l - blen)])?;
+        if n == 0 {
+            return Ok(n);
+        }
+        self.s.finish_line(b, n)
     }
 }
 impl AsyncRead for Packet2LineWrapper {}
 
-pub fn line2packet_peer(inner_peer: Peer, retain_newlines: bool) -> BoxedNewPeerFuture {
+pub fn line2packet_peer(inner_peer: Peer, settings: LineSettings) -> BoxedNewPeerFuture {
     let filtered = Line2PacketWrapper {
         inner: inner_peer.0,
         queue: vec![],
-        retain_newlines,
+        scanned: 0,
+        s: settings,
+        debt: Default::default(),
     };
     let thepeer = Peer::new(filtered, inner_peer.1);
     Box::new(ok(thepeer)) as BoxedNewPeerFuture
@@ -123,41 +533,58 @@ pub fn line2packet_peer(inner_peer: Peer, retain_newlines: bool) -> BoxedNewPeer
 struct Line2PacketWrapper {
     inner: Box<AsyncRead>,
     queue: Vec<u8>,
-    retain_newlines: bool,
+    /// Part of the queue already known to contain no boundary
+    scanned: usize,
+    s: LineSettings,
+    debt: ReadDebt,
+}
+
+impl Line2PacketWrapper {
+    /// Take the first complete line from the queue, if any
+    fn pop_line(&mut self) -> Option<bufpool::PooledBuf> {
+        let blen = self.s.boundary.len();
+        match find_subslice(&self.queue[self.scanned..], &self.s.boundary) {
+            Some(i) => {
+                let end = self.scanned + i + blen;
+                self.scanned = 0;
+                let mut line = bufpool::take(end);
+                line.extend(self.queue.drain(..end));
+                Some(line)
+            }
+            None => {
+                // A boundary may begin in the last few bytes and continue in the next read
+                self.scanned = self.queue.len().saturating_sub(blen - 1);
+                None
+            }
+        }
+    }
 }
 
 impl Read for Line2PacketWrapper {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
-        //eprint!("ql={} ", self.queue.len());
-        let mut queued_line_len = None;
-        for i in 0..self.queue.len() {
-            if self.queue[i] == b'\n' {
-                queued_line_len = Some(i);
-                break;
-            }
-        }
-        //eprint!("qll={:?} ", queued_line_len);
-
-        if let Some(mut n) = queued_line_len {
-            n += 1;
-            buf[0..n].copy_from_slice(&self.queue[0..n]);
-            ::std::mem::drop(self.queue.drain(0..n));
-            if !self.retain_newlines {
-                if n > 0 && (buf[n - 1] == b'\n') {
-                    n -= 1
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        let escape = self.s.conflict == SeparatorConflict::Escape;
+        loop {
+            if let Some(line) = self.pop_line() {
+                let n = self.s.message_len(&line);
+                if escape {
+                    let mut msg = bufpool::take(n);
+                    unescape_line(&line[..n], &mut msg);
+                    if msg.is_empty() {
+                        continue;
+                    }
+                    return self.debt.process_message(buf, &msg);
                 }
-                if n > 0 && (buf[n - 1] == b'\r') {
-                    n -= 1
+                if n == 0 {
+                    // Empty messages can't be delivered, as zero-length read means EOF
+                    continue;
                 }
+                return self.debt.process_message(buf, &line[..n]);
             }
-            //eprintln!("n={}", n);
-            Ok(n)
-        } else {
-            let mut n = match self.inner.read(buf) {
-                Ok(x) => x,
-                Err(e) => return Err(e),
-            };
 
+            let n = self.inner.read(buf)?;
             if n == 0 {
                 if self.queue.len() != 0 {
                     warn!(
@@ -168,27 +595,19 @@ impl Read for Line2PacketWrapper {
                 return Ok(0);
             }
 
-            let mut happy_case =
-                self.queue.len() == 0 && (!buf[0..(n - 1)].contains(&b'\n')) && buf[n - 1] == b'\n';
-
+            let blen = self.s.boundary.len();
+            let happy_case = self.queue.len() == 0 && !escape
+                && find_subslice(&buf[..n], &self.s.boundary).map(|i| i + blen) == Some(n);
             if happy_case {
                 // Specifically to avoid allocations when data is already nice
-                if !self.retain_newlines {
-                    if n > 0 && (buf[n - 1] == b'\n') {
-                        n -= 1
-                    }
-                    if n > 0 && (buf[n - 1] == b'\r') {
-                        n -= 1
-                    }
+                let n = self.s.message_len(&buf[..n]);
+                if n == 0 {
+                    continue;
                 }
-                //eprintln!("happy n={}", n);
-                Ok(n)
-            } else {
-                // Just queue up and recurse
-                self.queue.extend_from_slice(&buf[0..n]);
-                //eprintln!(" recurse");
-                self.read(buf)
+                return Ok(n);
             }
+            // Just queue up and look for a line again
+            self.queue.extend_from_slice(&buf[0..n]);
         }
     }
 }
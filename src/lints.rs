@@ -1,7 +1,112 @@
+//! Problems with the command line, found without touching the network.
+//!
+//! Each `Diagnostic` has a stable code that `--allow` takes and library users can match on:
+//!
+//! * `E0001` invalid option value or combination of options
+//! * `E0002` specifier does not parse
+//! * `E0003` file that a specifier reads does not exist
+//! * `E0004` malformed host name
+//! * `E0005` too many usages of stdin/stdout
+//! * `E0006` multiple reusers
+//! * `E0007` `--timestamps` without line mode
+//! * `E0008` `--linemode` can't insert line mode specifiers
+//! * `E0009` `--workers` without a TCP listener
+//! * `E0010` `--pkcs12-der` can't be used: unreadable, wrong password, key not matching, chain not verifying
+//! * `E0011` `--workers` with a specifier that keeps state for all sessions, like `reuse:` or `lb:`
+//! * `W0001` both specifiers are stdio
+//! * `W0002` replies on stdio go to a random client
+//! * `W0003` both directions are inhibited
+//! * `W0004` a message-oriented option with specifiers that are byte streams on both sides
+
+#[cfg(all(unix, feature = "libc"))]
+extern crate libc;
+
 use super::line_peer;
-use super::{primitive_reuse_peer, Specifier, SpecifierType, WebsocatConfiguration};
+use super::specparse::spec_chain;
+use super::{primitive_reuse_peer, Options, Specifier, SpecifierType, WebsocatConfiguration};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::Path;
 use std::rc::Rc;
 
+/// All diagnostic codes, see the module documentation
+pub const CODES: &[&str] = &[
+    "E0001", "E0002", "E0003", "E0004", "E0005", "E0006", "E0007", "E0008", "E0009", "E0010", "E0011", "W0001",
+    "W0002", "W0003", "W0004",
+];
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem with the command line, as reported by `--check`
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable code like `W0004`
+    pub code: &'static str,
+    /// Option or specifier the problem is about, if it is about a single one
+    pub subject: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error<S: Into<String>>(code: &'static str, message: S) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            subject: None,
+            message: message.into(),
+        }
+    }
+    pub fn warning<S: Into<String>>(code: &'static str, message: S) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            code,
+            subject: None,
+            message: message.into(),
+        }
+    }
+    /// Set the offending option or specifier
+    pub fn about<S: Into<String>>(mut self, subject: S) -> Diagnostic {
+        self.subject = Some(subject.into());
+        self
+    }
+
+    /// Like `Display`, with ANSI colours if `color` is set
+    pub fn render(&self, color: bool) -> String {
+        let (label, ansi) = match self.severity {
+            Severity::Error => ("error", "\x1b[1;31m"),
+            Severity::Warning => ("warning", "\x1b[1;33m"),
+        };
+        if color {
+            format!("{}{}[{}]\x1b[0m: {}", ansi, label, self.code, self.message)
+        } else {
+            format!("{}[{}]: {}", label, self.code, self.message)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render(false))
+    }
+}
+
+/// Whether diagnostics on stderr should be colorized
+#[cfg(all(unix, feature = "libc"))]
+pub fn stderr_is_tty() -> bool {
+    unsafe { libc::isatty(2) == 1 }
+}
+
+/// Whether diagnostics on stderr should be colorized
+#[cfg(not(all(unix, feature = "libc")))]
+pub fn stderr_is_tty() -> bool {
+    false
+}
+
 /// Diagnostics for specifiers and options combinations
 #[derive(PartialEq, Eq)]
 pub enum ConfigurationConcern {
@@ -11,6 +116,12 @@ pub enum ConfigurationConcern {
     NeedsStdioReuser2,
     MultipleReusers,
     DegenerateMode,
+    /// `--timestamps` without any line mode specifier
+    TimestampsInBinaryMode,
+    /// `--workers` with a left specifier that does not listen on TCP
+    WorkersWithoutTcpListener,
+    /// `--workers` with a specifier like `reuse:`, `broadcast:` or `lb:`, whose state would not be shared between threads
+    WorkersWithSharedState,
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -20,6 +131,131 @@ pub enum AutoInstallLinemodeConcern {
     AlreadyLine,
 }
 
+impl ConfigurationConcern {
+    /// How the concern is reported, if it is worth reporting at all
+    pub fn diagnostic(&self) -> Option<Diagnostic> {
+        use self::ConfigurationConcern::*;
+        match *self {
+            StdinToStdout => Some(Diagnostic::warning("W0001", "Both specifiers are stdio, stdin would just be copied to stdout")),
+            StdioConflict => Some(Diagnostic::error("E0005", "Too many usages of stdin/stdout")),
+            NeedsStdioReuser => Some(Diagnostic::warning("W0002", "Replies on stdio get directed at random connected client")),
+            NeedsStdioReuser2 => None,
+            MultipleReusers => Some(Diagnostic::error("E0006", "Multiple reusers is not allowed")),
+            DegenerateMode => Some(Diagnostic::warning("W0003", "Both directions are inhibited, nothing to do")),
+            TimestampsInBinaryMode => Some(Diagnostic::error("E0007", "--timestamps would corrupt binary data. Use it with --line or msg2line:").about("--timestamps")),
+            WorkersWithoutTcpListener => Some(Diagnostic::error("E0009", "--workers only works with TCP listeners like tcp-l: or ws-l:").about("--workers")),
+            WorkersWithSharedState => Some(Diagnostic::error("E0011", "--workers can't be used with reuse:, broadcast:, lb: or failover:, each thread would have its own clients and backends").about("--workers")),
+        }
+    }
+}
+
+impl AutoInstallLinemodeConcern {
+    pub fn message(&self) -> &'static str {
+        use self::AutoInstallLinemodeConcern::*;
+        match *self {
+            NoWebsocket => "No websocket usage is specified. Use line2msg: and msg2line: specifiers manually if needed.",
+            MultipleWebsocket => "Multiple websocket usages are specified. Use line2msg: and msg2line: specifiers manually if needed.",
+            AlreadyLine => "Can't auto-insert msg2line:/line2msg: if you have already manually specified some of them",
+        }
+    }
+
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::error("E0008", self.message()).about("--linemode")
+    }
+}
+
+/// Host name syntax check, without resolving it
+fn hostname_is_valid(h: &str) -> bool {
+    let h = if h.ends_with('.') { &h[..h.len() - 1] } else { h };
+    !h.is_empty() && h.len() <= 253 && h.split('.').all(|l| {
+        !l.is_empty()
+            && l.len() <= 63
+            && !l.starts_with('-')
+            && !l.ends_with('-')
+            && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Problems in a specifier string that can be found without touching the network:
+/// files that must exist and malformed host names
+pub fn check_specifier(s: &str) -> Vec<Diagnostic> {
+    let mut r = vec![];
+    for (class, full, arg) in spec_chain(s) {
+        let file = match class {
+            "ReadFileClass" | "LiteralFileClass" | "AssertFileClass" | "ReplayClass" => Some(&arg[..]),
+            "PrependFileClass" => arg.split(':').next(),
+            "WsClientClass" | "WssClientClass" => {
+                if let Ok(u) = full.parse::<::websocket::client::Url>() {
+                    if let Some(h) = u.host_str() {
+                        if !h.starts_with('[') && h.parse::<Ipv4Addr>().is_err() && !hostname_is_valid(h) {
+                            r.push(Diagnostic::error("E0004", format!("`{}`: invalid host name `{}`", full, h)).about(full.clone()));
+                        }
+                    }
+                }
+                None
+            }
+            _ => None,
+        };
+        if let Some(f) = file {
+            if !Path::new(f).is_file() {
+                r.push(Diagnostic::error("E0003", format!("`{}`: file `{}` does not exist", full, f)).about(full.clone()));
+            }
+        }
+    }
+    r
+}
+
+/// Problems with files named by options: the certificate of `--pkcs12-der`
+/// must load, match its key and verify against the rest of its archive
+pub fn check_option_files(opts: &Options) -> Vec<Diagnostic> {
+    let mut r = vec![];
+    if let Some(ref p) = opts.pkcs12_der {
+        #[cfg(feature = "ssl")]
+        {
+            let passwd = opts.pkcs12_passwd.clone().unwrap_or_default();
+            if let Err(e) = super::ssl_peer::check(p, &passwd) {
+                r.push(Diagnostic::error("E0010", format!("--pkcs12-der {}: {}", p.display(), e)).about("--pkcs12-der"));
+            }
+        }
+        #[cfg(not(feature = "ssl"))]
+        {
+            let e = format!("--pkcs12-der {}: SSL is not compiled in", p.display());
+            r.push(Diagnostic::error("E0010", e).about("--pkcs12-der"));
+        }
+    }
+    r
+}
+
+/// Everything `--check` reports about a parsed configuration.
+/// Problems with option values and specifier strings are found before there is one,
+/// see `check_specifier` for the latter.
+pub fn diagnostics(conf: &WebsocatConfiguration) -> Vec<Diagnostic> {
+    let mut r: Vec<Diagnostic> = conf.get_concerns().iter().filter_map(|c| c.diagnostic()).collect();
+    r.extend(conf.option_diagnostics());
+    r
+}
+
+/// Options that only make sense with message boundaries, if they are set
+fn message_options(o: &Options) -> Vec<&'static str> {
+    let mut r = vec![];
+    for &(name, set) in &[
+        ("--one-message", o.one_message),
+        ("--max-messages-in", o.max_messages_in.is_some()),
+        ("--max-messages-out", o.max_messages_out.is_some()),
+        ("--filter-in-regex", o.filter_in_regex.is_some()),
+        ("--filter-out-regex", o.filter_out_regex.is_some()),
+        ("--rewrite", !o.rewrite.is_empty()),
+        ("--batch-window-ms", o.batch_window_ms.is_some()),
+        ("--dedup-consecutive", o.dedup_consecutive),
+        ("--dedup-window", o.dedup_window.is_some()),
+    ] {
+        if set {
+            r.push(name);
+        }
+    }
+    r
+}
+
 #[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
 pub enum StdioUsageStatus {
     /// Does not use standard input or output at all
@@ -36,6 +272,8 @@ trait SpecifierExt {
     fn stdio_usage_status(&self) -> StdioUsageStatus;
     fn reuser_count(&self) -> usize;
     fn contains(&self, t: SpecifierType) -> bool;
+    fn listens_on_tcp(&self) -> bool;
+    fn shared_between_sessions(&self) -> bool;
 }
 
 impl<T: Specifier> SpecifierExt for T {
@@ -81,38 +319,65 @@ impl<T: Specifier> SpecifierExt for T {
         }
         false
     }
+
+    fn listens_on_tcp(&self) -> bool {
+        self.get_info().collect().iter().any(|i| i.tcp_listener)
+    }
+
+    fn shared_between_sessions(&self) -> bool {
+        self.get_info().collect().iter().any(|i| i.shared_between_sessions)
+    }
 }
 
 impl WebsocatConfiguration {
+    /// The concern to deal with first
     pub fn get_concern(&self) -> Option<ConfigurationConcern> {
+        self.get_concerns().into_iter().next()
+    }
+
+    /// All concerns at once, most important first
+    pub fn get_concerns(&self) -> Vec<ConfigurationConcern> {
         use self::ConfigurationConcern::*;
         use self::StdioUsageStatus::{IsItself, WithReuser};
+        let mut r = vec![];
 
         if self.s1.stdio_usage_status() == IsItself && self.s2.stdio_usage_status() == IsItself {
             if self.opts.unidirectional && self.opts.unidirectional_reverse {
-                return Some(DegenerateMode);
+                return vec![DegenerateMode];
             }
-            return Some(StdinToStdout);
+            return vec![StdinToStdout];
         }
 
         if self.s1.stdio_usage_status() >= WithReuser && self.s2.stdio_usage_status() >= WithReuser
         {
-            return Some(StdioConflict);
-        }
-
-        if self.s1.is_multiconnect()
+            r.push(StdioConflict);
+        } else if self.s1.is_multiconnect()
             && self.s2.stdio_usage_status() > WithReuser
             && !self.opts.oneshot
         {
             if !self.opts.unidirectional {
-                return Some(NeedsStdioReuser);
+                r.push(NeedsStdioReuser);
             } else {
-                return Some(NeedsStdioReuser2);
+                r.push(NeedsStdioReuser2);
             }
         }
 
         if self.s1.reuser_count() + self.s2.reuser_count() > 1 {
-            return Some(MultipleReusers);
+            r.push(MultipleReusers);
+        }
+
+        if self.opts.timestamps.is_some()
+            && !self.s1.contains(SpecifierType::Line)
+            && !self.s2.contains(SpecifierType::Line)
+        {
+            r.push(TimestampsInBinaryMode);
+        }
+
+        if self.opts.workers.is_some() && !self.s1.listens_on_tcp() {
+            r.push(WorkersWithoutTcpListener);
+        }
+        if self.opts.workers.is_some() && (self.s1.shared_between_sessions() || self.s2.shared_between_sessions()) {
+            r.push(WorkersWithSharedState);
         }
 
         // TODO: listener at right
@@ -123,7 +388,28 @@ impl WebsocatConfiguration {
         // TODO: multiple exec:s
         // TODO: exec: without --exec-args
 
-        None
+        r
+    }
+
+    /// Options that don't fit the specifiers. Unlike concerns, these never change what websocat does.
+    pub fn option_diagnostics(&self) -> Vec<Diagnostic> {
+        use SpecifierType::{Line, WebSocket};
+        let mut r = vec![];
+        if self.s1.contains(WebSocket) || self.s1.contains(Line) || self.s2.contains(WebSocket) || self.s2.contains(Line) {
+            return r;
+        }
+        for name in message_options(&self.opts) {
+            r.push(
+                Diagnostic::warning(
+                    "W0004",
+                    format!(
+                        "{} counts and matches messages, but neither specifier is message-oriented: it would see arbitrary chunks of the byte stream",
+                        name
+                    ),
+                ).about(name),
+            );
+        }
+        r
     }
 
     pub fn auto_install_reuser(self) -> Self {
@@ -0,0 +1,310 @@
+extern crate base64;
+
+use futures;
+use futures::future::ok;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::{BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Error as IoError, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::util::{hexdump, rfc3339_now};
+
+#[derive(Debug)]
+pub struct Log<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Log<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let gs = cp.global_state.borrow_mut().traffic_log.clone();
+        let opts = cp.program_options.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| log_peer(p, &gs, &opts))
+    }
+    specifier_boilerplate!(typ=Other globalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = LogClass,
+    target = Log,
+    prefixes = ["log:"],
+    arg_handling = subspec,
+    consumed_options = &["--log-file", "--log-raw", "--log-base64", "--log-max-size"],
+    help = r#"
+Log all data going through the specifier to a file (--log-file) or to stderr.
+
+Each record contains timestamp, direction (`>` is written to the subspecifier,
+`<` is read from it) and length, followed by a hex dump of the data.
+Use --log-raw or --log-base64 to dump data as is or base64-encoded instead.
+
+All connections share one log file. Writing and rotation happen on a separate
+thread, so a slow disk doesn't hold up the connections; if it can't keep up,
+records are dropped and a warning is printed. The file is flushed when
+a connection closes. With --log-max-size the file gets renamed to `<name>.1`
+when it grows over that size and a new file started.
+
+Example: observe binary protocol exchange
+
+    websocat ws-l:127.0.0.1:8080 log:tcp:127.0.0.1:5000 --log-file /tmp/dump.txt
+"#
+);
+
+#[derive(Debug, Clone, Copy)]
+enum DumpFormat {
+    Hex,
+    Raw,
+    Base64,
+}
+
+/// Records waiting for the writer thread. More than that and they get dropped.
+const QUEUE_DEPTH: usize = 1024;
+
+enum Cmd {
+    Record(Vec<u8>),
+    Flush,
+    Reopen,
+}
+
+/// Owns the log file. Lives on a thread of its own, so that a slow disk
+/// or rotation does not stall the reactor.
+struct Writer {
+    w: Box<Write + Send>,
+    path: Option<PathBuf>,
+    written: u64,
+    max_size: u64,
+    already_warned: bool,
+}
+
+/// Formats records and hands them to the writer thread. Never blocks,
+/// except for waiting for the thread to write everything out when dropped.
+pub struct TrafficLog {
+    tx: Option<SyncSender<Cmd>>,
+    thread: Option<JoinHandle<()>>,
+    format: DumpFormat,
+    dropped: usize,
+}
+
+pub type GlobalState = Rc<RefCell<Option<TrafficLog>>>;
+
+fn open_log_file(p: &PathBuf) -> Result<Box<Write + Send>, IoError> {
+    let f: File = OpenOptions::new().create(true).append(true).open(p)?;
+    Ok(Box::new(BufWriter::with_capacity(65536, f)) as Box<Write + Send>)
+}
+
+impl Writer {
+    /// Start a new file, moving the old one to `<name>.1`
+    fn rotate(&mut self) -> Result<(), IoError> {
+        if let Some(ref p) = self.path {
+            self.w.flush()?;
+            let mut old = p.clone().into_os_string();
+            old.push(".1");
+            ::std::fs::rename(p, &old)?;
+            self.w = open_log_file(p)?;
+            self.written = 0;
+        }
+        Ok(())
+    }
+
+    /// Reopen the file after it was moved away, e.g. by logrotate
+    fn reopen(&mut self) {
+        let p = match self.path {
+            Some(ref p) => p.clone(),
+            None => return,
+        };
+        let _ = self.w.flush();
+        match open_log_file(&p) {
+            Ok(w) => {
+                self.w = w;
+                self.written = ::std::fs::metadata(&p).map(|m| m.len()).unwrap_or(0);
+                self.already_warned = false;
+                info!("Reopened traffic log {}", p.display());
+            }
+            Err(e) => error!("Failed to reopen traffic log {}: {}", p.display(), e),
+        }
+    }
+
+    fn try_write(&mut self, rec: &[u8]) -> Result<(), IoError> {
+        self.w.write_all(rec)?;
+        self.written += rec.len() as u64;
+        if self.max_size != 0 && self.written >= self.max_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn run(mut self, rx: Receiver<Cmd>) {
+        for cmd in rx {
+            match cmd {
+                Cmd::Record(rec) => {
+                    if let Err(e) = self.try_write(&rec) {
+                        if !self.already_warned {
+                            self.already_warned = true;
+                            error!("Failed to write traffic log: {}", e);
+                        }
+                    }
+                }
+                Cmd::Flush => {
+                    let _ = self.w.flush();
+                }
+                Cmd::Reopen => self.reopen(),
+            }
+        }
+        let _ = self.w.flush();
+        debug!("Traffic log thread finished");
+    }
+}
+
+impl TrafficLog {
+    fn new(opts: &Options) -> Result<TrafficLog, IoError> {
+        let format = if opts.log_raw {
+            DumpFormat::Raw
+        } else if opts.log_base64 {
+            DumpFormat::Base64
+        } else {
+            DumpFormat::Hex
+        };
+        let path = opts.log_file.as_ref().map(PathBuf::from);
+        let w = match path {
+            Some(ref p) => open_log_file(p)?,
+            None => Box::new(::std::io::stderr()) as Box<Write + Send>,
+        };
+        let written = match path {
+            Some(ref p) => ::std::fs::metadata(p).map(|m| m.len()).unwrap_or(0),
+            None => 0,
+        };
+        let writer = Writer {
+            w,
+            path,
+            written,
+            max_size: opts.log_max_size,
+            already_warned: false,
+        };
+        let (tx, rx) = sync_channel(QUEUE_DEPTH);
+        let thread = thread::Builder::new()
+            .name("traffic log".to_string())
+            .spawn(move || writer.run(rx))?;
+        Ok(TrafficLog {
+            tx: Some(tx),
+            thread: Some(thread),
+            format,
+            dropped: 0,
+        })
+    }
+
+    fn send(&mut self, cmd: Cmd) {
+        let tx = match self.tx {
+            Some(ref x) => x,
+            None => return,
+        };
+        if let Err(TrySendError::Full(_)) = tx.try_send(cmd) {
+            if self.dropped == 0 {
+                warn!("Traffic log can't keep up, dropping records");
+            }
+            self.dropped += 1;
+        }
+    }
+
+    /// Reopen the file after it was moved away, e.g. by logrotate
+    pub fn reopen(&mut self) {
+        self.send(Cmd::Reopen);
+    }
+
+    pub fn record(&mut self, dir: &str, data: &[u8]) {
+        let mut rec = format!("{} {} {} bytes\n", rfc3339_now(), dir, data.len()).into_bytes();
+        match self.format {
+            DumpFormat::Hex => rec.extend_from_slice(hexdump(data).as_bytes()),
+            DumpFormat::Raw => {
+                rec.extend_from_slice(data);
+                rec.push(b'\n');
+            }
+            DumpFormat::Base64 => {
+                rec.extend_from_slice(base64::encode(data).as_bytes());
+                rec.push(b'\n');
+            }
+        }
+        self.send(Cmd::Record(rec));
+    }
+
+    pub fn flush(&mut self) {
+        self.send(Cmd::Flush);
+    }
+}
+
+impl Drop for TrafficLog {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread write out what's queued and exit
+        self.tx = None;
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+        if self.dropped > 0 {
+            warn!("{} traffic log records were dropped", self.dropped);
+        }
+    }
+}
+
+pub fn log_peer(inner_peer: Peer, gs: &GlobalState, opts: &Options) -> BoxedNewPeerFuture {
+    if gs.borrow().is_none() {
+        match TrafficLog::new(opts) {
+            Ok(x) => *gs.borrow_mut() = Some(x),
+            Err(e) => return super::peer_err(e),
+        }
+        let weak = Rc::downgrade(gs);
+        super::reload::on_reload(move || match weak.upgrade() {
+            Some(gs) => {
+                with_log(&gs, |l| l.reopen());
+                true
+            }
+            None => false,
+        });
+    }
+    let r = LogRead(inner_peer.0, gs.clone());
+    let w = LogWrite(inner_peer.1, gs.clone());
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+fn with_log<F: FnOnce(&mut TrafficLog)>(gs: &GlobalState, f: F) {
+    if let Some(ref mut x) = *gs.borrow_mut() {
+        f(x)
+    }
+}
+
+struct LogRead(Box<AsyncRead>, GlobalState);
+
+impl Read for LogRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let n = self.0.read(buf)?;
+        if n > 0 {
+            with_log(&self.1, |l| l.record("<", &buf[..n]));
+        } else {
+            with_log(&self.1, |l| l.flush());
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for LogRead {}
+
+struct LogWrite(Box<AsyncWrite>, GlobalState);
+
+impl Write for LogWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let n = self.0.write(buf)?;
+        with_log(&self.1, |l| l.record(">", &buf[..n]));
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.flush()
+    }
+}
+impl AsyncWrite for LogWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        with_log(&self.1, |l| l.flush());
+        self.0.shutdown()
+    }
+}
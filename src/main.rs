@@ -2,22 +2,35 @@
 extern crate websocat;
 
 extern crate futures;
+#[cfg(feature = "signal_handler")]
+extern crate tokio_signal;
 extern crate tokio_core;
 extern crate tokio_stdin_stdout;
 
 extern crate env_logger;
+extern crate log;
 
 #[macro_use]
 extern crate structopt;
 
 use structopt::StructOpt;
 
-use tokio_core::reactor::Core;
+use futures::Future;
+use tokio_core::reactor::{Core, Handle, Timeout};
 
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use websocat::builder::{Error as BuildError, WebsocatBuilder};
+use websocat::exit_code::{ErrorReport, ExitCode};
+use websocat::remote_log::RemoteLog;
 use websocat::{spec, Options, SpecifierClass, WebsocatConfiguration};
 
 type Result<T> = std::result::Result<T, Box<std::error::Error>>;
 
+/// Filled in once options are parsed and `--log-syslog` or `--log-journald` is set up
+type RemoteLogSlot = Arc<Mutex<Option<Arc<RemoteLog>>>>;
+
 #[derive(StructOpt, Debug)]
 #[structopt(
     after_help = "
@@ -28,22 +41,41 @@ Basic examples:
   Listen websocket and redirect it to a TCP port:
     websocat ws-l:127.0.0.1:8080 tcp:127.0.0.1:5678
     
+  Pretty-print a capture file made by record:
+    websocat --dump-capture session.cap
+    
+  Set up shell completion, including specifier prefixes (also zsh, fish):
+    source <(websocat --completions bash)
+    
+  List specifiers, options and Cargo features of this build (--json for tools):
+    websocat --list-features --json
+    
+  Read arguments from a file, one per line (also --config <file>):
+    websocat @/etc/websocat/bridge.conf
+    
   See more examples with the --long-help option
   
 Short list of specifiers (see --long-help):
   ws:// wss:// - inetd: ws-listen: inetd-ws: tcp: tcp-l: ws-c:
-  autoreconnect: reuse: mirror: threadedstdio: clogged:
+  autoreconnect: reuse: mirror: badmirror: threadedstdio: clogged:
   literal: literalreply: assert: udp-connect: open-async:
   readfile: writefile: open-fd: unix-connect: unix-listen:
   unix-dgram: abstract-connect: abstract-listen:
-  exec: sh-c:
+  exec: sh-c: lenprefix: log: jsonwrap: msgpack2json: cbor2json:
+  throttle: delay: record: replay: prepend: prepend-file: append:
+  literal-hex: literal-file: random: zero: null: count: seqnum:
+  crc: crc-stream: lb: failover: multilisten: clog:
+  chunk: unchunk:
+  assert-literal: assert-file: filtermsg:
 "
 )]
 struct Opt {
     /// First, listening/connecting specifier. See --long-help for info about specifiers.
-    s1: String,
-    /// Second, connecting specifier
-    s2: String,
+    #[structopt(raw(required_unless = r#""dump_capture""#))]
+    s1: Option<String>,
+    /// Second, connecting specifier. Not used with --bench.
+    #[structopt(raw(required_unless_one = r#"&["bench", "dump_capture"]"#))]
+    s2: Option<String>,
 
     #[structopt(
         short = "u",
@@ -82,6 +114,12 @@ struct Opt {
     )]
     dumpspec: bool,
 
+    #[structopt(
+        long = "dump-capture",
+        help = "Instead of running, pretty-print a capture file written by `record:`"
+    )]
+    dump_capture: Option<String>,
+
     #[structopt(long = "protocol", help = "Specify Sec-WebSocket-Protocol: header")]
     websocket_protocol: Option<String>,
 
@@ -128,17 +166,998 @@ struct Opt {
     #[structopt(long="websocket-version", help="Override the Sec-WebSocket-Version value")]
     websocket_version: Option<String>,
     
-    #[structopt(long="no-close", help="Don't send Close message to websocket on EOF")]
-    websocket_dont_close: bool,
+    #[structopt(long="no-close", help="Don't send Close message to websocket on EOF")]
+    websocket_dont_close: bool,
+    
+    #[structopt(
+        short="1",
+        long="one-message", 
+        help="Send and/or receive only one message. Use with --no-close and/or -u/-U.",
+    )]
+    one_message : bool,
+    
+    #[structopt(
+        long="close-timeout",
+        help="Wait this number of seconds for the WebSocket closing handshake to complete before tearing down the connection [default: 5]. 0 means don't wait",
+    )]
+    close_timeout: Option<u64>,
+    
+    #[structopt(
+        long="lenprefix-bytes",
+        help="Size of the length prefix for `lenprefix:`, 1, 2, 4 or 8 bytes",
+        default_value="4",
+    )]
+    lenprefix_bytes: usize,
+    
+    #[structopt(long="lenprefix-little-endian", help="Use little-endian length prefix in `lenprefix:`")]
+    lenprefix_little_endian: bool,
+    
+    #[structopt(
+        long="lenprefix-includes-header",
+        help="Length prefix value in `lenprefix:` counts the prefix bytes themselves",
+    )]
+    lenprefix_includes_header: bool,
+    
+    #[structopt(
+        long="lenprefix-max",
+        help="Maximum payload size of incoming `lenprefix:` frames. 0 means the default",
+        default_value="1048576",
+    )]
+    lenprefix_max: usize,
+    
+    #[structopt(
+        long="log-file",
+        help="File to write `log:` traffic dumps to instead of stderr. Reopened on SIGHUP.",
+    )]
+    log_file: Option<String>,
+    
+    #[structopt(long="log-raw", help="Write data as is instead of hex dump in `log:`")]
+    log_raw: bool,
+    
+    #[structopt(long="log-base64", help="Write data base64-encoded instead of hex dump in `log:`")]
+    log_base64: bool,
+    
+    #[structopt(
+        long="log-max-size",
+        help="Rotate --log-file when it grows over this number of bytes. 0 means never",
+        default_value="0",
+    )]
+    log_max_size: u64,
+    
+    #[structopt(
+        long="jsonl-input",
+        help="Make `jsonwrap:` expect JSON lines as input and unwrap their `data` into messages",
+    )]
+    jsonl_input: bool,
+    
+    #[structopt(
+        long="binary-as-base64",
+        help="In `msgpack2json:` and `cbor2json:`, show binary blobs as base64 strings instead of arrays of bytes",
+    )]
+    binary_as_base64: bool,
+    
+    #[structopt(
+        long="throttle-bytes-per-sec",
+        help="Data rate limit for `throttle:`, 0 means unlimited",
+        default_value="0",
+    )]
+    throttle_bytes_per_sec: u64,
+    
+    #[structopt(
+        long="throttle-messages-per-sec",
+        help="Message rate limit for `throttle:`, 0 means unlimited",
+        default_value="0",
+    )]
+    throttle_messages_per_sec: u64,
+    
+    #[structopt(
+        long="throttle-burst",
+        help="Token bucket size in bytes for `throttle:`. 0 means one second worth of traffic",
+        default_value="0",
+    )]
+    throttle_burst: u64,
+    
+    #[structopt(
+        long="throttle-direction",
+        help="Which direction `throttle:` limits: in (reads from the subspecifier), out or both",
+        default_value="both",
+    )]
+    throttle_direction: String,
+    
+    #[structopt(long="delay-ms", help="Fixed latency added by `delay:`, in milliseconds", default_value="0")]
+    delay_ms: u64,
+    
+    #[structopt(
+        long="delay-jitter-ms",
+        help="Maximum random latency added by `delay:` on top of --delay-ms, in milliseconds",
+        default_value="0",
+    )]
+    delay_jitter_ms: u64,
+    
+    #[structopt(
+        long="delay-max-queued",
+        help="Stop reading when this many messages are waiting in `delay:`",
+        default_value="64",
+    )]
+    delay_max_queued: usize,
+    
+    #[structopt(
+        long="delay-direction",
+        help="Which direction `delay:` affects: in (reads from the subspecifier), out or both",
+        default_value="both",
+    )]
+    delay_direction: String,
+    
+    #[structopt(long="record-file", help="Capture file for `record:`")]
+    record_file: Option<String>,
+    
+    #[structopt(long="replay-no-timing", help="Make `replay:` emit recorded data as fast as possible")]
+    replay_no_timing: bool,
+    
+    #[structopt(
+        long="replay-speed",
+        help="Playback speed factor for `replay:`, e.g. 2.0 is twice as fast as recorded",
+        default_value="1.0",
+    )]
+    replay_speed: f64,
+    
+    #[structopt(
+        long="replay-strict",
+        help="Make `replay:` fail if data sent to it differs from the recorded outgoing data",
+    )]
+    replay_strict: bool,
+    
+    #[structopt(
+        long="idle-timeout",
+        help="Close a session after this many seconds without data transfer (exit code is 2 if that ends the program)",
+    )]
+    idle_timeout: Option<u64>,
+    
+    #[structopt(
+        long="idle-timeout-direction",
+        help="Which data counts as activity for --idle-timeout: `in` (from the second specifier), `out` (to it) or `both`",
+        default_value="both",
+    )]
+    idle_timeout_direction: String,
+    
+    #[structopt(
+        long="separator",
+        help="Line separator for line2msg: and msg2line:, with escapes like \\0, \\r\\n or \\xNN",
+        default_value="\\n",
+    )]
+    separator: String,
+    
+    #[structopt(
+        long="separator-n",
+        help="Number of consecutive separators forming a line boundary",
+        default_value="1",
+    )]
+    separator_n: usize,
+    
+    #[structopt(
+        long="separator-conflict",
+        help="What msg2line: does with messages containing the separator: space, error, escape or pass",
+        default_value="space",
+    )]
+    separator_conflict: String,
+    
+    #[structopt(
+        long="line-escape",
+        help="How msg2line: deals with separators inside messages: backslash (reversed by line2msg:), base64, strip or error. Overrides --separator-conflict",
+    )]
+    line_escape: Option<String>,
+    
+    #[structopt(long="random-seed", help="Seed for `random:` and `badmirror:` to generate reproducible data or faults")]
+    random_seed: Option<u64>,
+    
+    #[structopt(
+        long="gen-message-size",
+        help="Size of each chunk (message) emitted by `random:` and `zero:`",
+    )]
+    gen_message_size: Option<usize>,
+    
+    #[structopt(
+        long="count-output",
+        help="Append `count:` summaries to this file as JSON lines instead of printing them to stderr",
+    )]
+    count_output: Option<String>,
+    
+    #[structopt(
+        long="seqnum-strict",
+        help="Make `seqnum:` fail the session on lost, duplicated or reordered messages",
+    )]
+    seqnum_strict: bool,
+    
+    #[structopt(
+        long="crc-algo",
+        help="Checksum for `crc:` and `crc-stream:`: crc32c or xxhash",
+        default_value="crc32c",
+    )]
+    crc_algo: String,
+    
+    #[structopt(
+        long="crc-strict",
+        help="Make `crc:` and `crc-stream:` fail the session on checksum mismatch instead of dropping the message",
+    )]
+    crc_strict: bool,
+    
+    #[structopt(
+        long="broadcast-retain",
+        help="Make `broadcast:` replay the most recent upstream message to each newly connected client",
+    )]
+    broadcast_retain: bool,
+    
+    #[structopt(
+        long="broadcast-retain-count",
+        help="Number of recent upstream messages `broadcast:` replays to new clients. Implies --broadcast-retain",
+    )]
+    broadcast_retain_count: Option<usize>,
+    
+    #[structopt(
+        long="broadcast-retain-max-bytes",
+        help="Limit on total size of messages retained by --broadcast-retain, 0 for no limit",
+        default_value="1048576",
+    )]
+    broadcast_retain_max_bytes: usize,
+    
+    #[structopt(
+        long="retain-across-reconnect",
+        help="Keep --broadcast-retain messages when `autoreconnect:` re-establishes the upstream",
+    )]
+    retain_across_reconnect: bool,
+    
+    #[structopt(
+        long="reuse-buffer",
+        help="Make `broadcast:` queue up to this number of messages arriving while no clients are connected, for the next client",
+    )]
+    reuse_buffer: Option<usize>,
+    
+    #[structopt(
+        long="reuse-buffer-bytes",
+        help="Limit on total size of messages queued by --reuse-buffer [default: 1048576]",
+    )]
+    reuse_buffer_bytes: Option<usize>,
+    
+    #[structopt(
+        long="reuse-buffer-overflow",
+        raw(possible_values = r#"&["drop-oldest", "drop-newest", "backpressure"]"#),
+        help="What to do when --reuse-buffer is full: drop-oldest, drop-newest or backpressure",
+        default_value="drop-oldest",
+    )]
+    reuse_buffer_overflow: String,
+    
+    #[structopt(
+        long="lb-policy",
+        help="How `lb:` picks a backend for each session: roundrobin, random or first-available",
+        default_value="roundrobin",
+    )]
+    lb_policy: String,
+    
+    #[structopt(
+        long="lb-quarantine",
+        help="Number of seconds `lb:` avoids a backend after failing to connect to it",
+        default_value="10",
+    )]
+    lb_quarantine: u64,
+    
+    #[structopt(
+        long="failover-cooldown",
+        help="Number of seconds `failover:` skips a target after failing to connect to it",
+        default_value="30",
+    )]
+    failover_cooldown: u64,
+    
+    #[structopt(
+        long="listen-best-effort",
+        help="Make `multilisten:` continue with other listeners if some of them fail",
+    )]
+    listen_best_effort: bool,
+    
+    #[structopt(
+        long="exec-arg",
+        raw(allow_hyphen_values = r#"true"#, number_of_values = r#"1"#),
+        help="Add one argument for the `exec:` specifier. Can be used multiple times.",
+    )]
+    exec_arg: Vec<String>,
+    
+    #[structopt(
+        long="exec-env",
+        raw(number_of_values = r#"1"#),
+        help="Set environment variable NAME=value for `exec:` and `sh-c:` children. Can be used multiple times.",
+        parse(try_from_str="interpret_exec_env"),
+    )]
+    exec_env: Vec<(String,String)>,
+    
+    #[structopt(
+        long="exec-clearenv",
+        help="Start `exec:` and `sh-c:` children with empty environment (except for --exec-env)",
+    )]
+    exec_clearenv: bool,
+    
+    #[structopt(long="exec-chdir", help="Working directory for `exec:` and `sh-c:` children")]
+    exec_chdir: Option<String>,
+    
+    #[structopt(
+        long="exec-umask",
+        help="File mode creation mask (octal, like 027) for `exec:` and `sh-c:` children",
+        parse(try_from_str="interpret_umask"),
+    )]
+    exec_umask: Option<u32>,
+    
+    #[structopt(
+        long="exec-pty",
+        help="Run `exec:` and `sh-c:` children in a pseudo-terminal instead of pipes",
+    )]
+    exec_pty: bool,
+    
+    #[structopt(long="pty-size", help="Initial terminal size for --exec-pty, like 80x24")]
+    pty_size: Option<String>,
+    
+    #[structopt(
+        long="pty-resize-prefix",
+        help="Messages starting with this prefix followed by COLSxROWS resize the --exec-pty terminal",
+    )]
+    pty_resize_prefix: Option<String>,
+    
+    #[structopt(
+        long="exec-kill-signal",
+        help="Signal sent to `exec:` and `sh-c:` children when the session ends, like TERM, INT or 15",
+        default_value="TERM",
+    )]
+    exec_kill_signal: String,
+    
+    #[structopt(
+        long="exec-kill-timeout",
+        help="Send SIGKILL to a child that is still running this number of seconds after --exec-kill-signal",
+    )]
+    exec_kill_timeout: Option<u64>,
+    
+    #[structopt(
+        long="exec-no-kill",
+        help="Don't terminate `exec:` and `sh-c:` children when the session ends, just close their input",
+    )]
+    exec_no_kill: bool,
+    
+    #[structopt(
+        long="exit-status-from-exec",
+        help="Wait for the `exec:` or `sh-c:` child and exit with its exit code (128+N if killed by signal N). If the session fails, exit code is 125.",
+    )]
+    exit_status_from_exec: bool,
+    
+    #[structopt(
+        long="env-headers",
+        raw(number_of_values = r#"1"#),
+        help="Comma-separated names of WebSocket request headers to pass to `exec:` and `sh-c:` children as WEBSOCAT_HEADER_<NAME> environment variables. Can be used multiple times.",
+    )]
+    env_headers: Vec<String>,
+    
+    #[structopt(
+        long="file-append",
+        help="Open files of `writefile:` and `open-async:` in append mode",
+    )]
+    file_append: bool,
+    
+    #[structopt(
+        long="file-create-new",
+        help="Fail instead of opening an existing file in `writefile:`, `appendfile:` and `open-async:`",
+    )]
+    file_create_new: bool,
+    
+    #[structopt(
+        long="file-truncate",
+        help="Truncate the file opened by `open-async:` (`writefile:` always truncates unless --file-append)",
+    )]
+    file_truncate: bool,
+    
+    #[structopt(
+        long="file-sync",
+        help="fsync files of `writefile:` and `appendfile:` when closing them",
+    )]
+    file_sync: bool,
+    
+    #[structopt(
+        long="file-start-offset",
+        help="Start reading `readfile:` at this byte offset; negative value counts from the end of file",
+        raw(allow_hyphen_values = r#"true"#),
+    )]
+    file_start_offset: Option<i64>,
+    
+    #[structopt(long="file-max-bytes", help="Read at most this many bytes from `readfile:`")]
+    file_max_bytes: Option<u64>,
+    
+    #[structopt(
+        long="file-follow",
+        help="Like `tail -F`: on end of `readfile:` file, wait for more data instead of finishing. Truncated or replaced file is reopened.",
+    )]
+    file_follow: bool,
+    
+    #[structopt(long="mirror-delay-ms", help="Delay echo of `badmirror:` by this many milliseconds", default_value="0")]
+    mirror_delay_ms: u64,
+    
+    #[structopt(long="mirror-drop-rate", help="Probability (0..1) of `badmirror:` silently dropping a message", default_value="0")]
+    mirror_drop_rate: f64,
+    
+    #[structopt(long="mirror-corrupt-rate", help="Probability (0..1) of `badmirror:` changing a random byte of a message", default_value="0")]
+    mirror_corrupt_rate: f64,
+    
+    #[structopt(long="mirror-dup-rate", help="Probability (0..1) of `badmirror:` echoing a message twice", default_value="0")]
+    mirror_dup_rate: f64,
+    
+    #[structopt(long="mirror-close-after", help="Make `badmirror:` abruptly fail the session after this many messages")]
+    mirror_close_after: Option<u64>,
+    
+    #[structopt(
+        long="clog-direction",
+        help="Which direction `clog:` stalls: read (from the subspecifier), write or both",
+        default_value="both",
+    )]
+    clog_direction: String,
+    
+    #[structopt(long="clog-after-bytes", help="Let this many bytes through `clog:` before stalling", default_value="0")]
+    clog_after_bytes: u64,
+    
+    #[structopt(long="clog-duration", help="Resume `clog:` after this many seconds instead of stalling forever")]
+    clog_duration: Option<u64>,
+    
+    #[structopt(long="assert-allow-extra", help="Let `assert:` and friends accept input continuing after the expected data")]
+    assert_allow_extra: bool,
+    
+    #[structopt(long="assert-timeout", help="Fail `assert:` and friends if input does not end in this many seconds")]
+    assert_timeout: Option<u64>,
+    
+    #[structopt(
+        long="filter-persistent",
+        help="Keep one `filtermsg:` process per direction, talking to it with length-prefixed frames",
+    )]
+    filter_persistent: bool,
+    
+    #[structopt(
+        long="filter-concurrency",
+        help="Maximum number of `filtermsg:` processes running at once per direction",
+        default_value="4",
+    )]
+    filter_concurrency: usize,
+    
+    #[structopt(
+        long="filter-direction",
+        help="Which direction `filtermsg:` filters: in (reads from the subspecifier), out or both",
+        default_value="both",
+    )]
+    filter_direction: String,
+    
+    #[structopt(
+        long="filter-in-regex",
+        help="Drop messages coming from the right specifier that match this regex (see --filter-mode)",
+    )]
+    filter_in_regex: Option<String>,
+    
+    #[structopt(
+        long="filter-out-regex",
+        help="Drop messages going to the right specifier that match this regex (see --filter-mode)",
+    )]
+    filter_out_regex: Option<String>,
+    
+    #[structopt(
+        long="filter-mode",
+        help="`drop` messages matching --filter-in-regex/--filter-out-regex, or `keep` only them",
+        default_value="drop",
+    )]
+    filter_mode: String,
+    
+    #[structopt(
+        long="rewrite",
+        raw(number_of_values = r#"1"#),
+        help="Rewrite text messages with sed-like s/pattern/replacement/[gi] expression. Can be used multiple times, applied in order.",
+    )]
+    rewrite: Vec<String>,
+    
+    #[structopt(
+        long="rewrite-direction",
+        help="Which messages --rewrite applies to: in (coming from the right specifier), out or both",
+        default_value="both",
+    )]
+    rewrite_direction: String,
+    
+    #[structopt(
+        long="rewrite-binary",
+        help="Also apply --rewrite to messages that are not valid UTF-8, as bytes",
+    )]
+    rewrite_binary: bool,
+    
+    #[structopt(long="rewrite-max-size", help="Don't apply --rewrite to messages longer than this number of bytes")]
+    rewrite_max_size: Option<usize>,
+    
+    #[structopt(
+        long="chunk-header",
+        help="Header for pieces produced by `chunk:`: none or seq/total (needed by `unchunk:`)",
+        default_value="none",
+    )]
+    chunk_header: String,
+    
+    #[structopt(
+        long="batch-window-ms",
+        help="Coalesce messages arriving within this many milliseconds after the first one into a single message",
+    )]
+    batch_window_ms: Option<u64>,
+    
+    #[structopt(long="batch-max-bytes", help="Send the --batch-window-ms batch early when it reaches this size")]
+    batch_max_bytes: Option<usize>,
+    
+    #[structopt(long="batch-max-count", help="Send the --batch-window-ms batch early when it has this many messages")]
+    batch_max_count: Option<usize>,
+    
+    #[structopt(
+        long="batch-separator",
+        help="Insert this between messages joined by --batch-window-ms. Escapes like \\n and \\x00 are recognized.",
+        default_value="",
+    )]
+    batch_separator: String,
+    
+    #[structopt(
+        long="batch-direction",
+        help="Which messages --batch-window-ms coalesces: in (coming from the right specifier), out or both",
+        default_value="out",
+    )]
+    batch_direction: String,
+    
+    #[structopt(long="dedup-consecutive", help="Drop messages identical to the previous one")]
+    dedup_consecutive: bool,
+    
+    #[structopt(
+        long="dedup-window",
+        help="Drop messages identical to any of the last <n> distinct messages (implies --dedup-consecutive)",
+    )]
+    dedup_window: Option<usize>,
+    
+    #[structopt(
+        long="dedup-direction",
+        help="Which messages are deduplicated: in (coming from the right specifier), out or both",
+        default_value="both",
+    )]
+    dedup_direction: String,
+    
+    #[structopt(
+        long="dedup-max-silence",
+        help="Let a duplicate message through if nothing has been passed for this many seconds",
+    )]
+    dedup_max_silence: Option<u64>,
+    
+    #[structopt(
+        long="max-messages-in",
+        help="Close the session gracefully after receiving this many messages from the right specifier",
+    )]
+    max_messages_in: Option<u64>,
+    
+    #[structopt(
+        long="max-messages-out",
+        help="Close the session gracefully after sending this many messages to the right specifier",
+    )]
+    max_messages_out: Option<u64>,
+    
+    #[structopt(
+        long="max-bytes-in",
+        help="Close the session gracefully after receiving this many bytes from the right specifier",
+    )]
+    max_bytes_in: Option<u64>,
+    
+    #[structopt(
+        long="max-bytes-out",
+        help="Close the session gracefully after sending this many bytes to the right specifier",
+    )]
+    max_bytes_out: Option<u64>,
+    
+    #[structopt(
+        long="metrics-addr",
+        help="Serve Prometheus-format counters on http://<addr>/metrics (and /healthz), like 127.0.0.1:9300",
+    )]
+    metrics_addr: Option<std::net::SocketAddr>,
+    
+    #[structopt(
+        long="lazy-connect",
+        help="Don't connect the right specifier until the left one sends some data. Sessions that end before that never connect.",
+    )]
+    lazy_connect: bool,
+    
+    #[structopt(
+        long="lazy-buffer-bytes",
+        help="Maximum size of the first chunk of data held back by --lazy-connect",
+        default_value="65536",
+    )]
+    lazy_buffer_bytes: usize,
+    
+    #[structopt(
+        long="total-max-bytes",
+        help="Shut down gracefully after relaying this many bytes in total, across all sessions and reconnects",
+    )]
+    total_max_bytes: Option<u64>,
+    
+    #[structopt(
+        long="total-max-messages",
+        help="Shut down gracefully after relaying this many messages in total, across all sessions and reconnects",
+    )]
+    total_max_messages: Option<u64>,
+    
+    #[structopt(long="total-max-duration", help="Shut down gracefully after running for this many seconds")]
+    total_max_duration: Option<u64>,
+    
+    #[structopt(
+        long="drain-timeout",
+        help="On graceful shutdown, wait at most this many seconds for sessions in progress to finish",
+        default_value="30",
+    )]
+    drain_timeout: u64,
+    
+    #[structopt(
+        long="stats",
+        help="On exit, print traffic statistics (bytes, messages, throughput, reconnects, close code, WebSocket bytes queued vs. actually sent) to stderr",
+    )]
+    stats: bool,
+    
+    #[structopt(long="stats-json", help="On exit, write the statistics as a JSON object to this file, or to stdout if `-`")]
+    stats_json: Option<String>,
+    
+    #[structopt(
+        long="pid-file",
+        help="Write process id to this file once listeners are bound; remove it on exit",
+        parse(from_os_str),
+    )]
+    pid_file: Option<std::path::PathBuf>,
+    
+    #[structopt(
+        long="pkcs12-der",
+        help="Certificate and key for `ssl-accept:` and `wss-l:`, as a PKCS#12 archive. Read again on SIGHUP.",
+        parse(from_os_str),
+    )]
+    pkcs12_der: Option<std::path::PathBuf>,
+    
+    #[structopt(long="pkcs12-passwd", help="Password for --pkcs12-der")]
+    pkcs12_passwd: Option<String>,
+    
+    #[structopt(
+        long="shutdown-close-code",
+        help="WebSocket status code sent to clients when SIGTERM or SIGINT closes their sessions",
+        default_value="1001",
+    )]
+    shutdown_close_code: u16,
+    
+    #[structopt(
+        long="max-sessions",
+        help="Maximum number of concurrent sessions across all listeners. Excess connections are closed (WebSocket clients get 503).",
+    )]
+    max_sessions: Option<u64>,
+    
+    #[structopt(
+        long="max-sessions-backpressure",
+        help="Instead of refusing connections above --max-sessions, stop accepting until a session ends",
+    )]
+    max_sessions_backpressure: bool,
+    
+    #[structopt(
+        long="log-format",
+        help="`json` to log connection lifecycle events (accepted, handshake, reconnect, session end) as JSON lines with a session id `sid`",
+        default_value="text",
+    )]
+    log_format: String,
+    
+    #[structopt(
+        long="events-file",
+        help="File to write --log-format json events to instead of stderr. Reopened on SIGHUP.",
+    )]
+    events_file: Option<String>,
+    
+    #[structopt(long="log-syslog", help="Send lifecycle events, warnings and errors to syslog via /dev/log")]
+    log_syslog: bool,
+    
+    #[structopt(long="log-syslog-addr", help="Send syslog messages over UDP to this host:port instead of /dev/log. Implies --log-syslog.")]
+    log_syslog_addr: Option<String>,
+    
+    #[structopt(
+        long="log-journald",
+        help="Send lifecycle events, warnings and errors to systemd-journald with fields like SESSION_ID, PEER and CLOSE_CODE",
+    )]
+    log_journald: bool,
+    
+    #[structopt(
+        long="on-connect",
+        help="Shell command to run in background when a session starts. Gets WEBSOCAT_SESSION_ID, WEBSOCAT_CLIENT, WEBSOCAT_URI in environment.",
+    )]
+    on_connect: Option<String>,
+    
+    #[structopt(
+        long="on-disconnect",
+        help="Shell command to run in background when a session ends, also with WEBSOCAT_BYTES_IN, WEBSOCAT_BYTES_OUT, WEBSOCAT_CLOSE_CODE, WEBSOCAT_ERROR",
+    )]
+    on_disconnect: Option<String>,
+    
+    #[structopt(
+        long="hook-max-concurrent",
+        help="Maximum number of --on-connect/--on-disconnect commands running at once; others wait in a queue",
+        default_value="4",
+    )]
+    hook_max_concurrent: usize,
+    
+    #[structopt(
+        long="old-exit-codes",
+        help="Exit with code 1 instead of 10-19 and with 0 after SIGINT/SIGTERM, like older versions. Codes 2, 3 and 125 of options that older versions lack stay. See --long-help for exit codes.",
+    )]
+    old_exit_codes: bool,
+    
+    #[structopt(
+        short="q",
+        long="quiet",
+        help="Don't log anything but errors, regardless of RUST_LOG. Also suppresses warnings about the command line.",
+    )]
+    quiet: bool,
+    
+    #[structopt(
+        long="errors-json",
+        help="Report a fatal error as a single-line JSON object on stderr, like {\"error\":\"handshake_rejected\",\"detail\":\"403 Forbidden\",\"url\":\"ws://...\",\"code\":14}. `code` is the exit code. Only the first failed session is reported.",
+    )]
+    errors_json: bool,
+    
+    #[structopt(
+        long="exit-on-epipe-status",
+        help="Exit with this code if stdout gets closed by its reader (like `| head -1`). By default that closes the sessions normally and exits with 0.",
+    )]
+    exit_on_epipe_status: Option<i32>,
+    
+    #[structopt(
+        long="check",
+        help="Validate options and specifiers, including existence of files they refer to, without connecting or listening. Lists all problems found.",
+    )]
+    check: bool,
+    
+    #[structopt(
+        long="allow",
+        raw(number_of_values = r#"1"#),
+        help="Don't report configuration warnings or --check diagnostics with this code, like W0004. Can be used multiple times.",
+    )]
+    allow: Vec<String>,
+    
+    #[structopt(
+        long="each-line-of",
+        help="Run a session for each target listed in this file (one per line), substituting it for `%s` in the second specifier. Prints a table of results.",
+    )]
+    each_line_of: Option<String>,
+    
+    #[structopt(
+        long="parallel",
+        help="With --each-line-of, maximum number of sessions at once",
+        default_value="8",
+    )]
+    parallel: usize,
+    
+    #[structopt(
+        long="target-timeout",
+        help="With --each-line-of, close a session still running after this many milliseconds and count its target as failed. 0 means no limit.",
+        default_value="10000",
+    )]
+    target_timeout: u64,
+    
+    #[structopt(
+        long="report-json",
+        help="With --each-line-of or --bench, print results as JSON instead of a table",
+    )]
+    report_json: bool,
+    
+    #[structopt(
+        long="bench",
+        help="Measure echo latency and throughput of the WebSocket server given as the only specifier",
+    )]
+    bench: bool,
+    
+    #[structopt(
+        long="bench-messages",
+        help="With --bench, number of messages to send, in total for all connections",
+        default_value="1000",
+    )]
+    bench_messages: u64,
+    
+    #[structopt(
+        long="bench-size",
+        help="With --bench, message size in bytes, at least 16",
+        default_value="64",
+    )]
+    bench_size: usize,
+    
+    #[structopt(
+        long="bench-concurrency",
+        help="With --bench, number of connections",
+        default_value="1",
+    )]
+    bench_concurrency: usize,
+    
+    #[structopt(
+        long="bench-rate",
+        help="With --bench, send this many messages per second in total, on schedule, instead of waiting for each echo. Latency is counted from the scheduled time.",
+    )]
+    bench_rate: Option<f64>,
+    
+    #[structopt(
+        long="readline",
+        help="Line editing and history (~/.websocat_history) for stdin when it is a terminal. Incoming messages are printed above the prompt. Ctrl-D sends EOF, Ctrl-C twice closes the connection.",
+    )]
+    readline: bool,
+    
+    #[structopt(
+        long="no-close-on-stdin-eof",
+        help="On EOF from the left specifier, stop sending, but keep the connection (and WebSocket) open until the right side closes or --idle-timeout fires. Not for -u or -E; -U never closes on stdin EOF anyway.",
+    )]
+    no_close_on_stdin_eof: bool,
+    
+    #[structopt(
+        long="close-stdin-only",
+        help="Like --no-close-on-stdin-eof, but half-close the outgoing direction right away where possible (e.g. TCP). WebSocket Close is still sent only when the incoming direction ends.",
+    )]
+    close_stdin_only: bool,
+    
+    #[structopt(
+        long="timestamps",
+        help="Prefix each line printed in line mode with the time of its message: rfc3339 (the default), epoch-ms or relative (seconds since session start). Not for binary mode.",
+        raw(min_values="0", require_equals="true"),
+    )]
+    timestamps: Option<String>,
+    
+    #[structopt(
+        long="timestamp-separator",
+        help="What goes between --timestamps and the line, with escapes like \\t",
+        default_value=" ",
+    )]
+    timestamp_separator: String,
+    
+    #[structopt(
+        long="notify-fd",
+        help="Once listeners are bound, write `READY` line to this file descriptor and close it. For supervisors other than systemd; `Type=notify` units get READY=1 via $NOTIFY_SOCKET automatically.",
+    )]
+    notify_fd: Option<i32>,
+    
+    #[structopt(
+        long="flush-after-message",
+        help="Flush stdout or the output file after each message or chunk of data written there. This is the default.",
+    )]
+    flush_after_message: bool,
+    
+    #[structopt(
+        long="flush-interval-ms",
+        help="Buffer stdout or the output file, flushing it at this interval (and when the buffer fills up). For raw stream mode, to trade latency for fewer writes.",
+    )]
+    flush_interval_ms: Option<u64>,
+    
+    #[structopt(
+        long="no-flush",
+        help="Buffer stdout or the output file as much as possible, flushing only when the buffer fills up or the session ends. For throughput.",
+    )]
+    no_flush: bool,
+    
+    #[structopt(
+        long="include-headers",
+        help="Print the WebSocket handshake response (status line, headers and an empty line) before incoming data, like `curl -i`. Printed once per session, even if the connection is re-established.",
+    )]
+    include_headers: bool,
+    
+    #[structopt(
+        long="include-headers-every-connect",
+        help="Like --include-headers, but print the handshake response again each time the WebSocket client connects, e.g. with `autoreconnect:`",
+    )]
+    include_headers_every_connect: bool,
+    
+    #[structopt(
+        long="response-header-file",
+        help="Write the WebSocket handshake response (status line and headers) to this file, overwriting it on each connect",
+        parse(from_os_str),
+    )]
+    response_header_file: Option<std::path::PathBuf>,
+    
+    #[structopt(
+        long="no-splice",
+        help="Always copy data through websocat, even if both specifiers are plain TCP or UNIX stream sockets that could be spliced, or readfile: to such a socket (Linux)",
+    )]
+    no_splice: bool,
+    
+    #[structopt(
+        long="buffer-size",
+        help="Size of the buffer each direction of a session reads into, in bytes [default: 65536]",
+    )]
+    buffer_size: Option<usize>,
+    
+    #[structopt(
+        long="high-watermark",
+        help="Keep reading ahead while the other side is slow to accept data, until this many bytes are queued. Reading resumes when the queue drains to half of that. Without it, nothing is read until the previous read is written out",
+    )]
+    high_watermark: Option<usize>,
+    
+    #[structopt(
+        long="cork-window-us",
+        help="Gather small WebSocket frames sent in quick succession into one write, holding them back for at most this many microseconds. 0 sends each frame with its own write [default: 200]",
+    )]
+    cork_window_us: Option<u64>,
+    
+    #[structopt(
+        long="workers",
+        help="Serve a TCP listener (tcp-l:, ws-l:) with this many threads, each accepting connections on its own SO_REUSEPORT socket and running its own sessions (Linux). Threads share no sessions, so not with --oneshot, reuse:, broadcast: or lb:",
+    )]
+    workers: Option<usize>,
+    
+    #[structopt(
+        long="preconnect",
+        help="For autoreconnect:, keep this many spare connections established in advance while the current one is in use, and switch to one of them when it fails",
+        default_value="0",
+    )]
+    preconnect: usize,
+    
+    #[structopt(
+        long="preconnect-max-age",
+        help="Number of seconds after which a --preconnect spare is replaced by a new one, so that a connection dropped silently by the peer or a NAT is not taken over. 0 keeps spares indefinitely",
+        default_value="30",
+    )]
+    preconnect_max_age: u64,
+    
+    #[structopt(
+        long="raw-relay",
+        help="Relay bytes without keeping message boundaries: with --high-watermark, each direction reads ahead into one buffer of that size allocated at session start, and nothing gets allocated while relaying. Chosen automatically for plain byte streams on both sides (tcp:, unix:, ...)",
+    )]
+    raw_relay: bool,
+    
+    #[structopt(
+        long="pipe-instances",
+        help="[Windows] Number of clients pipe-l: serves at once [default: 1]",
+    )]
+    pipe_instances: Option<usize>,
+    
+    #[structopt(
+        long="pipe-sddl",
+        help="[Windows] Security descriptor of the pipe created by pipe-l:, in SDDL like `D:(A;;GA;;;AU)`",
+    )]
+    pipe_sddl: Option<String>,
+    
+    #[structopt(
+        long="pipe-message-mode",
+        help="[Windows] Message-type named pipes for pipe: and pipe-l:, keeping message boundaries",
+    )]
+    pipe_message_mode: bool,
+    
+    #[structopt(
+        long="allow-partial-datagrams",
+        help="If udp:, unix-dgram: or seqpacket: sockets send only a part of a message, drop the rest with a warning instead of failing the session",
+    )]
+    allow_partial_datagrams: bool,
+    
+    #[structopt(
+        long="bind-retry",
+        help="If the address of tcp-l: or unix-l: is in use (or not available yet), try binding again this many times. Readiness is reported only after binding.",
+        default_value="0",
+    )]
+    bind_retry: usize,
+    
+    #[structopt(
+        long="bind-retry-delay",
+        help="Milliseconds to wait before each of --bind-retry attempts",
+        default_value="1000",
+    )]
+    bind_retry_delay: u64,
+    
+    #[structopt(
+        long="flush-timeout",
+        help="On shutdown, wait this number of seconds for queued WebSocket data to be sent before closing [default: 30]. 0 means no limit",
+    )]
+    flush_timeout: Option<u64>,
     
     #[structopt(
-        short="1",
-        long="one-message", 
-        help="Send and/or receive only one message. Use with --no-close and/or -u/-U.",
+        long="no-url-normalization",
+        help="Send the path and query of ws:// URLs (and --ws-c-uri) in the request line exactly as given, without percent-encoding them. For testing server parsers.",
     )]
-    one_message : bool,
+    no_url_normalization: bool,
     
-    // TODO: -v --quiet
+    // TODO: -v
+}
+
+impl Opt {
+    fn s1(&self) -> &str {
+        self.s1.as_ref().map_or("", |x| &x[..])
+    }
+    fn s2(&self) -> &str {
+        self.s2.as_ref().map_or("", |x| &x[..])
+    }
 }
 
 // TODO: make it byte-oriented/OsStr?
@@ -155,6 +1174,21 @@ fn interpret_custom_header(x:&str) -> Result<(String,Vec<u8>)> {
     Ok((hn.to_owned(), hv.as_bytes().to_vec()))
 }
 
+fn interpret_exec_env(x:&str) -> Result<(String,String)> {
+    let eq = match x.find('=') {
+        Some(0) | None => Err("Argument to --exec-env must look like NAME=value")?,
+        Some(x) => x,
+    };
+    Ok((x[..eq].to_owned(), x[eq+1..].to_owned()))
+}
+
+fn interpret_umask(x:&str) -> Result<u32> {
+    match u32::from_str_radix(x, 8) {
+        Ok(m) if m <= 0o777 => Ok(m),
+        _ => Err("Argument to --exec-umask must be an octal number like 022")?,
+    }
+}
+
 fn longhelp() {
     println!(
         r#"(see also the usual --help message)
@@ -192,17 +1226,44 @@ Here is the full list of specifier classes in this WebSocat build:
         println!("{}\n", help);
     }
 
-    macro_rules! my {
-        ($x:expr) => {
-            help1(&$x);
-        };
+    for c in websocat::specparse::registered_classes() {
+        help1(&*c);
     }
 
-    list_of_all_specifier_classes!(my);
-
     println!(
         r#"
   
+Exit codes:
+
+    0    success
+    1    other error
+    2    --idle-timeout fired
+    3    --total-max-bytes, --total-max-messages or --total-max-duration reached
+    10   invalid options or specifier combination
+    11   failed to resolve host name
+    12   failed to connect (refused, timed out, reset)
+    13   TLS failure
+    14   WebSocket handshake rejected with HTTP 4xx
+    15   WebSocket handshake rejected with HTTP 5xx
+    16   other WebSocket handshake failure
+    17   WebSocket closed with code other than 1000/1001, or without Close frame
+    18   local I/O error (bind, files, ...)
+    19   some of --each-line-of targets failed, or --bench echoes lost
+    125  session failed with --exit-status-from-exec
+    130  shut down by SIGINT (128+2)
+    143  shut down by SIGTERM (128+15)
+
+Stdout closed by its reader is not an error: sessions get closed normally and the exit
+code is 0, unless set with --exit-on-epipe-status.
+
+With --old-exit-codes, codes 10-19 become 1 and signal shutdown exits with 0, like in
+older versions. Codes 2, 3 and 125 stay, as they only come with options older versions
+don't have.
+
+With --errors-json, the error is printed as a JSON object with `error` field being
+one of: error, idle_timeout, usage, dns, connect_failed, tls, handshake_rejected,
+abnormal_close, io, session_failed. Errors of a peer also have `context` naming its
+specifier, like "tcp:10.0.0.5:9000 (right side, inside autoreconnect:), session 3".
   
 TODO:
   sctp:
@@ -222,18 +1283,749 @@ then forward resulting connection to the TCP port.
     );
 }
 
-fn run() -> Result<()> {
+/// --each-line-of: one session per target, then the report
+fn each_target(cmd: &Opt, list: &str, opts: Options) -> Result<()> {
+    use websocat::targets;
+    if cmd.parallel == 0 {
+        Err("--parallel must be positive")?
+    }
+    if !cmd.s2().contains(targets::PLACEHOLDER) {
+        Err("With --each-line-of, second specifier must contain `%s`")?
+    }
+    let text = std::fs::read_to_string(list)?;
+    let list = targets::parse_target_list(&text);
+    if list.is_empty() {
+        Err("No targets in --each-line-of file")?
+    }
+    OPTIONS_ACCEPTED.with(|x| x.set(true));
+    let mut core = Core::new()?;
+    let timeout = if cmd.target_timeout > 0 {
+        Some(std::time::Duration::from_millis(cmd.target_timeout))
+    } else {
+        None
+    };
+    let prog = targets::run_targets(&core.handle(), cmd.s1(), cmd.s2(), list, opts, cmd.parallel, timeout);
+    let results = core.run(prog).map_err(|()| "error running")?;
+    if cmd.report_json {
+        println!("{}", targets::to_json(&results));
+    } else {
+        let stdout = std::io::stdout();
+        targets::write_table(&results, &mut stdout.lock())?;
+    }
+    if results.iter().any(|r| !r.ok()) {
+        exit(ExitCode::TargetsFailed);
+    }
+    Ok(())
+}
+
+/// --bench: the only specifier is the echo server to measure
+fn bench(cmd: &Opt, mut opts: Options) -> Result<()> {
+    use websocat::bench;
+    if cmd.s2.is_some() {
+        Err("--bench takes only one specifier, the server to benchmark")?
+    }
+    if cmd.bench_size < bench::HEADER_LEN {
+        Err(format!("--bench-size must be at least {}", bench::HEADER_LEN))?
+    }
+    if cmd.bench_concurrency == 0 || cmd.bench_messages == 0 {
+        Err("--bench-concurrency and --bench-messages must be positive")?
+    }
+    if cmd.bench_rate.map_or(false, |r| !(r > 0.0)) {
+        Err("--bench-rate must be positive")?
+    }
+    let target = spec(cmd.s1())?;
+    // Payloads are not UTF-8
+    opts.websocket_text_mode = false;
+    let params = bench::BenchParams {
+        messages: cmd.bench_messages,
+        size: cmd.bench_size,
+        concurrency: cmd.bench_concurrency,
+        rate: cmd.bench_rate,
+    };
+    OPTIONS_ACCEPTED.with(|x| x.set(true));
+    let mut core = Core::new()?;
+    let prog = bench::run_bench(&core.handle(), target, opts, params);
+    let conns = core.run(prog).map_err(|()| "error running")?;
+    if cmd.report_json {
+        println!("{}", bench::to_json(&conns));
+    } else {
+        let stdout = std::io::stdout();
+        bench::write_table(&conns, &mut stdout.lock())?;
+    }
+    if !bench::BenchReport::aggregate(&conns).ok() {
+        exit(ExitCode::TargetsFailed);
+    }
+    Ok(())
+}
+
+/// Option a problem from `option_problems` is about, if it names one
+fn problem_subject(p: &str) -> Option<&str> {
+    p.split(|c: char| c.is_whitespace() || c == '`' || c == ',' || c == ':')
+        .find(|w| w.starts_with("--") && w.len() > 2)
+}
+
+/// --check: report every problem found without touching the network
+fn check(cmd: &Opt, opts: Options, problems: Vec<String>) -> Result<()> {
+    use websocat::lints::{check_option_files, check_specifier, diagnostics, stderr_is_tty, Diagnostic, Severity};
+    let mut diags: Vec<Diagnostic> = problems
+        .into_iter()
+        .map(|p| {
+            let subject = problem_subject(&p).map(|x| x.to_string());
+            let d = Diagnostic::error("E0001", p);
+            match subject {
+                Some(x) => d.about(x),
+                None => d,
+            }
+        })
+        .collect();
+    diags.extend(check_option_files(&opts));
+    let mut specs = vec![];
+    for s in &[cmd.s1(), cmd.s2()] {
+        match spec(s) {
+            Ok(x) => specs.push(x),
+            Err(e) => diags.push(Diagnostic::error("E0002", format!("`{}`: {}", s, e)).about(*s)),
+        }
+        diags.extend(check_specifier(s));
+    }
+    if specs.len() == 2 {
+        let s2 = specs.pop().unwrap();
+        let s1 = specs.pop().unwrap();
+        let mut websocat = WebsocatConfiguration { opts, s1, s2 };
+        if cmd.linemode {
+            websocat = match websocat.auto_install_linemode() {
+                Ok(x) => x,
+                Err((c, x)) => {
+                    diags.push(c.diagnostic());
+                    x
+                }
+            };
+        }
+        diags.extend(diagnostics(&websocat));
+    }
+    diags.retain(|d| !cmd.allow.iter().any(|a| a == d.code));
+
+    let color = stderr_is_tty();
+    for d in &diags {
+        eprintln!("{}", d.render(color));
+    }
+    let errors = diags.iter().filter(|d| d.severity == Severity::Error).count();
+    let warnings = diags.len() - errors;
+    if errors > 0 {
+        Err(ExitCode::Usage.error(format!("check failed: {} error(s), {} warning(s)", errors, warnings)))?
+    }
+    println!("{} {}: OK, {} warning(s)", cmd.s1(), cmd.s2(), warnings);
+    Ok(())
+}
+
+/// Invalid option values and combinations, all of them
+fn option_problems(opts: &Options) -> Vec<String> {
+    let mut r = vec![];
+    if websocat::util::parse_direction(&opts.idle_timeout_direction).is_none() {
+        r.push("--idle-timeout-direction must be `in`, `out` or `both`".to_string())
+    }
+    #[cfg(feature = "tokio-process")]
+    {
+        if websocat::process_peer::parse_signal(&opts.exec_kill_signal).is_none() {
+            r.push("--exec-kill-signal must be a signal name like TERM or a positive number".to_string())
+        }
+    }
+    if let Some(ref x) = opts.pty_size {
+        if websocat::util::parse_winsize(x).is_none() {
+            r.push("--pty-size must look like 80x24".to_string())
+        }
+    }
+    for &(name, p) in &[
+        ("--mirror-drop-rate", opts.mirror_drop_rate),
+        ("--mirror-corrupt-rate", opts.mirror_corrupt_rate),
+        ("--mirror-dup-rate", opts.mirror_dup_rate),
+    ] {
+        if !(p >= 0.0 && p <= 1.0) {
+            r.push(format!("{} must be between 0 and 1", name))
+        }
+    }
+    if websocat::util::parse_direction(&opts.filter_direction).is_none() {
+        r.push("--filter-direction must be `in`, `out` or `both`".to_string())
+    }
+    #[cfg(feature = "regex")]
+    {
+        for re in opts.filter_in_regex.iter().chain(opts.filter_out_regex.iter()) {
+            if let Err(e) = websocat::regex_filter::compile(re) {
+                r.push(format!("Invalid regex `{}`: {}", re, e))
+            }
+        }
+        if websocat::regex_filter::is_keep_mode(&opts.filter_mode).is_none() {
+            r.push("--filter-mode must be `drop` or `keep`".to_string())
+        }
+        for rw in &opts.rewrite {
+            if let Err(e) = websocat::regex_filter::parse_rewrite(rw) {
+                r.push(format!("Invalid --rewrite `{}`: {}", rw, e))
+            }
+        }
+    }
+    #[cfg(not(feature = "regex"))]
+    {
+        if opts.filter_in_regex.is_some() || opts.filter_out_regex.is_some() {
+            r.push("Regex filtering requires websocat to be built with `regex` feature".to_string())
+        }
+        if !opts.rewrite.is_empty() {
+            r.push("--rewrite requires websocat to be built with `regex` feature".to_string())
+        }
+    }
+    #[cfg(not(all(unix, feature = "unix_stdio", feature = "readline")))]
+    {
+        if opts.readline {
+            r.push("--readline requires a Unix websocat build with `readline` and `unix_stdio` features".to_string())
+        }
+    }
+    if websocat::util::parse_direction(&opts.batch_direction).is_none() {
+        r.push("--batch-direction must be `in`, `out` or `both`".to_string())
+    }
+    if opts.batch_window_ms.is_none()
+        && (opts.batch_max_bytes.is_some() || opts.batch_max_count.is_some())
+    {
+        r.push("--batch-max-bytes and --batch-max-count require --batch-window-ms".to_string())
+    }
+    if websocat::util::parse_direction(&opts.dedup_direction).is_none() {
+        r.push("--dedup-direction must be `in`, `out` or `both`".to_string())
+    }
+    if opts.dedup_window == Some(0) {
+        r.push("--dedup-window must be positive".to_string())
+    }
+    if websocat::util::parse_direction(&opts.rewrite_direction).is_none() {
+        r.push("--rewrite-direction must be `in`, `out` or `both`".to_string())
+    }
+    if opts.buffer_size.map_or(false, |x| x < 256) {
+        r.push("--buffer-size must be at least 256".to_string())
+    }
+    if let Some(e) = websocat::line_peer::boundary_problem(opts) {
+        r.push(e)
+    }
+    if opts.high_watermark == Some(0) {
+        r.push("--high-watermark must be positive".to_string())
+    }
+    if opts.pipe_instances.map_or(false, |x| x == 0 || x > 255) {
+        r.push("--pipe-instances must be from 1 to 255".to_string())
+    }
+    if !cfg!(windows) && (opts.pipe_instances.is_some() || opts.pipe_sddl.is_some() || opts.pipe_message_mode) {
+        r.push("--pipe-instances, --pipe-sddl and --pipe-message-mode are only supported on Windows".to_string())
+    }
+    if opts.raw_relay && opts.one_message {
+        r.push("--raw-relay can't be used with --one-message".to_string())
+    }
+    if opts.workers == Some(0) {
+        r.push("--workers must be positive".to_string())
+    }
+    if opts.workers.is_some() && (opts.total_max_bytes.is_some() || opts.total_max_messages.is_some()) {
+        r.push("--total-max-bytes and --total-max-messages can't be used with --workers".to_string())
+    }
+    if opts.workers.is_some() && opts.oneshot {
+        r.push("--oneshot can't be used with --workers, each thread would serve once".to_string())
+    }
+    if websocat::clog_peer::parse_clog_direction(&opts.clog_direction).is_none() {
+        r.push("--clog-direction must be `read`, `write` or `both`".to_string())
+    }
+    if websocat::chunk_peer::uses_chunk_header(&opts.chunk_header).is_none() {
+        r.push("--chunk-header must be `none` or `seq/total`".to_string())
+    }
+    if opts.file_append && opts.file_truncate {
+        r.push("--file-append and --file-truncate can't be used together".to_string())
+    }
+    if websocat::lb_peer::LbPolicy::from_str(&opts.lb_policy).is_none() {
+        r.push("--lb-policy must be `roundrobin`, `random` or `first-available`".to_string())
+    }
+    if websocat::events::is_json_format(&opts.log_format).is_none() {
+        r.push("--log-format must be `text` or `json`".to_string())
+    }
+    if opts.hook_max_concurrent == 0 {
+        r.push("--hook-max-concurrent must be positive".to_string())
+    }
+    if opts.max_sessions == Some(0) {
+        r.push("--max-sessions must be positive".to_string())
+    }
+    if opts.max_sessions_backpressure && opts.max_sessions.is_none() {
+        r.push("--max-sessions-backpressure requires --max-sessions".to_string())
+    }
+    if let Some(fd) = opts.notify_fd {
+        if cfg!(not(unix)) {
+            r.push("--notify-fd is not supported on this platform".to_string())
+        } else if fd < 3 {
+            r.push("--notify-fd must not be stdin, stdout or stderr".to_string())
+        }
+    }
+    if opts.flush_interval_ms == Some(0) {
+        r.push("--flush-interval-ms must be positive".to_string())
+    }
+    if [opts.flush_after_message, opts.flush_interval_ms.is_some(), opts.no_flush].iter().filter(|&&x| x).count() > 1 {
+        r.push("Only one of --flush-after-message, --flush-interval-ms and --no-flush can be used".to_string())
+    }
+    if opts.no_close_on_stdin_eof && opts.close_stdin_only {
+        r.push("--no-close-on-stdin-eof and --close-stdin-only can't be used together".to_string())
+    }
+    if (opts.no_close_on_stdin_eof || opts.close_stdin_only) && (opts.unidirectional || opts.exit_on_eof) {
+        r.push("--no-close-on-stdin-eof and --close-stdin-only need the incoming direction, so can't be used with -u or -E".to_string())
+    }
+    if let Some(ref x) = opts.timestamps {
+        if websocat::line_peer::TimestampFormat::from_str(x).is_none() {
+            r.push("--timestamps must be `rfc3339`, `epoch-ms` or `relative`".to_string())
+        }
+    }
+    if let Err(e) = websocat::util::unescape(&opts.timestamp_separator) {
+        r.push(format!("Invalid --timestamp-separator: {}", e))
+    }
+    r
+}
+
+/// `--completions` script: clap's one for options, plus specifier prefixes
+fn completions(shell: &str) -> Result<String> {
+    let sh = match shell {
+        "bash" => structopt::clap::Shell::Bash,
+        "zsh" => structopt::clap::Shell::Zsh,
+        "fish" => structopt::clap::Shell::Fish,
+        _ => Err("--completions supports `bash`, `zsh` and `fish`")?,
+    };
+    let mut clap_script = vec![];
+    Opt::clap().gen_completions_to("websocat", sh, &mut clap_script);
+    let clap_script = String::from_utf8(clap_script)?;
+
+    let value_options = option_names()?
+        .into_iter()
+        .filter(|x| option_takes_value(x))
+        .collect::<Vec<_>>();
+    Ok(websocat::completions::completion_script(shell, &clap_script, &value_options)?)
+}
+
+/// Names of all options, short and long
+fn option_names() -> Result<Vec<String>> {
+    // Bash completion script has the list handy
+    let mut bash = vec![];
+    Opt::clap().gen_completions_to("websocat", structopt::clap::Shell::Bash, &mut bash);
+    let bash = String::from_utf8(bash)?;
+    Ok(bash
+        .split("opts=\"")
+        .nth(1)
+        .and_then(|x| x.split('"').next())
+        .unwrap_or("")
+        .split_whitespace()
+        .filter(|x| x.starts_with('-'))
+        .map(|x| x.to_string())
+        .collect())
+}
+
+/// `--list-features [--json]`
+fn list_features(json: bool) -> Result<()> {
+    let options = option_names()?
+        .into_iter()
+        .map(|x| {
+            let typ = if !option_takes_value(&x) {
+                "flag"
+            } else if option_is_repeatable(&[x.clone(), "x".to_string()]) {
+                "values"
+            } else {
+                "value"
+            };
+            (x, typ)
+        })
+        .collect::<Vec<_>>();
+    if json {
+        println!("{}", websocat::capabilities::to_json(&options));
+    } else {
+        let stdout = std::io::stdout();
+        websocat::capabilities::write_text(&options, &mut stdout.lock())?;
+    }
+    Ok(())
+}
+
+/// Ask clap whether an option takes a value
+fn option_takes_value(name: &str) -> bool {
+    match Opt::clap().get_matches_from_safe(vec!["websocat", name]) {
+        Err(ref e) if e.kind == structopt::clap::ErrorKind::EmptyValue => true,
+        _ => false,
+    }
+}
+
+/// Ask clap whether an option may be given several times, like --header
+fn option_is_repeatable(tokens: &[String]) -> bool {
+    let mut v = vec!["websocat".to_string()];
+    v.extend(tokens.iter().cloned());
+    v.extend(tokens.iter().cloned());
+    match Opt::clap().get_matches_from_safe(v) {
+        Err(ref e) if e.kind == structopt::clap::ErrorKind::UnexpectedMultipleUsage => false,
+        _ => true,
+    }
+}
+
+/// Handle `@<file>` and `--config <file>`: merge arguments from the file
+/// with the command line ones, which take precedence
+fn args_with_config_file(argv: Vec<String>) -> Result<Vec<String>> {
+    let mut it = argv.into_iter();
+    let argv0 = it.next().unwrap_or_else(|| "websocat".to_string());
+    let mut cli = vec![];
+    let mut path = None;
+    while let Some(t) = it.next() {
+        if t == "--" {
+            cli.push(t);
+            cli.extend(it.by_ref());
+            break;
+        }
+        let p = if t.starts_with('@') && t.len() > 1 {
+            t[1..].to_string()
+        } else if t.starts_with("--config=") {
+            t["--config=".len()..].to_string()
+        } else if t == "--config" {
+            match it.next() {
+                Some(x) => x,
+                None => Err("--config requires a file name")?,
+            }
+        } else {
+            cli.push(t);
+            continue;
+        };
+        if path.is_some() {
+            Err("Only one configuration file may be given")?
+        }
+        path = Some(p);
+    }
+    let path = match path {
+        Some(x) => x,
+        None => {
+            cli.insert(0, argv0);
+            return Ok(cli);
+        }
+    };
+    let mut text = String::new();
+    std::fs::File::open(&path)
+        .and_then(|mut f| std::io::Read::read_to_string(&mut f, &mut text))
+        .map_err(|e| format!("Can't read configuration file {}: {}", path, e))?;
+    let from_file = websocat::util::parse_config_args(&text, |n| std::env::var(n).ok())
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut args = vec![argv0];
+    args.extend(websocat::util::merge_config_args(
+        from_file,
+        cli,
+        &path,
+        option_takes_value,
+        option_is_repeatable,
+    )?);
+    Ok(args)
+}
+
+/// Passes records to env_logger and also sends warnings and errors to the system log, if set up
+struct Logger {
+    inner: env_logger::Logger,
+    remote: RemoteLogSlot,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, m: &log::Metadata) -> bool {
+        self.inner.enabled(m) || m.level() <= log::Level::Warn
+    }
+    fn log(&self, r: &log::Record) {
+        self.inner.log(r);
+        if r.level() <= log::Level::Warn {
+            if let Some(ref x) = *self.remote.lock().unwrap() {
+                x.log(r.level(), &format!("{}", r.args()), &[]);
+            }
+        }
+    }
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+fn init_logger() -> RemoteLogSlot {
+    let inner = env_logger::Builder::from_env(env_logger::Env::default()).build();
+    let remote: RemoteLogSlot = Arc::new(Mutex::new(None));
+    log::set_max_level(inner.filter().max(log::LevelFilter::Warn));
+    let logger = Logger {
+        inner,
+        remote: remote.clone(),
+    };
+    log::set_boxed_logger(Box::new(logger)).expect("Assertion failed 8143");
+    remote
+}
+
+/// Set up `--log-syslog` or `--log-journald`, falling back to stderr with a warning
+fn init_remote_log(cmd: &Opt, slot: &RemoteLogSlot) -> Result<()> {
+    let syslog = cmd.log_syslog || cmd.log_syslog_addr.is_some();
+    if syslog && cmd.log_journald {
+        Err("--log-syslog and --log-journald can't be used together")?
+    }
+    let r = if cmd.log_journald {
+        RemoteLog::journald()
+    } else if syslog {
+        RemoteLog::syslog(cmd.log_syslog_addr.as_ref().map(|x| &x[..]))
+    } else {
+        return Ok(());
+    };
+    match r {
+        Ok(r) => {
+            let r = Arc::new(r);
+            *slot.lock().unwrap() = Some(r.clone());
+            websocat::events::set_remote(r.clone());
+            websocat::shutdown::at_exit(move || {
+                if r.dropped() > 0 {
+                    eprintln!("websocat: {} log messages could not be sent to the system log", r.dropped());
+                }
+            });
+        }
+        Err(e) => if !cmd.quiet {
+            eprintln!("websocat: warning: system log is not available ({}), logging to stderr only", e)
+        },
+    }
+    Ok(())
+}
+
+fn parse_args(args: Vec<String>) -> Result<Opt> {
+    match Opt::clap().get_matches_from_safe(args) {
+        Ok(m) => {
+            let mut cmd = Opt::from_clap(&m);
+            // Bare `--timestamps`
+            if m.is_present("timestamps") && cmd.timestamps.is_none() {
+                cmd.timestamps = Some("rfc3339".to_string());
+            }
+            Ok(cmd)
+        }
+        // --help or --version
+        Err(ref e) if !e.use_stderr() => e.exit(),
+        Err(e) => Err(ExitCode::Usage.error(e))?,
+    }
+}
+
+fn options(cmd: &Opt) -> Result<Options> {
+    macro_rules! opts {
+        ($($o:ident)*) => {
+            Options {
+                $($o : cmd.$o.clone(),)*
+                listen_spec: Some(cmd.s1().to_string()),
+                right_spec: cmd.s2.clone(),
+                callbacks: Default::default(),
+                shutdown_scope: None,
+                middleware: Default::default(),
+                batch_separator: websocat::util::unescape(&cmd.batch_separator)?,
+            }
+        };
+    }
+    Ok(opts!(
+        websocket_text_mode
+        websocket_protocol
+        udp_oneshot_mode
+        unidirectional
+        unidirectional_reverse
+        exit_on_eof
+        oneshot
+        unlink_unix_socket
+        exec_args
+        ws_c_uri
+        linemode_retain_newlines
+        origin
+        custom_headers
+        websocket_version
+        websocket_dont_close
+        one_message
+        close_timeout
+        lenprefix_bytes
+        lenprefix_little_endian
+        lenprefix_includes_header
+        lenprefix_max
+        log_file
+        log_raw
+        log_base64
+        log_max_size
+        jsonl_input
+        binary_as_base64
+        throttle_bytes_per_sec
+        throttle_messages_per_sec
+        throttle_burst
+        throttle_direction
+        delay_ms
+        delay_jitter_ms
+        delay_max_queued
+        delay_direction
+        record_file
+        replay_no_timing
+        replay_speed
+        replay_strict
+        idle_timeout
+        idle_timeout_direction
+        separator
+        separator_n
+        separator_conflict
+        line_escape
+        random_seed
+        gen_message_size
+        count_output
+        seqnum_strict
+        crc_algo
+        crc_strict
+        broadcast_retain
+        broadcast_retain_count
+        broadcast_retain_max_bytes
+        retain_across_reconnect
+        reuse_buffer
+        reuse_buffer_bytes
+        reuse_buffer_overflow
+        lb_policy
+        lb_quarantine
+        failover_cooldown
+        listen_best_effort
+        exec_arg
+        exec_env
+        exec_clearenv
+        exec_chdir
+        exec_umask
+        exec_pty
+        pty_size
+        pty_resize_prefix
+        exec_kill_signal
+        exec_kill_timeout
+        exec_no_kill
+        exit_status_from_exec
+        env_headers
+        file_append
+        file_create_new
+        file_truncate
+        file_sync
+        file_start_offset
+        file_max_bytes
+        file_follow
+        mirror_delay_ms
+        mirror_drop_rate
+        mirror_corrupt_rate
+        mirror_dup_rate
+        mirror_close_after
+        clog_direction
+        clog_after_bytes
+        clog_duration
+        assert_allow_extra
+        assert_timeout
+        filter_persistent
+        filter_concurrency
+        filter_direction
+        filter_in_regex
+        filter_out_regex
+        filter_mode
+        rewrite
+        rewrite_direction
+        rewrite_binary
+        rewrite_max_size
+        chunk_header
+        batch_window_ms
+        batch_max_bytes
+        batch_max_count
+        batch_direction
+        dedup_consecutive
+        dedup_window
+        dedup_direction
+        dedup_max_silence
+        max_messages_in
+        max_messages_out
+        max_bytes_in
+        max_bytes_out
+        metrics_addr
+        lazy_connect
+        lazy_buffer_bytes
+        total_max_bytes
+        total_max_messages
+        total_max_duration
+        drain_timeout
+        stats
+        stats_json
+        pid_file
+        pkcs12_der
+        pkcs12_passwd
+        shutdown_close_code
+        max_sessions
+        max_sessions_backpressure
+        log_format
+        events_file
+        on_connect
+        on_disconnect
+        hook_max_concurrent
+        readline
+        no_close_on_stdin_eof
+        close_stdin_only
+        timestamps
+        timestamp_separator
+        notify_fd
+        flush_after_message
+        flush_interval_ms
+        no_flush
+        include_headers
+        include_headers_every_connect
+        response_header_file
+        no_splice
+        buffer_size
+        high_watermark
+        cork_window_us
+        workers
+        preconnect
+        preconnect_max_age
+        raw_relay
+        pipe_instances
+        pipe_sddl
+        pipe_message_mode
+        allow_partial_datagrams
+        bind_retry
+        bind_retry_delay
+        flush_timeout
+        no_url_normalization
+    ))
+}
+
+/// The same configuration as on the main thread, for each of `--workers`.
+/// Warnings were already shown there.
+fn worker_configuration(args: Vec<String>) -> Result<WebsocatConfiguration> {
+    let cmd = parse_args(args)?;
+    let opts = options(&cmd)?;
+    let websocat = WebsocatBuilder::new()
+        .left(cmd.s1())
+        .right(cmd.s2())
+        .options(opts)
+        .linemode(cmd.linemode)
+        .on_warning(|_| ())
+        .build()?;
+    Ok(websocat)
+}
+
+fn run(remote_log: RemoteLogSlot) -> Result<()> {
+    websocat::epipe::ignore_sigpipe();
     if std::env::args().nth(1).unwrap_or_default() == "--long-help" {
         longhelp();
         return Ok(());
     }
+    if std::env::args().nth(1).unwrap_or_default() == "--list-features" {
+        return list_features(std::env::args().any(|x| x == "--json"));
+    }
+    if std::env::args().nth(1).unwrap_or_default() == "--completions" {
+        let shell = match std::env::args().nth(2) {
+            Some(x) => x,
+            None => Err("--completions requires a shell name: bash, zsh or fish")?,
+        };
+        print!("{}", completions(&shell)?);
+        return Ok(());
+    }
 
-    let cmd = Opt::from_args();
+    let args = args_with_config_file(std::env::args().collect())?;
+    OLD_EXIT_CODES.with(|x| x.set(args.iter().any(|a| a == "--old-exit-codes")));
+    ERRORS_JSON.with(|x| x.set(args.iter().any(|a| a == "--errors-json")));
+    let cmd = parse_args(args.clone())?;
+    OLD_EXIT_CODES.with(|x| x.set(cmd.old_exit_codes));
+    ERRORS_JSON.with(|x| x.set(cmd.errors_json));
+    if cmd.quiet {
+        log::set_max_level(log::LevelFilter::Error);
+    }
+    init_remote_log(&cmd, &remote_log)?;
 
     if cmd.longhelp {
         longhelp();
         return Ok(());
     }
+    if let Some(ref f) = cmd.dump_capture {
+        let stdout = std::io::stdout();
+        websocat::record_peer::dump_capture(f, &mut stdout.lock())?;
+        return Ok(());
+    }
 
     if false
     //    || cmd.oneshot
@@ -241,52 +2033,44 @@ fn run() -> Result<()> {
         Err("This mode is not implemented")?
     }
 
-    let opts = {
-        macro_rules! opts {
-            ($($o:ident)*) => {
-                Options {
-                    $($o : cmd.$o,)*
-                }
-            };
-        }
-        opts!(
-            websocket_text_mode
-            websocket_protocol
-            udp_oneshot_mode
-            unidirectional
-            unidirectional_reverse
-            exit_on_eof
-            oneshot
-            unlink_unix_socket
-            exec_args
-            ws_c_uri
-            linemode_retain_newlines
-            origin
-            custom_headers
-            websocket_version
-            websocket_dont_close
-            one_message
-        )
-    };
-
-    let s1 = spec(&cmd.s1)?;
-    let s2 = spec(&cmd.s2)?;
+    let opts = options(&cmd)?;
 
-    let mut websocat = WebsocatConfiguration { opts, s1, s2 };
-
-    if cmd.linemode {
-        use websocat::lints::AutoInstallLinemodeConcern::*;
-        websocat = match websocat.auto_install_linemode() {
-            Ok(x) => x,
-            Err((NoWebsocket,_)) => Err("No websocket usage is specified. Use line2msg: and msg2line: specifiers manually if needed.")?,
-            Err((MultipleWebsocket,_)) => Err("Multiple websocket usages are specified. Use line2msg: and msg2line: specifiers manually if needed.")?,
-            Err((AlreadyLine,_)) => Err("Can't auto-insert msg2line:/line2msg: if you have already manually specified some of them")?,
+    let mut problems = option_problems(&opts);
+    for a in &cmd.allow {
+        if !websocat::lints::CODES.contains(&&a[..]) {
+            problems.push(format!("--allow: unknown diagnostic code `{}`", a))
         }
     }
+    if cmd.check {
+        return check(&cmd, opts, problems);
+    }
+    if let Some(e) = problems.into_iter().next() {
+        Err(e)?
+    }
+    if let Some(ref list) = cmd.each_line_of {
+        return each_target(&cmd, list, opts);
+    }
+    if cmd.bench {
+        return bench(&cmd, opts);
+    }
 
-    while let Some(concern) = websocat.get_concern() {
-        use websocat::lints::ConfigurationConcern::*;
-        if concern == StdinToStdout {
+    let quiet = cmd.quiet;
+    let allow = cmd.allow.clone();
+    let color = websocat::lints::stderr_is_tty();
+    let built = WebsocatBuilder::new()
+        .left(cmd.s1())
+        .right(cmd.s2())
+        .options(opts)
+        .linemode(cmd.linemode)
+        .on_warning(move |x| {
+            if !quiet && !allow.iter().any(|a| a == x.code) {
+                eprintln!("{}", x.render(color));
+            }
+        })
+        .build();
+    let websocat = match built {
+        Ok(x) => x,
+        Err(BuildError::StdinToStdout) => {
             if cmd.dumpspec {
                 println!("cat mode");
                 return Ok(());
@@ -296,35 +2080,16 @@ fn run() -> Result<()> {
             ::std::io::copy(&mut ::std::io::stdin(), &mut ::std::io::stdout())?;
             return Ok(());
         }
-
-        if concern == DegenerateMode {
+        Err(BuildError::NothingToDo) => {
             if cmd.dumpspec {
                 println!("noop");
             }
             return Ok(());
         }
-
-        if concern == StdioConflict {
-            Err("Too many usages of stdin/stdout")?;
-        }
-
-        if concern == NeedsStdioReuser {
-            eprintln!("Warning: replies on stdio get directed at random connected client");
-            websocat = websocat.auto_install_reuser();
-            continue;
-        }
-
-        if concern == NeedsStdioReuser2 {
-            websocat = websocat.auto_install_reuser();
-            continue;
-        }
-
-        if concern == MultipleReusers {
-            eprintln!("Specifier dump: {:?} {:?}", websocat.s1, websocat.s2);
-            Err("Multiple reusers is not allowed")?;
-        }
-        break;
-    }
+        Err(BuildError::Configuration(x)) => Err(ExitCode::Usage.error(x))?,
+        Err(BuildError::Specifier(e)) => Err(e)?,
+        Err(e) => Err(e)?,
+    };
 
     if cmd.dumpspec {
         println!("{:?}", websocat.s1);
@@ -333,24 +2098,225 @@ fn run() -> Result<()> {
         return Ok(());
     }
 
+    OPTIONS_ACCEPTED.with(|x| x.set(true));
     let mut core = Core::new()?;
 
+    if let Some(ref addr) = websocat.opts.metrics_addr {
+        let srv = websocat::metrics::serve_metrics(addr, &core.handle())?;
+        core.handle().spawn(srv);
+    }
+
+    if let Some(secs) = websocat.opts.total_max_duration {
+        let t = Timeout::new(Duration::from_secs(secs), &core.handle())?;
+        core.handle().spawn(t.map_err(|_| ()).map(|()| {
+            let reason = format!("--total-max-duration {}", websocat::budget::BUDGET_REASON);
+            websocat::shutdown::initiate(&reason);
+        }));
+    }
+    let drain_timeout = Duration::from_secs(websocat.opts.drain_timeout);
+
+    if websocat.opts.stats || websocat.opts.stats_json.is_some() {
+        websocat::metrics::enable_report(websocat.opts.stats, websocat.opts.stats_json.clone());
+        websocat::shutdown::at_exit(websocat::metrics::report);
+    }
+    #[cfg(feature = "signal_handler")]
+    {
+        use futures::Stream;
+        // First SIGINT or SIGTERM closes everything gracefully, second one exits immediately
+        let close_code = websocat.opts.shutdown_close_code;
+        let signals = tokio_signal::ctrl_c(&core.handle())
+            .flatten_stream()
+            .map(|()| "SIGINT");
+        #[cfg(unix)]
+        let signals = signals.select(
+            tokio_signal::unix::Signal::new(tokio_signal::unix::SIGTERM, &core.handle())
+                .flatten_stream()
+                .map(|_| "SIGTERM"),
+        );
+        let handler = signals.fold(false, move |again, name| {
+            if again {
+                eprintln!("websocat: second signal, exiting immediately");
+                exit(ExitCode::Signal(2));
+            }
+            websocat::shutdown::initiate_and_close_sessions(&format!("got {}", name), close_code);
+            Ok::<_, std::io::Error>(true)
+        });
+        core.handle().spawn(handler.map(|_| ()).map_err(|_| ()));
+    }
+    #[cfg(all(unix, feature = "signal_handler"))]
+    {
+        use futures::Stream;
+        let sighup = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGHUP, &core.handle())
+            .flatten_stream()
+            .for_each(|_| {
+                websocat::reload::reload();
+                Ok(())
+            });
+        core.handle().spawn(sighup.map_err(|_| ()));
+    }
+    let pid_file = websocat.opts.pid_file.clone();
+    let notify_fd = websocat.opts.notify_fd;
+    let watchdog = websocat::sd_notify::init();
+    let status = if websocat.s1.is_multiconnect() {
+        format!("listening on {}", cmd.s1())
+    } else {
+        "running".to_string()
+    };
+
+    let exit_status_from_exec = websocat.opts.exit_status_from_exec;
+    let worker_count = websocat.opts.workers;
+    // The first failed session, which decides the exit code
+    let failure = std::rc::Rc::new(std::cell::RefCell::new(None));
+    let failure2 = failure.clone();
+    let errors_json = cmd.errors_json;
+    let epipe_status = cmd.exit_on_epipe_status;
     let prog = websocat.serve(
         core.handle(),
-        std::rc::Rc::new(|e| {
-            eprintln!("websocat: {}", e);
+        std::rc::Rc::new(move |e: Box<std::error::Error>| {
+            let mut f = failure2.borrow_mut();
+            if f.is_none() {
+                *f = Some(websocat::exit_code::report(&*e));
+            }
+            // With --errors-json, it is printed on exit
+            if !errors_json {
+                eprintln!("websocat: {}", e);
+            }
         }),
     );
-    core.run(prog).map_err(|()| "error running".to_string())?;
+    // Listeners are bound when `serve` returns
+    let mut workers = match worker_count {
+        Some(n) if n > 1 => Some(websocat::workers::spawn(n - 1, move |_| {
+            worker_configuration(args.clone())
+        })?),
+        _ => None,
+    };
+    let report_ready = move |h: &Handle| -> Result<()> {
+        if let Some(ref x) = pid_file {
+            websocat::pid_file::write_pid_file(x)?;
+        }
+        websocat::sd_notify::ready(&status, watchdog, h);
+        if let Some(fd) = notify_fd {
+            websocat::sd_notify::notify_fd(fd)?;
+        }
+        Ok(())
+    };
+    // Readiness waits for the listeners of this thread and of all workers, some of which
+    // may be retrying their bind. If one gives up or a worker can't start, readiness
+    // is not reported and the program shuts down with an error.
+    let workers_bound: Box<Future<Item = (), Error = ()>> = match workers {
+        Some(ref mut w) => w.all_bound(),
+        None => Box::new(futures::future::ok(())),
+    };
+    let h = core.handle();
+    let not_bound = std::rc::Rc::new(std::cell::Cell::new(false));
+    let not_bound2 = not_bound.clone();
+    core.handle().spawn(websocat::bind_retry::all_bound().join(workers_bound).then(move |r| {
+        match r {
+            Ok(_) => {
+                if let Err(e) = report_ready(&h) {
+                    eprintln!("websocat: {}", e);
+                    websocat::shutdown::initiate("failed to report readiness");
+                }
+            }
+            Err(()) => {
+                not_bound2.set(true);
+                websocat::shutdown::initiate("a listener failed to bind");
+            }
+        }
+        Ok(())
+    }));
+    let prog = prog
+        .select(websocat::shutdown::drained(&core.handle(), drain_timeout))
+        .map(|_| ())
+        .map_err(|_| ());
+    let r = core.run(prog).map_err(|()| "error running".to_string());
+    let shutdown_reason = websocat::shutdown::reason();
+    if shutdown_reason.is_some() {
+        // Listeners are closed, but sessions may still be in progress
+        let _ = core.run(websocat::shutdown::drained(&core.handle(), drain_timeout));
+    }
+    let joined = workers.map_or(Ok(()), |w| w.join());
+    if exit_status_from_exec {
+        if r.is_err() || joined.is_err() || not_bound.get() || failure.borrow().is_some() {
+            exit(ExitCode::SessionFailed);
+        }
+        #[cfg(feature = "tokio-process")]
+        {
+            if let Ok(Some(code)) = core.run(websocat::process_peer::wait_for_children()) {
+                websocat::shutdown::run_exit_hooks();
+                ::std::process::exit(code);
+            }
+        }
+    }
+    r?;
+    joined?;
+    if not_bound.get() {
+        Err("a listener failed to bind")?;
+    }
+    match shutdown_reason.as_ref().map(|x| &x[..]) {
+        Some("got SIGINT") => exit(ExitCode::Signal(2)),
+        Some("got SIGTERM") => exit(ExitCode::Signal(15)),
+        Some(x) if x.ends_with(websocat::budget::BUDGET_REASON) => exit(ExitCode::BudgetReached),
+        Some(x) if x == websocat::epipe::REASON => {
+            if let Some(code) = epipe_status {
+                websocat::shutdown::run_exit_hooks();
+                ::std::process::exit(code);
+            }
+        }
+        _ => (),
+    }
+    let failed = failure.borrow_mut().take();
+    if let Some(report) = failed {
+        if errors_json {
+            eprintln!("{}", report.to_json(OLD_EXIT_CODES.with(|x| x.get())));
+        }
+        exit(report.code);
+    }
+    if websocat::exit_code::abnormal_close_seen() {
+        if errors_json {
+            let report = ErrorReport {
+                code: ExitCode::AbnormalClose,
+                detail: "WebSocket closed abnormally".to_string(),
+                url: None,
+                context: None,
+            };
+            eprintln!("{}", report.to_json(OLD_EXIT_CODES.with(|x| x.get())));
+        }
+        exit(ExitCode::AbnormalClose);
+    }
     Ok(())
 }
 
+thread_local! {
+    /// --old-exit-codes
+    static OLD_EXIT_CODES: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    /// --errors-json
+    static ERRORS_JSON: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    /// Errors before this are about options, unless they say otherwise
+    static OPTIONS_ACCEPTED: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+/// Exit the process, printing --stats and removing --pid-file first
+fn exit(code: ExitCode) -> ! {
+    websocat::shutdown::run_exit_hooks();
+    ::std::process::exit(code.code(OLD_EXIT_CODES.with(|x| x.get())));
+}
+
 fn main() {
-    env_logger::init();
-    let r = run();
+    let remote_log = init_logger();
+    let r = run(remote_log);
 
     if let Err(e) = r {
-        eprintln!("websocat: {}", e);
-        ::std::process::exit(1);
+        let mut report = websocat::exit_code::report(&*e);
+        if report.code == ExitCode::Other && !OPTIONS_ACCEPTED.with(|x| x.get()) {
+            report.code = ExitCode::Usage;
+        }
+        if ERRORS_JSON.with(|x| x.get()) {
+            eprintln!("{}", report.to_json(OLD_EXIT_CODES.with(|x| x.get())));
+        } else {
+            eprintln!("websocat: {}", e);
+        }
+        exit(report.code);
     }
+    websocat::shutdown::run_exit_hooks();
 }
@@ -0,0 +1,422 @@
+//! Process-wide counters, the Prometheus text endpoint (`--metrics-addr`)
+//! and the summary printed on exit (`--stats`, `--stats-json`)
+//!
+//! Counters are per thread. With `--workers`, the other threads send theirs
+//! to the main one, which adds them up for reporting.
+
+extern crate serde_json;
+
+use futures::future::{loop_fn, ok, Loop};
+use futures::{Future, Stream};
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::mpsc::Receiver;
+use std::fmt::Write as FmtWrite;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use self::serde_json::{Map, Value};
+
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read, shutdown, write_all};
+
+use std::io::{Error as IoError, Read};
+use tokio_io::AsyncRead;
+
+use super::Options;
+
+/// Requests with bigger headers are not answered
+const MAX_REQUEST: usize = 8192;
+
+#[derive(Default, Clone)]
+struct Metrics {
+    sessions_started: u64,
+    sessions_ended: u64,
+    /// Indexed by direction: 0 is `in`, 1 is `out`
+    bytes: [u64; 2],
+    messages: [u64; 2],
+    reconnects: u64,
+    handshake_failures: u64,
+    close_codes: BTreeMap<u16, u64>,
+    /// Last received WebSocket Close frame
+    last_close: Option<(u16, String)>,
+    /// Counts reported by filters and other overlays, for the summary
+    extra: BTreeMap<&'static str, u64>,
+    /// Like `extra`, but maximums instead of sums
+    peaks: BTreeMap<&'static str, u64>,
+    end_reason: Option<String>,
+    first_session: Option<Instant>,
+    /// Bytes in the current second since the first session, for peak throughput
+    bucket: (u64, u64),
+    peak: u64,
+}
+
+/// Where to print the summary on exit
+struct ReportConfig {
+    human: bool,
+    json: Option<String>,
+}
+
+impl Metrics {
+    /// Add counters of another thread
+    fn merge(&mut self, o: &Metrics) {
+        self.sessions_started += o.sessions_started;
+        self.sessions_ended += o.sessions_ended;
+        for i in 0..2 {
+            self.bytes[i] += o.bytes[i];
+            self.messages[i] += o.messages[i];
+        }
+        self.reconnects += o.reconnects;
+        self.handshake_failures += o.handshake_failures;
+        for (k, v) in &o.close_codes {
+            *self.close_codes.entry(*k).or_insert(0) += *v;
+        }
+        if self.last_close.is_none() {
+            self.last_close = o.last_close.clone();
+        }
+        for (k, v) in &o.extra {
+            *self.extra.entry(*k).or_insert(0) += *v;
+        }
+        for (k, v) in &o.peaks {
+            let x = self.peaks.entry(*k).or_insert(0);
+            *x = (*x).max(*v);
+        }
+        if self.end_reason.is_none() {
+            self.end_reason = o.end_reason.clone();
+        }
+        self.first_session = match (self.first_session, o.first_session) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        // Threads peak at about the same time under load
+        self.peak += o.peak;
+    }
+}
+
+/// Counters of one thread, as sent by `--workers` threads
+#[derive(Clone)]
+pub struct Snapshot(Metrics);
+
+thread_local! {
+    static METRICS: RefCell<Metrics> = RefCell::new(Default::default());
+    static REPORT: RefCell<Option<ReportConfig>> = RefCell::new(None);
+    /// Where worker threads send their counters, and the latest ones of each
+    static WORKERS: RefCell<Option<(Receiver<(usize, Snapshot)>, BTreeMap<usize, Metrics>)>> = RefCell::new(None);
+}
+
+/// Counters of this thread so far
+pub fn snapshot() -> Snapshot {
+    METRICS.with(|m| Snapshot(m.borrow().clone()))
+}
+
+/// Include counters sent here by other threads, each with its number, in the reports of this thread
+pub fn collect_from(rx: Receiver<(usize, Snapshot)>) {
+    WORKERS.with(|w| *w.borrow_mut() = Some((rx, BTreeMap::new())))
+}
+
+/// Counters of this thread and of the ones that send theirs here
+fn combined<R, F: FnOnce(&Metrics) -> R>(f: F) -> R {
+    let mut all = METRICS.with(|m| m.borrow().clone());
+    WORKERS.with(|w| {
+        if let Some((ref rx, ref mut latest)) = *w.borrow_mut() {
+            while let Ok((i, Snapshot(m))) = rx.try_recv() {
+                latest.insert(i, m);
+            }
+            for m in latest.values() {
+                all.merge(m);
+            }
+        }
+    });
+    f(&all)
+}
+
+/// Whether sessions need to count their traffic
+pub fn needed(opts: &Options) -> bool {
+    opts.metrics_addr.is_some()
+        || opts.stats
+        || opts.stats_json.is_some()
+        || super::budget::enabled(opts)
+}
+
+fn with<F: FnOnce(&mut Metrics)>(f: F) {
+    METRICS.with(|m| f(&mut m.borrow_mut()))
+}
+
+pub fn session_started() {
+    with(|m| {
+        m.sessions_started += 1;
+        if m.first_session.is_none() {
+            m.first_session = Some(Instant::now());
+        }
+    })
+}
+pub fn session_ended() {
+    with(|m| m.sessions_ended += 1)
+}
+pub fn reconnect() {
+    with(|m| m.reconnects += 1)
+}
+pub fn handshake_failed() {
+    with(|m| m.handshake_failures += 1)
+}
+/// WebSocket Close frame received
+pub fn close_received(code: u16, reason: &str) {
+    with(|m| {
+        *m.close_codes.entry(code).or_insert(0) += 1;
+        m.last_close = Some((code, reason.to_string()));
+    })
+}
+/// Add to a named counter shown in the summary, like `regex_filter_dropped`
+pub fn add(name: &'static str, n: u64) {
+    with(|m| *m.extra.entry(name).or_insert(0) += n)
+}
+/// Raise a named value shown in the summary to `n` if it is lower, like `queued_bytes_peak`
+pub fn high_water(name: &'static str, n: u64) {
+    with(|m| {
+        let x = m.peaks.entry(name).or_insert(0);
+        *x = (*x).max(n);
+    })
+}
+/// Remember why a session ended other than by EOF or error, like a limit
+pub fn set_end_reason(reason: &str) {
+    with(|m| m.end_reason = Some(reason.to_string()))
+}
+
+/// Bytes and messages relayed in both directions by all sessions so far
+pub fn totals() -> (u64, u64) {
+    METRICS.with(|m| {
+        let m = m.borrow();
+        (m.bytes[0] + m.bytes[1], m.messages[0] + m.messages[1])
+    })
+}
+
+pub fn live_sessions() -> u64 {
+    METRICS.with(|m| {
+        let m = m.borrow();
+        m.sessions_started - m.sessions_ended
+    })
+}
+
+/// Reader that counts messages and bytes passing through a session
+pub struct CountRead {
+    pub inner: Box<AsyncRead>,
+    /// `true` for data coming from the right specifier
+    pub incoming: bool,
+}
+
+impl Read for CountRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            let i = if self.incoming { 0 } else { 1 };
+            with(|m| {
+                m.bytes[i] += n as u64;
+                m.messages[i] += 1;
+                let sec = m.first_session.map_or(0, |t| t.elapsed().as_secs());
+                if m.bucket.0 != sec {
+                    m.bucket = (sec, 0);
+                }
+                m.bucket.1 += n as u64;
+                if m.bucket.1 > m.peak {
+                    m.peak = m.bucket.1;
+                }
+            });
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for CountRead {}
+
+/// Render all counters in Prometheus text exposition format
+pub fn render() -> String {
+    let mut s = String::new();
+    combined(|m| {
+        let mut counter = |name: &str, help: &str, typ: &str, values: &[(&str, u64)]| {
+            let _ = writeln!(s, "# HELP websocat_{} {}", name, help);
+            let _ = writeln!(s, "# TYPE websocat_{} {}", name, typ);
+            for &(labels, v) in values {
+                let _ = writeln!(s, "websocat_{}{} {}", name, labels, v);
+            }
+        };
+        counter("sessions_started_total", "Sessions started", "counter", &[("", m.sessions_started)]);
+        counter("sessions_ended_total", "Sessions ended", "counter", &[("", m.sessions_ended)]);
+        counter(
+            "sessions_live",
+            "Sessions in progress",
+            "gauge",
+            &[("", m.sessions_started - m.sessions_ended)],
+        );
+        counter(
+            "bytes_total",
+            "Bytes relayed; in is from the right specifier",
+            "counter",
+            &[("{direction=\"in\"}", m.bytes[0]), ("{direction=\"out\"}", m.bytes[1])],
+        );
+        counter(
+            "messages_total",
+            "Messages (or stream reads) relayed",
+            "counter",
+            &[("{direction=\"in\"}", m.messages[0]), ("{direction=\"out\"}", m.messages[1])],
+        );
+        counter(
+            "sessions_admitted",
+            "Sessions counted against --max-sessions, including ones still connecting",
+            "gauge",
+            &[("", super::session_cap::live())],
+        );
+        counter(
+            "sessions_refused_total",
+            "Connections refused because of --max-sessions",
+            "counter",
+            &[("", m.extra.get("sessions_refused").cloned().unwrap_or(0))],
+        );
+        counter("reconnects_total", "autoreconnect: attempts", "counter", &[("", m.reconnects)]);
+        counter(
+            "handshake_failures_total",
+            "Failed WebSocket handshakes",
+            "counter",
+            &[("", m.handshake_failures)],
+        );
+        let labels: Vec<(String, u64)> = m.close_codes
+            .iter()
+            .map(|(c, v)| (format!("{{code=\"{}\"}}", c), *v))
+            .collect();
+        let values: Vec<(&str, u64)> = labels.iter().map(|&(ref l, v)| (&l[..], v)).collect();
+        counter(
+            "close_codes_total",
+            "Received WebSocket Close frames by status code",
+            "counter",
+            &values,
+        );
+    });
+    s
+}
+
+/// Print the summary on exit, to stderr (`human`) and/or as JSON to a file or `-` (stdout)
+pub fn enable_report(human: bool, json: Option<String>) {
+    REPORT.with(|r| *r.borrow_mut() = Some(ReportConfig { human, json }))
+}
+
+/// Summary of all counters as a JSON object
+pub fn summary() -> Value {
+    combined(|m| {
+        let duration = m.first_session
+            .map(|t| {
+                let d = t.elapsed();
+                d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+            })
+            .unwrap_or(0.0);
+        let total = m.bytes[0] + m.bytes[1];
+        let mut o = Map::new();
+        o.insert("bytes_in".into(), m.bytes[0].into());
+        o.insert("bytes_out".into(), m.bytes[1].into());
+        o.insert("messages_in".into(), m.messages[0].into());
+        o.insert("messages_out".into(), m.messages[1].into());
+        o.insert("duration_secs".into(), duration.into());
+        let avg = if duration > 0.0 { total as f64 / duration } else { 0.0 };
+        o.insert("avg_bytes_per_sec".into(), avg.into());
+        o.insert("peak_bytes_per_sec".into(), m.peak.into());
+        o.insert("sessions".into(), m.sessions_started.into());
+        o.insert("reconnects".into(), m.reconnects.into());
+        o.insert("handshake_failures".into(), m.handshake_failures.into());
+        if let Some((code, ref reason)) = m.last_close {
+            o.insert("close_code".into(), code.into());
+            o.insert("close_reason".into(), reason.clone().into());
+        }
+        if let Some(ref x) = m.end_reason {
+            o.insert("end_reason".into(), x.clone().into());
+        }
+        for (k, v) in m.extra.iter().chain(m.peaks.iter()) {
+            o.insert(k.to_string(), (*v).into());
+        }
+        Value::Object(o)
+    })
+}
+
+/// Print the summary if `enable_report` was called. Only the first call does something.
+pub fn report() {
+    let cfg = match REPORT.with(|r| r.borrow_mut().take()) {
+        Some(x) => x,
+        None => return,
+    };
+    let v = summary();
+    if cfg.human {
+        eprintln!("websocat stats:");
+        if let Value::Object(ref o) = v {
+            for (k, x) in o {
+                eprintln!("  {}: {}", k, x);
+            }
+        }
+    }
+    match cfg.json.as_ref().map(|x| &x[..]) {
+        None => (),
+        Some("-") => println!("{}", v),
+        Some(path) => {
+            let r = ::std::fs::File::create(path)
+                .and_then(|mut f| ::std::io::Write::write_all(&mut f, format!("{}\n", v).as_bytes()));
+            if let Err(e) = r {
+                eprintln!("websocat: failed to write {}: {}", path, e);
+            }
+        }
+    }
+}
+
+fn response(request: &[u8]) -> Vec<u8> {
+    let line = request.split(|&x| x == b'\r' || x == b'\n').next().unwrap_or(b"");
+    let line = String::from_utf8_lossy(line);
+    let mut words = line.split(' ');
+    let method = words.next().unwrap_or("");
+    let path = words.next().unwrap_or("");
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", render()),
+        ("GET", "/healthz") => ("200 OK", "ok\n".to_string()),
+        ("GET", _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    format!(
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    ).into_bytes()
+}
+
+fn serve_client(sock: TcpStream) -> Box<Future<Item = (), Error = IoError>> {
+    let head = loop_fn((sock, vec![]), |(sock, mut acc)| {
+        read(sock, vec![0; 1024]).map(move |(sock, buf, n)| {
+            acc.extend_from_slice(&buf[..n]);
+            let complete = acc.windows(4).any(|x| x == b"\r\n\r\n");
+            if n == 0 || complete || acc.len() >= MAX_REQUEST {
+                Loop::Break((sock, acc))
+            } else {
+                Loop::Continue((sock, acc))
+            }
+        })
+    });
+    Box::new(
+        head.and_then(|(sock, req)| write_all(sock, response(&req)))
+            .and_then(|(sock, _)| shutdown(sock))
+            .map(|_| ()),
+    )
+}
+
+/// Listen for HTTP requests for `/metrics` and `/healthz`. Clients are served
+/// in separate tasks and their errors are only logged.
+pub fn serve_metrics(
+    addr: &SocketAddr,
+    h: &Handle,
+) -> Result<Box<Future<Item = (), Error = ()>>, IoError> {
+    let l = TcpListener::bind(addr, h)?;
+    info!("Serving metrics on {}", addr);
+    let h = h.clone();
+    Ok(Box::new(
+        l.incoming()
+            .for_each(move |(sock, _)| {
+                h.spawn(serve_client(sock).map_err(|e| debug!("Metrics client: {}", e)));
+                ok(())
+            })
+            .map_err(|e| error!("Metrics listener: {}", e)),
+    ))
+}
@@ -0,0 +1,204 @@
+//! Per-message hooks of a session: inspect, rewrite, drop messages or close the session.
+//!
+//! Library users add them with `WebsocatBuilder::map_incoming` and `map_outgoing`;
+//! `--filter-in-regex`, `--filter-out-regex` and `--rewrite` are hooks as well, and run first.
+//! Incoming messages are the ones coming from the right specifier, outgoing are sent to it.
+//!
+//! Hooks run on the reactor thread, just like `lifecycle` callbacks.
+
+use futures;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use super::ws_peer::with_close_reason;
+use super::{read_message_part, ReadDebt};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// What a hook gets to see
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A whole message, even if it is bigger than the read buffer,
+    /// or a chunk of data for byte stream specifiers
+    Data(Vec<u8>),
+    /// A WebSocket peer in this direction sent Close, with status code and reason if any.
+    /// Only for information: the returned action is ignored.
+    CloseReceived(Option<(u16, String)>),
+}
+
+/// What to do with a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MiddlewareAction {
+    /// Let the message through as it is
+    Pass(Vec<u8>),
+    /// Send this instead
+    Replace(Vec<u8>),
+    Drop,
+    /// End the session, closing WebSocket peers with this status code and reason
+    Close(u16, String),
+}
+
+pub type MessageHook = Rc<RefCell<FnMut(Message) -> MiddlewareAction>>;
+
+pub fn hook<F: FnMut(Message) -> MiddlewareAction + 'static>(f: F) -> MessageHook {
+    Rc::new(RefCell::new(f))
+}
+
+/// Hooks set by the library user, carried in `Options`. Shared by all sessions.
+#[derive(Clone, Default)]
+pub struct Middleware {
+    pub incoming: Vec<MessageHook>,
+    pub outgoing: Vec<MessageHook>,
+}
+
+impl fmt::Debug for Middleware {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} incoming and {} outgoing hook(s)", self.incoming.len(), self.outgoing.len())
+    }
+}
+
+thread_local! {
+    static CLOSE_RECEIVED: RefCell<Option<Option<(u16, String)>>> = RefCell::new(None);
+}
+
+/// A WebSocket peer has received Close. Picked up by the `MiddlewareRead` it is being read through.
+pub fn note_close_received(code: Option<(u16, String)>) {
+    CLOSE_RECEIVED.with(|x| *x.borrow_mut() = Some(code));
+}
+
+#[derive(Default)]
+pub struct MiddlewareState {
+    /// Set by `MiddlewareAction::Close`
+    close: Option<(u16, String)>,
+}
+
+pub type HMiddlewareState = Rc<RefCell<MiddlewareState>>;
+
+/// Reader that passes each message through the hooks in order.
+/// After a hook asks to close, both directions of the session report EOF.
+pub struct MiddlewareRead {
+    inner: Box<AsyncRead>,
+    hooks: Vec<MessageHook>,
+    state: HMiddlewareState,
+    debt: ReadDebt,
+    /// Start of a message that did not fit the buffer, for hooks to see it whole
+    partial: Vec<u8>,
+    /// `incoming` or `outgoing`, for messages
+    direction: &'static str,
+}
+
+impl MiddlewareRead {
+    pub fn new(
+        inner: Box<AsyncRead>,
+        hooks: Vec<MessageHook>,
+        state: HMiddlewareState,
+        direction: &'static str,
+    ) -> MiddlewareRead {
+        MiddlewareRead {
+            inner,
+            hooks,
+            state,
+            debt: ReadDebt(None),
+            partial: vec![],
+            direction,
+        }
+    }
+
+    fn close_received(&mut self, code: Option<(u16, String)>) {
+        for h in &self.hooks {
+            let _ = (&mut *h.borrow_mut())(Message::CloseReceived(code.clone()));
+        }
+    }
+
+    /// `None` if the message is dropped
+    fn process(&mut self, msg: Vec<u8>) -> Option<Vec<u8>> {
+        let mut msg = msg;
+        for h in &self.hooks {
+            match (&mut *h.borrow_mut())(Message::Data(msg)) {
+                MiddlewareAction::Pass(x) | MiddlewareAction::Replace(x) => msg = x,
+                MiddlewareAction::Drop => {
+                    debug!("Dropped {} message", self.direction);
+                    return None;
+                }
+                MiddlewareAction::Close(code, reason) => {
+                    info!("Closing the session on {} message: {} {}", self.direction, code, reason);
+                    self.state.borrow_mut().close = Some((code, reason));
+                    // The other direction is likely waiting for data; let it see the closing
+                    futures::task::current().notify();
+                    return None;
+                }
+            }
+        }
+        Some(msg)
+    }
+}
+
+impl Read for MiddlewareRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        loop {
+            if self.state.borrow().close.is_some() {
+                return Ok(0);
+            }
+            CLOSE_RECEIVED.with(|x| x.borrow_mut().take());
+            let (r, more) = read_message_part(&mut *self.inner, buf);
+            if let Some(code) = CLOSE_RECEIVED.with(|x| x.borrow_mut().take()) {
+                self.close_received(code);
+            }
+            let n = r?;
+            if n == 0 {
+                if !self.partial.is_empty() {
+                    warn!("Dropped incomplete {} message", self.direction);
+                    self.partial.clear();
+                }
+                return Ok(0);
+            }
+            if more {
+                self.partial.extend_from_slice(&buf[..n]);
+                continue;
+            }
+            let msg = if self.partial.is_empty() {
+                buf[..n].to_vec()
+            } else {
+                let mut m = ::std::mem::replace(&mut self.partial, vec![]);
+                m.extend_from_slice(&buf[..n]);
+                m
+            };
+            if let Some(msg) = self.process(msg) {
+                // Longer messages get split if they don't fit the buffer
+                return self.debt.process_message(buf, &msg);
+            }
+        }
+    }
+}
+impl AsyncRead for MiddlewareRead {}
+
+/// Writer that closes WebSocket peers with the status code and reason a hook asked for
+pub struct MiddlewareWrite {
+    pub inner: Box<AsyncWrite>,
+    pub state: HMiddlewareState,
+}
+
+impl Write for MiddlewareWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for MiddlewareWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        let close = self.state.borrow().close.clone();
+        if let Some((code, reason)) = close {
+            let inner = &mut self.inner;
+            return with_close_reason(code, &reason, || inner.shutdown());
+        }
+        self.inner.shutdown()
+    }
+}
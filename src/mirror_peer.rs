@@ -8,15 +8,22 @@ use std;
 use std::io::Result as IoResult;
 use std::io::{Read, Write};
 
+use futures::task::Task;
 use futures::Async::{NotReady, Ready};
+use futures::Future;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
 
 use futures::sync::mpsc;
 
 use tokio_io::{AsyncRead, AsyncWrite};
 
+use super::util::XorShift;
 use super::ReadDebt;
-use super::{once, ConstructParams, PeerConstructor, Specifier};
+use super::{once, simple_err, ConstructParams, Options, PeerConstructor, Specifier};
 
 #[derive(Debug, Clone)]
 pub struct Mirror;
@@ -38,6 +45,47 @@ Similar to `exec:cat`.
 "#
 );
 
+#[derive(Debug, Clone)]
+pub struct BadMirror;
+impl Specifier for BadMirror {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        once(get_bad_mirror_peer(&cp.program_options, &cp.tokio_handle))
+    }
+    specifier_boilerplate!(noglobalstate singleconnect no_subspec typ=Other);
+}
+specifier_class!(
+    name = BadMirrorClass,
+    target = BadMirror,
+    prefixes = ["badmirror:"],
+    arg_handling = noarg,
+    consumed_options = &[
+        "--mirror-delay-ms",
+        "--mirror-drop-rate",
+        "--mirror-corrupt-rate",
+        "--mirror-dup-rate",
+        "--mirror-close-after",
+        "--random-seed",
+    ],
+    help = r#"
+Like `mirror:`, but unreliable, for testing how clients cope with faults.
+
+Faults are controlled by options:
+
+* --mirror-delay-ms <ms> - echo each message after a delay
+* --mirror-drop-rate <p> - silently drop a message with probability p
+* --mirror-corrupt-rate <p> - change a random byte of a message
+* --mirror-dup-rate <p> - echo a message twice
+* --mirror-close-after <n> - fail the session after n messages
+
+Use --random-seed to get the same faults each run.
+Each injected fault is logged at debug level with the message number.
+
+Example:
+
+    websocat -t ws-l:127.0.0.1:1234 badmirror: --mirror-drop-rate 0.1 --mirror-delay-ms 300
+"#
+);
+
 #[derive(Clone)]
 pub struct LiteralReply(pub Vec<u8>);
 impl Specifier for LiteralReply {
@@ -184,3 +232,156 @@ impl Read for LiteralReplyRead {
         }
     }
 }
+
+////
+struct BadMirrorState {
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    rng: XorShift,
+    delay: Duration,
+    drop_rate: f64,
+    corrupt_rate: f64,
+    dup_rate: f64,
+    close_after: Option<u64>,
+    /// Number of messages written so far
+    index: u64,
+    broken: bool,
+    shut_down: bool,
+    timer: Option<Timeout>,
+    handle: Handle,
+    reader: Option<Task>,
+}
+
+impl BadMirrorState {
+    fn wake_reader(&mut self) {
+        if let Some(t) = self.reader.take() {
+            t.notify();
+        }
+    }
+
+    fn push(&mut self, buf: &[u8]) {
+        self.index += 1;
+        let i = self.index;
+        if self.rng.chance(self.drop_rate) {
+            debug!("badmirror: dropping message #{}", i);
+            return;
+        }
+        let mut m = buf.to_vec();
+        if !m.is_empty() && self.rng.chance(self.corrupt_rate) {
+            let pos = self.rng.below(m.len() as u64) as usize;
+            let x = 1 + self.rng.below(255) as u8;
+            debug!("badmirror: corrupting byte {} of message #{}", pos, i);
+            m[pos] ^= x;
+        }
+        let due = Instant::now() + self.delay;
+        if self.rng.chance(self.dup_rate) {
+            debug!("badmirror: duplicating message #{}", i);
+            self.queue.push_back((due, m.clone()));
+        }
+        self.queue.push_back((due, m));
+        if Some(i) == self.close_after {
+            debug!("badmirror: breaking the session after message #{}", i);
+            self.broken = true;
+        }
+        self.wake_reader();
+    }
+}
+
+struct BadMirrorHandle(Rc<RefCell<BadMirrorState>>);
+struct BadMirrorRead {
+    s: Rc<RefCell<BadMirrorState>>,
+    debt: ReadDebt,
+}
+
+pub fn get_bad_mirror_peer(opts: &Options, h: &Handle) -> BoxedNewPeerFuture {
+    let rng = match opts.random_seed {
+        Some(x) => XorShift::with_seed(x),
+        None => XorShift::new(),
+    };
+    let s = Rc::new(RefCell::new(BadMirrorState {
+        queue: VecDeque::new(),
+        rng,
+        delay: Duration::from_millis(opts.mirror_delay_ms),
+        drop_rate: opts.mirror_drop_rate,
+        corrupt_rate: opts.mirror_corrupt_rate,
+        dup_rate: opts.mirror_dup_rate,
+        close_after: opts.mirror_close_after,
+        index: 0,
+        broken: false,
+        shut_down: false,
+        timer: None,
+        handle: h.clone(),
+        reader: None,
+    }));
+    let r = BadMirrorRead {
+        s: s.clone(),
+        debt: Default::default(),
+    };
+    let w = BadMirrorHandle(s);
+    Box::new(futures::future::ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+fn badmirror_broken<T>() -> IoResult<T> {
+    Err(simple_err("badmirror: closing the session as requested".to_string()))
+}
+
+impl AsyncRead for BadMirrorRead {}
+impl Read for BadMirrorRead {
+    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        let mut s = self.s.borrow_mut();
+        let due = match s.queue.front() {
+            Some(&(t, _)) => t,
+            None if s.broken => return badmirror_broken(),
+            None if s.shut_down => return Ok(0),
+            None => {
+                s.reader = Some(futures::task::current());
+                return wouldblock();
+            }
+        };
+        let now = Instant::now();
+        if due > now {
+            if s.timer.is_none() {
+                s.timer = Some(Timeout::new(due - now, &s.handle)?);
+            }
+            if let NotReady = s.timer.as_mut().unwrap().poll()? {
+                return wouldblock();
+            }
+        }
+        s.timer = None;
+        let m = s.queue.pop_front().unwrap().1;
+        self.debt.process_message(buf, &m)
+    }
+}
+
+impl AsyncWrite for BadMirrorHandle {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        let mut s = self.0.borrow_mut();
+        s.shut_down = true;
+        s.wake_reader();
+        Ok(Ready(()))
+    }
+}
+
+impl Write for BadMirrorHandle {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut s = self.0.borrow_mut();
+        if s.broken {
+            return badmirror_broken();
+        }
+        s.push(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BadMirrorHandle {
+    fn drop(&mut self) {
+        let mut s = self.0.borrow_mut();
+        s.shut_down = true;
+        s.wake_reader();
+    }
+}
@@ -0,0 +1,749 @@
+extern crate base64;
+extern crate serde_json;
+
+use futures;
+use futures::future::ok;
+
+use std::rc::Rc;
+
+use super::my_copy::BufferSettings;
+use super::{BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, PeerConstructor, ReadDebt, Specifier, WriteDebt};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use self::serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    MsgPack,
+    Cbor,
+}
+
+#[derive(Debug)]
+pub struct MsgPack2Json<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for MsgPack2Json<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let b64 = cp.program_options.binary_as_base64;
+        let max = BufferSettings::from_options(&cp.program_options).size;
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| binconv_peer(p, Format::MsgPack, b64, max))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = MsgPack2JsonClass,
+    target = MsgPack2Json,
+    prefixes = ["msgpack2json:"],
+    arg_handling = subspec,
+    consumed_options = &["--binary-as-base64"],
+    help = r#"
+Convert MessagePack messages read from the subspecifier to JSON text, and
+JSON text messages written to it to MessagePack. Each MessagePack value becomes
+one JSON message: values split across reads are reassembled, several values
+in one read are converted one by one. A value bigger than --buffer-size is dropped.
+
+Conversion rules:
+
+* JSON output gets a trailing newline;
+* binary blobs and non-UTF-8 strings become arrays of byte values,
+  or base64 strings with --binary-as-base64 (not converted back);
+* map keys that are not strings are replaced by their JSON text, e.g. `1` -> `"1"`;
+* extension values become `[type, blob]`;
+* NaN and infinities become `null`;
+* integers stay integers, other numbers are encoded as 64-bit floats.
+
+Messages that fail to convert are reported (with byte offset or JSON path) and dropped.
+
+Example: interactive session with a MessagePack-speaking server
+
+    websocat -t msgpack2json:ws://127.0.0.1:8080/rpc -
+"#
+);
+
+#[derive(Debug)]
+pub struct Cbor2Json<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Cbor2Json<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let b64 = cp.program_options.binary_as_base64;
+        let max = BufferSettings::from_options(&cp.program_options).size;
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| binconv_peer(p, Format::Cbor, b64, max))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = Cbor2JsonClass,
+    target = Cbor2Json,
+    prefixes = ["cbor2json:"],
+    arg_handling = subspec,
+    consumed_options = &["--binary-as-base64"],
+    help = r#"
+Like `msgpack2json:`, but for CBOR. Tags are ignored (tagged value is
+converted as is), `undefined` and unassigned simple values become `null`.
+
+Example:
+
+    websocat -t cbor2json:ws://127.0.0.1:8080/ -
+"#
+);
+
+pub fn binconv_peer(inner_peer: Peer, fmt: Format, b64: bool, max: usize) -> BoxedNewPeerFuture {
+    let r = BinConvRead {
+        inner: inner_peer.0,
+        fmt,
+        b64,
+        queue: vec![],
+        max,
+        debt: Default::default(),
+    };
+    let w = BinConvWrite {
+        inner: inner_peer.1,
+        fmt,
+        debt: Default::default(),
+    };
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+/// Convert one MessagePack or CBOR message to JSON text
+pub fn binary_to_json(fmt: Format, data: &[u8], b64: bool) -> Result<Vec<u8>, String> {
+    match binary_prefix_to_json(fmt, data, b64) {
+        Ok(Some((t, n))) if n == data.len() => Ok(t),
+        Ok(Some((_, n))) => Err(format!("Trailing data at byte offset {}", n)),
+        Ok(None) => Err(format!(
+            "Unexpected end of data at byte offset {}",
+            data.len()
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// Convert the MessagePack or CBOR value at the start of `data` to JSON text,
+/// also returning how many bytes it took. `None` if `data` ends before the value does.
+pub fn binary_prefix_to_json(
+    fmt: Format,
+    data: &[u8],
+    b64: bool,
+) -> Result<Option<(Vec<u8>, usize)>, String> {
+    let mut d = Decoder {
+        data,
+        pos: 0,
+        b64,
+        short: false,
+    };
+    let v = match fmt {
+        Format::MsgPack => d.msgpack_value(0),
+        Format::Cbor => d.cbor_value(0),
+    };
+    let v = match v {
+        Ok(v) => v,
+        Err(_) if d.short => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut t = v.to_string().into_bytes();
+    t.push(b'\n');
+    Ok(Some((t, d.pos)))
+}
+
+/// Convert one JSON text message to MessagePack or CBOR.
+/// Empty (whitespace-only) input gives empty output.
+pub fn json_to_binary(fmt: Format, text: &[u8]) -> Result<Vec<u8>, String> {
+    if text.iter().all(|x| (*x as char).is_whitespace()) {
+        return Ok(vec![]);
+    }
+    let v: Value = serde_json::from_slice(text).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let mut out = vec![];
+    let mut path = vec![];
+    match fmt {
+        Format::MsgPack => encode_msgpack(&mut out, &v, &mut path)?,
+        Format::Cbor => encode_cbor(&mut out, &v),
+    }
+    Ok(out)
+}
+
+const MAX_DEPTH: usize = 256;
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    b64: bool,
+    /// Failed only because the data ended too early
+    short: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: u64) -> Result<&'a [u8], String> {
+        if n > (self.data.len() - self.pos) as u64 {
+            self.short = true;
+            return Err(format!(
+                "Unexpected end of data at byte offset {}",
+                self.data.len()
+            ));
+        }
+        let s = &self.data[self.pos..(self.pos + n as usize)];
+        self.pos += n as usize;
+        Ok(s)
+    }
+
+    fn uint(&mut self, n: u64) -> Result<u64, String> {
+        Ok(self.take(n)?
+            .iter()
+            .fold(0u64, |a, &b| (a << 8) | (b as u64)))
+    }
+
+    /// Element count of a container. Each element takes at least one byte,
+    /// so bigger counts are rejected early instead of allocating for them.
+    fn count(&mut self, n: u64, at: usize) -> Result<usize, String> {
+        let l = self.uint(n)?;
+        self.check_count(l, at)?;
+        Ok(l as usize)
+    }
+
+    fn check_count(&mut self, l: u64, at: usize) -> Result<(), String> {
+        if l > (self.data.len() - self.pos) as u64 {
+            self.short = true;
+            return Err(format!("Length {} is too big at byte offset {}", l, at));
+        }
+        Ok(())
+    }
+
+    fn blob(&self, b: &[u8]) -> Value {
+        if self.b64 {
+            Value::String(base64::encode(b))
+        } else {
+            Value::Array(b.iter().map(|&x| Value::from(x)).collect())
+        }
+    }
+
+    fn text(&self, b: &[u8]) -> Value {
+        match ::std::str::from_utf8(b) {
+            Ok(s) => Value::String(s.to_string()),
+            Err(_) => self.blob(b),
+        }
+    }
+
+    fn msgpack_value(&mut self, depth: usize) -> Result<Value, String> {
+        let at = self.pos;
+        if depth > MAX_DEPTH {
+            return Err(format!("Nesting is too deep at byte offset {}", at));
+        }
+        let b = self.take(1)?[0];
+        Ok(match b {
+            0x00..=0x7f => Value::from(b),
+            0x80..=0x8f => self.msgpack_map((b & 0x0f) as usize, depth)?,
+            0x90..=0x9f => self.msgpack_array((b & 0x0f) as usize, depth)?,
+            0xa0..=0xbf => {
+                let s = self.take((b & 0x1f) as u64)?;
+                self.text(s)
+            }
+            0xc0 => Value::Null,
+            0xc1 => return Err(format!("Invalid byte 0xc1 at byte offset {}", at)),
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4..=0xc6 => {
+                let l = self.uint(1 << (b - 0xc4))?;
+                let s = self.take(l)?;
+                self.blob(s)
+            }
+            0xc7..=0xc9 => {
+                let l = self.uint(1 << (b - 0xc7))?;
+                self.msgpack_ext(l)?
+            }
+            0xca => Value::from(f32::from_bits(self.uint(4)? as u32) as f64),
+            0xcb => Value::from(f64::from_bits(self.uint(8)?)),
+            0xcc..=0xcf => Value::from(self.uint(1 << (b - 0xcc))?),
+            0xd0..=0xd3 => {
+                let n = 1 << (b - 0xd0);
+                let x = self.uint(n)?;
+                // sign-extend
+                let shift = 64 - 8 * n;
+                Value::from(((x << shift) as i64) >> shift)
+            }
+            0xd4..=0xd8 => self.msgpack_ext(1 << (b - 0xd4))?,
+            0xd9..=0xdb => {
+                let l = self.uint(1 << (b - 0xd9))?;
+                let s = self.take(l)?;
+                self.text(s)
+            }
+            0xdc | 0xdd => {
+                let l = self.count(2 << (b - 0xdc), at)?;
+                self.msgpack_array(l, depth)?
+            }
+            0xde | 0xdf => {
+                let l = self.count(2 << (b - 0xde), at)?;
+                self.msgpack_map(l, depth)?
+            }
+            0xe0..=0xff => Value::from(b as i8),
+        })
+    }
+
+    fn msgpack_ext(&mut self, len: u64) -> Result<Value, String> {
+        let typ = self.take(1)?[0] as i8;
+        let s = self.take(len)?;
+        Ok(Value::Array(vec![Value::from(typ), self.blob(s)]))
+    }
+
+    fn msgpack_array(&mut self, len: usize, depth: usize) -> Result<Value, String> {
+        let mut v = Vec::with_capacity(len);
+        for _ in 0..len {
+            v.push(self.msgpack_value(depth + 1)?);
+        }
+        Ok(Value::Array(v))
+    }
+
+    fn msgpack_map(&mut self, len: usize, depth: usize) -> Result<Value, String> {
+        let mut m = Map::new();
+        for _ in 0..len {
+            let k = self.msgpack_value(depth + 1)?;
+            let v = self.msgpack_value(depth + 1)?;
+            m.insert(key_to_string(k), v);
+        }
+        Ok(Value::Object(m))
+    }
+
+    /// Returns `None` for indefinite length
+    fn cbor_arg(&mut self, ai: u8, at: usize) -> Result<Option<u64>, String> {
+        Ok(Some(match ai {
+            0..=23 => ai as u64,
+            24..=27 => self.uint(1 << (ai - 24))?,
+            31 => return Ok(None),
+            _ => {
+                return Err(format!(
+                    "Reserved additional information {} at byte offset {}",
+                    ai, at
+                ))
+            }
+        }))
+    }
+
+    fn cbor_is_break(&self) -> bool {
+        self.data.get(self.pos) == Some(&0xff)
+    }
+
+    /// Concatenate definite-length chunks of indefinite-length string
+    fn cbor_chunks(&mut self, major: u8) -> Result<Vec<u8>, String> {
+        let mut v = vec![];
+        loop {
+            if self.cbor_is_break() {
+                self.pos += 1;
+                return Ok(v);
+            }
+            let at = self.pos;
+            let b = self.take(1)?[0];
+            if b >> 5 != major {
+                return Err(format!("Invalid string chunk at byte offset {}", at));
+            }
+            match self.cbor_arg(b & 0x1f, at)? {
+                Some(l) => v.extend_from_slice(self.take(l)?),
+                None => return Err(format!("Nested indefinite string at byte offset {}", at)),
+            }
+        }
+    }
+
+    fn cbor_value(&mut self, depth: usize) -> Result<Value, String> {
+        let at = self.pos;
+        if depth > MAX_DEPTH {
+            return Err(format!("Nesting is too deep at byte offset {}", at));
+        }
+        let b = self.take(1)?[0];
+        let major = b >> 5;
+        let ai = b & 0x1f;
+        if major == 7 {
+            return Ok(match ai {
+                20 => Value::Bool(false),
+                21 => Value::Bool(true),
+                25 => Value::from(half_to_f64(self.uint(2)? as u16)),
+                26 => Value::from(f32::from_bits(self.uint(4)? as u32) as f64),
+                27 => Value::from(f64::from_bits(self.uint(8)?)),
+                24 => {
+                    self.take(1)?;
+                    Value::Null
+                }
+                0..=23 => Value::Null,
+                31 => return Err(format!("Unexpected break at byte offset {}", at)),
+                _ => {
+                    return Err(format!(
+                        "Reserved additional information {} at byte offset {}",
+                        ai, at
+                    ))
+                }
+            });
+        }
+        let arg = self.cbor_arg(ai, at)?;
+        Ok(match (major, arg) {
+            (0, Some(n)) => Value::from(n),
+            (1, Some(n)) => {
+                if n <= i64::max_value() as u64 {
+                    Value::from(-1 - (n as i64))
+                } else {
+                    Value::from(-1.0 - (n as f64))
+                }
+            }
+            (2, Some(l)) => {
+                let s = self.take(l)?;
+                self.blob(s)
+            }
+            (2, None) => {
+                let s = self.cbor_chunks(2)?;
+                self.blob(&s)
+            }
+            (3, Some(l)) => {
+                let s = self.take(l)?;
+                self.text(s)
+            }
+            (3, None) => {
+                let s = self.cbor_chunks(3)?;
+                self.text(&s)
+            }
+            (4, Some(l)) => {
+                self.check_count(l, at)?;
+                let mut v = Vec::with_capacity(l as usize);
+                for _ in 0..l {
+                    v.push(self.cbor_value(depth + 1)?);
+                }
+                Value::Array(v)
+            }
+            (4, None) => {
+                let mut v = vec![];
+                while !self.cbor_is_break() {
+                    v.push(self.cbor_value(depth + 1)?);
+                }
+                self.pos += 1;
+                Value::Array(v)
+            }
+            (5, l) => {
+                let mut m = Map::new();
+                let mut i = 0;
+                loop {
+                    match l {
+                        Some(l) if i >= l => break,
+                        None if self.cbor_is_break() => {
+                            self.pos += 1;
+                            break;
+                        }
+                        _ => (),
+                    }
+                    let k = self.cbor_value(depth + 1)?;
+                    let v = self.cbor_value(depth + 1)?;
+                    m.insert(key_to_string(k), v);
+                    i += 1;
+                }
+                Value::Object(m)
+            }
+            (6, Some(_tag)) => self.cbor_value(depth + 1)?,
+            _ => {
+                return Err(format!(
+                    "Invalid indefinite length item at byte offset {}",
+                    at
+                ))
+            }
+        })
+    }
+}
+
+fn key_to_string(k: Value) -> String {
+    match k {
+        Value::String(s) => s,
+        x => x.to_string(),
+    }
+}
+
+fn half_to_f64(h: u16) -> f64 {
+    let exp = (h >> 10) & 0x1f;
+    let mant = (h & 0x3ff) as f64;
+    let v = match exp {
+        0 => mant * 2f64.powi(-24),
+        31 => {
+            if mant == 0.0 {
+                ::std::f64::INFINITY
+            } else {
+                ::std::f64::NAN
+            }
+        }
+        _ => (mant + 1024.0) * 2f64.powi(exp as i32 - 25),
+    };
+    if h & 0x8000 != 0 {
+        -v
+    } else {
+        v
+    }
+}
+
+#[derive(Debug)]
+enum PathElem<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+fn format_path(path: &[PathElem]) -> String {
+    let mut s = "$".to_string();
+    for e in path {
+        match *e {
+            PathElem::Key(k) => {
+                if !k.is_empty() && k.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    s.push('.');
+                    s.push_str(k);
+                } else {
+                    s.push_str(&format!("[{}]", Value::from(k)));
+                }
+            }
+            PathElem::Index(i) => s.push_str(&format!("[{}]", i)),
+        }
+    }
+    s
+}
+
+fn too_long(what: &str, path: &[PathElem]) -> String {
+    format!("{} at {} is too long for MessagePack", what, format_path(path))
+}
+
+fn msgpack_head(out: &mut Vec<u8>, fix: Option<u8>, codes: [u8; 3], l: usize) -> bool {
+    if let Some(fix) = fix {
+        out.push(fix | l as u8);
+    } else if l <= 0xff && codes[0] != 0 {
+        out.push(codes[0]);
+        out.push(l as u8);
+    } else if l <= 0xffff {
+        out.push(codes[1]);
+        out.extend_from_slice(&be(l as u64, 2));
+    } else if l as u64 <= 0xffff_ffff {
+        out.push(codes[2]);
+        out.extend_from_slice(&be(l as u64, 4));
+    } else {
+        return false;
+    }
+    true
+}
+
+fn be(x: u64, n: usize) -> Vec<u8> {
+    (0..n).rev().map(|i| (x >> (8 * i)) as u8).collect()
+}
+
+fn encode_msgpack<'a>(
+    out: &mut Vec<u8>,
+    v: &'a Value,
+    path: &mut Vec<PathElem<'a>>,
+) -> Result<(), String> {
+    match *v {
+        Value::Null => out.push(0xc0),
+        Value::Bool(b) => out.push(if b { 0xc3 } else { 0xc2 }),
+        Value::Number(ref n) => {
+            if let Some(x) = n.as_u64() {
+                match x {
+                    0..=0x7f => out.push(x as u8),
+                    0x80..=0xff => out.extend_from_slice(&[0xcc, x as u8]),
+                    0x100..=0xffff => {
+                        out.push(0xcd);
+                        out.extend_from_slice(&be(x, 2));
+                    }
+                    0x1_0000..=0xffff_ffff => {
+                        out.push(0xce);
+                        out.extend_from_slice(&be(x, 4));
+                    }
+                    _ => {
+                        out.push(0xcf);
+                        out.extend_from_slice(&be(x, 8));
+                    }
+                }
+            } else if let Some(x) = n.as_i64() {
+                // Negative numbers only here
+                if x >= -32 {
+                    out.push(x as u8);
+                } else if x >= -128 {
+                    out.extend_from_slice(&[0xd0, x as u8]);
+                } else if x >= -32768 {
+                    out.push(0xd1);
+                    out.extend_from_slice(&be(x as u64, 2));
+                } else if x >= -2_147_483_648 {
+                    out.push(0xd2);
+                    out.extend_from_slice(&be(x as u64, 4));
+                } else {
+                    out.push(0xd3);
+                    out.extend_from_slice(&be(x as u64, 8));
+                }
+            } else {
+                let x = n.as_f64().unwrap_or(0.0);
+                out.push(0xcb);
+                out.extend_from_slice(&be(x.to_bits(), 8));
+            }
+        }
+        Value::String(ref s) => {
+            let fix = if s.len() < 32 { Some(0xa0) } else { None };
+            if !msgpack_head(out, fix, [0xd9, 0xda, 0xdb], s.len()) {
+                return Err(too_long("Value", path));
+            }
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(ref a) => {
+            let fix = if a.len() < 16 { Some(0x90) } else { None };
+            if !msgpack_head(out, fix, [0, 0xdc, 0xdd], a.len()) {
+                return Err(too_long("Value", path));
+            }
+            for (i, x) in a.iter().enumerate() {
+                path.push(PathElem::Index(i));
+                encode_msgpack(out, x, path)?;
+                path.pop();
+            }
+        }
+        Value::Object(ref m) => {
+            let fix = if m.len() < 16 { Some(0x80) } else { None };
+            if !msgpack_head(out, fix, [0, 0xde, 0xdf], m.len()) {
+                return Err(too_long("Value", path));
+            }
+            for (k, x) in m {
+                path.push(PathElem::Key(k));
+                let fix = if k.len() < 32 { Some(0xa0) } else { None };
+                if !msgpack_head(out, fix, [0xd9, 0xda, 0xdb], k.len()) {
+                    return Err(too_long("Key", path));
+                }
+                out.extend_from_slice(k.as_bytes());
+                encode_msgpack(out, x, path)?;
+                path.pop();
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, x: u64) {
+    let m = major << 5;
+    match x {
+        0..=23 => out.push(m | x as u8),
+        24..=0xff => out.extend_from_slice(&[m | 24, x as u8]),
+        0x100..=0xffff => {
+            out.push(m | 25);
+            out.extend_from_slice(&be(x, 2));
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(m | 26);
+            out.extend_from_slice(&be(x, 4));
+        }
+        _ => {
+            out.push(m | 27);
+            out.extend_from_slice(&be(x, 8));
+        }
+    }
+}
+
+/// CBOR has no length limits that a JSON value can hit, so this cannot fail
+fn encode_cbor(out: &mut Vec<u8>, v: &Value) {
+    match *v {
+        Value::Null => out.push(0xf6),
+        Value::Bool(b) => out.push(if b { 0xf5 } else { 0xf4 }),
+        Value::Number(ref n) => {
+            if let Some(x) = n.as_u64() {
+                cbor_head(out, 0, x);
+            } else if let Some(x) = n.as_i64() {
+                cbor_head(out, 1, !x as u64);
+            } else {
+                let x = n.as_f64().unwrap_or(0.0);
+                out.push(0xfb);
+                out.extend_from_slice(&be(x.to_bits(), 8));
+            }
+        }
+        Value::String(ref s) => {
+            cbor_head(out, 3, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(ref a) => {
+            cbor_head(out, 4, a.len() as u64);
+            for x in a {
+                encode_cbor(out, x);
+            }
+        }
+        Value::Object(ref m) => {
+            cbor_head(out, 5, m.len() as u64);
+            for (k, x) in m {
+                cbor_head(out, 3, k.len() as u64);
+                out.extend_from_slice(k.as_bytes());
+                encode_cbor(out, x);
+            }
+        }
+    }
+}
+
+struct BinConvRead {
+    inner: Box<AsyncRead>,
+    fmt: Format,
+    b64: bool,
+    /// Data read but not converted yet, starting at a value boundary
+    queue: Vec<u8>,
+    /// How much of an incomplete value may be buffered
+    max: usize,
+    debt: ReadDebt,
+}
+
+impl Read for BinConvRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        loop {
+            if !self.queue.is_empty() {
+                match binary_prefix_to_json(self.fmt, &self.queue, self.b64) {
+                    Ok(Some((x, n))) => {
+                        self.queue.drain(..n);
+                        return self.debt.process_message(buf, &x);
+                    }
+                    Ok(None) if self.queue.len() > self.max => {
+                        error!(
+                            "Failed to decode {:?} message: value is bigger than {} bytes",
+                            self.fmt, self.max
+                        );
+                        self.queue.clear();
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        // No way to find where the next value starts
+                        error!("Failed to decode {:?} message: {}", self.fmt, e);
+                        self.queue.clear();
+                    }
+                }
+            }
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                if !self.queue.is_empty() {
+                    error!(
+                        "Failed to decode {:?} message: {} bytes of incomplete value at the end",
+                        self.fmt,
+                        self.queue.len()
+                    );
+                    self.queue.clear();
+                }
+                return Ok(0);
+            }
+            self.queue.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+impl AsyncRead for BinConvRead {}
+
+struct BinConvWrite {
+    inner: Box<AsyncWrite>,
+    fmt: Format,
+    debt: WriteDebt,
+}
+
+impl Write for BinConvWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let fmt = self.fmt;
+        self.debt.write_frame(&mut self.inner, || {
+            Ok(json_to_binary(fmt, buf).unwrap_or_else(|e| {
+                error!("Failed to encode {:?} message: {}", fmt, e);
+                vec![]
+            }))
+        })?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for BinConvWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
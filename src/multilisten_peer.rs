@@ -0,0 +1,131 @@
+use futures::future::{ok, Future};
+use futures::stream::Stream;
+
+use std::error::Error;
+use std::rc::Rc;
+
+use super::{multi, BoxedNewPeerFuture, BoxedNewPeerStream, LeftSpecToRightSpec, Peer};
+use super::{ConstructParams, PeerConstructor, PeerOverlay, Specifier, SpecifierType};
+
+pub struct MultiListen(pub Vec<(String, Rc<Specifier>)>);
+
+impl ::std::fmt::Debug for MultiListen {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let v: Vec<&Rc<Specifier>> = self.0.iter().map(|x| &x.1).collect();
+        write!(f, "MultiListen({:?})", v)
+    }
+}
+
+impl Specifier for MultiListen {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let best_effort = cp.program_options.listen_best_effort;
+        let mut merged: Option<BoxedNewPeerStream> = None;
+        let mut overlays: Vec<Option<PeerOverlay>> = vec![];
+        for (i, &(ref name, ref spec)) in self.0.iter().enumerate() {
+            let name = Rc::new(name.clone());
+            let (s, overlay) = accepted_peers(spec.construct(cp.clone()));
+            overlays.push(overlay);
+            let s = if best_effort {
+                let name = name.clone();
+                Box::new(
+                    s.then(move |r| match r {
+                        Ok(p) => Ok::<_, Box<Error>>(Some(p)),
+                        Err(e) => {
+                            error!("multilisten: {}: {}", name, e);
+                            Ok(None)
+                        }
+                    }).filter_map(|x| x),
+                ) as BoxedNewPeerStream
+            } else {
+                s
+            };
+            let name2 = name.clone();
+            let s = Box::new(s.map(move |p| {
+                info!("multilisten: session accepted by {}", name2);
+                let mut info = p.client_info().map(|x| (**x).clone()).unwrap_or_default();
+                info.listener = Some(i);
+                p.with_client_info(info)
+            })) as BoxedNewPeerStream;
+            merged = Some(match merged {
+                None => s,
+                Some(m) => Box::new(m.select(s)) as BoxedNewPeerStream,
+            });
+        }
+        let merged = merged.expect("multilisten: needs at least one listener");
+        if overlays.iter().all(|x| x.is_none()) {
+            return multi(merged);
+        }
+        // Overlays (like WebSocket handshakes) run after `--max-sessions`
+        // has counted the connection, same as for a single listener
+        let overlays = Rc::new(overlays);
+        let mapper = move |p: Peer| -> BoxedNewPeerFuture {
+            let i = p.client_info().and_then(|x| x.listener);
+            match i.and_then(|i| overlays[i].as_ref()) {
+                Some(f) => f(p),
+                None => Box::new(ok(p)) as BoxedNewPeerFuture,
+            }
+        };
+        PeerConstructor::OverlayM(merged, Rc::new(mapper) as PeerOverlay)
+    }
+    fn is_multiconnect(&self) -> bool {
+        true
+    }
+    fn uses_global_state(&self) -> bool {
+        self.0.iter().any(|x| x.1.uses_global_state())
+    }
+    /// WebSocket if all listeners are, so refused clients get a 503
+    fn get_type(&self) -> SpecifierType {
+        if self.0.iter().all(|x| x.1.get_type() == SpecifierType::WebSocket) {
+            SpecifierType::WebSocket
+        } else {
+            SpecifierType::Other
+        }
+    }
+    specifier_boilerplate!(no_subspec);
+}
+specifier_class!(
+    name = MultiListenClass,
+    target = MultiListen,
+    prefixes = ["multilisten:"],
+    arg_handling = {
+        fn construct(
+            self: &MultiListenClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            let mut v = vec![];
+            for n in just_arg.split('|') {
+                if n.is_empty() {
+                    Err("multilisten: empty listener in the list")?
+                }
+                v.push((n.to_string(), super::spec(n)?));
+            }
+            Ok(Rc::new(MultiListen(v)))
+        }
+    },
+    consumed_options = &["--listen-best-effort"],
+    help = r#"
+Accept connections from several listeners (a `|`-separated list of specifiers)
+and serve them all the same way, as if it were one listener.
+
+If any listener fails (e.g. can't bind its port), websocat exits.
+With --listen-best-effort, the failure is logged and other listeners continue.
+
+Limits like --max-sessions count the sessions of all listeners together.
+
+Example: serve WebSocket over both TCP and UNIX socket
+
+    websocat multilisten:'ws-l:127.0.0.1:8080|ws-l:unix-l:/run/ws.sock' tcp:127.0.0.1:5678
+"#
+);
+
+/// Connections accepted by a listener, and what makes them ready-to-use peers
+fn accepted_peers(pc: PeerConstructor) -> (BoxedNewPeerStream, Option<PeerOverlay>) {
+    use super::PeerConstructor::*;
+    match pc {
+        ServeMultipleTimes(s) => (s, None),
+        ServeOnce(f) => (Box::new(f.into_stream()) as BoxedNewPeerStream, None),
+        Overlay1(f, mapper) => (Box::new(f.into_stream()) as BoxedNewPeerStream, Some(mapper)),
+        OverlayM(s, mapper) => (s, Some(mapper)),
+    }
+}
@@ -1,8 +1,201 @@
+use std::collections::VecDeque;
+use std::fmt;
 use std::io;
+use std::io::Write;
+use std::time::Duration;
 
-use futures::{Future, Poll};
+use futures::{Async, Future, Poll, Stream};
+use tokio_core::reactor::{Handle, Interval, Timeout};
 
-use {AsyncRead, AsyncWrite};
+use super::ws_peer::{read_message_kind, with_message_kind, Mode1};
+use super::{bufpool, metrics};
+use {AsyncRead, AsyncWrite, Options};
+
+/// Read buffer of the transfer loop unless set by `--buffer-size`
+pub const DEFAULT_BUFFER_SIZE: usize = 65536;
+
+/// Read buffer size of the transfer loop and how far it may read ahead of a slow writer
+#[derive(Clone, Copy, Debug)]
+pub struct BufferSettings {
+    /// `--buffer-size`
+    pub size: usize,
+    /// `--high-watermark`. Without it, nothing is read until the previous read is written out.
+    pub high_watermark: Option<usize>,
+    /// `--raw-relay`: reads need not be written separately, so reading ahead
+    /// goes into one buffer of `high_watermark` bytes instead of a queue
+    pub raw: bool,
+}
+
+impl BufferSettings {
+    pub fn from_options(opts: &Options) -> BufferSettings {
+        BufferSettings {
+            size: opts.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            high_watermark: opts.high_watermark,
+            raw: opts.raw_relay,
+        }
+    }
+}
+
+pub const DEFAULT_CORK_WINDOW_US: u64 = 200;
+
+/// Whether writes may be held back until flush for up to `--cork-window-us`.
+/// Not with `--no-flush` or `--flush-interval-ms`, which could hold them much longer.
+pub fn corking(opts: &Options) -> bool {
+    opts.cork_window_us.unwrap_or(DEFAULT_CORK_WINDOW_US) > 0
+        && !opts.no_flush
+        && (opts.flush_interval_ms.is_none() || opts.flush_after_message)
+}
+
+/// When the transfer loop flushes the writer, besides at the end
+pub enum FlushPolicy {
+    /// After each write, the default
+    AfterWrite,
+    /// `--cork-window-us`: before waiting for more input, or when the window passes
+    /// since the first write that is not flushed yet. Lets WebSocket peers
+    /// gather small frames into one write.
+    Corked(Cork),
+    /// `--flush-interval-ms`
+    Interval(Interval),
+    /// `--no-flush`
+    Never,
+}
+
+impl fmt::Debug for FlushPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            FlushPolicy::AfterWrite => "AfterWrite",
+            FlushPolicy::Corked(_) => "Corked",
+            FlushPolicy::Interval(_) => "Interval",
+            FlushPolicy::Never => "Never",
+        };
+        f.write_str(name)
+    }
+}
+
+pub struct Cork {
+    window: Duration,
+    handle: Handle,
+    /// Started by the first write after a flush
+    timer: Option<Timeout>,
+}
+
+impl Cork {
+    fn start(&mut self) {
+        if self.timer.is_none() {
+            // Without the timer, writes are still flushed before waiting for input
+            self.timer = Timeout::new(self.window, &self.handle).ok();
+        }
+    }
+
+    fn expired(&mut self) -> io::Result<bool> {
+        match self.timer {
+            Some(ref mut t) => Ok(t.poll()?.is_ready()),
+            None => Ok(false),
+        }
+    }
+}
+
+impl FlushPolicy {
+    /// Policy for writing to the left (stdio or file) side
+    pub fn from_options(opts: &Options, h: &Handle) -> FlushPolicy {
+        if opts.no_flush {
+            return FlushPolicy::Never;
+        }
+        match opts.flush_interval_ms {
+            Some(ms) if !opts.flush_after_message => match Interval::new(Duration::from_millis(ms), h) {
+                Ok(i) => FlushPolicy::Interval(i),
+                Err(e) => {
+                    warn!("Can't set up flush timer, flushing after each write: {}", e);
+                    FlushPolicy::AfterWrite
+                }
+            },
+            _ => FlushPolicy::after_write(opts, h),
+        }
+    }
+
+    /// Flushing after each write, or as soon as it does not delay anything with `--cork-window-us`
+    pub fn after_write(opts: &Options, h: &Handle) -> FlushPolicy {
+        if !corking(opts) {
+            return FlushPolicy::AfterWrite;
+        }
+        let us = opts.cork_window_us.unwrap_or(DEFAULT_CORK_WINDOW_US);
+        FlushPolicy::Corked(Cork {
+            window: Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1000),
+            handle: h.clone(),
+            timer: None,
+        })
+    }
+
+    /// Whether the writer should be flushed before waiting for more input
+    fn before_waiting(&self) -> bool {
+        match *self {
+            FlushPolicy::Corked(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Output buffer size for stdio and files: they are written directly
+/// unless flushing is deferred by `--no-flush` or `--flush-interval-ms`
+pub fn output_buffer_size(opts: &Options) -> usize {
+    if opts.no_flush || (opts.flush_interval_ms.is_some() && !opts.flush_after_message) {
+        65536
+    } else {
+        0
+    }
+}
+
+/// Collects small writes until flushed or full
+pub struct BufferedWrite<W> {
+    inner: W,
+    buf: Vec<u8>,
+    cap: usize,
+}
+
+impl<W: Write> BufferedWrite<W> {
+    pub fn new(inner: W, cap: usize) -> Self {
+        BufferedWrite {
+            inner,
+            buf: Vec::with_capacity(cap),
+            cap,
+        }
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.buf.is_empty() {
+            let n = self.inner.write(&self.buf)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.buf.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BufferedWrite<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.cap {
+            self.drain()?;
+        }
+        if data.len() >= self.cap {
+            return self.inner.write(data);
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for BufferedWrite<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.flush());
+        self.inner.shutdown()
+    }
+}
 
 /// A future which will copy all data from a reader into a writer.
 /// A modified version of tokio_io::copy::Copy.
@@ -23,6 +216,24 @@ pub struct Copy<R, W> {
     stop_on_reader_zero_read: bool,
     once: bool,
     read_occurred: bool,
+    flush: FlushPolicy,
+    /// Written, but not flushed yet
+    dirty: bool,
+    /// `--flush-interval-ms` ticked while there was nothing to flush or flushing got stuck
+    flush_due: bool,
+    /// Reads waiting to be written, with `--high-watermark`. `pos` is an offset in the first one.
+    queue: VecDeque<Vec<u8>>,
+    /// Message kind of each of `queue`, see `kind`
+    kinds: VecDeque<Option<Mode1>>,
+    /// Bytes in `queue`
+    queued: usize,
+    high_watermark: Option<usize>,
+    /// Reading waits for `queue` to drain below half of the high watermark
+    paused: bool,
+    /// Reading ahead into `buf` as a ring instead of `queue`, see `poll_ring`
+    ring: bool,
+    /// Text or binary, if the reader told for the message in `buf`. Passed on to the writer.
+    kind: Option<Mode1>,
 }
 
 /// Creates a future which represents copying all the bytes from one object to
@@ -38,12 +249,24 @@ pub struct Copy<R, W> {
 /// well.
 ///
 /// Unlike original tokio_io::copy::copy, it does not always stop on zero length reads
-/// , handles BrokenPipe error kind as EOF and flushes after every write
-pub fn copy<R, W>(reader: R, writer: W, stop_on_reader_zero_read: bool, once : bool) -> Copy<R, W>
+/// , handles BrokenPipe error kind as EOF and flushes according to `flush` (after every write by default)
+pub fn copy<R, W>(
+    reader: R,
+    writer: W,
+    stop_on_reader_zero_read: bool,
+    once: bool,
+    flush: FlushPolicy,
+    buffering: BufferSettings,
+) -> Copy<R, W>
 where
     R: AsyncRead,
     W: AsyncWrite,
 {
+    let ring = buffering.raw && buffering.high_watermark.is_some();
+    let size = match buffering.high_watermark {
+        Some(high) if ring => high,
+        _ => buffering.size,
+    };
     Copy {
         reader: Some(reader),
         read_done: false,
@@ -51,11 +274,257 @@ where
         amt: 0,
         pos: 0,
         cap: 0,
-        // TODO - de-hardcode buffer size
-        buf: Box::new([0; 65536]),
+        buf: vec![0; size].into_boxed_slice(),
         stop_on_reader_zero_read,
         once,
         read_occurred: false,
+        flush,
+        dirty: false,
+        flush_due: false,
+        queue: VecDeque::new(),
+        kinds: VecDeque::new(),
+        queued: 0,
+        high_watermark: buffering.high_watermark,
+        paused: false,
+        ring,
+        kind: None,
+    }
+}
+
+impl<R, W> Copy<R, W>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    /// Notice timers of the flush policy
+    fn tick(&mut self) -> io::Result<()> {
+        match self.flush {
+            FlushPolicy::Interval(ref mut i) => while let Async::Ready(Some(())) = i.poll()? {
+                self.flush_due = true;
+            },
+            FlushPolicy::Corked(ref mut c) => if self.dirty && c.expired()? {
+                self.flush_due = true;
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn wrote(&mut self) {
+        self.dirty = true;
+        if let FlushPolicy::Corked(ref mut c) = self.flush {
+            c.start();
+        }
+    }
+
+    fn flushed(&mut self) {
+        self.dirty = false;
+        if let FlushPolicy::Corked(ref mut c) = self.flush {
+            c.timer = None;
+        }
+    }
+
+    /// `poll` with read-ahead: reading goes on while the writer is not ready,
+    /// until more than `high` bytes are queued. Then it waits for the queue to drain
+    /// to `high / 2`. Each read is written separately, keeping message boundaries.
+    fn poll_queued(&mut self, high: usize) -> Poll<(u64, R, W), io::Error> {
+        loop {
+            trace!("poll");
+            self.tick()?;
+            let mut progress = false;
+
+            if self.paused && self.queued <= high / 2 {
+                debug!("Write queue drained to {} bytes, resuming reads", self.queued);
+                self.paused = false;
+            }
+            if !self.read_done && !self.paused {
+                if self.read_occurred && self.once {
+                    self.read_done = true;
+                    continue;
+                }
+                let (rr, kind) = read_message_kind(self.reader.as_mut().unwrap(), &mut self.buf);
+                match rr {
+                    Ok(0) => {
+                        debug!("zero len");
+                        if self.stop_on_reader_zero_read {
+                            debug!("read_done");
+                            self.read_done = true;
+                        }
+                        continue;
+                    }
+                    Ok(n) => {
+                        trace!("read {}", n);
+                        self.read_occurred = true;
+                        let mut v = bufpool::take(n).into_vec();
+                        v.extend_from_slice(&self.buf[..n]);
+                        self.queue.push_back(v);
+                        self.kinds.push_back(kind);
+                        self.queued += n;
+                        metrics::high_water("queued_bytes_peak", self.queued as u64);
+                        if self.queued > high {
+                            debug!("{} bytes queued for a slow writer, pausing reads", self.queued);
+                            self.paused = true;
+                        }
+                        progress = true;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                        debug!("BrokenPipe: read_done");
+                        self.read_done = true;
+                        continue;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            while !self.queue.is_empty() {
+                let (writer, data) = (self.writer.as_mut().unwrap(), &self.queue[0][self.pos..]);
+                let i = match with_message_kind(self.kinds[0], || writer.write(data)) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "write zero byte into writer",
+                        ))
+                    }
+                    Ok(i) => i,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                };
+                trace!("write {}", i);
+                self.pos += i;
+                self.amt += i as u64;
+                self.queued -= i;
+                self.wrote();
+                progress = true;
+                if self.pos == self.queue[0].len() {
+                    self.pos = 0;
+                    if let Some(v) = self.queue.pop_front() {
+                        bufpool::recycle(v);
+                    }
+                    self.kinds.pop_front();
+                }
+            }
+
+            let finished = self.read_done && self.queue.is_empty();
+            if let Some(x) = self.end_round(finished, progress)? {
+                return Ok(x);
+            }
+        }
+    }
+
+    /// `poll` with read-ahead for `--raw-relay`: like `poll_queued`, but `buf`
+    /// is a ring of `high` bytes that reads fill and writes drain, so nothing
+    /// is allocated while relaying. Reads may get merged into one write.
+    fn poll_ring(&mut self) -> Poll<(u64, R, W), io::Error> {
+        let high = self.buf.len();
+        loop {
+            trace!("poll");
+            self.tick()?;
+            let mut progress = false;
+
+            if self.paused && self.queued <= high / 2 {
+                debug!("Write buffer drained to {} bytes, resuming reads", self.queued);
+                self.paused = false;
+            }
+            if !self.read_done && !self.paused {
+                if self.read_occurred && self.once {
+                    self.read_done = true;
+                    continue;
+                }
+                if self.queued == 0 {
+                    self.pos = 0;
+                }
+                // Free space after the data, or before it once the data wraps around
+                let start = (self.pos + self.queued) % high;
+                let end = if start < self.pos { self.pos } else { high };
+                match self.reader.as_mut().unwrap().read(&mut self.buf[start..end]) {
+                    Ok(0) => {
+                        debug!("zero len");
+                        if self.stop_on_reader_zero_read {
+                            debug!("read_done");
+                            self.read_done = true;
+                        }
+                        continue;
+                    }
+                    Ok(n) => {
+                        trace!("read {}", n);
+                        self.read_occurred = true;
+                        self.queued += n;
+                        metrics::high_water("queued_bytes_peak", self.queued as u64);
+                        if self.queued >= high {
+                            debug!("Write buffer full, pausing reads");
+                            self.paused = true;
+                        }
+                        progress = true;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                        debug!("BrokenPipe: read_done");
+                        self.read_done = true;
+                        continue;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            while self.queued > 0 {
+                let end = (self.pos + self.queued).min(high);
+                let i = match self.writer.as_mut().unwrap().write(&self.buf[self.pos..end]) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "write zero byte into writer",
+                        ))
+                    }
+                    Ok(i) => i,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                };
+                trace!("write {}", i);
+                self.pos = (self.pos + i) % high;
+                self.amt += i as u64;
+                self.queued -= i;
+                self.wrote();
+                progress = true;
+            }
+
+            let finished = self.read_done && self.queued == 0;
+            if let Some(x) = self.end_round(finished, progress)? {
+                return Ok(x);
+            }
+        }
+    }
+
+    /// Flushing and completion at the end of a `poll_queued` or `poll_ring` round.
+    /// `None` means going for another round.
+    fn end_round(&mut self, finished: bool, progress: bool) -> io::Result<Option<Async<(u64, R, W)>>> {
+        let flush_now = match self.flush {
+            FlushPolicy::AfterWrite => true,
+            // Nothing more to do until the reader or the writer is ready
+            FlushPolicy::Corked(_) => self.flush_due || finished || !progress,
+            _ => self.flush_due || finished,
+        };
+        if self.dirty && flush_now {
+            match self.writer.as_mut().unwrap().flush() {
+                Ok(()) => self.flushed(),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+        if !self.dirty {
+            self.flush_due = false;
+        }
+
+        if finished && !self.dirty {
+            let reader = self.reader.take().unwrap();
+            let writer = self.writer.take().unwrap();
+            debug!("done");
+            return Ok(Some(Async::Ready((self.amt, reader, writer))));
+        }
+        if !progress {
+            return Ok(Some(Async::NotReady));
+        }
+        Ok(None)
     }
 }
 
@@ -68,23 +537,38 @@ where
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<(u64, R, W), io::Error> {
+        if self.ring {
+            return self.poll_ring();
+        }
+        if let Some(high) = self.high_watermark {
+            return self.poll_queued(high);
+        }
         loop {
             // If our buffer is empty, then we need to read some data to
             // continue.
             trace!("poll");
+            self.tick()?;
+            if self.flush_due && self.dirty {
+                try_nb!(self.writer.as_mut().unwrap().flush());
+                self.flushed();
+            }
+            self.flush_due = false;
             if self.pos == self.cap && !self.read_done {
                 if self.read_occurred && self.once {
                     self.read_done = true;
                     continue;
                 }
-                let reader = self.reader.as_mut().unwrap();
-                let rr = reader.read(&mut self.buf);
+                let (rr, kind) = read_message_kind(self.reader.as_mut().unwrap(), &mut self.buf);
                 if let Err(ref e) = rr {
                     if e.kind() == io::ErrorKind::BrokenPipe {
                         debug!("BrokenPipe: read_done");
                         self.read_done = true;
                         continue;
                     }
+                    if e.kind() == io::ErrorKind::WouldBlock && self.dirty && self.flush.before_waiting() {
+                        try_nb!(self.writer.as_mut().unwrap().flush());
+                        self.flushed();
+                    }
                 }
                 let n = try_nb!(rr);
                 trace!("read {}", n);
@@ -98,6 +582,7 @@ where
                 } else {
                     self.pos = 0;
                     self.cap = n;
+                    self.kind = kind;
                     self.read_occurred = true;
                 }
             }
@@ -105,7 +590,8 @@ where
             // If our buffer has some data, let's write it out!
             while self.pos < self.cap {
                 let writer = self.writer.as_mut().unwrap();
-                let i = try_nb!(writer.write(&self.buf[self.pos..self.cap]));
+                let data = &self.buf[self.pos..self.cap];
+                let i = try_nb!(with_message_kind(self.kind, || writer.write(data)));
                 if i == 0 {
                     return Err(io::Error::new(
                         io::ErrorKind::WriteZero,
@@ -116,7 +602,14 @@ where
                     self.pos += i;
                     self.amt += i as u64;
                 }
-                try_nb!(writer.flush());
+                match self.flush {
+                    FlushPolicy::AfterWrite => try_nb!(writer.flush()),
+                    FlushPolicy::Corked(ref mut c) => {
+                        self.dirty = true;
+                        c.start();
+                    }
+                    _ => self.dirty = true,
+                }
             }
 
             // If we've written al the data and we've seen EOF, flush out the
@@ -0,0 +1,377 @@
+//! Windows named pipes, the local IPC counterpart of `unix:` and `unix-l:` there.
+//!
+//! Pipes use overlapped I/O through `mio-named-pipes`, registered with the reactor like sockets.
+
+extern crate mio_named_pipes;
+extern crate winapi;
+
+use futures;
+use futures::stream::Stream;
+use futures::task::{self, Task};
+use futures::{Async, Poll};
+use std;
+use std::cell::Cell;
+use std::ffi::OsStr;
+use std::io::Result as IoResult;
+use std::io::{Read, Write};
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, IntoRawHandle};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::Rc;
+use tokio_core::reactor::{Handle, PollEvented};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use self::mio_named_pipes::NamedPipe;
+use self::winapi::shared::minwindef::{DWORD, FALSE};
+use self::winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+use self::winapi::shared::winerror::{ERROR_MORE_DATA, ERROR_PIPE_BUSY};
+use self::winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use self::winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+use self::winapi::um::namedpipeapi::SetNamedPipeHandleState;
+use self::winapi::um::winbase::{
+    CreateNamedPipeW, GetNamedPipeClientProcessId, LocalFree, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, PIPE_ACCESS_DUPLEX,
+    PIPE_READMODE_BYTE, PIPE_READMODE_MESSAGE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_TYPE_MESSAGE,
+    PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+use self::winapi::um::winnt::PSECURITY_DESCRIPTOR;
+
+use super::error::io_context;
+use super::{box_up_err, peer_err_s, simple_err, BoxedNewPeerFuture, BoxedNewPeerStream, Peer};
+use super::{multi, once, ConstructParams, LeftSpecToRightSpec, Options, PeerConstructor, Specifier};
+
+/// In- and outbound buffer sizes of created pipes
+const PIPE_BUFFER: DWORD = 65536;
+
+#[derive(Debug, Clone)]
+pub struct NamedPipeConnect(pub PathBuf);
+impl Specifier for NamedPipeConnect {
+    fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        once(pipe_connect_peer(&p.tokio_handle, &self.0, &p.program_options))
+    }
+    specifier_boilerplate!(noglobalstate singleconnect no_subspec typ=Other);
+}
+specifier_class!(
+    name = NamedPipeConnectClass,
+    target = NamedPipeConnect,
+    prefixes = [
+        "pipe:",
+        "pipe-connect:",
+        "connect-pipe:",
+        "pipe-c:",
+        "c-pipe:"
+    ],
+    arg_handling = into,
+    takes_path = true,
+    help = r#"
+[Windows only] Connect to a named pipe. Argument is the pipe name like `\\.\pipe\the_pipe`.
+
+Fails right away if all instances of the pipe are busy.
+With --pipe-message-mode, the pipe is read in message mode
+and each message is delivered separately. A message too long
+for one read ends the connection with an error instead of being split.
+
+Example: forward connections from websockets to a named pipe
+
+    websocat ws-l:127.0.0.1:8088 pipe:\\.\pipe\the_pipe
+"#
+);
+
+#[derive(Debug, Clone)]
+pub struct NamedPipeListen(pub PathBuf);
+impl Specifier for NamedPipeListen {
+    fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        multi(pipe_listen_peer(&p.tokio_handle, &self.0, &p.program_options))
+    }
+    specifier_boilerplate!(noglobalstate multiconnect no_subspec typ=Other);
+}
+specifier_class!(
+    name = NamedPipeListenClass,
+    target = NamedPipeListen,
+    prefixes = ["pipe-listen:", "listen-pipe:", "pipe-l:", "l-pipe:"],
+    arg_handling = into,
+    takes_path = true,
+    help = r#"
+[Windows only] Create a named pipe and accept clients on it. Argument is the pipe name like `\\.\pipe\the_pipe`.
+
+--pipe-instances sets how many clients are served at once (1 by default),
+--pipe-sddl sets the security descriptor of the pipe, like `D:(A;;GA;;;AU)`,
+--pipe-message-mode creates a message-type pipe where each message written
+is one pipe message, like with `seqpacket-l:`. Remote clients are rejected.
+The process ID of the client is logged and passed on like the address of a TCP client.
+
+Named pipes have no half-close: the other side sees EOF only when the connection is closed.
+
+Example: forward connections from a named pipe to a WebSocket
+
+    websocat pipe-l:\\.\pipe\the_pipe ws://127.0.0.1:8089
+"#
+);
+
+/// Listener slot of a connection, freed when both halves are gone
+struct InstanceSlot(Rc<Instances>);
+
+impl Drop for InstanceSlot {
+    fn drop(&mut self) {
+        let x = &self.0;
+        x.live.set(x.live.get() - 1);
+        if let Some(t) = x.waiting.take() {
+            t.notify();
+        }
+    }
+}
+
+struct PipeConn {
+    io: PollEvented<NamedPipe>,
+    _slot: Option<InstanceSlot>,
+}
+
+/// Either half of a pipe connection
+#[derive(Clone)]
+struct PipeHalf(Rc<PipeConn>);
+
+impl Read for PipeHalf {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match (&self.0.io).read(buf) {
+            // Message mode only: the part that was read is gone, so don't pass on the rest as a message
+            Err(ref e) if e.raw_os_error() == Some(ERROR_MORE_DATA as i32) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "pipe message too long to be read at once",
+            )),
+            x => x,
+        }
+    }
+}
+
+impl Write for PipeHalf {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        (&self.0.io).write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for PipeHalf {}
+
+impl AsyncWrite for PipeHalf {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        // Nothing like `shutdown(SHUT_WR)` for pipes, closing the handle is the only way
+        Ok(().into())
+    }
+}
+
+fn pipe_peer(io: PollEvented<NamedPipe>, slot: Option<InstanceSlot>) -> Peer {
+    let h = PipeHalf(Rc::new(PipeConn { io, _slot: slot }));
+    Peer::new(h.clone(), h)
+}
+
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(Some(0)).collect()
+}
+
+fn open_client(path: &Path, message_mode: bool) -> IoResult<NamedPipe> {
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(FILE_FLAG_OVERLAPPED)
+        .open(path)?;
+    let pipe = unsafe { NamedPipe::from_raw_handle(f.into_raw_handle()) };
+    if message_mode {
+        let mut mode: DWORD = PIPE_READMODE_MESSAGE;
+        let ret = unsafe {
+            SetNamedPipeHandleState(pipe.as_raw_handle() as _, &mut mode, ptr::null_mut(), ptr::null_mut())
+        };
+        if ret == FALSE {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(pipe)
+}
+
+pub fn pipe_connect_peer(handle: &Handle, path: &Path, opts: &Options) -> BoxedNewPeerFuture {
+    let context = format!("pipe:{}", path.display());
+    let r = open_client(path, opts.pipe_message_mode)
+        .and_then(|x| PollEvented::new(x, handle))
+        .map(|x| {
+            info!("Connected to a named pipe");
+            pipe_peer(x, None)
+        })
+        .map_err(|e| {
+            if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) {
+                return box_up_err(simple_err(format!("{}: all pipe instances are busy", context)));
+            }
+            box_up_err(io_context(&context, e))
+        });
+    Box::new(futures::future::result(r)) as BoxedNewPeerFuture
+}
+
+/// Security descriptor from `--pipe-sddl`
+struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+impl SecurityDescriptor {
+    fn from_sddl(sddl: &str) -> IoResult<SecurityDescriptor> {
+        let s = wide(OsStr::new(sddl));
+        let mut sd = ptr::null_mut();
+        let ret = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(s.as_ptr(), SDDL_REVISION_1 as DWORD, &mut sd, ptr::null_mut())
+        };
+        if ret == FALSE {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(SecurityDescriptor(sd))
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.0) };
+    }
+}
+
+/// How `pipe-l:` creates instances of its pipe
+struct PipeSettings {
+    name: Vec<u16>,
+    instances: usize,
+    message_mode: bool,
+    sd: Option<SecurityDescriptor>,
+}
+
+impl PipeSettings {
+    fn create_instance(&self, first: bool) -> IoResult<NamedPipe> {
+        let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED;
+        if first {
+            // Don't end up serving a pipe someone else has created
+            open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+        let pipe_mode = if self.message_mode {
+            PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE
+        } else {
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE
+        } | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS;
+        let max = self.instances.min(PIPE_UNLIMITED_INSTANCES as usize) as DWORD;
+        let mut sa = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: ptr::null_mut(),
+            bInheritHandle: FALSE,
+        };
+        let psa = match self.sd {
+            Some(ref sd) => {
+                sa.lpSecurityDescriptor = sd.0;
+                &mut sa as *mut SECURITY_ATTRIBUTES
+            }
+            None => ptr::null_mut(),
+        };
+        let h = unsafe {
+            CreateNamedPipeW(self.name.as_ptr(), open_mode, pipe_mode, max, PIPE_BUFFER, PIPE_BUFFER, 0, psa)
+        };
+        if h == INVALID_HANDLE_VALUE {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(unsafe { NamedPipe::from_raw_handle(h as _) })
+    }
+}
+
+/// Connections of a listener, for `--pipe-instances`
+#[derive(Default)]
+struct Instances {
+    live: Cell<usize>,
+    /// Listener waiting for a connection to go away
+    waiting: Cell<Option<Task>>,
+}
+
+/// Keeps one instance of the pipe waiting for a client, and creates the next one
+/// once a client connects, unless `--pipe-instances` clients are connected already
+struct PipeListener {
+    settings: PipeSettings,
+    handle: Handle,
+    pending: Option<PollEvented<NamedPipe>>,
+    instances: Rc<Instances>,
+    name: PathBuf,
+}
+
+impl Stream for PipeListener {
+    type Item = Peer;
+    type Error = Box<std::error::Error>;
+
+    fn poll(&mut self) -> Poll<Option<Peer>, Box<std::error::Error>> {
+        if self.pending.is_none() {
+            // The instance waiting for a client counts towards the limit as well
+            if self.instances.live.get() >= self.settings.instances {
+                self.instances.waiting.set(Some(task::current()));
+                return Ok(Async::NotReady);
+            }
+            let x = self.settings.create_instance(false)?;
+            self.pending = Some(PollEvented::new(x, &self.handle)?);
+        }
+        let connected = {
+            let pending = self.pending.as_ref().unwrap();
+            match pending.get_ref().connect() {
+                Ok(()) => true,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    // Completion of the connection shows up as writability
+                    pending.need_write();
+                    false
+                }
+                Err(e) => return Err(box_up_err(e)),
+            }
+        };
+        if !connected {
+            return Ok(Async::NotReady);
+        }
+        let io = self.pending.take().unwrap();
+        let client = client_name(&io, &self.name);
+        info!("Incoming named pipe connection from {}", client);
+        self.instances.live.set(self.instances.live.get() + 1);
+        let slot = InstanceSlot(self.instances.clone());
+        let peer = pipe_peer(io, Some(slot)).with_client_info(LeftSpecToRightSpec {
+            client_addr: Some(client),
+            ..Default::default()
+        });
+        Ok(Async::Ready(Some(peer)))
+    }
+}
+
+/// Pipe name and process ID of the client, like `\\.\pipe\the_pipe pid 1234`
+fn client_name(io: &PollEvented<NamedPipe>, name: &Path) -> String {
+    let mut pid = 0;
+    let ret = unsafe { GetNamedPipeClientProcessId(io.get_ref().as_raw_handle() as _, &mut pid) };
+    if ret == FALSE {
+        return name.display().to_string();
+    }
+    format!("{} pid {}", name.display(), pid)
+}
+
+pub fn pipe_listen_peer(handle: &Handle, name: &Path, opts: &Options) -> BoxedNewPeerStream {
+    let context = format!("pipe-l:{}", name.display());
+    let sd = match opts.pipe_sddl {
+        Some(ref x) => match SecurityDescriptor::from_sddl(x) {
+            Ok(sd) => Some(sd),
+            Err(e) => return peer_err_s(io_context(&format!("--pipe-sddl {}", x), e)),
+        },
+        None => None,
+    };
+    let settings = PipeSettings {
+        name: wide(name.as_os_str()),
+        instances: opts.pipe_instances.unwrap_or(1),
+        message_mode: opts.pipe_message_mode,
+        sd,
+    };
+    // The first instance is created right away, so that errors show up when listening starts
+    let first = match settings
+        .create_instance(true)
+        .and_then(|x| PollEvented::new(x, handle))
+    {
+        Ok(x) => x,
+        Err(e) => return peer_err_s(io_context(&context, e)),
+    };
+    Box::new(PipeListener {
+        settings,
+        handle: handle.clone(),
+        pending: Some(first),
+        instances: Rc::new(Default::default()),
+        name: name.to_path_buf(),
+    }) as BoxedNewPeerStream
+}
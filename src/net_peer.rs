@@ -1,7 +1,7 @@
 use futures;
 use futures::future::Future;
 use futures::stream::Stream;
-use futures::unsync::oneshot::{channel, Receiver, Sender};
+use futures::task::{self, Task};
 use std;
 use std::io::Result as IoResult;
 use std::io::{Read, Write};
@@ -9,13 +9,17 @@ use std::net::SocketAddr;
 use tokio_core::reactor::Handle;
 use tokio_io::{AsyncRead, AsyncWrite};
 
-use std::cell::RefCell;
+use std::cell::Cell;
 use std::rc::Rc;
 
 use tokio_core::net::{TcpListener, TcpStream, UdpSocket};
 
+use super::addr::parse_socket_addr;
+use super::bind_retry;
+use super::datagram::WholeDatagrams;
+use super::error::{io_context, WebsocatError};
 use super::{box_up_err, peer_err_s, wouldblock, BoxedNewPeerFuture, BoxedNewPeerStream, Peer};
-use super::{multi, once, ConstructParams, Options, PeerConstructor, Specifier};
+use super::{multi, once, ConstructParams, LeftSpecToRightSpec, Options, PeerConstructor, Specifier};
 
 #[derive(Debug, Clone)]
 pub struct TcpConnect(pub SocketAddr);
@@ -29,9 +33,15 @@ specifier_class!(
     name = TcpConnectClass,
     target = TcpConnect,
     prefixes = ["tcp:", "tcp-connect:", "connect-tcp:", "tcp-c:", "c-tcp:"],
-    arg_handling = parse,
+    arg_handling = {
+        fn construct(self: &TcpConnectClass, _full: &str, just_arg: &str) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(TcpConnect(parse_socket_addr(just_arg)?)))
+        }
+    },
     help = r#"
 Connect to specified TCP host and port. Argument is a socket address.
+IPv6 addresses go in brackets, optionally with a zone (interface name or index),
+like `tcp:[fe80::1%eth0]:22`.
 
 Example: simulate netcat netcat
 
@@ -47,7 +57,17 @@ Example: redirect websocket connections to local SSH server over IPv6
 pub struct TcpListen(pub SocketAddr);
 impl Specifier for TcpListen {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
-        multi(tcp_listen_peer(&p.tokio_handle, &self.0))
+        if p.program_options.workers.is_some() {
+            return multi(tcp_listen_peer_reuseport(
+                &p.tokio_handle,
+                &self.0,
+                &p.program_options,
+            ));
+        }
+        multi(tcp_listen_peer(&p.tokio_handle, &self.0, &p.program_options))
+    }
+    fn is_tcp_listener(&self) -> bool {
+        true
     }
     specifier_boilerplate!(noglobalstate multiconnect no_subspec typ=Other);
 }
@@ -55,7 +75,11 @@ specifier_class!(
     name = TcpListenClass,
     target = TcpListen,
     prefixes = ["tcp-listen:", "listen-tcp:", "tcp-l:", "l-tcp:"],
-    arg_handling = parse,
+    arg_handling = {
+        fn construct(self: &TcpListenClass, _full: &str, just_arg: &str) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(TcpListen(parse_socket_addr(just_arg)?)))
+        }
+    },
     help = r#"
 Listen TCP port on specified address.
     
@@ -85,7 +109,12 @@ specifier_class!(
     name = UdpConnectClass,
     target = UdpConnect,
     prefixes = ["udp:", "udp-connect:", "connect-udp:", "udp-c:", "c-udp:"],
-    arg_handling = parse,
+    arg_handling = {
+        fn construct(self: &UdpConnectClass, _full: &str, just_arg: &str) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(UdpConnect(parse_socket_addr(just_arg)?)))
+        }
+    },
+    consumed_options = &["--udp-oneshot"],
     help = r#"
 Send and receive packets to specified UDP socket, from random UDP port  
 "#
@@ -103,7 +132,12 @@ specifier_class!(
     name = UdpListenClass,
     target = UdpListen,
     prefixes = ["udp-listen:", "listen-udp:", "udp-l:", "l-udp:"],
-    arg_handling = parse,
+    arg_handling = {
+        fn construct(self: &UdpListenClass, _full: &str, just_arg: &str) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(UdpListen(parse_socket_addr(just_arg)?)))
+        }
+    },
+    consumed_options = &["--udp-oneshot"],
     help = r#"
 Bind an UDP socket to specifier host:port, receive packet
 from any remote UDP socket, send replies to recently observed
@@ -181,50 +215,116 @@ impl Drop for MyTcpStream {
     }
 }
 
+#[cfg(unix)]
+fn tcp_peer(x: TcpStream) -> Peer {
+    use std::os::unix::io::AsRawFd;
+    let fd = x.as_raw_fd();
+    let x = Rc::new(x);
+    Peer::with_raw_fd(MyTcpStream(x.clone(), true), MyTcpStream(x, false), fd)
+}
+
+#[cfg(not(unix))]
+fn tcp_peer(x: TcpStream) -> Peer {
+    let x = Rc::new(x);
+    Peer::new(MyTcpStream(x.clone(), true), MyTcpStream(x, false))
+}
+
 pub fn tcp_connect_peer(handle: &Handle, addr: &SocketAddr) -> BoxedNewPeerFuture {
+    let addr2 = addr.to_string();
     Box::new(
         TcpStream::connect(&addr, handle)
             .map(|x| {
                 info!("Connected to TCP");
-                let x = Rc::new(x);
-                Peer::new(MyTcpStream(x.clone(), true), MyTcpStream(x.clone(), false))
+                tcp_peer(x)
             })
-            .map_err(box_up_err),
+            .map_err(move |source| box_up_err(WebsocatError::Connect { addr: addr2, source })),
     ) as BoxedNewPeerFuture
 }
 
-pub fn tcp_listen_peer(handle: &Handle, addr: &SocketAddr) -> BoxedNewPeerStream {
-    let bound = match TcpListener::bind(&addr, handle) {
-        Ok(x) => x,
-        Err(e) => return peer_err_s(e),
-    };
+/// Binding is retried if `--bind-retry` says so
+pub fn tcp_listen_peer(handle: &Handle, addr: &SocketAddr, opts: &Options) -> BoxedNewPeerStream {
+    let (addr, h) = (*addr, handle.clone());
+    bind_retry::listen(
+        handle,
+        format!("tcp-l:{}", addr),
+        opts,
+        move || TcpListener::bind(&addr, &h),
+        tcp_incoming,
+    )
+}
+
+/// Like `tcp_listen_peer`, with `SO_REUSEPORT` so that each of `--workers` can bind the address
+pub fn tcp_listen_peer_reuseport(
+    handle: &Handle,
+    addr: &SocketAddr,
+    opts: &Options,
+) -> BoxedNewPeerStream {
+    let (addr, h) = (*addr, handle.clone());
+    bind_retry::listen(
+        handle,
+        format!("tcp-l:{}", addr),
+        opts,
+        move || super::workers::bind_reuseport(&addr, &h),
+        tcp_incoming,
+    )
+}
+
+fn tcp_incoming(bound: TcpListener) -> BoxedNewPeerStream {
     Box::new(
         bound
             .incoming()
-            .map(|(x, _addr)| {
-                info!("Incoming TCP connection");
-                let x = Rc::new(x);
-                Peer::new(MyTcpStream(x.clone(), true), MyTcpStream(x.clone(), false))
+            .map(|(x, addr)| {
+                info!("Incoming TCP connection from {}", addr);
+                tcp_peer(x).with_client_info(LeftSpecToRightSpec {
+                    client_addr: Some(addr.to_string()),
+                    ..Default::default()
+                })
             })
             .map_err(|e| box_up_err(e)),
     ) as BoxedNewPeerStream
 }
 
-#[derive(Debug)]
-enum UdpPeerState {
-    ConnectMode,
-    WaitingForAddress((Sender<()>, Receiver<()>)),
-    HasAddress(SocketAddr),
+/// Where the listening socket replies to, shared by both halves.
+/// Only `Cell`s: neither half ever holds a borrow of it.
+#[derive(Default)]
+struct UdpReplyTo {
+    /// Last client seen by the reading half
+    addr: Cell<Option<SocketAddr>>,
+    /// Writing half waiting for the first (or, in oneshot mode, the next) client
+    waiting: Cell<Option<Task>>,
+}
+
+/// Reading half of a UDP peer. `reply_to` is `None` for connected sockets.
+struct UdpReadHalf {
+    s: Rc<UdpSocket>,
+    reply_to: Option<Rc<UdpReplyTo>>,
 }
 
-struct UdpPeer {
-    s: UdpSocket,
-    state: Option<UdpPeerState>,
+/// Writing half of a UDP peer
+struct UdpWriteHalf {
+    s: Rc<UdpSocket>,
+    reply_to: Option<Rc<UdpReplyTo>>,
     oneshot_mode: bool,
 }
 
-#[derive(Clone)]
-struct UdpPeerHandle(Rc<RefCell<UdpPeer>>);
+fn udp_peer(s: UdpSocket, listen: bool, opts: &Options) -> Peer {
+    let s = Rc::new(s);
+    let reply_to = if listen {
+        Some(Rc::new(UdpReplyTo::default()))
+    } else {
+        None
+    };
+    let r = UdpReadHalf {
+        s: s.clone(),
+        reply_to: reply_to.clone(),
+    };
+    let w = UdpWriteHalf {
+        s,
+        reply_to,
+        oneshot_mode: opts.udp_oneshot_mode,
+    };
+    Peer::new(r, WholeDatagrams::new(w, opts.allow_partial_datagrams))
+}
 
 fn get_zero_address(addr: &SocketAddr) -> SocketAddr {
     use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
@@ -247,13 +347,7 @@ pub fn udp_connect_peer(
             .and_then(|x| {
                 x.connect(addr)?;
 
-                let h1 = UdpPeerHandle(Rc::new(RefCell::new(UdpPeer {
-                    s: x,
-                    state: Some(UdpPeerState::ConnectMode),
-                    oneshot_mode: opts.udp_oneshot_mode,
-                })));
-                let h2 = h1.clone();
-                Ok(Peer::new(h1, h2))
+                Ok(udp_peer(x, false, &opts))
             })
             .map_err(box_up_err),
     )) as BoxedNewPeerFuture
@@ -267,72 +361,47 @@ pub fn udp_listen_peer(
     Box::new(futures::future::result(
         UdpSocket::bind(addr, handle)
             .and_then(|x| {
-                let h1 = UdpPeerHandle(Rc::new(RefCell::new(UdpPeer {
-                    s: x,
-                    state: Some(UdpPeerState::WaitingForAddress(channel())),
-                    oneshot_mode: opts.udp_oneshot_mode,
-                })));
-                let h2 = h1.clone();
-                Ok(Peer::new(h1, h2))
+                Ok(udp_peer(x, true, &opts))
             })
             .map_err(box_up_err),
     )) as BoxedNewPeerFuture
 }
 
-impl Read for UdpPeerHandle {
+impl Read for UdpReadHalf {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        let mut p = self.0.borrow_mut();
-        match p.state.take().expect("Assertion failed 193912") {
-            UdpPeerState::ConnectMode => {
-                p.state = Some(UdpPeerState::ConnectMode);
-                p.s.recv(buf)
-            }
-            UdpPeerState::HasAddress(oldaddr) => p
-                .s
-                .recv_from(buf)
-                .map(|(ret, addr)| {
-                    warn!("New client for the same listening UDP socket");
-                    p.state = Some(UdpPeerState::HasAddress(addr));
-                    ret
-                })
-                .map_err(|e| {
-                    p.state = Some(UdpPeerState::HasAddress(oldaddr));
-                    e
-                }),
-            UdpPeerState::WaitingForAddress((cmpl, pollster)) => match p.s.recv_from(buf) {
-                Ok((ret, addr)) => {
-                    p.state = Some(UdpPeerState::HasAddress(addr));
-                    let _ = cmpl.send(());
-                    Ok(ret)
+        let reply_to = match self.reply_to {
+            None => return self.s.recv(buf),
+            Some(ref x) => x,
+        };
+        let (ret, addr) = self.s.recv_from(buf)?;
+        match reply_to.addr.replace(Some(addr)) {
+            Some(old) if old != addr => warn!("New client for the same listening UDP socket"),
+            Some(_) => (),
+            None => {
+                if let Some(t) = reply_to.waiting.take() {
+                    t.notify();
                 }
-                Err(e) => {
-                    p.state = Some(UdpPeerState::WaitingForAddress((cmpl, pollster)));
-                    Err(e)
-                }
-            },
+            }
         }
+        Ok(ret)
     }
 }
 
-impl Write for UdpPeerHandle {
+impl Write for UdpWriteHalf {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        let mut p = self.0.borrow_mut();
-        match p.state.take().expect("Assertion failed 193913") {
-            UdpPeerState::ConnectMode => {
-                p.state = Some(UdpPeerState::ConnectMode);
-                p.s.send(buf)
-            }
-            UdpPeerState::HasAddress(a) => {
-                if p.oneshot_mode {
-                    p.state = Some(UdpPeerState::WaitingForAddress(channel()));
-                } else {
-                    p.state = Some(UdpPeerState::HasAddress(a));
+        let reply_to = match self.reply_to {
+            None => return self.s.send(buf),
+            Some(ref x) => x,
+        };
+        match reply_to.addr.get() {
+            Some(a) => {
+                if self.oneshot_mode {
+                    reply_to.addr.set(None);
                 }
-                p.s.send_to(buf, &a)
+                self.s.send_to(buf, &a)
             }
-            UdpPeerState::WaitingForAddress((cmpl, mut pollster)) => {
-                let _ = pollster.poll(); // register wakeup
-                p.state = Some(UdpPeerState::WaitingForAddress((cmpl, pollster)));
+            None => {
+                reply_to.waiting.set(Some(task::current()));
                 wouldblock()
             }
         }
@@ -343,9 +412,9 @@ impl Write for UdpPeerHandle {
     }
 }
 
-impl AsyncRead for UdpPeerHandle {}
+impl AsyncRead for UdpReadHalf {}
 
-impl AsyncWrite for UdpPeerHandle {
+impl AsyncWrite for UdpWriteHalf {
     fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
         Ok(().into())
     }
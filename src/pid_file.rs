@@ -0,0 +1,57 @@
+//! `--pid-file`: written once listeners are bound, removed on exit
+
+#[cfg(all(unix, feature = "libc"))]
+extern crate libc;
+
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(all(unix, feature = "libc"))]
+fn is_alive(pid: i32) -> bool {
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    ::std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(all(unix, feature = "libc")))]
+fn is_alive(_pid: i32) -> bool {
+    // Can't check; assume the file is stale
+    false
+}
+
+/// Write our pid to the file, refusing to overwrite a file
+/// belonging to a process that is still running.
+/// The file gets removed by `super::shutdown::run_exit_hooks`.
+pub fn write_pid_file(path: &Path) -> Result<(), Box<::std::error::Error>> {
+    let mut content = String::new();
+    match fs::File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+        Ok(_) => match content.trim().parse::<i32>() {
+            Ok(pid) if pid > 0 && is_alive(pid) => Err(format!(
+                "Pid file {} exists and process {} is running",
+                path.display(),
+                pid
+            ))?,
+            _ => info!("Overwriting stale pid file {}", path.display()),
+        },
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => Err(format!("Can't read pid file {}: {}", path.display(), e))?,
+    }
+    // Write to a temporary file first, so the pid file never appears half-written
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    {
+        let mut f = fs::File::create(&tmp)?;
+        writeln!(f, "{}", ::std::process::id())?;
+    }
+    fs::rename(&tmp, path)?;
+    let path = path.to_owned();
+    super::shutdown::at_exit(move || {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Failed to remove pid file {}: {}", path.display(), e);
+        }
+    });
+    Ok(())
+}
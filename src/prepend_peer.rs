@@ -0,0 +1,192 @@
+use futures::future::ok;
+use futures::{Async, Future};
+
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use super::util::unescape;
+use super::{box_up_err, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, PeerConstructor, Specifier, WriteDebt};
+
+use std::io::{Error as IoError, ErrorKind, Write};
+use tokio_io::{self, AsyncRead, AsyncWrite};
+
+/// Split `<data>:<subspecifier>` argument
+fn split_arg<'a>(just_arg: &'a str, prefix: &str) -> super::Result<(&'a str, Rc<Specifier>)> {
+    match just_arg.find(':') {
+        Some(i) => Ok((&just_arg[..i], super::spec(&just_arg[i + 1..])?)),
+        None => Err(format!("Expected {}<data>:<subspecifier>", prefix))?,
+    }
+}
+
+#[derive(Debug)]
+pub struct Prepend<T: Specifier>(pub T, pub Vec<u8>);
+impl<T: Specifier> Specifier for Prepend<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let data = self.1.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| prepend_peer(p, data.clone()))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = PrependClass,
+    target = Prepend,
+    prefixes = ["prepend:"],
+    arg_handling = {
+        fn construct(
+            self: &PrependClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            let (data, sub) = split_arg(just_arg, "prepend:")?;
+            Ok(Rc::new(Prepend(sub, unescape(data)?)))
+        }
+    },
+    help = r#"
+Send the specified data to the subspecifier right after it connects, before any other data.
+Argument is the data, then a colon, then subspecifier.
+Escapes `\n`, `\r`, `\t`, `\0`, `\\` and `\xNN` are recognized in the data;
+use `\x3a` for a colon.
+
+Connection is considered established only after the data is written,
+so it always precedes data from the other side.
+
+Example: log in to a line-based service before relaying stdin
+
+    websocat - prepend:'LOGIN user\n':tcp:127.0.0.1:1234
+"#
+);
+
+/// File contents are read when the specifier is parsed, not on each connection
+#[derive(Debug)]
+pub struct PrependFile<T: Specifier>(pub T, pub PathBuf, pub Vec<u8>);
+impl<T: Specifier> Specifier for PrependFile<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let data = self.2.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| prepend_peer(p, data.clone()))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = PrependFileClass,
+    target = PrependFile,
+    prefixes = ["prepend-file:"],
+    arg_handling = {
+        fn construct(
+            self: &PrependFileClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            let (path, sub) = split_arg(just_arg, "prepend-file:")?;
+            let data = match ::std::fs::read(path) {
+                Ok(x) => x,
+                Err(e) => Err(format!("Failed to read prepend-file `{}`: {}", path, e))?,
+            };
+            Ok(Rc::new(PrependFile(sub, PathBuf::from(path), data)))
+        }
+    },
+    takes_path = true,
+    help = r#"
+Like `prepend:`, but the data comes from a file (read once, at startup).
+Argument is a file path (without colons), then a colon, then subspecifier.
+
+Example:
+
+    websocat - prepend-file:handshake.bin:tcp:127.0.0.1:1234
+"#
+);
+
+#[derive(Debug)]
+pub struct Append<T: Specifier>(pub T, pub Vec<u8>);
+impl<T: Specifier> Specifier for Append<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let data = self.1.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| append_peer(p, data.clone()))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = AppendClass,
+    target = Append,
+    prefixes = ["append:"],
+    arg_handling = {
+        fn construct(
+            self: &AppendClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            let (data, sub) = split_arg(just_arg, "append:")?;
+            Ok(Rc::new(Append(sub, unescape(data)?)))
+        }
+    },
+    help = r#"
+Send the specified data to the subspecifier before shutting down its writing direction.
+Argument format and escapes are the same as in `prepend:`.
+
+Example: say goodbye to a line-based service
+
+    websocat - append:'QUIT\n':tcp:127.0.0.1:1234
+"#
+);
+
+pub fn prepend_peer(inner_peer: Peer, data: Vec<u8>) -> BoxedNewPeerFuture {
+    let Peer(r, w, fd, info) = inner_peer;
+    if data.is_empty() {
+        return Box::new(ok(Peer(r, w, fd, info))) as BoxedNewPeerFuture;
+    }
+    let f = tokio_io::io::write_all(w, data)
+        .and_then(|(w, _)| tokio_io::io::flush(w))
+        .map(move |w| Peer(r, w, fd, info))
+        .map_err(box_up_err);
+    Box::new(f) as BoxedNewPeerFuture
+}
+
+pub fn append_peer(inner_peer: Peer, data: Vec<u8>) -> BoxedNewPeerFuture {
+    let Peer(r, w, _, info) = inner_peer;
+    let w = AppendWrite {
+        inner: w,
+        tail: if data.is_empty() { None } else { Some(data) },
+        debt: Default::default(),
+    };
+    Box::new(ok(Peer(r, Box::new(w) as Box<AsyncWrite>, None, info))) as BoxedNewPeerFuture
+}
+
+struct AppendWrite {
+    inner: Box<AsyncWrite>,
+    /// Not yet started to be written
+    tail: Option<Vec<u8>>,
+    debt: WriteDebt,
+}
+
+impl Write for AppendWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for AppendWrite {
+    fn shutdown(&mut self) -> ::futures::Poll<(), IoError> {
+        if let Some(t) = self.tail.take() {
+            self.debt.0 = Some((t, 0));
+        }
+        if self.debt.0.is_some() {
+            match self.debt.write_frame(&mut self.inner, || unreachable!()) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                x => x?,
+            }
+        }
+        match self.inner.flush() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            x => x?,
+        }
+        self.inner.shutdown()
+    }
+}
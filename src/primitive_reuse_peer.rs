@@ -22,6 +22,9 @@ impl Specifier for Reuser {
         let inner = || self.0.construct(p).get_only_first_conn();
         once(connection_reuser(&mut reuser, inner))
     }
+    fn is_shared_between_sessions(&self) -> bool {
+        true
+    }
     specifier_boilerplate!(singleconnect has_subspec typ=Reuser globalstate);
     self_0_is_subspecifier!(...);
 }
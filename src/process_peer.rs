@@ -1,38 +1,59 @@
 extern crate tokio_process;
 
+#[cfg(all(unix, feature = "libc"))]
+extern crate libc;
+
 use futures;
+use futures::future::Future;
+use futures::task::Task;
 use std;
 use std::io::Result as IoResult;
-use std::io::{Read, Write};
-use tokio_core::reactor::Handle;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use std::cell::RefCell;
 use std::rc::Rc;
 
-use std::process::Command;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+
+use self::tokio_process::{Child, ChildStdin, ChildStdout, CommandExt};
 
-use self::tokio_process::{Child, CommandExt};
+use super::{once, ConstructParams, Options, PeerConstructor, Specifier};
+use super::{wouldblock, BoxedNewPeerFuture, Peer};
 
-use super::{once, ConstructParams, PeerConstructor, Specifier};
-use super::{BoxedNewPeerFuture, Peer};
-use std::process::Stdio;
+/// Options of `sh-c:` and `exec:`
+const EXEC_OPTIONS: &[&str] = &[
+    "--exec-args",
+    "--exec-arg",
+    "--exec-env",
+    "--exec-clearenv",
+    "--exec-chdir",
+    "--exec-umask",
+    "--exec-pty",
+    "--pty-size",
+    "--pty-resize-prefix",
+    "--exec-kill-signal",
+    "--exec-kill-timeout",
+    "--exec-no-kill",
+    "--exit-status-from-exec",
+];
 
 #[derive(Debug, Clone)]
 pub struct ShC(pub String);
 impl Specifier for ShC {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
-        let args = if cfg!(target_os = "windows") {
-            let mut args = Command::new("cmd");
-            args.arg("/C").arg(self.0.clone());
-            args
+        let (shell, flag) = if cfg!(target_os = "windows") {
+            ("cmd", "/C")
         } else {
-            let mut args = Command::new("sh");
-            args.arg("-c").arg(self.0.clone());
-            args
+            ("sh", "-c")
         };
-        let h = &p.tokio_handle;
-        once(Box::new(futures::future::result(process_connect_peer(h, args))) as BoxedNewPeerFuture)
+        let mut args = Command::new(shell);
+        args.arg(flag).arg(self.0.clone());
+        let r = process_connect_peer(args, shell, &p);
+        once(Box::new(futures::future::result(r)) as BoxedNewPeerFuture)
     }
     specifier_boilerplate!(noglobalstate singleconnect no_subspec typ=Other);
 }
@@ -41,8 +62,12 @@ specifier_class!(
     target = ShC,
     prefixes = ["sh-c:", "cmd:"], // TODO: change semantics of sh-c:
     arg_handling = into,
+    consumed_options = EXEC_OPTIONS,
     help = r#"
 Start specified command line using `sh -c` or `cmd /C`
+
+Options for `exec:` children (--exec-chdir, --exec-umask, --exec-env, ...)
+apply here too, as well as `WEBSOCAT_*` environment variables.
   
 Example: serve a counter
 
@@ -55,14 +80,25 @@ Example: unauthenticated shell
 "#
 );
 
+/// Program to execute and, for the `exec:[...]` form, its arguments.
+/// Otherwise arguments come from `--exec-arg` and `--exec-args`.
 #[derive(Debug, Clone)]
-pub struct Exec(pub String);
+pub struct Exec(pub String, pub Option<Vec<String>>);
 impl Specifier for Exec {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        let opts = &p.program_options;
         let mut args = Command::new(self.0.clone());
-        args.args(p.program_options.exec_args.clone());
-        let h = &p.tokio_handle;
-        once(Box::new(futures::future::result(process_connect_peer(h, args))) as BoxedNewPeerFuture)
+        match self.1 {
+            Some(ref x) => {
+                args.args(x);
+            }
+            None => {
+                args.args(&opts.exec_arg);
+                args.args(&opts.exec_args);
+            }
+        }
+        let r = process_connect_peer(args, &self.0, &p);
+        once(Box::new(futures::future::result(r)) as BoxedNewPeerFuture)
     }
     specifier_boilerplate!(noglobalstate singleconnect no_subspec typ=Other);
 }
@@ -70,10 +106,59 @@ specifier_class!(
     name = ExecClass,
     target = Exec,
     prefixes = ["exec:"],
-    arg_handling = into,
+    arg_handling = {
+        fn construct(self: &ExecClass, _full: &str, just_arg: &str) -> super::Result<Rc<Specifier>> {
+            if !just_arg.trim().starts_with('[') {
+                return Ok(Rc::new(Exec(just_arg.to_string(), None)));
+            }
+            let mut argv = super::util::parse_argv(just_arg)?;
+            if argv.is_empty() {
+                Err("exec: empty argument vector")?
+            }
+            let program = argv.remove(0);
+            Ok(Rc::new(Exec(program, Some(argv))))
+        }
+    },
+    consumed_options = EXEC_OPTIONS,
     help = r#"
 Execute a program directly (without a subshell), providing array of arguments on Unix
 
+Arguments are given either by --exec-arg (repeatable) and --exec-args options,
+or together with the program as a list of quoted strings: `exec:['prog','arg 1']`.
+Quotes inside arguments can be escaped with backslash.
+
+When the session ends, the child gets its stdin closed and --exec-kill-signal
+(SIGTERM by default), then SIGKILL after --exec-kill-timeout seconds, if set.
+Use --exec-no-kill to let it finish on its own.
+
+A child that exits before the session ends is reaped right away, with its exit
+status logged. Its output ends then, even if programs it left running in
+background still hold it open.
+
+With --exit-status-from-exec, the child is not signaled; websocat waits for it
+and exits with its exit code (128+N if killed by signal N), or 125 if the
+session itself failed.
+
+Options --exec-env NAME=value (repeatable), --exec-clearenv, --exec-chdir <dir>
+and --exec-umask <octal> control environment, working directory and file mode
+creation mask of the child; they apply to `sh-c:` too.
+
+The child also gets environment variables describing the session:
+
+* `WEBSOCAT_SESSION_ID` - increasing number of the spawned child
+* `WEBSOCAT_LISTEN_SPEC` - the first specifier, as given on the command line
+* `WEBSOCAT_CLIENT` - client's IP:port for `tcp-l:`, socket path for `unix-l:`
+  (`@name` for abstract sockets)
+* `WEBSOCAT_URI` - request URI, if the client came through `ws-l:`
+* `WEBSOCAT_HEADER_<NAME>` - request headers named by --env-headers
+  (like `--env-headers X-Forwarded-For,User-Agent`), with non-alphanumeric
+  characters of the name replaced by `_`. No headers are passed by default.
+
+With --exec-pty, the child runs in a pseudo-terminal, which makes interactive
+programs behave like in a terminal emulator. Use --pty-size 120x40 to set
+initial size; with --pty-resize-prefix <prefix>, messages starting with
+the prefix (like `<prefix>120x40`) resize the terminal instead of being typed.
+
 Example: Serve current date
 
   websocat -U ws-l:127.0.0.1:5667 exec:date
@@ -81,48 +166,411 @@ Example: Serve current date
 Example: pinger
 
   websocat -U ws-l:127.0.0.1:5667 exec:ping --exec-args 127.0.0.1 -c 1
+
+Example: arguments with spaces
+
+  websocat -U ws-l:127.0.0.1:5667 exec:"['./prog','--flag','value with spaces']"
   
 "#
 );
 
-fn process_connect_peer(h: &Handle, mut cmd: Command) -> Result<Peer, Box<std::error::Error>> {
+/// Apply `--exec-env`, `--exec-clearenv`, `--exec-chdir`, `--exec-umask`
+/// and set `WEBSOCAT_*` session variables
+fn setup_command(cmd: &mut Command, p: &ConstructParams) -> Result<(), Box<std::error::Error>> {
+    let opts = &p.program_options;
+    if opts.exec_clearenv {
+        cmd.env_clear();
+    }
+    let session_id = CHILDREN.with(|c| {
+        let mut c = c.borrow_mut();
+        c.spawned += 1;
+        c.spawned
+    });
+    cmd.env("WEBSOCAT_SESSION_ID", session_id.to_string());
+    if let Some(ref x) = opts.listen_spec {
+        cmd.env("WEBSOCAT_LISTEN_SPEC", x);
+    }
+    {
+        let info = p.left_to_right.info().borrow();
+        if let Some(ref x) = info.client_addr {
+            cmd.env("WEBSOCAT_CLIENT", x);
+        }
+        if let Some(ref x) = info.uri {
+            cmd.env("WEBSOCAT_URI", x);
+        }
+        for &(ref k, ref v) in &info.headers {
+            cmd.env(format!("WEBSOCAT_HEADER_{}", env_var_name(k)), v);
+        }
+    }
+    for &(ref k, ref v) in &opts.exec_env {
+        cmd.env(k, v);
+    }
+    if let Some(ref d) = opts.exec_chdir {
+        if !Path::new(d).is_dir() {
+            Err(format!("Cannot change directory to `{}`: not a directory", d))?
+        }
+        cmd.current_dir(d);
+    }
+    if let Some(mask) = opts.exec_umask {
+        set_umask(cmd, mask)?;
+    }
+    Ok(())
+}
+
+/// `X-Forwarded-For` -> `X_FORWARDED_FOR`
+fn env_var_name(header: &str) -> String {
+    header
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+#[cfg(all(unix, feature = "libc"))]
+fn set_umask(cmd: &mut Command, mask: u32) -> Result<(), Box<std::error::Error>> {
+    use std::os::unix::process::CommandExt as StdCommandExt;
+    #[allow(unused_unsafe, deprecated)]
+    unsafe {
+        cmd.before_exec(move || {
+            libc::umask(mask as libc::mode_t);
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(all(unix, feature = "libc")))]
+fn set_umask(_cmd: &mut Command, _mask: u32) -> Result<(), Box<std::error::Error>> {
+    Err("--exec-umask is not supported on this platform or build")?
+}
+
+#[cfg(all(unix, feature = "pty"))]
+use super::pty_peer::pty_process_peer;
+
+#[cfg(not(all(unix, feature = "pty")))]
+fn pty_process_peer(
+    _h: &Handle,
+    _cmd: Command,
+    _program: &str,
+    _opts: &Options,
+) -> Result<Peer, Box<std::error::Error>> {
+    Err("--exec-pty requires websocat to be built with `pty` feature")?
+}
+
+fn process_connect_peer(
+    mut cmd: Command,
+    program: &str,
+    p: &ConstructParams,
+) -> Result<Peer, Box<std::error::Error>> {
+    let h = &p.tokio_handle;
+    let opts = &p.program_options;
+    setup_command(&mut cmd, p)?;
+    if opts.exec_pty {
+        return pty_process_peer(h, cmd, program, opts);
+    }
     cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
-    let child = cmd.spawn_async(h)?;
+    let child = match cmd.spawn_async(h) {
+        Ok(x) => x,
+        Err(e) => Err(format!("Failed to execute `{}`: {}", program, e))?,
+    };
+    let child = ChildHandle::new(child, h, opts)?;
     let ph = ProcessPeer(Rc::new(RefCell::new(child)));
     Ok(Peer::new(ph.clone(), ph))
 }
 
+#[derive(Default)]
+struct Children {
+    /// Also serves as `WEBSOCAT_SESSION_ID`
+    spawned: u64,
+    running: usize,
+    last_exit_code: Option<i32>,
+    waiter: Option<Task>,
+}
+
+impl Children {
+    fn reaped(&mut self, code: Option<i32>) {
+        self.running -= 1;
+        if code.is_some() {
+            self.last_exit_code = code;
+        }
+        if let Some(t) = self.waiter.take() {
+            t.notify();
+        }
+    }
+}
+
+thread_local! {
+    static CHILDREN: RefCell<Children> = RefCell::new(Default::default());
+}
+
+/// Exit code as a shell would report it: 128+N for death by signal N
+fn exit_code(st: ExitStatus) -> i32 {
+    if let Some(x) = st.code() {
+        return x;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(x) = st.signal() {
+            return 128 + x;
+        }
+    }
+    1
+}
+
+/// Resolves when all children spawned by `exec:` and `sh-c:` have exited,
+/// with the exit code of the last one to exit (if there were any)
+pub fn wait_for_children() -> Box<Future<Item = Option<i32>, Error = ()>> {
+    Box::new(futures::future::poll_fn(|| {
+        CHILDREN.with(|c| {
+            let mut c = c.borrow_mut();
+            if c.running == 0 {
+                return Ok(futures::Async::Ready(c.last_exit_code));
+            }
+            c.waiter = Some(futures::task::current());
+            Ok(futures::Async::NotReady)
+        })
+    }))
+}
+
+/// How long output of an exited child is still waited for, in case the pipe
+/// is held open by something it left running
+const OUTPUT_LINGER_MS: u64 = 100;
+
+/// What the watcher of a child knows about it
+#[derive(Default)]
+struct ChildState {
+    exited: bool,
+    /// Sent `--exec-kill-signal`, so a failure exit status is expected
+    signalled: bool,
+    /// Started when the child exits, see `OUTPUT_LINGER_MS`
+    linger: Option<Timeout>,
+    /// Waiting for the child's output
+    reader: Option<Task>,
+}
+
+/// Owner of a spawned child. The child is reaped as soon as it exits, with the
+/// exit status logged, even if the session goes on. When dropped, closes child's
+/// stdio, sends it `--exec-kill-signal` (unless `--exec-no-kill` or it has already
+/// exited) and escalates to SIGKILL after `--exec-kill-timeout`.
+pub struct ChildHandle {
+    child: Rc<RefCell<Child>>,
+    stdin: Option<ChildStdin>,
+    stdout: Option<ChildStdout>,
+    state: Rc<RefCell<ChildState>>,
+    pid: u32,
+    handle: Handle,
+    kill: bool,
+    signal: i32,
+    timeout: Option<Duration>,
+}
+
+impl ChildHandle {
+    pub fn new(
+        mut child: Child,
+        h: &Handle,
+        opts: &Options,
+    ) -> Result<ChildHandle, Box<std::error::Error>> {
+        let signal = match parse_signal(&opts.exec_kill_signal) {
+            Some(x) => x,
+            None => Err(format!("Unknown signal `{}`", opts.exec_kill_signal))?,
+        };
+        // We kill it ourselves, in a more gentle way
+        child.forget();
+        let pid = child.id();
+        let stdin = child.stdin().take();
+        let stdout = child.stdout().take();
+        let child = Rc::new(RefCell::new(child));
+        let state: Rc<RefCell<ChildState>> = Default::default();
+        CHILDREN.with(|c| c.borrow_mut().running += 1);
+        h.spawn(watch(child.clone(), pid, state.clone(), h.clone()));
+        Ok(ChildHandle {
+            child,
+            stdin,
+            stdout,
+            state,
+            pid,
+            handle: h.clone(),
+            // Exit status is only meaningful if the child finishes on its own
+            kill: !opts.exec_no_kill && !opts.exit_status_from_exec,
+            signal,
+            timeout: opts.exec_kill_timeout.map(Duration::from_secs),
+        })
+    }
+
+    fn stdin(&mut self) -> &mut ChildStdin {
+        self.stdin.as_mut().expect("assertion failed 1425")
+    }
+
+    fn stdout(&mut self) -> &mut ChildStdout {
+        self.stdout.as_mut().expect("assertion failed 1425")
+    }
+
+    /// Output would block. Once the child has exited, it ends instead
+    /// (after `OUTPUT_LINGER_MS`), so that the session sees EOF.
+    fn output_pending(&mut self) -> IoResult<usize> {
+        let mut st = self.state.borrow_mut();
+        if !st.exited {
+            st.reader = Some(futures::task::current());
+            return wouldblock();
+        }
+        if let Some(ref mut t) = st.linger {
+            if let futures::Async::NotReady = t.poll()? {
+                return wouldblock();
+            }
+        }
+        debug!("Child process {} has exited, ending its output", self.pid);
+        Ok(0)
+    }
+}
+
+/// Reaps the child when it exits, logs and records its exit status
+fn watch(
+    child: Rc<RefCell<Child>>,
+    pid: u32,
+    state: Rc<RefCell<ChildState>>,
+    h: Handle,
+) -> Box<Future<Item = (), Error = ()>> {
+    Box::new(futures::future::poll_fn(move || child.borrow_mut().poll()).then(move |r| {
+        let mut st = state.borrow_mut();
+        let code = match r {
+            Ok(s) if s.success() || st.signalled => {
+                info!("Child process {} exited with {}", pid, s);
+                Some(exit_code(s))
+            }
+            Ok(s) => {
+                warn!("Child process {} exited with {}", pid, s);
+                Some(exit_code(s))
+            }
+            Err(e) => {
+                warn!("Failed to wait for child process {}: {}", pid, e);
+                None
+            }
+        };
+        CHILDREN.with(|c| c.borrow_mut().reaped(code));
+        st.exited = true;
+        st.linger = Timeout::new(Duration::from_millis(OUTPUT_LINGER_MS), &h).ok();
+        if let Some(t) = st.reader.take() {
+            t.notify();
+        }
+        Ok::<(), ()>(())
+    }))
+}
+
+/// Signal name (like `TERM`, `SIGINT`) or positive number. Empty string means SIGTERM.
+#[cfg(all(unix, feature = "libc"))]
+pub fn parse_signal(s: &str) -> Option<i32> {
+    if let Ok(x) = s.parse::<i32>() {
+        // 0 only checks that the process exists, negative numbers are not signals
+        return if x > 0 { Some(x) } else { None };
+    }
+    let s = if s.starts_with("SIG") { &s[3..] } else { s };
+    Some(match s {
+        "" | "TERM" => libc::SIGTERM,
+        "INT" => libc::SIGINT,
+        "HUP" => libc::SIGHUP,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        _ => return None,
+    })
+}
+
+/// Without signals, children are always terminated forcibly
+#[cfg(not(all(unix, feature = "libc")))]
+pub fn parse_signal(_s: &str) -> Option<i32> {
+    Some(0)
+}
+
+#[cfg(all(unix, feature = "libc"))]
+fn send_signal(child: &mut Child, sig: i32) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, sig);
+    }
+}
+
+#[cfg(not(all(unix, feature = "libc")))]
+fn send_signal(child: &mut Child, _sig: i32) {
+    let _ = child.kill();
+}
+
+#[cfg(all(unix, feature = "libc"))]
+fn kill_pid(pid: u32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+/// Not reached: the child is already killed by `send_signal`
+#[cfg(not(all(unix, feature = "libc")))]
+fn kill_pid(_pid: u32) {}
+
+impl Drop for ChildHandle {
+    fn drop(&mut self) {
+        // Let the child see EOF
+        self.stdin.take();
+        self.stdout.take();
+        let pid = self.pid;
+        if self.state.borrow().exited {
+            return;
+        }
+        if !self.kill {
+            info!("Leaving child process {} running", pid);
+            return;
+        }
+        debug!("Sending signal {} to child process {}", self.signal, pid);
+        self.state.borrow_mut().signalled = true;
+        send_signal(&mut self.child.borrow_mut(), self.signal);
+        let timer = match self.timeout {
+            Some(t) => Timeout::new(t, &self.handle).ok(),
+            None => None,
+        };
+        if let Some(t) = timer {
+            let state = self.state.clone();
+            self.handle.spawn(t.then(move |_| {
+                // Not after it is reaped: the pid may belong to someone else by then
+                if !state.borrow().exited {
+                    warn!("Child process {} is still running, killing it", pid);
+                    kill_pid(pid);
+                }
+                Ok::<(), ()>(())
+            }));
+        }
+    }
+}
+
 #[derive(Clone)]
-struct ProcessPeer(Rc<RefCell<Child>>);
+struct ProcessPeer(Rc<RefCell<ChildHandle>>);
 
 impl Read for ProcessPeer {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        self.0
-            .borrow_mut()
-            .stdout()
-            .as_mut()
-            .expect("assertion failed 1425")
-            .read(buf)
+        let mut c = self.0.borrow_mut();
+        match c.stdout().read(buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => c.output_pending(),
+            x => x,
+        }
     }
 }
 
 impl Write for ProcessPeer {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        self.0
-            .borrow_mut()
-            .stdin()
-            .as_mut()
-            .expect("assertion failed 1425")
-            .write(buf)
+        let r = self.0.borrow_mut().stdin().write(buf);
+        match r {
+            // The child has exited or closed its stdin. Its output still gets
+            // delivered, and the session ends normally when it is over.
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {
+                debug!("Child process does not accept input, discarding it");
+                Ok(buf.len())
+            }
+            x => x,
+        }
     }
 
     fn flush(&mut self) -> IoResult<()> {
-        self.0
-            .borrow_mut()
-            .stdin()
-            .as_mut()
-            .expect("assertion failed 1425")
-            .flush()
+        match self.0.borrow_mut().stdin().flush() {
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+            x => x,
+        }
     }
 }
 
@@ -130,11 +578,6 @@ impl AsyncRead for ProcessPeer {}
 
 impl AsyncWrite for ProcessPeer {
     fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
-        self.0
-            .borrow_mut()
-            .stdin()
-            .as_mut()
-            .expect("assertion failed 1425")
-            .shutdown()
+        self.0.borrow_mut().stdin().shutdown()
     }
 }
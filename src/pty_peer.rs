@@ -0,0 +1,182 @@
+//! Pseudo-terminal for `exec:` and `sh-c:` children (`--exec-pty`)
+
+extern crate libc;
+extern crate tokio_file_unix;
+extern crate tokio_process;
+
+use futures;
+use std;
+use std::fs::File as FsFile;
+use std::io::Result as IoResult;
+use std::io::{Error as IoError, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt as StdCommandExt;
+use std::process::{Command, Stdio};
+use tokio_core::reactor::{Handle, PollEvented};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use self::tokio_file_unix::File as UnixFile;
+use self::tokio_process::CommandExt;
+
+use super::process_peer::ChildHandle;
+use super::util::parse_winsize;
+use super::{Options, Peer};
+
+struct Pty {
+    master: PollEvented<UnixFile<FsFile>>,
+    master_fd: i32,
+    /// Terminated when the peer is dropped
+    _child: ChildHandle,
+}
+
+fn set_size(fd: i32, cols: u16, rows: u16) -> IoResult<()> {
+    let ws = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ as _, &ws) } == -1 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+/// Spawn prepared command with a new pseudo-terminal as its stdin, stdout,
+/// stderr and controlling terminal, and return a peer for the master side
+pub fn pty_process_peer(
+    h: &Handle,
+    mut cmd: Command,
+    program: &str,
+    opts: &Options,
+) -> Result<Peer, Box<std::error::Error>> {
+    let (cols, rows) = match opts.pty_size {
+        Some(ref x) => match parse_winsize(x) {
+            Some(s) => s,
+            None => Err("--pty-size must look like 80x24")?,
+        },
+        None => (80, 24),
+    };
+    let mut master = -1;
+    let mut slave = -1;
+    let (m, slave) = unsafe {
+        let ret = libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+        if ret == -1 {
+            Err(format!("openpty failed: {}", IoError::last_os_error()))?
+        }
+        libc::fcntl(master, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(slave, libc::F_SETFD, libc::FD_CLOEXEC);
+        // Owned from here on, so they get closed on any error below
+        let m: FsFile = FromRawFd::from_raw_fd(master);
+        let slave: FsFile = FromRawFd::from_raw_fd(slave);
+        (m, slave)
+    };
+    let dup = |f: &FsFile| f.try_clone().map_err(|e| format!("dup failed: {}", e));
+    let slave2 = dup(&slave)?;
+    let slave3 = dup(&slave)?;
+    // Command owns the slave descriptors, so they get closed in this process after spawning
+    cmd.stdin(Stdio::from(slave))
+        .stdout(Stdio::from(slave2))
+        .stderr(Stdio::from(slave3));
+    set_size(master, cols, rows)?;
+    #[allow(unused_unsafe, deprecated)]
+    unsafe {
+        // Runs in the child after stdio is set up
+        cmd.before_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(IoError::last_os_error());
+            }
+            if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(IoError::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = match cmd.spawn_async(h) {
+        Ok(x) => x,
+        Err(e) => Err(format!("Failed to execute `{}`: {}", program, e))?,
+    };
+    ::std::mem::drop(cmd);
+    let child = ChildHandle::new(child, h, opts)?;
+    let m = UnixFile::new_nb(m)?.into_io(h)?;
+    let s = Rc::new(RefCell::new(Pty {
+        master: m,
+        master_fd: master,
+        _child: child,
+    }));
+    let w = PtyWrite {
+        s: s.clone(),
+        resize_prefix: opts.pty_resize_prefix.clone(),
+    };
+    Ok(Peer::new(PtyRead(s), w))
+}
+
+struct PtyRead(Rc<RefCell<Pty>>);
+
+impl Read for PtyRead {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self.0.borrow_mut().master.read(buf) {
+            // Linux reports EIO instead of EOF after the child closes the terminal
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            x => x,
+        }
+    }
+}
+impl AsyncRead for PtyRead {}
+
+struct PtyWrite {
+    s: Rc<RefCell<Pty>>,
+    resize_prefix: Option<String>,
+}
+
+impl PtyWrite {
+    /// Handle the message as a resize command if it is one
+    fn try_resize(&mut self, buf: &[u8]) -> bool {
+        let prefix = match self.resize_prefix {
+            Some(ref x) => x.as_bytes(),
+            None => return false,
+        };
+        if !buf.starts_with(prefix) {
+            return false;
+        }
+        let arg = String::from_utf8_lossy(&buf[prefix.len()..]);
+        match parse_winsize(arg.trim()) {
+            Some((cols, rows)) => {
+                let fd = self.s.borrow().master_fd;
+                if let Err(e) = set_size(fd, cols, rows) {
+                    warn!("Failed to resize the terminal: {}", e);
+                }
+            }
+            None => warn!("Invalid terminal resize request: {}", arg),
+        }
+        true
+    }
+}
+
+impl Write for PtyWrite {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.try_resize(buf) {
+            return Ok(buf.len());
+        }
+        self.s.borrow_mut().master.write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.s.borrow_mut().master.flush()
+    }
+}
+
+impl AsyncWrite for PtyWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        // A terminal can't be half-closed. The child gets a signal when the peer is dropped.
+        Ok(futures::Async::Ready(()))
+    }
+}
@@ -0,0 +1,229 @@
+//! `--readline`: line editing and history for interactive use of stdio.
+//! The editor runs in its own thread; incoming data is printed above the prompt.
+
+extern crate libc;
+extern crate linefeed;
+
+use futures::stream::Stream;
+use futures::sync::mpsc;
+use futures::{Async, Future, Sink};
+
+use std::io::{Error as IoError, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use self::linefeed::{DefaultTerminal, Interface, ReadResult, Signal};
+
+use super::{wouldblock, BoxedNewPeerFuture, Options, Peer};
+
+const PROMPT: &str = "> ";
+
+/// How often the editor thread checks whether it should quit
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// How long to wait on exit for the editor thread to restore the terminal
+const EXIT_WAIT_MS: u64 = 500;
+
+enum Input {
+    Line(Vec<u8>),
+    /// Second Ctrl-C
+    Interrupt,
+}
+
+/// Whether `--readline` should take over stdio: only if stdin is a terminal
+pub fn wanted(opts: &Options) -> bool {
+    opts.readline && unsafe { libc::isatty(0) } == 1
+}
+
+fn history_path() -> Option<PathBuf> {
+    let home = ::std::env::var_os("HOME").or_else(|| ::std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".websocat_history"))
+}
+
+fn editor_thread(
+    iface: Arc<Interface<DefaultTerminal>>,
+    mut tx: mpsc::Sender<Input>,
+    quit: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+) {
+    let history = history_path();
+    let mut interrupted = false;
+    loop {
+        if quit.load(Ordering::SeqCst) {
+            let _ = iface.cancel_read_line();
+            break;
+        }
+        let r = match iface.read_line_step(Some(Duration::from_millis(POLL_INTERVAL_MS))) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("readline: {}", e);
+                break;
+            }
+        };
+        let input = match r {
+            ReadResult::Input(line) => {
+                interrupted = false;
+                iface.add_history_unique(line.clone());
+                if let Some(ref p) = history {
+                    if let Err(e) = iface.save_history(p) {
+                        debug!("Failed to save history: {}", e);
+                    }
+                }
+                let mut v = line.into_bytes();
+                v.push(b'\n');
+                Input::Line(v)
+            }
+            ReadResult::Eof => break,
+            ReadResult::Signal(Signal::Interrupt) if !interrupted => {
+                interrupted = true;
+                let _ = writeln!(iface, "Press Ctrl-C again to close the connection, Ctrl-D to send EOF");
+                continue;
+            }
+            ReadResult::Signal(_) => Input::Interrupt,
+        };
+        tx = match tx.send(input).wait() {
+            Ok(x) => x,
+            Err(_) => break,
+        };
+    }
+    done.store(true, Ordering::SeqCst);
+}
+
+pub fn get_readline_peer() -> Option<BoxedNewPeerFuture> {
+    let iface = match Interface::new("websocat") {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            warn!("Can't set up line editing, using plain stdio: {}", e);
+            return None;
+        }
+    };
+    iface.set_prompt(PROMPT);
+    iface.set_report_signal(Signal::Interrupt, true);
+    if let Some(p) = history_path() {
+        if let Err(e) = iface.load_history(&p) {
+            if e.kind() != ::std::io::ErrorKind::NotFound {
+                warn!("Failed to load history from {:?}: {}", p, e);
+            }
+        }
+    }
+    let (tx, rx) = mpsc::channel(0);
+    let quit = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let (iface2, quit2, done2) = (iface.clone(), quit.clone(), done.clone());
+    let spawned = thread::Builder::new()
+        .name("readline".to_string())
+        .spawn(move || editor_thread(iface2, tx, quit2, done2));
+    if let Err(e) = spawned {
+        warn!("Can't start line editing thread, using plain stdio: {}", e);
+        return None;
+    }
+    // The terminal must not be left in raw mode
+    super::shutdown::at_exit(move || {
+        quit.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_millis(EXIT_WAIT_MS);
+        while !done.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+    let r = ReadlineRead { rx, debt: vec![] };
+    let w = ReadlineWrite {
+        iface,
+        pending: Default::default(),
+    };
+    Some(Box::new(::futures::future::ok(Peer::new(r, w))) as BoxedNewPeerFuture)
+}
+
+struct ReadlineRead {
+    rx: mpsc::Receiver<Input>,
+    /// Rest of a line that did not fit into the buffer
+    debt: Vec<u8>,
+}
+
+impl Read for ReadlineRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.debt.is_empty() {
+            match self.rx.poll() {
+                Ok(Async::Ready(Some(Input::Line(x)))) => self.debt = x,
+                Ok(Async::Ready(Some(Input::Interrupt))) => {
+                    super::shutdown::initiate_and_close_sessions("got SIGINT", 1000);
+                    return Ok(0);
+                }
+                Ok(Async::Ready(None)) | Err(()) => return Ok(0),
+                Ok(Async::NotReady) => return wouldblock(),
+            }
+        }
+        let n = buf.len().min(self.debt.len());
+        buf[..n].copy_from_slice(&self.debt[..n]);
+        self.debt.drain(..n);
+        Ok(n)
+    }
+}
+impl AsyncRead for ReadlineRead {}
+
+/// Output waiting to be printed above the prompt, which takes whole lines.
+/// Lines are printed as they complete; what is left at the end of a message
+/// (when the writer is flushed) gets a line of its own.
+#[derive(Default)]
+pub struct PendingOutput {
+    partial: Vec<u8>,
+}
+
+impl PendingOutput {
+    /// Complete lines to print now, if any
+    pub fn push(&mut self, buf: &[u8]) -> Option<Vec<u8>> {
+        self.partial.extend_from_slice(buf);
+        let i = self.partial.iter().rposition(|&x| x == b'\n')?;
+        let rest = self.partial.split_off(i + 1);
+        Some(::std::mem::replace(&mut self.partial, rest))
+    }
+
+    /// Incomplete line at the end of a message, with a newline added
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.partial.is_empty() {
+            return None;
+        }
+        let mut rest = ::std::mem::replace(&mut self.partial, vec![]);
+        rest.push(b'\n');
+        Some(rest)
+    }
+}
+
+/// Prints incoming data above the prompt
+struct ReadlineWrite {
+    iface: Arc<Interface<DefaultTerminal>>,
+    pending: PendingOutput,
+}
+
+impl ReadlineWrite {
+    fn print(&self, data: &[u8]) -> Result<(), IoError> {
+        write!(self.iface, "{}", String::from_utf8_lossy(data))
+    }
+}
+
+impl Write for ReadlineWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if let Some(x) = self.pending.push(buf) {
+            self.print(&x)?;
+        }
+        Ok(buf.len())
+    }
+    /// Called after each message, unless --no-flush or --flush-interval-ms say otherwise
+    fn flush(&mut self) -> Result<(), IoError> {
+        if let Some(x) = self.pending.finish() {
+            self.print(&x)?;
+        }
+        Ok(())
+    }
+}
+impl AsyncWrite for ReadlineWrite {
+    fn shutdown(&mut self) -> ::futures::Poll<(), IoError> {
+        self.flush()?;
+        Ok(Async::Ready(()))
+    }
+}
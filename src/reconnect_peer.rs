@@ -3,7 +3,11 @@ extern crate tokio_io;
 
 use futures::future::ok;
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Rc;
+use std::time::Duration;
+
+use tokio_core::reactor::Timeout;
 
 use super::{BoxedNewPeerFuture, Peer};
 
@@ -16,6 +20,9 @@ use futures::{Async, Future, Poll};
 // TODO: shutdown write part if out writing part is shut down
 // TODO: stop if writing part and reading parts are closed (shutdown)?
 
+/// Notification that `autoreconnect:` dropped its connection and is establishing a new one
+pub type ReconnectHook = Rc<Fn()>;
+
 #[derive(Debug)]
 pub struct AutoReconnect(pub Rc<Specifier>);
 impl Specifier for AutoReconnect {
@@ -57,6 +64,14 @@ Example: keep connecting to the port or spin 100% CPU trying if it is closed.
 Example: keep remote logging connection open (or flood the host if port is closed):
 
     websocat -u ws-l:0.0.0.0:8080 reuse:autoreconnect:tcp:192.168.0.3:1025
+
+With --preconnect <n>, that many spare connections are established in advance
+(including TLS and WebSocket handshakes) while the current one is in use, and
+one of them takes over when it fails. Nothing is ever sent to a spare; data it
+receives is kept and delivered once it takes over. A spare that gets closed or
+fails is replaced, and so is one older than --preconnect-max-age seconds
+(30 by default), as a connection dropped silently by the other side or by a NAT
+would not be noticed otherwise. Spares are checked again before taking over.
   
 TODO: implement delays between reconnect attempts
 "#
@@ -67,12 +82,104 @@ struct State2 {
     already_warned: bool,
 }
 
+/// Read from a spare at most this much at once
+const SPARE_READ_CHUNK: usize = 4096;
+/// Stop reading from a spare that has received this much
+const SPARE_BUFFER_MAX: usize = 65536;
+
+/// Connection established in advance for `--preconnect`
+enum Spare {
+    Connecting(BoxedNewPeerFuture),
+    Ready(WarmSpare),
+}
+
+struct WarmSpare {
+    peer: Peer,
+    /// What the peer sent before being promoted, delivered first afterwards
+    received: VecDeque<Vec<u8>>,
+    buffered: usize,
+    /// Fires after `--preconnect-max-age`
+    expiry: Option<Timeout>,
+}
+
+impl WarmSpare {
+    /// Read whatever arrived, which also notices the connection being closed,
+    /// and check the age. Returns whether the spare is still usable.
+    fn check(&mut self) -> bool {
+        if let Some(ref mut t) = self.expiry {
+            match t.poll() {
+                Ok(Async::NotReady) => (),
+                _ => {
+                    info!("Spare connection reached --preconnect-max-age");
+                    super::metrics::add("preconnect_spare_expirations", 1);
+                    return false;
+                }
+            }
+        }
+        let mut buf = [0; SPARE_READ_CHUNK];
+        while self.buffered < SPARE_BUFFER_MAX {
+            match self.peer.0.read(&mut buf) {
+                Ok(0) => {
+                    info!("Spare connection closed");
+                    super::metrics::add("preconnect_spare_failures", 1);
+                    return false;
+                }
+                Ok(n) => {
+                    self.received.push_back(buf[..n].to_vec());
+                    self.buffered += n;
+                }
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => return true,
+                Err(e) => {
+                    info!("Spare connection failed: {}", e);
+                    super::metrics::add("preconnect_spare_failures", 1);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Reader of a promoted spare: first what it received earlier
+struct Prefixed {
+    received: VecDeque<Vec<u8>>,
+    inner: Box<AsyncRead>,
+}
+
+impl Read for Prefixed {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        let mut chunk = match self.received.pop_front() {
+            Some(x) => x,
+            None => return self.inner.read(b),
+        };
+        let n = chunk.len().min(b.len());
+        b[..n].copy_from_slice(&chunk[..n]);
+        if n < chunk.len() {
+            chunk.drain(..n);
+            self.received.push_front(chunk);
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for Prefixed {}
+
+enum SpareCheck {
+    Keep,
+    Connected(Peer),
+    Failed,
+}
+
 struct State {
     s: Rc<Specifier>,
     p: Option<Peer>,
     n: Option<BoxedNewPeerFuture>,
     cp: ConstructParams,
     aux: State2,
+    /// `--preconnect`
+    want_spares: usize,
+    /// `--preconnect-max-age`
+    max_age: Option<Duration>,
+    spares: Vec<Spare>,
 }
 
 /// This implementation's poll is to be reused many times, both after returning item and error
@@ -81,6 +188,8 @@ impl State {
     //type Error = Box<::std::error::Error>;
 
     fn poll(&mut self) -> Poll<&mut Peer, Box<::std::error::Error>> {
+        self.poll_spares();
+
         let pp = &mut self.p;
         let nn = &mut self.n;
 
@@ -92,7 +201,30 @@ impl State {
                 return Ok(Async::Ready(p));
             }
 
-            // Peer is not present: trying to create a new one
+            // Peer is not present: take over a spare if there is one
+            let warm = self.spares.iter().position(|x| match *x {
+                Spare::Ready(_) => true,
+                Spare::Connecting(_) => false,
+            });
+            if let Some(i) = warm {
+                if let Spare::Ready(mut w) = self.spares.remove(i) {
+                    if !w.check() {
+                        continue;
+                    }
+                    info!("Using a spare connection");
+                    super::metrics::add("preconnect_promotions", 1);
+                    let Peer(r, wr, fd, info) = w.peer;
+                    let r = Box::new(Prefixed {
+                        received: w.received,
+                        inner: r,
+                    });
+                    *pp = Some(Peer(r, wr, fd, info));
+                    *nn = None;
+                    continue;
+                }
+            }
+
+            // ... or create a new one
 
             if let Some(mut bnpf) = nn.take() {
                 match bnpf.poll() {
@@ -122,6 +254,57 @@ impl State {
             *nn = Some(pc.get_only_first_conn());
         }
     }
+
+    /// Keep `--preconnect` spares connecting while the current peer is in use,
+    /// and drop the ones that got closed
+    fn poll_spares(&mut self) {
+        if self.p.is_some() {
+            while self.spares.len() < self.want_spares {
+                debug!("Establishing a spare connection");
+                let pc: PeerConstructor = self.s.construct(self.cp.clone());
+                self.spares.push(Spare::Connecting(pc.get_only_first_conn()));
+            }
+        }
+        let mut i = 0;
+        while i < self.spares.len() {
+            let check = match self.spares[i] {
+                Spare::Connecting(ref mut f) => match f.poll() {
+                    Ok(Async::NotReady) => SpareCheck::Keep,
+                    Ok(Async::Ready(p)) => SpareCheck::Connected(p),
+                    Err(e) => {
+                        info!("Spare connection failed: {}", e);
+                        super::metrics::add("preconnect_spare_failures", 1);
+                        SpareCheck::Failed
+                    }
+                },
+                Spare::Ready(ref mut w) => if w.check() {
+                    SpareCheck::Keep
+                } else {
+                    SpareCheck::Failed
+                },
+            };
+            match check {
+                SpareCheck::Keep => i += 1,
+                // Checked on the next iteration
+                SpareCheck::Connected(peer) => {
+                    debug!("Spare connection established");
+                    let expiry = match self.max_age {
+                        Some(d) => Timeout::new(d, &self.cp.tokio_handle).ok(),
+                        None => None,
+                    };
+                    self.spares[i] = Spare::Ready(WarmSpare {
+                        peer,
+                        received: VecDeque::new(),
+                        buffered: 0,
+                        expiry,
+                    });
+                }
+                SpareCheck::Failed => {
+                    self.spares.remove(i);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -142,7 +325,12 @@ macro_rules! getpeer {
 impl State {
     fn reconnect(&mut self) {
         info!("Reconnect");
+        super::metrics::reconnect();
+        super::events::emit("reconnect", vec![]);
         self.p = None;
+        if let Some(ref hook) = self.cp.reconnect_hook {
+            hook();
+        }
     }
 }
 
@@ -212,17 +400,26 @@ impl AsyncWrite for PeerHandle {
     fn shutdown(&mut self) -> futures::Poll<(), IoError> {
         let mut state = self.0.borrow_mut();
         state.p = None;
+        state.spares.clear();
         Ok(Async::Ready(()))
     }
 }
 
 pub fn autoreconnector(s: Rc<Specifier>, cp: ConstructParams) -> BoxedNewPeerFuture {
+    let want_spares = cp.program_options.preconnect;
+    let max_age = match cp.program_options.preconnect_max_age {
+        0 => None,
+        x => Some(Duration::from_secs(x)),
+    };
     let s = Rc::new(RefCell::new(State {
         cp,
         s,
         p: None,
         n: None,
         aux: Default::default(),
+        want_spares,
+        max_age,
+        spares: vec![],
     }));
     let ph1 = PeerHandle(s.clone());
     let ph2 = PeerHandle(s);
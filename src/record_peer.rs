@@ -0,0 +1,383 @@
+use futures;
+use futures::future::ok;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::ws_peer::{WsEvent, WsEventHook, WsEventObserver};
+use super::{BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::util::hexdump;
+
+#[derive(Debug)]
+pub struct Record<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Record<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let gs = cp.global_state.borrow_mut().recorder.clone();
+        let opts = cp.program_options.clone();
+        if let Err(e) = open_recorder(&gs, &opts) {
+            return super::once(super::peer_err(e));
+        }
+        let mut cp = cp;
+        cp.ws_event_hook = Some(Rc::new(RecorderHook(gs.clone())) as WsEventHook);
+        let inner = self.0.construct(cp);
+        inner.map(move |p| record_peer(p, &gs))
+    }
+    specifier_boilerplate!(typ=Other globalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = RecordClass,
+    target = Record,
+    prefixes = ["record:"],
+    arg_handling = subspec,
+    consumed_options = &["--record-file"],
+    help = r#"
+Record all traffic going through the subspecifier to --record-file, with timestamps.
+
+Data read from the subspecifier is recorded as incoming, data written to it as outgoing.
+If the subspecifier is a WebSocket, pings, pongs and close frames
+(with status code) are recorded as well.
+
+The capture file can be inspected with `websocat --dump-capture <file>`
+and played back with `replay:`.
+
+Records are appended to the file. If a previous run crashed in the middle
+of a record, the incomplete tail is cut off before appending.
+All connections share one capture file, so it is best used with --oneshot.
+
+Example: record a session with a WebSocket server
+
+    websocat --record-file session.cap - record:ws://127.0.0.1:8080/
+"#
+);
+
+/// Capture file starts with this, followed by version byte
+pub const CAPTURE_MAGIC: &[u8] = b"WSCATCAP";
+pub const CAPTURE_VERSION: u8 = 1;
+const HEADER_LEN: usize = 9;
+
+/// Data read from the subspecifier is `In`, data written to it is `Out`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In = 0,
+    Out = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Data = 0,
+    Eof = 1,
+    Ping = 2,
+    Pong = 3,
+    /// Payload is empty or big-endian status code followed by reason
+    Close = 4,
+}
+
+/// One record of a capture file.
+///
+/// On disk: u32 big-endian length of the rest of the record,
+/// u64 big-endian timestamp in nanoseconds since UNIX epoch,
+/// direction byte, kind byte, payload.
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    pub ts_ns: u64,
+    pub dir: Direction,
+    pub kind: RecordKind,
+    pub payload: Vec<u8>,
+}
+
+impl CaptureRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        let l = 10 + self.payload.len();
+        let mut v = Vec::with_capacity(4 + l);
+        v.extend_from_slice(&be_bytes(l as u64, 4));
+        v.extend_from_slice(&be_bytes(self.ts_ns, 8));
+        v.push(self.dir as u8);
+        v.push(self.kind as u8);
+        v.extend_from_slice(&self.payload);
+        v
+    }
+}
+
+fn be_bytes(x: u64, n: usize) -> Vec<u8> {
+    (0..n).rev().map(|i| (x >> (8 * i)) as u8).collect()
+}
+
+fn be_read(b: &[u8]) -> u64 {
+    b.iter().fold(0u64, |a, &x| (a << 8) | (x as u64))
+}
+
+/// Parse capture file content. Second item of the result is the length of
+/// well-formed prefix of the file; it is less than file size if the file is truncated.
+pub fn parse_capture(data: &[u8]) -> Result<(Vec<CaptureRecord>, usize), String> {
+    if data.len() < HEADER_LEN || &data[..8] != CAPTURE_MAGIC {
+        return Err("Not a websocat capture file".to_string());
+    }
+    if data[8] != CAPTURE_VERSION {
+        return Err(format!("Unsupported capture file version {}", data[8]));
+    }
+    let mut pos = HEADER_LEN;
+    let mut v = vec![];
+    while data.len() - pos >= 4 {
+        let l = be_read(&data[pos..pos + 4]) as usize;
+        if l < 10 {
+            return Err(format!("Malformed record at offset {}", pos));
+        }
+        if data.len() - pos - 4 < l {
+            break;
+        }
+        let r = &data[pos + 4..pos + 4 + l];
+        let dir = match r[8] {
+            0 => Direction::In,
+            1 => Direction::Out,
+            x => return Err(format!("Invalid direction {} at offset {}", x, pos)),
+        };
+        let kind = match r[9] {
+            0 => RecordKind::Data,
+            1 => RecordKind::Eof,
+            2 => RecordKind::Ping,
+            3 => RecordKind::Pong,
+            4 => RecordKind::Close,
+            x => return Err(format!("Invalid record kind {} at offset {}", x, pos)),
+        };
+        v.push(CaptureRecord {
+            ts_ns: be_read(&r[0..8]),
+            dir,
+            kind,
+            payload: r[10..].to_vec(),
+        });
+        pos += 4 + l;
+    }
+    Ok((v, pos))
+}
+
+pub fn read_capture_file(path: &str) -> Result<(Vec<CaptureRecord>, bool), Box<::std::error::Error>> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    let (v, goodlen) = parse_capture(&data)?;
+    Ok((v, goodlen < data.len()))
+}
+
+/// Pretty-print a capture file, for `--dump-capture`
+pub fn dump_capture<W: Write>(path: &str, out: &mut W) -> Result<(), Box<::std::error::Error>> {
+    let (recs, truncated) = read_capture_file(path)?;
+    writeln!(out, "websocat capture v{}, {} records", CAPTURE_VERSION, recs.len())?;
+    let t0 = recs.first().map(|r| r.ts_ns).unwrap_or(0);
+    for r in &recs {
+        let t = r.ts_ns.saturating_sub(t0);
+        let arrow = match r.dir {
+            Direction::In => "<",
+            Direction::Out => ">",
+        };
+        let what = match r.kind {
+            RecordKind::Data => format!("data {} bytes", r.payload.len()),
+            RecordKind::Eof => "EOF".to_string(),
+            RecordKind::Ping => format!("ping {} bytes", r.payload.len()),
+            RecordKind::Pong => format!("pong {} bytes", r.payload.len()),
+            RecordKind::Close => {
+                if r.payload.len() >= 2 {
+                    format!(
+                        "close {} {}",
+                        be_read(&r.payload[..2]),
+                        String::from_utf8_lossy(&r.payload[2..])
+                    )
+                } else {
+                    "close".to_string()
+                }
+            }
+        };
+        writeln!(
+            out,
+            "{:6}.{:06} {} {}",
+            t / 1_000_000_000,
+            t % 1_000_000_000 / 1000,
+            arrow,
+            what
+        )?;
+        match r.kind {
+            RecordKind::Data | RecordKind::Ping | RecordKind::Pong => {
+                out.write_all(hexdump(&r.payload).as_bytes())?
+            }
+            _ => (),
+        }
+    }
+    if truncated {
+        writeln!(out, "(incomplete record at the end of file is ignored)")?;
+    }
+    Ok(())
+}
+
+pub struct Recorder {
+    w: BufWriter<File>,
+    already_warned: bool,
+}
+
+pub type GlobalState = Rc<RefCell<Option<Recorder>>>;
+
+fn now_ns() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64,
+        Err(_) => 0,
+    }
+}
+
+impl Recorder {
+    /// Open capture file for appending, writing header if it is new
+    /// and cutting off incomplete record left by a crash
+    fn open(path: &str) -> Result<Recorder, IoError> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        let mut data = vec![];
+        f.read_to_end(&mut data)?;
+        if data.is_empty() {
+            f.write_all(CAPTURE_MAGIC)?;
+            f.write_all(&[CAPTURE_VERSION])?;
+        } else {
+            let (_, goodlen) =
+                parse_capture(&data).map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+            if goodlen < data.len() {
+                warn!("Cutting off incomplete record at the end of {}", path);
+                f.set_len(goodlen as u64)?;
+            }
+            f.seek(SeekFrom::Start(goodlen as u64))?;
+        }
+        Ok(Recorder {
+            w: BufWriter::new(f),
+            already_warned: false,
+        })
+    }
+
+    pub fn record(&mut self, dir: Direction, kind: RecordKind, payload: &[u8]) {
+        let r = CaptureRecord {
+            ts_ns: now_ns(),
+            dir,
+            kind,
+            payload: payload.to_vec(),
+        };
+        if let Err(e) = self.w.write_all(&r.encode()) {
+            if !self.already_warned {
+                self.already_warned = true;
+                error!("Failed to write capture file: {}", e);
+            }
+        }
+    }
+
+    fn record_ws_event(&mut self, incoming: bool, ev: &WsEvent) {
+        let dir = if incoming {
+            Direction::In
+        } else {
+            Direction::Out
+        };
+        match *ev {
+            WsEvent::Ping(x) => self.record(dir, RecordKind::Ping, x),
+            WsEvent::Pong(x) => self.record(dir, RecordKind::Pong, x),
+            WsEvent::Close(None) => self.record(dir, RecordKind::Close, b""),
+            WsEvent::Close(Some((code, reason))) => {
+                let mut p = be_bytes(code as u64, 2);
+                p.extend_from_slice(reason.as_bytes());
+                self.record(dir, RecordKind::Close, &p);
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.w.flush();
+    }
+}
+
+fn open_recorder(gs: &GlobalState, opts: &Options) -> Result<(), IoError> {
+    if gs.borrow().is_some() {
+        return Ok(());
+    }
+    let path = match opts.record_file {
+        Some(ref x) => x,
+        None => {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "record: requires --record-file",
+            ))
+        }
+    };
+    *gs.borrow_mut() = Some(Recorder::open(path)?);
+    Ok(())
+}
+
+/// Records WebSocket control frames of the subspecifier
+struct RecorderHook(GlobalState);
+impl WsEventObserver for RecorderHook {
+    fn on_event(&self, incoming: bool, ev: &WsEvent) {
+        with_recorder(&self.0, |r| r.record_ws_event(incoming, ev))
+    }
+}
+
+fn with_recorder<F: FnOnce(&mut Recorder)>(gs: &GlobalState, f: F) {
+    if let Some(ref mut x) = *gs.borrow_mut() {
+        f(x)
+    }
+}
+
+pub fn record_peer(inner_peer: Peer, gs: &GlobalState) -> BoxedNewPeerFuture {
+    let r = RecordRead(inner_peer.0, gs.clone(), false);
+    let w = RecordWrite(inner_peer.1, gs.clone(), false);
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+/// The `bool` is whether EOF is already recorded
+struct RecordRead(Box<AsyncRead>, GlobalState, bool);
+
+impl Read for RecordRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let ret = self.0.read(buf);
+        let eof = match ret {
+            Ok(0) => true,
+            Ok(n) => {
+                with_recorder(&self.1, |r| r.record(Direction::In, RecordKind::Data, &buf[..n]));
+                false
+            }
+            Err(ref e) => e.kind() == ErrorKind::BrokenPipe,
+        };
+        if eof && !self.2 {
+            self.2 = true;
+            with_recorder(&self.1, |r| {
+                r.record(Direction::In, RecordKind::Eof, b"");
+                r.flush();
+            });
+        }
+        ret
+    }
+}
+impl AsyncRead for RecordRead {}
+
+struct RecordWrite(Box<AsyncWrite>, GlobalState, bool);
+
+impl Write for RecordWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let n = self.0.write(buf)?;
+        with_recorder(&self.1, |r| r.record(Direction::Out, RecordKind::Data, &buf[..n]));
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.flush()
+    }
+}
+impl AsyncWrite for RecordWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        if !self.2 {
+            self.2 = true;
+            with_recorder(&self.1, |r| r.record(Direction::Out, RecordKind::Eof, b""));
+        }
+        let ret = self.0.shutdown();
+        with_recorder(&self.1, |r| r.flush());
+        ret
+    }
+}
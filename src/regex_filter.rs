@@ -0,0 +1,279 @@
+//! Session-level message filtering (`--filter-in-regex`, `--filter-out-regex`)
+//! and rewriting (`--rewrite`) by regular expressions, as `middleware` hooks.
+//! Expressions are compiled once, in `Filters`, and shared by all sessions.
+
+extern crate regex;
+
+use self::regex::bytes::{Regex, RegexBuilder};
+
+use std::rc::Rc;
+
+use super::middleware::{hook, Message, MessageHook, MiddlewareAction};
+use super::util::parse_direction;
+use super::Options;
+
+/// Compile the pattern for matching message bytes
+pub fn compile(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("{}", e))
+}
+
+/// Interpret `--filter-mode`: `keep` means only matching messages pass
+pub fn is_keep_mode(mode: &str) -> Option<bool> {
+    match mode {
+        "" | "drop" => Some(false),
+        "keep" => Some(true),
+        _ => None,
+    }
+}
+
+/// `--filter-in-regex`, `--filter-out-regex` and `--rewrite`, compiled once for all sessions
+pub struct Filters {
+    filter_in: Option<Regex>,
+    filter_out: Option<Regex>,
+    keep: bool,
+    rewrites: Rc<Vec<Rewrite>>,
+    /// `--rewrite-direction`: incoming, outgoing
+    rewrite_in: bool,
+    rewrite_out: bool,
+    rewrite_binary: bool,
+    rewrite_max_size: Option<usize>,
+}
+
+impl Filters {
+    /// Fails if a pattern or a rewrite does not compile
+    pub fn new(opts: &Options) -> Result<Filters, String> {
+        let filter = |re: &Option<String>| match *re {
+            None => Ok(None),
+            Some(ref x) => compile(x).map(Some).map_err(|e| format!("Invalid regex `{}`: {}", x, e)),
+        };
+        let mut rewrites = vec![];
+        for x in &opts.rewrite {
+            rewrites.push(parse_rewrite(x).map_err(|e| format!("Invalid --rewrite `{}`: {}", x, e))?);
+        }
+        let (rewrite_in, rewrite_out) = parse_direction(&opts.rewrite_direction)
+            .ok_or("--rewrite-direction must be `in`, `out` or `both`")?;
+        Ok(Filters {
+            filter_in: filter(&opts.filter_in_regex)?,
+            filter_out: filter(&opts.filter_out_regex)?,
+            keep: is_keep_mode(&opts.filter_mode).ok_or("--filter-mode must be `drop` or `keep`")?,
+            rewrites: Rc::new(rewrites),
+            rewrite_in,
+            rewrite_out,
+            rewrite_binary: opts.rewrite_binary,
+            rewrite_max_size: opts.rewrite_max_size,
+        })
+    }
+
+    /// Hooks for a new session, for incoming and outgoing messages
+    pub fn hooks(&self) -> (Vec<MessageHook>, Vec<MessageHook>) {
+        let keep = self.keep;
+        let filter = |re: &Option<Regex>, direction| re.clone().map(|re| filter_hook(re, keep, direction));
+        let mut hooks_in: Vec<_> = filter(&self.filter_in, "incoming").into_iter().collect();
+        let mut hooks_out: Vec<_> = filter(&self.filter_out, "outgoing").into_iter().collect();
+        if !self.rewrites.is_empty() {
+            let rewriter = |direction| {
+                rewrite_hook(self.rewrites.clone(), self.rewrite_binary, self.rewrite_max_size, direction)
+            };
+            if self.rewrite_in {
+                hooks_in.push(rewriter("incoming"));
+            }
+            if self.rewrite_out {
+                hooks_out.push(rewriter("outgoing"));
+            }
+        }
+        (hooks_in, hooks_out)
+    }
+}
+
+/// Reports how many messages a hook has acted upon when the session ends
+struct Tally {
+    n: u64,
+    /// Log message, with the count and direction to be appended
+    what: &'static str,
+    metric: &'static str,
+    /// `incoming` or `outgoing`, for messages
+    direction: &'static str,
+}
+
+impl Drop for Tally {
+    fn drop(&mut self) {
+        if self.n > 0 {
+            info!("{} {} {} messages", self.what, self.n, self.direction);
+            super::metrics::add(self.metric, self.n);
+        }
+    }
+}
+
+/// Drops messages matching (or, in keep mode, not matching) the regex.
+/// Messages are matched as bytes, so binary messages are fine.
+pub fn filter_hook(re: Regex, keep: bool, direction: &'static str) -> MessageHook {
+    let mut dropped = Tally {
+        n: 0,
+        what: "Regex filter dropped",
+        metric: "regex_filter_dropped",
+        direction,
+    };
+    hook(move |m| match m {
+        Message::Data(x) => {
+            if re.is_match(&x) == keep {
+                return MiddlewareAction::Pass(x);
+            }
+            dropped.n += 1;
+            debug!("Dropped {} message of {} bytes", direction, x.len());
+            MiddlewareAction::Drop
+        }
+        Message::CloseReceived(_) => MiddlewareAction::Drop,
+    })
+}
+
+/// One `s/pattern/replacement/flags` expression of `--rewrite`
+pub struct Rewrite {
+    re: Regex,
+    /// Replacement in `regex` crate syntax (`${1}`)
+    repl: Vec<u8>,
+    global: bool,
+}
+
+/// Parse sed-like `s/pattern/replacement/flags`. Any character after `s` can be
+/// the delimiter. Replacement may refer to groups as `\1`, `$1` or `${1}` and to the whole
+/// match as `&`; other `$` signs are literal. Flags: `g` (replace all occurrences), `i` (ignore case).
+pub fn parse_rewrite(s: &str) -> Result<Rewrite, String> {
+    let mut chars = s.chars();
+    if chars.next() != Some('s') {
+        return Err("must look like s/pattern/replacement/".to_string());
+    }
+    let delim = match chars.next() {
+        Some(c) if c != '\\' && !c.is_alphanumeric() => c,
+        _ => return Err("missing or invalid delimiter after `s`".to_string()),
+    };
+    // Split into pattern, replacement and flags, unescaping the delimiter
+    let mut parts = vec![String::new()];
+    while let Some(c) = chars.next() {
+        if parts.len() == 3 {
+            parts[2].push(c);
+        } else if c == delim {
+            parts.push(String::new());
+        } else if c == '\\' {
+            match chars.next() {
+                Some(c2) if c2 == delim => parts.last_mut().unwrap().push(c2),
+                Some(c2) => {
+                    parts.last_mut().unwrap().push(c);
+                    parts.last_mut().unwrap().push(c2);
+                }
+                None => return Err("trailing backslash".to_string()),
+            }
+        } else {
+            parts.last_mut().unwrap().push(c);
+        }
+    }
+    if parts.len() != 3 {
+        return Err(format!("expected three `{}` delimiters", delim));
+    }
+    let (mut global, mut icase) = (false, false);
+    for f in parts[2].chars() {
+        match f {
+            'g' => global = true,
+            'i' => icase = true,
+            _ => return Err(format!("unknown flag `{}`", f)),
+        }
+    }
+    let re = RegexBuilder::new(&parts[0])
+        .case_insensitive(icase)
+        .build()
+        .map_err(|e| format!("{}", e))?;
+    Ok(Rewrite {
+        re,
+        repl: convert_replacement(&parts[1]).into_bytes(),
+        global,
+    })
+}
+
+/// Translate sed replacement syntax into `regex` crate syntax
+fn convert_replacement(r: &str) -> String {
+    let mut ret = String::with_capacity(r.len());
+    let mut chars = r.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => ret.push_str("${0}"),
+            '\\' => match chars.next() {
+                Some(d) if d.is_digit(10) => {
+                    ret.push_str("${");
+                    ret.push(d);
+                    ret.push('}');
+                }
+                Some('n') => ret.push('\n'),
+                Some('t') => ret.push('\t'),
+                Some('$') => ret.push_str("$$"),
+                Some(x) => ret.push(x),
+                None => ret.push('\\'),
+            },
+            '$' => match chars.clone().next() {
+                Some(d) if d.is_digit(10) || d == '{' => ret.push('$'),
+                _ => ret.push_str("$$"),
+            },
+            x => ret.push(x),
+        }
+    }
+    ret
+}
+
+impl Rewrite {
+    /// Returns `None` if the expression did not match
+    pub fn apply(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        if !self.re.is_match(msg) {
+            return None;
+        }
+        let r = if self.global {
+            self.re.replace_all(msg, &self.repl[..])
+        } else {
+            self.re.replace(msg, &self.repl[..])
+        };
+        Some(r.into_owned())
+    }
+}
+
+/// Applies `--rewrite` expressions in order to each message.
+/// Messages that are not valid UTF-8 are left alone unless `binary` is set.
+pub fn rewrite_hook(
+    rewrites: Rc<Vec<Rewrite>>,
+    binary: bool,
+    max_size: Option<usize>,
+    direction: &'static str,
+) -> MessageHook {
+    let mut modified = Tally {
+        n: 0,
+        what: "Rewrote",
+        metric: "rewritten",
+        direction,
+    };
+    let rewrite = move |msg: &[u8]| -> Option<Vec<u8>> {
+        if max_size.map_or(false, |m| msg.len() > m) {
+            debug!("Not rewriting {} message of {} bytes: too big", direction, msg.len());
+            return None;
+        }
+        if !binary && ::std::str::from_utf8(msg).is_err() {
+            return None;
+        }
+        let mut ret: Option<Vec<u8>> = None;
+        for rw in rewrites.iter() {
+            let r = match ret {
+                Some(ref x) => rw.apply(x),
+                None => rw.apply(msg),
+            };
+            if r.is_some() {
+                ret = r;
+            }
+        }
+        ret
+    };
+    hook(move |m| match m {
+        Message::Data(x) => match rewrite(&x) {
+            None => MiddlewareAction::Pass(x),
+            Some(r) => {
+                modified.n += 1;
+                MiddlewareAction::Replace(r)
+            }
+        },
+        Message::CloseReceived(_) => MiddlewareAction::Drop,
+    })
+}
@@ -0,0 +1,26 @@
+//! Reopening of output files on SIGHUP, to cooperate with logrotate,
+//! and reloading of TLS certificates
+
+use std::cell::RefCell;
+
+thread_local! {
+    static HOOKS: RefCell<Vec<Box<Fn() -> bool>>> = RefCell::new(vec![]);
+}
+
+/// Register something to be done on reload. The hook returns `false`
+/// when it is no longer needed (e.g. its file is closed) and should be forgotten.
+pub fn on_reload<F: Fn() -> bool + 'static>(f: F) {
+    HOOKS.with(|h| h.borrow_mut().push(Box::new(f)))
+}
+
+/// Run all reload hooks
+pub fn reload() {
+    info!("Reloading");
+    let hooks = HOOKS.with(|h| ::std::mem::replace(&mut *h.borrow_mut(), vec![]));
+    let hooks: Vec<_> = hooks.into_iter().filter(|f| f()).collect();
+    // Hooks may have registered new hooks meanwhile
+    HOOKS.with(|h| h.borrow_mut().extend(hooks));
+    // Certificates are shared by all threads, so they are not in the thread's hooks
+    #[cfg(feature = "ssl")]
+    super::ssl_peer::reload();
+}
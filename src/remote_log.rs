@@ -0,0 +1,181 @@
+//! `--log-syslog` and `--log-journald`: lifecycle events, warnings and errors
+//! sent to the system log. Sockets are non-blocking; what can't be sent
+//! right away is dropped and counted, so logging never stalls the reactor.
+
+use log::Level;
+
+use std::io::Error as IoError;
+use std::net::{ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Syslog facility "daemon"
+const FACILITY: u8 = 3;
+
+const IDENTIFIER: &str = "websocat";
+
+enum Sock {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+impl Sock {
+    fn send(&self, data: &[u8]) -> Result<usize, IoError> {
+        match *self {
+            #[cfg(unix)]
+            Sock::Unix(ref s) => s.send(data),
+            Sock::Udp(ref s) => s.send(data),
+        }
+    }
+}
+
+pub struct RemoteLog {
+    sock: Sock,
+    journald: bool,
+    dropped: AtomicUsize,
+    pid: u32,
+}
+
+/// Syslog severity for a log level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Journal field name for an event field, if it has a well-known one
+fn journal_field(name: &str) -> String {
+    match name {
+        "sid" => "SESSION_ID".to_string(),
+        "peer" => "PEER".to_string(),
+        "code" | "close_code" => "CLOSE_CODE".to_string(),
+        x => format!("WEBSOCAT_{}", x.to_uppercase()),
+    }
+}
+
+/// Append a field in the native journal protocol
+fn journal_append(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Binary-safe form: name, newline, little-endian 64-bit length, data
+        buf.push(b'\n');
+        let l = value.len() as u64;
+        for i in 0..8 {
+            buf.push((l >> (i * 8)) as u8);
+        }
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+impl RemoteLog {
+    fn new(sock: Sock, journald: bool) -> RemoteLog {
+        RemoteLog {
+            sock,
+            journald,
+            dropped: AtomicUsize::new(0),
+            pid: ::std::process::id(),
+        }
+    }
+
+    /// Syslog to `/dev/log`, or over UDP to `addr` if given
+    pub fn syslog(addr: Option<&str>) -> Result<RemoteLog, IoError> {
+        let sock = match addr {
+            Some(a) => {
+                let a = match a.to_socket_addrs()?.next() {
+                    Some(x) => x,
+                    None => Err(IoError::new(::std::io::ErrorKind::NotFound, "no address for syslog server"))?,
+                };
+                let s = UdpSocket::bind(if a.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+                s.connect(a)?;
+                Sock::Udp(s)
+            }
+            #[cfg(unix)]
+            None => {
+                let s = UnixDatagram::unbound()?;
+                s.connect(SYSLOG_SOCKET)?;
+                Sock::Unix(s)
+            }
+            #[cfg(not(unix))]
+            None => Err(IoError::new(::std::io::ErrorKind::NotFound, "no local syslog socket on this platform"))?,
+        };
+        set_nonblocking(&sock)?;
+        Ok(RemoteLog::new(sock, false))
+    }
+
+    /// systemd-journald, using its native protocol
+    #[cfg(unix)]
+    pub fn journald() -> Result<RemoteLog, IoError> {
+        let s = UnixDatagram::unbound()?;
+        s.connect(JOURNALD_SOCKET)?;
+        let sock = Sock::Unix(s);
+        set_nonblocking(&sock)?;
+        Ok(RemoteLog::new(sock, true))
+    }
+
+    #[cfg(not(unix))]
+    pub fn journald() -> Result<RemoteLog, IoError> {
+        Err(IoError::new(::std::io::ErrorKind::NotFound, "journald is not available on this platform"))
+    }
+
+    /// Send a message with optional structured fields. Never blocks.
+    pub fn log(&self, level: Level, msg: &str, fields: &[(&str, String)]) {
+        let pkt = if self.journald {
+            let mut b = Vec::with_capacity(msg.len() + 64);
+            journal_append(&mut b, "MESSAGE", msg);
+            journal_append(&mut b, "PRIORITY", &severity(level).to_string());
+            journal_append(&mut b, "SYSLOG_IDENTIFIER", IDENTIFIER);
+            for &(k, ref v) in fields {
+                journal_append(&mut b, &journal_field(k), v);
+            }
+            b
+        } else {
+            let pri = FACILITY * 8 + severity(level);
+            let mut line = match self.sock {
+                #[cfg(unix)]
+                Sock::Unix(_) => format!("<{}>{}[{}]: {}", pri, IDENTIFIER, self.pid, msg),
+                Sock::Udp(_) => format!(
+                    "<{}>1 {} - {} {} - - {}",
+                    pri,
+                    super::util::rfc3339_now(),
+                    IDENTIFIER,
+                    self.pid,
+                    msg
+                ),
+            };
+            for &(k, ref v) in fields {
+                line.push_str(&format!(" {}={}", k, v));
+            }
+            line.into_bytes()
+        };
+        if self.sock.send(&pkt).is_err() {
+            // Would block, log daemon gone or message too big; nowhere to report it now
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Messages that could not be sent
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn set_nonblocking(s: &Sock) -> Result<(), IoError> {
+    match *s {
+        #[cfg(unix)]
+        Sock::Unix(ref s) => s.set_nonblocking(true),
+        Sock::Udp(ref s) => s.set_nonblocking(true),
+    }
+}
@@ -0,0 +1,228 @@
+use futures;
+use futures::Async::{NotReady, Ready};
+use futures::Future;
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::record_peer::{read_capture_file, Direction, RecordKind};
+use super::{once, simple_err, wouldblock, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, ReadDebt, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Clone, Debug)]
+pub struct Replay(pub PathBuf);
+impl Specifier for Replay {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let r = replay_peer(&self.0, &cp.program_options, &cp.tokio_handle);
+        once(Box::new(futures::future::result(r)) as BoxedNewPeerFuture)
+    }
+    specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
+}
+specifier_class!(
+    name = ReplayClass,
+    target = Replay,
+    prefixes = ["replay:"],
+    arg_handling = into,
+    takes_path = true,
+    consumed_options = &["--replay-no-timing", "--replay-speed", "--replay-strict"],
+    help = r#"
+Play back a capture file made by `record:`, acting as the recorded subspecifier.
+Argument is a file path.
+
+Recorded incoming data is emitted with original timing (scaled by --replay-speed,
+or as fast as possible with --replay-no-timing). EOF is signaled at the
+recorded end of session, or at the end of the file if the capture is truncated.
+WebSocket control frames from the capture are not replayed.
+
+Data written to the peer is discarded, unless --replay-strict is specified.
+Then it must match recorded outgoing data, otherwise the session fails
+with an error showing the difference.
+
+Example: test a client application against a recorded server session
+
+    websocat --replay-strict ws-l:127.0.0.1:8080 replay:session.cap
+"#
+);
+
+pub fn replay_peer(path: &PathBuf, opts: &Options, h: &Handle) -> super::Result<Peer> {
+    let path = path.to_str().ok_or("Invalid file name for replay:")?;
+    let (recs, truncated) = read_capture_file(path)?;
+    if truncated {
+        warn!("Capture file {} is truncated, replaying its complete part", path);
+    }
+    let speed = if opts.replay_speed > 0.0 {
+        opts.replay_speed
+    } else {
+        1.0
+    };
+    let t0 = recs.first().map(|r| r.ts_ns).unwrap_or(0);
+    let offset = |ts: u64| {
+        if opts.replay_no_timing {
+            return Duration::from_secs(0);
+        }
+        let ns = (ts.saturating_sub(t0) as f64 / speed) as u64;
+        Duration::new(ns / 1_000_000_000, (ns % 1_000_000_000) as u32)
+    };
+
+    let mut incoming = VecDeque::new();
+    let mut eof_at = None;
+    let mut expected = vec![];
+    for r in &recs {
+        match (r.dir, r.kind) {
+            (Direction::In, RecordKind::Data) if eof_at.is_none() => {
+                incoming.push_back((offset(r.ts_ns), r.payload.clone()))
+            }
+            (Direction::In, RecordKind::Eof) if eof_at.is_none() => eof_at = Some(offset(r.ts_ns)),
+            (Direction::Out, RecordKind::Data) => expected.extend_from_slice(&r.payload),
+            _ => (),
+        }
+    }
+    let start = Instant::now();
+    let r = ReplayRead {
+        incoming,
+        eof_at,
+        start,
+        timer: None,
+        handle: h.clone(),
+        debt: Default::default(),
+    };
+    let w = ReplayWrite {
+        expected: if opts.replay_strict {
+            Some(expected)
+        } else {
+            None
+        },
+        pos: 0,
+    };
+    Ok(Peer::new(r, w))
+}
+
+struct ReplayRead {
+    incoming: VecDeque<(Duration, Vec<u8>)>,
+    eof_at: Option<Duration>,
+    start: Instant,
+    timer: Option<Timeout>,
+    handle: Handle,
+    debt: ReadDebt,
+}
+
+impl ReplayRead {
+    /// `Ok(true)` if the time has come
+    fn poll_time(&mut self, offset: Duration) -> ::std::io::Result<bool> {
+        let due = self.start + offset;
+        let now = Instant::now();
+        if due <= now {
+            self.timer = None;
+            return Ok(true);
+        }
+        if self.timer.is_none() {
+            self.timer = Some(Timeout::new(due - now, &self.handle)?);
+        }
+        match self.timer.as_mut().unwrap().poll()? {
+            Ready(()) => {
+                self.timer = None;
+                Ok(true)
+            }
+            NotReady => Ok(false),
+        }
+    }
+}
+
+impl Read for ReplayRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        let offset = match self.incoming.front() {
+            Some(&(t, _)) => t,
+            None => match self.eof_at {
+                Some(t) => t,
+                None => return Ok(0),
+            },
+        };
+        if !self.poll_time(offset)? {
+            return wouldblock();
+        }
+        match self.incoming.pop_front() {
+            Some((_, x)) => self.debt.process_message(buf, &x),
+            None => Ok(0),
+        }
+    }
+}
+impl AsyncRead for ReplayRead {}
+
+/// Show bytes like `"ab\n\x00"`, at most `max` of them
+fn show_bytes(b: &[u8], max: usize) -> String {
+    let mut s = String::from("\"");
+    for &c in b.iter().take(max) {
+        s.extend(::std::ascii::escape_default(c).map(|x| x as char));
+    }
+    s.push('"');
+    if b.len() > max {
+        s.push_str("...");
+    }
+    s
+}
+
+/// Compare outgoing data `buf` against what is left of recorded data
+fn check_expected(exp: &[u8], buf: &[u8], pos: usize) -> Result<(), IoError> {
+    let common = exp.len().min(buf.len());
+    if let Some(i) = (0..common).find(|&i| exp[i] != buf[i]) {
+        let from = i.saturating_sub(8);
+        return Err(simple_err(format!(
+            "Replay mismatch at outgoing byte {}: expected {}, got {}",
+            pos + i,
+            show_bytes(&exp[from..], 32),
+            show_bytes(&buf[from..], 32)
+        )));
+    }
+    if buf.len() > exp.len() {
+        return Err(simple_err(format!(
+            "Replay mismatch at outgoing byte {}: expected end of data, got {}",
+            pos + common,
+            show_bytes(&buf[common..], 32)
+        )));
+    }
+    Ok(())
+}
+
+struct ReplayWrite {
+    /// `None` if not verifying
+    expected: Option<Vec<u8>>,
+    pos: usize,
+}
+
+impl Write for ReplayWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let pos = self.pos;
+        if let Some(ref x) = self.expected {
+            check_expected(&x[pos..], buf, pos)?;
+        }
+        self.pos += buf.len();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+impl AsyncWrite for ReplayWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        if let Some(ref x) = self.expected {
+            if self.pos < x.len() {
+                return Err(simple_err(format!(
+                    "Replay mismatch at outgoing byte {}: expected {}, got end of data",
+                    self.pos,
+                    show_bytes(&x[self.pos..], 32)
+                )));
+            }
+        }
+        Ok(Ready(()))
+    }
+}
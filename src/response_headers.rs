@@ -0,0 +1,116 @@
+//! `--include-headers` and `--response-header-file`: WebSocket handshake response
+//! headers, curl -i style, shown before the data of the session
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Error as IoError, Read, Write};
+
+use tokio_io::AsyncRead;
+
+use super::{events, Options, ReadDebt};
+
+/// Response header block of the latest connection of a session, and how many connections there were
+struct Noted {
+    generation: u64,
+    block: Vec<u8>,
+}
+
+thread_local! {
+    static NOTED: RefCell<HashMap<u64, Noted>> = RefCell::new(HashMap::new());
+}
+
+pub fn needed(opts: &Options) -> bool {
+    opts.include_headers || opts.include_headers_every_connect || opts.response_header_file.is_some()
+}
+
+/// A WebSocket client of the current session has finished its handshake
+pub fn note<H: Display>(opts: &Options, headers: &H) {
+    if !needed(opts) {
+        return;
+    }
+    // Status line, headers and an empty line, like HTTP has them
+    let block = format!("HTTP/1.1 101 Switching Protocols\r\n{}\r\n", headers).into_bytes();
+    if let Some(ref p) = opts.response_header_file {
+        if let Err(e) = File::create(p).and_then(|mut f| f.write_all(&block)) {
+            warn!("Failed to write response headers to {:?}: {}", p, e);
+        }
+    }
+    if let Some(sid) = events::current_sid() {
+        NOTED.with(|n| {
+            let mut n = n.borrow_mut();
+            let generation = n.get(&sid).map_or(0, |x| x.generation) + 1;
+            n.insert(sid, Noted { generation, block });
+        });
+    }
+}
+
+/// Reader of incoming data that puts the header block in front of it
+pub struct IncludeHeadersRead {
+    inner: Box<AsyncRead>,
+    sid: Option<u64>,
+    /// Generation of the last block seen
+    seen: u64,
+    every_connect: bool,
+    debt: ReadDebt,
+}
+
+impl IncludeHeadersRead {
+    pub fn new(inner: Box<AsyncRead>, opts: &Options) -> Self {
+        IncludeHeadersRead {
+            inner,
+            sid: events::current_sid(),
+            seen: 0,
+            every_connect: opts.include_headers_every_connect,
+            debt: ReadDebt(None),
+        }
+    }
+
+    /// Header block to emit now, if a connection was established since the last check
+    fn pending(&mut self) -> Option<Vec<u8>> {
+        let sid = self.sid?;
+        let (generation, block) = NOTED.with(|n| {
+            n.borrow().get(&sid).map(|x| (x.generation, x.block.clone()))
+        })?;
+        if generation == self.seen {
+            return None;
+        }
+        let first = self.seen == 0;
+        self.seen = generation;
+        if first || self.every_connect {
+            Some(block)
+        } else {
+            None
+        }
+    }
+}
+
+impl Read for IncludeHeadersRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ret) = self.debt.check_debt(buf) {
+            return ret;
+        }
+        if let Some(block) = self.pending() {
+            return self.debt.process_message(buf, &block);
+        }
+        let n = self.inner.read(buf)?;
+        // Reconnected while reading: the data is from the new connection
+        if n > 0 {
+            if let Some(mut block) = self.pending() {
+                block.extend_from_slice(&buf[..n]);
+                return self.debt.process_message(buf, &block);
+            }
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for IncludeHeadersRead {}
+
+impl Drop for IncludeHeadersRead {
+    fn drop(&mut self) {
+        if let Some(sid) = self.sid {
+            NOTED.with(|n| n.borrow_mut().remove(&sid));
+        }
+    }
+}
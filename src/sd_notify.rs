@@ -0,0 +1,136 @@
+//! Readiness notification for supervisors: systemd's `$NOTIFY_SOCKET` protocol
+//! (`READY=1`, `WATCHDOG=1`, `STOPPING=1`) and `--notify-fd`
+
+#[cfg(all(target_os = "linux", feature = "libc"))]
+extern crate libc;
+
+use futures::{Future, Stream};
+
+use std::cell::RefCell;
+use std::io::{Error as IoError, ErrorKind};
+use std::time::Duration;
+
+use tokio_core::reactor::{Handle, Interval};
+
+thread_local! {
+    static SOCKET: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Take `$NOTIFY_SOCKET` and watchdog settings from the environment, so that
+/// child processes don't notify on our behalf. Returns the watchdog interval, if any.
+pub fn init() -> Option<Duration> {
+    let socket = ::std::env::var("NOTIFY_SOCKET").ok().and_then(|x| if x.is_empty() { None } else { Some(x) });
+    let usec = ::std::env::var("WATCHDOG_USEC").ok().and_then(|x| x.parse::<u64>().ok());
+    let pid = ::std::env::var("WATCHDOG_PID").ok().and_then(|x| x.parse::<u32>().ok());
+    for v in &["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+        ::std::env::remove_var(v);
+    }
+    let enabled = socket.is_some();
+    SOCKET.with(|s| *s.borrow_mut() = socket);
+    let for_us = pid.map_or(true, |p| p == ::std::process::id());
+    match usec {
+        Some(u) if enabled && for_us && u > 0 => Some(Duration::new(u / 1_000_000, (u % 1_000_000) as u32 * 1000)),
+        _ => None,
+    }
+}
+
+/// Send a state like `READY=1` to systemd, if it asked for notifications
+pub fn notify(state: &str) {
+    let socket = SOCKET.with(|s| s.borrow().clone());
+    if let Some(path) = socket {
+        debug!("sd_notify: {:?}", state);
+        if let Err(e) = send(&path, state) {
+            warn!("Failed to notify systemd at {}: {}", path, e);
+        }
+    }
+}
+
+/// Listeners are bound: send `READY=1` with the status line and start watchdog pings
+pub fn ready(status: &str, watchdog: Option<Duration>, h: &Handle) {
+    notify(&format!("READY=1\nSTATUS={}", status));
+    if let Some(d) = watchdog {
+        // Ping twice per watchdog period, as systemd recommends
+        match Interval::new(d / 2, h) {
+            Ok(i) => h.spawn(i.for_each(|()| {
+                notify("WATCHDOG=1");
+                Ok(())
+            }).map_err(|e| warn!("Watchdog timer failed: {}", e))),
+            Err(e) => warn!("Failed to start watchdog timer: {}", e),
+        }
+    }
+}
+
+/// Graceful shutdown has started
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+#[cfg(unix)]
+fn send(path: &str, msg: &str) -> Result<(), IoError> {
+    use std::os::unix::net::UnixDatagram;
+    if path.starts_with('@') {
+        return send_abstract(&path[1..], msg);
+    }
+    let s = UnixDatagram::unbound()?;
+    s.send_to(msg.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &str, _msg: &str) -> Result<(), IoError> {
+    Err(IoError::new(ErrorKind::Other, "notification socket is not supported on this platform"))
+}
+
+/// Rust's own sockets can't address abstract-namespaced sockets
+#[cfg(all(target_os = "linux", feature = "libc"))]
+fn send_abstract(name: &str, msg: &str) -> Result<(), IoError> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+    let s = UnixDatagram::unbound()?;
+    let mut addr: libc::sockaddr_un = unsafe { ::std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let name = name.as_bytes();
+    if name.len() + 1 > addr.sun_path.len() {
+        return Err(IoError::new(ErrorKind::InvalidInput, "socket name is too long"));
+    }
+    for (i, &b) in name.iter().enumerate() {
+        addr.sun_path[i + 1] = b as libc::c_char;
+    }
+    let len = ::std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+    let r = unsafe {
+        libc::sendto(
+            s.as_raw_fd(),
+            msg.as_ptr() as *const libc::c_void,
+            msg.len(),
+            0,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, not(all(target_os = "linux", feature = "libc"))))]
+fn send_abstract(_name: &str, _msg: &str) -> Result<(), IoError> {
+    Err(IoError::new(
+        ErrorKind::Other,
+        "abstract notification sockets need websocat built with `libc` feature on Linux",
+    ))
+}
+
+/// `--notify-fd`: write `READY` line to the file descriptor and close it
+#[cfg(unix)]
+pub fn notify_fd(fd: i32) -> Result<(), IoError> {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    let mut f = unsafe { ::std::fs::File::from_raw_fd(fd) };
+    f.write_all(b"READY\n")
+}
+
+#[cfg(not(unix))]
+pub fn notify_fd(_fd: i32) -> Result<(), IoError> {
+    Err(IoError::new(ErrorKind::Other, "--notify-fd is not supported on this platform"))
+}
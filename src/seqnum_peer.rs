@@ -0,0 +1,189 @@
+use futures;
+use futures::future::ok;
+
+use std::collections::BTreeSet;
+
+use super::{simple_err, BoxedNewPeerFuture, Peer, WriteDebt};
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct SeqNum<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for SeqNum<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| seqnum_peer(p, &opts))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = SeqNumClass,
+    target = SeqNum,
+    prefixes = ["seqnum:"],
+    arg_handling = subspec,
+    consumed_options = &["--seqnum-strict"],
+    help = r#"
+Detect lost, duplicated and reordered messages.
+
+Each message written to the subspecifier is prefixed with a magic byte 0xD5
+and 8-byte big-endian sequence number. Messages read from it get the prefix
+verified and stripped. Anomalies are logged (or abort the session with
+--seqnum-strict); a report is printed to stderr at the end of session.
+Messages without the magic byte are always an error.
+
+Use it on both ends of a link, e.g. over UDP:
+
+    websocat ws-l:127.0.0.1:8080 seqnum:udp:10.0.0.2:1234
+    websocat seqnum:udp-l:0.0.0.0:1234 ws://127.0.0.1:8081/
+"#
+);
+
+const SEQNUM_MAGIC: u8 = 0xD5;
+const PREFIX_LEN: usize = 9;
+/// How many missing sequence numbers to remember for detecting late arrivals
+const MAX_MISSING: usize = 65536;
+
+pub fn seqnum_peer(inner_peer: Peer, opts: &Options) -> BoxedNewPeerFuture {
+    let r = SeqNumRead {
+        inner: inner_peer.0,
+        strict: opts.seqnum_strict,
+        expected: 0,
+        missing: BTreeSet::new(),
+        received: 0,
+        lost: 0,
+        duplicated: 0,
+        reordered: 0,
+    };
+    let w = SeqNumWrite {
+        inner: inner_peer.1,
+        next: 0,
+        debt: Default::default(),
+    };
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+struct SeqNumRead {
+    inner: Box<AsyncRead>,
+    strict: bool,
+    expected: u64,
+    /// Skipped sequence numbers that may still arrive late
+    missing: BTreeSet<u64>,
+    received: u64,
+    lost: u64,
+    duplicated: u64,
+    reordered: u64,
+}
+
+impl SeqNumRead {
+    /// Account for an incoming sequence number, returning a description of an anomaly if any
+    fn check(&mut self, seq: u64) -> Option<String> {
+        self.received += 1;
+        if seq == self.expected {
+            self.expected += 1;
+            return None;
+        }
+        if seq > self.expected {
+            let gap = seq - self.expected;
+            self.lost += gap;
+            for x in self.expected..seq {
+                if self.missing.len() >= MAX_MISSING {
+                    break;
+                }
+                self.missing.insert(x);
+            }
+            let msg = format!("seqnum: {} message(s) lost before #{}", gap, seq);
+            self.expected = seq + 1;
+            return Some(msg);
+        }
+        if self.missing.remove(&seq) {
+            self.lost -= 1;
+            self.reordered += 1;
+            Some(format!("seqnum: message #{} arrived late", seq))
+        } else {
+            self.duplicated += 1;
+            Some(format!("seqnum: duplicate message #{}", seq))
+        }
+    }
+}
+
+impl Read for SeqNumRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        loop {
+            let n = self.inner.read(buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            if n < PREFIX_LEN || buf[0] != SEQNUM_MAGIC {
+                return Err(simple_err(
+                    "seqnum: incoming message lacks sequence number prefix".to_string(),
+                ));
+            }
+            let mut seq = 0u64;
+            for &b in &buf[1..PREFIX_LEN] {
+                seq = (seq << 8) | b as u64;
+            }
+            if let Some(msg) = self.check(seq) {
+                if self.strict {
+                    return Err(simple_err(msg));
+                }
+                warn!("{}", msg);
+            }
+            if n == PREFIX_LEN {
+                // Empty message can't be delivered, as zero-length read means EOF
+                continue;
+            }
+            for i in PREFIX_LEN..n {
+                buf[i - PREFIX_LEN] = buf[i];
+            }
+            return Ok(n - PREFIX_LEN);
+        }
+    }
+}
+impl AsyncRead for SeqNumRead {}
+
+impl Drop for SeqNumRead {
+    fn drop(&mut self) {
+        if self.received == 0 {
+            return;
+        }
+        eprintln!(
+            "seqnum: received={} lost={} duplicated={} reordered={}",
+            self.received, self.lost, self.duplicated, self.reordered
+        );
+    }
+}
+
+struct SeqNumWrite {
+    inner: Box<AsyncWrite>,
+    next: u64,
+    debt: WriteDebt,
+}
+
+impl Write for SeqNumWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let seq = self.next;
+        self.debt.write_frame(&mut self.inner, || {
+            let mut v = Vec::with_capacity(PREFIX_LEN + buf.len());
+            v.push(SEQNUM_MAGIC);
+            for i in (0..8).rev() {
+                v.push((seq >> (i * 8)) as u8);
+            }
+            v.extend_from_slice(buf);
+            Ok(v)
+        })?;
+        self.next += 1;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for SeqNumWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,229 @@
+//! Process-wide cap on concurrent sessions (`--max-sessions`),
+//! enforced where listeners hand out new connections.
+//!
+//! With `--workers`, sessions of all worker threads count against the cap.
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::io::{read, write_all};
+
+use super::{metrics, Options, Peer};
+
+/// Don't repeat the "refusing sessions" warning more often than this
+const WARN_INTERVAL_SECS: u64 = 10;
+
+/// How long a refused WebSocket client gets to send its request before the 503
+const REFUSE_TIMEOUT_MS: u64 = 5000;
+
+/// How often a backpressured listener checks for slots freed by other `--workers` threads
+const SHARED_RECHECK_MS: u64 = 50;
+
+const HTTP_503: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+Content-Length: 0\r\n\
+Connection: close\r\n\
+Retry-After: 1\r\n\
+\r\n";
+
+#[derive(Default)]
+struct CapState {
+    live: u64,
+    /// Backpressured listeners waiting for a slot
+    waiters: Vec<Task>,
+    last_warning: Option<Instant>,
+    refused_since_warning: u64,
+}
+
+thread_local! {
+    static STATE: RefCell<CapState> = RefCell::new(Default::default());
+}
+
+/// Slots held by sessions of all `--workers` threads
+static SHARED_LIVE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Sessions holding a slot, including ones still connecting the right specifier
+pub fn live() -> u64 {
+    STATE.with(|s| s.borrow().live)
+}
+
+/// Held by a spawned session for its whole lifetime.
+/// Dropping it, whatever the way the session ends, frees the slot.
+pub struct SessionSlot {
+    /// Also counted in `SHARED_LIVE`
+    shared: bool,
+}
+
+impl SessionSlot {
+    fn acquire(shared: bool) -> SessionSlot {
+        STATE.with(|s| s.borrow_mut().live += 1);
+        SessionSlot { shared }
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        if self.shared {
+            SHARED_LIVE.fetch_sub(1, Ordering::SeqCst);
+        }
+        let waiters = STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            s.live -= 1;
+            ::std::mem::replace(&mut s.waiters, vec![])
+        });
+        for t in waiters {
+            t.notify();
+        }
+    }
+}
+
+fn note_refused(max: u64) {
+    metrics::add("sessions_refused", 1);
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.refused_since_warning += 1;
+        let due = s.last_warning
+            .map_or(true, |t| t.elapsed() >= Duration::from_secs(WARN_INTERVAL_SECS));
+        if due {
+            warn!(
+                "--max-sessions {} reached, refused {} connection(s)",
+                max, s.refused_since_warning
+            );
+            s.last_warning = Some(Instant::now());
+            s.refused_since_warning = 0;
+        }
+    })
+}
+
+/// Wraps a listener stream, pairing each new connection with a `SessionSlot`.
+/// At the cap, new connections are either refused
+/// (closed, or answered with 503 if `http_refusal` is set) or,
+/// with `--max-sessions-backpressure`, not accepted until a slot frees up.
+pub struct Capped<S> {
+    inner: S,
+    max: Option<u64>,
+    backpressure: bool,
+    http_refusal: bool,
+    handle: Handle,
+    /// The cap is shared with other `--workers` threads
+    shared: bool,
+    /// Slots freed by other threads don't wake this task up, so it checks from time to time
+    recheck: Option<Timeout>,
+}
+
+impl<S> Capped<S> {
+    pub fn new(inner: S, opts: &Options, http_refusal: bool, h: &Handle) -> Capped<S> {
+        Capped {
+            inner,
+            max: opts.max_sessions,
+            backpressure: opts.max_sessions_backpressure,
+            http_refusal,
+            handle: h.clone(),
+            shared: opts.workers.is_some() && opts.max_sessions.is_some(),
+            recheck: None,
+        }
+    }
+
+    fn at_cap(&self) -> bool {
+        if self.shared {
+            return self.max.map_or(false, |m| SHARED_LIVE.load(Ordering::SeqCst) as u64 >= m);
+        }
+        self.max.map_or(false, |m| live() >= m)
+    }
+
+    /// A slot for a new session, unless at the cap
+    fn acquire(&self) -> Option<SessionSlot> {
+        if !self.shared {
+            if self.at_cap() {
+                return None;
+            }
+            return Some(SessionSlot::acquire(false));
+        }
+        // Other threads may be taking slots at the same time
+        let max = self.max.unwrap_or(0);
+        if SHARED_LIVE.fetch_add(1, Ordering::SeqCst) as u64 >= max {
+            SHARED_LIVE.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(SessionSlot::acquire(true))
+    }
+
+    /// Whether it is time to check the shared cap again
+    fn poll_recheck(&mut self) -> bool {
+        if self.recheck.is_none() {
+            self.recheck = Timeout::new(Duration::from_millis(SHARED_RECHECK_MS), &self.handle).ok();
+        }
+        let due = match self.recheck {
+            Some(ref mut t) => match t.poll() {
+                Ok(Async::NotReady) => false,
+                _ => true,
+            },
+            None => false,
+        };
+        if due {
+            self.recheck = None;
+        }
+        due
+    }
+
+    fn refuse(&self, peer: Peer) {
+        note_refused(self.max.unwrap_or(0));
+        if !self.http_refusal {
+            // Dropping the peer closes the connection
+            return;
+        }
+        let Peer(r, w, _, _) = peer;
+        // Read the request first, so closing the socket does not reset the response away
+        let answer = read(r, vec![0; 4096])
+            .and_then(move |_| write_all(w, HTTP_503))
+            .map(|_| ())
+            .map_err(|e| debug!("Failed to send 503: {}", e));
+        let answer: Box<Future<Item = (), Error = ()>> = match Timeout::new(
+            Duration::from_millis(REFUSE_TIMEOUT_MS),
+            &self.handle,
+        ) {
+            Ok(t) => Box::new(answer.select(t.map_err(|_| ())).then(|_| Ok::<(), ()>(()))),
+            Err(_) => Box::new(answer),
+        };
+        self.handle.spawn(answer);
+    }
+}
+
+impl<S> Stream for Capped<S>
+where
+    S: Stream<Item = Peer>,
+{
+    type Item = (Peer, SessionSlot);
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Option<(Peer, SessionSlot)>, S::Error> {
+        loop {
+            if self.backpressure && self.at_cap() {
+                STATE.with(|s| {
+                    let mut s = s.borrow_mut();
+                    if !s.waiters.iter().any(|t| t.will_notify_current()) {
+                        s.waiters.push(task::current());
+                    }
+                });
+                if self.shared && self.poll_recheck() {
+                    continue;
+                }
+                return Ok(Async::NotReady);
+            }
+            match self.inner.poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::Ready(Some(peer)) => match self.acquire() {
+                    Some(slot) => return Ok(Async::Ready(Some((peer, slot)))),
+                    None => {
+                        self.refuse(peer);
+                        continue;
+                    }
+                },
+            }
+        }
+    }
+}
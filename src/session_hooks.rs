@@ -0,0 +1,144 @@
+//! `--on-connect` and `--on-disconnect` commands, run in background threads
+//! (at most `--hook-max-concurrent` at once) so they never hold up sessions
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::lifecycle::{SessionCallbacks, SessionMeta, SessionStats};
+use super::Options;
+
+/// On exit, wait this long for queued hooks to get started
+const EXIT_WAIT_SECS: u64 = 5;
+
+struct Pool {
+    tx: Sender<Command>,
+    /// Hooks submitted, but not started yet
+    pending: Arc<AtomicUsize>,
+}
+
+thread_local! {
+    static POOL: RefCell<Option<Pool>> = RefCell::new(None);
+}
+
+fn worker(rx: Arc<Mutex<Receiver<Command>>>, pending: Arc<AtomicUsize>) {
+    loop {
+        let cmd = match rx.lock().unwrap().recv() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        run(cmd, &pending);
+    }
+}
+
+fn run(mut cmd: Command, pending: &AtomicUsize) {
+    debug!("Running hook {:?}", cmd);
+    let child = cmd.spawn();
+    pending.fetch_sub(1, Ordering::SeqCst);
+    match child.and_then(|c| c.wait_with_output()) {
+        Ok(out) => {
+            // Hook's stdout must not mix with the data websocat may be writing to stdout
+            let _ = ::std::io::stderr().write_all(&out.stdout);
+            if !out.status.success() {
+                warn!("Hook {:?} failed: {}", cmd, out.status);
+            }
+        }
+        Err(e) => warn!("Failed to run hook {:?}: {}", cmd, e),
+    }
+}
+
+fn submit(cmd: Command, opts: &Options) {
+    POOL.with(|p| {
+        let mut p = p.borrow_mut();
+        if p.is_none() {
+            let (tx, rx) = channel();
+            let rx = Arc::new(Mutex::new(rx));
+            let pending = Arc::new(AtomicUsize::new(0));
+            for i in 0..opts.hook_max_concurrent.max(1) {
+                let rx = rx.clone();
+                let pending = pending.clone();
+                let spawned = thread::Builder::new()
+                    .name(format!("hook{}", i))
+                    .spawn(move || worker(rx, pending));
+                if let Err(e) = spawned {
+                    error!("Failed to start hook thread: {}", e);
+                }
+            }
+            // Let `--on-disconnect` of sessions closed by shutdown get started
+            let pending2 = pending.clone();
+            super::shutdown::at_exit(move || {
+                let deadline = Instant::now() + Duration::from_secs(EXIT_WAIT_SECS);
+                while pending2.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            });
+            *p = Some(Pool { tx, pending });
+        }
+        if let Some(ref p) = *p {
+            p.pending.fetch_add(1, Ordering::SeqCst);
+            if p.tx.send(cmd).is_err() {
+                p.pending.fetch_sub(1, Ordering::SeqCst);
+                warn!("Hook threads are gone, not running the hook");
+            }
+        }
+    })
+}
+
+fn command(script: &str, opts: &Options, event: &str, meta: &SessionMeta) -> Command {
+    let (shell, flag) = if cfg!(target_os = "windows") {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut cmd = Command::new(shell);
+    cmd.arg(flag).arg(script);
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::inherit());
+    cmd.env("WEBSOCAT_EVENT", event);
+    if let Some(sid) = meta.sid {
+        cmd.env("WEBSOCAT_SESSION_ID", sid.to_string());
+    }
+    if let Some(ref x) = opts.listen_spec {
+        cmd.env("WEBSOCAT_LISTEN_SPEC", x);
+    }
+    if let Some(ref x) = meta.peer {
+        cmd.env("WEBSOCAT_CLIENT", x);
+    }
+    if let Some(ref x) = meta.uri {
+        cmd.env("WEBSOCAT_URI", x);
+    }
+    cmd
+}
+
+/// Runs `--on-connect` and `--on-disconnect` commands
+pub struct HookCommands<'a>(pub &'a Options);
+
+impl<'a> SessionCallbacks for HookCommands<'a> {
+    fn on_session_start(&self, meta: &SessionMeta) {
+        let opts = self.0;
+        if let Some(ref script) = opts.on_connect {
+            let cmd = command(script, opts, "connect", meta);
+            submit(cmd, opts);
+        }
+    }
+
+    fn on_session_end(&self, meta: &SessionMeta, result: Result<(), &str>, stats: &SessionStats) {
+        let opts = self.0;
+        if let Some(ref script) = opts.on_disconnect {
+            let mut cmd = command(script, opts, "disconnect", meta);
+            cmd.env("WEBSOCAT_BYTES_IN", stats.bytes_in.to_string());
+            cmd.env("WEBSOCAT_BYTES_OUT", stats.bytes_out.to_string());
+            if let Some(c) = stats.close_code {
+                cmd.env("WEBSOCAT_CLOSE_CODE", c.to_string());
+            }
+            if let Err(e) = result {
+                cmd.env("WEBSOCAT_ERROR", e);
+            }
+            submit(cmd, opts);
+        }
+    }
+}
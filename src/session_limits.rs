@@ -0,0 +1,129 @@
+//! Session-level message and byte limits (`--max-messages-in`, `--max-bytes-out` and so on)
+
+use futures;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::ws_peer::with_close_code;
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// WebSocket status code "normal closure", sent when a limit is reached
+const LIMIT_CLOSE_CODE: u16 = 1000;
+
+#[derive(Default)]
+pub struct LimitState {
+    /// Which limit ended the session
+    tripped: Option<String>,
+}
+
+pub type HLimitState = Rc<RefCell<LimitState>>;
+
+impl LimitState {
+    pub fn new() -> HLimitState {
+        Rc::new(RefCell::new(Default::default()))
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.tripped.as_ref().map(|x| &x[..])
+    }
+}
+
+/// Reader that counts messages and bytes and reports EOF after any limit
+/// of the session is reached, so both directions finish gracefully.
+pub struct LimitRead {
+    inner: Box<AsyncRead>,
+    state: HLimitState,
+    messages_left: Option<u64>,
+    bytes_left: Option<u64>,
+    /// `in` or `out`, for messages
+    direction: &'static str,
+}
+
+impl LimitRead {
+    pub fn new(
+        inner: Box<AsyncRead>,
+        state: HLimitState,
+        max_messages: Option<u64>,
+        max_bytes: Option<u64>,
+        direction: &'static str,
+    ) -> LimitRead {
+        LimitRead {
+            inner,
+            state,
+            messages_left: max_messages,
+            bytes_left: max_bytes,
+            direction,
+        }
+    }
+
+    fn check_exhausted(&mut self) {
+        let what = if self.messages_left == Some(0) {
+            "messages"
+        } else if self.bytes_left == Some(0) {
+            "bytes"
+        } else {
+            return;
+        };
+        let mut st = self.state.borrow_mut();
+        if st.tripped.is_none() {
+            let reason = format!("--max-{}-{} reached", what, self.direction);
+            info!("{}, closing", reason);
+            super::metrics::set_end_reason(&reason);
+            st.tripped = Some(reason);
+            // The other direction is likely waiting for data; let it see the limit
+            futures::task::current().notify();
+        }
+    }
+}
+
+impl Read for LimitRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.check_exhausted();
+        if self.state.borrow().tripped.is_some() {
+            return Ok(0);
+        }
+        let l = match self.bytes_left {
+            Some(b) if (buf.len() as u64) > b => b as usize,
+            _ => buf.len(),
+        };
+        let n = self.inner.read(&mut buf[..l])?;
+        if n > 0 {
+            if let Some(ref mut m) = self.messages_left {
+                *m -= 1;
+            }
+            if let Some(ref mut b) = self.bytes_left {
+                *b -= n as u64;
+            }
+            self.check_exhausted();
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for LimitRead {}
+
+/// Writer that closes WebSocket peers with "normal closure" status after a limit is reached
+pub struct LimitWrite {
+    pub inner: Box<AsyncWrite>,
+    pub state: HLimitState,
+}
+
+impl Write for LimitWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.inner.flush()
+    }
+}
+impl AsyncWrite for LimitWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        if self.state.borrow().tripped.is_some() {
+            let inner = &mut self.inner;
+            return with_close_code(LIMIT_CLOSE_CODE, || inner.shutdown());
+        }
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,351 @@
+//! Process-wide graceful shutdown: stop accepting new sessions,
+//! optionally close the live ones, then wait for them up to `--drain-timeout`.
+//!
+//! The same can be requested for just one serve loop through its `Scope`.
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::ws_peer::with_close_code;
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// How often to check whether in-flight sessions have finished
+const DRAIN_CHECK_INTERVAL_MS: u64 = 100;
+
+#[derive(Default)]
+struct ShutdownState {
+    reason: Option<String>,
+    since: Option<Instant>,
+    waiters: Vec<Task>,
+    /// Set when live sessions are to be closed, with this WebSocket status code
+    close_code: Option<u16>,
+    /// Tasks of live sessions, to wake them up for closing
+    sessions: HashMap<u64, Task>,
+    /// Readers of sessions that have not ended yet, for draining a `Scope`
+    live_readers: usize,
+}
+
+impl ShutdownState {
+    /// Returns whether this is the first request
+    fn request(&mut self, reason: &str) -> bool {
+        if self.reason.is_some() {
+            return false;
+        }
+        self.reason = Some(reason.to_string());
+        self.since = Some(Instant::now());
+        for t in self.waiters.drain(..) {
+            t.notify();
+        }
+        true
+    }
+
+    /// Tasks of sessions to wake up for closing, empty if closing was already requested
+    fn close_sessions(&mut self, code: u16) -> Vec<Task> {
+        if self.close_code.is_some() {
+            return vec![];
+        }
+        self.close_code = Some(code);
+        self.sessions.drain().map(|(_, t)| t).collect()
+    }
+
+    fn poll_requested(&mut self) -> bool {
+        if self.reason.is_some() {
+            return true;
+        }
+        if !self.waiters.iter().any(|t| t.will_notify_current()) {
+            self.waiters.push(task::current());
+        }
+        false
+    }
+}
+
+/// Shutdown of a single serve loop, carried in `Options::shutdown_scope`.
+/// Process-wide shutdown applies to scoped serve loops as well.
+#[derive(Clone, Default)]
+pub struct Scope(Rc<RefCell<ShutdownState>>);
+
+impl ::std::fmt::Debug for Scope {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Scope({:?})", self.0.borrow().reason)
+    }
+}
+
+impl Scope {
+    /// Stop accepting new sessions in this scope. Only the first reason is remembered.
+    pub fn initiate(&self, reason: &str) {
+        if self.0.borrow_mut().request(reason) {
+            info!("Shutting down a serve loop: {}", reason);
+        }
+    }
+
+    /// Like `initiate`, also closing live sessions of this scope
+    pub fn initiate_and_close_sessions(&self, reason: &str, code: u16) {
+        let sessions = self.0.borrow_mut().close_sessions(code);
+        info!("Closing {} sessions", sessions.len());
+        for t in sessions {
+            t.notify();
+        }
+        self.initiate(reason);
+    }
+
+    pub fn reason(&self) -> Option<String> {
+        self.0.borrow().reason.clone()
+    }
+
+    /// Resolves after shutdown of this scope is requested and its sessions have finished,
+    /// or `timeout` after the request, whichever comes first
+    pub fn drained(&self, h: &Handle, timeout: Duration) -> Drained {
+        Drained {
+            timeout,
+            timer: None,
+            handle: h.clone(),
+            scope: Some(self.clone()),
+        }
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<ShutdownState> = RefCell::new(Default::default());
+    static NEXT_SESSION_ID: ::std::cell::Cell<u64> = ::std::cell::Cell::new(0);
+    static EXIT_HOOKS: RefCell<Vec<Box<Fn()>>> = RefCell::new(vec![]);
+    static FORWARD: RefCell<Vec<Rc<Fn(&str, Option<u16>)>>> = RefCell::new(vec![]);
+}
+
+/// Pass process-wide shutdown requests made on this thread on to other threads, like `--workers`.
+/// `f` gets the reason and, if live sessions are to be closed, the status code.
+pub fn forward<F: Fn(&str, Option<u16>) + 'static>(f: F) {
+    FORWARD.with(|x| x.borrow_mut().push(Rc::new(f)))
+}
+
+fn forward_request(reason: &str, close_code: Option<u16>) {
+    let fwd = FORWARD.with(|x| x.borrow().clone());
+    for f in fwd {
+        f(reason, close_code);
+    }
+}
+
+/// Register cleanup (like removing the pid file) to be done before the process exits,
+/// including exits caused by signals
+pub fn at_exit<F: Fn() + 'static>(f: F) {
+    EXIT_HOOKS.with(|h| h.borrow_mut().push(Box::new(f)))
+}
+
+/// Run and forget all hooks registered with `at_exit`
+pub fn run_exit_hooks() {
+    let hooks = EXIT_HOOKS.with(|h| ::std::mem::replace(&mut *h.borrow_mut(), vec![]));
+    for f in hooks {
+        f();
+    }
+}
+
+/// Request graceful shutdown. Only the first reason is remembered.
+pub fn initiate(reason: &str) {
+    if STATE.with(|s| s.borrow_mut().request(reason)) {
+        info!("Shutting down: {}", reason);
+        super::metrics::set_end_reason(reason);
+        super::sd_notify::stopping();
+        forward_request(reason, None);
+    }
+}
+
+/// Request graceful shutdown and also close all live sessions,
+/// sending Close frames with the given code to WebSocket peers
+pub fn initiate_and_close_sessions(reason: &str, code: u16) {
+    let sessions = STATE.with(|s| s.borrow_mut().close_sessions(code));
+    info!("Closing {} sessions", sessions.len());
+    for t in sessions {
+        t.notify();
+    }
+    forward_request(reason, Some(code));
+    initiate(reason);
+}
+
+/// Why shutdown was requested, if it was
+pub fn reason() -> Option<String> {
+    STATE.with(|s| s.borrow().reason.clone())
+}
+
+/// Like `reason().is_some()` (also for the scope, if any), but also arrange for the current task
+/// to be notified when shutdown gets requested
+fn poll_requested(scope: Option<&Scope>) -> bool {
+    if STATE.with(|s| s.borrow_mut().poll_requested()) {
+        return true;
+    }
+    scope.map_or(false, |x| x.0.borrow_mut().poll_requested())
+}
+
+/// Listener stream that ends when shutdown is requested, process-wide or for the scope
+pub struct UntilShutdown<S>(pub S, pub Option<Scope>);
+
+impl<S: Stream> Stream for UntilShutdown<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if poll_requested(self.1.as_ref()) {
+            info!("Not accepting new connections");
+            return Ok(Async::Ready(None));
+        }
+        self.0.poll()
+    }
+}
+
+/// Resolves after shutdown is requested and all sessions have finished,
+/// or `timeout` after the request, whichever comes first
+pub struct Drained {
+    timeout: Duration,
+    timer: Option<Timeout>,
+    handle: Handle,
+    scope: Option<Scope>,
+}
+
+pub fn drained(h: &Handle, timeout: Duration) -> Drained {
+    Drained {
+        timeout,
+        timer: None,
+        handle: h.clone(),
+        scope: None,
+    }
+}
+
+impl Future for Drained {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        let requested = match self.scope {
+            None => poll_requested(None),
+            Some(ref x) => x.0.borrow_mut().poll_requested(),
+        };
+        if !requested {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            let live = match self.scope {
+                None => super::metrics::live_sessions(),
+                // Both readers of a session are dropped when it ends
+                Some(ref x) => (x.0.borrow().live_readers + 1) as u64 / 2,
+            };
+            if live == 0 {
+                return Ok(Async::Ready(()));
+            }
+            let since = match self.scope {
+                None => STATE.with(|s| s.borrow().since),
+                Some(ref x) => x.0.borrow().since,
+            };
+            let since = since.unwrap_or_else(Instant::now);
+            if since.elapsed() >= self.timeout {
+                warn!("Drain timeout reached with {} sessions in progress", live);
+                return Ok(Async::Ready(()));
+            }
+            if self.timer.is_none() {
+                let t = Timeout::new(
+                    Duration::from_millis(DRAIN_CHECK_INTERVAL_MS),
+                    &self.handle,
+                ).map_err(|e| error!("{}", e))?;
+                self.timer = Some(t);
+            }
+            match self.timer.as_mut().unwrap().poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => self.timer = None,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+fn closing(scope: Option<&Scope>) -> Option<u16> {
+    STATE
+        .with(|s| s.borrow().close_code)
+        .or_else(|| scope.and_then(|x| x.0.borrow().close_code))
+}
+
+/// Whether live sessions are to be closed, for transfers that bypass `ShutdownRead`
+pub fn closing_requested(scope: Option<&Scope>) -> bool {
+    closing(scope).is_some()
+}
+
+/// Reader that reports EOF when live sessions are to be closed
+/// (so data already received gets delivered and the session ends normally).
+/// Registers the session's task when first read from.
+pub struct ShutdownRead {
+    inner: Box<AsyncRead>,
+    id: Option<u64>,
+    scope: Option<Scope>,
+}
+
+impl ShutdownRead {
+    pub fn new(inner: Box<AsyncRead>, scope: Option<Scope>) -> ShutdownRead {
+        if let Some(ref x) = scope {
+            x.0.borrow_mut().live_readers += 1;
+        }
+        ShutdownRead { inner, id: None, scope }
+    }
+}
+
+impl Read for ShutdownRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if closing(self.scope.as_ref()).is_some() {
+            return Ok(0);
+        }
+        if self.id.is_none() {
+            let id = NEXT_SESSION_ID.with(|n| {
+                n.set(n.get() + 1);
+                n.get()
+            });
+            STATE.with(|s| s.borrow_mut().sessions.insert(id, task::current()));
+            if let Some(ref x) = self.scope {
+                x.0.borrow_mut().sessions.insert(id, task::current());
+            }
+            self.id = Some(id);
+        }
+        self.inner.read(buf)
+    }
+}
+impl AsyncRead for ShutdownRead {}
+
+impl Drop for ShutdownRead {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            STATE.with(|s| s.borrow_mut().sessions.remove(&id));
+        }
+        if let Some(ref x) = self.scope {
+            let mut x = x.0.borrow_mut();
+            x.live_readers -= 1;
+            if let Some(id) = self.id {
+                x.sessions.remove(&id);
+            }
+        }
+    }
+}
+
+/// Writer that closes WebSocket peers with the shutdown status code
+pub struct ShutdownWrite(pub Box<AsyncWrite>, pub Option<Scope>);
+
+impl Write for ShutdownWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.flush()
+    }
+}
+impl AsyncWrite for ShutdownWrite {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        if let Some(code) = closing(self.1.as_ref()) {
+            let inner = &mut self.0;
+            return with_close_code(code, || inner.shutdown());
+        }
+        self.0.shutdown()
+    }
+}
@@ -1,66 +1,205 @@
+use super::error::WebsocatError;
 use super::{Result, Specifier, SpecifierClass};
 use std::rc::Rc;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
 
 pub fn spec(s: &str) -> Result<Rc<Specifier>> {
-    Specifier::from_str(s)
+    Specifier::from_str(s).map_err(|e| {
+        if e.is::<WebsocatError>() {
+            e
+        } else {
+            Box::new(WebsocatError::Specifier(e.to_string())) as Box<::std::error::Error>
+        }
+    })
 }
 
-impl Specifier {
-    fn from_str(s: &str) -> Result<Rc<Specifier>> {
-        #[cfg(not(feature = "ssl"))]
-        {
-            if s.starts_with("wss://") {
-                Err("SSL is not compiled in. Use ws:// or get/make another Websocat build.\nYou can also try to workaround missing SSL by using ws-c:cmd:socat trick (see some ws-c: example)")?
+/// What follows a specifier prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    NoArg,
+    /// Another specifier, like in `log:`
+    Subspecifier,
+    /// File system path, possibly followed by more, like in `prepend-file:`
+    Path,
+    Other,
+}
+
+/// Registered specifier class, for shell completion and other introspection
+#[derive(Debug, Clone)]
+pub struct ClassInfo {
+    pub name: &'static str,
+    pub prefixes: Vec<&'static str>,
+    pub arg: ArgKind,
+    pub help: &'static str,
+}
+
+type Registry = Mutex<Vec<Arc<SpecifierClass>>>;
+
+/// Filled with built-in classes on first use. Shared by all threads, `--workers` included.
+fn registry() -> &'static Registry {
+    static INIT: Once = ONCE_INIT;
+    static mut REGISTRY: *const Registry = 0 as *const Registry;
+    unsafe {
+        INIT.call_once(|| REGISTRY = Box::into_raw(Box::new(Mutex::new(builtin_classes()))));
+        &*REGISTRY
+    }
+}
+
+fn builtin_classes() -> Vec<Arc<SpecifierClass>> {
+    let mut r: Vec<Arc<SpecifierClass>> = vec![];
+    macro_rules! my {
+        ($x:expr) => {
+            r.push(Arc::new($x));
+        };
+    }
+    list_of_all_specifier_classes!(my);
+    r
+}
+
+/// Make specifier strings starting with one of the class's prefixes resolve to it.
+/// Prefixes are tried in registration order, built-in classes first.
+///
+/// The registry is process-wide: classes registered before `--workers` threads
+/// are started are available there as well.
+pub fn register_specifier_class(class: Arc<SpecifierClass>) {
+    registry().lock().unwrap().push(class);
+}
+
+/// Built-in classes in `--long-help` order, then the ones added by `register_specifier_class`
+pub fn registered_classes() -> Vec<Arc<SpecifierClass>> {
+    registry().lock().unwrap().clone()
+}
+
+/// All specifier classes, in `--long-help` order
+pub fn specifier_classes() -> Vec<ClassInfo> {
+    registered_classes()
+        .into_iter()
+        .map(|c| {
+            ClassInfo {
+                name: c.get_name(),
+                prefixes: c.get_prefixes(),
+                arg: c.arg_kind(),
+                help: c.help(),
+            }
+        })
+        .collect()
+}
+
+/// One level of a specifier string: class name, the full string at this level and the argument
+pub type SpecLevel = (&'static str, String, String);
+
+fn spec_level(s: &str) -> Option<(SpecLevel, bool)> {
+    for x in registered_classes() {
+        for pre in x.get_prefixes() {
+            if s.starts_with(pre) {
+                let rest = &s[pre.len()..];
+                let has_sub = match x.construct(s, rest) {
+                    Ok(x) => x.get_info().subspecifier.is_some(),
+                    Err(_) => false,
+                };
+                return Some(((x.get_name(), s.to_string(), rest.to_string()), has_sub));
             }
         }
+    }
+    None
+}
 
-        #[cfg(not(any(target_os = "linux", target_os = "android")))]
-        {
-            if s.starts_with("abstract") {
-                warn!("Abstract-namespaced UNIX sockets are unlikely to be supported here");
+/// Walk a specifier string without constructing anything beyond the specifiers themselves.
+/// Returns levels outermost first, up to the first one that does not parse.
+pub fn spec_chain(s: &str) -> Vec<SpecLevel> {
+    let mut r = vec![];
+    let mut s = s.to_string();
+    while let Some((level, has_sub)) = spec_level(&s) {
+        let rest = level.2.clone();
+        r.push(level);
+        if !has_sub {
+            break;
+        }
+        // Subspecifier is either the whole argument or follows `<arg>:`, like in `prepend-file:`
+        s = if spec_level(&rest).is_some() {
+            rest
+        } else {
+            match rest.find(':') {
+                Some(i) => rest[i + 1..].to_string(),
+                None => break,
             }
+        };
+    }
+    r
+}
+
+/// Shorthands that stand for a nested specifier string
+pub fn expand_shorthand(s: &str) -> Option<String> {
+    if s == "inetd-ws:" {
+        Some("ws-l:inetd:".to_string())
+    } else if s.starts_with("l-ws-unix:") {
+        Some(format!("ws-l:unix-l:{}", &s[10..]))
+    } else if s.starts_with("l-ws-abstract:") {
+        Some(format!("ws-l:abstract-l::{}", &s[14..]))
+    } else if s.starts_with("wss-l:") {
+        Some(format!("ws-l:ssl-accept:tcp-l:{}", &s[6..]))
+    } else {
+        None
+    }
+}
+
+/// Specifier strings this build knows it can't handle, with the reason
+pub fn unsupported(s: &str) -> Option<&'static str> {
+    #[cfg(not(feature = "ssl"))]
+    {
+        if s.starts_with("wss://") || s.starts_with("wss-l:") || s.starts_with("ssl-accept:") {
+            return Some("SSL is not compiled in. Use ws:// or get/make another Websocat build.\nYou can also try to workaround missing SSL by using ws-c:cmd:socat trick (see some ws-c: example)");
         }
+    }
 
-        macro_rules! my {
-            ($x:expr) => {
-                for pre in $x.get_prefixes() {
-                    if s.starts_with(pre) {
-                        let rest = &s[pre.len()..];
-                        return $x.construct(s, rest);
-                    }
-                }
-            };
+    if s.starts_with("open:") {
+        return Some("There is no `open:` specifier. Consider `open-async:` or `readfile:` or `writefile:` or `appendfile:`");
+    }
+
+    #[cfg(not(unix))]
+    {
+        if s.starts_with("unix") || s.starts_with("abstract") {
+            return Some("`unix*:` or `abstract*:` are not supported in this Websocat build");
         }
-        list_of_all_specifier_classes!(my);
-
-        if s == "inetd-ws:" {
-            return spec("ws-l:inetd:");
-        } else if s.starts_with("l-ws-unix:") {
-            return spec(&format!("ws-l:unix-l:{}", &s[10..]));
-        } else if s.starts_with("l-ws-abstract:") {
-            return spec(&format!("ws-l:abstract-l::{}", &s[14..]));
+    }
+
+    #[cfg(not(feature = "tokio-process"))]
+    {
+        if s.starts_with("sh-c:") {
+            return Some("`sh-c:` is not supported in this Websocat build");
+        } else if s.starts_with("exec:") {
+            return Some("`exec:` is not supported in this Websocat build");
         }
+    }
+    None
+}
 
-        if s.starts_with("open:") {
-            return Err("There is no `open:` specifier. Consider `open-async:` or `readfile:` or `writefile:` or `appendfile:`")?;
+impl Specifier {
+    fn from_str(s: &str) -> Result<Rc<Specifier>> {
+        if let Some(e) = unsupported(s) {
+            Err(e)?
         }
 
-        #[cfg(not(unix))]
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
         {
-            if s.starts_with("unix") || s.starts_with("abstract") {
-                Err("`unix*:` or `abstract*:` are not supported in this Websocat build")?
+            if s.starts_with("abstract") {
+                warn!("Abstract-namespaced UNIX sockets are unlikely to be supported here");
             }
         }
 
-        #[cfg(not(feature = "tokio-process"))]
-        {
-            if s.starts_with("sh-c:") {
-                Err("`sh-c:` is not supported in this Websocat build")?
-            } else if s.starts_with("exec:") {
-                Err("`exec:` is not supported in this Websocat build")?
+        for x in registered_classes() {
+            for pre in x.get_prefixes() {
+                if s.starts_with(pre) {
+                    let rest = &s[pre.len()..];
+                    return x.construct(s, rest);
+                }
             }
         }
 
+        if let Some(x) = expand_shorthand(s) {
+            return spec(&x);
+        }
+
         error!(
             "Invalid specifier string `{}`. See --long-help for the list of specifiers",
             s
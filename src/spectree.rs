@@ -0,0 +1,243 @@
+//! Parsing specifier strings into a tree that can be inspected without running anything,
+//! for tools that generate websocat command lines.
+
+use std::fmt;
+
+use super::specparse::{expand_shorthand, registered_classes, unsupported, ArgKind};
+use super::{OneSpecifierInfo, SpecifierClass, SpecifierType};
+
+/// One level of a parsed specifier string, with overlays on top of what they wrap.
+/// For example, `autoreconnect:tcp:127.0.0.1:80` is `AutoReconnectClass` wrapping `TcpConnectClass`.
+#[derive(Debug, Clone)]
+pub struct SpecTree {
+    /// Like `TcpConnectClass`
+    pub class: &'static str,
+    /// Prefix as written, like `tcp-c:`
+    pub prefix: &'static str,
+    /// Argument of this level, without the subspecifier: `127.0.0.1:80` in `tcp:127.0.0.1:80`,
+    /// `greeting.txt` in `prepend-file:greeting.txt:-`, empty in `log:-`
+    pub arg: String,
+    pub sub: Option<Box<SpecTree>>,
+    pub info: OneSpecifierInfo,
+}
+
+/// Why a specifier string did not parse
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Byte offset in the whole string where the offending part starts
+    pub position: usize,
+    pub message: String,
+    /// Known prefix the unknown one is probably a misspelling of
+    pub suggestion: Option<&'static str>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at position {})", self.message, self.position)?;
+        if let Some(x) = self.suggestion {
+            write!(f, ". Did you mean `{}`?", x)?;
+        }
+        Ok(())
+    }
+}
+
+impl ::std::error::Error for ParseError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Parse a specifier string like `ws-l:127.0.0.1:8080` or `reuse:log:-`
+pub fn parse(s: &str) -> Result<SpecTree, ParseError> {
+    parse_at(s, 0)
+}
+
+fn find_class(s: &str) -> Option<(::std::sync::Arc<SpecifierClass>, &'static str)> {
+    for x in registered_classes() {
+        for pre in x.get_prefixes() {
+            if s.starts_with(pre) {
+                return Some((x, pre));
+            }
+        }
+    }
+    None
+}
+
+fn parse_at(s: &str, offset: usize) -> Result<SpecTree, ParseError> {
+    let error = |position: usize, message: String| ParseError {
+        position,
+        message,
+        suggestion: None,
+    };
+    if let Some(e) = unsupported(s) {
+        return Err(error(offset, e.to_string()));
+    }
+    let (class, prefix) = match find_class(s) {
+        Some(x) => x,
+        None => {
+            if let Some(x) = expand_shorthand(s) {
+                return parse_at(&x, offset);
+            }
+            return Err(ParseError {
+                position: offset,
+                message: format!("Unknown specifier `{}`", s),
+                suggestion: suggest(s),
+            });
+        }
+    };
+    let rest = &s[prefix.len()..];
+    let spec = match class.construct(s, rest) {
+        Ok(x) => x,
+        Err(e) => {
+            // Blame the innermost level that does not parse
+            if class.arg_kind() == ArgKind::Subspecifier {
+                parse_at(rest, offset + prefix.len())?;
+            }
+            return Err(error(offset + prefix.len(), e.to_string()));
+        }
+    };
+    let info = spec.get_info();
+    let (arg, sub) = if info.subspecifier.is_none() {
+        (rest.to_string(), None)
+    } else if find_class(rest).is_some() || expand_shorthand(rest).is_some() {
+        (String::new(), Some(Box::new(parse_at(rest, offset + prefix.len())?)))
+    } else {
+        // Subspecifier follows `<arg>:`, like in `prepend-file:`, or is implied, like in `ws-l:127.0.0.1:80`
+        let split = rest.find(':').and_then(|i| {
+            parse_at(&rest[i + 1..], offset + prefix.len() + i + 1)
+                .ok()
+                .map(|sub| (rest[..i].to_string(), Some(Box::new(sub))))
+        });
+        split.unwrap_or_else(|| (rest.to_string(), None))
+    };
+    Ok(SpecTree {
+        class: class.get_name(),
+        prefix,
+        arg,
+        sub,
+        info: info.this,
+    })
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let x = (prev[j] + if ca == cb { 0 } else { 1 }).min(prev[j + 1] + 1).min(cur[j] + 1);
+            cur.push(x);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Closest known prefix to what `s` starts with, if it is close enough to be a typo
+fn suggest(s: &str) -> Option<&'static str> {
+    let word = match (s.find("://"), s.find(':')) {
+        (Some(i), _) => &s[..i + 3],
+        (None, Some(i)) => &s[..i + 1],
+        (None, None) => s,
+    };
+    let mut best: Option<(usize, &'static str)> = None;
+    for class in registered_classes() {
+        for pre in class.get_prefixes() {
+            let d = edit_distance(word, pre);
+            if d <= 2 && d < pre.len() && best.map_or(true, |(bd, _)| d < bd) {
+                best = Some((d, pre));
+            }
+        }
+    }
+    best.map(|(_, pre)| pre)
+}
+
+/// Command line options a registered class pays attention to
+pub fn consumed_options(class: &str) -> &'static [&'static str] {
+    registered_classes()
+        .into_iter()
+        .find(|c| c.get_name() == class)
+        .map_or(&[], |c| c.consumed_options())
+}
+
+/// Prefixes of these classes are not interchangeable, so the one written is kept
+fn prefix_matters(class: &str) -> bool {
+    match class {
+        "WsClientClass" | "WssClientClass" => true,
+        _ => false,
+    }
+}
+
+impl SpecTree {
+    /// Levels from the outermost overlay to the innermost specifier
+    pub fn levels(&self) -> Vec<&SpecTree> {
+        let mut r = vec![self];
+        let mut x = self;
+        while let Some(ref sub) = x.sub {
+            r.push(sub);
+            x = sub;
+        }
+        r
+    }
+
+    /// Class names from the outermost overlay to the innermost specifier
+    pub fn overlay_chain(&self) -> Vec<&'static str> {
+        self.levels().iter().map(|x| x.class).collect()
+    }
+
+    /// Accepts multiple connections, like `tcp-l:`
+    pub fn is_listener(&self) -> bool {
+        self.info.multiconnect
+    }
+
+    /// Data comes in messages, not as a byte stream: there is a WebSocket or line-to-message level
+    pub fn is_message_oriented(&self) -> bool {
+        self.levels().iter().any(|x| x.info.typ == SpecifierType::WebSocket || x.info.typ == SpecifierType::Line)
+    }
+
+    pub fn uses_stdio(&self) -> bool {
+        self.levels().iter().any(|x| x.info.typ == SpecifierType::Stdio)
+    }
+
+    pub fn uses_global_state(&self) -> bool {
+        self.levels().iter().any(|x| x.info.uses_global_state)
+    }
+
+    /// Command line options that affect this specifier string
+    pub fn consumed_options(&self) -> Vec<&'static str> {
+        let mut r = vec![];
+        for level in self.levels() {
+            for &o in consumed_options(level.class) {
+                if !r.contains(&o) {
+                    r.push(o);
+                }
+            }
+        }
+        r
+    }
+
+    fn canonical_prefix(&self) -> &'static str {
+        if prefix_matters(self.class) {
+            return self.prefix;
+        }
+        registered_classes()
+            .into_iter()
+            .find(|c| c.get_name() == self.class)
+            .and_then(|c| c.get_prefixes().into_iter().next())
+            .unwrap_or(self.prefix)
+    }
+}
+
+/// Canonical form: primary prefix of each class
+impl fmt::Display for SpecTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.canonical_prefix(), self.arg)?;
+        if let Some(ref sub) = self.sub {
+            if !self.arg.is_empty() {
+                write!(f, ":")?;
+            }
+            write!(f, "{}", sub)?;
+        }
+        Ok(())
+    }
+}
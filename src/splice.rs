@@ -0,0 +1,524 @@
+//! Zero-copy forwarding with `splice(2)` when both sides of a session are plain byte streams
+//! like `tcp:` or `unix:`, and with `sendfile(2)` from `readfile:` to such a socket. Linux only.
+//!
+//! Data moves from one socket to the other through a pipe without being copied to userspace.
+//! Any overlay (`log:`, `throttle:`, WebSocket, session options that look at data)
+//! hides the file descriptor and the session takes the generic `my_copy` path.
+//! `--no-splice` forces the generic path as well.
+
+use std::cell::Cell;
+use std::io;
+
+use futures::{Future, Poll};
+
+use super::my_copy::{self, BufferSettings, FlushPolicy};
+use super::shutdown::{ShutdownRead, ShutdownWrite};
+use super::{budget, events, metrics, Options};
+
+#[cfg(unix)]
+pub use std::os::unix::io::RawFd;
+#[cfg(not(unix))]
+pub type RawFd = i32;
+
+thread_local! {
+    static ZERO_COPY_BYTES: Cell<u64> = Cell::new(0);
+}
+
+/// Bytes moved by `splice(2)` and `sendfile(2)` on this thread so far
+pub fn zero_copy_bytes() -> u64 {
+    ZERO_COPY_BYTES.with(|x| x.get())
+}
+
+#[cfg(all(target_os = "linux", feature = "libc"))]
+fn count_zero_copy(n: usize) {
+    ZERO_COPY_BYTES.with(|x| x.set(x.get() + n as u64));
+}
+
+/// Descriptor behind a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerFd {
+    pub fd: RawFd,
+    /// Only reading goes through `fd`, like with `readfile:`
+    pub read_only: bool,
+}
+
+/// Descriptors to forward between, if both sides of this direction have them
+pub fn pair(from: Option<PeerFd>, to: Option<PeerFd>) -> Option<(RawFd, RawFd)> {
+    match (from, to) {
+        (Some(f), Some(t)) if !t.read_only => Some((f.fd, t.fd)),
+        _ => None,
+    }
+}
+
+/// Whether session options let data bypass websocat
+pub fn enabled(opts: &Options) -> bool {
+    cfg!(all(target_os = "linux", feature = "libc")) && !opts.no_splice && transparent(opts)
+}
+
+/// Whether nothing in the session looks at the data or at how it is split into reads
+pub fn transparent(opts: &Options) -> bool {
+    !opts.one_message
+        && opts.idle_timeout.map_or(true, |x| x == 0)
+        && !opts.dedup_consecutive
+        && opts.dedup_window.is_none()
+        && opts.batch_window_ms.is_none()
+        && opts.filter_in_regex.is_none()
+        && opts.filter_out_regex.is_none()
+        && opts.rewrite.is_empty()
+        && opts.middleware.incoming.is_empty()
+        && opts.middleware.outgoing.is_empty()
+        && opts.max_messages_in.is_none()
+        && opts.max_messages_out.is_none()
+        && opts.max_bytes_in.is_none()
+        && opts.max_bytes_out.is_none()
+        && !metrics::needed(opts)
+        && !events::tracking(opts)
+        && !budget::enabled(opts)
+        && !opts.include_headers
+        && !opts.include_headers_every_connect
+}
+
+/// One direction of a session: `splice(2)` or `sendfile(2)` if `fds` (from, to) are given,
+/// the usual loop otherwise.
+///
+/// The outermost layers of the session's halves are always `ShutdownRead` and `ShutdownWrite`,
+/// taken here as they are instead of being boxed once more.
+pub enum Copy {
+    Generic(my_copy::Copy<ShutdownRead, ShutdownWrite>),
+    #[cfg(all(target_os = "linux", feature = "libc"))]
+    Spliced(imp::Splice),
+    #[cfg(all(target_os = "linux", feature = "libc"))]
+    Sent(imp::Sendfile),
+}
+
+/// `file_max_bytes` limits what is sent from a regular file,
+/// which is expected to be positioned at `--file-start-offset` already
+pub fn copy(
+    from: ShutdownRead,
+    to: ShutdownWrite,
+    once: bool,
+    flush: FlushPolicy,
+    buffering: BufferSettings,
+    fds: Option<(RawFd, RawFd)>,
+    file_max_bytes: Option<u64>,
+    scope: Option<super::shutdown::Scope>,
+) -> Copy {
+    #[cfg(all(target_os = "linux", feature = "libc"))]
+    {
+        match fds {
+            Some(fds) if imp::is_regular_file(fds.0) => {
+                if imp::is_socket(fds.1) {
+                    debug!("Sending the file with sendfile(2)");
+                    return Copy::Sent(imp::Sendfile::new(from, to, fds, file_max_bytes, scope));
+                }
+            }
+            Some(fds) => match imp::Pipe::new() {
+                Ok(pipe) => {
+                    debug!("Forwarding with splice(2)");
+                    return Copy::Spliced(imp::Splice::new(from, to, fds, pipe, scope));
+                }
+                Err(e) => debug!("Can't create a pipe for splice(2): {}", e),
+            },
+            None => (),
+        }
+    }
+    let _ = (fds, file_max_bytes, scope);
+    Copy::Generic(my_copy::copy(from, to, true, once, flush, buffering))
+}
+
+impl Future for Copy {
+    type Item = (u64, ShutdownRead, ShutdownWrite);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        match *self {
+            Copy::Generic(ref mut x) => x.poll(),
+            #[cfg(all(target_os = "linux", feature = "libc"))]
+            Copy::Spliced(ref mut x) => x.poll(),
+            #[cfg(all(target_os = "linux", feature = "libc"))]
+            Copy::Sent(ref mut x) => x.poll(),
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "libc"))]
+mod imp {
+    extern crate libc;
+
+    use std::io;
+    use std::io::{Read, Write};
+    use std::ptr;
+
+    use futures::{Async, Future, Poll};
+
+    use super::super::shutdown::{self, Scope, ShutdownRead, ShutdownWrite};
+    use super::RawFd;
+
+    /// How much to move with one `splice` call, the default pipe capacity
+    const CHUNK: usize = 65536;
+    /// How much `sendfile` moves in one `poll`, so that other sessions get their turn
+    const SENDFILE_PER_POLL: u64 = 4 << 20;
+
+    fn file_type(fd: RawFd) -> libc::mode_t {
+        let mut st: libc::stat = unsafe { ::std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut st) } == -1 {
+            return 0;
+        }
+        st.st_mode & libc::S_IFMT
+    }
+
+    pub fn is_regular_file(fd: RawFd) -> bool {
+        file_type(fd) == libc::S_IFREG
+    }
+
+    pub fn is_socket(fd: RawFd) -> bool {
+        file_type(fd) == libc::S_IFSOCK
+    }
+
+    pub struct Pipe {
+        r: RawFd,
+        w: RawFd,
+    }
+
+    impl Pipe {
+        pub fn new() -> io::Result<Pipe> {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Pipe { r: fds[0], w: fds[1] })
+        }
+    }
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.r);
+                libc::close(self.w);
+            }
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        let ret = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    /// `splice` does not work for these descriptors, plain `read` and `write` should be used
+    fn unsupported(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::EINVAL) || e.raw_os_error() == Some(libc::ENOSYS)
+    }
+
+    /// Moves data kernel-side while the sockets are ready.
+    ///
+    /// Readiness is tracked by the reactor through `reader` and `writer`, which `splice`
+    /// bypasses. So when `splice` would block, the same is tried through them: a read into `buf`,
+    /// or a write of one byte taken out of the pipe. That registers interest, and whatever
+    /// they manage to transfer is written out of `buf` before splicing resumes.
+    pub struct Splice {
+        reader: Option<ShutdownRead>,
+        writer: Option<ShutdownWrite>,
+        from: RawFd,
+        to: RawFd,
+        /// `None` after falling back to `read`/`write`
+        pipe: Option<Pipe>,
+        /// Bytes in the pipe
+        in_pipe: usize,
+        buf: Box<[u8]>,
+        pos: usize,
+        cap: usize,
+        amt: u64,
+        read_done: bool,
+        /// The first read goes through `reader`, so the session gets registered for shutdown
+        started: bool,
+        scope: Option<Scope>,
+    }
+
+    impl Splice {
+        pub fn new(
+            reader: ShutdownRead,
+            writer: ShutdownWrite,
+            fds: (RawFd, RawFd),
+            pipe: Pipe,
+            scope: Option<Scope>,
+        ) -> Splice {
+            Splice {
+                reader: Some(reader),
+                writer: Some(writer),
+                from: fds.0,
+                to: fds.1,
+                pipe: Some(pipe),
+                in_pipe: 0,
+                buf: vec![0; CHUNK].into_boxed_slice(),
+                pos: 0,
+                cap: 0,
+                amt: 0,
+                read_done: false,
+                started: false,
+                scope,
+            }
+        }
+
+        /// Move bytes from the front of the pipe into `buf`
+        fn take_from_pipe(&mut self, n: usize) -> io::Result<()> {
+            let pipe_r = self.pipe.as_ref().unwrap().r;
+            let n = n.min(self.buf.len());
+            let ret = unsafe { libc::read(pipe_r, self.buf.as_mut_ptr() as *mut libc::c_void, n) };
+            if ret <= 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.pos = 0;
+            self.cap = ret as usize;
+            self.in_pipe -= self.cap;
+            Ok(())
+        }
+
+        /// Continue with `read` and `write`, starting with what is left in the pipe
+        fn fall_back(&mut self, e: &io::Error) -> io::Result<()> {
+            debug!("splice(2) is not usable here, copying: {}", e);
+            if self.in_pipe > 0 {
+                // Never more than `CHUNK`, it fits
+                let n = self.in_pipe;
+                self.take_from_pipe(n)?;
+            }
+            self.pipe = None;
+            Ok(())
+        }
+    }
+
+    impl Future for Splice {
+        type Item = (u64, ShutdownRead, ShutdownWrite);
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+            loop {
+                // Bytes in `buf` came out of the pipe or were read later than its contents
+                while self.pos < self.cap {
+                    let i = try_nb!(self.writer.as_mut().unwrap().write(&self.buf[self.pos..self.cap]));
+                    if i == 0 {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero, "write zero byte into writer"));
+                    }
+                    self.pos += i;
+                    self.amt += i as u64;
+                }
+
+                if self.in_pipe > 0 {
+                    let pipe_r = self.pipe.as_ref().unwrap().r;
+                    match splice(pipe_r, self.to, self.in_pipe) {
+                        Ok(n) => {
+                            self.in_pipe -= n;
+                            self.amt += n as u64;
+                            super::count_zero_copy(n);
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.take_from_pipe(1)?,
+                        Err(ref e) if unsupported(e) => self.fall_back(e)?,
+                        Err(e) => return Err(e),
+                    }
+                    continue;
+                }
+
+                if !self.read_done && shutdown::closing_requested(self.scope.as_ref()) {
+                    debug!("Closing a spliced session");
+                    self.read_done = true;
+                }
+
+                if self.read_done {
+                    try_nb!(self.writer.as_mut().unwrap().flush());
+                    let reader = self.reader.take().unwrap();
+                    let writer = self.writer.take().unwrap();
+                    debug!("done");
+                    return Ok(Async::Ready((self.amt, reader, writer)));
+                }
+
+                if self.started && self.pipe.is_some() {
+                    let pipe_w = self.pipe.as_ref().unwrap().w;
+                    match splice(self.from, pipe_w, CHUNK) {
+                        Ok(0) => {
+                            debug!("zero len");
+                            self.read_done = true;
+                            continue;
+                        }
+                        Ok(n) => {
+                            self.in_pipe = n;
+                            continue;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                            debug!("BrokenPipe: read_done");
+                            self.read_done = true;
+                            continue;
+                        }
+                        Err(ref e) if unsupported(e) => self.fall_back(e)?,
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                self.started = true;
+                let rr = self.reader.as_mut().unwrap().read(&mut self.buf);
+                if let Err(ref e) = rr {
+                    if e.kind() == io::ErrorKind::BrokenPipe {
+                        debug!("BrokenPipe: read_done");
+                        self.read_done = true;
+                        continue;
+                    }
+                }
+                let n = try_nb!(rr);
+                if n == 0 {
+                    debug!("zero len");
+                    self.read_done = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                }
+            }
+        }
+    }
+    fn sendfile(to: RawFd, from: RawFd, len: usize) -> io::Result<usize> {
+        let ret = unsafe { libc::sendfile(to, from, ptr::null_mut(), len) };
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    /// Sends a regular file from its current position, like `Splice` does with sockets.
+    ///
+    /// When the socket is not ready, a chunk of the file is read through `reader`
+    /// and written through `writer` instead, which registers interest.
+    pub struct Sendfile {
+        reader: Option<ShutdownRead>,
+        writer: Option<ShutdownWrite>,
+        from: RawFd,
+        to: RawFd,
+        /// Left to send, for `--file-max-bytes`
+        remaining: Option<u64>,
+        buf: Box<[u8]>,
+        pos: usize,
+        cap: usize,
+        amt: u64,
+        read_done: bool,
+        /// The first read goes through `reader`, so the session gets registered for shutdown
+        started: bool,
+        /// `sendfile` does not work here, only `reader` is used
+        fallen_back: bool,
+        scope: Option<Scope>,
+    }
+
+    impl Sendfile {
+        pub fn new(
+            reader: ShutdownRead,
+            writer: ShutdownWrite,
+            fds: (RawFd, RawFd),
+            remaining: Option<u64>,
+            scope: Option<Scope>,
+        ) -> Sendfile {
+            Sendfile {
+                reader: Some(reader),
+                writer: Some(writer),
+                from: fds.0,
+                to: fds.1,
+                remaining,
+                buf: vec![0; CHUNK].into_boxed_slice(),
+                pos: 0,
+                cap: 0,
+                amt: 0,
+                read_done: false,
+                started: false,
+                fallen_back: false,
+                scope,
+            }
+        }
+    }
+
+    impl Future for Sendfile {
+        type Item = (u64, ShutdownRead, ShutdownWrite);
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+            let mut budget = SENDFILE_PER_POLL;
+            loop {
+                while self.pos < self.cap {
+                    let i = try_nb!(self.writer.as_mut().unwrap().write(&self.buf[self.pos..self.cap]));
+                    if i == 0 {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero, "write zero byte into writer"));
+                    }
+                    self.pos += i;
+                    self.amt += i as u64;
+                }
+
+                if !self.read_done && shutdown::closing_requested(self.scope.as_ref()) {
+                    debug!("Closing a session sending a file");
+                    self.read_done = true;
+                }
+                if self.remaining == Some(0) {
+                    self.read_done = true;
+                }
+
+                if self.read_done {
+                    try_nb!(self.writer.as_mut().unwrap().flush());
+                    let reader = self.reader.take().unwrap();
+                    let writer = self.writer.take().unwrap();
+                    debug!("done");
+                    return Ok(Async::Ready((self.amt, reader, writer)));
+                }
+
+                let limit = self.remaining.map_or(CHUNK, |x| x.min(CHUNK as u64) as usize);
+                if self.started && !self.fallen_back {
+                    if budget == 0 {
+                        ::futures::task::current().notify();
+                        return Ok(Async::NotReady);
+                    }
+                    match sendfile(self.to, self.from, limit.min(budget as usize)) {
+                        Ok(0) => {
+                            debug!("zero len");
+                            self.read_done = true;
+                            continue;
+                        }
+                        Ok(n) => {
+                            self.amt += n as u64;
+                            super::count_zero_copy(n);
+                            budget -= n as u64;
+                            if let Some(ref mut x) = self.remaining {
+                                *x -= n as u64;
+                            }
+                            continue;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(ref e) if unsupported(e) => {
+                            debug!("sendfile(2) is not usable here, copying: {}", e);
+                            self.fallen_back = true;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                self.started = true;
+                let rr = self.reader.as_mut().unwrap().read(&mut self.buf[..limit]);
+                if let Err(ref e) = rr {
+                    if e.kind() == io::ErrorKind::BrokenPipe {
+                        debug!("BrokenPipe: read_done");
+                        self.read_done = true;
+                        continue;
+                    }
+                }
+                let n = try_nb!(rr);
+                if n == 0 {
+                    debug!("zero len");
+                    self.read_done = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                    if let Some(ref mut x) = self.remaining {
+                        *x -= n as u64;
+                    }
+                }
+            }
+        }
+    }
+}
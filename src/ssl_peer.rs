@@ -0,0 +1,222 @@
+//! TLS server side: `ssl-accept:` and the `wss-l:` shorthand.
+//!
+//! Certificates are loaded from `--pkcs12-der` once per process and shared by
+//! all `--workers` threads. `reload` (SIGHUP) loads them again; handshakes that
+//! start afterwards use the new certificate, established sessions are left alone.
+
+extern crate native_tls;
+#[cfg(all(unix, not(target_os = "macos")))]
+extern crate openssl;
+extern crate tokio_tls;
+
+use self::native_tls::{Pkcs12, TlsAcceptor};
+use self::tokio_tls::TlsAcceptorExt;
+use futures::future::Future;
+use tokio_io::AsyncRead;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Mutex, Once, ONCE_INIT};
+
+use super::error::WebsocatError;
+use super::ws_peer::PeerForWs;
+use super::{box_up_err, once, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+#[derive(Debug)]
+pub struct SslAccept<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for SslAccept<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let path = match load_once(&cp.program_options) {
+            Ok(x) => x,
+            Err(e) => return once(Box::new(::futures::future::err(e)) as BoxedNewPeerFuture),
+        };
+        let inner = self.0.construct(cp);
+        inner.map(move |p| ssl_accept_peer(p, &path))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = SslAcceptClass,
+    target = SslAccept,
+    prefixes = ["ssl-accept:"],
+    arg_handling = subspec,
+    consumed_options = &["--pkcs12-der", "--pkcs12-passwd"],
+    help = r#"
+Accept a TLS connection using arbitrary backing stream.
+Only in websocat builds with `ssl` feature.
+
+The certificate and key are taken from a PKCS#12 archive given by --pkcs12-der
+(and --pkcs12-passwd). On SIGHUP the archive is read again: new connections
+get the new certificate, established ones keep going. If the new archive can't
+be loaded, the old certificate stays in use and an error is logged.
+
+Example: The same as in `wss-l:` example below, but longer
+
+    websocat -E -t ws-l:ssl-accept:tcp-l:127.0.0.1:1443 mirror: --pkcs12-der=q.pkcs12
+
+`wss-l:` is a shorthand for `ws-l:ssl-accept:tcp-l:`.
+
+Example: Echo server over secure websocket
+
+    websocat -E -t wss-l:127.0.0.1:1443 mirror: --pkcs12-der=q.pkcs12
+"#
+);
+
+/// Acceptors by `--pkcs12-der` path, with the password to load them again
+type Registry = Mutex<HashMap<PathBuf, (String, TlsAcceptor)>>;
+
+fn registry() -> &'static Registry {
+    static INIT: Once = ONCE_INIT;
+    static mut REGISTRY: *const Registry = 0 as *const Registry;
+    unsafe {
+        INIT.call_once(|| REGISTRY = Box::into_raw(Box::new(Mutex::new(HashMap::new()))));
+        &*REGISTRY
+    }
+}
+
+/// Make sure the certificate named by the options is loaded, returning its path
+fn load_once(opts: &Options) -> super::Result<PathBuf> {
+    let path = match opts.pkcs12_der {
+        Some(ref x) => x.clone(),
+        None => Err("ssl-accept: requires --pkcs12-der")?,
+    };
+    let passwd = opts.pkcs12_passwd.clone().unwrap_or_default();
+    let mut r = registry().lock().unwrap();
+    if !r.contains_key(&path) {
+        let (acceptor, details) = load(&path, &passwd)?;
+        info!("Loaded TLS certificate from {}: {}", path.display(), details);
+        r.insert(path.clone(), (passwd, acceptor));
+    }
+    Ok(path)
+}
+
+/// Read a PKCS#12 archive. Building the acceptor checks that the key matches the certificate.
+fn load(path: &Path, passwd: &str) -> super::Result<(TlsAcceptor, String)> {
+    let der = ::std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let pkcs12 = Pkcs12::from_der(&der, passwd)?;
+    let acceptor = TlsAcceptor::builder(pkcs12)?.build()?;
+    Ok((acceptor, describe(&der, passwd)))
+}
+
+/// Fingerprint and expiry of the certificate, for the log
+#[cfg(all(unix, not(target_os = "macos")))]
+fn describe(der: &[u8], passwd: &str) -> String {
+    use self::openssl::hash::MessageDigest;
+    use self::openssl::pkcs12::Pkcs12;
+    let parsed = match Pkcs12::from_der(der).and_then(|x| x.parse(passwd)) {
+        Ok(x) => x,
+        Err(e) => return format!("can't inspect the certificate: {}", e),
+    };
+    let fingerprint = match parsed.cert.digest(MessageDigest::sha256()) {
+        Ok(x) => x.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":"),
+        Err(e) => return format!("can't inspect the certificate: {}", e),
+    };
+    format!("SHA-256 fingerprint {}, expires {}", fingerprint, parsed.cert.not_after())
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn describe(_der: &[u8], _passwd: &str) -> String {
+    "certificate details are not available on this platform".to_string()
+}
+
+/// Check that the key belongs to the certificate and that the certificate verifies
+/// against the other certificates of the archive, or is self-signed if there are none.
+/// This also catches expired certificates.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn verify_chain(der: &[u8], passwd: &str) -> Result<(), String> {
+    use self::openssl::pkcs12::Pkcs12;
+    use self::openssl::stack::Stack;
+    use self::openssl::x509::store::X509StoreBuilder;
+    use self::openssl::x509::X509StoreContext;
+    let e = |e: self::openssl::error::ErrorStack| e.to_string();
+    let parsed = Pkcs12::from_der(der).and_then(|x| x.parse(passwd)).map_err(e)?;
+    if !parsed.cert.public_key().map_err(e)?.public_eq(&parsed.pkey) {
+        return Err("the private key does not match the certificate".to_string());
+    }
+    let mut store = X509StoreBuilder::new().map_err(e)?;
+    let mut untrusted = Stack::new().map_err(e)?;
+    match parsed.chain {
+        Some(chain) if chain.len() > 0 => {
+            // The last one is the root, or the closest to it the archive has
+            let mut chain: Vec<_> = chain.into_iter().collect();
+            store.add_cert(chain.pop().unwrap()).map_err(e)?;
+            for c in chain {
+                untrusted.push(c).map_err(e)?;
+            }
+        }
+        _ => store.add_cert(parsed.cert.clone()).map_err(e)?,
+    }
+    let store = store.build();
+    let mut ctx = X509StoreContext::new().map_err(e)?;
+    let verified = ctx
+        .init(&store, &parsed.cert, &untrusted, |c| {
+            let ok = c.verify_cert()?;
+            Ok(if ok { None } else { Some(c.error().to_string()) })
+        })
+        .map_err(e)?;
+    match verified {
+        None => Ok(()),
+        Some(x) => Err(format!("certificate chain does not verify: {}", x)),
+    }
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn verify_chain(_der: &[u8], _passwd: &str) -> Result<(), String> {
+    Ok(())
+}
+
+/// `--check`: whether the certificate can be used, without keeping it loaded.
+/// Returns its details for the log.
+pub fn check(path: &Path, passwd: &str) -> Result<String, String> {
+    let (_, details) = load(path, passwd).map_err(|e| e.to_string())?;
+    let der = ::std::fs::read(path).map_err(|e| e.to_string())?;
+    verify_chain(&der, passwd)?;
+    Ok(details)
+}
+
+/// Load all certificates again. Ones that fail to load are kept as they were.
+pub fn reload() {
+    let mut r = registry().lock().unwrap();
+    for (path, entry) in r.iter_mut() {
+        match load(path, &entry.0) {
+            Ok((acceptor, details)) => {
+                entry.1 = acceptor;
+                info!("Reloaded TLS certificate from {}: {}", path.display(), details);
+            }
+            Err(e) => error!(
+                "Failed to reload TLS certificate from {}, keeping the old one: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+pub fn ssl_accept_peer(inner_peer: Peer, path: &Path) -> BoxedNewPeerFuture {
+    let acceptor = match registry().lock().unwrap().get(path) {
+        Some(x) => x.1.clone(),
+        None => return super::peer_strerr("TLS certificate is not loaded"),
+    };
+    let info = inner_peer.3.clone();
+    let client = info.as_ref().and_then(|x| x.client_addr.clone()).unwrap_or_default();
+    Box::new(
+        acceptor
+            .accept_async(PeerForWs(inner_peer))
+            .map(move |s| {
+                info!("Accepted TLS connection");
+                let (r, w) = s.split();
+                let mut p = Peer::new(r, w);
+                p.3 = info;
+                p
+            })
+            .map_err(move |e| {
+                box_up_err(WebsocatError::Tls {
+                    url: client,
+                    message: e.to_string(),
+                })
+            }),
+    ) as BoxedNewPeerFuture
+}
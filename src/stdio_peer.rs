@@ -1,11 +1,10 @@
 #[cfg(unix)]
 extern crate tokio_file_unix;
-#[cfg(all(unix, feature = "signal_handler"))]
-extern crate tokio_signal;
 extern crate tokio_stdin_stdout;
+#[cfg(feature = "libc")]
+extern crate libc;
 
 use futures;
-use futures::future::Future;
 use std;
 use std::cell::RefCell;
 use std::io::Result as IoResult;
@@ -20,16 +19,27 @@ use self::tokio_file_unix::File as UnixFile;
 use std::fs::{File as FsFile, OpenOptions};
 
 use super::{BoxedNewPeerFuture, Peer, Result};
-use futures::Stream;
 
-use super::{once, ConstructParams, PeerConstructor, Specifier};
+use super::{once, ConstructParams, Options, PeerConstructor, Specifier};
 
 #[derive(Clone, Debug)]
 pub struct Stdio;
 impl Specifier for Stdio {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        #[cfg(feature = "readline")]
+        {
+            if super::readline_peer::wanted(&p.program_options) {
+                if let Some(x) = super::readline_peer::get_readline_peer() {
+                    return once(x);
+                }
+            }
+        }
         let ret;
-        ret = get_stdio_peer(&mut p.global_state.borrow_mut().stdio, &p.tokio_handle);
+        ret = get_stdio_peer(
+            &mut p.global_state.borrow_mut().stdio,
+            &p.tokio_handle,
+            super::my_copy::output_buffer_size(&p.program_options),
+        );
         once(ret)
     }
     specifier_boilerplate!(typ=Stdio globalstate singleconnect no_subspec);
@@ -40,6 +50,7 @@ specifier_class!(
     target = Stdio,
     prefixes = ["-", "stdio:", "inetd:"],
     arg_handling = noarg,
+    consumed_options = &["--flush-after-message", "--flush-interval-ms", "--no-flush"],
     help = r#"
 Read input from console, print to console.
 
@@ -69,7 +80,7 @@ pub struct OpenAsync(pub PathBuf);
 impl Specifier for OpenAsync {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
         let ret;
-        ret = get_file_peer(&self.0, &p.tokio_handle);
+        ret = get_file_peer(&self.0, &p.tokio_handle, &p.program_options);
         once(ret)
     }
     specifier_boilerplate!(typ=Other noglobalstate singleconnect no_subspec);
@@ -79,9 +90,15 @@ specifier_class!(
     target = OpenAsync,
     prefixes = ["open-async:"],
     arg_handling = into,
+    takes_path = true,
+    consumed_options = &["--file-append", "--file-truncate", "--file-create-new"],
     help = r#"
 Open file for read and write and use it like a socket.
 Not for regular files, see readfile/writefile instead.
+
+The file is opened in non-blocking mode, so opening a FIFO without
+the other side does not hang. Options --file-append, --file-create-new
+and --file-truncate apply.
   
 Example: Serve big blobs of random data to clients
 
@@ -114,7 +131,7 @@ Example: Serve random data to clients v2
 "#
 );
 
-fn get_stdio_peer_impl(s: &mut GlobalState, handle: &Handle) -> Result<Peer> {
+fn get_stdio_peer_impl(s: &mut GlobalState, handle: &Handle, bufsize: usize) -> Result<Peer> {
     let si;
     let so;
     {
@@ -131,29 +148,23 @@ fn get_stdio_peer_impl(s: &mut GlobalState, handle: &Handle) -> Result<Peer> {
         let stdout = self::UnixFile::new_nb(std::io::stdout())?;
 
         si = stdin.into_reader(&handle)?;
-        so = stdout.into_io(&handle)?;
+        so = super::epipe::EpipeAsEof::new(stdout.into_io(&handle)?);
 
         let s_clone = s.clone();
 
-        #[cfg(all(unix, feature = "signal_handler"))]
-        {
-            info!("Installing signal handler");
-            let ctrl_c = tokio_signal::ctrl_c(&handle).flatten_stream();
-            let prog = ctrl_c.for_each(move |()| {
-                restore_blocking_status(&s_clone);
-                ::std::process::exit(0);
-                #[allow(unreachable_code)]
-                Ok(())
-            });
-            handle.spawn(prog.map_err(|_| ()));
-        }
+        // Signals are handled by the program; restore stdio on exits caused by them too
+        super::shutdown::at_exit(move || restore_blocking_status(&s_clone));
+    }
+    if bufsize > 0 {
+        return Ok(Peer::new(si, super::my_copy::BufferedWrite::new(so, bufsize)));
     }
     Ok(Peer::new(si, so))
 }
 
-pub fn get_stdio_peer(s: &mut GlobalState, handle: &Handle) -> BoxedNewPeerFuture {
+/// Stdout gets buffered if `bufsize` is not zero
+pub fn get_stdio_peer(s: &mut GlobalState, handle: &Handle, bufsize: usize) -> BoxedNewPeerFuture {
     info!("get_stdio_peer (async)");
-    Box::new(futures::future::result(get_stdio_peer_impl(s, handle))) as BoxedNewPeerFuture
+    Box::new(futures::future::result(get_stdio_peer_impl(s, handle, bufsize))) as BoxedNewPeerFuture
 }
 
 #[derive(Default, Clone)]
@@ -208,22 +219,29 @@ impl Write for FileWrapper {
     }
 }
 
-fn get_file_peer_impl(p: &Path, handle: &Handle) -> Result<Peer> {
-    let oo = OpenOptions::new()
-        .read(true)
+fn get_file_peer_impl(p: &Path, handle: &Handle, opts: &Options) -> Result<Peer> {
+    let mut oo = OpenOptions::new();
+    oo.read(true)
         .write(true)
-        .create(false)
-        .open(p)?;
-    let f = self::UnixFile::new_nb(oo)?;
+        .append(opts.file_append)
+        .truncate(opts.file_truncate)
+        .create_new(opts.file_create_new);
+    #[cfg(feature = "libc")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        // Otherwise opening a FIFO blocks the whole process until the other side shows up
+        oo.custom_flags(libc::O_NONBLOCK);
+    }
+    let f = self::UnixFile::new_nb(oo.open(p)?)?;
 
     let s = f.into_io(&handle)?;
     let ss = FileWrapper(Rc::new(RefCell::new(s)));
     Ok(Peer::new(ss.clone(), ss))
 }
 
-pub fn get_file_peer(p: &Path, handle: &Handle) -> BoxedNewPeerFuture {
+pub fn get_file_peer(p: &Path, handle: &Handle, opts: &Options) -> BoxedNewPeerFuture {
     info!("get_file_peer");
-    Box::new(futures::future::result(get_file_peer_impl(p, handle))) as BoxedNewPeerFuture
+    Box::new(futures::future::result(get_file_peer_impl(p, handle, opts))) as BoxedNewPeerFuture
 }
 
 fn get_fd_peer_impl(fd: i32, handle: &Handle) -> Result<Peer> {
@@ -1,6 +1,8 @@
 extern crate tokio_stdin_stdout;
 
-use super::{BoxedNewPeerFuture, Peer};
+use super::BoxedNewPeerFuture;
+#[cfg(not(windows))]
+use super::Peer;
 
 use super::{once, ConstructParams, PeerConstructor, Specifier};
 
@@ -9,7 +11,15 @@ use std::rc::Rc;
 #[derive(Debug, Clone)]
 pub struct ThreadedStdio;
 impl Specifier for ThreadedStdio {
-    fn construct(&self, _: ConstructParams) -> PeerConstructor {
+    fn construct(&self, _p: ConstructParams) -> PeerConstructor {
+        #[cfg(feature = "readline")]
+        {
+            if super::readline_peer::wanted(&_p.program_options) {
+                if let Some(x) = super::readline_peer::get_readline_peer() {
+                    return once(x);
+                }
+            }
+        }
         once(get_stdio_peer())
     }
     specifier_boilerplate!(globalstate singleconnect no_subspec typ=Stdio);
@@ -34,17 +44,27 @@ specifier_class!(
     target = ThreadedStdio,
     prefixes = ["-", "stdio:", "inetd:"],
     arg_handling = noarg,
+    consumed_options = &["--flush-after-message", "--flush-interval-ms", "--no-flush"],
     help = r#"
 Read input from console, print to console (threaded version).
 
+On Windows, console input is read as lines and converted to UTF-8, console output
+is converted from UTF-8. Redirected input and output pass bytes unchanged.
+
 This specifier can be specified only one time.
 "#
 );
 
+#[cfg(windows)]
+pub fn get_stdio_peer() -> BoxedNewPeerFuture {
+    super::windows_stdio::stdio_peer()
+}
+
+#[cfg(not(windows))]
 pub fn get_stdio_peer() -> BoxedNewPeerFuture {
     info!("get_stdio_peer (threaded)");
     Box::new(::futures::future::ok(Peer::new(
         tokio_stdin_stdout::stdin(0),
-        tokio_stdin_stdout::stdout(0),
+        super::epipe::EpipeAsEof::new(tokio_stdin_stdout::stdout(0)),
     ))) as BoxedNewPeerFuture
 }
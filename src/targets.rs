@@ -0,0 +1,204 @@
+//! `--each-line-of`: run the same session against a list of targets,
+//! substituting each one for `%s` in the right specifier
+
+extern crate serde_json;
+
+use futures::future::{ok, Future};
+use futures::stream::{self, Stream};
+
+use std::cell::{Cell, RefCell};
+use std::io::{Error as IoError, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::AsyncRead;
+
+use self::serde_json::{Map, Value};
+
+use super::{spec, BoxedNewPeerFuture, ConstructParams, Options, Peer, PeerConstructor, Specifier};
+use super::WebsocatConfiguration;
+
+/// Placeholder in the right specifier template
+pub const PLACEHOLDER: &str = "%s";
+
+#[derive(Debug)]
+pub struct TargetResult {
+    pub target: String,
+    /// First error of the session, if it failed
+    pub error: Option<String>,
+    /// Time from the start of the session to the first byte from the right specifier
+    pub latency: Option<Duration>,
+}
+
+impl TargetResult {
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Targets from a `--each-line-of` file: one per line, blank lines and `#` comments ignored
+pub fn parse_target_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect()
+}
+
+/// Remembers when the first byte was read from the subspecifier
+#[derive(Debug)]
+struct FirstResponse<T: Specifier>(T, Rc<Cell<Option<Instant>>>);
+impl<T: Specifier> Specifier for FirstResponse<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let seen = self.1.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| {
+            let r = FirstResponseRead {
+                inner: p.0,
+                seen: seen.clone(),
+            };
+            Box::new(ok(Peer(Box::new(r), p.1, None, None))) as BoxedNewPeerFuture
+        })
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+
+struct FirstResponseRead {
+    inner: Box<AsyncRead>,
+    seen: Rc<Cell<Option<Instant>>>,
+}
+impl Read for FirstResponseRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let n = self.inner.read(buf)?;
+        if n > 0 && self.seen.get().is_none() {
+            self.seen.set(Some(Instant::now()));
+        }
+        Ok(n)
+    }
+}
+impl AsyncRead for FirstResponseRead {}
+
+fn run_one(
+    h: &Handle,
+    s1: &str,
+    template: &str,
+    target: String,
+    opts: Options,
+    timeout: Option<Duration>,
+) -> Box<Future<Item = TargetResult, Error = ()>> {
+    let specs = spec(s1).and_then(|a| spec(&template.replace(PLACEHOLDER, &target)).map(|b| (a, b)));
+    let (s1, s2) = match specs {
+        Ok(x) => x,
+        Err(e) => {
+            return Box::new(ok(TargetResult {
+                target,
+                error: Some(format!("{}", e)),
+                latency: None,
+            }))
+        }
+    };
+    let seen = Rc::new(Cell::new(None));
+    let error = Rc::new(RefCell::new(None));
+    let error2 = error.clone();
+    let s2 = Rc::new(FirstResponse(s2, seen.clone())) as Rc<Specifier>;
+    let start = Instant::now();
+    let conf = WebsocatConfiguration { opts, s1, s2 };
+    let session = conf.serve(
+        h.clone(),
+        Rc::new(move |e: Box<::std::error::Error>| {
+            let mut x = error2.borrow_mut();
+            if x.is_none() {
+                *x = Some(format!("{}", e));
+            }
+        }),
+    );
+    let deadline: Box<Future<Item = (), Error = ()>> = match timeout.map(|t| Timeout::new(t, h)) {
+        Some(Ok(t)) => Box::new(t.map_err(|_| ())),
+        Some(Err(e)) => {
+            *error.borrow_mut() = Some(format!("{}", e));
+            Box::new(ok(()))
+        }
+        None => Box::new(::futures::future::empty()),
+    };
+    // Dropping the session, if it is still running, closes its connections
+    let timed_out = Rc::new(Cell::new(false));
+    let timed_out2 = timed_out.clone();
+    let session = session.select(deadline.map(move |()| timed_out2.set(true)));
+    Box::new(session.then(move |_| {
+        let mut error = error.borrow_mut().take();
+        if timed_out.get() && error.is_none() {
+            let t = timeout.unwrap_or_default();
+            error = Some(format!("timed out after {}ms", millis(t)));
+        }
+        Ok::<_, ()>(TargetResult {
+            target,
+            error,
+            latency: seen.get().map(|t| t.duration_since(start)),
+        })
+    }))
+}
+
+/// Run a session for each target, at most `parallel` at once.
+/// A session still running after `timeout` is closed and its target counted as failed.
+/// Failed targets don't stop the others. Results are in the order of `targets`.
+pub fn run_targets(
+    h: &Handle,
+    s1: &str,
+    template: &str,
+    targets: Vec<String>,
+    opts: Options,
+    parallel: usize,
+    timeout: Option<Duration>,
+) -> Box<Future<Item = Vec<TargetResult>, Error = ()>> {
+    let h = h.clone();
+    let s1 = s1.to_string();
+    let template = template.to_string();
+    Box::new(
+        stream::iter_ok(targets)
+            .map(move |t| run_one(&h, &s1, &template, t, opts.clone(), timeout))
+            .buffered(parallel.max(1))
+            .collect(),
+    )
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_nanos()) / 1_000_000
+}
+
+/// Human-readable table of results
+pub fn write_table<W: Write>(results: &[TargetResult], w: &mut W) -> Result<(), IoError> {
+    let width = results.iter().map(|r| r.target.len()).max().unwrap_or(0).max(6);
+    writeln!(w, "{:width$}  {:6}  {:>10}  {}", "TARGET", "RESULT", "LATENCY", "ERROR", width = width)?;
+    for r in results {
+        let latency = r.latency.map_or("-".to_string(), |d| format!("{}ms", millis(d)));
+        writeln!(
+            w,
+            "{:width$}  {:6}  {:>10}  {}",
+            r.target,
+            if r.ok() { "ok" } else { "FAILED" },
+            latency,
+            r.error.as_ref().map_or("", |x| &x[..]),
+            width = width
+        )?;
+    }
+    let failed = results.iter().filter(|r| !r.ok()).count();
+    writeln!(w, "{} target(s), {} failed", results.len(), failed)
+}
+
+/// Results as a JSON array for `--report-json`
+pub fn to_json(results: &[TargetResult]) -> String {
+    let a = results
+        .iter()
+        .map(|r| {
+            let mut o = Map::new();
+            o.insert("target".into(), r.target.as_str().into());
+            o.insert("ok".into(), r.ok().into());
+            o.insert("latency_ms".into(), r.latency.map_or(Value::Null, |d| millis(d).into()));
+            o.insert("error".into(), r.error.as_ref().map_or(Value::Null, |x| x.as_str().into()));
+            Value::Object(o)
+        })
+        .collect();
+    Value::Array(a).to_string()
+}
@@ -0,0 +1,178 @@
+//! Blocking reads and writes done by threads of their own, connected to the reactor
+//! with bounded channels. For handles that can't be polled, like the Windows console.
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::{brokenpipe, wouldblock};
+
+/// Chunks in flight between a thread and the reactor, each direction
+pub const CHANNEL_DEPTH: usize = 4;
+/// Size of each read done by the reading thread
+pub const READ_CHUNK: usize = 65536;
+
+/// Reading half, fed by a thread reading `r`. EOF after `r` reports one or fails.
+///
+/// When dropped, the thread is asked to stop and `cancel` is called with its handle,
+/// to interrupt a read it is blocked in.
+pub struct ThreadedRead {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    cancel: Box<Fn(&JoinHandle<()>)>,
+}
+
+pub fn spawn_reader<R, C>(name: &str, mut r: R, cancel: C) -> io::Result<ThreadedRead>
+where
+    R: Read + Send + 'static,
+    C: Fn(&JoinHandle<()>) + 'static,
+{
+    let (mut tx, rx) = mpsc::channel(CHANNEL_DEPTH);
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = stop.clone();
+    let thread = thread::Builder::new().name(name.to_string()).spawn(move || {
+        let mut buf = vec![0; READ_CHUNK];
+        loop {
+            let x = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => Ok(buf[..n].to_vec()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            if stop2.load(Ordering::SeqCst) {
+                // Cancelled, the error (if any) is about that
+                break;
+            }
+            let failed = x.is_err();
+            tx = match tx.send(x).wait() {
+                Ok(tx) => tx,
+                Err(_) => break,
+            };
+            if failed {
+                break;
+            }
+        }
+        debug!("Reading thread finished");
+    })?;
+    Ok(ThreadedRead {
+        rx,
+        chunk: vec![],
+        pos: 0,
+        stop,
+        thread: Some(thread),
+        cancel: Box::new(cancel),
+    })
+}
+
+impl Read for ThreadedRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.chunk.len() {
+                let n = buf.len().min(self.chunk.len() - self.pos);
+                buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            match self.rx.poll() {
+                Ok(Async::Ready(Some(Ok(chunk)))) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(Async::Ready(Some(Err(e)))) => return Err(e),
+                Ok(Async::Ready(None)) | Err(()) => return Ok(0),
+                Ok(Async::NotReady) => return wouldblock(),
+            }
+        }
+    }
+}
+impl AsyncRead for ThreadedRead {}
+
+impl Drop for ThreadedRead {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(ref t) = self.thread {
+            (self.cancel)(t);
+        }
+        // Not joined: the thread may still be stuck in a read that can't be interrupted
+        self.thread = None;
+    }
+}
+
+/// Writing half, feeding a thread that writes to `w`. Shutting it down
+/// waits for the thread to write everything out.
+pub struct ThreadedWrite {
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    done: oneshot::Receiver<io::Result<()>>,
+}
+
+pub fn spawn_writer<W>(name: &str, mut w: W) -> io::Result<ThreadedWrite>
+where
+    W: Write + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<Vec<u8>>(CHANNEL_DEPTH);
+    let (done_tx, done) = oneshot::channel();
+    thread::Builder::new().name(name.to_string()).spawn(move || {
+        let mut r = Ok(());
+        for chunk in rx.wait() {
+            let chunk = match chunk {
+                Ok(x) => x,
+                Err(()) => break,
+            };
+            r = w.write_all(&chunk).and_then(|()| w.flush());
+            if r.is_err() {
+                break;
+            }
+        }
+        debug!("Writing thread finished");
+        let _ = done_tx.send(r);
+    })?;
+    Ok(ThreadedWrite { tx: Some(tx), done })
+}
+
+impl Write for ThreadedWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let tx = match self.tx {
+            Some(ref mut x) => x,
+            None => return brokenpipe(),
+        };
+        match tx.start_send(buf.to_vec()) {
+            Ok(AsyncSink::Ready) => Ok(buf.len()),
+            Ok(AsyncSink::NotReady(_)) => wouldblock(),
+            // The thread has failed, `shutdown` tells how
+            Err(_) => brokenpipe(),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let tx = match self.tx {
+            Some(ref mut x) => x,
+            None => return Ok(()),
+        };
+        match tx.poll_complete() {
+            Ok(Async::Ready(())) => Ok(()),
+            Ok(Async::NotReady) => wouldblock(),
+            Err(_) => brokenpipe(),
+        }
+    }
+}
+
+impl AsyncWrite for ThreadedWrite {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.flush());
+        self.tx = None;
+        match self.done.poll() {
+            Ok(Async::Ready(r)) => r.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // Thread panicked
+            Err(_) => brokenpipe(),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use futures;
+use futures::future::ok;
+use futures::{Async, Future};
+
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::{wouldblock, BoxedNewPeerFuture, Peer};
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+use super::util::parse_direction;
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct Throttle<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Throttle<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let h = cp.tokio_handle.clone();
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p| throttle_peer(p, &opts, &h))
+    }
+    specifier_boilerplate!(typ=Other noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = ThrottleClass,
+    target = Throttle,
+    prefixes = ["throttle:"],
+    arg_handling = subspec,
+    consumed_options = &[
+        "--throttle-bytes-per-sec",
+        "--throttle-messages-per-sec",
+        "--throttle-burst",
+        "--throttle-direction",
+    ],
+    help = r#"
+Limit data rate going through the subspecifier using a token bucket.
+
+Options: --throttle-bytes-per-sec, --throttle-messages-per-sec,
+--throttle-burst (bucket size in bytes, default is one second worth of traffic),
+--throttle-direction in|out|both (`in` is data read from the subspecifier,
+`out` is data written to it).
+
+When the bucket is empty, the overlay stops reading (or accepting writes)
+until enough tokens accumulate, so backpressure propagates upstream.
+A read or a write is never split: its full size is taken from the bucket,
+which may go into debt that is paid back by waiting.
+
+Example: emulate a 64 kbit/s link
+
+    websocat --throttle-bytes-per-sec 8000 ws-l:127.0.0.1:8080 throttle:tcp:127.0.0.1:5678
+"#
+);
+
+/// Classic token bucket with fractional tokens
+pub struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    pub fn new(rate: f64, capacity: f64) -> Bucket {
+        Bucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let d = now.duration_since(self.last);
+        self.last = now;
+        let secs = d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + secs * self.rate).min(self.capacity);
+    }
+
+    /// How long to wait until at least one token is available
+    pub fn wait_time(&mut self) -> Option<Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            return None;
+        }
+        let secs = (1.0 - self.tokens) / self.rate;
+        Some(Duration::new(
+            secs as u64,
+            (secs.fract() * 1e9) as u32 + 1_000_000,
+        ))
+    }
+
+    pub fn spend(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// Byte and message buckets for one direction
+struct Limiter {
+    bytes: Option<Bucket>,
+    messages: Option<Bucket>,
+    timer: Option<Timeout>,
+    handle: Handle,
+}
+
+impl Limiter {
+    fn new(opts: &Options, h: &Handle) -> Limiter {
+        let bps = opts.throttle_bytes_per_sec as f64;
+        let mps = opts.throttle_messages_per_sec as f64;
+        let burst = match opts.throttle_burst {
+            0 => bps,
+            x => x as f64,
+        };
+        Limiter {
+            bytes: if bps > 0.0 {
+                Some(Bucket::new(bps, burst.max(1.0)))
+            } else {
+                None
+            },
+            messages: if mps > 0.0 {
+                Some(Bucket::new(mps, mps.max(1.0)))
+            } else {
+                None
+            },
+            timer: None,
+            handle: h.clone(),
+        }
+    }
+
+    /// `Ok(false)` means a timer is set up and the current task will be woken up later
+    fn poll_ready(&mut self) -> Result<bool, IoError> {
+        loop {
+            if let Some(ref mut t) = self.timer {
+                if let Async::NotReady = t.poll()? {
+                    return Ok(false);
+                }
+            }
+            self.timer = None;
+            let w1 = self.bytes.as_mut().and_then(|b| b.wait_time());
+            let w2 = self.messages.as_mut().and_then(|b| b.wait_time());
+            let w = match (w1, w2) {
+                (None, None) => return Ok(true),
+                (Some(a), Some(b)) => a.max(b),
+                (Some(a), None) | (None, Some(a)) => a,
+            };
+            self.timer = Some(Timeout::new(w, &self.handle)?);
+        }
+    }
+
+    fn spend(&mut self, n: usize) {
+        if let Some(ref mut b) = self.bytes {
+            b.spend(n);
+        }
+        if let Some(ref mut b) = self.messages {
+            b.spend(1);
+        }
+    }
+}
+
+pub fn throttle_peer(inner_peer: Peer, opts: &Options, h: &Handle) -> BoxedNewPeerFuture {
+    let (din, dout) = match parse_direction(&opts.throttle_direction) {
+        Some(x) => x,
+        None => return super::peer_strerr("--throttle-direction must be `in`, `out` or `both`"),
+    };
+    if opts.throttle_bytes_per_sec == 0 && opts.throttle_messages_per_sec == 0 {
+        warn!("throttle: used without --throttle-bytes-per-sec or --throttle-messages-per-sec");
+    }
+    let lim = |x: bool| if x { Some(Limiter::new(opts, h)) } else { None };
+    let r = ThrottleRead(inner_peer.0, lim(din));
+    let w = ThrottleWrite(inner_peer.1, lim(dout));
+    Box::new(ok(Peer::new(r, w))) as BoxedNewPeerFuture
+}
+
+struct ThrottleRead(Box<AsyncRead>, Option<Limiter>);
+
+impl Read for ThrottleRead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Some(ref mut l) = self.1 {
+            if !l.poll_ready()? {
+                return wouldblock();
+            }
+            let n = self.0.read(buf)?;
+            if n > 0 {
+                l.spend(n);
+            }
+            return Ok(n);
+        }
+        self.0.read(buf)
+    }
+}
+impl AsyncRead for ThrottleRead {}
+
+struct ThrottleWrite(Box<AsyncWrite>, Option<Limiter>);
+
+impl Write for ThrottleWrite {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if let Some(ref mut l) = self.1 {
+            if !l.poll_ready()? {
+                return wouldblock();
+            }
+            let n = self.0.write(buf)?;
+            l.spend(n);
+            return Ok(n);
+        }
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.flush()
+    }
+}
+impl AsyncWrite for ThrottleWrite {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.0.shutdown()
+    }
+}
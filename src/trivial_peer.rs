@@ -5,15 +5,21 @@ use std;
 use std::io::Result as IoResult;
 use std::io::{Read, Write};
 
-use futures::Async::Ready;
+use futures::task::Task;
+use futures::Async::{NotReady, Ready};
+use futures::Future;
 
+use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use super::wouldblock;
 use super::ReadDebt;
 
-use super::{once, simple_err, ConstructParams, PeerConstructor, Specifier};
+use super::util::{hexdump, parse_hex, unescape};
+use super::{once, simple_err, ConstructParams, Options, PeerConstructor, Specifier};
 
 #[derive(Clone)]
 pub struct Literal(pub Vec<u8>);
@@ -32,9 +38,18 @@ specifier_class!(
     name = LiteralClass,
     target = Literal,
     prefixes = ["literal:"],
-    arg_handling = into,
+    arg_handling = {
+        fn construct(
+            self: &LiteralClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(Literal(unescape(just_arg)?)))
+        }
+    },
     help = r#"
 Output a string, discard input.
+Escapes `\n`, `\r`, `\t`, `\0`, `\\` and `\xNN` are recognized.
 
 Example:
 
@@ -42,11 +57,58 @@ Example:
 "#
 );
 
+specifier_class!(
+    name = LiteralHexClass,
+    target = Literal,
+    prefixes = ["literal-hex:"],
+    arg_handling = {
+        fn construct(
+            self: &LiteralHexClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(Literal(parse_hex(just_arg)?)))
+        }
+    },
+    help = r#"
+Like `literal:`, but the argument is hex-encoded data. Whitespace is ignored.
+
+Example: send a binary message
+
+    websocat -b - literal-hex:'de ad be ef'
+"#
+);
+
+specifier_class!(
+    name = LiteralFileClass,
+    target = Literal,
+    prefixes = ["literal-file:"],
+    arg_handling = {
+        fn construct(
+            self: &LiteralFileClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            let data = std::fs::read(just_arg)
+                .map_err(|e| format!("literal-file: can't read `{}`: {}", just_arg, e))?;
+            Ok(Rc::new(Literal(data)))
+        }
+    },
+    takes_path = true,
+    help = r#"
+Like `literal:`, but the data is read from the specified file on startup.
+
+Example:
+
+    websocat ws-l:127.0.0.1:8080 literal-file:reply.json
+"#
+);
+
 #[derive(Clone)]
 pub struct Assert(pub Vec<u8>);
 impl Specifier for Assert {
-    fn construct(&self, _: ConstructParams) -> PeerConstructor {
-        once(get_assert_peer(self.0.clone()))
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        once(get_assert_peer(self.0.clone(), true, &cp.program_options, &cp.tokio_handle))
     }
     specifier_boilerplate!(noglobalstate singleconnect no_subspec typ=Other);
 }
@@ -60,17 +122,24 @@ specifier_class!(
     target = Assert,
     prefixes = ["assert:"],
     arg_handling = into,
+    consumed_options = &["--assert-allow-extra", "--assert-timeout"],
     help = r#"
 Check the input. Read entire input and panic the program if the input is not equal
 to the specified string. Used in tests.
+
+Input is compared as it arrives. On mismatch, the byte offset and hex dumps
+of expected and received data around it are printed.
+
+With --assert-allow-extra, input may continue after the expected data.
+With --assert-timeout <secs>, the check fails if input does not end in time.
 "#
 );
 
 #[derive(Clone)]
 pub struct Assert2(pub Vec<u8>);
 impl Specifier for Assert2 {
-    fn construct(&self, _: ConstructParams) -> PeerConstructor {
-        once(get_assert2_peer(self.0.clone()))
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        once(get_assert_peer(self.0.clone(), false, &cp.program_options, &cp.tokio_handle))
     }
     specifier_boilerplate!(noglobalstate singleconnect no_subspec typ=Other);
 }
@@ -84,9 +153,58 @@ specifier_class!(
     target = Assert2,
     prefixes = ["assert2:"],
     arg_handling = into,
+    consumed_options = &["--assert-allow-extra", "--assert-timeout"],
     help = r#"
 Check the input. Read entire input and emit an error if the input is not equal
 to the specified string.
+
+Otherwise like `assert:`.
+"#
+);
+
+specifier_class!(
+    name = AssertLiteralClass,
+    target = Assert2,
+    prefixes = ["assert-literal:"],
+    arg_handling = {
+        fn construct(
+            self: &AssertLiteralClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            Ok(Rc::new(Assert2(unescape(just_arg)?)))
+        }
+    },
+    consumed_options = &["--assert-allow-extra", "--assert-timeout"],
+    help = r#"
+Like `assert2:`, but escapes `\n`, `\r`, `\t`, `\0`, `\\` and `\xNN` are recognized
+in the expected string, like in `literal:`.
+"#
+);
+
+specifier_class!(
+    name = AssertFileClass,
+    target = Assert2,
+    prefixes = ["assert-file:"],
+    arg_handling = {
+        fn construct(
+            self: &AssertFileClass,
+            _full: &str,
+            just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            let data = std::fs::read(just_arg)
+                .map_err(|e| format!("assert-file: can't read `{}`: {}", just_arg, e))?;
+            Ok(Rc::new(Assert2(data)))
+        }
+    },
+    takes_path = true,
+    consumed_options = &["--assert-allow-extra", "--assert-timeout"],
+    help = r#"
+Like `assert2:`, but expected data is read from the specified file on startup.
+
+Example: check that the server replies with a known blob
+
+    websocat -b --assert-timeout 5 ws://127.0.0.1:8080/ assert-file:expected.bin
 "#
 );
 
@@ -120,16 +238,29 @@ pub fn get_literal_peer(b: Vec<u8>) -> BoxedNewPeerFuture {
     let p = Peer::new(r, w);
     Box::new(futures::future::ok(p)) as BoxedNewPeerFuture
 }
-pub fn get_assert_peer(b: Vec<u8>) -> BoxedNewPeerFuture {
-    let r = DevNull;
-    let w = AssertPeer(vec![], b, true);
-    let p = Peer::new(r, w);
-    Box::new(futures::future::ok(p)) as BoxedNewPeerFuture
-}
-pub fn get_assert2_peer(b: Vec<u8>) -> BoxedNewPeerFuture {
-    let r = DevNull;
-    let w = AssertPeer(vec![], b, false);
-    let p = Peer::new(r, w);
+/// Peer that checks written data against `expected`, panicking on mismatch if `panic`
+/// is set or failing the session otherwise
+pub fn get_assert_peer(expected: Vec<u8>, panic: bool, opts: &Options, h: &Handle) -> BoxedNewPeerFuture {
+    let s = Rc::new(RefCell::new(AssertState {
+        expected,
+        received: vec![],
+        panic,
+        allow_extra: opts.assert_allow_extra,
+        done: false,
+        reader: None,
+    }));
+    let w = AssertPeer(s.clone());
+    let p = match opts.assert_timeout {
+        None => Peer::new(DevNull, w),
+        Some(t) => {
+            let timer = match Timeout::new(Duration::from_secs(t), h) {
+                Ok(x) => x,
+                Err(e) => return Box::new(futures::future::err(Box::new(e) as Box<std::error::Error>)),
+            };
+            // The reading half does not finish until the check is over
+            Peer::new(AssertTimer { s, timer, secs: t }, w)
+        }
+    };
     Box::new(futures::future::ok(p)) as BoxedNewPeerFuture
 }
 /// A special peer that returns NotReady without registering for any wakeup, deliberately hanging all connections forever.
@@ -173,16 +304,99 @@ impl Read for DevNull {
     }
 }
 
-struct AssertPeer(Vec<u8>, Vec<u8>, bool);
+struct AssertState {
+    expected: Vec<u8>,
+    received: Vec<u8>,
+    panic: bool,
+    allow_extra: bool,
+    /// Input ended or the check failed
+    done: bool,
+    reader: Option<Task>,
+}
+
+impl AssertState {
+    fn fail(&mut self, msg: String) -> IoResult<()> {
+        self.done = true;
+        if let Some(t) = self.reader.take() {
+            t.notify();
+        }
+        if self.panic {
+            panic!("{}", msg);
+        }
+        error!("{}", msg);
+        Err(simple_err(msg))
+    }
+
+    /// Check newly received bytes starting at `from`
+    fn check(&mut self, from: usize) -> IoResult<()> {
+        let n = self.received.len().min(self.expected.len());
+        if from < n {
+            let off = self.received[from..n]
+                .iter()
+                .zip(&self.expected[from..n])
+                .position(|(a, b)| a != b);
+            if let Some(i) = off {
+                let msg = mismatch_report(&self.expected, &self.received, from + i);
+                return self.fail(msg);
+            }
+        }
+        if self.received.len() > self.expected.len() && !self.allow_extra {
+            let msg = format!(
+                "Assertion failed: got more data than {} expected bytes\n{}",
+                self.expected.len(),
+                window_dump("extra", &self.received, self.expected.len()),
+            );
+            return self.fail(msg);
+        }
+        Ok(())
+    }
+}
+
+/// Size of hexdump shown around the first difference
+const ASSERT_WINDOW: usize = 32;
+
+fn window_dump(name: &str, data: &[u8], offset: usize) -> String {
+    let start = offset.saturating_sub(ASSERT_WINDOW / 2).min(data.len());
+    let end = (start + ASSERT_WINDOW).min(data.len());
+    format!(
+        "{} (bytes {}..{} of {}):\n{}",
+        name,
+        start,
+        end,
+        data.len(),
+        hexdump(&data[start..end])
+    )
+}
+
+fn mismatch_report(expected: &[u8], received: &[u8], offset: usize) -> String {
+    format!(
+        "Assertion failed: data differs at byte offset {} ({} bytes matched)\n{}{}",
+        offset,
+        offset,
+        window_dump("expected", expected, offset),
+        window_dump("received", received, offset),
+    )
+}
+
+struct AssertPeer(Rc<RefCell<AssertState>>);
 impl AsyncWrite for AssertPeer {
     fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
-        if self.2 {
-            assert_eq!(self.0, self.1);
-        } else {
-            if self.0 != self.1 {
-                error!("Assertion failed");
-                return Err(simple_err("Assertion failed".into()));
-            }
+        let mut s = self.0.borrow_mut();
+        if s.done {
+            return Ok(Ready(()));
+        }
+        if s.received.len() < s.expected.len() {
+            let msg = format!(
+                "Assertion failed: input ended after {} of {} expected bytes\n{}",
+                s.received.len(),
+                s.expected.len(),
+                window_dump("missing", &s.expected, s.received.len()),
+            );
+            s.fail(msg)?;
+        }
+        s.done = true;
+        if let Some(t) = s.reader.take() {
+            t.notify();
         }
         info!("Assertion succeed");
         Ok(Ready(()))
@@ -191,7 +405,10 @@ impl AsyncWrite for AssertPeer {
 
 impl Write for AssertPeer {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        self.0.extend_from_slice(buf);
+        let mut s = self.0.borrow_mut();
+        let from = s.received.len();
+        s.received.extend_from_slice(buf);
+        s.check(from)?;
         Ok(buf.len())
     }
     fn flush(&mut self) -> IoResult<()> {
@@ -199,6 +416,35 @@ impl Write for AssertPeer {
     }
 }
 
+/// Reading half of assertion peers with `--assert-timeout`
+struct AssertTimer {
+    s: Rc<RefCell<AssertState>>,
+    timer: Timeout,
+    secs: u64,
+}
+
+impl AsyncRead for AssertTimer {}
+impl Read for AssertTimer {
+    fn read(&mut self, _buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
+        if self.s.borrow().done {
+            return Ok(0);
+        }
+        if let NotReady = self.timer.poll()? {
+            self.s.borrow_mut().reader = Some(futures::task::current());
+            return wouldblock();
+        }
+        let mut s = self.s.borrow_mut();
+        let msg = format!(
+            "Assertion failed: input did not end in {} seconds, got {} of {} expected bytes",
+            self.secs,
+            s.received.len(),
+            s.expected.len()
+        );
+        s.fail(msg)?;
+        Ok(0)
+    }
+}
+
 struct CloggedPeer;
 impl AsyncWrite for CloggedPeer {
     fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
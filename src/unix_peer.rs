@@ -11,7 +11,6 @@ use std::io::{Read, Write};
 use tokio_core::reactor::Handle;
 use tokio_io::{AsyncRead, AsyncWrite};
 
-use std::cell::RefCell;
 use std::rc::Rc;
 
 use std::path::{Path, PathBuf};
@@ -20,8 +19,11 @@ use self::tokio_uds::{UnixDatagram, UnixListener, UnixStream};
 
 #[allow(unused)]
 use super::simple_err;
+use super::bind_retry;
+use super::datagram::WholeDatagrams;
+use super::error::WebsocatError;
 use super::{box_up_err, peer_err_s, BoxedNewPeerFuture, BoxedNewPeerStream, Peer};
-use super::{multi, once, ConstructParams, Options, PeerConstructor, Specifier};
+use super::{multi, once, ConstructParams, LeftSpecToRightSpec, Options, PeerConstructor, Specifier};
 
 #[derive(Debug, Clone)]
 pub struct UnixConnect(pub PathBuf);
@@ -42,6 +44,7 @@ specifier_class!(
         "c-unix:"
     ],
     arg_handling = into,
+    takes_path = true,
     help = r#"
 Connect to UNIX socket. Argument is filesystem path.
 
@@ -68,6 +71,8 @@ specifier_class!(
     target = UnixListen,
     prefixes = ["unix-listen:", "listen-unix:", "unix-l:", "l-unix:"],
     arg_handling = into,
+    takes_path = true,
+    consumed_options = &["--unlink"],
     help = r#"
 Listen for connections on a specified UNIX socket
 
@@ -133,6 +138,8 @@ specifier_class!(
             Ok(Rc::new(UnixDgram(splits[0].into(), splits[1].into())))
         }
     },
+    takes_path = true,
+    consumed_options = &["--udp-oneshot"],
     help = r#"
 Send packets to one path, receive from the other.
 A socket for sending must be already openend.
@@ -205,6 +212,7 @@ specifier_class!(
         "l-abstract:"
     ],
     arg_handling = into,
+    consumed_options = &["--unlink"],
     help = r#"
 Listen for connections on a specified abstract UNIX socket
 
@@ -259,6 +267,7 @@ specifier_class!(
             Ok(Rc::new(UnixDgram(splits[0].into(), splits[1].into())))
         }
     },
+    consumed_options = &["--udp-oneshot"],
     help = r#"
 Send packets to one address, receive from the other.
 A socket for sending must be already openend.
@@ -281,7 +290,7 @@ pub struct SeqpacketConnect(pub PathBuf);
 #[cfg(feature = "seqpacket")]
 impl Specifier for SeqpacketConnect {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
-        once(seqpacket_connect_peer(&p.tokio_handle, &self.0))
+        once(seqpacket_connect_peer(&p.tokio_handle, &self.0, p.program_options))
     }
     specifier_boilerplate!(noglobalstate singleconnect no_subspec typ=Other);
 }
@@ -297,6 +306,7 @@ specifier_class!(
         "c-seqpacket:"
     ],
     arg_handling = into,
+    takes_path = true,
     help = r#"
 Connect to AF_UNIX SOCK_SEQPACKET socket. Argument is a filesystem path.
 
@@ -331,6 +341,8 @@ specifier_class!(
         "l-seqpacket:"
     ],
     arg_handling = into,
+    takes_path = true,
+    consumed_options = &["--unlink"],
     help = r#"
 Listen for connections on a specified AF_UNIX SOCK_SEQPACKET socket
 
@@ -344,19 +356,30 @@ Example: forward connections from a UNIX seqpacket socket to a WebSocket
 "#
 );
 
+/// Retry calls interrupted by a signal instead of failing the session with `EINTR`.
+/// `EWOULDBLOCK` is left to the reactor-registered socket, which schedules a wakeup.
+fn retry_eintr<T, F: FnMut() -> IoResult<T>>(mut f: F) -> IoResult<T> {
+    loop {
+        match f() {
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            r => return r,
+        }
+    }
+}
+
 // based on https://github.com/tokio-rs/tokio-core/blob/master/examples/proxy.rs
 #[derive(Clone)]
 struct MyUnixStream(Rc<UnixStream>, bool);
 
 impl Read for MyUnixStream {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        (&*self.0).read(buf)
+        retry_eintr(|| (&*self.0).read(buf))
     }
 }
 
 impl Write for MyUnixStream {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        (&*self.0).write(buf)
+        retry_eintr(|| (&*self.0).write(buf))
     }
 
     fn flush(&mut self) -> IoResult<()> {
@@ -382,53 +405,75 @@ impl Drop for MyUnixStream {
     }
 }
 
+/// Stream sockets can be spliced; `seqpacket:` ones would lose message boundaries
+fn unix_stream_peer(x: UnixStream) -> Peer {
+    use std::os::unix::io::AsRawFd;
+    let fd = x.as_raw_fd();
+    let x = Rc::new(x);
+    Peer::with_raw_fd(MyUnixStream(x.clone(), true), MyUnixStream(x, false), fd)
+}
+
 pub fn unix_connect_peer(handle: &Handle, addr: &Path) -> BoxedNewPeerFuture {
+    let addr2 = addr.display().to_string();
     Box::new(futures::future::result(
         UnixStream::connect(&addr, handle)
             .map(|x| {
                 info!("Connected to a unix socket");
-                let x = Rc::new(x);
-                Peer::new(
-                    MyUnixStream(x.clone(), true),
-                    MyUnixStream(x.clone(), false),
-                )
+                unix_stream_peer(x)
             })
-            .map_err(box_up_err),
+            .map_err(move |source| box_up_err(WebsocatError::Connect { addr: addr2, source })),
     )) as BoxedNewPeerFuture
 }
 
-pub fn unix_listen_peer(handle: &Handle, addr: &Path, opts: Rc<Options>) -> BoxedNewPeerStream {
+/// Describe UNIX socket client for `WEBSOCAT_CLIENT`. Clients are usually unnamed,
+/// in which case the listening socket is reported.
+fn unix_client_name(client: &::std::os::unix::net::SocketAddr, listener: &Path) -> String {
+    let p = client.as_pathname().unwrap_or(listener);
+    let s = p.to_string_lossy();
+    if s.starts_with('\0') {
+        format!("@{}", &s[1..])
+    } else {
+        s.into_owned()
+    }
+}
+
+pub fn unix_listen_peer(
+    handle: &Handle,
+    addr: &Path,
+    opts: Rc<Options>,
+) -> BoxedNewPeerStream {
     if opts.unlink_unix_socket {
         let _ = ::std::fs::remove_file(addr);
     };
-    let bound = match UnixListener::bind(&addr, handle) {
-        Ok(x) => x,
-        Err(e) => return peer_err_s(e),
-    };
     // TODO: chmod
-    Box::new(
-        bound
-            .incoming()
-            .map(|(x, _addr)| {
-                info!("Incoming unix socket connection");
-                let x = Rc::new(x);
-                Peer::new(
-                    MyUnixStream(x.clone(), true),
-                    MyUnixStream(x.clone(), false),
-                )
-            })
-            .map_err(|e| box_up_err(e)),
-    ) as BoxedNewPeerStream
-}
-
-struct DgramPeer {
-    s: UnixDatagram,
-    #[allow(unused)]
-    oneshot_mode: bool, // TODO
+    let listen_path = addr.to_path_buf();
+    let (path, h) = (listen_path.clone(), handle.clone());
+    let incoming = move |bound: UnixListener| {
+        Box::new(
+            bound
+                .incoming()
+                .map(move |(x, addr)| {
+                    info!("Incoming unix socket connection");
+                    unix_stream_peer(x).with_client_info(LeftSpecToRightSpec {
+                        client_addr: Some(unix_client_name(&addr, &listen_path)),
+                        ..Default::default()
+                    })
+                })
+                .map_err(|e| box_up_err(e)),
+        ) as BoxedNewPeerStream
+    };
+    bind_retry::listen(
+        handle,
+        format!("unix-l:{}", addr.display()),
+        &opts,
+        move || UnixListener::bind(&path, &h),
+        incoming,
+    )
 }
 
+/// Either half of a datagram peer. Sending and receiving don't need anything but the socket.
 #[derive(Clone)]
-struct DgramPeerHandle(Rc<RefCell<DgramPeer>>);
+struct DgramPeerHandle(Rc<UnixDatagram>);
 
 pub fn dgram_peer(
     handle: &Handle,
@@ -441,11 +486,8 @@ pub fn dgram_peer(
             .and_then(|x| {
                 x.connect(connectaddr)?;
 
-                let h1 = DgramPeerHandle(Rc::new(RefCell::new(DgramPeer {
-                    s: x,
-                    oneshot_mode: opts.udp_oneshot_mode,
-                })));
-                let h2 = h1.clone();
+                let h1 = DgramPeerHandle(Rc::new(x));
+                let h2 = WholeDatagrams::new(h1.clone(), opts.allow_partial_datagrams);
                 Ok(Peer::new(h1, h2))
             })
             .map_err(box_up_err),
@@ -509,38 +551,35 @@ pub fn dgram_peer_workaround(
         handle: &Handle,
         bindaddr: &Path,
         connectaddr: &Path,
-        opts: Rc<Options>,
+        allow_partial: bool,
     ) -> Result<Peer, Box<::std::error::Error>> {
         if let Some(fd) = getfd(bindaddr, connectaddr) {
             let s: ::std::os::unix::net::UnixDatagram =
                 unsafe { ::std::os::unix::io::FromRawFd::from_raw_fd(fd) };
+            // Sending and receiving must report `EWOULDBLOCK` for the reactor to take over
+            s.set_nonblocking(true)?;
             let ss = UnixDatagram::from_datagram(s, handle)?;
-            let h1 = DgramPeerHandle(Rc::new(RefCell::new(DgramPeer {
-                s: ss,
-                oneshot_mode: opts.udp_oneshot_mode,
-            })));
-            let h2 = h1.clone();
+            let h1 = DgramPeerHandle(Rc::new(ss));
+            let h2 = WholeDatagrams::new(h1.clone(), allow_partial);
             Ok(Peer::new(h1, h2))
         } else {
             Err("Failed to get, bind or connect socket")?
         }
     }
     Box::new(futures::future::result({
-        getpeer(handle, bindaddr, connectaddr, opts)
+        getpeer(handle, bindaddr, connectaddr, opts.allow_partial_datagrams)
     })) as BoxedNewPeerFuture
 }
 
 impl Read for DgramPeerHandle {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        let p = self.0.borrow_mut();
-        p.s.recv(buf)
+        retry_eintr(|| self.0.recv(buf))
     }
 }
 
 impl Write for DgramPeerHandle {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        let p = self.0.borrow_mut();
-        p.s.send(buf)
+        retry_eintr(|| self.0.send(buf))
     }
 
     fn flush(&mut self) -> IoResult<()> {
@@ -557,7 +596,7 @@ impl AsyncWrite for DgramPeerHandle {
 }
 
 #[cfg(feature = "seqpacket")]
-pub fn seqpacket_connect_peer(handle: &Handle, addr: &Path) -> BoxedNewPeerFuture {
+pub fn seqpacket_connect_peer(handle: &Handle, addr: &Path, opts: Rc<Options>) -> BoxedNewPeerFuture {
     fn getfd(addr: &Path) -> Option<i32> {
         use self::libc::{
             c_char, close, connect, sa_family_t, sockaddr_un, socket, socklen_t, AF_UNIX,
@@ -591,21 +630,23 @@ pub fn seqpacket_connect_peer(handle: &Handle, addr: &Path) -> BoxedNewPeerFutur
             Some(s)
         }
     }
-    fn getpeer(handle: &Handle, addr: &Path) -> Result<Peer, Box<::std::error::Error>> {
+    fn getpeer(handle: &Handle, addr: &Path, allow_partial: bool) -> Result<Peer, Box<::std::error::Error>> {
         if let Some(fd) = getfd(addr) {
             let s: ::std::os::unix::net::UnixStream =
                 unsafe { ::std::os::unix::io::FromRawFd::from_raw_fd(fd) };
+            // Connected while blocking; from now on `EWOULDBLOCK` goes to the reactor
+            s.set_nonblocking(true)?;
             let ss = UnixStream::from_stream(s, handle)?;
             let x = Rc::new(ss);
             Ok(Peer::new(
                 MyUnixStream(x.clone(), true),
-                MyUnixStream(x.clone(), false),
+                WholeDatagrams::new(MyUnixStream(x.clone(), false), allow_partial),
             ))
         } else {
             Err("Failed to get or connect socket")?
         }
     }
-    Box::new(futures::future::result({ getpeer(handle, addr) })) as BoxedNewPeerFuture
+    Box::new(futures::future::result({ getpeer(handle, addr, opts.allow_partial_datagrams) })) as BoxedNewPeerFuture
 }
 
 #[cfg(feature = "seqpacket")]
@@ -660,12 +701,16 @@ pub fn seqpacket_listen_peer(
             Some(s)
         }
     }
+    let allow_partial = opts.allow_partial_datagrams;
     let fd = match getfd(addr, opts) {
         Some(x) => x,
         None => return peer_err_s(simple_err("Failed to get or bind socket".into())),
     };
     let l1: ::std::os::unix::net::UnixListener =
         unsafe { ::std::os::unix::io::FromRawFd::from_raw_fd(fd) };
+    if let Err(e) = l1.set_nonblocking(true) {
+        return peer_err_s(e);
+    }
     let bound = match UnixListener::from_listener(l1, handle) {
         Ok(x) => x,
         Err(e) => return peer_err_s(e),
@@ -673,12 +718,12 @@ pub fn seqpacket_listen_peer(
     Box::new(
         bound
             .incoming()
-            .map(|(x, _addr)| {
+            .map(move |(x, _addr)| {
                 info!("Incoming unix socket connection");
                 let x = Rc::new(x);
                 Peer::new(
                     MyUnixStream(x.clone(), true),
-                    MyUnixStream(x.clone(), false),
+                    WholeDatagrams::new(MyUnixStream(x.clone(), false), allow_partial),
                 )
             })
             .map_err(|e| box_up_err(e)),
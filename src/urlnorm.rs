@@ -0,0 +1,102 @@
+//! Percent-encoding of `ws://` URLs (and `--ws-c-uri`) before they are parsed.
+//!
+//! Path and query are encoded exactly once: valid `%XX` sequences are kept, a `%`
+//! not starting one becomes `%25`, and bytes not allowed there (space, non-ASCII,
+//! `#` as WebSocket URLs have no fragments, ...) are encoded. `+` is left alone.
+//! With `--no-url-normalization`, the path and query are sent as written instead,
+//! see `ws_client_peer::StatusSniff`.
+
+use super::util::{find_subslice, hex_digit};
+
+/// Where the authority of `url` ends, if it has one
+fn authority_end(url: &str) -> Option<usize> {
+    let start = url.find("://")? + 3;
+    let rest = &url[start..];
+    Some(start + rest.find(|c: char| c == '/' || c == '?' || c == '#').unwrap_or(rest.len()))
+}
+
+fn allowed(b: u8) -> bool {
+    match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => true,
+        b'-' | b'.' | b'_' | b'~' => true,
+        b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' => true,
+        b':' | b'@' | b'/' | b'?' => true,
+        _ => false,
+    }
+}
+
+/// Value of `%XX` at the start of `s`
+fn escape(s: &[u8]) -> Option<u8> {
+    if s.len() < 3 || s[0] != b'%' {
+        return None;
+    }
+    Some(hex_digit(&s[1])? << 4 | hex_digit(&s[2])?)
+}
+
+/// `url` with its path and query percent-encoded as described in the module docs
+pub fn normalize(url: &str) -> String {
+    let end = match authority_end(url) {
+        Some(x) => x,
+        None => return url.to_string(),
+    };
+    let mut out = url[..end].to_string();
+    let rest = url[end..].as_bytes();
+    for (i, &b) in rest.iter().enumerate() {
+        if allowed(b) || escape(&rest[i..]).is_some() {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Path and query of `url` exactly as written, for the request line
+pub fn request_target(url: &str) -> String {
+    let rest = match authority_end(url) {
+        Some(x) => &url[x..],
+        None => "",
+    };
+    if rest.starts_with('/') {
+        rest.to_string()
+    } else {
+        format!("/{}", rest)
+    }
+}
+
+/// Undo `%XX` escapes. Invalid ones are kept as they are.
+pub fn percent_decode(s: &str) -> Vec<u8> {
+    let s = s.as_bytes();
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match escape(&s[i..]) {
+            Some(x) => {
+                out.push(x);
+                i += 3;
+            }
+            None => {
+                out.push(s[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Replace the target of the request line at the start of `head` with `target`.
+/// `None` until the whole line is there.
+pub fn rewrite_request_line(head: &[u8], target: &[u8]) -> Option<Vec<u8>> {
+    let eol = find_subslice(head, b"\r\n")?;
+    let line = &head[..eol];
+    let sp1 = line.iter().position(|&x| x == b' ')?;
+    let sp2 = line.iter().rposition(|&x| x == b' ')?;
+    if sp2 <= sp1 {
+        return Some(head.to_vec());
+    }
+    let mut out = Vec::with_capacity(head.len() + target.len());
+    out.extend_from_slice(&line[..sp1 + 1]);
+    out.extend_from_slice(target);
+    out.extend_from_slice(&head[sp2..]);
+    Some(out)
+}
@@ -0,0 +1,408 @@
+//! Small helpers shared by various peers: time formatting, dumps, encodings
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Convert days since 1970-01-01 to (year, month, day)
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = z + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = (z - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// Format time like `2024-05-01T12:00:00.123Z`
+pub fn format_rfc3339(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    let secs = d.as_secs();
+    let ms = d.subsec_nanos() / 1_000_000;
+    let (y, m, dd) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        y,
+        m,
+        dd,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        ms
+    )
+}
+
+pub fn rfc3339_now() -> String {
+    format_rfc3339(SystemTime::now())
+}
+
+/// Classic 16-bytes-per-line hex dump with offsets and ASCII column
+pub fn hexdump(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 4 + 16);
+    for (i, chunk) in data.chunks(16).enumerate() {
+        s.push_str(&format!("{:08x} ", i * 16));
+        for j in 0..16 {
+            if j == 8 {
+                s.push(' ');
+            }
+            match chunk.get(j) {
+                Some(b) => s.push_str(&format!(" {:02x}", b)),
+                None => s.push_str("   "),
+            }
+        }
+        s.push_str("  |");
+        for &b in chunk {
+            s.push(if b >= 0x20 && b < 0x7F { b as char } else { '.' });
+        }
+        s.push_str("|\n");
+    }
+    s
+}
+
+/// Interpret `in`, `out` or `both` (also empty string) as (in, out) flags
+pub fn parse_direction(s: &str) -> Option<(bool, bool)> {
+    match s {
+        "" | "both" => Some((true, true)),
+        "in" => Some((true, false)),
+        "out" => Some((false, true)),
+        _ => None,
+    }
+}
+
+/// Small non-cryptographic PRNG (xorshift64*) for jitter and the like
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new() -> XorShift {
+        let d = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let local = 0u8;
+        let seed = d.as_secs() ^ ((d.subsec_nanos() as u64) << 32) ^ (&local as *const u8 as u64);
+        XorShift(seed | 1)
+    }
+
+    /// Reproducible sequence for the given seed
+    pub fn with_seed(seed: u64) -> XorShift {
+        let mut r = XorShift((seed << 1) | 1);
+        // Small seeds give poorly mixed first outputs
+        for _ in 0..8 {
+            r.next_u64();
+        }
+        r
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// True with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    /// Uniformly distributed number in `0..n`, or 0 if `n` is 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+}
+
+/// Interpret `\n`, `\r`, `\t`, `\0`, `\\` and `\xNN` escapes
+pub fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let b = s.as_bytes();
+    let mut v = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] != b'\\' {
+            v.push(b[i]);
+            i += 1;
+            continue;
+        }
+        let c = match b.get(i + 1) {
+            Some(&b'n') => b'\n',
+            Some(&b'r') => b'\r',
+            Some(&b't') => b'\t',
+            Some(&b'0') => 0,
+            Some(&b'\\') => b'\\',
+            Some(&b'x') => {
+                match (b.get(i + 2).and_then(hex_digit), b.get(i + 3).and_then(hex_digit)) {
+                    (Some(h), Some(l)) => v.push(h * 16 + l),
+                    _ => return Err(format!("Invalid \\x escape at offset {} in `{}`", i, s)),
+                }
+                i += 4;
+                continue;
+            }
+            Some(&x) => {
+                return Err(format!(
+                    "Unknown escape `\\{}` at offset {} in `{}`",
+                    x as char, i, s
+                ))
+            }
+            None => return Err(format!("Trailing backslash at offset {} in `{}`", i, s)),
+        };
+        v.push(c);
+        i += 2;
+    }
+    Ok(v)
+}
+
+/// Value of a hex digit
+pub fn hex_digit(c: &u8) -> Option<u8> {
+    match *c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode hex string like `01 ab FF`, ignoring whitespace
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let mut v = Vec::with_capacity(s.len() / 2);
+    let mut hi: Option<(usize, u8)> = None;
+    for (i, c) in s.bytes().enumerate() {
+        if (c as char).is_whitespace() {
+            continue;
+        }
+        let d = match hex_digit(&c) {
+            Some(d) => d,
+            None => return Err(format!("Invalid hex digit at offset {} in `{}`", i, s)),
+        };
+        hi = match hi {
+            None => Some((i, d)),
+            Some((_, h)) => {
+                v.push(h * 16 + d);
+                None
+            }
+        };
+    }
+    if let Some((i, _)) = hi {
+        return Err(format!("Odd number of hex digits, unpaired one at offset {} in `{}`", i, s));
+    }
+    Ok(v)
+}
+
+/// Position of the first occurrence of `needle` in `haystack`
+pub fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return None;
+    }
+    (0..(haystack.len() - needle.len() + 1)).find(|&i| &haystack[i..(i + needle.len())] == needle)
+}
+
+/// Parse terminal size like `80x24` into (columns, rows)
+pub fn parse_winsize(s: &str) -> Option<(u16, u16)> {
+    let x = s.find('x')?;
+    let cols = s[..x].parse().ok()?;
+    let rows = s[(x + 1)..].parse().ok()?;
+    Some((cols, rows))
+}
+
+/// Parse argument vector like `['prog', "--flag", 'value with \'quotes\'']`.
+/// Both quote styles are accepted, with `\\`, `\'`, `\"`, `\n`, `\r`, `\t` and `\0` escapes.
+pub fn parse_argv(s: &str) -> Result<Vec<String>, String> {
+    let err = |what: &str, i: usize| Err(format!("{} at offset {} in `{}`", what, i, s));
+    let mut it = s.char_indices().peekable();
+    let mut v = vec![];
+    macro_rules! skip_ws {
+        () => {
+            while let Some(&(_, c)) = it.peek() {
+                if !c.is_whitespace() {
+                    break;
+                }
+                it.next();
+            }
+        };
+    }
+    skip_ws!();
+    match it.next() {
+        Some((_, '[')) => (),
+        Some((i, _)) => return err("Expected `[`", i),
+        None => return err("Expected `[`", 0),
+    }
+    loop {
+        skip_ws!();
+        let (i, q) = match it.next() {
+            Some((_, ']')) => break,
+            Some((i, c)) if c == '\'' || c == '"' => (i, c),
+            Some((i, _)) => return err("Expected a quoted argument or `]`", i),
+            None => return err("Unterminated argument list", s.len()),
+        };
+        let mut a = String::new();
+        loop {
+            match it.next() {
+                Some((_, c)) if c == q => break,
+                Some((j, '\\')) => {
+                    let c = match it.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, '0')) => '\0',
+                        Some((_, c)) if c == '\\' || c == '\'' || c == '"' => c,
+                        Some(_) => return err("Unknown escape", j),
+                        None => return err("Trailing backslash", j),
+                    };
+                    a.push(c);
+                }
+                Some((_, c)) => a.push(c),
+                None => return err("Unterminated quoted argument", i),
+            }
+        }
+        v.push(a);
+        skip_ws!();
+        match it.next() {
+            Some((_, ',')) => (),
+            Some((_, ']')) => break,
+            Some((i, _)) => return err("Expected `,` or `]`", i),
+            None => return err("Unterminated argument list", s.len()),
+        }
+    }
+    skip_ws!();
+    if let Some((i, _)) = it.next() {
+        return err("Trailing characters after `]`", i);
+    }
+    Ok(v)
+}
+
+/// Replace `${NAME}` with values from `lookup`; `$$` stands for a literal `$`.
+/// An unknown variable or unterminated `${` is an error.
+pub fn expand_env<F: Fn(&str) -> Option<String>>(s: &str, lookup: F) -> Result<String, String> {
+    let mut r = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        r.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with("$$") {
+            r.push('$');
+            rest = &rest[2..];
+        } else if rest.starts_with("${") {
+            let end = match rest.find('}') {
+                Some(x) => x,
+                None => return Err(format!("Unterminated `${{` in `{}`", s)),
+            };
+            let name = &rest[2..end];
+            match lookup(name) {
+                Some(v) => r.push_str(&v),
+                None => return Err(format!("Environment variable `{}` is not set", name)),
+            }
+            rest = &rest[(end + 1)..];
+        } else {
+            r.push('$');
+            rest = &rest[1..];
+        }
+    }
+    r.push_str(rest);
+    Ok(r)
+}
+
+/// Arguments from a configuration file: one per line, surrounding whitespace
+/// trimmed, blank lines and lines starting with `#` skipped, `${NAME}` expanded.
+pub fn parse_config_args<F: Fn(&str) -> Option<String>>(
+    text: &str,
+    lookup: F,
+) -> Result<Vec<String>, String> {
+    let mut v = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        v.push(expand_env(line, &lookup).map_err(|e| format!("line {}: {}", n + 1, e))?);
+    }
+    Ok(v)
+}
+
+/// An option with its value, or a positional argument if `name` is `None`
+struct ArgGroup {
+    name: Option<String>,
+    tokens: Vec<String>,
+}
+
+fn split_args<T: Fn(&str) -> bool>(args: Vec<String>, takes_value: &T) -> Vec<ArgGroup> {
+    let mut groups = vec![];
+    let mut it = args.into_iter();
+    let mut only_positional = false;
+    while let Some(t) = it.next() {
+        if only_positional || t == "-" || !t.starts_with('-') {
+            groups.push(ArgGroup { name: None, tokens: vec![t] });
+            continue;
+        }
+        if t == "--" {
+            only_positional = true;
+            continue;
+        }
+        let name = t.splitn(2, '=').next().unwrap_or_default().to_string();
+        let mut tokens = vec![t.clone()];
+        if !t.contains('=') && takes_value(&name) {
+            tokens.extend(it.next());
+        }
+        groups.push(ArgGroup { name: Some(name), tokens });
+    }
+    groups
+}
+
+/// Merge arguments from configuration file `source` with the command line ones.
+///
+/// An option given in both places is taken from the command line, unless
+/// `repeatable` tells it may be given several times (like `--header`): then
+/// the file's ones come first. Specifiers go last, after `--`; they may come
+/// from either place, but not from both if that makes more than two.
+pub fn merge_config_args<T, R>(
+    file: Vec<String>,
+    cli: Vec<String>,
+    source: &str,
+    takes_value: T,
+    repeatable: R,
+) -> Result<Vec<String>, String>
+where
+    T: Fn(&str) -> bool,
+    R: Fn(&[String]) -> bool,
+{
+    let file = split_args(file, &takes_value);
+    let cli = split_args(cli, &takes_value);
+    let positional = |g: &[ArgGroup]| -> Vec<String> {
+        g.iter().filter(|x| x.name.is_none()).flat_map(|x| x.tokens.clone()).collect()
+    };
+    let (fpos, cpos) = (positional(&file), positional(&cli));
+    if !fpos.is_empty() && !cpos.is_empty() && fpos.len() + cpos.len() > 2 {
+        return Err(format!(
+            "Specifiers are given both in {} (`{}`) and on the command line (`{}`)",
+            source,
+            fpos.join("` `"),
+            cpos.join("` `")
+        ));
+    }
+    let mut args = vec![];
+    for g in &file {
+        let name = match g.name {
+            Some(ref x) => x,
+            None => continue,
+        };
+        if cli.iter().any(|c| c.name.as_ref() == Some(name)) && !repeatable(&g.tokens) {
+            // Command line wins
+            continue;
+        }
+        args.extend(g.tokens.iter().cloned());
+    }
+    for g in cli.iter().filter(|x| x.name.is_some()) {
+        args.extend(g.tokens.iter().cloned());
+    }
+    args.push("--".to_string());
+    args.extend(fpos);
+    args.extend(cpos);
+    Ok(args)
+}
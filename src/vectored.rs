@@ -0,0 +1,82 @@
+//! Writing several buffers with one call: `writev(2)` on plain sockets (Linux),
+//! one buffer at a time otherwise.
+
+#[cfg(all(target_os = "linux", feature = "libc"))]
+extern crate libc;
+
+use std::io::{Result as IoResult, Write};
+
+use super::splice::RawFd;
+
+/// Write half that can take a frame header and its payload at once
+pub trait WriteVectored: Write {
+    /// Like `write`, for the concatenation of `bufs`. May write only a part of it.
+    fn write_vectored(&mut self, bufs: &[&[u8]]) -> IoResult<usize> {
+        write_first(self, bufs)
+    }
+}
+
+/// The fallback: write the first non-empty buffer
+pub fn write_first<W: Write + ?Sized>(w: &mut W, bufs: &[&[u8]]) -> IoResult<usize> {
+    for b in bufs {
+        if !b.is_empty() {
+            return w.write(b);
+        }
+    }
+    Ok(0)
+}
+
+/// `writev(2)` on `fd`, the descriptor behind `w`, if there is one.
+///
+/// The reactor tracks readiness through `w`, which `writev` bypasses. When the socket
+/// is not ready, `w` is written to instead, so the task gets woken up once it is.
+#[cfg(all(target_os = "linux", feature = "libc"))]
+pub fn writev_fd<W: Write + ?Sized>(fd: Option<RawFd>, w: &mut W, bufs: &[&[u8]]) -> IoResult<usize> {
+    let fd = match fd {
+        Some(x) => x,
+        None => return write_first(w, bufs),
+    };
+    let empty = libc::iovec {
+        iov_base: ::std::ptr::null_mut(),
+        iov_len: 0,
+    };
+    // Header and payload, a few more pieces are just in case
+    let mut iov = [empty, empty, empty, empty];
+    let mut n = 0;
+    for b in bufs.iter().filter(|b| !b.is_empty()).take(iov.len()) {
+        iov[n] = libc::iovec {
+            iov_base: b.as_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        };
+        n += 1;
+    }
+    if n == 0 {
+        return Ok(0);
+    }
+    let ret = unsafe { libc::writev(fd, iov.as_ptr(), n as libc::c_int) };
+    if ret >= 0 {
+        return Ok(ret as usize);
+    }
+    let e = ::std::io::Error::last_os_error();
+    if e.kind() == ::std::io::ErrorKind::WouldBlock {
+        return write_first(w, bufs);
+    }
+    Err(e)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "libc")))]
+pub fn writev_fd<W: Write + ?Sized>(_fd: Option<RawFd>, w: &mut W, bufs: &[&[u8]]) -> IoResult<usize> {
+    write_first(w, bufs)
+}
+
+#[cfg(unix)]
+impl WriteVectored for ::tokio_core::net::TcpStream {
+    fn write_vectored(&mut self, bufs: &[&[u8]]) -> IoResult<usize> {
+        use std::os::unix::io::AsRawFd;
+        let fd = self.as_raw_fd();
+        writev_fd(Some(fd), self, bufs)
+    }
+}
+
+#[cfg(not(unix))]
+impl WriteVectored for ::tokio_core::net::TcpStream {}
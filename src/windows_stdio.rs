@@ -0,0 +1,189 @@
+//! Stdin and stdout on Windows, read and written by threads of their own (see `threaded_io`).
+//!
+//! Console handles are read with `ReadConsoleW` and written with `WriteConsoleW`, converting
+//! between UTF-16 and UTF-8 (see `console_text`). Redirected ones pass bytes through unchanged.
+//! The console modes are put back on exit, including after errors and Ctrl-C.
+
+extern crate winapi;
+
+use std::io::{self, Read, Write};
+use std::os::windows::io::AsRawHandle;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::thread::JoinHandle;
+
+use self::winapi::shared::minwindef::{BOOL, DWORD, FALSE};
+use self::winapi::um::consoleapi::{GetConsoleMode, ReadConsoleW, SetConsoleCtrlHandler, SetConsoleMode, WriteConsoleW};
+use self::winapi::um::ioapiset::CancelSynchronousIo;
+use self::winapi::um::processenv::GetStdHandle;
+use self::winapi::um::winbase::{STD_INPUT_HANDLE, STD_OUTPUT_HANDLE};
+use self::winapi::um::wincon::{ENABLE_ECHO_INPUT, ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT};
+use self::winapi::um::winnt::HANDLE;
+
+use super::console_text::{Utf16Decoder, Utf8Encoder};
+use super::epipe::EpipeAsEof;
+use super::threaded_io::{spawn_reader, spawn_writer};
+use super::{shutdown, BoxedNewPeerFuture, Peer};
+
+/// Ctrl-Z at the start of a console line means EOF, like with `type con`
+const CTRL_Z: u16 = 0x1a;
+
+static MODES_SAVED: AtomicBool = ATOMIC_BOOL_INIT;
+static STDIN_MODE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn console_mode(h: HANDLE) -> Option<DWORD> {
+    let mut mode = 0;
+    if unsafe { GetConsoleMode(h, &mut mode) } == FALSE {
+        None
+    } else {
+        Some(mode)
+    }
+}
+
+/// Put the console input mode back the way it was before `stdio_peer`
+pub fn restore_console_modes() {
+    if MODES_SAVED.swap(false, Ordering::SeqCst) {
+        let h = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+        unsafe { SetConsoleMode(h, STDIN_MODE.load(Ordering::SeqCst) as DWORD) };
+    }
+}
+
+unsafe extern "system" fn on_ctrl(_ctrl_type: DWORD) -> BOOL {
+    restore_console_modes();
+    // Let the default handler end the process
+    FALSE
+}
+
+/// Line input with echo, Ctrl-C handled by the system. Saved to be restored on exit.
+fn set_console_modes(h: HANDLE, original: DWORD) {
+    if MODES_SAVED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    STDIN_MODE.store(original as usize, Ordering::SeqCst);
+    unsafe {
+        SetConsoleMode(h, original | ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT);
+        SetConsoleCtrlHandler(Some(on_ctrl), 1);
+    }
+    shutdown::at_exit(restore_console_modes);
+}
+
+/// Console input as UTF-8
+struct ConsoleRead {
+    h: HANDLE,
+    decoder: Utf16Decoder,
+    decoded: Vec<u8>,
+    pos: usize,
+    at_line_start: bool,
+}
+unsafe impl Send for ConsoleRead {}
+
+impl Read for ConsoleRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.decoded.len() {
+            let mut units = [0u16; 4096];
+            let mut n: DWORD = 0;
+            let ret = unsafe {
+                ReadConsoleW(
+                    self.h,
+                    units.as_mut_ptr() as *mut _,
+                    units.len() as DWORD,
+                    &mut n,
+                    ptr::null_mut(),
+                )
+            };
+            if ret == FALSE {
+                return Err(io::Error::last_os_error());
+            }
+            let units = &units[..n as usize];
+            if self.at_line_start && units.first() == Some(&CTRL_Z) {
+                return Ok(0);
+            }
+            if let Some(&last) = units.last() {
+                self.at_line_start = last == u16::from(b'\n');
+            }
+            self.decoded.clear();
+            self.pos = 0;
+            self.decoder.decode(units, &mut self.decoded);
+        }
+        let n = buf.len().min(self.decoded.len() - self.pos);
+        buf[..n].copy_from_slice(&self.decoded[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Console output from UTF-8
+struct ConsoleWrite {
+    h: HANDLE,
+    encoder: Utf8Encoder,
+}
+unsafe impl Send for ConsoleWrite {}
+
+impl Write for ConsoleWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut units = vec![];
+        self.encoder.encode(buf, &mut units);
+        let mut done = 0;
+        while done < units.len() {
+            let mut n: DWORD = 0;
+            let ret = unsafe {
+                WriteConsoleW(
+                    self.h,
+                    units[done..].as_ptr() as *const _,
+                    (units.len() - done) as DWORD,
+                    &mut n,
+                    ptr::null_mut(),
+                )
+            };
+            if ret == FALSE {
+                return Err(io::Error::last_os_error());
+            }
+            done += n as usize;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Interrupt the blocking read of the stdin thread
+fn cancel_read(t: &JoinHandle<()>) {
+    unsafe { CancelSynchronousIo(t.as_raw_handle() as HANDLE) };
+}
+
+fn stdio_halves() -> io::Result<Peer> {
+    let hin = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+    let hout = unsafe { GetStdHandle(STD_OUTPUT_HANDLE) };
+    let r = match console_mode(hin) {
+        Some(mode) => {
+            set_console_modes(hin, mode);
+            let r = ConsoleRead {
+                h: hin,
+                decoder: Default::default(),
+                decoded: vec![],
+                pos: 0,
+                at_line_start: true,
+            };
+            spawn_reader("websocat-stdin", r, cancel_read)?
+        }
+        None => spawn_reader("websocat-stdin", io::stdin(), cancel_read)?,
+    };
+    let w = match console_mode(hout) {
+        Some(_) => spawn_writer(
+            "websocat-stdout",
+            ConsoleWrite {
+                h: hout,
+                encoder: Default::default(),
+            },
+        )?,
+        None => spawn_writer("websocat-stdout", io::stdout())?,
+    };
+    Ok(Peer::new(r, EpipeAsEof::new(w)))
+}
+
+pub fn stdio_peer() -> BoxedNewPeerFuture {
+    info!("get_stdio_peer (Windows threads)");
+    Box::new(::futures::future::result(stdio_halves().map_err(|e| Box::new(e) as Box<::std::error::Error>)))
+        as BoxedNewPeerFuture
+}
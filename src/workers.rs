@@ -0,0 +1,242 @@
+//! `--workers`: more threads serving a TCP listener, each with its own reactor
+//! and its own listening socket bound with `SO_REUSEPORT`, so that the kernel
+//! spreads incoming connections across them. Linux only.
+//!
+//! Peers are `Rc`-based and sessions never leave the thread that accepted them.
+//! Only process-wide things cross threads: shutdown requests go from the main thread
+//! to the workers, workers send their counters back (see `metrics::collect_from`),
+//! and `--max-sessions` counts the sessions of all threads (see `session_cap`).
+//! Workers record bind outcomes of their listeners together with the main thread
+//! (see `bind_retry::share`) and tell when they have set all of them up
+//! (see `Workers::all_bound`), so that readiness is reported only when every thread
+//! accepts connections.
+
+#[cfg(all(target_os = "linux", feature = "libc"))]
+extern crate libc;
+
+use futures::future::join_all;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::mpsc::Sender;
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle, Interval};
+
+use super::{bind_retry, metrics, shutdown, WebsocatConfiguration};
+
+/// How often workers send their counters to the main thread
+const STATS_INTERVAL_MS: u64 = 1000;
+
+/// Backlog of each listening socket
+#[cfg(all(target_os = "linux", feature = "libc"))]
+const BACKLOG: libc::c_int = 1024;
+
+/// Shutdown reason and, if live sessions are to be closed, the status code
+type Request = (String, Option<u16>);
+
+/// Threads started by `spawn`
+pub struct Workers {
+    threads: Vec<(UnboundedSender<Request>, JoinHandle<bool>)>,
+    /// Completed by each worker once its listeners are bound, dropped if that fails
+    bound: Vec<oneshot::Receiver<()>>,
+}
+
+/// Start `n` threads besides the current one. Each calls `configure` with its number
+/// (starting from 1) and serves the result until shutdown.
+///
+/// Process-wide shutdown requested on the current thread is passed on to the workers,
+/// and their counters show up in `--stats` and `--metrics-addr` of the current thread.
+pub fn spawn<F>(n: usize, configure: F) -> io::Result<Workers>
+where
+    F: Fn(usize) -> Result<WebsocatConfiguration, Box<::std::error::Error>> + Send + Sync + 'static,
+{
+    let configure = Arc::new(configure);
+    let (stats_tx, stats_rx) = mpsc::channel();
+    metrics::collect_from(stats_rx);
+    let mut threads = vec![];
+    let mut bound = vec![];
+    for i in 1..n + 1 {
+        let (tx, rx) = unbounded();
+        let (bound_tx, bound_rx) = oneshot::channel();
+        let configure = configure.clone();
+        let stats = stats_tx.clone();
+        let outcomes = bind_retry::outcomes();
+        let t = thread::Builder::new()
+            .name(format!("websocat-worker-{}", i))
+            .spawn(move || {
+                // A listener that fails here keeps the main thread from reporting readiness too
+                bind_retry::share(outcomes);
+                match run_worker(i, &*configure, rx, &stats, bound_tx) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("websocat: worker {}: {}", i, e);
+                        false
+                    }
+                }
+            })?;
+        threads.push((tx, t));
+        bound.push(bound_rx);
+    }
+    let senders: Vec<_> = threads.iter().map(|x| x.0.clone()).collect();
+    shutdown::forward(move |reason, close_code| {
+        for tx in &senders {
+            let _ = tx.unbounded_send((reason.to_string(), close_code));
+        }
+    });
+    Ok(Workers { threads, bound })
+}
+
+impl Workers {
+    /// Resolves when the listeners of all workers are bound.
+    /// Fails as soon as one of the workers could not bind or could not start at all.
+    /// Can be taken only once.
+    pub fn all_bound(&mut self) -> Box<Future<Item = (), Error = ()>> {
+        let bound = ::std::mem::replace(&mut self.bound, vec![]);
+        Box::new(join_all(bound).map(|_| ()).map_err(|_| ()))
+    }
+
+    /// Shut the workers down, if that was not requested already, and wait for them to drain.
+    /// Fails if some of them failed, naming them.
+    pub fn join(self) -> Result<(), String> {
+        let mut handles = vec![];
+        for (tx, t) in self.threads {
+            let _ = tx.unbounded_send(("the main thread has finished".to_string(), None));
+            handles.push(t);
+        }
+        let mut failed = vec![];
+        for (i, t) in handles.into_iter().enumerate() {
+            match t.join() {
+                Ok(true) => (),
+                _ => failed.push((i + 1).to_string()),
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("--workers thread failed: {}", failed.join(", ")))
+        }
+    }
+}
+
+fn run_worker<F>(
+    i: usize,
+    configure: &F,
+    requests: UnboundedReceiver<Request>,
+    stats: &Sender<(usize, metrics::Snapshot)>,
+    bound: oneshot::Sender<()>,
+) -> Result<(), Box<::std::error::Error>>
+where
+    F: Fn(usize) -> Result<WebsocatConfiguration, Box<::std::error::Error>>,
+{
+    let mut core = Core::new()?;
+    let h = core.handle();
+    let websocat = configure(i)?;
+    let drain_timeout = Duration::from_secs(websocat.opts.drain_timeout);
+
+    h.spawn(requests.for_each(|(reason, close_code)| {
+        match close_code {
+            Some(code) => shutdown::initiate_and_close_sessions(&reason, code),
+            None => shutdown::initiate(&reason),
+        }
+        Ok(())
+    }));
+    let stats2 = stats.clone();
+    let report = Interval::new(Duration::from_millis(STATS_INTERVAL_MS), &h)?.for_each(move |()| {
+        let _ = stats2.send((i, metrics::snapshot()));
+        Ok(())
+    });
+    h.spawn(report.map_err(|_| ()));
+
+    let prog = websocat.serve(
+        h.clone(),
+        Rc::new(move |e: Box<::std::error::Error>| eprintln!("websocat: worker {}: {}", i, e)),
+    );
+    // Listeners are bound, or retrying, when `serve` returns
+    h.spawn(bind_retry::all_bound().map(move |()| {
+        let _ = bound.send(());
+    }));
+    let prog = prog
+        .select(shutdown::drained(&h, drain_timeout))
+        .map(|_| ())
+        .map_err(|_| ());
+    let r = core.run(prog);
+    if shutdown::reason().is_some() {
+        let _ = core.run(shutdown::drained(&h, drain_timeout));
+    }
+    let _ = stats.send((i, metrics::snapshot()));
+    r.map_err(|()| "error running")?;
+    Ok(())
+}
+
+/// Listening socket that other threads can bind to the same address as well
+#[cfg(all(target_os = "linux", feature = "libc"))]
+pub fn bind_reuseport(addr: &SocketAddr, h: &Handle) -> io::Result<TcpListener> {
+    use std::mem::{size_of, zeroed};
+    use std::os::unix::io::FromRawFd;
+
+    let family = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owns the socket from now on, closing it on errors below
+    let l = unsafe { ::std::net::TcpListener::from_raw_fd(fd) };
+    let one: libc::c_int = 1;
+    for &opt in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                opt,
+                &one as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let ret = match *addr {
+        SocketAddr::V4(ref a) => unsafe {
+            let mut sa: libc::sockaddr_in = zeroed();
+            sa.sin_family = libc::AF_INET as libc::sa_family_t;
+            sa.sin_port = a.port().to_be();
+            sa.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            libc::bind(
+                fd,
+                &sa as *const libc::sockaddr_in as *const libc::sockaddr,
+                size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        },
+        SocketAddr::V6(ref a) => unsafe {
+            let mut sa: libc::sockaddr_in6 = zeroed();
+            sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sa.sin6_port = a.port().to_be();
+            sa.sin6_flowinfo = a.flowinfo();
+            sa.sin6_addr.s6_addr = a.ip().octets();
+            sa.sin6_scope_id = a.scope_id();
+            libc::bind(
+                fd,
+                &sa as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+            )
+        },
+    };
+    if ret == -1 || unsafe { libc::listen(fd, BACKLOG) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    TcpListener::from_listener(l, addr, h)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "libc")))]
+pub fn bind_reuseport(_addr: &SocketAddr, _h: &Handle) -> io::Result<TcpListener> {
+    Err(io::Error::new(io::ErrorKind::Other, "--workers is only supported on Linux"))
+}
@@ -1,47 +1,96 @@
+extern crate base64;
 extern crate websocket;
 extern crate hyper;
+#[cfg(feature = "ssl")]
+extern crate native_tls;
+#[cfg(feature = "ssl")]
+extern crate tokio_tls;
 
 use self::websocket::client::async::ClientNew;
 use self::websocket::stream::async::Stream as WsStream;
-use self::websocket::ClientBuilder;
+use self::websocket::{ClientBuilder, WebSocketError};
 use futures::future::Future;
-use futures::stream::Stream;
+use tokio_core::net::TcpStream;
 use tokio_core::reactor::Handle;
 
-use std::cell::RefCell;
+use std::io::{Read, Result as IoResult, Write};
+use std::net::ToSocketAddrs;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::addr::{split_url_zone, with_scope};
+use super::urlnorm;
+use super::error::WebsocatError;
 
 use self::websocket::client::Url;
 
-use super::{box_up_err, peer_err, BoxedNewPeerFuture, Peer};
+use super::{peer_err, BoxedNewPeerFuture, Peer};
 
-use super::ws_peer::{Mode1, PeerForWs, WsReadWrapper, WsWriteWrapper};
+use super::vectored::{write_first, WriteVectored};
+use super::ws_peer::{finish_building_ws_peer, PeerForWs, WsEventHook};
 use super::{once, ConstructParams, Options, PeerConstructor, Specifier};
 
 use self::hyper::header::Headers;
 
+/// Options of the WebSocket client classes
+const WS_CLIENT_OPTIONS: &[&str] = &[
+    "--protocol",
+    "--origin",
+    "--header",
+    "--websocket-version",
+    "--ws-c-uri",
+    "--no-url-normalization",
+    "--no-close",
+    "--text",
+    "--close-timeout",
+    "--flush-timeout",
+    "--include-headers",
+    "--include-headers-every-connect",
+    "--response-header-file",
+];
+
+/// URL (see `urlnorm`), the scope id of its IPv6 zone, which the URL itself
+/// can't keep (see `addr`), and its path and query as written
 #[derive(Debug, Clone)]
-pub struct WsClient(pub Url);
+pub struct WsClient(pub Url, pub Option<u32>, pub Option<String>);
 impl Specifier for WsClient {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
         let url = self.0.clone();
-        once(get_ws_client_peer(&p.tokio_handle, &url, p.program_options))
+        let raw_target = if p.program_options.no_url_normalization {
+            self.2.clone()
+        } else {
+            None
+        };
+        once(get_ws_client_peer(
+            &p.tokio_handle,
+            &url,
+            self.1,
+            raw_target,
+            p.program_options,
+            p.ws_event_hook,
+        ))
     }
     specifier_boilerplate!(noglobalstate singleconnect no_subspec typ=WebSocket);
 }
 specifier_class!(
     name = WsClientClass,
     target = WsClient,
-    prefixes = ["ws://", "wss://"],
+    prefixes = ["ws://"],
     arg_handling = {
         fn construct(
             self: &WsClientClass,
             full: &str,
             _just_arg: &str,
         ) -> super::Result<Rc<Specifier>> {
-            Ok(Rc::new(WsClient(full.parse()?)))
+            let (url, scope) = split_url_zone(full)?;
+            let raw = urlnorm::request_target(&url);
+            Ok(Rc::new(WsClient(urlnorm::normalize(&url).parse()?, scope, Some(raw))))
         }
     },
+    consumed_options = WS_CLIENT_OPTIONS,
     help = r#"
 WebSocket client. Argument is host and URL.
 
@@ -49,8 +98,43 @@ Example: manually interact with a web socket
 
     websocat - ws://echo.websocket.org/
 
+IPv6 addresses go in brackets, with an optional zone written after `%25`,
+like `ws://[fe80::1%25eth0]:8080/`. The zone is not sent in `Host:`.
+
+Path and query get percent-encoded where needed (`ws://host/a b#c` requests
+`/a%20b%23c`), existing `%XX` sequences are kept. Use --no-url-normalization
+to send them as written. User name and password, like in `ws://user:pass@host/`,
+are sent as Basic authorization, unless there is an `Authorization` --header.
+
 Example: forward TCP port 4554 to a websocket
 
+    websocat tcp-l:127.0.0.1:4554 ws://127.0.0.1/some_websocket"#
+);
+
+#[cfg(feature = "ssl")]
+specifier_class!(
+    name = WssClientClass,
+    target = WsClient,
+    prefixes = ["wss://"],
+    arg_handling = {
+        fn construct(
+            self: &WssClientClass,
+            full: &str,
+            _just_arg: &str,
+        ) -> super::Result<Rc<Specifier>> {
+            let (url, scope) = split_url_zone(full)?;
+            Ok(Rc::new(WsClient(urlnorm::normalize(&url).parse()?, scope, None)))
+        }
+    },
+    consumed_options = WS_CLIENT_OPTIONS,
+    help = r#"
+WebSocket client over TLS. Argument is host and URL.
+Only in websocat builds with `ssl` feature.
+
+IPv6 zones are written as for `ws://`, like `wss://[fe80::1%25eth0]/`.
+
+Example: forward TCP port 4554 to a secure websocket
+
     websocat tcp-l:127.0.0.1:4554 wss://127.0.0.1/some_websocket"#
 );
 
@@ -58,16 +142,28 @@ Example: forward TCP port 4554 to a websocket
 pub struct WsConnect<T: Specifier>(pub T);
 impl<T: Specifier> Specifier for WsConnect<T> {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        let hook = p.ws_event_hook.clone();
+        let mut p = p;
+        p.ws_event_hook = None;
         let inner = self.0.construct(p.clone());
 
-        let url: Url = match p.program_options.ws_c_uri.parse() {
+        let uri = p.program_options.ws_c_uri.clone();
+        let url: Url = match urlnorm::normalize(&uri).parse() {
             Ok(x) => x,
             Err(e) => return PeerConstructor::ServeOnce(peer_err(e)),
         };
+        let raw_target = if p.program_options.no_url_normalization {
+            Some(urlnorm::request_target(&uri))
+        } else {
+            None
+        };
 
         let opts = p.program_options;
+        let h = p.tokio_handle;
 
-        inner.map(move |q| get_ws_client_peer_wrapped(&url, q, opts.clone()))
+        inner.map(move |q| {
+            get_ws_client_peer_wrapped(&h, &url, raw_target.clone(), q, opts.clone(), hook.clone())
+        })
     }
     specifier_boilerplate!(noglobalstate has_subspec typ=WebSocket);
     self_0_is_subspecifier!(proxy_is_multiconnect);
@@ -77,6 +173,7 @@ specifier_class!(
     target = WsConnect,
     prefixes = ["ws-c:", "c-ws:", "ws-connect:", "connect-ws:"],
     arg_handling = subspec,
+    consumed_options = WS_CLIENT_OPTIONS,
     help = r#"
 Low-level WebSocket connector. Argument is a subspecifier.
 
@@ -93,25 +190,183 @@ Example: connect to echo server, observing WebSocket TCP packet exchange
 "#
 );
 
-fn get_ws_client_peer_impl<S, F>(uri: &Url, opts: Rc<Options>, f: F) -> BoxedNewPeerFuture
+/// How far connecting got, for choosing exit code on failure.
+/// Values of 100 and more are HTTP status of the handshake response.
+type Progress = Arc<AtomicUsize>;
+/// Done by the websocket library, can't tell
+const PROGRESS_UNKNOWN: usize = 0;
+const PROGRESS_RESOLVING: usize = 1;
+const PROGRESS_CONNECTING: usize = 2;
+const PROGRESS_HANDSHAKE: usize = 3;
+
+/// Remembers HTTP status code of the handshake response passing through.
+///
+/// With `--no-url-normalization`, also puts the request target as written
+/// into the request line, in place of the one the library made from the URL.
+pub struct StatusSniff<T> {
+    inner: T,
+    progress: Progress,
+    head: Vec<u8>,
+    raw_target: Option<String>,
+    /// Start of the request until its first line is complete, then what is left to write of it
+    out: Vec<u8>,
+}
+
+impl<T> StatusSniff<T> {
+    fn new(inner: T, progress: Progress, raw_target: Option<String>) -> Self {
+        StatusSniff {
+            inner,
+            progress,
+            head: Vec::with_capacity(12),
+            raw_target,
+            out: vec![],
+        }
+    }
+}
+
+impl<T: Write> StatusSniff<T> {
+    fn write_out(&mut self) -> IoResult<()> {
+        while !self.out.is_empty() {
+            let n = self.inner.write(&self.out)?;
+            if n == 0 {
+                return Err(::std::io::ErrorKind::WriteZero.into());
+            }
+            self.out.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for StatusSniff<T> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let n = self.inner.read(buf)?;
+        if self.head.len() < 12 {
+            let l = (12 - self.head.len()).min(n);
+            self.head.extend_from_slice(&buf[..l]);
+            // `HTTP/1.1 403`
+            if self.head.len() == 12 && self.head.starts_with(b"HTTP/1.") {
+                let status = ::std::str::from_utf8(&self.head[9..12]).ok().and_then(|x| x.parse().ok());
+                if let Some(x) = status {
+                    self.progress.store(x, Ordering::SeqCst);
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+impl<T: AsyncRead> AsyncRead for StatusSniff<T> {}
+impl<T: WriteVectored> WriteVectored for StatusSniff<T> {
+    fn write_vectored(&mut self, bufs: &[&[u8]]) -> IoResult<usize> {
+        if self.raw_target.is_some() || !self.out.is_empty() {
+            return write_first(self, bufs);
+        }
+        self.inner.write_vectored(bufs)
+    }
+}
+impl<T: Write> Write for StatusSniff<T> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.raw_target.is_some() {
+            self.out.extend_from_slice(buf);
+            let line = match self.raw_target {
+                Some(ref t) => urlnorm::rewrite_request_line(&self.out, t.as_bytes()),
+                None => None,
+            };
+            if let Some(x) = line {
+                self.out = x;
+                self.raw_target = None;
+                match self.write_out() {
+                    Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => (),
+                    Err(e) => return Err(e),
+                    Ok(()) => (),
+                }
+            }
+            return Ok(buf.len());
+        }
+        self.write_out()?;
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        if self.raw_target.is_none() {
+            self.write_out()?;
+        }
+        self.inner.flush()
+    }
+}
+impl<T: AsyncWrite> AsyncWrite for StatusSniff<T> {
+    fn shutdown(&mut self) -> ::futures::Poll<(), ::std::io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+fn handshake_error(e: WebSocketError, progress: usize, url: String) -> Box<::std::error::Error> {
+    let message = e.to_string();
+    let e = match e {
+        #[cfg(feature = "ssl")]
+        WebSocketError::TlsError(_)
+        | WebSocketError::TlsHandshakeFailure
+        | WebSocketError::TlsHandshakeInterruption => WebsocatError::Tls { url, message },
+        WebSocketError::IoError(source) => if progress == PROGRESS_RESOLVING {
+            WebsocatError::Dns { url, source }
+        } else {
+            WebsocatError::Connect { addr: url, source }
+        },
+        _ if progress >= 100 => WebsocatError::Handshake {
+            url,
+            status: Some(progress as u16),
+            message,
+        },
+        WebSocketError::ResponseError(_) => WebsocatError::Handshake {
+            url,
+            status: None,
+            message,
+        },
+        e => return Box::new(e),
+    };
+    Box::new(e)
+}
+
+/// `Authorization` from user name and password in the URL, unless given by --header
+fn basic_auth(uri: &Url, opts: &Options) -> Option<String> {
+    if uri.username().is_empty() && uri.password().is_none() {
+        return None;
+    }
+    if opts.custom_headers.iter().any(|x| x.0.eq_ignore_ascii_case("authorization")) {
+        return None;
+    }
+    let mut credentials = urlnorm::percent_decode(uri.username());
+    credentials.push(b':');
+    credentials.extend(urlnorm::percent_decode(uri.password().unwrap_or("")));
+    Some(format!("Basic {}", base64::encode(&credentials)))
+}
+
+fn get_ws_client_peer_impl<S, F>(
+    handle: &Handle,
+    uri: &Url,
+    opts: Rc<Options>,
+    hook: Option<WsEventHook>,
+    progress: Progress,
+    f: F,
+) -> BoxedNewPeerFuture
 where
-    S: WsStream + Send + 'static,
-    F: FnOnce(ClientBuilder) -> ClientNew<S>,
+    S: WsStream + WriteVectored + Send + 'static,
+    F: FnOnce(ClientBuilder<'static>) -> ClientNew<S>,
 {
-    let mode1 = if opts.websocket_text_mode {
-        Mode1::Text
-    } else {
-        Mode1::Binary
+    let h = handle.clone();
+    let stage1 = match ClientBuilder::new(uri.as_str()) {
+        Ok(x) => x,
+        Err(e) => return peer_err(e),
     };
-
-    let stage1 = ClientBuilder::from_url(uri);
-    let stage2 = if opts.custom_headers.is_empty() {
+    let auth = basic_auth(uri, &opts);
+    let stage2 = if opts.custom_headers.is_empty() && auth.is_none() {
         stage1
     } else {
         let mut h = Headers::new();
         for (hn,hv) in opts.custom_headers.clone() {
             h.append_raw(hn,hv);
         }
+        if let Some(x) = auth {
+            h.append_raw("Authorization", x.into_bytes());
+        }
         stage1.custom_headers(&h)
     };
     let stage3 = if let Some(ref x) = opts.origin {
@@ -130,46 +385,165 @@ where
         stage4
     };
     let after_connect = f(stage5);
+    let url = uri.as_str().to_string();
+    let url2 = url.clone();
     Box::new(
         after_connect
-            .map(move |(duplex, _)| {
+            .map(move |(duplex, headers)| {
                 info!("Connected to ws",);
-                let (sink, stream) = duplex.split();
-                let mpsink = Rc::new(RefCell::new(sink));
-
-                let ws_str = WsReadWrapper {
-                    s: stream,
-                    pingreply: mpsink.clone(),
-                    debt: Default::default(),
-                };
-                let ws_sin = WsWriteWrapper(mpsink, mode1, !opts.websocket_dont_close);
-
-                let ws = Peer::new(ws_str, ws_sin);
-                ws
+                super::events::note_uri(&url2);
+                let protocol = super::lifecycle::protocol(&headers);
+                super::lifecycle::handshake(&opts, &super::lifecycle::header_pairs(&headers), protocol);
+                super::response_headers::note(&opts, &headers);
+                let close_on_shutdown = !opts.websocket_dont_close;
+                finish_building_ws_peer(&opts, duplex, close_on_shutdown, false, &h, hook)
             })
-            .map_err(box_up_err),
+            .map_err(move |e| {
+                super::metrics::handshake_failed();
+                super::events::emit("handshake_failed", vec![("error", format!("{}", e).into())]);
+                handshake_error(e, progress.load(Ordering::SeqCst), url)
+            }),
     ) as BoxedNewPeerFuture
 }
 
-pub fn get_ws_client_peer(handle: &Handle, uri: &Url, opts: Rc<Options>) -> BoxedNewPeerFuture {
+pub fn get_ws_client_peer(
+    handle: &Handle,
+    uri: &Url,
+    scope: Option<u32>,
+    raw_target: Option<String>,
+    opts: Rc<Options>,
+    hook: Option<WsEventHook>,
+) -> BoxedNewPeerFuture {
     info!("get_ws_client_peer");
-    get_ws_client_peer_impl(uri, opts, |before_connect| {
-        #[cfg(feature = "ssl")]
-        let after_connect = before_connect.async_connect(None, handle);
-        #[cfg(not(feature = "ssl"))]
-        let after_connect = before_connect.async_connect_insecure(handle);
-        after_connect
+    let progress = Arc::new(AtomicUsize::new(PROGRESS_UNKNOWN));
+    if uri.scheme() == "ws" {
+        return get_plain_ws_client_peer(handle, uri, scope, raw_target, opts, hook, progress);
+    }
+    if raw_target.is_some() {
+        warn!("--no-url-normalization only applies to ws:// URLs");
+    }
+    #[cfg(feature = "ssl")]
+    {
+        if let Some(scope) = scope {
+            return get_scoped_wss_client_peer(handle, uri, scope, opts, hook, progress);
+        }
+        get_ws_client_peer_impl(handle, uri, opts, hook, progress, |before_connect| {
+            before_connect.async_connect(None, handle)
+        })
+    }
+    #[cfg(not(feature = "ssl"))]
+    {
+        // Not going to connect in plain text to a server expecting TLS
+        super::peer_strerr(super::specparse::unsupported("wss://").unwrap_or("SSL is not compiled in"))
+    }
+}
+
+/// Connect `ws://` without the library's help, to tell DNS, TCP and HTTP failures apart
+fn get_plain_ws_client_peer(
+    handle: &Handle,
+    uri: &Url,
+    scope: Option<u32>,
+    raw_target: Option<String>,
+    opts: Rc<Options>,
+    hook: Option<WsEventHook>,
+    progress: Progress,
+) -> BoxedNewPeerFuture {
+    let h = handle.clone();
+    let p = progress.clone();
+    let addr = uri.with_default_port(|_| Ok(80))
+        .and_then(|x| x.to_socket_addrs())
+        .map(|mut x| x.next().map(|a| with_scope(a, scope)));
+    get_ws_client_peer_impl(handle, uri, opts, hook, progress, move |before_connect| {
+        p.store(PROGRESS_RESOLVING, Ordering::SeqCst);
+        let addr = match addr {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                let e = ::std::io::Error::new(::std::io::ErrorKind::NotFound, "no addresses for host");
+                return Box::new(::futures::future::err(WebSocketError::IoError(e)));
+            }
+            Err(e) => return Box::new(::futures::future::err(WebSocketError::IoError(e))),
+        };
+        p.store(PROGRESS_CONNECTING, Ordering::SeqCst);
+        Box::new(
+            TcpStream::connect(&addr, &h)
+                .map_err(WebSocketError::IoError)
+                .and_then(move |s| {
+                    p.store(PROGRESS_HANDSHAKE, Ordering::SeqCst);
+                    before_connect.async_connect_on(StatusSniff::new(s, p, raw_target))
+                }),
+        )
     })
 }
 
+/// `wss://` to an IPv6 address with a zone. The library would connect without the scope id,
+/// so the TCP connection and the TLS handshake are made here.
+#[cfg(feature = "ssl")]
+fn get_scoped_wss_client_peer(
+    handle: &Handle,
+    uri: &Url,
+    scope: u32,
+    opts: Rc<Options>,
+    hook: Option<WsEventHook>,
+    progress: Progress,
+) -> BoxedNewPeerFuture {
+    use self::native_tls::TlsConnector;
+    use self::tokio_tls::TlsConnectorExt;
+
+    let h = handle.clone();
+    let p = progress.clone();
+    let addr = uri.with_default_port(|_| Ok(443))
+        .and_then(|x| x.to_socket_addrs())
+        .map(|mut x| x.next().map(|a| with_scope(a, Some(scope))));
+    // Without brackets, as in the certificate
+    let domain = uri.host_str().unwrap_or("").trim_matches(|c| c == '[' || c == ']').to_string();
+    get_ws_client_peer_impl(handle, uri, opts, hook, progress, move |before_connect| {
+        p.store(PROGRESS_RESOLVING, Ordering::SeqCst);
+        let addr = match addr {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                let e = ::std::io::Error::new(::std::io::ErrorKind::NotFound, "no addresses for host");
+                return Box::new(::futures::future::err(WebSocketError::IoError(e)));
+            }
+            Err(e) => return Box::new(::futures::future::err(WebSocketError::IoError(e))),
+        };
+        let connector = match TlsConnector::builder().and_then(|x| x.build()) {
+            Ok(x) => x,
+            Err(e) => return Box::new(::futures::future::err(WebSocketError::TlsError(e))),
+        };
+        p.store(PROGRESS_CONNECTING, Ordering::SeqCst);
+        Box::new(
+            TcpStream::connect(&addr, &h)
+                .map_err(WebSocketError::IoError)
+                .and_then(move |s| {
+                    p.store(PROGRESS_HANDSHAKE, Ordering::SeqCst);
+                    connector.connect_async(&domain, s).map_err(WebSocketError::TlsError)
+                })
+                .and_then(move |s| before_connect.async_connect_on(Box::new(s) as Box<WsStream + Send>)),
+        )
+    })
+}
+
+/// TLS streams from `async_connect`: records can't be written with `writev`
+#[cfg(feature = "ssl")]
+impl WriteVectored for Box<WsStream + Send> {}
+
 unsafe impl Send for PeerForWs {
     //! https://github.com/cyderize/rust-websocket/issues/168
 }
 
-pub fn get_ws_client_peer_wrapped(uri: &Url, inner: Peer, opts: Rc<Options>) -> BoxedNewPeerFuture {
+pub fn get_ws_client_peer_wrapped(
+    handle: &Handle,
+    uri: &Url,
+    raw_target: Option<String>,
+    inner: Peer,
+    opts: Rc<Options>,
+    hook: Option<WsEventHook>,
+) -> BoxedNewPeerFuture {
     info!("get_ws_client_peer_wrapped");
-    get_ws_client_peer_impl(uri, opts, |before_connect| {
-        let after_connect = before_connect.async_connect_on(PeerForWs(inner));
+    let progress = Arc::new(AtomicUsize::new(PROGRESS_HANDSHAKE));
+    let p = progress.clone();
+    get_ws_client_peer_impl(handle, uri, opts, hook, progress, move |before_connect| {
+        let after_connect = before_connect.async_connect_on(StatusSniff::new(PeerForWs(inner), p, raw_target));
         after_connect
     })
 }
@@ -1,13 +1,22 @@
+extern crate rand;
 extern crate websocket;
 
+use self::rand::Rng;
+
 use self::websocket::stream::async::Stream as WsStream;
-use self::websocket::OwnedMessage;
+use self::websocket::message::CloseData;
+use self::websocket::codec::ws::{Context, MessageCodec};
+use self::websocket::{OwnedMessage, WebSocketError};
 use futures;
+use futures::future::Future;
 use futures::sink::Sink;
 use futures::stream::Stream;
+use futures::task::Task;
 use std;
-use std::io::Result as IoResult;
+use std::io::{Error as IoError, Result as IoResult};
 use std::io::{Read, Write};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_io::{self, AsyncRead, AsyncWrite};
 
 use std::cell::RefCell;
@@ -15,44 +24,458 @@ use std::rc::Rc;
 
 use futures::Async::{NotReady, Ready};
 
-use super::{brokenpipe, io_other_error, wouldblock, Peer};
+use super::{brokenpipe, io_other_error, wouldblock, Options, Peer};
 
+use super::error::WebsocatError;
+use super::metrics;
+use super::vectored::WriteVectored;
 use super::ReadDebt;
 
-type MultiProducerWsSink<T> = Rc<
-    RefCell<
-        futures::stream::SplitSink<
-            tokio_io::codec::Framed<T, websocket::async::MessageCodec<websocket::OwnedMessage>>,
-        >,
-    >,
->;
-type WsSource<T> = futures::stream::SplitStream<
-    tokio_io::codec::Framed<T, websocket::async::MessageCodec<websocket::OwnedMessage>>,
->;
-
-pub struct WsReadWrapper<T: WsStream + 'static> {
+type MultiProducerWsSink<T> = Rc<RefCell<FrameSink<T>>>;
+type WsSource<T> = tokio_io::codec::Framed<SharedStream<T>, MessageCodec<OwnedMessage>>;
+pub type Duplex<T> =
+    tokio_io::codec::Framed<T, websocket::async::MessageCodec<websocket::OwnedMessage>>;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Upgraded connection, read by the library's codec and written by `FrameSink`
+pub struct SharedStream<T>(Rc<RefCell<T>>);
+
+impl<T: Read> Read for SharedStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+impl<T: AsyncRead> AsyncRead for SharedStream<T> {}
+impl<T: Write> Write for SharedStream<T> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+impl<T: AsyncWrite> AsyncWrite for SharedStream<T> {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        self.0.borrow_mut().shutdown()
+    }
+}
+
+/// Frame header: FIN, opcode, length and masking key if any. Returns the used length of `h`.
+fn encode_header(h: &mut [u8; 14], opcode: u8, len: usize, mask: Option<[u8; 4]>) -> usize {
+    h[0] = 0x80 | opcode;
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    let mut n = if len < 126 {
+        h[1] = mask_bit | len as u8;
+        2
+    } else if len <= 0xFFFF {
+        h[1] = mask_bit | 126;
+        h[2] = (len >> 8) as u8;
+        h[3] = len as u8;
+        4
+    } else {
+        h[1] = mask_bit | 127;
+        for i in 0..8 {
+            h[2 + i] = ((len as u64) >> (56 - 8 * i)) as u8;
+        }
+        10
+    };
+    if let Some(key) = mask {
+        h[n..n + 4].copy_from_slice(&key);
+        n += 4;
+    }
+    n
+}
+
+/// Masking keys must be unpredictable to whoever controls the payload (RFC 6455, 10.3),
+/// so they come from a cryptographically secure generator
+fn mask_key() -> [u8; 4] {
+    let key: u32 = rand::thread_rng().gen();
+    [(key >> 24) as u8, (key >> 16) as u8, (key >> 8) as u8, key as u8]
+}
+
+/// Frames with payloads up to this size get corked
+const CORK_FRAME_MAX: usize = 1024;
+/// Corked frames are written out before they take more than this
+const CORK_BATCH_MAX: usize = 16384;
+
+/// Sending half of a WebSocket connection. The header of each frame is built on the stack
+/// and written together with the payload by one vectored write where the stream allows,
+/// without copying the payload. Clients mask into a buffer reused for all messages.
+///
+/// Unless `--cork-window-us` is 0, small frames are gathered in `pending` instead, and written
+/// out together on flush. The transfer loop flushes before waiting for more input.
+///
+/// Bytes of frames taken and bytes written to the stream go to the summary as
+/// `ws_bytes_accepted` and `ws_bytes_flushed`, so output lost on exit shows up there.
+/// The number of writes to the stream goes there as `ws_writes`.
+pub struct FrameSink<T> {
+    stream: Rc<RefCell<T>>,
+    /// Client role: frames are masked
+    mask: bool,
+    masked: Vec<u8>,
+    /// Rest of frames that were not written completely, and corked frames
+    pending: Vec<u8>,
+    cork: bool,
+    accepted: u64,
+    flushed: u64,
+    writes: u64,
+}
+
+impl<T: WsStream + WriteVectored> FrameSink<T> {
+    /// Write out the rest of earlier frames. `Ok(false)` if the stream is not ready.
+    fn write_pending(&mut self) -> IoResult<bool> {
+        while !self.pending.is_empty() {
+            let n = match self.stream.borrow_mut().write(&self.pending) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                return Err(::std::io::ErrorKind::WriteZero.into());
+            }
+            self.writes += 1;
+            self.pending.drain(..n);
+            self.flushed += n as u64;
+        }
+        Ok(true)
+    }
+
+    /// Send a frame, keeping what the stream did not take for later.
+    /// `Ok(false)` if earlier frames are still being written.
+    pub fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> IoResult<bool> {
+        let corked = self.cork && payload.len() <= CORK_FRAME_MAX
+            && self.pending.len() + payload.len() + 14 <= CORK_BATCH_MAX;
+        if corked {
+            self.cork_frame(opcode, payload);
+            return Ok(true);
+        }
+        if !self.write_pending()? {
+            return Ok(false);
+        }
+        let mut header = [0; 14];
+        if !self.mask {
+            let hlen = encode_header(&mut header, opcode, payload.len(), None);
+            self.write_frame(&header[..hlen], payload)?;
+            return Ok(true);
+        }
+        let key = mask_key();
+        let hlen = encode_header(&mut header, opcode, payload.len(), Some(key));
+        let mut masked = ::std::mem::replace(&mut self.masked, vec![]);
+        masked.clear();
+        masked.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i & 3]));
+        let ret = self.write_frame(&header[..hlen], &masked);
+        self.masked = masked;
+        ret.map(|()| true)
+    }
+
+    /// Append a frame to `pending`, to be written out on flush
+    fn cork_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mut header = [0; 14];
+        let key = if self.mask { Some(mask_key()) } else { None };
+        let hlen = encode_header(&mut header, opcode, payload.len(), key);
+        self.accepted += (hlen + payload.len()) as u64;
+        self.pending.extend_from_slice(&header[..hlen]);
+        match key {
+            Some(key) => self.pending
+                .extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i & 3])),
+            None => self.pending.extend_from_slice(payload),
+        }
+    }
+
+    fn write_frame(&mut self, header: &[u8], payload: &[u8]) -> IoResult<()> {
+        let n = match self.stream.borrow_mut().write_vectored(&[header, payload]) {
+            Ok(n) => {
+                self.writes += 1;
+                n
+            }
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(e),
+        };
+        self.accepted += (header.len() + payload.len()) as u64;
+        self.flushed += n as u64;
+        if n < header.len() {
+            self.pending.extend_from_slice(&header[n..]);
+            self.pending.extend_from_slice(payload);
+        } else {
+            self.pending.extend_from_slice(&payload[n - header.len()..]);
+        }
+        Ok(())
+    }
+}
+
+impl<T> Drop for FrameSink<T> {
+    fn drop(&mut self) {
+        if self.accepted == 0 {
+            return;
+        }
+        if self.flushed < self.accepted {
+            warn!("{} bytes of WebSocket output were not sent", self.accepted - self.flushed);
+        }
+        metrics::add("ws_bytes_accepted", self.accepted);
+        metrics::add("ws_bytes_flushed", self.flushed);
+        metrics::add("ws_writes", self.writes);
+    }
+}
+
+impl<T: WsStream + WriteVectored> Sink for FrameSink<T> {
+    type SinkItem = OwnedMessage;
+    type SinkError = WebSocketError;
+
+    fn start_send(&mut self, msg: OwnedMessage) -> futures::StartSend<OwnedMessage, WebSocketError> {
+        let sent = match msg {
+            OwnedMessage::Text(ref x) => self.send_frame(OPCODE_TEXT, x.as_bytes())?,
+            OwnedMessage::Binary(ref x) => self.send_frame(OPCODE_BINARY, x)?,
+            OwnedMessage::Ping(ref x) => self.send_frame(OPCODE_PING, x)?,
+            OwnedMessage::Pong(ref x) => self.send_frame(OPCODE_PONG, x)?,
+            OwnedMessage::Close(ref x) => {
+                let mut payload = vec![];
+                if let Some(ref cd) = *x {
+                    payload.push((cd.status_code >> 8) as u8);
+                    payload.push(cd.status_code as u8);
+                    payload.extend_from_slice(cd.reason.as_bytes());
+                }
+                self.send_frame(OPCODE_CLOSE, &payload)?
+            }
+        };
+        if sent {
+            Ok(futures::AsyncSink::Ready)
+        } else {
+            Ok(futures::AsyncSink::NotReady(msg))
+        }
+    }
+
+    fn poll_complete(&mut self) -> futures::Poll<(), WebSocketError> {
+        if !self.write_pending()? {
+            return Ok(NotReady);
+        }
+        try_nb!(self.stream.borrow_mut().flush());
+        Ok(Ready(()))
+    }
+}
+
+/// Receiving errors of the WebSocket library, other than I/O ones, are protocol violations
+fn protocol_error(e: WebSocketError) -> IoError {
+    match e {
+        WebSocketError::IoError(x) => x,
+        e => WebsocatError::from(e).into(),
+    }
+}
+
+/// Progress of the closing handshake, shared by read and write parts of a WebSocket peer
+#[derive(Default)]
+pub struct CloseState {
+    /// Our Close frame is already sent (or queued)
+    pub sent: bool,
+    /// Peer's Close frame arrived, with status code and reason if any
+    pub received: Option<Option<(u16, String)>>,
+    /// Writing part waiting in `shutdown` for the peer's Close
+    waiter: Option<Task>,
+}
+pub type HCloseState = Rc<RefCell<CloseState>>;
+
+/// WebSocket control frame, as reported to observers like `record:`
+pub enum WsEvent<'a> {
+    Ping(&'a [u8]),
+    Pong(&'a [u8]),
+    Close(Option<(u16, &'a str)>),
+}
+pub trait WsEventObserver {
+    /// `incoming` is `false` for control frames sent by us
+    fn on_event(&self, incoming: bool, ev: &WsEvent);
+}
+pub type WsEventHook = Rc<WsEventObserver>;
+
+fn report(hook: &Option<WsEventHook>, incoming: bool, ev: WsEvent) {
+    if let Some(ref h) = *hook {
+        h.on_event(incoming, &ev);
+    }
+}
+
+thread_local! {
+    static SHUTDOWN_CLOSE_CODE: RefCell<Option<(u16, String)>> = RefCell::new(None);
+    static DEFER_CLOSE: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    static MESSAGE_KIND: std::cell::Cell<Option<Mode1>> = std::cell::Cell::new(None);
+}
+
+/// Make WebSocket peers shut down from within `f` only flush, without sending Close.
+/// For `--close-stdin-only`, which sends Close later, when the incoming direction ends.
+pub fn without_close<T, F: FnOnce() -> T>(f: F) -> T {
+    let old = DEFER_CLOSE.with(|c| c.replace(true));
+    let ret = f();
+    DEFER_CLOSE.with(|c| c.set(old));
+    ret
+}
+
+/// Make WebSocket peers shut down from within `f` send this status code in their Close frame.
+/// Used by overlays and session logic that shut down through several layers of wrappers.
+pub fn with_close_code<T, F: FnOnce() -> T>(code: u16, f: F) -> T {
+    with_close_reason(code, "", f)
+}
+
+/// For readers that know whether the message they return is text or binary,
+/// like `jsonwrap:` with --jsonl-input. The transfer loop picks it up with `read_message_kind`.
+pub fn note_message_kind(kind: Mode1) {
+    MESSAGE_KIND.with(|c| c.set(Some(kind)));
+}
+
+/// `r.read(buf)`, and the message kind the reader noted while doing it, if any
+pub fn read_message_kind<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> (IoResult<usize>, Option<Mode1>) {
+    MESSAGE_KIND.with(|c| c.set(None));
+    let ret = r.read(buf);
+    (ret, MESSAGE_KIND.with(|c| c.replace(None)))
+}
+
+/// Make WebSocket peers written to from within `f` send messages of this kind
+/// instead of following --text
+pub fn with_message_kind<T, F: FnOnce() -> T>(kind: Option<Mode1>, f: F) -> T {
+    let old = MESSAGE_KIND.with(|c| c.replace(kind));
+    let ret = f();
+    MESSAGE_KIND.with(|c| c.set(old));
+    ret
+}
+
+/// Like `with_close_code`, with a reason in the Close frame
+pub fn with_close_reason<T, F: FnOnce() -> T>(code: u16, reason: &str, f: F) -> T {
+    let old = SHUTDOWN_CLOSE_CODE.with(|c| ::std::mem::replace(&mut *c.borrow_mut(), Some((code, reason.to_string()))));
+    let ret = f();
+    SHUTDOWN_CLOSE_CODE.with(|c| *c.borrow_mut() = old);
+    ret
+}
+
+pub struct WsReadWrapper<T: WsStream + WriteVectored + 'static> {
     pub s: WsSource<T>,
     pub pingreply: MultiProducerWsSink<T>,
     pub debt: ReadDebt,
+    pub close: HCloseState,
+    /// Server role: after Close is received, wait for client to close TCP connection
+    pub wait_for_fin: bool,
+    pub close_timeout: Option<Duration>,
+    pub handle: Handle,
+    pub drain_timer: Option<Timeout>,
+    pub hook: Option<WsEventHook>,
 }
 
-impl<T: WsStream + 'static> AsyncRead for WsReadWrapper<T> {}
+impl<T: WsStream + WriteVectored + 'static> WsReadWrapper<T> {
+    fn handle_close(&mut self, x: Option<CloseData>) -> IoResult<()> {
+        let code = x.map(|cd| (cd.status_code, cd.reason));
+        match code {
+            Some((c, ref r)) => info!("Received WebSocket close: code {} {}", c, r),
+            None => info!("Received WebSocket close without status code"),
+        }
+        if let Some(&(c, ref r)) = code.as_ref() {
+            super::metrics::close_received(c, r);
+            super::events::note_close(c);
+            super::events::emit("close_received", vec![("code", c.into()), ("reason", r.as_str().into())]);
+        }
+        super::middleware::note_close_received(code.clone());
+        if !self.wait_for_fin && code.as_ref().map_or(false, |&(c, _)| c != 1000 && c != 1001) {
+            super::exit_code::note_abnormal_close();
+        }
+        report(
+            &self.hook,
+            true,
+            WsEvent::Close(code.as_ref().map(|&(c, ref r)| (c, r.as_str()))),
+        );
+        let already_sent = {
+            let mut cs = self.close.borrow_mut();
+            cs.received = Some(code.clone());
+            if let Some(t) = cs.waiter.take() {
+                t.notify();
+            }
+            cs.sent
+        };
+        if already_sent {
+            info!("Close handshake completed");
+            return Ok(());
+        }
+        // Echo the status code back, as RFC 6455 5.5.1 suggests
+        let reply = OwnedMessage::Close(code.as_ref().map(|&(c, _)| CloseData::new(c, "".to_string())));
+        let mut sink = self.pingreply.borrow_mut();
+        match sink.start_send(reply).map_err(io_other_error)? {
+            futures::AsyncSink::NotReady(_) => {
+                warn!("Failed to reply to WebSocket close due to channel contention");
+            }
+            futures::AsyncSink::Ready => {
+                self.close.borrow_mut().sent = true;
+                report(&self.hook, false, WsEvent::Close(code.as_ref().map(|&(c, _)| (c, ""))));
+                let _ = sink.poll_complete().map_err(io_other_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard everything after Close until the client closes TCP connection
+    fn drain(&mut self) -> IoResult<usize> {
+        loop {
+            match self.s.poll() {
+                Ok(Ready(Some(_))) => {
+                    debug!("Discarding a frame after close");
+                    continue;
+                }
+                Ok(Ready(None)) => {
+                    debug!("Client closed the connection after close handshake");
+                    return brokenpipe();
+                }
+                Err(_) => return brokenpipe(),
+                Ok(NotReady) => (),
+            }
+            let timeout = match self.close_timeout {
+                Some(x) => x,
+                None => return brokenpipe(),
+            };
+            if self.drain_timer.is_none() {
+                self.drain_timer = Some(Timeout::new(timeout, &self.handle)?);
+            }
+            return match self.drain_timer.as_mut().unwrap().poll()? {
+                Ready(()) => {
+                    info!("Client have not closed the connection after close handshake");
+                    brokenpipe()
+                }
+                NotReady => wouldblock(),
+            };
+        }
+    }
+}
 
-impl<T: WsStream + 'static> Read for WsReadWrapper<T> {
+impl<T: WsStream + WriteVectored + 'static> AsyncRead for WsReadWrapper<T> {}
+
+impl<T: WsStream + WriteVectored + 'static> Read for WsReadWrapper<T> {
     fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
         if let Some(ret) = self.debt.check_debt(buf) {
             return ret;
         }
-        match self.s.poll().map_err(io_other_error)? {
-            Ready(Some(OwnedMessage::Close(_))) => {
-                debug!("incoming close");
+        if self.close.borrow().received.is_some() {
+            return if self.wait_for_fin {
+                self.drain()
+            } else {
                 brokenpipe()
+            };
+        }
+        match self.s.poll().map_err(protocol_error)? {
+            Ready(Some(OwnedMessage::Close(x))) => {
+                debug!("incoming close");
+                self.handle_close(x)?;
+                if self.wait_for_fin {
+                    self.drain()
+                } else {
+                    brokenpipe()
+                }
             }
             Ready(None) => {
                 debug!("incoming None");
+                if !self.wait_for_fin {
+                    super::exit_code::note_abnormal_close();
+                }
                 brokenpipe()
             }
             Ready(Some(OwnedMessage::Ping(x))) => {
+                report(&self.hook, true, WsEvent::Ping(&x));
+                report(&self.hook, false, WsEvent::Pong(&x));
                 let om = OwnedMessage::Pong(x);
                 let mut sink = self.pingreply.borrow_mut();
                 let mut proceed = false;
@@ -72,17 +495,18 @@ impl<T: WsStream + 'static> Read for WsReadWrapper<T> {
 
                 Ok(0)
             }
-            Ready(Some(OwnedMessage::Pong(_))) => {
+            Ready(Some(OwnedMessage::Pong(x))) => {
                 warn!("Received a pong from websocket");
+                report(&self.hook, true, WsEvent::Pong(&x));
                 Ok(0)
             }
             Ready(Some(OwnedMessage::Text(x))) => {
                 debug!("incoming text");
-                self.debt.process_message(buf, x.as_str().as_bytes())
+                self.debt.process_owned(buf, x.into_bytes())
             }
             Ready(Some(OwnedMessage::Binary(x))) => {
                 debug!("incoming binary");
-                self.debt.process_message(buf, x.as_slice())
+                self.debt.process_owned(buf, x)
             }
             NotReady => wouldblock(),
         }
@@ -95,68 +519,220 @@ pub enum Mode1 {
     Binary,
 }
 
-pub struct WsWriteWrapper<T: WsStream + 'static>(pub MultiProducerWsSink<T>, pub Mode1, pub bool);
+pub struct WsWriteWrapper<T: WsStream + WriteVectored + 'static> {
+    pub sink: MultiProducerWsSink<T>,
+    pub mode: Mode1,
+    /// Send Close message on shutdown
+    pub close_on_shutdown: bool,
+    pub close: HCloseState,
+    pub close_timeout: Option<Duration>,
+    pub handle: Handle,
+    pub close_timer: Option<Timeout>,
+    pub flush_timeout: Option<Duration>,
+    pub flush_timer: Option<Timeout>,
+    pub hook: Option<WsEventHook>,
+}
 
-impl<T: WsStream + 'static> AsyncWrite for WsWriteWrapper<T> {
+impl<T: WsStream + WriteVectored + 'static> WsWriteWrapper<T> {
+    /// Write out queued frames, waiting at most `--flush-timeout` for the peer to take them
+    fn drain(&mut self) -> futures::Poll<(), std::io::Error> {
+        if let Ready(()) = self.sink.borrow_mut().poll_complete().map_err(io_other_error)? {
+            return Ok(Ready(()));
+        }
+        let timeout = match self.flush_timeout {
+            Some(x) => x,
+            None => return Ok(NotReady),
+        };
+        if self.flush_timer.is_none() {
+            self.flush_timer = Some(Timeout::new(timeout, &self.handle)?);
+        }
+        match self.flush_timer.as_mut().unwrap().poll()? {
+            Ready(()) => {
+                let left = self.sink.borrow().pending.len();
+                Err(IoError::new(
+                    ::std::io::ErrorKind::TimedOut,
+                    format!("Timed out sending the last {} bytes of WebSocket output", left),
+                ))
+            }
+            NotReady => Ok(NotReady),
+        }
+    }
+}
+
+impl<T: WsStream + WriteVectored + 'static> AsyncWrite for WsWriteWrapper<T> {
+    /// Send what is queued (bounded by --flush-timeout), then Close, and wait
+    /// for the peer's Close (bounded by --close-timeout)
     fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
-        if ! self.2 { return Ok(Ready(())); }
-        let mut sink = self.0.borrow_mut();
-        match sink
-            .start_send(OwnedMessage::Close(None))
-            .map_err(io_other_error)?
-        {
-            futures::AsyncSink::NotReady(_) => wouldblock(),
-            futures::AsyncSink::Ready => {
-                // Too lazy to implement a state machine here just for
-                // properly handling this.
-                // And shutdown result is ignored here anyway.
-                let _ = sink.poll_complete().map_err(|_| ()).map(|_| ());
-                Ok(Ready(()))
+        if let NotReady = self.drain()? {
+            return Ok(NotReady);
+        }
+        if !self.close_on_shutdown || DEFER_CLOSE.with(|c| c.get()) {
+            return Ok(Ready(()));
+        }
+        if !self.close.borrow().sent {
+            let code = SHUTDOWN_CLOSE_CODE.with(|c| c.borrow().clone());
+            let msg = OwnedMessage::Close(code.clone().map(|(c, r)| CloseData::new(c, r)));
+            match self.sink
+                .borrow_mut()
+                .start_send(msg)
+                .map_err(io_other_error)?
+            {
+                futures::AsyncSink::NotReady(_) => return Ok(NotReady),
+                futures::AsyncSink::Ready => {
+                    debug!("Sent close");
+                    self.close.borrow_mut().sent = true;
+                    report(&self.hook, false, WsEvent::Close(code.as_ref().map(|&(c, ref r)| (c, r.as_str()))));
+                }
             }
         }
+        if let NotReady = self.drain()? {
+            return Ok(NotReady);
+        }
+        if self.close.borrow().received.is_some() {
+            return Ok(Ready(()));
+        }
+        let timeout = match self.close_timeout {
+            Some(x) => x,
+            None => return Ok(Ready(())),
+        };
+        if self.close_timer.is_none() {
+            self.close_timer = Some(Timeout::new(timeout, &self.handle)?);
+        }
+        self.close.borrow_mut().waiter = Some(futures::task::current());
+        match self.close_timer.as_mut().unwrap().poll()? {
+            Ready(()) => {
+                warn!("Peer have not replied to our WebSocket close in time");
+                Err(WebsocatError::Protocol {
+                    close_code: None,
+                    message: "WebSocket close handshake timed out".to_string(),
+                }.into())
+            }
+            NotReady => Ok(NotReady),
+        }
     }
 }
 
-impl<T: WsStream + 'static> Write for WsWriteWrapper<T> {
+impl<T: WsStream + WriteVectored + 'static> Write for WsWriteWrapper<T> {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        let om = match self.1 {
-            Mode1::Binary => OwnedMessage::Binary(buf.to_vec()),
-            Mode1::Text => {
-                let text_tmp;
-                let text = match ::std::str::from_utf8(buf) {
-                    Ok(x) => x,
-                    Err(_) => {
-                        error!(
-                            "Invalid UTF-8 in --text mode. Sending lossy data. May be \
-                             caused by unlucky buffer splits."
-                        );
-                        text_tmp = String::from_utf8_lossy(buf);
-                        text_tmp.as_ref()
-                    }
-                };
-                OwnedMessage::Text(text.to_string())
-            }
+        let text_tmp;
+        let mode = MESSAGE_KIND.with(|c| c.get()).unwrap_or(self.mode);
+        let (opcode, payload) = match mode {
+            Mode1::Binary => (OPCODE_BINARY, buf),
+            Mode1::Text => match ::std::str::from_utf8(buf) {
+                Ok(_) => (OPCODE_TEXT, buf),
+                Err(_) => {
+                    error!(
+                        "Invalid UTF-8 in --text mode. Sending lossy data. May be \
+                         caused by unlucky buffer splits."
+                    );
+                    text_tmp = String::from_utf8_lossy(buf);
+                    (OPCODE_TEXT, text_tmp.as_bytes())
+                }
+            },
         };
-        match self.0.borrow_mut().start_send(om).map_err(io_other_error)? {
-            futures::AsyncSink::NotReady(_) => wouldblock(),
-            futures::AsyncSink::Ready => Ok(buf.len()),
+        if self.sink.borrow_mut().send_frame(opcode, payload)? {
+            Ok(buf.len())
+        } else {
+            wouldblock()
         }
     }
     fn flush(&mut self) -> IoResult<()> {
-        match self.0.borrow_mut().poll_complete().map_err(io_other_error)? {
+        match self.sink.borrow_mut().poll_complete().map_err(io_other_error)? {
             NotReady => wouldblock(),
             Ready(()) => Ok(()),
         }
     }
 }
 
-impl<T: WsStream + 'static> Drop for WsWriteWrapper<T> {
+impl<T: WsStream + WriteVectored + 'static> Drop for WsWriteWrapper<T> {
     fn drop(&mut self) {
         debug!("drop WsWriteWrapper",);
         // moved to shutdown()
     }
 }
 
+/// Split upgraded WebSocket connection into a `Peer`
+pub fn finish_building_ws_peer<S>(
+    opts: &Options,
+    duplex: Duplex<S>,
+    close_on_shutdown: bool,
+    server_role: bool,
+    handle: &Handle,
+    hook: Option<WsEventHook>,
+) -> Peer
+where
+    S: WsStream + WriteVectored + 'static,
+{
+    let mode1 = if opts.websocket_text_mode {
+        Mode1::Text
+    } else {
+        Mode1::Binary
+    };
+    let close_timeout = match opts.close_timeout.unwrap_or(5) {
+        0 => None,
+        x => Some(Duration::from_secs(x)),
+    };
+    let flush_timeout = match opts.flush_timeout.unwrap_or(30) {
+        0 => None,
+        x => Some(Duration::from_secs(x)),
+    };
+
+    // Reading stays with the library's codec, writing goes through `FrameSink`
+    let mut parts = duplex.into_parts();
+    let pending = parts.writebuf.to_vec();
+    parts.writebuf.clear();
+    let shared = Rc::new(RefCell::new(parts.inner));
+    let context = if server_role {
+        Context::Server
+    } else {
+        Context::Client
+    };
+    let stream: WsSource<S> = tokio_io::codec::Framed::from_parts(
+        tokio_io::codec::FramedParts {
+            inner: SharedStream(shared.clone()),
+            readbuf: parts.readbuf,
+            writebuf: parts.writebuf,
+        },
+        MessageCodec::new(context),
+    );
+    let mpsink = Rc::new(RefCell::new(FrameSink {
+        stream: shared,
+        mask: !server_role,
+        masked: vec![],
+        accepted: pending.len() as u64,
+        flushed: 0,
+        writes: 0,
+        pending,
+        cork: super::my_copy::corking(opts),
+    }));
+    let close: HCloseState = Default::default();
+
+    let ws_str = WsReadWrapper {
+        s: stream,
+        pingreply: mpsink.clone(),
+        debt: Default::default(),
+        close: close.clone(),
+        wait_for_fin: server_role,
+        close_timeout,
+        handle: handle.clone(),
+        drain_timer: None,
+        hook: hook.clone(),
+    };
+    let ws_sin = WsWriteWrapper {
+        sink: mpsink,
+        mode: mode1,
+        close_on_shutdown,
+        close,
+        close_timeout,
+        handle: handle.clone(),
+        close_timer: None,
+        flush_timeout,
+        flush_timer: None,
+        hook,
+    };
+    Peer::new(ws_str, ws_sin)
+}
+
 pub struct PeerForWs(pub Peer);
 
 //implicit impl websocket::stream::async::Stream for PeerForWs {}
@@ -172,6 +748,12 @@ impl AsyncWrite for PeerForWs {
         (self.0).1.shutdown()
     }
 }
+impl WriteVectored for PeerForWs {
+    fn write_vectored(&mut self, bufs: &[&[u8]]) -> IoResult<usize> {
+        let fd = (self.0).2.and_then(|x| if x.read_only { None } else { Some(x.fd) });
+        super::vectored::writev_fd(fd, &mut *(self.0).1, bufs)
+    }
+}
 impl Write for PeerForWs {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
         (self.0).1.write(buf)
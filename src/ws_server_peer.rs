@@ -2,28 +2,27 @@ extern crate websocket;
 
 use self::websocket::WebSocketError;
 use futures::future::Future;
-use futures::stream::Stream;
 
-use std::cell::RefCell;
 use std::rc::Rc;
+use tokio_core::reactor::Handle;
 
 use self::websocket::server::upgrade::async::IntoWs;
 
-use super::ws_peer::{Mode1, PeerForWs, WsReadWrapper, WsWriteWrapper};
+use super::ws_peer::{finish_building_ws_peer, PeerForWs, WsEventHook};
 use super::{box_up_err, io_other_error, BoxedNewPeerFuture, Peer};
-use super::{ConstructParams, PeerConstructor, Specifier};
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
 
 #[derive(Debug)]
 pub struct WsServer<T: Specifier>(pub T);
 impl<T: Specifier> Specifier for WsServer<T> {
     fn construct(&self, cp: ConstructParams) -> PeerConstructor {
-        let mode1 = if cp.program_options.websocket_text_mode {
-            Mode1::Text
-        } else {
-            Mode1::Binary
-        };
-        let inner = self.0.construct(cp.clone());
-        inner.map(move |p| ws_upgrade_peer(p, mode1))
+        let opts = cp.program_options.clone();
+        let h = cp.tokio_handle.clone();
+        let hook = cp.ws_event_hook.clone();
+        let mut cp = cp;
+        cp.ws_event_hook = None;
+        let inner = self.0.construct(cp);
+        inner.map(move |p| ws_upgrade_peer(p, opts.clone(), &h, hook.clone()))
     }
     specifier_boilerplate!(typ=WebSocket noglobalstate has_subspec);
     self_0_is_subspecifier!(proxy_is_multiconnect);
@@ -50,6 +49,7 @@ specifier_class!(
             Ok(Rc::new(WsServer(super::spec(just_arg)?)))
         }
     },
+    consumed_options = &["--text", "--close-timeout", "--flush-timeout", "--env-headers"],
     help = r#"
 WebSocket server. Argument is either IPv4 host and port to listen
 or a subspecifier.
@@ -78,7 +78,14 @@ Example: the same, but more verbose:
             boxup(super::ws_server_peer::WsUpgrade(spec(x)?))
 */
 
-pub fn ws_upgrade_peer(inner_peer: Peer, mode1: Mode1) -> BoxedNewPeerFuture {
+pub fn ws_upgrade_peer(
+    inner_peer: Peer,
+    opts: Rc<Options>,
+    h: &Handle,
+    hook: Option<WsEventHook>,
+) -> BoxedNewPeerFuture {
+    let h = h.clone();
+    let mut info = inner_peer.client_info().map(|x| (**x).clone()).unwrap_or_default();
     let step1 = PeerForWs(inner_peer);
     let step2: Box<
         Future<Item = self::websocket::server::upgrade::async::Upgrade<_>, Error = _>,
@@ -89,23 +96,39 @@ pub fn ws_upgrade_peer(inner_peer: Peer, mode1: Mode1) -> BoxedNewPeerFuture {
             info!("Incoming connection to websocket: {}", x.request.subject.1);
             debug!("{:?}", x.request);
             debug!("{:?}", x.headers);
+            super::events::note_uri(&format!("{}", x.request.subject.1));
+            info.uri = Some(format!("{}", x.request.subject.1));
+            info.headers = selected_headers(&x.request.headers, &opts.env_headers);
+            let request_headers = super::lifecycle::header_pairs(&x.request.headers);
             x.accept().map(move |(y, headers)| {
                 debug!("{:?}", headers);
                 info!("Upgraded");
-                let (sink, stream) = y.split();
-                let mpsink = Rc::new(RefCell::new(sink));
-
-                let ws_str = WsReadWrapper {
-                    s: stream,
-                    pingreply: mpsink.clone(),
-                    debt: Default::default(),
-                };
-                let ws_sin = WsWriteWrapper(mpsink, mode1, true /* send Close on shutdown */);
-
-                let ws = Peer::new(ws_str, ws_sin);
-                ws
+                let protocol = super::lifecycle::protocol(&headers);
+                super::lifecycle::handshake(&opts, &request_headers, protocol);
+                finish_building_ws_peer(&opts, y, true /* send Close on shutdown */, true, &h, hook)
+                    .with_client_info(info)
             })
         });
-    let step4 = step3.map_err(box_up_err);
+    let step4 = step3.map_err(|e| {
+        super::metrics::handshake_failed();
+        super::events::emit("handshake_failed", vec![("error", format!("{}", e).into())]);
+        box_up_err(e)
+    });
     Box::new(step4) as BoxedNewPeerFuture
 }
+
+/// Request headers named in `--env-headers` (each argument may be a comma-separated list)
+fn selected_headers(headers: &self::websocket::header::Headers, names: &[String]) -> Vec<(String, String)> {
+    let mut v = vec![];
+    for n in names.iter().flat_map(|x| x.split(',')) {
+        let n = n.trim();
+        if n.is_empty() {
+            continue;
+        }
+        if let Some(raw) = headers.get_raw(n) {
+            let val: Vec<String> = raw.iter().map(|x| String::from_utf8_lossy(x).into_owned()).collect();
+            v.push((n.to_string(), val.join(", ")));
+        }
+    }
+    v
+}
@@ -0,0 +1,158 @@
+//! Heap allocations made by the relaying code, counted by a global allocator.
+//! Only allocations of the thread running the reactor are counted, so clients
+//! and servers on other threads don't get in the way.
+//!
+//! Run with `--nocapture` to see the figures.
+
+extern crate futures;
+extern crate tokio_core;
+extern crate websocat;
+
+use futures::sync::oneshot;
+use futures::Future;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::rc::Rc;
+use std::thread;
+
+use tokio_core::reactor::Core;
+use websocat::{spec, Options, WebsocatConfiguration};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = Cell::new(0);
+}
+
+fn count() {
+    // Not available while the thread is being torn down
+    let _ = ALLOCATIONS.try_with(|x| x.set(x.get() + 1));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Allocations of the current thread so far
+fn allocations() -> u64 {
+    ALLOCATIONS.with(|x| x.get())
+}
+
+/// Serve `s1` to `s2` on `core` until the end of the test
+fn serve(core: &Core, s1: &str, s2: &str, opts: Options) {
+    let websocat = WebsocatConfiguration {
+        opts,
+        s1: spec(s1).unwrap(),
+        s2: spec(s2).unwrap(),
+    };
+    let prog = websocat.serve(core.handle(), Rc::new(|e| panic!("{}", e)));
+    core.handle().spawn(prog);
+}
+
+/// Resolves when `f` has finished on another thread, fails if it panicked
+fn in_thread<F: FnOnce() + Send + 'static>(f: F) -> oneshot::Receiver<()> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        f();
+        let _ = tx.send(());
+    });
+    rx
+}
+
+/// Sessions of `tcp-l:` to `mirror:` for connections that end right away
+#[test]
+fn allocations_per_session() {
+    const WARMUP: usize = 50;
+    const SESSIONS: usize = 500;
+
+    let mut core = Core::new().unwrap();
+    serve(&core, "tcp-l:127.0.0.1:46011", "mirror:", Default::default());
+    let clients = |n| {
+        in_thread(move || {
+            for _ in 0..n {
+                let mut c = TcpStream::connect("127.0.0.1:46011").unwrap();
+                c.shutdown(Shutdown::Write).unwrap();
+                let mut buf = vec![];
+                c.read_to_end(&mut buf).unwrap();
+                assert!(buf.is_empty());
+            }
+        })
+    };
+
+    core.run(clients(WARMUP)).unwrap();
+    let before = allocations();
+    core.run(clients(SESSIONS)).unwrap();
+    let per_session = (allocations() - before) as f64 / SESSIONS as f64;
+    println!("{:.1} allocations per session", per_session);
+    assert!(per_session < 1000.0, "{:.1} allocations per session", per_session);
+}
+
+/// `--raw-relay` allocates its buffer at session start and nothing while relaying:
+/// a session that makes thousands more reads and writes makes no more allocations
+#[test]
+fn raw_relay_steady_state() {
+    const SHORT: usize = 1 << 20;
+    const LONG: usize = 9 << 20;
+    const BUFFER: usize = 4096;
+
+    let mut core = Core::new().unwrap();
+    // Discards everything, one connection after another
+    let sink = std::net::TcpListener::bind("127.0.0.1:46013").unwrap();
+    thread::spawn(move || {
+        for c in sink.incoming() {
+            let _ = std::io::copy(&mut c.unwrap(), &mut std::io::sink());
+        }
+    });
+    serve(
+        &core,
+        "tcp-l:127.0.0.1:46012",
+        "tcp:127.0.0.1:46013",
+        Options {
+            raw_relay: true,
+            no_splice: true,
+            buffer_size: Some(BUFFER),
+            high_watermark: Some(BUFFER),
+            ..Default::default()
+        },
+    );
+    let send = |n| {
+        in_thread(move || {
+            let mut c = TcpStream::connect("127.0.0.1:46012").unwrap();
+            c.write_all(&vec![0; n]).unwrap();
+            c.shutdown(Shutdown::Write).unwrap();
+            let mut buf = vec![];
+            c.read_to_end(&mut buf).unwrap();
+        })
+    };
+
+    core.run(send(SHORT)).unwrap();
+    let before = allocations();
+    core.run(send(SHORT)).unwrap();
+    let short = allocations() - before;
+    let before = allocations();
+    core.run(send(LONG)).unwrap();
+    let long = allocations() - before;
+    println!("{} allocations relaying {} bytes, {} relaying {} bytes", short, SHORT, long, LONG);
+    // The long session reads and writes at least (LONG - SHORT) / BUFFER = 2048 times more
+    assert!(long <= short + 16, "{} allocations relaying {} bytes, {} relaying {} bytes", short, SHORT, long, LONG);
+}
@@ -1,9 +1,13 @@
 extern crate websocat;
 
+extern crate base64;
 extern crate env_logger;
 extern crate futures;
+extern crate log;
 extern crate tokio_core;
+extern crate tokio_io;
 extern crate tokio_timer;
+extern crate websocket;
 
 use futures::future::Future;
 
@@ -61,6 +65,9 @@ macro_rules! wt {
             
         })
     };
+    (stage3, onerror = $f:expr,) => {
+        std::rc::Rc::new($f)
+    };
 }
 
 macro_rules! prepare {
@@ -223,3 +230,5453 @@ fn abstract_() {
     run!(core, prog);
 }
 
+
+#[test]
+fn ws_close_handshake() {
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "ws-l:127.0.0.1:45915",
+        "assert:qwert5y",
+        nodelay,
+        opts = Options {
+            oneshot: true,
+            close_timeout: Some(2),
+            ..dflt()
+        },
+        errpanic,
+    );
+    let prog2 = wt!(
+        core,
+        "literal:qwert5y",
+        "ws://127.0.0.1:45915/",
+        delay = 200,
+        opts = Options {
+            close_timeout: Some(2),
+            ..dflt()
+        },
+        errpanic,
+    );
+
+    let prog = prog1.join(prog2);
+    run!(core, prog);
+}
+
+/// Accept one connection and answer its WebSocket handshake by hand
+fn raw_ws_accept(l: &std::net::TcpListener) -> std::net::TcpStream {
+    use std::io::{Read, Write};
+    use std::str::FromStr;
+    use websocket::header::{WebSocketAccept, WebSocketKey};
+
+    let (mut s, _) = l.accept().unwrap();
+    let mut req = vec![];
+    let mut b = [0u8; 1];
+    while !req.ends_with(b"\r\n\r\n") {
+        assert_eq!(s.read(&mut b).unwrap(), 1);
+        req.push(b[0]);
+    }
+    let req = String::from_utf8(req).unwrap();
+    let key = req
+        .lines()
+        .find(|x| x.to_lowercase().starts_with("sec-websocket-key:"))
+        .unwrap()[18..]
+        .trim();
+    let accept = WebSocketAccept::new(&WebSocketKey::from_str(key).unwrap());
+    write!(
+        s,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept.serialize()
+    ).unwrap();
+    s
+}
+
+/// Connect and do the client side of the WebSocket handshake by hand
+fn raw_ws_connect(addr: &str, path: &str, extra_headers: &str) -> std::net::TcpStream {
+    use std::io::{Read, Write};
+    let mut s = std::net::TcpStream::connect(addr).unwrap();
+    s.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    write!(
+        s,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+        path, addr, extra_headers
+    ).unwrap();
+    let mut resp = vec![];
+    let mut b = [0u8; 1];
+    while !resp.ends_with(b"\r\n\r\n") {
+        assert_eq!(s.read(&mut b).unwrap(), 1);
+        resp.push(b[0]);
+    }
+    assert!(resp.starts_with(b"HTTP/1.1 101"));
+    s
+}
+
+/// Send a short binary message from the client side, masked with a zero key
+fn raw_ws_send(s: &mut std::net::TcpStream, data: &[u8]) {
+    use std::io::Write;
+    assert!(data.len() < 126);
+    let mut f = vec![0x82, 0x80 | data.len() as u8, 0, 0, 0, 0];
+    f.extend_from_slice(data);
+    s.write_all(&f).unwrap();
+}
+
+/// Opcode and unmasked payload of the next frame
+fn raw_ws_frame(s: &mut std::net::TcpStream) -> (u8, Vec<u8>) {
+    use std::io::Read;
+    let mut h = [0u8; 2];
+    s.read_exact(&mut h).unwrap();
+    let mut len = (h[1] & 0x7F) as usize;
+    if len == 126 {
+        let mut l = [0u8; 2];
+        s.read_exact(&mut l).unwrap();
+        len = (l[0] as usize) << 8 | l[1] as usize;
+    }
+    assert!(len < 127);
+    let mut mask = [0u8; 4];
+    if h[1] & 0x80 != 0 {
+        s.read_exact(&mut mask).unwrap();
+    }
+    let mut payload = vec![0u8; len];
+    s.read_exact(&mut payload).unwrap();
+    for (i, x) in payload.iter_mut().enumerate() {
+        *x ^= mask[i % 4];
+    }
+    (h[0] & 0x0F, payload)
+}
+
+/// The peer takes its time to answer our Close. The connection must stay up until it does.
+#[test]
+fn ws_close_handshake_slow_peer() {
+    use std::io::{Read, Write};
+
+    prepare!(core);
+    let l = std::net::TcpListener::bind("127.0.0.1:45978").unwrap();
+    let peer = std::thread::spawn(move || {
+        let mut s = raw_ws_accept(&l);
+        assert_eq!(raw_ws_frame(&mut s), (0x2, b"qwert8y".to_vec()));
+        assert_eq!(raw_ws_frame(&mut s).0, 0x8);
+        s.set_read_timeout(Some(std::time::Duration::from_millis(300))).unwrap();
+        let mut b = [0u8; 16];
+        let early = s.read(&mut b);
+        assert!(early.is_err(), "connection closed before our Close: {:?}", early);
+        s.write_all(b"\x88\x02\x03\xE8").unwrap();
+        s.set_read_timeout(Some(std::time::Duration::from_secs(3))).unwrap();
+        assert_eq!(s.read(&mut b).unwrap(), 0);
+    });
+    let start = std::time::Instant::now();
+    let client = wt!(
+        core,
+        "literal:qwert8y",
+        "ws://127.0.0.1:45978/",
+        nodelay,
+        opts = Options {
+            close_timeout: Some(5),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, client);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+    peer.join().unwrap();
+}
+
+/// The peer never answers our Close: the session fails after --close-timeout
+#[test]
+fn ws_close_handshake_timeout() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    prepare!(core);
+    let l = std::net::TcpListener::bind("127.0.0.1:45979").unwrap();
+    let peer = std::thread::spawn(move || {
+        let mut s = raw_ws_accept(&l);
+        assert_eq!(raw_ws_frame(&mut s).0, 0x2);
+        assert_eq!(raw_ws_frame(&mut s).0, 0x8);
+        std::thread::sleep(std::time::Duration::from_secs(3));
+    });
+    let error = Rc::new(RefCell::new(None));
+    let error2 = error.clone();
+    let start = std::time::Instant::now();
+    let client = wt!(
+        core,
+        "literal:qwert9y",
+        "ws://127.0.0.1:45979/",
+        nodelay,
+        opts = Options {
+            close_timeout: Some(1),
+            ..dflt()
+        },
+        onerror = move |e: Box<std::error::Error>| *error2.borrow_mut() = Some(e.to_string()),
+    );
+    let _ = core.run(client);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= std::time::Duration::from_secs(1));
+    assert!(elapsed < std::time::Duration::from_secs(3));
+    let error = error.borrow().clone().unwrap();
+    assert!(error.contains("close handshake timed out"), "{}", error);
+    peer.join().unwrap();
+}
+
+#[test]
+fn lenprefix() {
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "lenprefix:tcp-l:127.0.0.1:45916",
+        "assert:qwert6y",
+        nodelay,
+        opts = Options {
+            oneshot: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let prog2 = wt!(
+        core,
+        "literal:qwert6y",
+        "lenprefix:tcp:127.0.0.1:45916",
+        delay = 200,
+        noopts,
+        errpanic,
+    );
+
+    let prog = prog1.join(prog2);
+    run!(core, prog);
+}
+
+/// Frames split at every possible place map 1:1 to frames on the other side,
+/// zero-length ones are skipped
+#[test]
+fn lenprefix_split_frames() {
+    use std::io::{Read, Write};
+
+    prepare!(core);
+    let src = std::net::TcpListener::bind("127.0.0.1:45980").unwrap();
+    let dst = std::net::TcpListener::bind("127.0.0.1:45981").unwrap();
+    let src = std::thread::spawn(move || {
+        let (mut s, _) = src.accept().unwrap();
+        s.set_nodelay(true).unwrap();
+        for piece in &[&b"\x03"[..], b"\x00ab", b"c", b"\x00\x00\x02", b"\x00de"] {
+            s.write_all(piece).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        s.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut rest = vec![];
+        s.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    });
+    let dst = std::thread::spawn(move || {
+        let (mut s, _) = dst.accept().unwrap();
+        s.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut got = vec![];
+        s.read_to_end(&mut got).unwrap();
+        got
+    });
+    let prog = wt!(
+        core,
+        "lenprefix:tcp:127.0.0.1:45980",
+        "lenprefix:tcp:127.0.0.1:45981",
+        nodelay,
+        opts = Options {
+            lenprefix_bytes: 2,
+            lenprefix_little_endian: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    src.join().unwrap();
+    assert_eq!(dst.join().unwrap(), b"\x03\x00abc\x02\x00de".to_vec());
+}
+
+/// Lengths that count the prefix itself, and frames over --lenprefix-max
+#[test]
+fn lenprefix_header_and_max() {
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    prepare!(core);
+    let l = std::net::TcpListener::bind("127.0.0.1:45982").unwrap();
+    let peer = std::thread::spawn(move || {
+        let (mut s, _) = l.accept().unwrap();
+        // A frame of just the prefix is empty, the last one is 9 bytes long
+        s.write_all(b"\x05xyzw\x01\x0a123456789").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    });
+    let error = Rc::new(RefCell::new(None));
+    let error2 = error.clone();
+    let prog = wt!(
+        core,
+        "lenprefix:tcp:127.0.0.1:45982",
+        "assert:xyzw",
+        nodelay,
+        opts = Options {
+            lenprefix_bytes: 1,
+            lenprefix_includes_header: true,
+            lenprefix_max: 5,
+            ..dflt()
+        },
+        onerror = move |e: Box<std::error::Error>| *error2.borrow_mut() = Some(e.to_string()),
+    );
+    let _ = core.run(prog);
+    let error = error.borrow().clone().unwrap();
+    assert!(error.contains("frame is too big"), "{}", error);
+    peer.join().unwrap();
+}
+
+/// The biggest 8-byte length, over the default --lenprefix-max and over what fits in memory
+#[test]
+fn lenprefix_huge_length() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    prepare!(core);
+    // On 32-bit systems the length is over any --lenprefix-max
+    for &(max, expected) in &[(0, "frame is too big"), (usize::max_value(), "too big")] {
+        let error = Rc::new(RefCell::new(None));
+        let error2 = error.clone();
+        let prog = wt!(
+            core,
+            "lenprefix:literal-hex:ffffffffffffffff 6869",
+            "null:",
+            nodelay,
+            opts = Options {
+                lenprefix_bytes: 8,
+                lenprefix_max: max,
+                ..dflt()
+            },
+            onerror = move |e: Box<std::error::Error>| *error2.borrow_mut() = Some(e.to_string()),
+        );
+        let _ = core.run(prog);
+        let error = error.borrow().clone().unwrap();
+        assert!(error.contains(expected), "{}", error);
+    }
+}
+
+/// Contents of a traffic log once `needle` shows up in it. The writer thread may lag behind.
+fn traffic_log_with(path: &std::path::Path, needle: &str) -> String {
+    let mut x = String::new();
+    for _ in 0..100 {
+        x = std::fs::read_to_string(path).unwrap_or_default();
+        if x.contains(needle) {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    x
+}
+
+#[test]
+fn traffic_log_formats() {
+    prepare!(core);
+    let path = std::env::temp_dir().join(format!("websocat_test_{}.dump", std::process::id()));
+    let rotated = format!("{}.1", path.display());
+    let logged = |core: &mut Core, opts: Options| {
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&rotated);
+        let prog = wt!(core,
+            "log:literal:0123456789abcdefXY",
+            "assert:0123456789abcdefXY",
+            nodelay,
+            opts = Options {
+                log_file: Some(path.to_str().unwrap().to_string()),
+                ..opts
+            },
+            errpanic,
+        );
+        run!(core, prog);
+        std::fs::read_to_string(&path).unwrap()
+    };
+
+    let hex = logged(&mut core, dflt());
+    assert!(hex.contains(" < 18 bytes\n"), "{}", hex);
+    assert!(hex.contains(
+        "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n"
+    ), "{}", hex);
+    assert!(hex.contains("00000010  58 59 "), "{}", hex);
+
+    let raw = logged(&mut core, Options { log_raw: true, ..dflt() });
+    assert!(raw.ends_with(" < 18 bytes\n0123456789abcdefXY\n"), "{}", raw);
+
+    let b64 = logged(&mut core, Options { log_base64: true, ..dflt() });
+    assert!(b64.ends_with(" < 18 bytes\nMDEyMzQ1Njc4OWFiY2RlZlhZ\n"), "{}", b64);
+
+    let after_rotation = logged(&mut core, Options { log_raw: true, log_max_size: 10, ..dflt() });
+    assert_eq!(after_rotation, "");
+    assert!(std::fs::read_to_string(&rotated).unwrap().contains("0123456789abcdefXY"));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&rotated);
+}
+
+#[test]
+fn jsonwrap_input() {
+    prepare!(core);
+    let prog = wt!(core,
+        r#"jsonwrap:literal:{"ts":"x","dir":"in","type":"binary","data_b64":"cXdlcnQ3eQ=="}"#,
+        "assert:qwert7y",
+        nodelay,
+        opts = Options {
+            jsonl_input: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+/// `type` of --jsonl-input lines sets the WebSocket message type
+#[test]
+fn jsonwrap_input_type() {
+    use std::io::Write;
+
+    prepare!(core);
+    let l = std::net::TcpListener::bind("127.0.0.1:45984").unwrap();
+    let peer = std::thread::spawn(move || {
+        let mut s = raw_ws_accept(&l);
+        assert_eq!(raw_ws_frame(&mut s), (0x1, b"hello".to_vec()));
+        assert_eq!(raw_ws_frame(&mut s), (0x2, b"qwert7y".to_vec()));
+        assert_eq!(raw_ws_frame(&mut s).0, 0x8);
+        s.write_all(b"\x88\x02\x03\xE8").unwrap();
+    });
+    let client = wt!(core,
+        concat!(
+            r#"jsonwrap:literal:{"type":"text","data":"hello"}"#,
+            "\n",
+            r#"{"type":"binary","data_b64":"cXdlcnQ3eQ=="}"#,
+        ),
+        "ws://127.0.0.1:45984/",
+        nodelay,
+        opts = Options {
+            jsonl_input: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, client);
+    peer.join().unwrap();
+}
+
+/// MessagePack values split across reads and several values in one read
+#[test]
+fn msgpack2json_split_values() {
+    use std::io::{Read, Write};
+
+    prepare!(core);
+    let src = std::net::TcpListener::bind("127.0.0.1:45985").unwrap();
+    let dst = std::net::TcpListener::bind("127.0.0.1:45986").unwrap();
+    let src = std::thread::spawn(move || {
+        let (mut s, _) = src.accept().unwrap();
+        s.set_nodelay(true).unwrap();
+        for piece in &[&b"\x92"[..], b"\x01\xa3a", b"bc\x05\x06"] {
+            s.write_all(piece).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        s.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut rest = vec![];
+        s.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    });
+    let dst = std::thread::spawn(move || {
+        let (mut s, _) = dst.accept().unwrap();
+        s.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut got = vec![];
+        s.read_to_end(&mut got).unwrap();
+        got
+    });
+    let prog = wt!(
+        core,
+        "msgpack2json:tcp:127.0.0.1:45985",
+        "tcp:127.0.0.1:45986",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+    src.join().unwrap();
+    assert_eq!(dst.join().unwrap(), b"[1,\"abc\"]\n5\n6\n".to_vec());
+}
+
+#[test]
+fn cbor2json() {
+    prepare!(core);
+    let prog1 = wt!(core, r#"literal:"qwerty""#, "cbor2json:assert:fqwerty", nodelay, noopts, errpanic,);
+    let prog2 = wt!(core, "cbor2json:literal:fqwerty", "assert:\"qwerty\"\n", nodelay, noopts, errpanic,);
+    let prog = prog1.join(prog2);
+    run!(core, prog);
+}
+
+#[test]
+fn throttle() {
+    prepare!(core);
+    let data = "x".repeat(2100);
+    let s1 = format!("throttle:literal:{}", data);
+    let s2 = format!("assert:{}", data);
+    let prog = wt!(core,
+        &s1,
+        &s2,
+        nodelay,
+        opts = Options {
+            throttle_bytes_per_sec: 2000,
+            throttle_burst: 100,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let start = std::time::Instant::now();
+    run!(core, prog);
+    // 2000 bytes of debt at 2000 bytes per second
+    let t = start.elapsed();
+    assert!(t >= std::time::Duration::from_millis(800), "{:?}", t);
+    assert!(t <= std::time::Duration::from_millis(2000), "{:?}", t);
+}
+
+#[test]
+fn delay() {
+    prepare!(core);
+    let prog = wt!(core,
+        "delay:literal:qwert8y",
+        "delay:assert:qwert8y",
+        nodelay,
+        opts = Options {
+            delay_ms: 200,
+            delay_jitter_ms: 50,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let start = std::time::Instant::now();
+    run!(core, prog);
+    // One delay on reading, another one on writing
+    let t = start.elapsed();
+    assert!(t >= std::time::Duration::from_millis(400), "{:?}", t);
+    assert!(t <= std::time::Duration::from_millis(2000), "{:?}", t);
+}
+
+#[test]
+fn record() {
+    use websocat::record_peer::{read_capture_file, Direction, RecordKind};
+    prepare!(core);
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.cap", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    let prog = wt!(core,
+        "record:literal:qwert9y",
+        "assert:qwert9y",
+        nodelay,
+        opts = Options {
+            record_file: Some(path.clone()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let (recs, truncated) = read_capture_file(&path).unwrap();
+    let dump = websocat_bin().args(&["--dump-capture", &path]).output().unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(!truncated);
+    assert_eq!(recs[0].dir, Direction::In);
+    assert_eq!(recs[0].kind, RecordKind::Data);
+    assert_eq!(recs[0].payload, b"qwert9y");
+    assert!(recs.iter().any(|r| r.dir == Direction::In && r.kind == RecordKind::Eof));
+    assert!(dump.status.success(), "{}", String::from_utf8_lossy(&dump.stderr));
+    let dump = String::from_utf8(dump.stdout).unwrap();
+    assert!(dump.contains("< data 7 bytes"), "{}", dump);
+    assert!(dump.contains("< EOF"), "{}", dump);
+
+    // All sessions share the capture file
+    let s = websocat::spec("record:literal:qwert9y").unwrap();
+    assert!(s.get_info().this.uses_global_state);
+}
+
+#[test]
+fn replay() {
+    use websocat::record_peer::{CaptureRecord, Direction, RecordKind, CAPTURE_MAGIC, CAPTURE_VERSION};
+    prepare!(core);
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_replay_{}.cap", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let rec = |ms: u64, kind, payload: &[u8]| {
+        CaptureRecord {
+            ts_ns: 1_000_000_000_000 + ms * 1_000_000,
+            dir: Direction::In,
+            kind,
+            payload: payload.to_vec(),
+        }.encode()
+    };
+    let mut data = CAPTURE_MAGIC.to_vec();
+    data.push(CAPTURE_VERSION);
+    data.extend(rec(0, RecordKind::Data, b"qwer"));
+    data.extend(rec(100, RecordKind::Data, b"t10y"));
+    data.extend(rec(150, RecordKind::Eof, b""));
+    // Truncated record at the end must be ignored
+    data.extend_from_slice(&rec(200, RecordKind::Data, b"zzz")[..7]);
+    std::fs::write(&path, data).unwrap();
+
+    let prog = wt!(core,
+        &format!("replay:{}", path),
+        "assert:qwert10y",
+        nodelay,
+        opts = Options {
+            replay_strict: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let start = std::time::Instant::now();
+    run!(core, prog);
+    let _ = std::fs::remove_file(&path);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(140));
+}
+
+#[test]
+fn prepend_append() {
+    prepare!(core);
+    let prog = wt!(core,
+        "literal:qwert11y",
+        concat!(r"prepend:>>\x3a:append:<<\n:assert:>>:qwert11y<<", "\n"),
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn prepend_file() {
+    prepare!(core);
+    let path = std::env::temp_dir().join(format!("websocat_test_{}.prepend", std::process::id()));
+    std::fs::write(&path, "hello ").unwrap();
+    let s = format!("prepend-file:{}:assert:hello qwert12y", path.display());
+    let prog = wt!(core, "literal:qwert12y", &s, nodelay, noopts, errpanic,);
+    // Read when parsed, not when connecting
+    std::fs::remove_file(&path).unwrap();
+    run!(core, prog);
+
+    let e = spec(&s).unwrap_err().to_string();
+    assert!(e.contains("Failed to read prepend-file"), "{}", e);
+    assert!(e.contains(&path.display().to_string()), "{}", e);
+}
+
+#[test]
+fn separator() {
+    prepare!(core);
+    let prog = wt!(core,
+        "msg2line:line2msg:literal:ab;;cd;cd;;ef",
+        r"assert:ab;;cd\x3bcd;;",
+        nodelay,
+        opts = Options {
+            separator: ";".to_string(),
+            separator_n: 2,
+            separator_conflict: "escape".to_string(),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+/// The line boundary has to fit in a read buffer, with room to spare
+#[test]
+fn separator_longer_than_buffer() {
+    use websocat::line_peer::LineSettings;
+    let opts = |n, buffer_size| Options {
+        separator_n: n,
+        buffer_size,
+        ..dflt()
+    };
+    assert!(LineSettings::from_options(&opts(300, Some(256))).is_err());
+    assert!(LineSettings::from_options(&opts(256, Some(256))).is_err());
+    assert!(LineSettings::from_options(&opts(255, Some(256))).is_ok());
+    assert!(LineSettings::from_options(&opts(usize::max_value(), None)).is_err());
+
+    let out = websocat_bin()
+        .args(&["-u", "--separator-n", "300", "--buffer-size", "256", "msg2line:literal:a", "-"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--buffer-size"));
+}
+
+fn line_escape_opts(mode: &str) -> Options {
+    Options {
+        line_escape: Some(mode.to_string()),
+        ..dflt()
+    }
+}
+
+#[test]
+fn line_escape_backslash() {
+    prepare!(core);
+    let prog = wt!(core,
+        "msg2line:literal:a\\nb\\\\c\\r",
+        "assert:a\\nb\\\\c\\r\n",
+        nodelay,
+        opts = line_escape_opts("backslash"),
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn line_escape_backslash_roundtrip() {
+    prepare!(core);
+    let prog = wt!(core,
+        "line2msg:msg2line:literal:a\\nb\\\\c\\r",
+        "assert:a\nb\\c\r",
+        nodelay,
+        opts = line_escape_opts("backslash"),
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn line_escape_buffer_boundary() {
+    // Message fills the whole 64k copy buffer, its escaped form does not fit there
+    let mut msg = "q".repeat(65535);
+    msg.push('\n');
+    prepare!(core);
+    let prog = wt!(core,
+        &format!("line2msg:msg2line:literal:{}", msg),
+        &format!("assert:{}", msg),
+        nodelay,
+        opts = line_escape_opts("backslash"),
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn line_escape_base64() {
+    prepare!(core);
+    let prog = wt!(core,
+        "msg2line:literal:a\nb",
+        "assert:YQpi\n",
+        nodelay,
+        opts = line_escape_opts("base64"),
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "msg2line:literal:ab",
+        "assert:ab\n",
+        nodelay,
+        opts = line_escape_opts("base64"),
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn line_escape_strip() {
+    prepare!(core);
+    let prog = wt!(core,
+        "msg2line:literal:a\nb\r\nc\n",
+        "assert:abc\n",
+        nodelay,
+        opts = line_escape_opts("strip"),
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn line_escape_error() {
+    prepare!(core);
+    let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+    let failed2 = failed.clone();
+    let prog = wt!(core,
+        "msg2line:literal:a\nb",
+        "assert:",
+        nodelay,
+        opts = line_escape_opts("error"),
+        onerror = move |_| failed2.set(true),
+    );
+    let _ = core.run(prog);
+    assert!(failed.get());
+}
+
+#[test]
+fn literal_escapes() {
+    prepare!(core);
+    let prog = wt!(core,
+        r"literal:a\x3a\\\n\0",
+        "assert:a:\\\n\0",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+    assert!(spec(r"literal:\q").is_err());
+    assert!(spec(r"literal:\x4").is_err());
+}
+
+#[test]
+fn assert_diagnostics() {
+    prepare!(core);
+    let msg = std::rc::Rc::new(std::cell::RefCell::new(String::new()));
+    let msg2 = msg.clone();
+    let prog = wt!(core,
+        "literal:qwerty",
+        r"assert-literal:qwXrty\n",
+        nodelay,
+        noopts,
+        onerror = move |e: Box<std::error::Error>| *msg2.borrow_mut() = format!("{}", e),
+    );
+    let _ = core.run(prog);
+    assert!(msg.borrow().contains("differs at byte offset 2"), "{}", msg.borrow());
+
+    let prog = wt!(core,
+        "literal:qwerty",
+        r"assert-literal:qwe",
+        nodelay,
+        opts = Options {
+            assert_allow_extra: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+
+    let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+    let failed2 = failed.clone();
+    let prog = wt!(core,
+        "clogged:",
+        "assert2:qwe",
+        nodelay,
+        opts = Options {
+            assert_timeout: Some(1),
+            ..dflt()
+        },
+        onerror = move |_| failed2.set(true),
+    );
+    let _ = core.run(prog);
+    assert!(failed.get());
+}
+
+#[test]
+#[cfg(feature = "regex")]
+fn regex_filter() {
+    prepare!(core);
+    let prog = wt!(core,
+        r#"literal:{"type":"heartbeat"}"#,
+        "assert:",
+        nodelay,
+        opts = Options {
+            filter_out_regex: Some(r#"^\{"type":"heartbeat""#.to_string()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "literal-hex:00 ff 71",
+        r"assert-literal:\0\xffq",
+        nodelay,
+        opts = Options {
+            filter_out_regex: Some(r"(?-u)\xff".to_string()),
+            filter_mode: "keep".to_string(),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    // Messages bigger than the buffer are matched as a whole
+    let prog = wt!(core,
+        r#"literal:{"type":"heartbeat","seq":12345,"pad":"........"}"#,
+        "assert:",
+        nodelay,
+        opts = Options {
+            filter_out_regex: Some(r#"^\{"type":"heartbeat".*\}$"#.to_string()),
+            buffer_size: Some(16),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    // A pattern that does not compile fails the whole thing instead of passing everything,
+    // also for library users that skip the checks of the command line
+    let error = std::rc::Rc::new(std::cell::RefCell::new(None));
+    let error2 = error.clone();
+    let prog = wt!(core,
+        "literal:secret",
+        "assert:",
+        nodelay,
+        opts = Options {
+            filter_out_regex: Some("(secret".to_string()),
+            ..dflt()
+        },
+        onerror = move |e: Box<std::error::Error>| *error2.borrow_mut() = Some(e.to_string()),
+    );
+    assert!(core.run(prog).is_err());
+    let error = error.borrow().clone().unwrap();
+    assert!(error.contains("Invalid regex `(secret`"), "{}", error);
+}
+
+#[test]
+#[cfg(feature = "regex")]
+fn rewrite() {
+    prepare!(core);
+    let prog = wt!(core,
+        r#"literal:{"env":"prod","id":"a1b2"}"#,
+        r#"assert:{"env":"staging","id":"b2a1"}"#,
+        nodelay,
+        opts = Options {
+            rewrite: vec![
+                r#"s/"env":"prod"/"env":"staging"/"#.to_string(),
+                r#"s|"id":"(..)(..)"|"id":"\2\1"|"#.to_string(),
+            ],
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "literal:qwerty",
+        "assert:qwerty",
+        nodelay,
+        opts = Options {
+            rewrite: vec!["s/w/W/g".to_string()],
+            rewrite_max_size: Some(3),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    // `$` not referring to a group is literal
+    let prog = wt!(core,
+        "literal:price 5 EUR",
+        "assert:price 5 USD ($), was $EUR",
+        nodelay,
+        opts = Options {
+            rewrite: vec![r"s/(\d) EUR/$1 USD ($), was $EUR/".to_string()],
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    // Matches across the boundary of the read buffer
+    let prog = wt!(core,
+        r#"literal:{"id":"a1b2","flag":0,"env":"prod"}"#,
+        r#"assert:{"id":"a1b2","flag":0,"env":"staging"}"#,
+        nodelay,
+        opts = Options {
+            rewrite: vec![r#"s/"env":"prod"/"env":"staging"/"#.to_string()],
+            buffer_size: Some(16),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    // A rewrite that does not compile is an error, not skipped
+    let error = std::rc::Rc::new(std::cell::RefCell::new(None));
+    let error2 = error.clone();
+    let prog = wt!(core,
+        "literal:qwerty",
+        "assert:",
+        nodelay,
+        opts = Options {
+            rewrite: vec!["s/w/W/g".to_string(), "s/(/x/".to_string()],
+            ..dflt()
+        },
+        onerror = move |e: Box<std::error::Error>| *error2.borrow_mut() = Some(e.to_string()),
+    );
+    assert!(core.run(prog).is_err());
+    let error = error.borrow().clone().unwrap();
+    assert!(error.contains("Invalid --rewrite `s/(/x/`"), "{}", error);
+}
+
+#[test]
+fn chunk() {
+    prepare!(core);
+    let prog = wt!(core,
+        "literal:hello world",
+        "chunk:8:assert:1/3:hell2/3:o wo3/3:rld",
+        nodelay,
+        opts = Options {
+            chunk_header: "seq/total".to_string(),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "unchunk:literal:1/1:qwerty",
+        "assert:qwerty",
+        nodelay,
+        opts = Options {
+            chunk_header: "seq/total".to_string(),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    assert!(spec("chunk:0:mirror:").is_err());
+}
+
+/// Accepts at most 3 bytes per write, and every other write would block
+struct ShortWrites(std::rc::Rc<std::cell::RefCell<Vec<u8>>>, bool);
+impl std::io::Write for ShortWrites {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.1 = !self.1;
+        if self.1 {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(3);
+        self.0.borrow_mut().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl tokio_io::AsyncWrite for ShortWrites {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        Ok(().into())
+    }
+}
+
+#[test]
+fn chunk_short_writes() {
+    use std::io::Write;
+    let out = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let inner = websocat::Peer::new(std::io::Cursor::new(vec![]), ShortWrites(out.clone(), false));
+    let opts = Options {
+        chunk_header: "seq/total".to_string(),
+        ..dflt()
+    };
+    let peer = websocat::chunk_peer::chunk_peer(inner, 8, &opts).wait().unwrap();
+    let mut w = peer.1;
+    for msg in &[&b"hello world"[..], b"qwerty"] {
+        // Retried until the whole message is written, like the copy loop does
+        loop {
+            match w.write(msg) {
+                Ok(n) => {
+                    assert_eq!(n, msg.len());
+                    break;
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+    assert_eq!(&out.borrow()[..], &b"1/3:hell2/3:o wo3/3:rld1/1:qwerty"[..]);
+}
+
+#[test]
+fn batching() {
+    prepare!(core);
+    // Without batching each message gets its own chunk header
+    let prog = wt!(core,
+        "lenprefix:literal-hex:00000002 6869 00000003 717765",
+        "chunk:100:assert:1/1:hi1/1:qwe",
+        nodelay,
+        opts = Options {
+            chunk_header: "seq/total".to_string(),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    // Long window, but EOF flushes the batch immediately
+    let prog = wt!(core,
+        "lenprefix:literal-hex:00000002 6869 00000003 717765",
+        "chunk:100:assert:1/1:hi,qwe",
+        nodelay,
+        opts = Options {
+            chunk_header: "seq/total".to_string(),
+            batch_window_ms: Some(60000),
+            batch_separator: b",".to_vec(),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    // Count limit flushes before the window ends
+    let prog = wt!(core,
+        "lenprefix:literal-hex:00000002 6869 00000003 717765 00000001 7a",
+        "chunk:100:assert:1/1:hiqwe1/1:z",
+        nodelay,
+        opts = Options {
+            chunk_header: "seq/total".to_string(),
+            batch_window_ms: Some(60000),
+            batch_max_count: Some(2),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+/// The batch is delivered when the window ends, without waiting for more messages
+#[test]
+fn batching_window() {
+    use std::io::{Read, Write};
+
+    prepare!(core);
+    let src = std::net::TcpListener::bind("127.0.0.1:45993").unwrap();
+    let src = std::thread::spawn(move || {
+        let (mut s, _) = src.accept().unwrap();
+        s.write_all(b"\x00\x00\x00\x02hi\x00\x00\x00\x03qwe").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        s.write_all(b"\x00\x00\x00\x01z").unwrap();
+        s.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut rest = vec![];
+        s.read_to_end(&mut rest).unwrap();
+    });
+    let prog = wt!(core,
+        "lenprefix:tcp:127.0.0.1:45993",
+        "chunk:100:assert:1/1:hi,qwe1/1:z",
+        nodelay,
+        opts = Options {
+            chunk_header: "seq/total".to_string(),
+            batch_window_ms: Some(100),
+            batch_separator: b",".to_vec(),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    src.join().unwrap();
+}
+
+#[test]
+fn dedup() {
+    prepare!(core);
+    let prog = wt!(core,
+        "lenprefix:literal-hex:00000001 61 00000001 61 00000001 62 00000001 61 00000001 61",
+        "assert:aba",
+        nodelay,
+        opts = Options {
+            dedup_consecutive: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "lenprefix:literal-hex:00000001 61 00000001 62 00000001 61 00000001 63 00000001 61",
+        "assert:abca",
+        nodelay,
+        opts = Options {
+            dedup_window: Some(2),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn session_limits() {
+    prepare!(core);
+    let prog = wt!(core,
+        "lenprefix:literal-hex:00000001 61 00000001 62 00000001 63",
+        "assert:ab",
+        nodelay,
+        opts = Options {
+            max_messages_out: Some(2),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "literal:qwerty",
+        "assert:qwe",
+        nodelay,
+        opts = Options {
+            max_messages_out: Some(5),
+            max_bytes_out: Some(3),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn literal_hex() {
+    prepare!(core);
+    let prog = wt!(core,
+        "literal-hex:71 77 65\n72 74",
+        "assert:qwert",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+    assert!(spec("literal-hex:7177 6").is_err());
+    assert!(spec("literal-hex:zz").is_err());
+}
+
+#[test]
+fn literal_file() {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.lit", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    std::fs::write(&path, b"qwert12y\n").unwrap();
+    prepare!(core);
+    let prog = wt!(core,
+        &format!("literal-file:{}", path),
+        "assert:qwert12y\n",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn file_flags() {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.flags", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    prepare!(core);
+    let flags = || Options {
+        file_create_new: true,
+        file_sync: true,
+        ..dflt()
+    };
+    let prog = wt!(core, "literal:qwe", &format!("writefile:{}", path), nodelay, opts = flags(), errpanic,);
+    run!(core, prog);
+    let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+    let failed2 = failed.clone();
+    let prog = wt!(core,
+        "literal:rty",
+        &format!("writefile:{}", path),
+        nodelay,
+        opts = flags(),
+        onerror = move |_| failed2.set(true),
+    );
+    let _ = core.run(prog);
+    assert!(failed.get());
+    let prog = wt!(core,
+        "literal:rty",
+        &format!("writefile:{}", path),
+        nodelay,
+        opts = Options {
+            file_append: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    assert_eq!(std::fs::read(&path).unwrap(), b"qwerty");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn file_follow_rotation() {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.log", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    std::fs::write(&path, b"0123456789abc").unwrap();
+    prepare!(core);
+    let prog = wt!(core,
+        &format!("readfile:{}", path),
+        "assert:abcefgh",
+        nodelay,
+        opts = Options {
+            file_start_offset: Some(-3),
+            file_max_bytes: Some(7),
+            file_follow: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let path2 = path.clone();
+    let rotate = tokio_timer::wheel()
+        .build()
+        .sleep(std::time::Duration::from_millis(400))
+        .map_err(|_| ())
+        .map(move |()| {
+            std::fs::rename(&path2, format!("{}.1", path2)).unwrap();
+            std::fs::write(&path2, b"efgh").unwrap();
+        });
+    core.handle().spawn(rotate);
+    run!(core, prog);
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(format!("{}.1", path));
+}
+
+fn random_to_file(seed: u64, name: &str) -> Vec<u8> {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}_{}.rnd", std::process::id(), name));
+    let path = path.to_str().unwrap().to_string();
+    prepare!(core);
+    let prog = wt!(core,
+        "random:1000",
+        &format!("writefile:{}", path),
+        nodelay,
+        opts = Options {
+            random_seed: Some(seed),
+            gen_message_size: Some(64),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let data = std::fs::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    data
+}
+
+#[test]
+fn random() {
+    let a = random_to_file(42, "a");
+    let b = random_to_file(42, "b");
+    let c = random_to_file(43, "c");
+    assert_eq!(a.len(), 1000);
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+}
+
+#[test]
+fn count() {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.count", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    prepare!(core);
+    let prog = wt!(core,
+        "random:1000",
+        "count:",
+        nodelay,
+        opts = Options {
+            gen_message_size: Some(100),
+            count_output: Some(path.clone()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "literal:qwert13y",
+        "count:mirror:",
+        nodelay,
+        opts = Options {
+            count_output: Some(path.clone()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let summary = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let lines: Vec<&str> = summary.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", summary);
+    assert!(
+        lines[0].starts_with(r#"{"in":{"messages":0,"bytes":0,"#),
+        "{}",
+        lines[0]
+    );
+    assert!(
+        lines[0].contains(r#""out":{"messages":10,"bytes":1000,"#),
+        "{}",
+        lines[0]
+    );
+    assert!(
+        lines[1].starts_with(r#"{"in":{"messages":1,"bytes":8,"#),
+        "{}",
+        lines[1]
+    );
+    assert!(
+        lines[1].contains(r#""out":{"messages":1,"bytes":8,"#),
+        "{}",
+        lines[1]
+    );
+}
+
+#[test]
+fn seqnum() {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.seq", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    prepare!(core);
+    let prog = wt!(core,
+        "literal:qwert15y",
+        &format!("seqnum:writefile:{}", path),
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+    let data = std::fs::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(data, b"\xd5\0\0\0\0\0\0\0\0qwert15y".to_vec());
+
+    // A gap is only logged by default
+    let prog = wt!(core,
+        "seqnum:literal-hex:d5 0000000000000005 7177657274313579",
+        "assert:qwert15y",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn seqnum_strict() {
+    prepare!(core);
+    let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+    let failed2 = failed.clone();
+    let prog = wt!(core,
+        "seqnum:literal-hex:d5 0000000000000005 7177657274313579",
+        "assert:",
+        nodelay,
+        opts = Options {
+            seqnum_strict: true,
+            ..dflt()
+        },
+        onerror = move |_| failed2.set(true),
+    );
+    let _ = core.run(prog);
+    assert!(failed.get());
+}
+
+#[test]
+fn checksums() {
+    use websocat::crc_peer::{crc32c, xxh32};
+    assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    assert_eq!(xxh32(b"", 0), 0x02CC_5D05);
+    assert_eq!(xxh32(b"abc", 0), 0x32D1_53FF);
+    assert_eq!(
+        xxh32(b"Nobody inspects the spammish repetition", 0),
+        0xE229_3B2F
+    );
+}
+
+#[test]
+fn crc() {
+    prepare!(core);
+    let prog = wt!(core,
+        "crc:literal-hex:7177657274313679 6f35c512",
+        "assert:qwert16y",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.crc", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let prog = wt!(core,
+        "literal:qwert16y",
+        &format!("crc-stream:writefile:{}", path),
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+    let data = std::fs::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(data, b"\0\0\0\x08qwert16y\x6f\x35\xc5\x12".to_vec());
+}
+
+#[test]
+fn crc_strict() {
+    prepare!(core);
+    let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+    let failed2 = failed.clone();
+    let prog = wt!(core,
+        "crc-stream:literal-hex:00000008 7177657274313679 6f35c513",
+        "assert:",
+        nodelay,
+        opts = Options {
+            crc_strict: true,
+            ..dflt()
+        },
+        onerror = move |_| failed2.set(true),
+    );
+    let _ = core.run(prog);
+    assert!(failed.get());
+}
+
+#[test]
+fn badmirror() {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.badmirror", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    prepare!(core);
+    let prog = wt!(core,
+        "literal:qwert14y",
+        "count:badmirror:",
+        nodelay,
+        opts = Options {
+            count_output: Some(path.clone()),
+            mirror_delay_ms: 100,
+            mirror_dup_rate: 1.0,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let summary = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(summary.starts_with(r#"{"in":{"messages":2,"bytes":16,"#), "{}", summary);
+
+    let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+    let failed2 = failed.clone();
+    let prog = wt!(core,
+        "random:1000",
+        "badmirror:",
+        nodelay,
+        opts = Options {
+            gen_message_size: Some(100),
+            mirror_close_after: Some(3),
+            ..dflt()
+        },
+        onerror = move |_| failed2.set(true),
+    );
+    let _ = core.run(prog);
+    assert!(failed.get());
+}
+
+#[test]
+fn clog() {
+    prepare!(core);
+    let prog = wt!(core,
+        "literal:qwert15y",
+        "clog:assert:qwert15y",
+        nodelay,
+        opts = Options {
+            clog_direction: "write".to_string(),
+            clog_after_bytes: 4,
+            clog_duration: Some(1),
+            ..dflt()
+        },
+        errpanic,
+    );
+    let start = std::time::Instant::now();
+    run!(core, prog);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(900));
+}
+
+#[test]
+fn idle_timeout() {
+    use websocat::idle_timeout::is_idle_timeout;
+    prepare!(core);
+    let timed_out = std::rc::Rc::new(std::cell::Cell::new(false));
+    let timed_out2 = timed_out.clone();
+    let prog = wt!(core,
+        "mirror:",
+        "mirror:",
+        nodelay,
+        opts = Options {
+            idle_timeout: Some(1),
+            idle_timeout_direction: "both".to_string(),
+            ..dflt()
+        },
+        onerror = move |e: Box<std::error::Error>| {
+            assert!(is_idle_timeout(&*e), "{}", e);
+            timed_out2.set(true);
+        },
+    );
+    let start = std::time::Instant::now();
+    // The session ends with an error, so no `run!` here
+    let _ = core.run(prog);
+    let t = start.elapsed();
+    assert!(timed_out.get());
+    assert!(t >= std::time::Duration::from_millis(900), "{:?}", t);
+    assert!(t <= std::time::Duration::from_millis(3000), "{:?}", t);
+}
+
+#[test]
+fn broadcast_retain() {
+    prepare!(core);
+    let prog1 = wt!(core,
+        "tcp-l:127.0.0.1:45930",
+        "broadcast:literal:status",
+        nodelay,
+        opts = Options {
+            broadcast_retain: true,
+            broadcast_retain_max_bytes: 1024,
+            ..dflt()
+        },
+        errignore,
+    );
+    // The first client starts the upstream and gets the message live,
+    // the second one connects after it was sent and gets the retained copy
+    let prog2 = wt!(core,
+        "tcp:127.0.0.1:45930",
+        "assert:status",
+        delay = 200,
+        opts = Options {
+            one_message: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let prog3 = wt!(core,
+        "tcp:127.0.0.1:45930",
+        "assert:status",
+        delay = 400,
+        opts = Options {
+            one_message: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    let prog = prog2.join(prog3);
+    run!(core, prog);
+}
+
+/// --broadcast-retain-max-bytes 0 does not limit retained messages
+#[test]
+fn broadcast_retain_unlimited_bytes() {
+    prepare!(core);
+    let prog1 = wt!(core,
+        "tcp-l:127.0.0.1:45987",
+        "broadcast:literal:status",
+        nodelay,
+        opts = Options {
+            broadcast_retain: true,
+            broadcast_retain_max_bytes: 0,
+            ..dflt()
+        },
+        errignore,
+    );
+    let prog2 = wt!(core,
+        "tcp:127.0.0.1:45987",
+        "assert:status",
+        delay = 200,
+        opts = Options {
+            one_message: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let prog3 = wt!(core,
+        "tcp:127.0.0.1:45987",
+        "assert:status",
+        delay = 400,
+        opts = Options {
+            one_message: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    let prog = prog2.join(prog3);
+    run!(core, prog);
+}
+
+/// Messages arriving while no clients are connected reach the next client,
+/// with the default --reuse-buffer-bytes
+#[test]
+fn broadcast_reuse_buffer() {
+    use std::io::Write;
+
+    prepare!(core);
+    let upstream = std::net::TcpListener::bind("127.0.0.1:45989").unwrap();
+    let upstream = std::thread::spawn(move || {
+        let (mut s, _) = upstream.accept().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(400));
+        s.write_all(b"queued").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+    });
+    let prog1 = wt!(core,
+        "tcp-l:127.0.0.1:45988",
+        "broadcast:tcp:127.0.0.1:45989",
+        nodelay,
+        opts = Options {
+            exit_on_eof: true,
+            reuse_buffer: Some(4),
+            ..dflt()
+        },
+        errignore,
+    );
+    // Starts the upstream and leaves before it sends anything
+    let first = std::thread::spawn(|| {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let s = std::net::TcpStream::connect("127.0.0.1:45988").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        drop(s);
+    });
+    let prog2 = wt!(core,
+        "tcp:127.0.0.1:45988",
+        "assert:queued",
+        delay = 700,
+        opts = Options {
+            one_message: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    run!(core, prog2);
+    first.join().unwrap();
+    upstream.join().unwrap();
+}
+
+#[test]
+fn reuse_buffer_overflow_mode_checked() {
+    let out = websocat_bin()
+        .args(&["--reuse-buffer-overflow", "drop-everything", "literal:x", "-"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let err = String::from_utf8_lossy(&out.stderr);
+    assert!(err.contains("drop-everything"), "{}", err);
+    assert!(out.stdout.is_empty());
+
+    prepare!(core);
+    let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+    let failed2 = failed.clone();
+    let prog = wt!(core,
+        "broadcast:literal:x",
+        "assert:x",
+        nodelay,
+        opts = Options {
+            reuse_buffer_overflow: "drop-everything".to_string(),
+            ..dflt()
+        },
+        onerror = move |e: Box<std::error::Error>| {
+            assert!(format!("{}", e).contains("--reuse-buffer-overflow"), "{}", e);
+            failed2.set(true);
+        },
+    );
+    let _ = core.run(prog);
+    assert!(failed.get());
+}
+
+#[test]
+fn lb() {
+    prepare!(core);
+    let prog1 = wt!(core,
+        "literal:qwert14y",
+        "tcp-l:127.0.0.1:45933",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    // Nothing listens on the first backend, so it gets skipped
+    let prog2 = wt!(core,
+        "lb:tcp:127.0.0.1:45934|tcp:127.0.0.1:45933",
+        "assert:qwert14y",
+        delay = 200,
+        opts = Options {
+            lb_policy: "first-available".to_string(),
+            lb_quarantine: 10,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let prog = prog1.join(prog2);
+    run!(core, prog);
+}
+
+#[test]
+fn failover() {
+    prepare!(core);
+    let prog1 = wt!(core,
+        "literal:qwert15y",
+        "tcp-l:127.0.0.1:45935",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    let prog2 = wt!(core,
+        "failover:tcp:127.0.0.1:45936|tcp:127.0.0.1:45935",
+        "assert:qwert15y",
+        delay = 200,
+        opts = Options {
+            failover_cooldown: 30,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let prog = prog1.join(prog2);
+    run!(core, prog);
+}
+
+#[test]
+fn multilisten() {
+    prepare!(core);
+    let prog1 = wt!(core,
+        "multilisten:tcp-l:127.0.0.1:45937|tcp-l:127.0.0.1:45938",
+        "literal:qwert16y",
+        nodelay,
+        noopts,
+        errignore,
+    );
+    let prog2 = wt!(core,
+        "tcp:127.0.0.1:45937",
+        "assert:qwert16y",
+        delay = 200,
+        noopts,
+        errpanic,
+    );
+    let prog3 = wt!(core,
+        "tcp:127.0.0.1:45938",
+        "assert:qwert16y",
+        delay = 200,
+        noopts,
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    let prog = prog2.join(prog3);
+    run!(core, prog);
+}
+
+/// --max-sessions counts sessions of all listeners, before the WebSocket handshake
+#[test]
+fn multilisten_max_sessions() {
+    use std::io::{Read, Write};
+    use std::time::Duration;
+    use tokio_core::reactor::Timeout;
+
+    prepare!(core);
+    let prog1 = wt!(core,
+        "multilisten:ws-l:127.0.0.1:45990|ws-l:127.0.0.1:45991",
+        "clogged:",
+        nodelay,
+        opts = Options {
+            max_sessions: Some(1),
+            ..dflt()
+        },
+        errignore,
+    );
+    let prog2 = wt!(core, "ws://127.0.0.1:45990/", "clogged:", delay = 100, noopts, errignore,);
+    let refused = std::thread::spawn(|| {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        let mut s = std::net::TcpStream::connect("127.0.0.1:45991").unwrap();
+        s.write_all(
+            b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        ).unwrap();
+        let mut reply = vec![];
+        s.read_to_end(&mut reply).unwrap();
+        reply
+    });
+    let h = core.handle();
+    h.spawn(prog1);
+    h.spawn(prog2);
+    core.run(Timeout::new(Duration::from_millis(1000), &h).unwrap()).unwrap();
+    let reply = refused.join().unwrap();
+    assert!(reply.starts_with(b"HTTP/1.1 503"), "{}", String::from_utf8_lossy(&reply));
+    assert_eq!(websocat::session_cap::live(), 1);
+}
+
+#[test]
+fn argv_parsing() {
+    use websocat::util::parse_argv;
+    assert_eq!(
+        parse_argv(r#" ['prog', "--flag" ,'it\'s \"x\"\t', ] "#).unwrap(),
+        vec!["prog", "--flag", "it's \"x\"\t"]
+    );
+    assert_eq!(parse_argv("[]").unwrap(), Vec::<String>::new());
+    assert!(parse_argv("['a' 'b']").is_err());
+    assert!(parse_argv("['a'").is_err());
+    assert!(parse_argv("['a\\q']").is_err());
+    assert!(parse_argv("[a]").is_err());
+}
+
+#[test]
+fn socket_addr_forms() {
+    use websocat::addr::{parse_socket_addr, split_url_zone};
+    let ok = |s: &str| parse_socket_addr(s).unwrap().to_string();
+    assert_eq!(ok("127.0.0.1:80"), "127.0.0.1:80");
+    assert_eq!(ok("[::1]:9000"), "[::1]:9000");
+    assert_eq!(ok("[::ffff:1.2.3.4]:1"), "[::ffff:1.2.3.4]:1");
+    let scoped = |s: &str| match parse_socket_addr(s).unwrap() {
+        std::net::SocketAddr::V6(a) => (a.ip().to_string(), a.port(), a.scope_id()),
+        x => panic!("{}", x),
+    };
+    assert_eq!(scoped("[fe80::1%3]:8080"), ("fe80::1".to_string(), 8080, 3));
+    assert_eq!(scoped("[fe80::1]:8080"), ("fe80::1".to_string(), 8080, 0));
+    #[cfg(all(target_os = "linux", feature = "libc"))]
+    assert!(scoped("[fe80::1%lo]:1").2 > 0);
+    for &(s, why) in &[
+        ("", "empty"),
+        ("127.0.0.1", "missing `:port`"),
+        ("127.0.0.1:", "missing port number"),
+        ("127.0.0.1:65536", "`65536` is not a port number"),
+        ("localhost:80", "`localhost` is not an IPv4 address"),
+        ("::1:80", "IPv6 addresses need brackets"),
+        ("[::1:80", "missing `]`"),
+        ("[::1]", "expected `:port` after `]`"),
+        ("[::1]80", "expected `:port` after `]`"),
+        ("[::g]:80", "`::g` is not an IPv6 address"),
+        ("[1.2.3.4]:80", "`1.2.3.4` is not an IPv6 address"),
+        ("[fe80::1%]:80", "empty zone id"),
+        ("[fe80::1%no-such-if0]:80", "no-such-if0"),
+    ] {
+        let e = parse_socket_addr(s).unwrap_err();
+        assert!(e.contains(why), "{}: {}", s, e);
+    }
+
+    let split = |s: &str| split_url_zone(s).unwrap();
+    assert_eq!(split("ws://[fe80::1%252]:8080/x?y"), ("ws://[fe80::1]:8080/x?y".to_string(), Some(2)));
+    assert_eq!(split("ws://u@[fe80::1%257]:1"), ("ws://u@[fe80::1]:1".to_string(), Some(7)));
+    assert_eq!(split("ws://[::1]:8080/%25"), ("ws://[::1]:8080/%25".to_string(), None));
+    assert_eq!(split("ws://127.0.0.1:8080/"), ("ws://127.0.0.1:8080/".to_string(), None));
+    assert!(split_url_zone("ws://[fe80::1%25/").is_err());
+    // `%25` is the delimiter: a bare `%` would make `%251` mean either zone 251 or zone 1
+    for &(s, why) in &[("ws://[fe80::1%25]/", "empty zone id"), ("ws://[fe80::1%2]/", "`%25`"), ("ws://[fe80::1%]/", "`%25`")] {
+        let e = split_url_zone(s).unwrap_err();
+        assert!(e.contains(why), "{}: {}", s, e);
+    }
+    assert!(split_url_zone("ws://[nope%251]/").is_err());
+    assert!(websocat::spec("ws://[fe80::1%251]:8080/").is_ok());
+    #[cfg(feature = "ssl")]
+    assert!(websocat::spec("wss://[fe80::1%251]:8080/").is_ok());
+    assert!(websocat::spec("tcp:[fe80::1%1]:22").is_ok());
+    assert!(websocat::spec("tcp:[fe80::1%]:22").is_err());
+}
+
+/// Server on `[::1]:port` that reads from its first client until `done` says so,
+/// passes the data on and hangs up. `None` if IPv6 is not available.
+#[cfg(target_os = "linux")]
+fn ipv6_first_read(port: u16, done: fn(&[u8]) -> bool) -> Option<std::sync::mpsc::Receiver<Vec<u8>>> {
+    use std::io::Read;
+    let l = std::net::TcpListener::bind(("::1", port)).ok()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut c = l.accept().unwrap().0;
+        let mut got = vec![];
+        let mut b = [0];
+        while !done(&got) && c.read(&mut b).unwrap() == 1 {
+            got.push(b[0]);
+        }
+        tx.send(got).unwrap();
+    });
+    Some(rx)
+}
+
+/// The zone picks the interface to connect through, it is not sent.
+/// On a loopback address the kernel ignores it.
+#[test]
+#[cfg(target_os = "linux")]
+fn ipv6_zone_on_wire() {
+    prepare!(core);
+    let head = match ipv6_first_read(46014, |x| x.ends_with(b"\r\n\r\n")) {
+        Some(x) => x,
+        None => return,
+    };
+    let client = wt!(core, "literal:hi", "ws://[::1%251]:46014/x", nodelay, noopts, errignore,);
+    let _ = core.run(client);
+    let head = String::from_utf8(head.recv().unwrap()).unwrap();
+    assert!(head.starts_with("GET /x HTTP/1.1\r\n"), "{}", head);
+    assert!(head.contains("\r\nHost: [::1]:46014\r\n"), "{}", head);
+    assert!(!head.contains('%'), "{}", head);
+}
+
+/// `wss://` connects with the zone too. The server hangs up after the first byte
+/// of the client hello, failing the TLS handshake.
+#[test]
+#[cfg(all(target_os = "linux", feature = "ssl"))]
+fn ipv6_zone_wss() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use websocat::error::{find, WebsocatError};
+
+    prepare!(core);
+    let first = match ipv6_first_read(46015, |x| !x.is_empty()) {
+        Some(x) => x,
+        None => return,
+    };
+    let failure = Rc::new(RefCell::new(None));
+    let failure2 = failure.clone();
+    let client = wt!(core,
+        "literal:hi",
+        "wss://[::1%251]:46015/",
+        nodelay,
+        noopts,
+        onerror = move |e: Box<std::error::Error>| {
+            *failure2.borrow_mut() = Some(match find(&*e) {
+                Some(&WebsocatError::Tls { .. }) => Ok(()),
+                _ => Err(e.to_string()),
+            });
+        },
+    );
+    let _ = core.run(client);
+    // Handshake record
+    assert_eq!(first.recv().unwrap(), vec![0x16]);
+    assert_eq!(failure.borrow_mut().take(), Some(Ok(())));
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn exec_argv() {
+    prepare!(core);
+    let prog = wt!(core,
+        "exec:['printf','%s|%s|%s','a b','c','$HOME']",
+        "assert:a b|c|$HOME",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "exec:['sh','-c','printf %s \"$QQQ\"; pwd']",
+        "assert:qwert17y/\n",
+        nodelay,
+        opts = Options {
+            exec_env: vec![("QQQ".to_string(), "qwert17y".to_string())],
+            exec_chdir: Some("/".to_string()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+#[cfg(all(unix, feature = "pty", feature = "tokio-process"))]
+fn exec_pty() {
+    prepare!(core);
+    let prog = wt!(core,
+        "sh-c:test -t 0 && test -t 1 && test -t 2 && stty size",
+        "assert:30 100\r\n",
+        nodelay,
+        opts = Options {
+            exec_pty: true,
+            pty_size: Some("100x30".to_string()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio-process", feature = "libc"))]
+fn exec_kill_signal_values() {
+    use websocat::process_peer::parse_signal;
+    assert_eq!(parse_signal(""), Some(15));
+    assert_eq!(parse_signal("TERM"), Some(15));
+    assert_eq!(parse_signal("SIGKILL"), Some(9));
+    assert_eq!(parse_signal("1"), Some(1));
+    assert_eq!(parse_signal("0"), None);
+    assert_eq!(parse_signal("-9"), None);
+    assert_eq!(parse_signal("-1"), None);
+    assert_eq!(parse_signal("SIGBOGUS"), None);
+
+    let out = websocat_bin()
+        .args(&["--exec-kill-signal", "0", "literal:x", "-"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--exec-kill-signal"));
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn exit_status_from_exec() {
+    prepare!(core);
+    let prog = wt!(core,
+        "exec:['sh','-c','printf x; kill -TERM $$']",
+        "assert:x",
+        nodelay,
+        opts = Options {
+            exit_status_from_exec: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let code = core.run(websocat::process_peer::wait_for_children()).unwrap();
+    assert_eq!(code, Some(128 + 15));
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn exec_early_exit() {
+    prepare!(core);
+    // The background `sleep` keeps the output pipe open
+    let prog = wt!(core,
+        "exec:['sh','-c','printf x; sleep 5 & exit 3']",
+        "assert:x",
+        nodelay,
+        opts = Options {
+            exit_status_from_exec: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    let start = std::time::Instant::now();
+    run!(core, prog);
+    let code = core.run(websocat::process_peer::wait_for_children()).unwrap();
+    assert_eq!(code, Some(3));
+    assert!(start.elapsed() < std::time::Duration::from_secs(3));
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn filtermsg() {
+    prepare!(core);
+    let prog = wt!(core,
+        "literal:qwert16y",
+        "filtermsg:tr a-z A-Z:assert:QWERT16Y",
+        nodelay,
+        opts = Options {
+            filter_direction: "out".to_string(),
+            filter_concurrency: 4,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+/// Filter output over --buffer-size fails the session instead of being buffered
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn filtermsg_output_limit() {
+    for &(cmd, persistent) in &[
+        ("head -c 5000 /dev/zero", false),
+        ("printf '\\177\\377\\377\\377'; cat >/dev/null", true),
+    ] {
+        prepare!(core);
+        let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+        let failed2 = failed.clone();
+        let prog = wt!(core,
+            "literal:qwert18y",
+            &format!("filtermsg:{}:assert:", cmd),
+            nodelay,
+            opts = Options {
+                filter_direction: "out".to_string(),
+                filter_persistent: persistent,
+                buffer_size: Some(1024),
+                ..dflt()
+            },
+            onerror = move |e: Box<std::error::Error>| {
+                assert!(format!("{}", e).contains("longer than 1024 bytes"), "{}", e);
+                failed2.set(true);
+            },
+        );
+        let _ = core.run(prog);
+        assert!(failed.get(), "{}", cmd);
+    }
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn exec_umask_chdir() {
+    prepare!(core);
+    let prog = wt!(core,
+        "sh-c:test -n \"$WEBSOCAT_SESSION_ID\" && umask && pwd",
+        "assert:0027\n/\n",
+        nodelay,
+        opts = Options {
+            exec_umask: Some(0o027),
+            exec_chdir: Some("/".to_string()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn exec_session_env() {
+    prepare!(core);
+    let prog1 = wt!(core,
+        "ws-l:127.0.0.1:45939",
+        "sh-c:printf '%s|%s|%s' \"$WEBSOCAT_URI\" \"$WEBSOCAT_HEADER_X_TEST\" \"$WEBSOCAT_LISTEN_SPEC\"",
+        nodelay,
+        opts = Options {
+            env_headers: vec!["x-test".to_string()],
+            listen_spec: Some("qwe".to_string()),
+            ..dflt()
+        },
+        errignore,
+    );
+    let prog2 = wt!(core,
+        "ws://127.0.0.1:45939/path?q=1",
+        "assert:/path?q=1|hi|qwe",
+        delay = 200,
+        opts = Options {
+            custom_headers: vec![("X-Test".to_string(), b"hi".to_vec())],
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    run!(core, prog2);
+}
+
+/// The `websocat` executable built along with the tests
+fn websocat_bin() -> std::process::Command {
+    std::process::Command::new(env!("CARGO_BIN_EXE_websocat"))
+}
+
+/// Each `--env-headers` takes one value and leaves the specifiers alone
+#[test]
+fn env_headers_args() {
+    let out = websocat_bin()
+        .args(&["--env-headers", "x-a", "--env-headers", "x-b,x-c", "literal:ok", "-"])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(out.stdout, b"ok".to_vec());
+}
+
+/// A client's process sees that client's URI and headers, even if another
+/// client connected after it but before its process was started
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn exec_session_env_concurrent() {
+    prepare!(core);
+    let server = wt!(core,
+        "ws-l:127.0.0.1:45983",
+        "sh-c:printf '%s|%s|%s' \"$WEBSOCAT_URI\" \"$WEBSOCAT_HEADER_X_TEST\" \"${WEBSOCAT_CLIENT%:*}\"",
+        nodelay,
+        opts = Options {
+            env_headers: vec!["x-test".to_string()],
+            lazy_connect: true,
+            ..dflt()
+        },
+        errignore,
+    );
+    core.handle().spawn(server);
+    let client = |path: &'static str, header: &'static str, wait: u64| {
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let mut s = raw_ws_connect("127.0.0.1:45983", path, &format!("X-Test: {}\r\n", header));
+            std::thread::sleep(std::time::Duration::from_millis(wait));
+            raw_ws_send(&mut s, b"go");
+            raw_ws_frame(&mut s).1
+        })
+    };
+    // The first one starts its process only after the second one has connected
+    let first = client("/first", "1", 400);
+    std::thread::sleep(std::time::Duration::from_millis(150));
+    let second = client("/second", "2", 0);
+    let t = tokio_timer::wheel().build();
+    let _ = core.run(t.sleep(std::time::Duration::from_millis(1500)));
+    assert_eq!(first.join().unwrap(), b"/first|1|127.0.0.1".to_vec());
+    assert_eq!(second.join().unwrap(), b"/second|2|127.0.0.1".to_vec());
+}
+
+#[test]
+fn url_normalization() {
+    use websocat::urlnorm::{normalize, percent_decode, request_target};
+    for &(from, to) in &[
+        ("ws://h/a b", "ws://h/a%20b"),
+        ("ws://h/a%20b", "ws://h/a%20b"),
+        ("ws://h/a%2fb%2F", "ws://h/a%2fb%2F"),
+        ("ws://h/a#b", "ws://h/a%23b"),
+        ("ws://h/100%", "ws://h/100%25"),
+        ("ws://h/%zz%2", "ws://h/%25zz%252"),
+        ("ws://h/\u{fc}ber", "ws://h/%C3%BCber"),
+        ("ws://h/a+b?q=1+2&r=a b", "ws://h/a+b?q=1+2&r=a%20b"),
+        ("ws://h?q=%", "ws://h?q=%25"),
+        ("ws://u s@h:1/", "ws://u s@h:1/"),
+        ("ws://h", "ws://h"),
+    ] {
+        assert_eq!(normalize(from), to);
+        assert_eq!(normalize(to), to);
+    }
+    assert_eq!(request_target("ws://h:1/a b#c"), "/a b#c");
+    assert_eq!(request_target("ws://h?x"), "/?x");
+    assert_eq!(request_target("ws://h"), "/");
+    assert_eq!(percent_decode("a%20b%3a%zz%"), b"a b:%zz%");
+}
+
+/// What a `ws-l:` server sees of the URL: request target and Basic authorization
+#[test]
+#[cfg(all(unix, feature = "tokio-process"))]
+fn url_normalization_on_wire() {
+    prepare!(core);
+    let server = wt!(core,
+        "ws-l:127.0.0.1:45977",
+        "sh-c:printf '%s|%s' \"$WEBSOCAT_URI\" \"$WEBSOCAT_HEADER_AUTHORIZATION\"",
+        nodelay,
+        opts = Options {
+            env_headers: vec!["authorization".to_string()],
+            ..dflt()
+        },
+        errignore,
+    );
+    core.handle().spawn(server);
+    let auth = format!("Basic {}", base64::encode("us er:p@ss"));
+    for &(url, raw, ref expected) in &[
+        ("ws://127.0.0.1:45977/a b/\u{fc}?x=1+2#f", false, "/a%20b/%C3%BC?x=1+2%23f|".to_string()),
+        ("ws://127.0.0.1:45977/x%zz%41", false, "/x%25zz%41|".to_string()),
+        ("ws://127.0.0.1:45977/x%zz%41", true, "/x%zz%41|".to_string()),
+        ("ws://us%20er:p%40ss@127.0.0.1:45977/", false, format!("/|{}", auth)),
+    ] {
+        let client = wt!(core,
+            url,
+            &format!("assert:{}", expected),
+            delay = 200,
+            opts = Options {
+                no_url_normalization: raw,
+                ..dflt()
+            },
+            errpanic,
+        );
+        run!(core, client);
+    }
+}
+
+#[test]
+fn metrics() {
+    prepare!(core);
+    let addr = "127.0.0.1:45940".parse().unwrap();
+    let srv = websocat::metrics::serve_metrics(&addr, &core.handle()).unwrap();
+    core.handle().spawn(srv);
+    let prog = wt!(core,
+        "prepend:GET /healthz HTTP/1.0\r\n\r\n:tcp:127.0.0.1:45940",
+        r"assert-literal:HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: 3\r\nConnection: close\r\n\r\nok\n",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+    let page = websocat::metrics::render();
+    assert!(page.contains("websocat_sessions_ended_total 1\n"));
+    assert!(page.contains("websocat_sessions_live 0\n"));
+}
+
+#[test]
+fn lazy_connect() {
+    prepare!(core);
+    // Nothing listens on port 1; the right side must not be touched
+    let prog = wt!(core,
+        "literal:",
+        "tcp:127.0.0.1:1",
+        nodelay,
+        opts = Options {
+            lazy_connect: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let prog = wt!(core,
+        "literal:qwerty",
+        "assert:qwerty",
+        nodelay,
+        opts = Options {
+            lazy_connect: true,
+            lazy_buffer_bytes: 4,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn total_budget() {
+    prepare!(core);
+    let prog = wt!(core,
+        "lenprefix:literal-hex:00000001 61 00000001 62 00000001 63",
+        "assert:ab",
+        nodelay,
+        opts = Options {
+            total_max_messages: Some(2),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let reason = websocat::shutdown::reason().unwrap();
+    assert!(reason.ends_with(websocat::budget::BUDGET_REASON));
+}
+
+#[test]
+fn stats_summary() {
+    prepare!(core);
+    let prog = wt!(core,
+        "literal:qwerty",
+        "assert:qwerty",
+        nodelay,
+        opts = Options {
+            stats: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog);
+    let v = websocat::metrics::summary();
+    assert_eq!(v["bytes_out"], 6);
+    assert_eq!(v["messages_out"], 1);
+    assert_eq!(v["bytes_in"], 0);
+    assert_eq!(v["sessions"], 1);
+}
+
+/// Everything read from a file before EOF reaches a server that reads slowly
+#[test]
+fn ws_upload_flushed() {
+    use std::io::Write;
+    let mut src = std::env::temp_dir();
+    src.push(format!("websocat_test_{}.upload_src", std::process::id()));
+    let mut dst = src.clone();
+    dst.set_extension("upload_dst");
+    let mut x = 54321u32;
+    let content: Vec<u8> = (0..3_000_000)
+        .map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        })
+        .collect();
+    std::fs::File::create(&src).unwrap().write_all(&content).unwrap();
+    let _ = std::fs::remove_file(&dst);
+
+    prepare!(core);
+    let server = wt!(core,
+        "ws-l:127.0.0.1:45976",
+        &format!("throttle:writefile:{}", dst.to_str().unwrap()),
+        nodelay,
+        opts = Options {
+            oneshot: true,
+            unidirectional: true,
+            throttle_bytes_per_sec: 3_000_000,
+            throttle_burst: 65536,
+            throttle_direction: "out".to_string(),
+            ..dflt()
+        },
+        errpanic,
+    );
+    let client = wt!(core,
+        &format!("readfile:{}", src.to_str().unwrap()),
+        "ws://127.0.0.1:45976/",
+        delay = 200,
+        opts = Options {
+            unidirectional: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, server.join(client).map(|_| ()));
+    let received = std::fs::read(&dst).unwrap();
+    let _ = std::fs::remove_file(&src);
+    let _ = std::fs::remove_file(&dst);
+    assert!(received == content, "got {} of {} bytes", received.len(), content.len());
+    let v = websocat::metrics::summary();
+    assert_eq!(v["ws_bytes_accepted"], v["ws_bytes_flushed"]);
+}
+
+#[test]
+fn pid_file() {
+    prepare!(core);
+    let path = std::env::temp_dir().join(format!("websocat_test_{}.pid", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let prog1 = wt!(core,
+        "tcp-l:127.0.0.1:45941",
+        "literal:qwerty",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    // Same order as in main: listeners are bound once `serve` returns
+    websocat::pid_file::write_pid_file(&path).unwrap();
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(content.trim(), std::process::id().to_string());
+    // Our own process is alive, so second instance must refuse to start
+    assert!(websocat::pid_file::write_pid_file(&path).is_err());
+    // Connect right away, without any delay
+    let prog2 = wt!(core,
+        "tcp:127.0.0.1:45941",
+        "assert:qwerty",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    run!(core, prog2);
+    websocat::shutdown::run_exit_hooks();
+    assert!(!path.exists());
+}
+
+#[test]
+fn shutdown_closes_sessions() {
+    prepare!(core);
+    let prog1 = wt!(core,
+        "ws-l:127.0.0.1:45942",
+        "clogged:",
+        nodelay,
+        noopts,
+        errignore,
+    );
+    let prog2 = wt!(core,
+        "ws://127.0.0.1:45942",
+        "clogged:",
+        delay = 200,
+        noopts,
+        errpanic,
+    );
+    let t = tokio_timer::wheel().build();
+    let signal = t
+        .sleep(std::time::Duration::from_millis(500))
+        .map(|()| websocat::shutdown::initiate_and_close_sessions("test", 1001))
+        .map_err(|_| ());
+    core.handle().spawn(prog1);
+    core.handle().spawn(signal);
+    // Would hang forever without the shutdown
+    run!(core, prog2);
+    assert_eq!(websocat::shutdown::reason(), Some("test".to_string()));
+}
+
+#[test]
+fn reload_reopens_log() {
+    prepare!(core);
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("websocat_test_{}.log", std::process::id()));
+    let moved = dir.join(format!("websocat_test_{}.log.old", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&moved);
+    let prog1 = wt!(core,
+        "tcp-l:127.0.0.1:45943",
+        "log:literal:hi",
+        nodelay,
+        opts = Options {
+            log_file: Some(path.to_str().unwrap().to_string()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    let prog2 = wt!(core, "tcp:127.0.0.1:45943", "assert:hi", nodelay, noopts, errpanic,);
+    run!(core, prog2);
+    std::fs::rename(&path, &moved).unwrap();
+    websocat::reload::reload();
+    let prog3 = wt!(core, "tcp:127.0.0.1:45943", "assert:hi", nodelay, noopts, errpanic,);
+    run!(core, prog3);
+    assert!(traffic_log_with(&moved, "2 bytes").contains("2 bytes"));
+    assert!(traffic_log_with(&path, "2 bytes").contains("2 bytes"));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&moved);
+}
+
+#[test]
+fn max_sessions() {
+    prepare!(core);
+    let prog1 = wt!(core,
+        "tcp-l:127.0.0.1:45944",
+        "clogged:",
+        nodelay,
+        opts = Options {
+            max_sessions: Some(1),
+            ..dflt()
+        },
+        errignore,
+    );
+    let prog2 = wt!(core, "tcp:127.0.0.1:45944", "clogged:", delay = 100, noopts, errignore,);
+    // Gets closed right away instead of hanging
+    let prog3 = wt!(core, "tcp:127.0.0.1:45944", "assert:", delay = 300, noopts, errpanic,);
+    core.handle().spawn(prog1);
+    core.handle().spawn(prog2);
+    run!(core, prog3);
+    assert_eq!(websocat::session_cap::live(), 1);
+}
+
+#[test]
+fn json_events() {
+    prepare!(core);
+    let path = std::env::temp_dir().join(format!("websocat_test_{}.events", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let prog1 = wt!(core,
+        "ws-l:127.0.0.1:45945",
+        "literal:hi",
+        nodelay,
+        opts = Options {
+            log_format: "json".to_string(),
+            events_file: Some(path.to_str().unwrap().to_string()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    let prog2 = wt!(core, "ws://127.0.0.1:45945", "assert:hi", delay = 200, noopts, errpanic,);
+    run!(core, prog2);
+    let log = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let accepted = log.lines().find(|l| l.contains("\"event\":\"accepted\"")).unwrap();
+    let sid = &accepted[accepted.find("\"sid\":").unwrap()..];
+    let sid = &sid[..sid.find(|c| c == ',' || c == '}').unwrap()];
+    let lines: Vec<&str> = log.lines().filter(|l| l.contains(sid)).collect();
+    assert!(lines.iter().any(|l| l.contains("\"event\":\"handshake_ok\"")));
+    assert!(lines.iter().any(|l| l.contains("\"event\":\"session_start\"")));
+}
+
+#[test]
+fn syslog_udp() {
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    server.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let r = websocat::remote_log::RemoteLog::syslog(Some(&addr)).unwrap();
+    r.log(log::Level::Warn, "accepted", &[("sid", "5".to_string())]);
+    let mut buf = [0; 1024];
+    let n = server.recv(&mut buf).unwrap();
+    let msg = String::from_utf8_lossy(&buf[..n]);
+    // daemon.warning
+    assert!(msg.starts_with("<28>1 "));
+    assert!(msg.ends_with(" accepted sid=5"));
+    assert_eq!(r.dropped(), 0);
+}
+
+/// Sends windows of datagrams to a `mirror:` behind websocat, so that its peer
+/// reads and writes in the same turns. Returns how many came back.
+fn dgram_echo_rounds<S, R>(mut send: S, mut recv: R) -> usize
+where
+    S: FnMut(&[u8]) -> std::io::Result<usize>,
+    R: FnMut(&mut [u8]) -> std::io::Result<usize>,
+{
+    const ROUNDS: u8 = 100;
+    const WINDOW: u8 = 16;
+    let mut got = 0;
+    let mut buf = [0; 256];
+    for round in 0..ROUNDS {
+        for k in 0..WINDOW {
+            send(&[round, k, 1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        }
+        for _ in 0..WINDOW {
+            let n = recv(&mut buf).unwrap();
+            assert_eq!(n, 10);
+            assert_eq!(buf[0], round);
+            got += 1;
+        }
+    }
+    got
+}
+
+#[test]
+fn udp_listen_simultaneous() {
+    prepare!(core);
+    let prog1 = wt!(core, "udp-l:127.0.0.1:45969", "mirror:", nodelay, noopts, errpanic,);
+    core.handle().spawn(prog1);
+    let (tx, rx) = futures::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let c = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        c.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        c.connect("127.0.0.1:45969").unwrap();
+        let _ = tx.send(dgram_echo_rounds(|b| c.send(b), |b| c.recv(b)));
+    });
+    assert_eq!(core.run(rx).unwrap(), 1600);
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_dgram_simultaneous() {
+    let dir = std::env::temp_dir();
+    let a = dir.join(format!("websocat_test_{}.dgram_a", std::process::id()));
+    let b = dir.join(format!("websocat_test_{}.dgram_b", std::process::id()));
+    let _ = std::fs::remove_file(&a);
+    let _ = std::fs::remove_file(&b);
+    let c = std::os::unix::net::UnixDatagram::bind(&b).unwrap();
+    c.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        &format!("unix-dgram:{}:{}", a.to_str().unwrap(), b.to_str().unwrap()),
+        "mirror:",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    let (tx, rx) = futures::sync::oneshot::channel();
+    let a2 = a.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        c.connect(&a2).unwrap();
+        let _ = tx.send(dgram_echo_rounds(|x| c.send(x), |x| c.recv(x)));
+    });
+    assert_eq!(core.run(rx).unwrap(), 1600);
+    let _ = std::fs::remove_file(&a);
+    let _ = std::fs::remove_file(&b);
+}
+
+#[cfg(unix)]
+#[test]
+fn session_hooks() {
+    prepare!(core);
+    let path = std::env::temp_dir().join(format!("websocat_test_{}.hook", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let prog1 = wt!(core,
+        "tcp-l:127.0.0.1:45946",
+        "literal:hi",
+        nodelay,
+        opts = Options {
+            on_disconnect: Some(format!(
+                "echo $WEBSOCAT_EVENT $WEBSOCAT_BYTES_IN > {}",
+                path.to_str().unwrap()
+            )),
+            hook_max_concurrent: 1,
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    let prog2 = wt!(core, "tcp:127.0.0.1:45946", "assert:hi", nodelay, noopts, errpanic,);
+    run!(core, prog2);
+    let t = tokio_timer::wheel().build();
+    let mut content = String::new();
+    for _ in 0..50 {
+        let _ = core.run(t.sleep(std::time::Duration::from_millis(100)));
+        content = std::fs::read_to_string(&path).unwrap_or_default();
+        if !content.is_empty() {
+            break;
+        }
+    }
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(content, "disconnect 2\n");
+}
+
+#[test]
+fn config_file_args() {
+    use websocat::util::parse_config_args;
+    let env = |n: &str| if n == "PORT" { Some("8080".to_string()) } else { None };
+    let text = "# bridge\n\n--text\n  --header\nX-Cost: $$5\nws-l:127.0.0.1:${PORT}\n";
+    assert_eq!(
+        parse_config_args(text, env).unwrap(),
+        vec!["--text", "--header", "X-Cost: $5", "ws-l:127.0.0.1:8080"]
+    );
+    let e = parse_config_args("tcp:${HOST}:1\n", env).unwrap_err();
+    assert!(e.starts_with("line 1:") && e.contains("HOST"));
+}
+
+#[test]
+fn exit_codes() {
+    use websocat::exit_code::{classify, ExitCode};
+    assert_eq!(ExitCode::Http(Some(403)).code(false), 14);
+    assert_eq!(ExitCode::Http(Some(503)).code(false), 15);
+    assert_eq!(ExitCode::Dns.code(true), 1);
+    assert_eq!(ExitCode::Signal(15).code(false), 143);
+    assert_eq!(ExitCode::Signal(15).code(true), 0);
+    assert_eq!(ExitCode::IdleTimeout.code(true), 2);
+    assert_eq!(ExitCode::BudgetReached.code(true), 3);
+    assert_eq!(ExitCode::SessionFailed.code(true), 125);
+    assert_eq!(ExitCode::TargetsFailed.code(true), 1);
+    let e = std::io::Error::new(std::io::ErrorKind::Other, ExitCode::Tls.error("handshake"));
+    assert_eq!(classify(&e), ExitCode::Tls);
+
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "tcp-l:127.0.0.1:45947",
+        "literalreply:HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n",
+        nodelay,
+        noopts,
+        errignore,
+    );
+    core.handle().spawn(prog1);
+    let code = std::rc::Rc::new(std::cell::Cell::new(None));
+    let code2 = code.clone();
+    let prog2 = wt!(
+        core,
+        "literal:hi",
+        "ws://127.0.0.1:45947/",
+        delay = 200,
+        noopts,
+        onerror = move |e: Box<std::error::Error>| code2.set(Some(classify(&*e))),
+    );
+    let _ = core.run(prog2);
+    assert_eq!(code.get(), Some(ExitCode::Http(Some(403))));
+}
+
+#[test]
+fn check_specifiers() {
+    use websocat::lints::{check_specifier, Severity};
+    let d = check_specifier("log:prepend-file:/nonexistent/hello:readfile:/nonexistent/x");
+    assert_eq!(d.len(), 2);
+    assert!(d.iter().all(|x| x.severity == Severity::Error));
+    assert_eq!(check_specifier("ws://bad_host.example/").len(), 1);
+    assert!(check_specifier("ws://127.0.0.1:8080/").is_empty());
+    assert!(check_specifier("autoreconnect:ws://example.com/").is_empty());
+    let codes: Vec<_> = d.iter().map(|x| x.code).collect();
+    assert_eq!(codes, vec!["E0003", "E0003"]);
+    assert_eq!(check_specifier("ws://bad_host.example/")[0].code, "E0004");
+}
+
+#[test]
+fn lint_codes() {
+    use websocat::lints::{diagnostics, Severity};
+    let conf = |s1: &str, s2: &str, opts: Options| WebsocatConfiguration {
+        opts,
+        s1: spec(s1).unwrap(),
+        s2: spec(s2).unwrap(),
+    };
+    let d = diagnostics(&conf(
+        "tcp-l:127.0.0.1:1234",
+        "tcp:127.0.0.1:1235",
+        Options {
+            max_messages_in: Some(3),
+            one_message: true,
+            ..dflt()
+        },
+    ));
+    assert_eq!(d.len(), 2);
+    assert!(d.iter().all(|x| x.code == "W0004" && x.severity == Severity::Warning));
+    assert_eq!(d[0].subject.as_ref().map(|x| &x[..]), Some("--one-message"));
+    assert_eq!(d[1].subject.as_ref().map(|x| &x[..]), Some("--max-messages-in"));
+    assert_eq!(d[1].to_string().split(':').next(), Some("warning[W0004]"));
+
+    let ws = Options {
+        max_messages_in: Some(3),
+        ..dflt()
+    };
+    assert!(diagnostics(&conf("ws-l:127.0.0.1:1234", "tcp:127.0.0.1:1235", ws)).is_empty());
+
+    let d = diagnostics(&conf("ws-l:127.0.0.1:1234", "-", dflt()));
+    assert_eq!(d.iter().map(|x| x.code).collect::<Vec<_>>(), vec!["W0002"]);
+    let d = diagnostics(&conf("-", "-", dflt()));
+    assert_eq!(d[0].code, "W0001");
+}
+
+#[test]
+fn each_target() {
+    use websocat::targets::{parse_target_list, run_targets};
+    prepare!(core);
+    let prog1 = wt!(core, "tcp-l:127.0.0.1:45948", "mirror:", nodelay, noopts, errignore,);
+    core.handle().spawn(prog1);
+    let list = parse_target_list("# fleet\n127.0.0.1:45948\n\n127.0.0.1:1\n");
+    assert_eq!(list.len(), 2);
+    let prog2 = run_targets(&core.handle(), "literal:ping", "tcp:%s", list, dflt(), 2, None);
+    let results = core.run(prog2).unwrap();
+    assert!(results[0].ok());
+    assert!(results[0].latency.is_some());
+    assert!(!results[1].ok());
+    assert_eq!(results[1].target, "127.0.0.1:1");
+}
+
+/// A target that never finishes fails after the timeout, without holding up the others
+#[test]
+fn each_target_timeout() {
+    use websocat::targets::run_targets;
+    prepare!(core);
+    let prog1 = wt!(core, "tcp-l:127.0.0.1:45996", "clogged:", nodelay, noopts, errignore,);
+    core.handle().spawn(prog1);
+    let prog2 = wt!(core, "tcp-l:127.0.0.1:45997", "mirror:", nodelay, noopts, errignore,);
+    core.handle().spawn(prog2);
+    let list = vec!["127.0.0.1:45996".to_string(), "127.0.0.1:45997".to_string()];
+    let timeout = Some(std::time::Duration::from_millis(300));
+    let start = std::time::Instant::now();
+    let prog3 = run_targets(&core.handle(), "literal:ping", "tcp:%s", list, dflt(), 1, timeout);
+    let results = core.run(prog3).unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(3));
+    assert_eq!(results[0].error, Some("timed out after 300ms".to_string()));
+    assert!(results[1].ok(), "{:?}", results[1]);
+}
+
+#[test]
+fn bench_echo() {
+    use websocat::bench::{run_bench, BenchParams, BenchReport};
+    prepare!(core);
+    let prog1 = wt!(core, "ws-l:127.0.0.1:45949", "mirror:", nodelay, noopts, errignore,);
+    core.handle().spawn(prog1);
+    let params = BenchParams {
+        messages: 20,
+        size: 32,
+        concurrency: 2,
+        rate: None,
+    };
+    let target = spec("ws://127.0.0.1:45949/").unwrap();
+    let conns = core.run(run_bench(&core.handle(), target, dflt(), params)).unwrap();
+    assert_eq!(conns.len(), 2);
+    let total = BenchReport::aggregate(&conns);
+    assert_eq!(total.received, 20);
+    assert!(total.ok());
+    assert!(total.percentile(50.0).is_some());
+}
+
+#[test]
+fn keep_open_after_stdin_eof() {
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "ws-l:127.0.0.1:45950",
+        "delay:literal:late",
+        nodelay,
+        opts = Options {
+            delay_ms: 300,
+            ..dflt()
+        },
+        errignore,
+    );
+    core.handle().spawn(prog1);
+    let prog2 = wt!(
+        core,
+        "assert:late",
+        "ws://127.0.0.1:45950/",
+        delay = 100,
+        opts = Options {
+            no_close_on_stdin_eof: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog2);
+    let prog3 = wt!(
+        core,
+        "assert:late",
+        "ws://127.0.0.1:45950/",
+        nodelay,
+        opts = Options {
+            close_stdin_only: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog3);
+}
+
+#[test]
+fn timestamps() {
+    use std::time::{Duration, UNIX_EPOCH};
+    use websocat::line_peer::TimestampFormat;
+    use websocat::lints::ConfigurationConcern::TimestampsInBinaryMode;
+    let t = UNIX_EPOCH + Duration::from_millis(1_500_000_000_123);
+    let d = Duration::from_millis(1250);
+    assert_eq!(TimestampFormat::Rfc3339.format(t, d), "2017-07-14T02:40:00.123Z");
+    assert_eq!(TimestampFormat::EpochMs.format(t, d), "1500000000123");
+    assert_eq!(TimestampFormat::Relative.format(t, d), "1.250");
+    assert_eq!(TimestampFormat::from_str("hh:mm"), None);
+
+    let conf = |s1: &str| WebsocatConfiguration {
+        opts: Options {
+            timestamps: Some("relative".to_string()),
+            ..dflt()
+        },
+        s1: spec(s1).unwrap(),
+        s2: spec("assert:").unwrap(),
+    };
+    assert!(conf("literal:a").get_concerns().contains(&TimestampsInBinaryMode));
+    assert!(!conf("msg2line:literal:a").get_concerns().contains(&TimestampsInBinaryMode));
+}
+
+/// Connections to one address are served by `--workers` threads, which report back their counters
+#[test]
+#[cfg(target_os = "linux")]
+fn workers_reuseport() {
+    use std::io::{Read, Write};
+    use websocat::lints::ConfigurationConcern::WorkersWithoutTcpListener;
+
+    let opts = || Options {
+        workers: Some(3),
+        stats: true,
+        ..dflt()
+    };
+    let conf = |s1: &str| WebsocatConfiguration {
+        opts: opts(),
+        s1: spec(s1).unwrap(),
+        s2: spec("mirror:").unwrap(),
+    };
+    assert!(conf("unix-l:zxc").get_concerns().contains(&WorkersWithoutTcpListener));
+    assert!(!conf("tcp-l:127.0.0.1:45970").get_concerns().contains(&WorkersWithoutTcpListener));
+    assert!(!conf("ws-l:127.0.0.1:45970").get_concerns().contains(&WorkersWithoutTcpListener));
+
+    let mut workers = websocat::workers::spawn(3, move |_| {
+        Ok(WebsocatConfiguration {
+            opts: opts(),
+            s1: spec("tcp-l:127.0.0.1:45970")?,
+            s2: spec("mirror:")?,
+        })
+    }).unwrap();
+    let mut core = Core::new().unwrap();
+    core.run(workers.all_bound()).unwrap();
+    for i in 0..30 {
+        let mut c = None;
+        for _ in 0..50 {
+            match std::net::TcpStream::connect("127.0.0.1:45970") {
+                Ok(x) => {
+                    c = Some(x);
+                    break;
+                }
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(100)),
+            }
+        }
+        let mut c = c.unwrap();
+        let msg = format!("hello {}", i);
+        c.write_all(msg.as_bytes()).unwrap();
+        let mut buf = vec![0; msg.len()];
+        c.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, msg.as_bytes());
+    }
+    websocat::shutdown::initiate("test finished");
+    workers.join().unwrap();
+    assert_eq!(websocat::metrics::summary()["sessions"].as_u64(), Some(30));
+}
+
+/// A worker that can't bind keeps readiness from being reported and is reported by `join`
+#[test]
+#[cfg(target_os = "linux")]
+fn workers_bind_failure() {
+    // Without SO_REUSEPORT, so the workers can't bind next to it
+    let _holder = std::net::TcpListener::bind("127.0.0.1:46008").unwrap();
+    let mut workers = websocat::workers::spawn(2, move |_| {
+        Ok(WebsocatConfiguration {
+            opts: Options {
+                workers: Some(3),
+                ..dflt()
+            },
+            s1: spec("tcp-l:127.0.0.1:46008")?,
+            s2: spec("mirror:")?,
+        })
+    }).unwrap();
+    let mut core = Core::new().unwrap();
+    assert!(core.run(workers.all_bound()).is_err());
+    // Recorded for the thread that started the workers as well
+    assert_eq!(websocat::bind_retry::failed(), 2);
+    assert!(core.run(websocat::bind_retry::all_bound()).is_err());
+    let e = workers.join().unwrap_err();
+    assert!(e.contains("1, 2"), "{}", e);
+
+    let mut workers = websocat::workers::spawn(1, |_| Err("no configuration".into())).unwrap();
+    assert!(core.run(workers.all_bound()).is_err());
+    assert!(workers.join().is_err());
+}
+
+/// Each `--workers` thread would have its own clients of `reuse:` and own backend health of `lb:`
+#[test]
+fn workers_shared_state() {
+    use websocat::lints::ConfigurationConcern::WorkersWithSharedState;
+    let conf = |s1: &str, s2: &str| WebsocatConfiguration {
+        opts: Options {
+            workers: Some(2),
+            ..dflt()
+        },
+        s1: spec(s1).unwrap(),
+        s2: spec(s2).unwrap(),
+    };
+    for s2 in &["reuse:mirror:", "broadcast:mirror:", "lb:tcp:127.0.0.1:1|tcp:127.0.0.1:2", "failover:tcp:127.0.0.1:1|tcp:127.0.0.1:2"] {
+        assert!(conf("tcp-l:127.0.0.1:46009", s2).get_concerns().contains(&WorkersWithSharedState), "{}", s2);
+    }
+    assert!(!conf("tcp-l:127.0.0.1:46009", "mirror:").get_concerns().contains(&WorkersWithSharedState));
+
+    let out = websocat_bin()
+        .args(&["--workers", "2", "--oneshot", "tcp-l:127.0.0.1:46009", "mirror:"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--oneshot"));
+    let out = websocat_bin()
+        .args(&["--workers", "2", "tcp-l:127.0.0.1:46009", "broadcast:mirror:"])
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("broadcast:"));
+}
+
+#[test]
+fn errors_json() {
+    use websocat::exit_code::{report, ExitCode};
+    let e = ExitCode::Dns.error("no addresses for host");
+    assert_eq!(
+        report(&*e).to_json(false),
+        r#"{"code":11,"detail":"no addresses for host","error":"dns"}"#
+    );
+
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "tcp-l:127.0.0.1:45951",
+        "literalreply:HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n",
+        nodelay,
+        noopts,
+        errignore,
+    );
+    core.handle().spawn(prog1);
+    let json = std::rc::Rc::new(std::cell::RefCell::new(None));
+    let json2 = json.clone();
+    let prog2 = wt!(
+        core,
+        "literal:hi",
+        "ws://127.0.0.1:45951/",
+        delay = 200,
+        noopts,
+        onerror = move |e: Box<std::error::Error>| *json2.borrow_mut() = Some(report(&*e).to_json(false)),
+    );
+    let _ = core.run(prog2);
+    assert_eq!(
+        json.borrow().as_ref().map(|x| &x[..]),
+        Some(r#"{"code":14,"detail":"403 Forbidden","error":"handshake_rejected","url":"ws://127.0.0.1:45951/"}"#)
+    );
+}
+
+#[test]
+fn completions() {
+    use websocat::specparse::{specifier_classes, ArgKind};
+    let classes = specifier_classes();
+    let arg = |prefix: &str| classes.iter().find(|c| c.prefixes.contains(&prefix)).map(|c| c.arg);
+    assert_eq!(arg("log:"), Some(ArgKind::Subspecifier));
+    assert_eq!(arg("readfile:"), Some(ArgKind::Path));
+    assert_eq!(arg("mirror:"), Some(ArgKind::NoArg));
+    assert_eq!(arg("tcp-l:"), Some(ArgKind::Other));
+
+    let script = websocat::completions::completion_script("bash", "", &["--header".to_string()]).unwrap();
+    assert!(script.contains("'listen-ws:'"));
+    assert!(script.contains("'--header'"));
+    let script = websocat::completions::completion_script("zsh", "_websocat \"$@\"\n", &[]).unwrap();
+    assert!(script.ends_with("_websocat_full \"$@\"\n"));
+    assert!(websocat::completions::completion_script("tcsh", "", &[]).is_err());
+}
+
+/// Specifier prefixes that must not disappear. Extend when adding specifiers.
+const SPECIFIERS_SNAPSHOT: &str = "
+ws:// ws-l: l-ws: ws-listen: listen-ws: ws-c: c-ws: ws-connect: connect-ws:
+tcp: tcp-connect: connect-tcp: tcp-c: c-tcp: tcp-listen: listen-tcp: tcp-l: l-tcp:
+udp: udp-connect: connect-udp: udp-c: c-udp: udp-listen: listen-udp: udp-l: l-udp:
+readfile: writefile: appendfile: threadedstdio:
+reuse: reuse-broadcast: broadcast-reuse: broadcast: autoreconnect: lb: failover: multilisten:
+msg2line: line2msg: lenprefix: log: jsonwrap: msgpack2json: cbor2json:
+throttle: delay: clog: chunk: unchunk: record: replay: prepend: prepend-file: append:
+seqnum: crc: crc-stream: mirror: badmirror: literalreply: clogged:
+literal: literal-hex: literal-file: random: zero: null: count:
+assert: assert2: assert-literal: assert-file:
+";
+
+#[test]
+fn list_features() {
+    use websocat::specparse::specifier_classes;
+    let prefixes: Vec<&str> = specifier_classes().iter().flat_map(|c| c.prefixes.clone()).collect();
+    for p in SPECIFIERS_SNAPSHOT.split_whitespace() {
+        assert!(prefixes.contains(&p), "specifier `{}` is gone", p);
+    }
+    #[cfg(unix)]
+    {
+        for p in &["unix:", "unix-listen:", "unix-dgram:", "abstract:", "abstract-listen:", "abstract-dgram:"] {
+            assert!(prefixes.contains(p), "specifier `{}` is gone", p);
+        }
+    }
+    #[cfg(all(unix, feature = "unix_stdio"))]
+    {
+        for p in &["open-async:", "open-fd:"] {
+            assert!(prefixes.contains(p), "specifier `{}` is gone", p);
+        }
+    }
+    assert_eq!(prefixes.contains(&"wss://"), cfg!(feature = "ssl"));
+
+    let json = websocat::capabilities::to_json(&[("--text".to_string(), "flag")]);
+    assert!(json.starts_with(r#"{"features":["#));
+    assert!(json.contains(r#"{"argument":"specifier","help":"#));
+    assert!(json.contains(r#""name":"LogClass","needs_argument":true,"prefixes":["log:"]}"#));
+    assert!(json.contains(r#""options":[{"name":"--text","type":"flag"}]"#));
+    assert!(json.contains(r#""version":""#));
+}
+
+#[cfg(unix)]
+#[test]
+fn sd_notify() {
+    use std::os::unix::net::UnixDatagram;
+    let path = std::env::temp_dir().join(format!("websocat_test_notify_{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let sock = UnixDatagram::bind(&path).unwrap();
+    std::env::set_var("NOTIFY_SOCKET", &path);
+    std::env::set_var("WATCHDOG_USEC", "3000000");
+    let watchdog = websocat::sd_notify::init();
+    assert_eq!(watchdog, Some(std::time::Duration::from_secs(3)));
+    assert!(std::env::var("NOTIFY_SOCKET").is_err());
+    websocat::sd_notify::stopping();
+    let mut buf = [0; 64];
+    let n = sock.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"STOPPING=1");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn flush_control() {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.flush", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    prepare!(core);
+    for opts in vec![
+        Options {
+            no_flush: true,
+            ..dflt()
+        },
+        Options {
+            flush_interval_ms: Some(20),
+            ..dflt()
+        },
+    ] {
+        let _ = std::fs::remove_file(&path);
+        let prog = wt!(core,
+            &format!("writefile:{}", path),
+            "literal:buffered",
+            nodelay,
+            opts = opts,
+            errpanic,
+        );
+        run!(core, prog);
+        assert_eq!(std::fs::read(&path).unwrap(), b"buffered");
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn include_headers() {
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.include_headers", std::process::id()));
+    let mut header_path = path.clone();
+    header_path.set_extension("response");
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "ws-l:127.0.0.1:45952",
+        "literal:hello",
+        nodelay,
+        noopts,
+        errignore,
+    );
+    core.handle().spawn(prog1);
+    let prog2 = wt!(
+        core,
+        &format!("writefile:{}", path.to_str().unwrap()),
+        "ws://127.0.0.1:45952/",
+        delay = 200,
+        opts = Options {
+            include_headers: true,
+            response_header_file: Some(header_path.clone()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    run!(core, prog2);
+    let output = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+    let headers = String::from_utf8(std::fs::read(&header_path).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&header_path);
+    assert!(headers.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(headers.contains("Sec-WebSocket-Accept: "));
+    assert!(headers.ends_with("\r\n\r\n"));
+    assert_eq!(output, format!("{}hello", headers));
+}
+
+#[test]
+fn builder() {
+    use websocat::builder::{Error, WebsocatBuilder};
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.builder", std::process::id()));
+    prepare!(core);
+    let server = WebsocatBuilder::new()
+        .left("ws-l:127.0.0.1:45953")
+        .right("literal:hello")
+        .on_session_error(|_| ())
+        .run(&core.handle());
+    core.handle().spawn(server.map_err(|e| panic!("{}", e)));
+
+    let t = tokio_timer::wheel().build();
+    let client = WebsocatBuilder::new()
+        .left("ws://127.0.0.1:45953/")
+        .right("assert:hello")
+        .run(&core.handle());
+    core.run(t.sleep(std::time::Duration::from_millis(200)).map_err(|_| Error::NothingToDo).and_then(|()| client)).unwrap();
+
+    let reconnecting = WebsocatBuilder::new()
+        .left("autoreconnect:ws://127.0.0.1:45953/")
+        .right(&format!("writefile:{}", path.to_str().unwrap()))
+        .unidirectional(true)
+        .run(&core.handle());
+    core.handle().spawn(reconnecting.map_err(|_| ()));
+    let _ = core.run(t.sleep(std::time::Duration::from_millis(500)));
+    let written = std::fs::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(written.starts_with(b"hellohello"));
+
+    match WebsocatBuilder::new().left("nonexistent-prefix:").right("-").build() {
+        Err(Error::Specifier(_)) => (),
+        _ => panic!(),
+    }
+    match WebsocatBuilder::new().left("-").right("-").build() {
+        Err(Error::StdinToStdout) => (),
+        _ => panic!(),
+    }
+}
+
+/// Toy specifier class from outside the crate: `greet:<name>` reads `hello, <name>`
+struct GreetClass;
+#[derive(Debug)]
+struct Greet(String);
+
+impl websocat::SpecifierClass for GreetClass {
+    fn get_name(&self) -> &'static str {
+        "GreetClass"
+    }
+    fn get_prefixes(&self) -> Vec<&'static str> {
+        vec!["greet:"]
+    }
+    fn help(&self) -> &'static str {
+        "Reads a greeting for the given name\n"
+    }
+    fn arg_kind(&self) -> websocat::specparse::ArgKind {
+        websocat::specparse::ArgKind::Other
+    }
+    fn construct(&self, _full: &str, just_arg: &str) -> Result<std::rc::Rc<websocat::Specifier>, Box<std::error::Error>> {
+        Ok(std::rc::Rc::new(Greet(just_arg.to_string())))
+    }
+}
+
+impl websocat::Specifier for Greet {
+    fn construct(&self, _p: websocat::ConstructParams) -> websocat::PeerConstructor {
+        let greeting = format!("hello, {}", self.0).into_bytes();
+        let peer = websocat::Peer::new(std::io::Cursor::new(greeting), std::io::sink());
+        websocat::once(Box::new(futures::future::ok(peer)))
+    }
+    fn is_multiconnect(&self) -> bool {
+        false
+    }
+    fn uses_global_state(&self) -> bool {
+        false
+    }
+    fn get_type(&self) -> websocat::SpecifierType {
+        websocat::SpecifierType::Other
+    }
+}
+
+#[test]
+fn external_specifier() {
+    assert!(spec("greet:world").is_err());
+    websocat::register_specifier_class(std::sync::Arc::new(GreetClass));
+    let info = websocat::specparse::specifier_classes();
+    // Other tests may register their classes in the meantime
+    let greet = info.iter().find(|x| x.name == "GreetClass").unwrap();
+    assert_eq!(greet.prefixes, vec!["greet:"]);
+    // Visible on other threads too, like the ones of --workers
+    assert!(std::thread::spawn(|| spec("greet:world").is_ok()).join().unwrap());
+    let chain = websocat::specparse::spec_chain("log:greet:world");
+    assert_eq!(chain.iter().map(|x| x.0).collect::<Vec<_>>(), vec!["LogClass", "GreetClass"]);
+    assert!(websocat::capabilities::to_json(&[]).contains(r#""prefixes":["greet:"]"#));
+
+    prepare!(core);
+    let prog = wt!(core,
+        "greet:world",
+        "assert:hello, world",
+        nodelay,
+        noopts,
+        errpanic,
+    );
+    run!(core, prog);
+}
+
+#[test]
+fn spectree() {
+    use websocat::spectree::parse;
+    let t = parse("reuse:autoreconnect:tcp-c:127.0.0.1:80").unwrap();
+    assert_eq!(t.overlay_chain(), vec!["ReuserClass", "AutoReconnectClass", "TcpConnectClass"]);
+    assert_eq!(t.levels()[2].arg, "127.0.0.1:80");
+    assert_eq!(t.to_string(), "reuse:autoreconnect:tcp:127.0.0.1:80");
+    assert!(!t.is_listener());
+
+    let t = parse("ws-l:tcp-l:127.0.0.1:8080").unwrap();
+    assert!(t.is_listener());
+    assert!(t.is_message_oriented());
+    assert!(t.consumed_options().contains(&"--text"));
+    assert!(parse("log:-").unwrap().uses_stdio());
+    assert!(parse("log:-").unwrap().consumed_options().contains(&"--log-file"));
+    assert_eq!(parse("inetd-ws:").unwrap().to_string(), "ws-l:-");
+
+    let e = parse("tcp-lisen:127.0.0.1:80").unwrap_err();
+    assert_eq!((e.position, e.suggestion), (0, Some("tcp-listen:")));
+    let e = parse("log:tcp-lisen:127.0.0.1:80").unwrap_err();
+    assert_eq!((e.position, e.suggestion), (4, Some("tcp-listen:")));
+    let e = parse("log:tcp:not-an-address").unwrap_err();
+    assert_eq!((e.position, e.suggestion), (8, None));
+
+    // Every registered prefix is recognized, and what parses prints back in a stable form
+    let args = ["", "-", "127.0.0.1:1234", "127.0.0.1:1234/", "1", "00", "x", "x:-"];
+    for class in websocat::specparse::specifier_classes() {
+        for prefix in class.prefixes {
+            for arg in &args {
+                let s = format!("{}{}", prefix, arg);
+                match parse(&s) {
+                    Ok(t) => {
+                        assert_eq!(t.class, class.name, "{}", s);
+                        let canonical = t.to_string();
+                        let again = parse(&canonical).unwrap();
+                        assert_eq!(again.to_string(), canonical, "{}", s);
+                        assert_eq!(again.overlay_chain(), t.overlay_chain(), "{}", s);
+                    }
+                    Err(e) => assert!(
+                        e.position >= prefix.len() || websocat::specparse::unsupported(&s).is_some(),
+                        "{}: {}",
+                        s,
+                        e
+                    ),
+                }
+            }
+        }
+    }
+
+    // What the classes declare is what the command line has
+    let help = websocat_bin().arg("--help").output().unwrap();
+    let help = String::from_utf8_lossy(&help.stdout);
+    for class in websocat::specparse::registered_classes() {
+        for o in class.consumed_options() {
+            assert!(help.contains(&format!("{} ", o)) || help.contains(&format!("{}\n", o)), "{}: {}", class.get_name(), o);
+        }
+    }
+    assert_eq!(parse("tcp-l:127.0.0.1:80").unwrap().consumed_options(), Vec::<&str>::new());
+}
+
+#[test]
+fn lifecycle_callbacks() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use websocat::builder::WebsocatBuilder;
+    use websocat::lifecycle::{SessionCallbacks, SessionMeta, SessionStats};
+
+    struct Recorder(RefCell<Vec<String>>);
+    impl SessionCallbacks for Recorder {
+        fn on_session_start(&self, meta: &SessionMeta) {
+            self.0.borrow_mut().push(format!("start {:?}", meta.uri));
+        }
+        fn on_handshake(&self, meta: &SessionMeta, headers: &[(String, String)]) {
+            let upgrade = headers.iter().any(|h| h.0.eq_ignore_ascii_case("Upgrade"));
+            self.0.borrow_mut().push(format!("handshake {:?} {}", meta.uri, upgrade));
+        }
+        fn on_session_end(&self, _meta: &SessionMeta, result: Result<(), &str>, stats: &SessionStats) {
+            self.0.borrow_mut().push(format!("end {:?} {}", result, stats.bytes_out));
+        }
+    }
+
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "ws-l:127.0.0.1:45954",
+        "literal:hello",
+        nodelay,
+        noopts,
+        errignore,
+    );
+    core.handle().spawn(prog1);
+    let recorder = Rc::new(Recorder(RefCell::new(vec![])));
+    let client = WebsocatBuilder::new()
+        .left("ws://127.0.0.1:45954/")
+        .right("assert:hello")
+        .callbacks(recorder.clone())
+        .run(&core.handle());
+    let t = tokio_timer::wheel().build();
+    let delay = t.sleep(std::time::Duration::from_millis(200)).map_err(|_| websocat::builder::Error::NothingToDo);
+    core.run(delay.and_then(|()| client)).unwrap();
+    let uri = Some("ws://127.0.0.1:45954/");
+    assert_eq!(
+        *recorder.0.borrow(),
+        vec![
+            format!("handshake {:?} true", uri),
+            format!("start {:?}", uri),
+            "end Ok(()) 5".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn embed() {
+    use std::io::Read;
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "ws-l:127.0.0.1:45955",
+        "literal:hello",
+        nodelay,
+        noopts,
+        errignore,
+    );
+    core.handle().spawn(prog1);
+    let t = tokio_timer::wheel().build();
+    core.run(t.sleep(std::time::Duration::from_millis(200))).unwrap();
+
+    let conn = websocat::embed::connect("ws://127.0.0.1:45955/", dflt(), &core.handle());
+    let mut conn = core.run(conn).unwrap();
+    let mut received = vec![];
+    let reading = futures::future::poll_fn(|| loop {
+        let mut buf = [0; 16];
+        match conn.read(&mut buf) {
+            Ok(0) => return Ok(futures::Async::Ready(())),
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+            Err(e) => return Err(e),
+        }
+    });
+    core.run(reading).unwrap();
+    assert_eq!(received, b"hello");
+
+    assert!(core.run(websocat::embed::connect("ws-l:127.0.0.1:45956", dflt(), &core.handle())).is_err());
+}
+
+/// Clients that fail or stall in the WebSocket handshake don't hold up the others
+#[test]
+fn embed_listen_handshake_failures() {
+    use std::io::Write;
+    prepare!(core);
+    let conns = websocat::embed::listen("ws-l:127.0.0.1:46000", dflt(), &core.handle());
+    let _stalled = std::net::TcpStream::connect("127.0.0.1:46000").unwrap();
+    let mut bad = std::net::TcpStream::connect("127.0.0.1:46000").unwrap();
+    bad.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    let t = tokio_timer::wheel().build();
+    let timeout = t.sleep(std::time::Duration::from_secs(5)).then(|_| -> Result<_, Box<std::error::Error>> { Err("timed out".into()) });
+    let client = websocat::embed::connect("ws://127.0.0.1:46000/", dflt(), &core.handle());
+    let first = conns.into_future().map_err(|(e, _)| e).join(client);
+    let ((accepted, conns), _client) = core.run(first.select(timeout).map_err(|(e, _)| e)).map(|(x, _)| x).unwrap();
+    assert!(accepted.is_some());
+
+    // And the listener keeps accepting
+    let client = websocat::embed::connect("ws://127.0.0.1:46000/", dflt(), &core.handle());
+    let ((accepted, _), _client) = core.run(conns.into_future().map_err(|(e, _)| e).join(client)).unwrap();
+    assert!(accepted.is_some());
+}
+
+#[test]
+fn cancel() {
+    use std::io::Read;
+    use websocat::builder::{Outcome, WebsocatBuilder};
+    prepare!(core);
+    let (server, canceller) = WebsocatBuilder::new()
+        .left("ws-l:127.0.0.1:45956")
+        .right("mirror:")
+        .on_session_error(|_| ())
+        .configure(|o| o.drain_timeout = 5)
+        .run_cancellable(&core.handle());
+    let outcome = std::rc::Rc::new(std::cell::Cell::new(None));
+    let outcome2 = outcome.clone();
+    core.handle().spawn(server.map(move |x| outcome2.set(Some(x))).map_err(|e| panic!("{}", e)));
+
+    let t = tokio_timer::wheel().build();
+    core.run(t.sleep(std::time::Duration::from_millis(200))).unwrap();
+    let conn = websocat::embed::connect("ws://127.0.0.1:45956/", dflt(), &core.handle());
+    let mut conn = core.run(conn).unwrap();
+    core.run(t.sleep(std::time::Duration::from_millis(100))).unwrap();
+
+    // From another thread, twice
+    let c2 = canceller.clone();
+    std::thread::spawn(move || {
+        c2.cancel_and_close();
+        c2.cancel();
+    }).join().unwrap();
+
+    // The client sees the session closed
+    let reading = futures::future::poll_fn(|| loop {
+        let mut buf = [0; 16];
+        match conn.read(&mut buf) {
+            Ok(0) => return Ok(futures::Async::Ready(())),
+            Ok(_) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+            Err(e) => return Err(e),
+        }
+    });
+    core.run(reading).unwrap();
+    core.run(t.sleep(std::time::Duration::from_millis(200))).unwrap();
+    assert_eq!(outcome.get(), Some(Outcome::Cancelled));
+
+    // Not accepting anymore
+    let conn = websocat::embed::connect("ws://127.0.0.1:45956/", dflt(), &core.handle());
+    assert!(core.run(conn).is_err());
+    canceller.cancel();
+}
+
+/// `cancel_and_close` after `cancel` closes the sessions that `cancel` left running
+#[test]
+fn cancel_twice() {
+    use std::io::{Read, Write};
+    use websocat::builder::{Outcome, WebsocatBuilder};
+    prepare!(core);
+    let (server, canceller) = WebsocatBuilder::new()
+        .left("ws-l:127.0.0.1:46001")
+        .right("mirror:")
+        .on_session_error(|_| ())
+        .configure(|o| o.drain_timeout = 5)
+        .run_cancellable(&core.handle());
+    let outcome = std::rc::Rc::new(std::cell::Cell::new(None));
+    let outcome2 = outcome.clone();
+    core.handle().spawn(server.map(move |x| outcome2.set(Some(x))).map_err(|e| panic!("{}", e)));
+
+    let t = tokio_timer::wheel().build();
+    core.run(t.sleep(std::time::Duration::from_millis(200))).unwrap();
+    let conn = websocat::embed::connect("ws://127.0.0.1:46001/", dflt(), &core.handle());
+    let mut conn = core.run(conn).unwrap();
+
+    canceller.cancel();
+    core.run(t.sleep(std::time::Duration::from_millis(100))).unwrap();
+    assert_eq!(outcome.get(), None);
+    // The session is still served
+    conn.write_all(b"ping").unwrap();
+    let mut got = vec![];
+    let echo = futures::future::poll_fn(|| loop {
+        let mut buf = [0; 16];
+        match conn.read(&mut buf) {
+            Ok(0) => panic!("closed too early"),
+            Ok(n) => {
+                got.extend_from_slice(&buf[..n]);
+                if got.len() >= 4 {
+                    return Ok(futures::Async::Ready(()));
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+            Err(e) => return Err(e),
+        }
+    });
+    core.run(echo).unwrap();
+    assert_eq!(got, b"ping");
+
+    canceller.cancel_and_close();
+    let closed = futures::future::poll_fn(|| loop {
+        let mut buf = [0; 16];
+        match conn.read(&mut buf) {
+            Ok(0) => return Ok(futures::Async::Ready(())),
+            Ok(_) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+            Err(e) => return Err(e),
+        }
+    });
+    let closed = closed.select(t.sleep(std::time::Duration::from_secs(3)).then(|_| Err(std::io::ErrorKind::TimedOut.into())));
+    core.run(closed).map_err(|(e, _)| e).unwrap();
+    core.run(t.sleep(std::time::Duration::from_millis(200))).unwrap();
+    assert_eq!(outcome.get(), Some(Outcome::Cancelled));
+}
+
+#[test]
+fn structured_errors() {
+    use websocat::error::find;
+    use websocat::exit_code::ExitCode;
+    use websocat::WebsocatError;
+
+    let e = spec("nonexistent-prefix:").unwrap_err();
+    assert_eq!(e.to_string(), "Wrong specifier");
+    match find(&*e) {
+        Some(&WebsocatError::Specifier(_)) => (),
+        x => panic!("{:?}", x),
+    }
+    let e = spec("log:tcp:notanaddress").unwrap_err();
+    assert_eq!(e.to_string(), "notanaddress".parse::<std::net::SocketAddr>().unwrap_err().to_string());
+    assert_eq!(find(&*e).map(|x| x.exit_code()), Some(ExitCode::Other));
+
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "tcp-l:127.0.0.1:45957",
+        "literalreply:HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        nodelay,
+        noopts,
+        errignore,
+    );
+    core.handle().spawn(prog1);
+    let mut failures = vec![];
+    for right in &["ws://127.0.0.1:45957/", "ws://127.0.0.1:1/", "tcp:127.0.0.1:1"] {
+        let got = std::rc::Rc::new(std::cell::RefCell::new(None));
+        let got2 = got.clone();
+        let prog2 = wt!(
+            core,
+            "literal:hi",
+            right,
+            delay = 200,
+            noopts,
+            onerror = move |e: Box<std::error::Error>| {
+                let s = match find(&*e) {
+                    Some(&WebsocatError::Handshake { ref url, status, .. }) => format!("handshake {} {:?}", url, status),
+                    Some(&WebsocatError::Connect { ref addr, ref source }) => format!("connect {} {:?}", addr, source.kind()),
+                    x => format!("{:?}", x),
+                };
+                *got2.borrow_mut() = Some((s, e.to_string()));
+            },
+        );
+        let _ = core.run(prog2);
+        failures.push(got.borrow_mut().take().unwrap());
+    }
+    assert_eq!(failures[0].0, "handshake ws://127.0.0.1:45957/ Some(404)");
+    assert_eq!(failures[1].0, "connect ws://127.0.0.1:1/ ConnectionRefused");
+    assert_eq!(failures[2].0, "connect 127.0.0.1:1 ConnectionRefused");
+    assert!(failures[2].1.starts_with("Connection refused"));
+
+    // Boxed I/O and WebSocket library errors come out typed, with the same messages
+    let io = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+    let msg = io.to_string();
+    let e = websocat::box_up_err(io);
+    assert_eq!(e.to_string(), msg);
+    match find(&*e) {
+        Some(&WebsocatError::Io { ref source, .. }) => assert_eq!(source.kind(), std::io::ErrorKind::BrokenPipe),
+        x => panic!("{:?}", x),
+    }
+    assert_eq!(find(&*e).unwrap().context(), None);
+    let msg = websocket::WebSocketError::ProtocolError("bad frame").to_string();
+    let e = websocat::box_up_err(websocket::WebSocketError::ProtocolError("bad frame"));
+    assert_eq!(e.to_string(), msg);
+    assert_eq!(find(&*e).map(|x| x.exit_code()), Some(ExitCode::AbnormalClose));
+    let e = websocat::box_up_err(websocat::error::io_context("tcp:127.0.0.1:1", std::io::ErrorKind::BrokenPipe.into()));
+    assert_eq!(find(&*e).unwrap().context(), Some("tcp:127.0.0.1:1"));
+    // I/O errors with messages of their own are left alone
+    let e = websocat::box_up_err(websocat::simple_err("custom".to_string()));
+    assert!(find(&*e).is_none());
+    assert_eq!(e.to_string(), "custom");
+}
+
+#[test]
+fn middleware() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use websocat::builder::{Error, WebsocatBuilder};
+    use websocat::middleware::{Message, MiddlewareAction};
+
+    prepare!(core);
+    let server = WebsocatBuilder::new()
+        .left("ws-l:127.0.0.1:45958")
+        .right("literal:hello")
+        .on_session_error(|_| ())
+        .run(&core.handle());
+    core.handle().spawn(server.map_err(|e| panic!("{}", e)));
+
+    let seen = Rc::new(RefCell::new(vec![]));
+    let seen2 = seen.clone();
+    let t = tokio_timer::wheel().build();
+    // Data from the left specifier goes to the right one, so it is outgoing
+    let client = WebsocatBuilder::new()
+        .left("ws://127.0.0.1:45958/")
+        .right("assert:HELLO")
+        .map_outgoing(move |m| {
+            seen2.borrow_mut().push(m.clone());
+            match m {
+                Message::Data(x) => MiddlewareAction::Replace(x.to_ascii_uppercase()),
+                Message::CloseReceived(_) => MiddlewareAction::Drop,
+            }
+        })
+        .map_outgoing(|m| match m {
+            Message::Data(ref x) if x.is_empty() => MiddlewareAction::Drop,
+            Message::Data(x) => MiddlewareAction::Pass(x),
+            Message::CloseReceived(_) => MiddlewareAction::Drop,
+        })
+        .run(&core.handle());
+    core.run(t.sleep(std::time::Duration::from_millis(200)).map_err(|_| Error::NothingToDo).and_then(|()| client)).unwrap();
+    let seen = seen.borrow();
+    assert_eq!(seen[0], Message::Data(b"hello".to_vec()));
+    assert!(seen[1..].iter().any(|m| match *m {
+        Message::CloseReceived(_) => true,
+        _ => false,
+    }));
+}
+
+/// The minimal build is `cargo test --no-default-features`, optionally with `--features seqpacket`:
+/// no TLS, regexes or subprocesses, but WebSocket over TCP and UNIX sockets and all overlays.
+#[cfg(not(feature = "ssl"))]
+#[test]
+fn minimal_build() {
+    for s in &["wss://127.0.0.1/", "autoreconnect:wss://127.0.0.1/"] {
+        let e = spec(s).unwrap_err();
+        assert!(e.to_string().starts_with("SSL is not compiled in"), "{}", e);
+    }
+    // Made by a library user, bypassing the specifier parser
+    prepare!(core);
+    let s1 = spec("literal:hi").unwrap();
+    let s2 = std::rc::Rc::new(websocat::ws_client_peer::WsClient("wss://127.0.0.1:1/".parse().unwrap(), None, None));
+    let failed = std::rc::Rc::new(std::cell::Cell::new(false));
+    let failed2 = failed.clone();
+    let prog = WebsocatConfiguration {
+        opts: dflt(),
+        s1,
+        s2,
+    }.serve(
+        core.handle(),
+        std::rc::Rc::new(move |e: Box<std::error::Error>| {
+            assert!(e.to_string().starts_with("SSL is not compiled in"));
+            failed2.set(true)
+        }),
+    );
+    let _ = core.run(prog);
+    assert!(failed.get());
+}
+
+/// Send `chunks` megabytes through `tcp-l:127.0.0.1:<port>` to `tcp:127.0.0.1:<port + 1>`.
+/// Returns bytes that went through `splice(2)` and the time it took, in seconds.
+fn forward_megabytes(port: u16, chunks: u64, no_splice: bool) -> (u64, f64) {
+    use futures::Stream;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Instant;
+    use tokio_core::net::{TcpListener, TcpStream};
+
+    struct Counter(Rc<Cell<u64>>);
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.set(self.0.get() + buf.len() as u64);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl tokio_io::AsyncWrite for Counter {
+        fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+            Ok(().into())
+        }
+    }
+
+    static CHUNK: [u8; 1 << 20] = [0; 1 << 20];
+
+    prepare!(core);
+    let h = core.handle();
+    let proxy = format!("127.0.0.1:{}", port);
+    let sink = format!("127.0.0.1:{}", port + 1);
+    let listener = TcpListener::bind(&sink.parse().unwrap(), &h).unwrap();
+    let prog1 = wt!(
+        core,
+        &format!("tcp-l:{}", proxy),
+        &format!("tcp:{}", sink),
+        nodelay,
+        opts = Options {
+            no_splice,
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+
+    let count = Rc::new(Cell::new(0));
+    let count2 = count.clone();
+    let received = listener
+        .incoming()
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(c, _)| tokio_io::io::copy(c.unwrap().0, Counter(count2)));
+    let sent = TcpStream::connect(&proxy.parse().unwrap(), &h)
+        .and_then(move |c| {
+            futures::stream::iter_ok::<_, std::io::Error>(0..chunks)
+                .fold(c, |c, _| tokio_io::io::write_all(c, &CHUNK[..]).map(|(c, _)| c))
+        })
+        .and_then(|c| c.shutdown(std::net::Shutdown::Write).map(|()| c));
+
+    let spliced_before = websocat::splice::zero_copy_bytes();
+    let start = Instant::now();
+    let (_, copied) = core.run(sent.join(received)).unwrap();
+    let took = start.elapsed();
+    assert_eq!(copied.0, chunks << 20);
+    assert_eq!(count.get(), chunks << 20);
+    let secs = took.as_secs() as f64 + f64::from(took.subsec_nanos()) * 1e-9;
+    (websocat::splice::zero_copy_bytes() - spliced_before, secs)
+}
+
+/// Plain TCP forwarding goes through `splice(2)` on Linux, unless `--no-splice` is given
+#[test]
+fn splice_forwarding() {
+    let (spliced, _) = forward_megabytes(45959, 8, false);
+    if cfg!(all(target_os = "linux", feature = "libc")) {
+        // The first read is done in userspace
+        assert!(spliced > (7 << 20), "{}", spliced);
+    } else {
+        assert_eq!(spliced, 0);
+    }
+    let (spliced, _) = forward_megabytes(45961, 8, true);
+    assert_eq!(spliced, 0);
+}
+
+/// A few hundred megabytes, spliced and then copied. Splicing should not be slower.
+/// Run with `cargo test --release splice_throughput -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn splice_throughput() {
+    const CHUNKS: u64 = 256;
+    let (_, spliced) = forward_megabytes(46002, CHUNKS, false);
+    let (_, copied) = forward_megabytes(46004, CHUNKS, true);
+    println!("spliced: {:.0} MB/s", CHUNKS as f64 / spliced);
+    println!("copied: {:.0} MB/s", CHUNKS as f64 / copied);
+    if cfg!(all(target_os = "linux", feature = "libc")) {
+        assert!(spliced < copied * 1.2, "spliced {:.3}s, copied {:.3}s", spliced, copied);
+    }
+}
+
+/// A mostly sparse file sent by `readfile:` to `tcp:` arrives intact,
+/// with and without `--file-start-offset` and `--file-max-bytes`
+#[test]
+fn sendfile_checksum() {
+    use futures::Stream;
+    use std::cell::Cell;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::rc::Rc;
+    use tokio_core::net::TcpListener;
+
+    /// FNV-1a of everything written
+    struct Hasher(Rc<Cell<(u64, u64)>>);
+    impl std::io::Write for Hasher {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let (mut h, n) = self.0.get();
+            for &b in buf {
+                h = (h ^ u64::from(b)).wrapping_mul(0x100000001b3);
+            }
+            self.0.set((h, n + buf.len() as u64));
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl tokio_io::AsyncWrite for Hasher {
+        fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+            Ok(().into())
+        }
+    }
+    const FNV_INIT: u64 = 0xcbf29ce484222325;
+
+    const LEN: u64 = 24 << 20;
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.sendfile", std::process::id()));
+    {
+        let mut f = std::fs::File::create(&path).unwrap();
+        f.set_len(LEN).unwrap();
+        let mut x = 12345u32;
+        for &at in &[0, 4095, 5_000_000, 10 << 20, LEN - 70000] {
+            let chunk: Vec<u8> = (0..65536)
+                .map(|_| {
+                    x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                    (x >> 16) as u8
+                })
+                .collect();
+            f.seek(SeekFrom::Start(at)).unwrap();
+            f.write_all(&chunk).unwrap();
+        }
+    }
+    let content = std::fs::read(&path).unwrap();
+
+    let cases = [
+        (None, None, 0, LEN),
+        (Some(4000), Some(20_000_000), 4000, 20_000_000),
+        (Some(-100_000), None, LEN - 100_000, LEN),
+    ];
+    for (i, &(file_start_offset, file_max_bytes, from, to)) in cases.iter().enumerate() {
+        let mut expected = Hasher(Rc::new(Cell::new((FNV_INIT, 0))));
+        expected.write_all(&content[from as usize..to as usize]).unwrap();
+
+        prepare!(core);
+        let addr = format!("127.0.0.1:{}", 45966 + i);
+        let listener = TcpListener::bind(&addr.parse().unwrap(), &core.handle()).unwrap();
+        let prog1 = wt!(
+            core,
+            &format!("readfile:{}", path.to_str().unwrap()),
+            &format!("tcp:{}", addr),
+            nodelay,
+            opts = Options {
+                file_start_offset,
+                file_max_bytes,
+                ..dflt()
+            },
+            errpanic,
+        );
+
+        let got = Rc::new(Cell::new((FNV_INIT, 0)));
+        let got2 = got.clone();
+        let received = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(c, _)| tokio_io::io::copy(c.unwrap().0, Hasher(got2)))
+            .map_err(|e| panic!("{}", e));
+        core.run(prog1.join(received)).unwrap();
+        assert_eq!(got.get(), expected.0.get());
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+/// Small client messages over a unix socket.
+/// Run with `cargo test --release ws_unix_throughput -- --ignored --nocapture`.
+#[test]
+#[ignore]
+#[cfg(unix)]
+fn ws_unix_throughput() {
+    use std::time::{Duration, Instant};
+
+    const MESSAGES: usize = 20000;
+    const SIZE: usize = 64;
+
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.wsbench", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "ws-l:unix-l:wsbench",
+        &format!("writefile:{}", path),
+        nodelay,
+        opts = Options {
+            unlink_unix_socket: true,
+            oneshot: true,
+            ..dflt()
+        },
+        errignore,
+    );
+    let prog2 = wt!(
+        core,
+        &format!("random:{}", MESSAGES * SIZE),
+        "ws-c:unix-c:wsbench",
+        delay = 200,
+        opts = Options {
+            random_seed: Some(7),
+            gen_message_size: Some(SIZE),
+            ws_c_uri: "ws://localhost/".to_string(),
+            unidirectional: true,
+            ..dflt()
+        },
+        errpanic,
+    );
+
+    let start = Instant::now();
+    let prog = prog1.join(prog2);
+    run!(core, prog);
+    let took = start.elapsed() - Duration::from_millis(200);
+    assert_eq!(std::fs::read(&path).unwrap().len(), MESSAGES * SIZE);
+    let secs = took.as_secs() as f64 + f64::from(took.subsec_nanos()) * 1e-9;
+    let rate = MESSAGES as f64 / secs;
+    println!("{}-byte messages: {:.0}/s", SIZE, rate);
+    // A loose bound, so that only a gross slowdown fails
+    assert!(rate > 10000.0, "{:.0} messages per second", rate);
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file("wsbench");
+}
+
+#[test]
+fn bufpool_no_aliasing() {
+    use websocat::bufpool::BufPool;
+
+    let pool = BufPool::new();
+    let mut a = pool.take(16);
+    a.extend_from_slice(b"in flight");
+    let a_ptr = a.as_ptr();
+    let mut b = pool.take(16);
+    b.extend_from_slice(b"another");
+    assert_ne!(b.as_ptr(), a_ptr);
+    assert_eq!(&a[..], b"in flight");
+    drop(a);
+
+    // Reused only after being dropped, and empty
+    let c = pool.take(4);
+    assert_eq!(c.as_ptr(), a_ptr);
+    assert!(c.is_empty());
+    assert_eq!(&b[..], b"another");
+
+    // Taken out of the pool, not given back on drop
+    let d = b.into_vec();
+    let s = pool.stats();
+    assert_eq!((s.taken, s.reused, s.returned, s.discarded), (3, 1, 1, 0));
+    drop(d);
+    drop(c);
+    assert_eq!(pool.stats().returned, 2);
+}
+
+#[test]
+fn bufpool_limits() {
+    use websocat::bufpool::{BufPool, MAX_BUFFERS, MAX_CAPACITY};
+
+    let pool = BufPool::new();
+    let mut large = pool.take(16);
+    large.reserve(MAX_CAPACITY + 1);
+    drop(large);
+    assert_eq!(pool.stats().discarded, 1);
+    assert_eq!(pool.stats().returned, 0);
+
+    let bufs: Vec<_> = (0..MAX_BUFFERS + 2).map(|_| pool.take(16)).collect();
+    drop(bufs);
+    let s = pool.stats();
+    assert_eq!(s.returned, MAX_BUFFERS as u64);
+    assert_eq!(s.discarded, 3);
+}
+
+#[test]
+fn bufpool_line_roundtrip() {
+    // Many messages through pooled buffers must come out intact
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("websocat_test_{}", std::process::id()));
+    let dir = dir.to_str().unwrap().to_string();
+    let mut files = vec![];
+    for (i, stack) in ["", "line2msg:msg2line:"].iter().enumerate() {
+        let path = format!("{}_{}.pool", dir, i);
+        prepare!(core);
+        let prog = wt!(core,
+            &format!("{}random:300000", stack),
+            &format!("writefile:{}", path),
+            nodelay,
+            opts = Options {
+                random_seed: Some(5),
+                gen_message_size: Some(1000),
+                line_escape: Some("backslash".to_string()),
+                ..dflt()
+            },
+            errpanic,
+        );
+        run!(core, prog);
+        files.push(std::fs::read(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+    assert_eq!(files[0].len(), 300000);
+    assert!(files[0] == files[1]);
+}
+
+#[test]
+fn high_watermark_bounded() {
+    // Writes stall for a second; reading ahead must stop at the watermark
+    const TOTAL: usize = 4_000_000;
+    const HIGH: usize = 100_000;
+    const BUFFER: usize = 4096;
+    for &msg in [None, Some(1000)].iter() {
+        let before = websocat::metrics::summary()["bytes_out"].as_u64().unwrap();
+        prepare!(core);
+        let prog = wt!(core,
+            &format!("random:{}", TOTAL),
+            "clog:null:",
+            nodelay,
+            opts = Options {
+                gen_message_size: msg,
+                clog_direction: "write".to_string(),
+                clog_duration: Some(1),
+                buffer_size: Some(BUFFER),
+                high_watermark: Some(HIGH),
+                stats: true,
+                ..dflt()
+            },
+            errpanic,
+        );
+        run!(core, prog);
+        let v = websocat::metrics::summary();
+        let peak = v["queued_bytes_peak"].as_u64().unwrap() as usize;
+        assert!(peak > HIGH);
+        assert!(peak <= HIGH + BUFFER);
+        assert_eq!((v["bytes_out"].as_u64().unwrap() - before) as usize, TOTAL);
+    }
+}
+
+/// Message source for driving a peer by hand
+struct Messages(std::collections::VecDeque<Vec<u8>>);
+impl std::io::Read for Messages {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.pop_front() {
+            Some(m) => {
+                assert!(m.len() <= buf.len());
+                buf[..m.len()].copy_from_slice(&m);
+                Ok(m.len())
+            }
+            None => Ok(0),
+        }
+    }
+}
+impl tokio_io::AsyncRead for Messages {}
+impl std::io::Write for Messages {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl tokio_io::AsyncWrite for Messages {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        Ok(().into())
+    }
+}
+
+/// `testmsgs:<base64>,<base64>,...,` reads these messages, one per read
+struct TestMsgsClass;
+#[derive(Debug)]
+struct TestMsgs(Vec<Vec<u8>>);
+
+impl websocat::SpecifierClass for TestMsgsClass {
+    fn get_name(&self) -> &'static str {
+        "TestMsgsClass"
+    }
+    fn get_prefixes(&self) -> Vec<&'static str> {
+        vec!["testmsgs:"]
+    }
+    fn help(&self) -> &'static str {
+        "Reads the given messages\n"
+    }
+    fn arg_kind(&self) -> websocat::specparse::ArgKind {
+        websocat::specparse::ArgKind::Other
+    }
+    fn construct(&self, _full: &str, just_arg: &str) -> Result<std::rc::Rc<websocat::Specifier>, Box<std::error::Error>> {
+        let mut msgs = vec![];
+        let mut parts: Vec<&str> = just_arg.split(',').collect();
+        parts.pop();
+        for x in parts {
+            msgs.push(base64::decode(x)?);
+        }
+        Ok(std::rc::Rc::new(TestMsgs(msgs)))
+    }
+}
+
+impl websocat::Specifier for TestMsgs {
+    fn construct(&self, _p: websocat::ConstructParams) -> websocat::PeerConstructor {
+        let peer = websocat::Peer::new(
+            Messages(self.0.iter().cloned().collect()),
+            Messages(Default::default()),
+        );
+        websocat::once(Box::new(futures::future::ok(peer)))
+    }
+    fn is_multiconnect(&self) -> bool {
+        false
+    }
+    fn uses_global_state(&self) -> bool {
+        false
+    }
+    fn get_type(&self) -> websocat::SpecifierType {
+        websocat::SpecifierType::Other
+    }
+}
+
+/// Output of `msg2line:` reading with `bufsize` buffer, and whether it failed
+fn msg2line_output(opts: &Options, msgs: &[Vec<u8>], bufsize: usize) -> (Vec<u8>, bool) {
+    use std::io::Read;
+    static REGISTER: std::sync::Once = std::sync::ONCE_INIT;
+    REGISTER.call_once(|| websocat::register_specifier_class(std::sync::Arc::new(TestMsgsClass)));
+    let arg: String = msgs.iter().map(|m| format!("{},", base64::encode(m))).collect();
+    prepare!(core);
+    let conn = websocat::embed::connect(&format!("msg2line:testmsgs:{}", arg), opts.clone(), &core.handle());
+    let mut conn = core.run(conn).unwrap();
+    let mut out = vec![];
+    let mut buf = vec![0; bufsize];
+    loop {
+        match conn.read(&mut buf) {
+            Ok(0) => return (out, false),
+            Ok(n) => out.extend_from_slice(&buf[..n]),
+            Err(_) => return (out, true),
+        }
+    }
+}
+
+fn find_bytes(h: &[u8], n: &[u8]) -> Option<usize> {
+    h.windows(n.len()).position(|w| w == n)
+}
+
+/// What `msg2line:` made of one message before it started emitting lines in place.
+/// `None` is an error.
+fn msg2line_reference(msg: &[u8], bd: &[u8], mode: &str) -> Option<Vec<u8>> {
+    let newline = bd == b"\n";
+    let special = |c: u8| c == bd[0] || (newline && c == b'\r');
+    let chomp = |m: &[u8]| -> Vec<u8> {
+        let mut m = m;
+        if m.ends_with(bd) {
+            m = &m[..(m.len() - bd.len())];
+        }
+        if newline && m.ends_with(b"\r") {
+            m = &m[..(m.len() - 1)];
+        }
+        m.to_vec()
+    };
+    let mut v;
+    match mode {
+        "space" => {
+            v = chomp(msg);
+            if newline {
+                for c in &mut v {
+                    if *c == b'\n' || *c == b'\r' {
+                        *c = b' ';
+                    }
+                }
+            } else {
+                let mut i = 0;
+                while let Some(j) = find_bytes(&v[i..], bd) {
+                    for c in &mut v[(i + j)..(i + j + bd.len())] {
+                        *c = b' ';
+                    }
+                    i += j + bd.len();
+                }
+            }
+        }
+        "error" => {
+            v = chomp(msg);
+            let n = v.len();
+            v.extend_from_slice(bd);
+            return if find_bytes(&v, bd) == Some(n) { Some(v) } else { None };
+        }
+        "pass" => v = chomp(msg),
+        "strip" => {
+            let m = chomp(msg);
+            v = vec![];
+            let mut i = 0;
+            while i < m.len() {
+                if m[i..].starts_with(bd) {
+                    i += bd.len();
+                } else if newline && m[i] == b'\r' {
+                    i += 1;
+                } else {
+                    v.push(m[i]);
+                    i += 1;
+                }
+            }
+        }
+        "escape" => {
+            v = vec![];
+            for &c in msg {
+                if c == b'\\' {
+                    v.extend_from_slice(b"\\\\");
+                } else if special(c) {
+                    let e = match c {
+                        b'\n' => "\\n".to_string(),
+                        b'\r' => "\\r".to_string(),
+                        b'\t' => "\\t".to_string(),
+                        0 => "\\0".to_string(),
+                        _ => format!("\\x{:02x}", c),
+                    };
+                    v.extend_from_slice(e.as_bytes());
+                } else {
+                    v.push(c);
+                }
+            }
+        }
+        "base64" => {
+            let m = chomp(msg);
+            v = if find_bytes(&m, bd).is_some() || m.iter().any(|&c| special(c)) {
+                base64::encode(&m).into_bytes()
+            } else {
+                m
+            };
+        }
+        _ => unreachable!(),
+    }
+    v.extend_from_slice(bd);
+    Some(v)
+}
+
+#[test]
+fn msg2line_exhaustive() {
+    // All messages up to 4 bytes long made of bytes that matter to some separator
+    let alphabet = [b'a', b'b', b'\n', b'\r', b'\\', b';', 0, 0xff];
+    let mut msgs: Vec<Vec<u8>> = alphabet.iter().map(|&c| vec![c]).collect();
+    let mut prev = msgs.clone();
+    for _ in 1..4 {
+        let mut next = vec![];
+        for m in &prev {
+            for &c in &alphabet {
+                let mut x = m.clone();
+                x.push(c);
+                next.push(x);
+            }
+        }
+        msgs.extend(next.iter().cloned());
+        prev = next;
+    }
+
+    // (--separator, --separator-n, boundary)
+    let separators: [(&str, usize, &[u8]); 6] = [
+        ("", 1, b"\n"),
+        (";", 1, b";"),
+        (";", 2, b";;"),
+        ("\\r\\n", 1, b"\r\n"),
+        ("ab", 1, b"ab"),
+        ("\\0", 1, b"\0"),
+    ];
+    let modes = ["space", "error", "escape", "pass", "strip", "base64"];
+    for &(sep, n, bd) in &separators {
+        for &mode in &modes {
+            let opts = Options {
+                separator: sep.to_string(),
+                separator_n: n,
+                separator_conflict: mode.to_string(),
+                ..dflt()
+            };
+            // Lines longer than the buffer of 7 get continued in subsequent reads
+            for &bufsize in &[7, 4096] {
+                if mode == "error" {
+                    for m in &msgs {
+                        let (out, failed) = msg2line_output(&opts, &[m.clone()], bufsize);
+                        match msg2line_reference(m, bd, mode) {
+                            Some(x) => assert!(!failed && out == x, "{:?} {:?} {:?}", sep, mode, m),
+                            None => assert!(failed, "{:?} {:?} {:?}", sep, mode, m),
+                        }
+                    }
+                    continue;
+                }
+                let expected: Vec<u8> = msgs.iter()
+                    .flat_map(|m| msg2line_reference(m, bd, mode).unwrap())
+                    .collect();
+                let (out, failed) = msg2line_output(&opts, &msgs, bufsize);
+                assert!(!failed);
+                assert!(out == expected, "{:?} {:?} {}", sep, mode, bufsize);
+            }
+        }
+    }
+}
+
+#[test]
+fn cork_small_frames() {
+    // Tiny messages must arrive intact and in order whether corked or not
+    let mut files = vec![];
+    let mut writes = vec![];
+    for (i, &window) in [None, Some(0), Some(5000)].iter().enumerate() {
+        let writes_before = websocat::metrics::summary()["ws_writes"].as_u64().unwrap_or(0);
+        let mut path = std::env::temp_dir();
+        path.push(format!("websocat_test_{}_{}.cork", std::process::id(), i));
+        let path = path.to_str().unwrap().to_string();
+        let addr = format!("127.0.0.1:{}", 45963 + i);
+        prepare!(core);
+        let prog1 = wt!(core,
+            &format!("ws-l:tcp-l:{}", addr),
+            &format!("writefile:{}", path),
+            nodelay,
+            opts = Options {
+                oneshot: true,
+                ..dflt()
+            },
+            errignore,
+        );
+        let prog2 = wt!(core,
+            "random:64000",
+            &format!("ws-c:tcp:{}", addr),
+            delay = 200,
+            opts = Options {
+                random_seed: Some(9),
+                gen_message_size: Some(16),
+                ws_c_uri: "ws://localhost/".to_string(),
+                unidirectional: true,
+                cork_window_us: window,
+                ..dflt()
+            },
+            errpanic,
+        );
+        run!(core, prog1.join(prog2));
+        files.push(std::fs::read(&path).unwrap());
+        writes.push(websocat::metrics::summary()["ws_writes"].as_u64().unwrap() - writes_before);
+        let _ = std::fs::remove_file(&path);
+    }
+    assert_eq!(files[0].len(), 64000);
+    assert!(files[0] == files[1]);
+    assert!(files[0] == files[2]);
+    // 4000 messages from a source that is always ready: corked, they go out in batches
+    // of up to 16 KiB or when the window passes; with --cork-window-us 0, one write each
+    assert!(writes[0] < 1000, "{:?}", writes);
+    assert!(writes[1] >= 4000, "{:?}", writes);
+    assert!(writes[2] < 100, "{:?}", writes);
+}
+
+/// A lone message is written out when the session waits for more input,
+/// not when the cork window passes
+#[test]
+fn cork_single_message() {
+    use std::time::{Duration, Instant};
+    use tokio_core::net::TcpStream;
+
+    prepare!(core);
+    let opts = || Options {
+        cork_window_us: Some(3_000_000),
+        ..dflt()
+    };
+    let server = wt!(core, "ws-l:tcp-l:127.0.0.1:46006", "mirror:", nodelay, opts = opts(), errignore,);
+    let client = wt!(core,
+        "tcp-l:127.0.0.1:46007",
+        "ws-c:tcp:127.0.0.1:46006",
+        nodelay,
+        opts = Options {
+            ws_c_uri: "ws://localhost/".to_string(),
+            ..opts()
+        },
+        errignore,
+    );
+    core.handle().spawn(server);
+    core.handle().spawn(client);
+
+    let t = tokio_timer::wheel().build();
+    let start = Instant::now();
+    let echo = TcpStream::connect(&"127.0.0.1:46007".parse().unwrap(), &core.handle())
+        .and_then(|c| tokio_io::io::write_all(c, b"hello"))
+        .and_then(|(c, _)| tokio_io::io::read_exact(c, [0; 5]));
+    let timeout = t.sleep(Duration::from_secs(2)).then(|_| Err(std::io::ErrorKind::TimedOut.into()));
+    let ((_conn, got), _) = core.run(echo.select(timeout)).map_err(|(e, _)| e).unwrap();
+    assert_eq!(&got, b"hello");
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+/// `autoreconnect:` with `--preconnect` switches to a spare connection established while
+/// the first one was in use, delivering what the spare received in the meantime
+#[test]
+fn preconnect_spare_takes_over() {
+    use futures::Stream;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Timeout;
+
+    /// Collects everything written
+    struct Collect(Rc<RefCell<Vec<u8>>>);
+    impl std::io::Write for Collect {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl tokio_io::AsyncWrite for Collect {
+        fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+            Ok(().into())
+        }
+    }
+
+    prepare!(core);
+    let h = core.handle();
+    let promotions = || websocat::metrics::summary()["preconnect_promotions"].as_u64().unwrap_or(0);
+    let promotions_before = promotions();
+
+    // The first connection goes away after a while, the ones after it stay
+    let server = TcpListener::bind(&"127.0.0.1:45971".parse().unwrap(), &h).unwrap();
+    let accepted = Rc::new(Cell::new(0));
+    let held = Rc::new(RefCell::new(vec![]));
+    let (accepted2, h2) = (accepted.clone(), h.clone());
+    let serve = server.incoming().for_each(move |(c, _)| {
+        let k = accepted2.get();
+        accepted2.set(k + 1);
+        let held = held.clone();
+        let f: Box<Future<Item = (), Error = std::io::Error>> = match k {
+            0 => {
+                let linger = Timeout::new(Duration::from_millis(300), &h2).unwrap();
+                Box::new(tokio_io::io::write_all(c, &b"first\n"[..]).and_then(|(c, _)| linger.map(move |()| drop(c))))
+            }
+            1 => Box::new(
+                tokio_io::io::write_all(c, &b"second\n"[..]).map(move |(c, _)| held.borrow_mut().push(c)),
+            ),
+            _ => {
+                held.borrow_mut().push(c);
+                return Ok(());
+            }
+        };
+        h2.spawn(f.map_err(|e| panic!("{}", e)));
+        Ok(())
+    });
+    h.spawn(serve.map_err(|e| panic!("{}", e)));
+
+    let sink = TcpListener::bind(&"127.0.0.1:45972".parse().unwrap(), &h).unwrap();
+    let got = Rc::new(RefCell::new(vec![]));
+    let got2 = got.clone();
+    let received = sink.incoming()
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(c, _)| tokio_io::io::copy(c.unwrap().0, Collect(got2)))
+        .map(|_| ());
+    h.spawn(received.map_err(|e| panic!("{}", e)));
+
+    let prog1 = wt!(
+        core,
+        "autoreconnect:tcp:127.0.0.1:45971",
+        "tcp:127.0.0.1:45972",
+        nodelay,
+        opts = Options {
+            preconnect: 1,
+            ..dflt()
+        },
+        errpanic,
+    );
+    h.spawn(prog1);
+
+    core.run(Timeout::new(Duration::from_millis(1500), &h).unwrap()).unwrap();
+    assert_eq!(&got.borrow()[..], &b"first\nsecond\n"[..]);
+    assert_eq!(promotions() - promotions_before, 1);
+    // The first connection, the spare that took over and a new spare
+    assert_eq!(accepted.get(), 3);
+}
+
+/// Spares older than `--preconnect-max-age` are replaced rather than taken over,
+/// as the other side may have dropped them without closing
+#[test]
+fn preconnect_spare_max_age() {
+    use futures::Stream;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor::Timeout;
+
+    prepare!(core);
+    let h = core.handle();
+    let counter = |name: &str| websocat::metrics::summary()[name].as_u64().unwrap_or(0);
+    let expirations_before = counter("preconnect_spare_expirations");
+    let promotions_before = counter("preconnect_promotions");
+
+    // The first connection goes away after the spares have expired twice, the others stay silent
+    let server = TcpListener::bind(&"127.0.0.1:46010".parse().unwrap(), &h).unwrap();
+    let accepted = Rc::new(Cell::new(0));
+    let held = Rc::new(RefCell::new(vec![]));
+    let (accepted2, h2) = (accepted.clone(), h.clone());
+    let serve = server.incoming().for_each(move |(c, _)| {
+        accepted2.set(accepted2.get() + 1);
+        if accepted2.get() == 1 {
+            let linger = Timeout::new(Duration::from_millis(2500), &h2).unwrap();
+            h2.spawn(linger.map(move |()| drop(c)).map_err(|e| panic!("{}", e)));
+        } else {
+            held.borrow_mut().push(c);
+        }
+        Ok(())
+    });
+    h.spawn(serve.map_err(|e| panic!("{}", e)));
+
+    let prog1 = wt!(
+        core,
+        "autoreconnect:tcp:127.0.0.1:46010",
+        "mirror:",
+        nodelay,
+        opts = Options {
+            preconnect: 1,
+            preconnect_max_age: 1,
+            ..dflt()
+        },
+        errpanic,
+    );
+    h.spawn(prog1);
+
+    core.run(Timeout::new(Duration::from_millis(3000), &h).unwrap()).unwrap();
+    assert!(counter("preconnect_spare_expirations") - expirations_before >= 2);
+    assert_eq!(counter("preconnect_promotions") - promotions_before, 1);
+    // The first connection, at least three spares and the replacement of the one that took over
+    assert!(accepted.get() >= 5, "{}", accepted.get());
+}
+
+/// Thousands of connections to `tcp-l:` that end right away.
+/// Run with `--nocapture` to compare the session setup rate.
+#[test]
+fn accept_close_rate() {
+    use futures::Stream;
+    use std::time::Instant;
+    use tokio_core::net::TcpStream;
+
+    const CONNECTIONS: u64 = 5000;
+
+    prepare!(core);
+    let h = core.handle();
+    let sessions = || websocat::metrics::summary()["sessions"].as_u64().unwrap_or(0);
+    let sessions_before = sessions();
+    let prog1 = wt!(core, "tcp-l:127.0.0.1:45973", "mirror:", nodelay, noopts, errpanic,);
+    h.spawn(prog1);
+
+    let addr = "127.0.0.1:45973".parse().unwrap();
+    let h2 = h.clone();
+    let clients = futures::stream::iter_ok::<_, std::io::Error>(0..CONNECTIONS)
+        .map(move |_| {
+            TcpStream::connect(&addr, &h2)
+                .and_then(|c| c.shutdown(std::net::Shutdown::Write).map(|()| c))
+                .and_then(|c| tokio_io::io::read_to_end(c, vec![]))
+                .map(|(_, buf)| assert!(buf.is_empty()))
+        })
+        .buffer_unordered(50)
+        .for_each(|()| Ok(()));
+
+    let start = Instant::now();
+    core.run(clients).unwrap();
+    let took = start.elapsed();
+    assert_eq!(sessions() - sessions_before, CONNECTIONS);
+    let secs = took.as_secs() as f64 + f64::from(took.subsec_nanos()) * 1e-9;
+    println!("{:.0} connections/s", CONNECTIONS as f64 / secs);
+}
+
+/// `--raw-relay` reads ahead into one fixed buffer: no per-read buffers are taken,
+/// and no more than the high watermark is ever held
+#[test]
+fn raw_relay_fixed_buffer() {
+    const TOTAL: usize = 4_000_000;
+    const HIGH: usize = 100_000;
+    for &raw_relay in [true, false].iter() {
+        let taken = || websocat::metrics::summary()["pooled_buffers_taken"].as_u64().unwrap_or(0);
+        let (taken_before, bytes_before) = (taken(), websocat::metrics::summary()["bytes_out"].as_u64().unwrap());
+        prepare!(core);
+        let prog = wt!(core,
+            &format!("random:{}", TOTAL),
+            "clog:null:",
+            nodelay,
+            opts = Options {
+                clog_direction: "write".to_string(),
+                clog_duration: Some(1),
+                buffer_size: Some(4096),
+                high_watermark: Some(HIGH),
+                raw_relay,
+                stats: true,
+                ..dflt()
+            },
+            errpanic,
+        );
+        run!(core, prog);
+        let v = websocat::metrics::summary();
+        assert_eq!((v["bytes_out"].as_u64().unwrap() - bytes_before) as usize, TOTAL);
+        if raw_relay {
+            assert_eq!(taken() - taken_before, 0);
+            assert!(v["queued_bytes_peak"].as_u64().unwrap() as usize <= HIGH);
+        } else {
+            // Each read is queued in a buffer of its own
+            assert!(taken() - taken_before > 0);
+        }
+    }
+}
+
+/// Bytes read and written by `threaded_io` threads come through unchanged
+#[test]
+fn threaded_io_roundtrip() {
+    use std::sync::{Arc, Mutex};
+    use websocat::threaded_io::{spawn_reader, spawn_writer};
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            // Short writes, to be retried by the thread
+            let n = buf.len().min(1000);
+            self.0.lock().unwrap().extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let binary: Vec<u8> = (0..300_000u32).map(|x| (x * 7 + x / 256) as u8).collect();
+    let lines: Vec<u8> = (0..10_000).flat_map(|i| format!("line {}\r\n", i).into_bytes()).collect();
+    for input in [binary, lines].iter() {
+        prepare!(core);
+        let out = Arc::new(Mutex::new(vec![]));
+        let r = spawn_reader("test-reader", std::io::Cursor::new(input.clone()), |_| ()).unwrap();
+        let w = spawn_writer("test-writer", Shared(out.clone())).unwrap();
+        let copied = tokio_io::io::copy(r, w).and_then(|(n, _, w)| tokio_io::io::shutdown(w).map(move |_| n));
+        let n = core.run(copied).unwrap();
+        assert_eq!(n as usize, input.len());
+        assert!(*out.lock().unwrap() == *input);
+    }
+}
+
+/// Console text conversions keep characters split across reads and writes intact
+#[test]
+fn windows_console_conversions() {
+    use websocat::console_text::{Utf16Decoder, Utf8Encoder};
+
+    let text = "ascii, кириллица, 😀 and more 😀";
+    let units: Vec<u16> = text.encode_utf16().collect();
+    let bytes = text.as_bytes();
+    for split in 1..units.len() {
+        let mut d = Utf16Decoder::default();
+        let mut out = vec![];
+        d.decode(&units[..split], &mut out);
+        d.decode(&units[split..], &mut out);
+        assert_eq!(&out[..], bytes);
+    }
+    for split in 1..bytes.len() {
+        let mut e = Utf8Encoder::default();
+        let mut out = vec![];
+        e.encode(&bytes[..split], &mut out);
+        e.encode(&bytes[split..], &mut out);
+        assert_eq!(out, units);
+    }
+}
+
+/// Broken text from the console or for it is replaced, without losing what follows
+#[test]
+fn windows_console_invalid_text() {
+    use websocat::console_text::{Utf16Decoder, Utf8Encoder};
+
+    let decode = |parts: &[&[u16]]| {
+        let mut d = Utf16Decoder::default();
+        let mut out = vec![];
+        for p in parts {
+            d.decode(p, &mut out);
+        }
+        String::from_utf8(out).unwrap()
+    };
+    // A lone low surrogate, and a high one followed by something else
+    assert_eq!(decode(&[&[0x61, 0xDC00, 0x62]]), "a\u{FFFD}b");
+    assert_eq!(decode(&[&[0x61, 0xD83D], &[0x62]]), "a\u{FFFD}b");
+    assert_eq!(decode(&[&[0xD83D], &[0xDE00, 0x0A]]), "\u{1F600}\n");
+    assert_eq!(decode(&[&[], &[0x61]]), "a");
+
+    let encode = |parts: &[&[u8]]| {
+        let mut e = Utf8Encoder::default();
+        let mut out = vec![];
+        for p in parts {
+            e.encode(p, &mut out);
+        }
+        String::from_utf16(&out).unwrap()
+    };
+    assert_eq!(encode(&[b"a\xFFb"]), "a\u{FFFD}b");
+    // An invalid byte before a character that is split across writes
+    assert_eq!(encode(&[b"a\xFF\xF0\x9F", b"\x98\x80"]), "a\u{FFFD}\u{1F600}");
+    assert_eq!(encode(&[b"\xF0", b"\x9F", b"\x98", b"\x80!"]), "\u{1F600}!");
+    // A sequence cut short by something else
+    assert_eq!(encode(&[b"\xF0\x9F", b"a"]), "\u{FFFD}a");
+}
+
+/// Bytes through stdin and stdout of the binary come back unchanged in binary and text mode
+#[test]
+#[cfg(windows)]
+fn windows_stdio_roundtrip() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let input: Vec<u8> = b"line\r\nunix line\n\x1a not EOF\n\xFF\x00 \xD0\xB1\n".to_vec();
+    for mode in &["-b", "-t"] {
+        let mut child = websocat_bin()
+            .args(&[*mode, "-", "mirror:"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&input).unwrap();
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, input, "{}", mode);
+    }
+}
+
+/// `pipe-l:` and `pipe:` in byte and message mode
+#[test]
+#[cfg(windows)]
+fn named_pipe() {
+    for &message_mode in [false, true].iter() {
+        let name = format!(r"\\.\pipe\websocat_test_{}_{}", std::process::id(), message_mode);
+        let opts = || Options {
+            pipe_message_mode: message_mode,
+            ..dflt()
+        };
+        prepare!(core);
+        let prog1 = wt!(core, "literal:qwerty", &format!("pipe-l:{}", name), nodelay, opts = opts(), errpanic,);
+        let prog2 = wt!(core, &format!("pipe:{}", name), "assert:qwerty", delay = 200, opts = opts(), errpanic,);
+        run!(core, prog1.join(prog2));
+    }
+}
+
+/// With `--pipe-instances 1`, a second client finds the pipe busy while the first is connected
+#[test]
+#[cfg(windows)]
+fn named_pipe_instances() {
+    use std::time::Duration;
+    use tokio_core::reactor::Timeout;
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    let name = format!(r"\\.\pipe\websocat_test_{}_instances", std::process::id());
+    prepare!(core);
+    let h = core.handle();
+    let server = wt!(core, &format!("pipe-l:{}", name), "mirror:", nodelay, opts = Options {
+        pipe_instances: Some(1),
+        ..dflt()
+    }, errignore,);
+    h.spawn(server);
+    let open = || std::fs::OpenOptions::new().read(true).write(true).open(&name);
+    core.run(Timeout::new(Duration::from_millis(100), &h).unwrap()).unwrap();
+    let first = open().unwrap();
+    core.run(Timeout::new(Duration::from_millis(100), &h).unwrap()).unwrap();
+    assert_eq!(open().unwrap_err().raw_os_error(), Some(ERROR_PIPE_BUSY));
+    drop(first);
+    core.run(Timeout::new(Duration::from_millis(100), &h).unwrap()).unwrap();
+    open().unwrap();
+}
+
+/// Named pipe options are refused where there are no named pipes
+#[test]
+#[cfg(not(windows))]
+fn pipe_options_elsewhere() {
+    for args in &[&["--pipe-message-mode"][..], &["--pipe-instances", "2"][..], &["--pipe-sddl", "D:(A;;GA;;;AU)"][..]] {
+        let out = websocat_bin().args(*args).args(&["literal:a", "-"]).output().unwrap();
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("only supported on Windows"));
+    }
+}
+
+/// Many small records through a seqpacket socket whose reader stalls for a second,
+/// so that sends keep hitting a full socket buffer. None of them may be lost or fail.
+#[cfg(all(unix, feature = "seqpacket"))]
+#[test]
+fn seqpacket_full_buffer() {
+    const TOTAL: usize = 500_000;
+    let mut sock = std::env::temp_dir();
+    sock.push(format!("websocat_test_{}.seqpacket", std::process::id()));
+    let sock = sock.to_str().unwrap().to_string();
+    let mut path = std::env::temp_dir();
+    path.push(format!("websocat_test_{}.seqpacket_out", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&sock);
+    let _ = std::fs::remove_file(&path);
+    let opts = || Options {
+        oneshot: true,
+        unlink_unix_socket: true,
+        gen_message_size: Some(50),
+        clog_direction: "write".to_string(),
+        clog_after_bytes: 1,
+        clog_duration: Some(1),
+        ..dflt()
+    };
+    prepare!(core);
+    let server = wt!(core,
+        &format!("seqpacket-l:{}", sock),
+        &format!("clog:writefile:{}", path),
+        nodelay,
+        opts = opts(),
+        errpanic,
+    );
+    let client = wt!(core,
+        &format!("random:{}", TOTAL),
+        &format!("seqpacket:{}", sock),
+        delay = 100,
+        opts = opts(),
+        errpanic,
+    );
+    run!(core, server.join(client));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), TOTAL as u64);
+    let _ = std::fs::remove_file(&sock);
+    let _ = std::fs::remove_file(&path);
+}
+
+/// Stdout whose reader has exited, and stdin with nothing to say
+#[derive(Debug)]
+struct ClosedStdoutSpec;
+struct NoInput;
+struct ClosedPipe;
+
+impl std::io::Read for NoInput {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::WouldBlock.into())
+    }
+}
+impl tokio_io::AsyncRead for NoInput {}
+
+impl std::io::Write for ClosedPipe {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+impl tokio_io::AsyncWrite for ClosedPipe {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl websocat::Specifier for ClosedStdoutSpec {
+    fn construct(&self, _p: websocat::ConstructParams) -> websocat::PeerConstructor {
+        let peer = websocat::Peer::new(NoInput, websocat::epipe::EpipeAsEof::new(ClosedPipe));
+        websocat::once(Box::new(futures::future::ok(peer)))
+    }
+    fn is_multiconnect(&self) -> bool {
+        false
+    }
+    fn uses_global_state(&self) -> bool {
+        false
+    }
+    fn get_type(&self) -> websocat::SpecifierType {
+        websocat::SpecifierType::Other
+    }
+}
+
+#[test]
+fn epipe_closes_normally() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use websocat::middleware::{Message, MiddlewareAction};
+
+    prepare!(core);
+    let close = Rc::new(RefCell::new(None));
+    let close2 = close.clone();
+    let server = websocat::builder::WebsocatBuilder::new()
+        .left("ws-l:127.0.0.1:45974")
+        .right("zero:")
+        .map_outgoing(move |m| {
+            if let Message::CloseReceived(ref x) = m {
+                *close2.borrow_mut() = Some(x.clone());
+            }
+            MiddlewareAction::Drop
+        })
+        .on_session_error(|_| ())
+        .run(&core.handle());
+    core.handle().spawn(server.map_err(|_| ()));
+    let client = WebsocatConfiguration {
+        opts: dflt(),
+        s1: Rc::new(ClosedStdoutSpec),
+        s2: spec("ws://127.0.0.1:45974/").unwrap(),
+    }.serve(
+        core.handle(),
+        Rc::new(|e: Box<std::error::Error>| panic!("{}", e)),
+    );
+    let t = tokio_timer::wheel().build();
+    let client = t.sleep(std::time::Duration::from_millis(200)).map_err(|_| ()).and_then(|()| client);
+    run!(core, client);
+    // Decides the exit code: 0 unless --exit-on-epipe-status
+    assert_eq!(websocat::shutdown::reason(), Some(websocat::epipe::REASON.to_string()));
+    assert!(websocat::metrics::summary().to_string().contains("stdout_dropped_bytes"));
+    let _ = core.run(t.sleep(std::time::Duration::from_millis(200)));
+    assert_eq!(*close.borrow(), Some(Some((1000, String::new()))));
+}
+
+/// `websocat ... | head -c 100`: the end of `head` ends websocat with status 0,
+/// or the one given by `--exit-on-epipe-status`
+#[test]
+#[cfg(unix)]
+fn epipe_exit_status() {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+
+    for &(extra, code) in &[(&[][..], 0), (&["--exit-on-epipe-status", "3"][..], 3)] {
+        let mut ws = websocat_bin()
+            .args(extra)
+            .args(&["-u", "-b", "random:", "-"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut head = Command::new("head")
+            .args(&["-c", "100"])
+            .stdin(ws.stdout.take().unwrap())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut got = vec![];
+        head.stdout.take().unwrap().read_to_end(&mut got).unwrap();
+        assert!(head.wait().unwrap().success());
+        assert_eq!(got.len(), 100);
+        assert_eq!(ws.wait().unwrap().code(), Some(code));
+    }
+}
+
+/// A socket that sends only half of each packet, like one with a tiny `SO_SNDBUF` might
+#[test]
+fn short_datagram_sends() {
+    use std::io::Write;
+    use websocat::datagram::WholeDatagrams;
+
+    struct HalfSender(Vec<Vec<u8>>);
+    impl Write for HalfSender {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = (buf.len() + 1) / 2;
+            self.0.push(buf[..n].to_vec());
+            Ok(n)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut w = WholeDatagrams::new(HalfSender(vec![]), false);
+    let e = w.write_all(b"datagram").unwrap_err();
+    assert_eq!(e.to_string(), "Short datagram send: 4 of 8 bytes");
+
+    let mut w = WholeDatagrams::new(HalfSender(vec![]), true);
+    w.write_all(b"datagram").unwrap();
+    w.write_all(b"x").unwrap();
+    // The tail is never sent as a packet of its own
+    assert_eq!(w.get_ref().0, vec![b"data".to_vec(), b"x".to_vec()]);
+    assert_eq!(websocat::metrics::summary()["datagrams_truncated"], 1);
+}
+
+/// Errors name the specifier they came from as written on the command line, and the session
+#[test]
+fn error_context_messages() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use websocat::exit_code::report;
+
+    prepare!(core);
+    // The last one fails while waiting for the first data to connect lazily
+    let cases = [
+        ("literal:hi", "tcp:127.0.0.1:1", false),
+        ("literal:hi", "log:tcp:127.0.0.1:1", false),
+        ("readfile:/", "null:", false),
+        ("readfile:/", "null:", true),
+    ];
+    let mut got = vec![];
+    for &(left, right, lazy_connect) in &cases {
+        let failure = Rc::new(RefCell::new(None));
+        let failure2 = failure.clone();
+        let prog = wt!(
+            core,
+            left,
+            right,
+            nodelay,
+            opts = Options {
+                listen_spec: Some(left.to_string()),
+                right_spec: Some(right.to_string()),
+                lazy_connect,
+                ..dflt()
+            },
+            onerror = move |e: Box<std::error::Error>| {
+                *failure2.borrow_mut() = Some((e.to_string(), report(&*e).to_json(false)));
+            },
+        );
+        let _ = core.run(prog);
+        let x = failure.borrow_mut().take().unwrap();
+        got.push(x);
+    }
+    // Texts of operating system errors differ between systems, what comes before them does not
+    assert!(got[0].0.starts_with("tcp:127.0.0.1:1 (right side), session 1: "), "{}", got[0].0);
+    assert!(got[0].1.contains(r#""context":"tcp:127.0.0.1:1 (right side), session 1","#), "{}", got[0].1);
+    assert!(got[0].1.contains(r#""error":"connect_failed""#), "{}", got[0].1);
+    assert!(
+        got[1].0.starts_with("tcp:127.0.0.1:1 (right side, inside log:), session 2: "),
+        "{}",
+        got[1].0
+    );
+    #[cfg(target_os = "linux")]
+    {
+        assert_eq!(got[2].0, "readfile:/ (left side), session 3: Is a directory (os error 21)");
+        assert_eq!(
+            got[2].1,
+            r#"{"code":18,"context":"readfile:/ (left side), session 3","detail":"Is a directory (os error 21)","error":"io"}"#
+        );
+        assert_eq!(got[3].0, "readfile:/ (left side), session 4: Is a directory (os error 21)");
+    }
+}
+
+/// The port is taken for a while, as if by a previous instance that has not exited yet
+#[test]
+fn bind_retry() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    prepare!(core);
+    let holder = std::net::TcpListener::bind("127.0.0.1:45975").unwrap();
+    let opts = || Options {
+        oneshot: true,
+        bind_retry: 10,
+        bind_retry_delay: 100,
+        ..dflt()
+    };
+    let server = wt!(core, "tcp-l:127.0.0.1:45975", "mirror:", nodelay, opts = opts(), errpanic,);
+    assert_eq!(websocat::bind_retry::pending(), 1);
+    let ready = Rc::new(Cell::new(false));
+    let ready2 = ready.clone();
+    core.handle().spawn(websocat::bind_retry::all_bound().map(move |()| ready2.set(true)));
+    let t = tokio_timer::wheel().build();
+    let release = t
+        .sleep(std::time::Duration::from_millis(250))
+        .map(move |()| drop(holder))
+        .map_err(|_| ());
+    core.handle().spawn(release);
+    let client = wt!(core,
+        "literal:hi",
+        "tcp:127.0.0.1:45975",
+        delay = 600,
+        noopts,
+        errpanic,
+    );
+    run!(core, server.join(client).map(|_| ()));
+    assert!(ready.get());
+    assert_eq!(websocat::bind_retry::pending(), 0);
+    assert_eq!(websocat::bind_retry::bound(), 1);
+    assert_eq!(websocat::bind_retry::failed(), 0);
+
+    // Gives up after the last attempt
+    let _holder = std::net::TcpListener::bind("127.0.0.1:45975").unwrap();
+    let failed = Rc::new(Cell::new(false));
+    let failed2 = failed.clone();
+    let server = wt!(core,
+        "tcp-l:127.0.0.1:45975",
+        "mirror:",
+        nodelay,
+        opts = Options {
+            bind_retry: 2,
+            bind_retry_delay: 10,
+            ..dflt()
+        },
+        onerror = move |_| failed2.set(true),
+    );
+    let _ = core.run(server);
+    assert!(failed.get());
+    assert_eq!(websocat::bind_retry::pending(), 0);
+    assert_eq!(websocat::bind_retry::failed(), 1);
+    assert!(core.run(websocat::bind_retry::all_bound()).is_err());
+}
+
+/// A listener that can't bind must not be reported as ready
+#[cfg(unix)]
+#[test]
+fn no_readiness_without_listener() {
+    use std::os::unix::net::UnixDatagram;
+    let dir = std::env::temp_dir();
+    let sock_path = dir.join(format!("websocat_test_notify_busy_{}", std::process::id()));
+    let pid_path = dir.join(format!("websocat_test_busy_{}.pid", std::process::id()));
+    let _ = std::fs::remove_file(&sock_path);
+    // A stale pid file would be replaced on readiness, and the new one removed on exit
+    std::fs::write(&pid_path, "0\n").unwrap();
+    let sock = UnixDatagram::bind(&sock_path).unwrap();
+    sock.set_nonblocking(true).unwrap();
+    let _holder = std::net::TcpListener::bind("127.0.0.1:45999").unwrap();
+    // Failing right away, and after running out of retries
+    for retry in &[&[][..], &["--bind-retry", "2", "--bind-retry-delay", "10"][..]] {
+        let out = websocat_bin()
+            .env("NOTIFY_SOCKET", &sock_path)
+            .arg("--pid-file")
+            .arg(&pid_path)
+            .args(*retry)
+            .args(&["tcp-l:127.0.0.1:45999", "mirror:"])
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("tcp-l:127.0.0.1:45999"));
+        assert_eq!(std::fs::read_to_string(&pid_path).unwrap(), "0\n");
+    }
+    let _ = std::fs::remove_file(&pid_path);
+    let mut buf = [0; 256];
+    while let Ok(n) = sock.recv(&mut buf) {
+        assert!(!String::from_utf8_lossy(&buf[..n]).contains("READY"));
+    }
+    let _ = std::fs::remove_file(&sock_path);
+}
+
+/// Run the `openssl` command in `dir`
+#[cfg(all(unix, feature = "ssl"))]
+fn openssl_in(dir: &std::path::Path, args: &[&str]) {
+    let st = std::process::Command::new("openssl").current_dir(dir).args(args).status().unwrap();
+    assert!(st.success());
+}
+
+/// Pack a key and certificate, and maybe more certificates, into a PKCS#12 archive
+#[cfg(all(unix, feature = "ssl"))]
+fn pkcs12_in(dir: &std::path::Path, key: &str, crt: &str, chain: Option<&str>, out: &str, pass: &str) {
+    let pass = format!("pass:{}", pass);
+    let mut args = vec![
+        "pkcs12", "-export", "-inkey", key, "-in", crt, "-out", out, "-passout", &pass,
+        "-certpbe", "PBE-SHA1-3DES", "-keypbe", "PBE-SHA1-3DES", "-macalg", "sha1",
+    ];
+    if let Some(c) = chain {
+        args.extend(&["-certfile", c]);
+    }
+    openssl_in(dir, &args);
+}
+
+/// Subject line of the certificate a TLS server presents
+#[cfg(all(unix, feature = "ssl"))]
+fn tls_server_subject(addr: &str) -> String {
+    for _ in 0..50 {
+        let out = std::process::Command::new("openssl")
+            .args(&["s_client", "-connect", addr])
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap();
+        let out = String::from_utf8_lossy(&out.stdout).into_owned();
+        if let Some(x) = out.lines().find(|x| x.starts_with("subject=")) {
+            return x.to_string();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    panic!("no TLS server at {}", addr);
+}
+
+/// Swapping the `--pkcs12-der` file and sending SIGHUP changes the certificate of new connections
+#[test]
+#[cfg(all(unix, feature = "ssl", feature = "signal_handler"))]
+fn tls_cert_reload() {
+    use std::process::{Command, Stdio};
+    let dir = std::env::temp_dir().join(format!("websocat_test_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in &["first", "second"] {
+        let subj = format!("/CN={}", name);
+        let (key, crt) = (format!("{}.key", name), format!("{}.crt", name));
+        openssl_in(&dir, &["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", &subj, "-keyout", &key, "-out", &crt]);
+        pkcs12_in(&dir, &key, &crt, None, &format!("{}.p12", name), "");
+    }
+    let path = dir.join("server.p12");
+    std::fs::copy(dir.join("first.p12"), &path).unwrap();
+
+    let child = websocat_bin()
+        .args(&["--pkcs12-der", path.to_str().unwrap(), "wss-l:127.0.0.1:45994", "mirror:"])
+        .env("RUST_LOG", "websocat::ssl_peer=info")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let hup = || {
+        let st = Command::new("kill").args(&["-HUP", &child.id().to_string()]).status().unwrap();
+        assert!(st.success());
+        std::thread::sleep(std::time::Duration::from_millis(300));
+    };
+    assert!(tls_server_subject("127.0.0.1:45994").contains("first"));
+
+    std::fs::copy(dir.join("second.p12"), &path).unwrap();
+    hup();
+    assert!(tls_server_subject("127.0.0.1:45994").contains("second"));
+
+    // A broken file leaves the previous certificate in place
+    std::fs::write(&path, b"garbage").unwrap();
+    hup();
+    assert!(tls_server_subject("127.0.0.1:45994").contains("second"));
+
+    let mut child = child;
+    child.kill().unwrap();
+    let out = child.wait_with_output().unwrap();
+    let err = String::from_utf8_lossy(&out.stderr);
+    assert!(err.contains("Loaded TLS certificate"), "{}", err);
+    assert!(err.contains("Reloaded TLS certificate"), "{}", err);
+    assert!(err.contains("SHA-256 fingerprint"), "{}", err);
+    assert!(err.contains("keeping the old one"), "{}", err);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Events and `log:` traffic dumps go to separate files and don't mix
+#[test]
+fn json_events_apart_from_traffic_log() {
+    prepare!(core);
+    let dir = std::env::temp_dir();
+    let events = dir.join(format!("websocat_test_{}.events2", std::process::id()));
+    let traffic = dir.join(format!("websocat_test_{}.traffic", std::process::id()));
+    let _ = std::fs::remove_file(&events);
+    let _ = std::fs::remove_file(&traffic);
+    let prog1 = wt!(core,
+        "ws-l:127.0.0.1:45995",
+        "log:literal:hi",
+        nodelay,
+        opts = Options {
+            log_format: "json".to_string(),
+            events_file: Some(events.to_str().unwrap().to_string()),
+            log_file: Some(traffic.to_str().unwrap().to_string()),
+            ..dflt()
+        },
+        errpanic,
+    );
+    core.handle().spawn(prog1);
+    let prog2 = wt!(core, "ws://127.0.0.1:45995", "assert:hi", delay = 200, noopts, errpanic,);
+    run!(core, prog2);
+    let ev = std::fs::read_to_string(&events).unwrap();
+    let tr = traffic_log_with(&traffic, "2 bytes");
+    let _ = std::fs::remove_file(&events);
+    let _ = std::fs::remove_file(&traffic);
+    assert!(ev.contains("\"event\":\"accepted\""), "{}", ev);
+    for l in ev.lines() {
+        assert!(l.starts_with('{') && l.ends_with('}'), "{}", l);
+        assert!(!l.contains("2 bytes"), "{}", l);
+    }
+    assert!(tr.contains("2 bytes"), "{}", tr);
+    assert!(!tr.contains("\"event\""), "{}", tr);
+}
+
+#[test]
+fn config_file_merge() {
+    use websocat::util::merge_config_args;
+    let v = |x: &[&str]| x.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let takes_value = |n: &str| n == "--header" || n == "--buffer-size" || n == "-B";
+    let repeatable = |t: &[String]| t[0].starts_with("--header");
+    let merge = |file: &[&str], cli: &[&str]| merge_config_args(v(file), v(cli), "a.conf", takes_value, repeatable);
+
+    // Scalar options from the command line override the file's
+    assert_eq!(
+        merge(&["--buffer-size", "10", "--text", "ws-l:127.0.0.1:8080"], &["--buffer-size=20", "mirror:"]).unwrap(),
+        v(&["--text", "--buffer-size=20", "--", "ws-l:127.0.0.1:8080", "mirror:"])
+    );
+    // Repeatable ones are appended, the file's first
+    assert_eq!(
+        merge(&["--header", "A: 1", "ws://a", "-"], &["--header", "B: 2"]).unwrap(),
+        v(&["--header", "A: 1", "--header", "B: 2", "--", "ws://a", "-"])
+    );
+    // A value that looks like a specifier stays with its option
+    assert_eq!(
+        merge(&["-B", "5"], &["-", "-"]).unwrap(),
+        v(&["-B", "5", "--", "-", "-"])
+    );
+    // `--` on the command line ends the options
+    assert_eq!(
+        merge(&["ws://a"], &["--", "--weird-file"]).unwrap(),
+        v(&["--", "ws://a", "--weird-file"])
+    );
+    let e = merge(&["ws://a", "-"], &["mirror:"]).unwrap_err();
+    assert!(e.contains("a.conf") && e.contains("`ws://a` `-`") && e.contains("`mirror:`"), "{}", e);
+}
+
+/// `--check` loads `--pkcs12-der` and verifies the certificate chain in it
+#[test]
+#[cfg(all(unix, feature = "ssl"))]
+fn check_pkcs12() {
+    let dir = std::env::temp_dir().join(format!("websocat_test_check_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let req = |args: &[&str]| {
+        let mut v = vec!["req", "-newkey", "rsa:2048", "-nodes", "-days", "1"];
+        v.extend(args);
+        openssl_in(&dir, &v);
+    };
+    req(&["-x509", "-subj", "/CN=ca", "-keyout", "ca.key", "-out", "ca.crt"]);
+    req(&["-subj", "/CN=leaf", "-keyout", "leaf.key", "-out", "leaf.csr"]);
+    openssl_in(&dir, &[
+        "x509", "-req", "-in", "leaf.csr", "-CA", "ca.crt", "-CAkey", "ca.key", "-CAcreateserial", "-days", "1",
+        "-out", "leaf.crt",
+    ]);
+    pkcs12_in(&dir, "leaf.key", "leaf.crt", Some("ca.crt"), "full.p12", "secret");
+    pkcs12_in(&dir, "leaf.key", "leaf.crt", None, "no_ca.p12", "secret");
+    std::fs::write(dir.join("garbage.p12"), b"garbage").unwrap();
+
+    let check = |file: &str, pass: &str| {
+        let out = websocat_bin()
+            .args(&["--check", "--pkcs12-der", dir.join(file).to_str().unwrap(), "--pkcs12-passwd", pass])
+            .args(&["wss-l:127.0.0.1:1443", "mirror:"])
+            .output()
+            .unwrap();
+        (out.status.success(), String::from_utf8_lossy(&out.stderr).into_owned())
+    };
+    let (ok, err) = check("full.p12", "secret");
+    assert!(ok, "{}", err);
+    for &(file, pass) in &[("full.p12", "wrong"), ("no_ca.p12", "secret"), ("garbage.p12", ""), ("missing.p12", "")] {
+        let (ok, err) = check(file, pass);
+        assert!(!ok, "{} {}", file, err);
+        assert!(err.contains("E0010") && err.contains(file), "{}", err);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Incoming data goes above the prompt as whole lines; a message that
+/// does not end with a newline gets one when the writer is flushed
+#[test]
+#[cfg(all(unix, feature = "unix_stdio", feature = "readline"))]
+fn readline_pending_output() {
+    use websocat::readline_peer::PendingOutput;
+    let mut p = PendingOutput::default();
+    assert_eq!(p.push(b"hel"), None);
+    assert_eq!(p.push(b"lo\nwor"), Some(b"hello\n".to_vec()));
+    assert_eq!(p.finish(), Some(b"wor\n".to_vec()));
+    assert_eq!(p.finish(), None);
+    assert_eq!(p.push(b"a\nb\n"), Some(b"a\nb\n".to_vec()));
+    assert_eq!(p.finish(), None);
+}
+
+/// Without a terminal on stdin, --readline leaves stdio alone
+#[test]
+#[cfg(all(unix, feature = "unix_stdio", feature = "readline"))]
+fn readline_bypassed_in_pipes() {
+    use std::io::Write;
+    use std::process::Stdio;
+    let mut child = websocat_bin()
+        .args(&["--readline", "-", "mirror:"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"one\ntwo").unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(out.stdout, b"one\ntwo".to_vec());
+}
+
+/// With -U the left side is not read at all, so its EOF can't close the session,
+/// with or without --no-close-on-stdin-eof. -u and -E conflict with the option.
+#[test]
+fn keep_open_after_stdin_eof_unidirectional() {
+    prepare!(core);
+    let prog1 = wt!(
+        core,
+        "ws-l:127.0.0.1:45998",
+        "delay:literal:late",
+        nodelay,
+        opts = Options {
+            delay_ms: 300,
+            ..dflt()
+        },
+        errignore,
+    );
+    core.handle().spawn(prog1);
+    for &keep_open in &[false, true] {
+        let prog2 = wt!(
+            core,
+            "assert:late",
+            "ws://127.0.0.1:45998/",
+            delay = 100,
+            opts = Options {
+                unidirectional_reverse: true,
+                no_close_on_stdin_eof: keep_open,
+                ..dflt()
+            },
+            errpanic,
+        );
+        run!(core, prog2);
+    }
+
+    for flag in &["-u", "-E"] {
+        let out = websocat_bin()
+            .args(&[flag, "--no-close-on-stdin-eof", "-", "ws://127.0.0.1:45998/"])
+            .output()
+            .unwrap();
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("-u or -E"));
+    }
+    let out = websocat_bin()
+        .args(&["-U", "--close-stdin-only", "--check", "-", "ws://127.0.0.1:45998/"])
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+}